@@ -0,0 +1,344 @@
+//! Local admin socket
+//!
+//! Serves a few read-only JSON endpoints over HTTP on a unix domain socket,
+//! so the agent can be inspected on the host without reading its logs:
+//! - `GET /status`: connection state, snapshot version, buffer depth
+//! - `GET /checks`: last result of each check
+//! - `GET /jobs`: recently executed commands
+//! - `GET /metrics`: agent self-metrics in the Prometheus text format
+//!
+//! Example: `curl --unix-socket /run/opsmap/agent.sock http://localhost/status`
+//!
+//! `/metrics` can also be served on a localhost TCP port for Prometheus.
+//! Windows has no unix socket here; only that TCP listener is available.
+
+use anyhow::{Context, Result};
+use serde_json::json;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::RwLock;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info};
+
+use crate::executor;
+use crate::metrics::metrics;
+use crate::AgentState;
+
+/// Largest request head we accept
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time allowed for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Endpoints available on a listener
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Endpoints {
+    /// Everything, on the root-only unix socket
+    #[cfg_attr(not(unix), allow(dead_code))]
+    All,
+    /// Only `/metrics`, on the TCP listener
+    Metrics,
+}
+
+/// An HTTP response
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string_pretty(&body).unwrap_or_default(),
+        }
+    }
+
+    fn metrics(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+}
+
+/// Serve the admin endpoints until the agent exits
+#[cfg(unix)]
+pub async fn serve(state: Arc<RwLock<AgentState>>) {
+    let settings = state.read().await.config.admin.clone();
+    if !settings.enabled {
+        return;
+    }
+
+    let listener = match bind(Path::new(&settings.socket_path)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(error = %e, path = %settings.socket_path, "Failed to open admin socket");
+            return;
+        }
+    };
+    info!(path = %settings.socket_path, "Admin socket listening");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, state, Endpoints::All).await {
+                        debug!(error = %e, "Admin request failed");
+                    }
+                });
+            }
+            Err(e) => error!(error = %e, "Failed to accept admin connection"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(state: Arc<RwLock<AgentState>>) {
+    if state.read().await.config.admin.enabled {
+        info!("Admin socket is not available on this platform, use metrics.listen_addr");
+    }
+}
+
+/// Serve `/metrics` on TCP until the agent exits, if enabled
+pub async fn serve_metrics(state: Arc<RwLock<AgentState>>) {
+    let settings = state.read().await.config.metrics.clone();
+    if !settings.enabled {
+        return;
+    }
+
+    let listener = match TcpListener::bind(&settings.listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(error = %e, addr = %settings.listen_addr, "Failed to open metrics listener");
+            return;
+        }
+    };
+    info!(addr = %settings.listen_addr, "Metrics endpoint listening");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, state, Endpoints::Metrics).await {
+                        debug!(error = %e, "Metrics request failed");
+                    }
+                });
+            }
+            Err(e) => error!(error = %e, "Failed to accept metrics connection"),
+        }
+    }
+}
+
+/// Bind the socket, replacing a stale one, readable by root only
+#[cfg(unix)]
+fn bind(path: &Path) -> Result<UnixListener> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+async fn handle<S>(
+    mut stream: S,
+    state: Arc<RwLock<AgentState>>,
+    endpoints: Endpoints,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .context("Timed out reading request")??;
+
+    let response = match parse_request_line(&head) {
+        Some(("GET", path)) => route(path, &state, endpoints).await,
+        Some(_) => Response::json(405, json!({ "error": "method not allowed" })),
+        None => Response::json(400, json!({ "error": "bad request" })),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read until the end of the request headers
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request too large");
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+/// Method and path of an HTTP request, without the query string
+fn parse_request_line(head: &str) -> Option<(&str, &str)> {
+    let mut parts = head.lines().next()?.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let path = target.split('?').next().unwrap_or(target);
+    Some((method, path))
+}
+
+async fn route(path: &str, state: &Arc<RwLock<AgentState>>, endpoints: Endpoints) -> Response {
+    let path = path.trim_end_matches('/');
+    if endpoints == Endpoints::Metrics && path != "/metrics" {
+        return Response::json(404, json!({ "error": "not found" }));
+    }
+
+    match path {
+        "/metrics" => {
+            metrics().set_buffer_size(state.read().await.buffer.len());
+            Response::metrics(metrics().render())
+        }
+        "/status" => {
+            let state = state.read().await;
+            let (components, checks) = state.scheduler.snapshot_size();
+            Response::json(
+                200,
+                json!({
+                    "agent_id": state.config.agent.id,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "gateway_url": state.config.gateway.url,
+                    "connected": state.is_connected,
+                    "snapshot_version": state.scheduler.snapshot_version(),
+                    "components": components,
+                    "checks": checks,
+                    "buffer_depth": state.buffer.len(),
+                    "unacked": state.outbox.len(),
+                    "buffer_expired": state.buffer.expired(),
+                }),
+            )
+        }
+        "/checks" => {
+            let results = state.read().await.scheduler.last_results();
+            Response::json(200, json!(results))
+        }
+        "/jobs" => Response::json(200, json!(executor::recent_jobs())),
+        _ => Response::json(404, json!({ "error": "not found" })),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AgentConfig;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(
+            parse_request_line("GET /status?pretty=1 HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some(("GET", "/status"))
+        );
+        assert_eq!(parse_request_line(""), None);
+        assert_eq!(parse_request_line("GET\r\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let mut config = AgentConfig::default();
+        config.agent.id = "agent-1".to_string();
+        let state = Arc::new(RwLock::new(AgentState::new(config)));
+
+        let response = route("/status", &state, Endpoints::All).await;
+        assert_eq!(response.status, 200);
+        let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["agent_id"], "agent-1");
+        assert_eq!(body["connected"], false);
+        assert_eq!(body["snapshot_version"], serde_json::Value::Null);
+        assert_eq!(body["buffer_depth"], 0);
+
+        let response = route("/checks/", &state, Endpoints::All).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "[]");
+
+        let response = route("/unknown", &state, Endpoints::All).await;
+        assert_eq!(response.status, 404);
+
+        // The TCP listener only exposes metrics
+        let response = route("/status", &state, Endpoints::Metrics).await;
+        assert_eq!(response.status, 404);
+        let response = route("/metrics", &state, Endpoints::Metrics).await;
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("opsmap_agent_buffer_size 0"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_over_socket() {
+        let dir = std::env::temp_dir().join(format!("opsmap-admin-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("agent.sock");
+
+        let mut config = AgentConfig::default();
+        config.admin.socket_path = path.to_string_lossy().to_string();
+        let state = Arc::new(RwLock::new(AgentState::new(config)));
+        let server = tokio::spawn(serve(state));
+
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /jobs HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: application/json"));
+
+        server.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,18 +3,24 @@
 //! Buffers data when the agent is disconnected from the Gateway.
 //! Data is persisted to disk to survive agent restarts.
 
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
 use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 use tracing::{debug, error, info, warn};
 
+use crate::metrics::metrics;
+
 /// Offline buffer for storing data when disconnected
 pub struct OfflineBuffer {
     queue: VecDeque<serde_json::Value>,
     max_size: usize,
     file_path: Option<String>,
+    coalesce: bool,
+    max_age: Option<chrono::Duration>,
+    /// Items dropped for exceeding `max_age`
+    expired: u64,
 }
 
 impl OfflineBuffer {
@@ -23,23 +29,45 @@ impl OfflineBuffer {
             queue: VecDeque::with_capacity(max_size.min(10000)),
             max_size,
             file_path: None,
+            coalesce: false,
+            max_age: None,
+            expired: 0,
         }
     }
 
+    /// Coalesce status deltas per check: every status transition is kept,
+    /// plus only the newest sample since the last one
+    pub fn set_coalesce(&mut self, coalesce: bool) {
+        self.coalesce = coalesce;
+    }
+
     /// Create buffer with file persistence
     pub fn with_file(max_size: usize, file_path: &str) -> Self {
         let mut buffer = Self::new(max_size);
-        buffer.file_path = Some(file_path.to_string());
-        buffer.load_from_file();
+        buffer.set_file(file_path);
         buffer
     }
 
-    /// Push data to buffer
-    pub fn push(&mut self, data: serde_json::Value) {
+    /// Persist to a file, loading what an earlier run left in it
+    pub fn set_file(&mut self, file_path: &str) {
+        self.file_path = Some(file_path.to_string());
+        self.load_from_file();
+    }
+
+    /// Push data to buffer, with secrets masked
+    pub fn push(&mut self, mut data: serde_json::Value) {
+        crate::redact::value(&mut data);
+        if self.coalesce {
+            self.coalesce_with(&data);
+        }
+
         if self.queue.len() >= self.max_size {
             // Remove oldest item
             self.queue.pop_front();
-            warn!(max_size = self.max_size, "Buffer full, dropping oldest item");
+            warn!(
+                max_size = self.max_size,
+                "Buffer full, dropping oldest item"
+            );
         }
 
         self.queue.push_back(data);
@@ -51,9 +79,74 @@ impl OfflineBuffer {
         }
     }
 
-    /// Pop data from buffer (FIFO)
+    /// Drop items older than `max_age_secs` when they are loaded or popped;
+    /// an age too large to represent never expires anything
+    pub fn set_max_age(&mut self, max_age_secs: Option<u64>) {
+        self.max_age = max_age_secs
+            .and_then(|secs| i64::try_from(secs).ok())
+            .and_then(chrono::Duration::try_seconds);
+    }
+
+    /// Items dropped so far for exceeding the max age
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Whether an item's `timestamp` is older than the max age; items
+    /// without one never expire
+    fn is_expired(&self, item: &serde_json::Value, now: DateTime<Utc>) -> bool {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+
+        item.get("timestamp")
+            .and_then(|t| t.as_str())
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .is_some_and(|t| now - t.with_timezone(&Utc) > max_age)
+    }
+
+    fn record_expired(&mut self) {
+        self.expired += 1;
+        metrics().inc_buffer_expired();
+    }
+
+    /// Drop the previous sample for the same check if it is neither a status
+    /// transition nor, once `data` is queued, the newest sample
+    fn coalesce_with(&mut self, data: &serde_json::Value) {
+        let key = match check_key(data) {
+            Some(key) => key,
+            None => return,
+        };
+
+        let mut same_check = self
+            .queue
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, item)| check_key(item).as_ref() == Some(&key));
+        let (last, last_status) = match same_check.next() {
+            Some((i, item)) => (i, item.get("status")),
+            None => return,
+        };
+        let transition = match same_check.next() {
+            Some((_, previous)) => previous.get("status") != last_status,
+            None => true,
+        };
+
+        if !transition {
+            self.queue.remove(last);
+        }
+    }
+
+    /// Pop data from buffer (FIFO), skipping expired items
     pub fn pop(&mut self) -> Option<serde_json::Value> {
-        let item = self.queue.pop_front();
+        let now = Utc::now();
+        let mut item = self.queue.pop_front();
+        while item.as_ref().is_some_and(|i| self.is_expired(i, now)) {
+            self.record_expired();
+            item = self.queue.pop_front();
+        }
 
         if item.is_some() && self.file_path.is_some() {
             self.save_to_file();
@@ -62,6 +155,25 @@ impl OfflineBuffer {
         item
     }
 
+    /// Change the maximum size, dropping the oldest items if it shrank
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+
+        if self.queue.len() > max_size {
+            let dropped = self.queue.len() - max_size;
+            self.queue.drain(..dropped);
+            warn!(
+                dropped = dropped,
+                max_size = max_size,
+                "Buffer shrunk, dropped oldest items"
+            );
+
+            if self.file_path.is_some() {
+                self.save_to_file();
+            }
+        }
+    }
+
     /// Get current buffer size
     pub fn len(&self) -> usize {
         self.queue.len()
@@ -99,13 +211,16 @@ impl OfflineBuffer {
         match File::open(path) {
             Ok(file) => {
                 let reader = BufReader::new(file);
+                let now = Utc::now();
                 let mut count = 0;
 
                 for line in reader.lines() {
                     match line {
                         Ok(l) => {
                             if let Ok(data) = serde_json::from_str(&l) {
-                                if self.queue.len() < self.max_size {
+                                if self.is_expired(&data, now) {
+                                    self.record_expired();
+                                } else if self.queue.len() < self.max_size {
                                     self.queue.push_back(data);
                                     count += 1;
                                 }
@@ -120,6 +235,12 @@ impl OfflineBuffer {
                 if count > 0 {
                     info!(count = count, "Loaded items from buffer file");
                 }
+                if self.expired > 0 {
+                    warn!(
+                        expired = self.expired,
+                        "Dropped expired items from buffer file"
+                    );
+                }
             }
             Err(e) => {
                 warn!(error = %e, path = %path, "Failed to open buffer file");
@@ -166,6 +287,14 @@ impl OfflineBuffer {
     }
 }
 
+/// Identifies the check a buffered status delta belongs to
+fn check_key(item: &serde_json::Value) -> Option<(&str, &str)> {
+    Some((
+        item.get("component_id")?.as_str()?,
+        item.get("check_name")?.as_str()?,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,4 +331,60 @@ mod tests {
         let item = buffer.pop().unwrap();
         assert_eq!(item["test"], 2); // First item should be dropped
     }
+
+    #[test]
+    fn test_set_max_size() {
+        let mut buffer = OfflineBuffer::new(10);
+
+        for i in 0..5 {
+            buffer.push(json!({"test": i}));
+        }
+        buffer.set_max_size(3);
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop().unwrap()["test"], 2);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut buffer = OfflineBuffer::new(10);
+        buffer.set_max_age(Some(3600));
+
+        let old = (Utc::now() - chrono::Duration::hours(2)).to_rfc3339();
+        buffer.push(json!({"test": 1, "timestamp": old}));
+        buffer.push(json!({"test": 2, "timestamp": Utc::now().to_rfc3339()}));
+        buffer.push(json!({"test": 3}));
+
+        assert_eq!(buffer.pop().unwrap()["test"], 2);
+        assert_eq!(buffer.pop().unwrap()["test"], 3);
+        assert!(buffer.pop().is_none());
+        assert_eq!(buffer.expired(), 1);
+
+        // Out of range is no limit, not a panic
+        buffer.set_max_age(Some(u64::MAX));
+        buffer.push(json!({"test": 4, "timestamp": old}));
+        assert_eq!(buffer.pop().unwrap()["test"], 4);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut buffer = OfflineBuffer::new(100);
+        buffer.set_coalesce(true);
+
+        let delta = |check: &str, status: &str, n: u32| json!({"component_id": "db", "check_name": check, "status": status, "n": n});
+        for (n, status) in ["ok", "ok", "ok", "error", "error", "ok", "ok"]
+            .iter()
+            .enumerate()
+        {
+            buffer.push(delta("port", status, n as u32));
+        }
+        buffer.push(delta("disk", "ok", 10));
+        buffer.push(delta("disk", "ok", 11));
+
+        let kept: Vec<_> = std::iter::from_fn(|| buffer.pop())
+            .map(|item| item["n"].as_u64().unwrap())
+            .collect();
+        // Transitions 0, 3, 5, the newest port sample 6, and the disk pair
+        assert_eq!(kept, vec![0, 3, 5, 6, 10, 11]);
+    }
 }
@@ -1,5 +1,10 @@
 //! Agent configuration module
 
+#[path = "../../../shared/interpolate.rs"]
+mod interpolate;
+pub mod paths;
+pub mod validate;
+
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -11,9 +16,39 @@ pub struct AgentConfig {
     pub agent: AgentSettings,
     pub gateway: GatewaySettings,
     pub tls: TlsSettings,
+    #[serde(default)]
     pub scheduler: SchedulerSettings,
+    #[serde(default)]
     pub buffer: BufferSettings,
     #[serde(default)]
+    pub plugins: PluginSettings,
+    #[serde(default)]
+    pub scripting: ScriptSettings,
+    #[serde(default)]
+    pub wasm: WasmSettings,
+    #[serde(default)]
+    pub discovery: DiscoverySettings,
+    #[serde(default)]
+    pub inventory: InventorySettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub enrollment: EnrollmentSettings,
+    #[serde(default)]
+    pub upgrade: UpgradeSettings,
+    #[serde(default)]
+    pub jobs: JobSettings,
+    #[serde(default)]
+    pub files: FileSettings,
+    #[serde(default)]
+    pub redaction: RedactionSettings,
+    #[serde(default)]
+    pub secrets: SecretsSettings,
+    #[serde(default)]
+    pub standalone: StandaloneSettings,
+    #[serde(default)]
     pub labels: HashMap<String, String>,
 }
 
@@ -23,31 +58,96 @@ pub struct AgentSettings {
     pub id: String,
     #[serde(default)]
     pub hostname: Option<String>,
+    /// Time allowed for a graceful shutdown; keep below systemd's TimeoutStopSec
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    /// How often hostname and IP addresses are checked for changes to send
+    /// the Gateway; 0 disables
+    #[serde(default = "default_metadata_interval")]
+    pub metadata_interval_secs: u64,
 }
 
 fn default_agent_id() -> String {
     "auto".to_string()
 }
 
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_metadata_interval() -> u64 {
+    60
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatewaySettings {
     pub url: String,
     #[serde(default = "default_reconnect_interval")]
     pub reconnect_interval_secs: u64,
+    /// Silence from the Gateway after which the agent pings it; 0 disables
     #[serde(default = "default_heartbeat_interval")]
     pub heartbeat_interval_secs: u64,
+    /// Heartbeat intervals without any frame from the Gateway after which
+    /// the connection is given up for dead and reopened
+    #[serde(default = "default_heartbeat_misses")]
+    pub heartbeat_misses: u32,
     #[serde(default = "default_timeout")]
     pub timeout_secs: u64,
+    /// Frames larger than this are deflated, if the Gateway supports it
+    #[serde(default = "default_compress_above")]
+    pub compress_above: usize,
+    /// Idle time before TCP keepalive probes, and between them; 0 disables
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive_secs: u64,
+    /// Time a WebSocket send may take before the connection is given up
+    /// and reopened; on Linux also how long sent data may stay
+    /// unacknowledged (TCP_USER_TIMEOUT). 0 disables
+    #[serde(default = "default_write_timeout")]
+    pub write_timeout_secs: u64,
+    /// "websocket", or "poll" to exchange messages in periodic HTTPS requests
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    /// Time between requests with `transport: poll`
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval_secs: u64,
 }
 
 fn default_reconnect_interval() -> u64 {
     10
 }
 
+fn default_transport() -> String {
+    if cfg!(feature = "websocket") {
+        "websocket".to_string()
+    } else {
+        "poll".to_string()
+    }
+}
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+fn default_compress_above() -> usize {
+    64 * 1024
+}
+
 fn default_heartbeat_interval() -> u64 {
     30
 }
 
+fn default_heartbeat_misses() -> u32 {
+    3
+}
+
+fn default_tcp_keepalive() -> u64 {
+    30
+}
+
+fn default_write_timeout() -> u64 {
+    30
+}
+
 fn default_timeout() -> u64 {
     60
 }
@@ -75,6 +175,13 @@ pub struct SchedulerSettings {
     pub batch_send_interval_secs: u64,
     #[serde(default = "default_max_concurrent")]
     pub max_concurrent_checks: usize,
+    /// Number of recent results kept per check for trend metrics
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /// Local exceptions to the checks of the snapshot; a missing file is
+    /// no exceptions
+    #[serde(default = "default_overrides_file")]
+    pub overrides_file: String,
 }
 
 fn default_check_interval() -> u64 {
@@ -89,46 +196,772 @@ fn default_max_concurrent() -> usize {
     10
 }
 
+fn default_history_size() -> usize {
+    120
+}
+
+fn default_overrides_file() -> String {
+    paths::join(paths::CONFIG_DIR, "overrides.yaml")
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            default_check_interval_secs: default_check_interval(),
+            batch_send_interval_secs: default_batch_interval(),
+            max_concurrent_checks: default_max_concurrent(),
+            history_size: default_history_size(),
+            overrides_file: default_overrides_file(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BufferSettings {
     #[serde(default = "default_buffer_size")]
     pub max_size: usize,
     pub file_path: Option<String>,
+    /// Keep only status transitions and the newest sample per check
+    #[serde(default)]
+    pub coalesce: bool,
+    /// Buffered items older than this are dropped instead of replayed
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
 }
 
 fn default_buffer_size() -> usize {
     10000
 }
 
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self {
+            max_size: default_buffer_size(),
+            file_path: Some(paths::join(paths::STATE_DIR, "buffer.json")),
+            coalesce: false,
+            max_age_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginSettings {
+    /// Directory containing Nagios-compatible plugin executables
+    #[serde(default = "default_plugins_dir")]
+    pub directory: String,
+}
+
+fn default_plugins_dir() -> String {
+    paths::join(paths::LIB_DIR, "plugins")
+}
+
+impl Default for PluginSettings {
+    fn default() -> Self {
+        Self {
+            directory: default_plugins_dir(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptSettings {
+    /// Allow "script" checks to run at all
+    #[serde(default = "default_scripting_enabled")]
+    pub enabled: bool,
+    /// Directories scripts may read files from
+    #[serde(default)]
+    pub allowed_read_paths: Vec<String>,
+    /// Hosts scripts may send HTTP probes to
+    #[serde(default)]
+    pub allowed_http_hosts: Vec<String>,
+    /// Upper bound on script operations, to stop runaway loops
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_scripting_enabled() -> bool {
+    true
+}
+
+fn default_max_operations() -> u64 {
+    1_000_000
+}
+
+impl Default for ScriptSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_scripting_enabled(),
+            allowed_read_paths: Vec::new(),
+            allowed_http_hosts: Vec::new(),
+            max_operations: default_max_operations(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WasmSettings {
+    /// Directory containing .wasm check/action modules
+    #[serde(default = "default_wasm_dir")]
+    pub directory: String,
+    /// Directories modules may read files from
+    #[serde(default)]
+    pub allowed_read_paths: Vec<String>,
+    /// host:port pairs modules may open TCP connections to
+    #[serde(default)]
+    pub allowed_connect: Vec<String>,
+    /// Fuel (instruction budget) per invocation
+    #[serde(default = "default_wasm_fuel")]
+    pub fuel: u64,
+}
+
+fn default_wasm_dir() -> String {
+    paths::join(paths::LIB_DIR, "wasm")
+}
+
+fn default_wasm_fuel() -> u64 {
+    100_000_000
+}
+
+impl Default for WasmSettings {
+    fn default() -> Self {
+        Self {
+            directory: default_wasm_dir(),
+            allowed_read_paths: Vec::new(),
+            allowed_connect: Vec::new(),
+            fuel: default_wasm_fuel(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoverySettings {
+    /// Periodically report listening sockets, services and containers
+    #[serde(default = "default_discovery_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_discovery_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_discovery_enabled() -> bool {
+    true
+}
+
+fn default_discovery_interval() -> u64 {
+    3600
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_discovery_enabled(),
+            interval_secs: default_discovery_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventorySettings {
+    /// Periodically report hardware and software inventory
+    #[serde(default = "default_inventory_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_inventory_interval")]
+    pub interval_secs: u64,
+    /// Include the installed OS packages list (dpkg or rpm)
+    #[serde(default)]
+    pub include_packages: bool,
+}
+
+fn default_inventory_enabled() -> bool {
+    true
+}
+
+fn default_inventory_interval() -> u64 {
+    86400
+}
+
+impl Default for InventorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_inventory_enabled(),
+            interval_secs: default_inventory_interval(),
+            include_packages: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminSettings {
+    /// Serve status and debug endpoints on a local unix socket
+    #[serde(default = "default_admin_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_admin_socket")]
+    pub socket_path: String,
+}
+
+fn default_admin_enabled() -> bool {
+    true
+}
+
+fn default_admin_socket() -> String {
+    paths::join(paths::RUNTIME_DIR, "agent.sock")
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_admin_enabled(),
+            socket_path: default_admin_socket(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSettings {
+    /// Serve Prometheus metrics on a TCP port (always available on the admin socket)
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_metrics_listen_addr")]
+    pub listen_addr: String,
+}
+
+fn default_metrics_listen_addr() -> String {
+    "127.0.0.1:9101".to_string()
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: default_metrics_listen_addr(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentSettings {
+    /// One-time token; when set and no client certificate exists, the agent enrolls
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Enrollment endpoint, defaults to /enroll on the Gateway host
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Where the agent ID assigned at enrollment is kept
+    #[serde(default = "default_enrollment_id_file")]
+    pub id_file: String,
+}
+
+fn default_enrollment_id_file() -> String {
+    paths::join(paths::STATE_DIR, "agent_id")
+}
+
+impl Default for EnrollmentSettings {
+    fn default() -> Self {
+        Self {
+            token: None,
+            url: None,
+            id_file: default_enrollment_id_file(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpgradeSettings {
+    /// Accept `upgrade` commands (binaries must still be signed with a release key)
+    #[serde(default = "default_upgrade_enabled")]
+    pub enabled: bool,
+}
+
+fn default_upgrade_enabled() -> bool {
+    true
+}
+
+impl Default for UpgradeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_upgrade_enabled(),
+        }
+    }
+}
+
+/// `file_push` and `file_fetch` commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSettings {
+    /// Directories files may be fetched from; none by default
+    #[serde(default)]
+    pub readable_dirs: Vec<String>,
+    /// Directories files may be pushed to; none by default
+    #[serde(default)]
+    pub writable_dirs: Vec<String>,
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Largest chunk carried by one command
+    #[serde(default = "default_chunk_bytes")]
+    pub chunk_bytes: usize,
+}
+
+fn default_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_chunk_bytes() -> usize {
+    512 * 1024
+}
+
+impl Default for FileSettings {
+    fn default() -> Self {
+        Self {
+            readable_dirs: Vec::new(),
+            writable_dirs: Vec::new(),
+            max_file_bytes: default_max_file_bytes(),
+            chunk_bytes: default_chunk_bytes(),
+        }
+    }
+}
+
+/// Masking of secrets in logs, command results and the offline buffer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactionSettings {
+    /// Regular expressions whose matches are masked, or only their first
+    /// capture group when they have one
+    #[serde(default = "default_redaction_patterns")]
+    pub patterns: Vec<String>,
+}
+
+fn default_redaction_patterns() -> Vec<String> {
+    vec![
+        // Credentials in URLs
+        r"[A-Za-z][A-Za-z0-9+.-]*://[^/\s:@]+:([^/\s@]+)@".to_string(),
+        // password=..., "token": "...", also in JSON log lines
+        format!(
+            r#"(?i)\b(?:{})\\?["']?\s*[=:]\s*\\?["']?([^\s"'\\&,;]+)"#,
+            crate::redact::SECRET_NAMES.join("|")
+        ),
+        r"(?i)\bauthorization:\s*(?:bearer|basic)\s+([^\s,;]+)".to_string(),
+    ]
+}
+
+impl Default for RedactionSettings {
+    fn default() -> Self {
+        Self {
+            patterns: default_redaction_patterns(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretsSettings {
+    /// Where `{{secret:NAME}}` resolves: "file" (`jobs.secrets_dir`),
+    /// "env", "exec" or "vault"
+    #[serde(default = "default_secrets_provider")]
+    pub provider: String,
+    /// Prefix of the variables the "env" provider reads
+    #[serde(default = "default_secrets_env_prefix")]
+    pub env_prefix: String,
+    /// Command the "exec" provider runs with the secret name as last
+    /// argument; it prints the value
+    #[serde(default)]
+    pub exec_command: String,
+    #[serde(default = "default_secrets_exec_timeout")]
+    pub exec_timeout_secs: u64,
+    #[serde(default)]
+    pub vault: VaultSettings,
+    /// How long values from "exec" and "vault" are reused; 0 disables
+    #[serde(default = "default_secrets_cache_ttl")]
+    pub cache_ttl_secs: u64,
+}
+
+fn default_secrets_provider() -> String {
+    "file".to_string()
+}
+
+fn default_secrets_env_prefix() -> String {
+    "OPSMAP_SECRET_".to_string()
+}
+
+fn default_secrets_exec_timeout() -> u64 {
+    10
+}
+
+fn default_secrets_cache_ttl() -> u64 {
+    300
+}
+
+impl Default for SecretsSettings {
+    fn default() -> Self {
+        Self {
+            provider: default_secrets_provider(),
+            env_prefix: default_secrets_env_prefix(),
+            exec_command: String::new(),
+            exec_timeout_secs: default_secrets_exec_timeout(),
+            vault: VaultSettings::default(),
+            cache_ttl_secs: default_secrets_cache_ttl(),
+        }
+    }
+}
+
+/// HashiCorp Vault KV v2 secrets engine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultSettings {
+    /// e.g. https://vault.example.com:8200
+    #[serde(default)]
+    pub addr: String,
+    /// File holding the token; VAULT_TOKEN when unset
+    #[serde(default)]
+    pub token_file: Option<String>,
+    #[serde(default = "default_vault_mount")]
+    pub mount: String,
+    /// Path under the mount where secret names are looked up
+    #[serde(default)]
+    pub path: String,
+    /// CA certificate for the Vault server, if not in the system store
+    #[serde(default)]
+    pub ca_file: Option<String>,
+}
+
+fn default_vault_mount() -> String {
+    "secret".to_string()
+}
+
+impl Default for VaultSettings {
+    fn default() -> Self {
+        Self {
+            addr: String::new(),
+            token_file: None,
+            mount: default_vault_mount(),
+            path: String::new(),
+            ca_file: None,
+        }
+    }
+}
+
+/// Checks defined here instead of by a Gateway snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StandaloneSettings {
+    /// Run the checks below without connecting to a Gateway
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where the last result of each check is written as JSON
+    #[serde(default = "default_results_file")]
+    pub results_file: Option<String>,
+    #[serde(default)]
+    pub components: Vec<StandaloneComponent>,
+}
+
+fn default_results_file() -> Option<String> {
+    Some(paths::join(paths::STATE_DIR, "results.json"))
+}
+
+impl Default for StandaloneSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            results_file: default_results_file(),
+            components: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StandaloneComponent {
+    pub id: String,
+    /// The id when not set
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "type", default = "default_component_type")]
+    pub component_type: String,
+    #[serde(default)]
+    pub checks: Vec<StandaloneCheck>,
+}
+
+fn default_component_type() -> String {
+    "service".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StandaloneCheck {
+    pub name: String,
+    /// A native check, or "plugin", "script", "wasm" and the like
+    #[serde(rename = "type")]
+    pub check_type: String,
+    #[serde(default = "default_check_config")]
+    pub config: serde_json::Value,
+    /// `scheduler.default_check_interval_secs` when not set
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    #[serde(default = "default_check_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_check_config() -> serde_json::Value {
+    serde_json::json!({})
+}
+
+fn default_check_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSettings {
+    /// Limits for detached jobs; a command's `params.limits` overrides them
+    #[serde(default)]
+    pub limits: JobLimits,
+    /// How limits are applied: "auto", "systemd" (a transient scope) or
+    /// "cgroupfs" (a cgroup v2 directory under `cgroup_root`)
+    #[serde(default = "default_cgroup_manager")]
+    pub cgroup_manager: String,
+    #[serde(default = "default_cgroup_root")]
+    pub cgroup_root: String,
+    /// Files that secret references resolve to with the "file" provider
+    #[serde(default = "default_secrets_dir")]
+    pub secrets_dir: String,
+    /// Detached jobs running at once; more wait in a FIFO queue
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent: usize,
+    /// Detached jobs running at once for the same component
+    #[serde(default = "default_max_concurrent_per_component")]
+    pub max_concurrent_per_component: usize,
+    /// Jobs allowed to wait; commands beyond are refused
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+    /// How long a command id is remembered, so a redelivered command is
+    /// answered with its first response instead of running again; 0 disables
+    #[serde(default = "default_dedup_ttl")]
+    pub dedup_ttl_secs: u64,
+    /// Bytes of stdout and of stderr kept in a command result; beyond that
+    /// the start and end are kept and the middle cut
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Also write output that was cut in full to the job log directory,
+    /// where `job_logs` reads it back
+    #[serde(default)]
+    pub spill_output: bool,
+    /// Interpreters "script" commands may name, with the arguments that
+    /// go before the script file
+    #[serde(default = "default_interpreters")]
+    pub interpreters: HashMap<String, String>,
+    /// Where commands scheduled with `run_at` or `run_after_secs` are kept
+    /// until they run
+    #[serde(default = "default_scheduled_file")]
+    pub scheduled_file: String,
+    /// Scheduled commands kept at once; more are refused, and 0 refuses all
+    #[serde(default = "default_max_scheduled")]
+    pub max_scheduled: usize,
+    /// Runtime for commands that target a container: "docker" or "podman"
+    #[serde(default = "default_container_runtime")]
+    pub container_runtime: String,
+    /// Run detached commands of a `run_as_user` through that user's login
+    /// shell, so their profile sets up the environment (Unix)
+    #[serde(default)]
+    pub login_shell: bool,
+    /// Run detached jobs under a supervisor that records their exit
+    /// status, reported later as a `job_update` (Unix)
+    #[serde(default)]
+    pub track_exit: bool,
+    /// A job log larger than this is rotated to `<name>.log.1`; 0 disables
+    #[serde(default = "default_log_max_bytes")]
+    pub log_max_bytes: u64,
+    /// Rotated parts kept for each job log
+    #[serde(default = "default_log_keep")]
+    pub log_keep: usize,
+    /// Job logs, rotated parts included, kept in all; the oldest go first,
+    /// and 0 sets no limit
+    #[serde(default = "default_log_max_total_bytes")]
+    pub log_max_total_bytes: u64,
+    /// Job logs not written to for this long are removed; 0 keeps them
+    #[serde(default = "default_log_max_age")]
+    pub log_max_age_secs: u64,
+    /// How often job logs are rotated and expired
+    #[serde(default = "default_log_cleanup_interval")]
+    pub log_cleanup_interval_secs: u64,
+}
+
+fn default_cgroup_manager() -> String {
+    "auto".to_string()
+}
+
+fn default_cgroup_root() -> String {
+    "/sys/fs/cgroup/opsmap".to_string()
+}
+
+fn default_secrets_dir() -> String {
+    paths::join(paths::CONFIG_DIR, "secrets")
+}
+
+fn default_max_concurrent_jobs() -> usize {
+    4
+}
+
+fn default_max_concurrent_per_component() -> usize {
+    1
+}
+
+fn default_max_queued() -> usize {
+    50
+}
+
+fn default_dedup_ttl() -> u64 {
+    3600
+}
+
+fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_scheduled_file() -> String {
+    paths::join(paths::STATE_DIR, "scheduled.json")
+}
+
+fn default_max_scheduled() -> usize {
+    100
+}
+
+fn default_container_runtime() -> String {
+    "docker".to_string()
+}
+
+fn default_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_keep() -> usize {
+    3
+}
+
+fn default_log_max_total_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_log_max_age() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_log_cleanup_interval() -> u64 {
+    300
+}
+
+fn default_interpreters() -> HashMap<String, String> {
+    #[cfg(unix)]
+    let interpreters = [
+        ("sh", "/bin/sh"),
+        ("bash", "/bin/bash"),
+        ("python", "python3"),
+    ];
+    #[cfg(windows)]
+    let interpreters = [
+        ("cmd", "cmd /C"),
+        (
+            "powershell",
+            "powershell -NoProfile -NonInteractive -ExecutionPolicy Bypass -File",
+        ),
+        ("python", "python"),
+    ];
+    interpreters
+        .into_iter()
+        .map(|(name, command)| (name.to_string(), command.to_string()))
+        .collect()
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            limits: JobLimits::default(),
+            cgroup_manager: default_cgroup_manager(),
+            cgroup_root: default_cgroup_root(),
+            secrets_dir: default_secrets_dir(),
+            max_concurrent: default_max_concurrent_jobs(),
+            max_concurrent_per_component: default_max_concurrent_per_component(),
+            max_queued: default_max_queued(),
+            dedup_ttl_secs: default_dedup_ttl(),
+            max_output_bytes: default_max_output_bytes(),
+            spill_output: false,
+            interpreters: default_interpreters(),
+            scheduled_file: default_scheduled_file(),
+            max_scheduled: default_max_scheduled(),
+            container_runtime: default_container_runtime(),
+            login_shell: false,
+            track_exit: false,
+            log_max_bytes: default_log_max_bytes(),
+            log_keep: default_log_keep(),
+            log_max_total_bytes: default_log_max_total_bytes(),
+            log_max_age_secs: default_log_max_age(),
+            log_cleanup_interval_secs: default_log_cleanup_interval(),
+        }
+    }
+}
+
+/// Resource limits for a detached job (Linux, cgroup v2)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobLimits {
+    /// CPU time as a percentage of one CPU; 200 allows two full CPUs
+    #[serde(default)]
+    pub cpu_percent: Option<u32>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// Maximum number of processes and threads
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+}
+
+impl JobLimits {
+    pub fn is_empty(&self) -> bool {
+        self.cpu_percent.is_none() && self.memory_mb.is_none() && self.pids_max.is_none()
+    }
+
+    /// These limits, with any set in `overrides` taking precedence
+    pub fn merge(&self, overrides: &JobLimits) -> JobLimits {
+        JobLimits {
+            cpu_percent: overrides.cpu_percent.or(self.cpu_percent),
+            memory_mb: overrides.memory_mb.or(self.memory_mb),
+            pids_max: overrides.pids_max.or(self.pids_max),
+        }
+    }
+}
+
 impl Default for AgentConfig {
     fn default() -> Self {
+        let cert_dir = paths::join(paths::CONFIG_DIR, "certs");
         Self {
             agent: AgentSettings {
                 id: "auto".to_string(),
                 hostname: None,
+                shutdown_timeout_secs: default_shutdown_timeout(),
+                metadata_interval_secs: default_metadata_interval(),
             },
             gateway: GatewaySettings {
                 url: "wss://gateway.opsmap.local:443".to_string(),
                 reconnect_interval_secs: 10,
                 heartbeat_interval_secs: 30,
+                heartbeat_misses: default_heartbeat_misses(),
+                tcp_keepalive_secs: default_tcp_keepalive(),
+                write_timeout_secs: default_write_timeout(),
                 timeout_secs: 60,
+                compress_above: default_compress_above(),
+                transport: default_transport(),
+                poll_interval_secs: default_poll_interval(),
             },
             tls: TlsSettings {
                 enabled: true,
-                cert_file: Some("/etc/opsmap/certs/agent.crt".to_string()),
-                key_file: Some("/etc/opsmap/certs/agent.key".to_string()),
-                ca_file: Some("/etc/opsmap/certs/ca.crt".to_string()),
+                cert_file: Some(paths::join(&cert_dir, "agent.crt")),
+                key_file: Some(paths::join(&cert_dir, "agent.key")),
+                ca_file: Some(paths::join(&cert_dir, "ca.crt")),
                 verify_server: true,
             },
-            scheduler: SchedulerSettings {
-                default_check_interval_secs: 30,
-                batch_send_interval_secs: 60,
-                max_concurrent_checks: 10,
-            },
-            buffer: BufferSettings {
-                max_size: 10000,
-                file_path: Some("/var/lib/opsmap/buffer.json".to_string()),
-            },
+            scheduler: SchedulerSettings::default(),
+            buffer: BufferSettings::default(),
+            plugins: PluginSettings::default(),
+            scripting: ScriptSettings::default(),
+            wasm: WasmSettings::default(),
+            discovery: DiscoverySettings::default(),
+            inventory: InventorySettings::default(),
+            admin: AdminSettings::default(),
+            metrics: MetricsSettings::default(),
+            enrollment: EnrollmentSettings::default(),
+            upgrade: UpgradeSettings::default(),
+            jobs: JobSettings::default(),
+            files: FileSettings::default(),
+            redaction: RedactionSettings::default(),
+            secrets: SecretsSettings::default(),
+            standalone: StandaloneSettings::default(),
             labels: HashMap::new(),
         }
     }
@@ -140,8 +973,12 @@ pub fn load_config(path: &Path) -> Result<AgentConfig> {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        let config: AgentConfig = serde_yaml::from_str(&content)
-            .with_context(|| "Failed to parse config file")?;
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str(&content).with_context(|| "Failed to parse config file")?;
+        interpolate::interpolate_value(&mut value)?;
+
+        let config: AgentConfig =
+            serde_yaml::from_value(value).with_context(|| "Failed to parse config file")?;
 
         Ok(config)
     } else {
@@ -154,6 +991,19 @@ pub fn load_config(path: &Path) -> Result<AgentConfig> {
     }
 }
 
+/// Top-level config sections whose values differ between two configs
+pub fn changed_sections(old: &AgentConfig, new: &AgentConfig) -> Vec<String> {
+    let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) => (old, new),
+        _ => return Vec::new(),
+    };
+
+    new.iter()
+        .filter(|(key, value)| old.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -189,4 +1039,18 @@ labels:
         assert_eq!(config.agent.id, "test-agent");
         assert_eq!(config.labels.get("role"), Some(&"database".to_string()));
     }
+
+    #[test]
+    fn test_changed_sections() {
+        let old = AgentConfig::default();
+        assert!(changed_sections(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.buffer.max_size = 500;
+        new.labels.insert("env".to_string(), "staging".to_string());
+
+        let mut changed = changed_sections(&old, &new);
+        changed.sort();
+        assert_eq!(changed, ["buffer", "labels"]);
+    }
 }
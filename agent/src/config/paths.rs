@@ -0,0 +1,81 @@
+//! Default file locations per platform
+//!
+//! Linux follows the FHS, the BSDs keep local configuration under
+//! `/usr/local/etc` and state under `/var/db`, macOS uses `/usr/local` and
+//! `/Library/Logs`, Windows everything under `C:\ProgramData\OpsMap`.
+
+use std::path::Path;
+
+#[cfg(target_os = "macos")]
+mod platform {
+    pub const CONFIG_FILE: &str = "/usr/local/etc/opsmap/agent.yaml";
+    pub const CONFIG_DIR: &str = "/usr/local/etc/opsmap";
+    pub const STATE_DIR: &str = "/usr/local/var/opsmap";
+    pub const LOG_DIR: &str = "/Library/Logs/OpsMap";
+    pub const RUNTIME_DIR: &str = "/var/run/opsmap";
+    pub const LIB_DIR: &str = "/usr/local/lib/opsmap";
+}
+
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+mod platform {
+    pub const CONFIG_FILE: &str = "/usr/local/etc/opsmap/agent.yaml";
+    pub const CONFIG_DIR: &str = "/usr/local/etc/opsmap";
+    pub const STATE_DIR: &str = "/var/db/opsmap";
+    pub const LOG_DIR: &str = "/var/log/opsmap";
+    pub const RUNTIME_DIR: &str = "/var/run/opsmap";
+    pub const LIB_DIR: &str = "/usr/local/lib/opsmap";
+}
+
+#[cfg(windows)]
+mod platform {
+    pub const CONFIG_FILE: &str = r"C:\ProgramData\OpsMap\agent.yaml";
+    pub const CONFIG_DIR: &str = r"C:\ProgramData\OpsMap";
+    pub const STATE_DIR: &str = r"C:\ProgramData\OpsMap";
+    pub const LOG_DIR: &str = r"C:\ProgramData\OpsMap\logs";
+    pub const RUNTIME_DIR: &str = r"C:\ProgramData\OpsMap";
+    pub const LIB_DIR: &str = r"C:\ProgramData\OpsMap";
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+mod platform {
+    pub const CONFIG_FILE: &str = "/etc/opsmap/agent.yaml";
+    pub const CONFIG_DIR: &str = "/etc/opsmap";
+    pub const STATE_DIR: &str = "/var/lib/opsmap";
+    pub const LOG_DIR: &str = "/var/log/opsmap";
+    pub const RUNTIME_DIR: &str = "/run/opsmap";
+    pub const LIB_DIR: &str = "/usr/lib/opsmap";
+}
+
+pub use platform::*;
+
+/// `name` inside `dir`, with the platform's separator
+pub fn join(dir: &str, name: &str) -> String {
+    Path::new(dir).join(name).to_string_lossy().to_string()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linux_paths() {
+        assert_eq!(
+            join(STATE_DIR, "buffer.json"),
+            "/var/lib/opsmap/buffer.json"
+        );
+        assert_eq!(join(LOG_DIR, "jobs"), "/var/log/opsmap/jobs");
+        assert!(CONFIG_FILE.starts_with(CONFIG_DIR));
+    }
+}
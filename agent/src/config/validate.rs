@@ -0,0 +1,422 @@
+//! Config validation
+//!
+//! Checks a parsed configuration for problems that would only show up at
+//! runtime: wrong URL schemes, missing or unreadable certificates, unwritable
+//! buffer paths. Used by `opsmap-agent validate`.
+
+use std::path::Path;
+
+use super::AgentConfig;
+
+#[path = "../../../shared/validation.rs"]
+mod validation;
+pub use validation::Validation;
+use validation::{check_private_key, check_readable_file};
+
+/// Validate an agent configuration
+pub fn validate(config: &AgentConfig) -> Validation {
+    let mut v = Validation::default();
+
+    // Gateway; polls are plain HTTP requests, so http(s):// URLs do too
+    let url = &config.gateway.url;
+    let polling = config.gateway.transport == "poll";
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        Some("wss") => {}
+        Some("https") if polling => {}
+        Some(scheme @ ("ws" | "http")) if scheme == "ws" || polling => {
+            if config.tls.enabled {
+                v.error(format!(
+                    "gateway.url '{}' uses {}:// but tls.enabled is true",
+                    url, scheme
+                ));
+            } else {
+                v.warning(format!("gateway.url '{}' is not encrypted", url));
+            }
+        }
+        _ if polling => v.error(format!(
+            "gateway.url '{}' must start with ws://, wss://, http:// or https://",
+            url
+        )),
+        _ => v.error(format!(
+            "gateway.url '{}' must start with ws:// or wss://",
+            url
+        )),
+    }
+
+    match config.gateway.transport.as_str() {
+        "websocket" if !cfg!(feature = "websocket") => {
+            v.error("gateway.transport is websocket but this agent was built without WebSocket support, use poll");
+        }
+        "websocket" => {}
+        "poll" => {
+            if config.gateway.poll_interval_secs == 0 {
+                v.error("gateway.poll_interval_secs must be greater than 0");
+            }
+        }
+        other => v.error(format!(
+            "gateway.transport '{}' must be websocket or poll",
+            other
+        )),
+    }
+    if config.enrollment.token.is_some() && !cfg!(feature = "enrollment") {
+        v.error("enrollment.token is set but this agent was built without enrollment support");
+    }
+
+    if config.gateway.reconnect_interval_secs == 0 {
+        v.error("gateway.reconnect_interval_secs must be greater than 0");
+    }
+    if config.gateway.heartbeat_interval_secs > 0 && config.gateway.heartbeat_misses == 0 {
+        v.error("gateway.heartbeat_misses must be greater than 0");
+    }
+    if config.scheduler.batch_send_interval_secs == 0 {
+        v.error("scheduler.batch_send_interval_secs must be greater than 0");
+    }
+    if config.buffer.max_size == 0 {
+        v.error("buffer.max_size must be greater than 0");
+    }
+
+    // TLS
+    if config.tls.enabled {
+        match (&config.tls.cert_file, &config.tls.key_file) {
+            (Some(cert), Some(key)) => {
+                check_readable_file(&mut v, "tls.cert_file", cert);
+                check_readable_file(&mut v, "tls.key_file", key);
+                check_private_key(&mut v, "tls.key_file", key);
+            }
+            (None, None) => {
+                v.warning("tls.cert_file and tls.key_file are not set, mTLS is disabled")
+            }
+            _ => v.error("tls.cert_file and tls.key_file must be set together"),
+        }
+
+        if let Some(ref ca) = config.tls.ca_file {
+            check_readable_file(&mut v, "tls.ca_file", ca);
+        }
+        if !config.tls.verify_server {
+            v.warning("tls.verify_server is false, the Gateway certificate is not verified");
+        }
+    }
+
+    // Buffer persistence
+    if let Some(ref path) = config.buffer.file_path {
+        check_writable_parent(&mut v, "buffer.file_path", path);
+    }
+
+    // Check sandboxes
+    if !Path::new(&config.plugins.directory).is_dir() {
+        v.warning(format!(
+            "plugins.directory '{}' does not exist, plugin checks will fail",
+            config.plugins.directory
+        ));
+    }
+    for path in &config.scripting.allowed_read_paths {
+        if !Path::new(path).exists() {
+            v.warning(format!(
+                "scripting.allowed_read_paths entry '{}' does not exist",
+                path
+            ));
+        }
+    }
+
+    // Job limits
+    if !matches!(
+        config.jobs.cgroup_manager.as_str(),
+        "auto" | "systemd" | "cgroupfs"
+    ) {
+        v.error(format!(
+            "jobs.cgroup_manager '{}' must be auto, systemd or cgroupfs",
+            config.jobs.cgroup_manager
+        ));
+    }
+    let limits = &config.jobs.limits;
+    if limits.cpu_percent == Some(0) || limits.memory_mb == Some(0) || limits.pids_max == Some(0) {
+        v.error("jobs.limits values must be greater than 0");
+    }
+    if !limits.is_empty() && !cfg!(any(target_os = "linux", windows)) {
+        v.warning(
+            "jobs.limits are only enforced on Linux and Windows, detached jobs will be refused",
+        );
+    }
+    if config.jobs.max_concurrent == 0
+        || config.jobs.max_concurrent_per_component == 0
+        || config.jobs.max_queued == 0
+    {
+        v.error("jobs.max_concurrent, max_concurrent_per_component and max_queued must be greater than 0");
+    }
+    if config.jobs.track_exit && cfg!(windows) {
+        v.warning("jobs.track_exit has no effect on Windows");
+    }
+    if config.jobs.max_output_bytes == 0 {
+        v.error("jobs.max_output_bytes must be greater than 0");
+    }
+    if config.jobs.log_cleanup_interval_secs == 0 {
+        v.error("jobs.log_cleanup_interval_secs must be greater than 0");
+    }
+    let (log_max_bytes, log_max_total_bytes) =
+        (config.jobs.log_max_bytes, config.jobs.log_max_total_bytes);
+    if log_max_total_bytes > 0 && log_max_total_bytes < log_max_bytes {
+        v.warning("jobs.log_max_total_bytes is below jobs.log_max_bytes, logs are removed before they are rotated");
+    }
+    if !crate::executor::CONTAINER_RUNTIMES.contains(&config.jobs.container_runtime.as_str()) {
+        v.error(format!(
+            "jobs.container_runtime must be one of {}",
+            crate::executor::CONTAINER_RUNTIMES.join(", ")
+        ));
+    }
+
+    // The agent runs without them, as it does without the file
+    if let Err(e) = crate::scheduler::Overrides::load(&config.scheduler.overrides_file) {
+        v.warning(format!("scheduler.overrides_file: {:#}, ignored", e));
+    }
+
+    // Redaction
+    if let Err(e) = crate::redact::compile(&config.redaction.patterns) {
+        v.error(format!("redaction.patterns: {}", e));
+    }
+
+    // Secrets
+    let secrets = &config.secrets;
+    match secrets.provider.as_str() {
+        "file" | "env" => {}
+        "exec" if secrets.exec_command.trim().is_empty() => {
+            v.error("secrets.exec_command is required with the exec provider");
+        }
+        "exec" if secrets.exec_timeout_secs == 0 => {
+            v.error("secrets.exec_timeout_secs must be greater than 0");
+        }
+        "exec" => {}
+        "vault" if !cfg!(feature = "http") => {
+            v.error("secrets.provider is vault but this agent was built without HTTP support");
+        }
+        "vault" if secrets.vault.addr.is_empty() => {
+            v.error("secrets.vault.addr is required with the vault provider");
+        }
+        "vault" => {}
+        other => v.error(format!(
+            "secrets.provider must be file, env, exec or vault, not '{}'",
+            other
+        )),
+    }
+
+    // Standalone mode
+    let standalone = &config.standalone;
+    if standalone.enabled {
+        if standalone.components.is_empty() {
+            v.warning(
+                "standalone.enabled is true but standalone.components is empty, nothing is checked",
+            );
+        }
+        let mut ids = std::collections::HashSet::new();
+        for component in &standalone.components {
+            if !ids.insert(component.id.as_str()) {
+                v.error(format!(
+                    "standalone.components: duplicate id '{}'",
+                    component.id
+                ));
+            }
+            let mut names = std::collections::HashSet::new();
+            for check in &component.checks {
+                if !names.insert(check.name.as_str()) {
+                    v.error(format!(
+                        "standalone.components: duplicate check '{}' in '{}'",
+                        check.name, component.id
+                    ));
+                }
+                if check.interval_secs == Some(0) {
+                    v.error(format!(
+                        "standalone.components: interval_secs of '{}' in '{}' must be greater than 0",
+                        check.name, component.id
+                    ));
+                }
+            }
+        }
+    } else if !standalone.components.is_empty() {
+        v.warning(
+            "standalone.components are set but standalone.enabled is false, they are not checked",
+        );
+    }
+
+    // Labels
+    for (key, value) in &config.labels {
+        if let Err(e) = crate::facts::check(value) {
+            v.error(format!("labels.{}: {}", key, e));
+        }
+    }
+
+    // File transfer
+    if config.files.chunk_bytes == 0 || config.files.max_file_bytes == 0 {
+        v.error("files.chunk_bytes and files.max_file_bytes must be greater than 0");
+    }
+    let file_dirs = config.files.readable_dirs.len() + config.files.writable_dirs.len();
+    if file_dirs > 0 && !cfg!(feature = "files") {
+        v.error("files directories are set but this agent was built without file transfer support");
+    }
+    for dir in config
+        .files
+        .readable_dirs
+        .iter()
+        .chain(&config.files.writable_dirs)
+    {
+        if !Path::new(dir).is_dir() {
+            v.warning(format!("files directory '{}' does not exist", dir));
+        }
+    }
+
+    v
+}
+
+fn check_writable_parent(v: &mut Validation, field: &str, path: &str) {
+    let parent = match Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        Some(parent) => parent,
+        None => Path::new("."),
+    };
+
+    if !parent.is_dir() {
+        v.error(format!(
+            "{} directory '{}' does not exist",
+            field,
+            parent.display()
+        ));
+    } else if !is_writable(parent) {
+        v.error(format!(
+            "{} directory '{}' is not writable",
+            field,
+            parent.display()
+        ));
+    }
+}
+
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+    nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+    std::fs::metadata(dir).is_ok_and(|m| !m.permissions().readonly())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AgentConfig {
+        let mut config = AgentConfig::default();
+        config.tls.cert_file = None;
+        config.tls.key_file = None;
+        config.tls.ca_file = None;
+        config.buffer.file_path = None;
+        config
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let v = validate(&config());
+        assert!(v.is_ok(), "{:?}", v.errors);
+    }
+
+    #[test]
+    fn test_url_scheme() {
+        let mut c = config();
+        c.gateway.url = "https://gateway:443".to_string();
+        assert!(!validate(&c).is_ok());
+
+        c.gateway.url = "ws://gateway:8080".to_string();
+        assert!(!validate(&c).is_ok());
+
+        c.tls.enabled = false;
+        let v = validate(&c);
+        assert!(v.is_ok());
+        assert!(v.warnings.iter().any(|w| w.contains("not encrypted")));
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut c = config();
+        c.gateway.transport = "poll".to_string();
+        assert!(validate(&c).is_ok());
+
+        c.gateway.url = "https://gateway:8443".to_string();
+        assert!(validate(&c).is_ok());
+        c.gateway.url = "http://gateway:8080".to_string();
+        assert!(!validate(&c).is_ok());
+        c.gateway.url = "ftp://gateway".to_string();
+        assert!(!validate(&c).is_ok());
+        c.gateway.url = "wss://gateway:443".to_string();
+
+        c.gateway.poll_interval_secs = 0;
+        assert!(!validate(&c).is_ok());
+
+        c.gateway.transport = "http2".to_string();
+        assert!(!validate(&c).is_ok());
+    }
+
+    #[test]
+    fn test_job_limits() {
+        let mut c = config();
+        c.jobs.limits.memory_mb = Some(512);
+        c.jobs.limits.pids_max = Some(0);
+        assert!(!validate(&c).is_ok());
+
+        c.jobs.limits.pids_max = Some(100);
+        c.jobs.cgroup_manager = "cgroup-v1".to_string();
+        assert!(!validate(&c).is_ok());
+
+        c.jobs.cgroup_manager = "auto".to_string();
+        c.jobs.max_concurrent = 0;
+        assert!(!validate(&c).is_ok());
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let mut c = config();
+        c.tls.cert_file = Some("/nonexistent/agent.crt".to_string());
+        c.tls.key_file = Some("/nonexistent/agent.key".to_string());
+        c.buffer.file_path = Some("/nonexistent/dir/buffer.json".to_string());
+
+        let v = validate(&c);
+        assert_eq!(v.errors.len(), 3, "{:?}", v.errors);
+    }
+
+    #[test]
+    fn test_malformed_overrides() {
+        let path =
+            std::env::temp_dir().join(format!("opsmap-overrides-{}.yaml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "checks: {not a list").unwrap();
+
+        let mut c = config();
+        c.scheduler.overrides_file = path.to_string_lossy().to_string();
+        let v = validate(&c);
+        assert!(v.is_ok(), "{:?}", v.errors);
+        assert!(v.warnings.iter().any(|w| w.contains("overrides_file")));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("opsmap-validate-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("agent.crt");
+        let key = dir.join("agent.key");
+        std::fs::write(&cert, "cert").unwrap();
+        std::fs::write(&key, "key").unwrap();
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut c = config();
+        c.tls.cert_file = Some(cert.to_string_lossy().to_string());
+        c.tls.key_file = Some(key.to_string_lossy().to_string());
+
+        let v = validate(&c);
+        assert!(v.is_ok());
+        assert!(v.warnings.iter().any(|w| w.contains("chmod 600")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
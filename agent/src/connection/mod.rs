@@ -1,20 +1,66 @@
 //! Gateway connection module
 //!
 //! Handles WebSocket connection to the Gateway with automatic reconnection
-//! and fallback to HTTPS polling.
+//! and fallback to HTTPS polling (`gateway.transport: poll`).
+
+#[cfg(feature = "websocket")]
+#[path = "../../../shared/deflate.rs"]
+mod deflate;
+mod poll;
+
+#[cfg(feature = "websocket")]
+use deflate::{deflate, inflate};
+pub use poll::{Poll, Polled};
 
 use anyhow::{anyhow, Context, Result};
-use futures_util::{SinkExt, StreamExt};
+#[cfg(feature = "websocket")]
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "websocket")]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "websocket")]
 use tokio::net::TcpStream;
+#[cfg(feature = "websocket")]
+use tokio::sync::{Mutex, Notify};
+#[cfg(feature = "websocket")]
 use tokio_tungstenite::{
-    connect_async_tls_with_config,
-    tungstenite::protocol::Message,
+    client_async_tls_with_config,
+    tungstenite::{client::IntoClientRequest, protocol::Message},
     MaybeTlsStream, WebSocketStream,
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
 
 use crate::config::AgentConfig;
+#[cfg(feature = "websocket")]
+use crate::config::GatewaySettings;
+use crate::discovery::DiscoveryReport;
+use crate::inventory::Inventory;
+#[cfg(feature = "websocket")]
+use crate::metrics::metrics;
+
+/// Protocol version spoken by this agent
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features this agent understands
+pub const CAPABILITIES: &[&str] = &[
+    "acks",
+    "config_update",
+    "deflate",
+    "diagnostics",
+    "discovery",
+    "inventory",
+    "metadata",
+    "msgpack",
+    "snapshot_delta",
+];
+
+/// How long to wait for the Gateway to answer our registration
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Message types from the Gateway
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,12 +68,36 @@ use crate::config::AgentConfig;
 pub enum GatewayMessage {
     #[serde(rename = "snapshot")]
     Snapshot(Snapshot),
+    #[serde(rename = "snapshot_delta")]
+    SnapshotDelta(SnapshotDelta),
     #[serde(rename = "command")]
     Command(Command),
     #[serde(rename = "ping")]
     Ping,
     #[serde(rename = "config_update")]
     ConfigUpdate(ConfigUpdate),
+    /// Sequenced messages up to `seq` reached the backend
+    #[serde(rename = "ack")]
+    Ack { seq: u64 },
+    /// Answer to our registration
+    #[serde(rename = "registered")]
+    Registered(Accepted),
+    /// One of our messages was refused, and why
+    #[serde(rename = "protocol_error")]
+    ProtocolError(ProtocolError),
+}
+
+/// Protocol version and features the Gateway settled on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Accepted {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Accepted {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
 }
 
 /// Snapshot of components this agent should manage
@@ -37,6 +107,22 @@ pub struct Snapshot {
     pub components: Vec<ComponentSnapshot>,
 }
 
+/// Changes to the snapshot at `base_version`, producing `version`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    pub base_version: u64,
+    pub version: u64,
+    pub operations: Vec<SnapshotOperation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum SnapshotOperation {
+    Add { component: ComponentSnapshot },
+    Update { component: ComponentSnapshot },
+    Remove { component_id: String },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ComponentSnapshot {
     pub id: String,
@@ -63,10 +149,43 @@ pub struct ActionDefinition {
     pub args: Vec<String>,
     #[serde(default)]
     pub run_as_user: Option<String>,
+    /// Extra environment; secret references are resolved on the agent
+    #[serde(default)]
+    pub env: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    pub cwd: Option<String>,
     #[serde(default)]
     pub is_async: bool,
     #[serde(default)]
     pub confirmation_required: bool,
+    /// Priority of the action's process: nice, I/O class, OOM score
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<crate::executor::Priority>,
+    /// Check of the component that must pass once the action has run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify: Option<VerifySpec>,
+}
+
+/// How an action's outcome is verified: `check` names one of the
+/// component's checks, retried every `interval_secs` until it is ok or
+/// `timeout_secs` have passed, starting `delay_secs` after the action
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifySpec {
+    pub check: String,
+    #[serde(default = "default_verify_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_verify_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub delay_secs: u64,
+}
+
+fn default_verify_timeout() -> u64 {
+    60
+}
+
+fn default_verify_interval() -> u64 {
+    5
 }
 
 /// Command to execute
@@ -78,12 +197,53 @@ pub struct Command {
     pub action_name: Option<String>,
     pub params: serde_json::Value,
     pub timeout_secs: u64,
+    /// W3C trace context of the Gateway's routing span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<crate::telemetry::TraceContext>,
+    /// Id given to the command when it was submitted, echoed on its responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Run at this time instead of now, even if the Gateway is unreachable then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Run this many seconds after it was received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_after_secs: Option<u64>,
 }
 
-/// Configuration update from Gateway
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Settings pushed by an operator through the Gateway; those left out stay
+/// as they are
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ConfigUpdate {
+    #[serde(default)]
     pub check_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub batch_send_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub buffer_max_size: Option<usize>,
+    #[serde(default)]
+    pub buffer_max_age_secs: Option<u64>,
+    /// A level or `RUST_LOG` directives
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Labels to add or change
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub remove_labels: Vec<String>,
+}
+
+/// Why the Gateway refused a message: "oversized", "invalid_json",
+/// "unknown_type", "unknown_field", "invalid_payload"...
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub kind: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub message_type: Option<String>,
+    #[serde(default)]
+    pub field: Option<String>,
 }
 
 /// Messages sent to the Gateway
@@ -100,6 +260,33 @@ pub enum AgentMessage {
     CommandResponse(CommandResponse),
     #[serde(rename = "pong")]
     Pong,
+    #[serde(rename = "discovery")]
+    Discovery(DiscoveryReport),
+    #[serde(rename = "inventory")]
+    Inventory(Inventory),
+    /// Part of a support bundle asked for with a `diagnostics` command
+    #[serde(rename = "diagnostics")]
+    Diagnostics(DiagnosticsChunk),
+    /// A detached job ended, after its command was answered
+    #[serde(rename = "job_update")]
+    JobUpdate(JobUpdate),
+    /// Hostname, labels or addresses changed since registration
+    #[serde(rename = "metadata_update")]
+    MetadataUpdate(AgentMetadata),
+    /// A snapshot delta did not apply; ask for the full snapshot
+    #[serde(rename = "snapshot_request")]
+    SnapshotRequest { version: Option<u64> },
+    /// The agent is going away on purpose
+    #[serde(rename = "disconnect")]
+    Disconnect { reason: String },
+}
+
+/// A frame to the Gateway; status messages carry a sequence number to ack
+#[derive(Debug, Serialize)]
+struct Frame<'a> {
+    #[serde(flatten)]
+    message: &'a AgentMessage,
+    seq: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,8 +294,21 @@ pub struct RegisterPayload {
     pub agent_id: String,
     pub hostname: String,
     pub labels: std::collections::HashMap<String, String>,
+    pub ip_addresses: Vec<String>,
     pub version: String,
     pub os: String,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// What the Gateway knows about the agent besides its id, sent again in a
+/// metadata update when it changes
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AgentMetadata {
+    pub hostname: String,
+    pub labels: std::collections::HashMap<String, String>,
+    /// Addresses of the host's interfaces, without loopback and link-local
+    pub ip_addresses: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -126,14 +326,49 @@ pub struct StatusBatch {
     pub deltas: Vec<StatusDelta>,
 }
 
+/// How a detached job ended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobUpdate {
+    /// Id of the command that started the job
+    pub job_id: String,
+    pub agent_id: String,
+    /// Id the agent gave the detached process
+    pub detached_job_id: String,
+    pub status: String, // "exited", "failed" or "killed"
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A part of a support bundle's JSON text; the parts of a bundle, joined
+/// in `index` order, make the whole
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagnosticsChunk {
+    /// Id of the `diagnostics` command
+    pub job_id: String,
+    pub agent_id: String,
+    pub index: usize,
+    pub total: usize,
+    pub data: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandResponse {
     pub job_id: String,
     pub agent_id: String,
-    pub status: String, // "started", "completed", "failed", "timeout"
+    pub status: String, // "started", "scheduled", "completed", "failed", "timeout"
     pub result: Option<CommandResult>,
     pub error: Option<String>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Position in the job queue of a "started" command still waiting to run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    /// When a "scheduled" command will run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduled_for: Option<chrono::DateTime<chrono::Utc>>,
+    /// The command's correlation id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -143,140 +378,648 @@ pub struct CommandResult {
     pub stderr: String,
     pub duration_ms: u64,
     pub timed_out: bool,
+    /// stdout or stderr exceeded `jobs.max_output_bytes` and was cut
+    #[serde(default)]
+    pub truncated: bool,
+    /// Results of the steps of a pipeline, in the order they ran
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepResult>,
+    /// Result of the check a "run_check" command ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<crate::native_commands::NativeResult>,
+}
+
+/// Outcome of one pipeline step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+    pub name: String,
+    /// "completed", "failed", "timeout" or "skipped"
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Run to undo an earlier step after a failure
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rollback: bool,
+}
+
+/// What a polling connection reads next
+pub enum PollStep {
+    Message(GatewayMessage),
+    Poll(Poll),
+}
+
+/// How messages travel to and from the Gateway
+enum Transport {
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WsLink>),
+    Poll(poll::PollTransport),
+}
+
+#[cfg(feature = "websocket")]
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The write half of a WebSocket, shared with the `Receiver` for pings
+#[cfg(feature = "websocket")]
+type WsSink = Arc<Mutex<SplitSink<WsStream, Message>>>;
+
+/// Both halves of a WebSocket; the read half goes to the `Receiver`
+#[cfg(feature = "websocket")]
+struct WsLink {
+    sink: WsSink,
+    stream: Option<SplitStream<WsStream>>,
+    /// Wakes the receiver when a send breaks the connection or it is dropped
+    broken: Arc<Notify>,
+}
+
+#[cfg(feature = "websocket")]
+impl Drop for WsLink {
+    fn drop(&mut self) {
+        self.broken.notify_one();
+    }
+}
+
+/// When to ping a silent Gateway, and when to give up on it
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+struct Heartbeat {
+    interval: Duration,
+    misses: u32,
 }
 
 /// Gateway connection
 pub struct GatewayConnection {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    agent_id: String,
+    transport: Transport,
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    compress_above: usize,
+    /// Polls are their own heartbeat, so WebSocket connections only
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    heartbeat: Option<Heartbeat>,
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    write_timeout: Option<Duration>,
+    /// A send failed or stalled, and the stream may hold half a frame:
+    /// everything fails from then on, so the agent reconnects
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    broken: bool,
+    accepted: Accepted,
+    /// Messages that arrived while waiting for the registration answer
+    early: VecDeque<GatewayMessage>,
+    /// Metadata as the Gateway last heard it
+    metadata: AgentMetadata,
 }
 
 impl GatewayConnection {
     /// Connect to the Gateway
     pub async fn connect(config: &AgentConfig) -> Result<Self> {
         let url = &config.gateway.url;
-        info!(url = %url, "Connecting to Gateway");
+        info!(url = %url, transport = %config.gateway.transport, "Connecting to Gateway");
+
+        let transport = if config.gateway.transport == "poll" {
+            Transport::Poll(poll::PollTransport::new(config)?)
+        } else {
+            Self::connect_websocket(config).await?
+        };
+
+        let mut connection = Self {
+            transport,
+            compress_above: config.gateway.compress_above,
+            heartbeat: (config.gateway.heartbeat_interval_secs > 0).then(|| Heartbeat {
+                interval: Duration::from_secs(config.gateway.heartbeat_interval_secs),
+                misses: config.gateway.heartbeat_misses,
+            }),
+            write_timeout: (config.gateway.write_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.gateway.write_timeout_secs)),
+            broken: false,
+            accepted: Accepted {
+                protocol_version: 1,
+                capabilities: Vec::new(),
+            },
+            early: VecDeque::new(),
+            metadata: AgentMetadata::default(),
+        };
+
+        // Register with Gateway
+        connection.register(config).await?;
+
+        Ok(connection)
+    }
+
+    #[cfg(feature = "websocket")]
+    async fn connect_websocket(config: &AgentConfig) -> Result<Transport> {
+        let request = config
+            .gateway
+            .url
+            .as_str()
+            .into_client_request()
+            .context("Invalid Gateway URL")?;
+        let host = request
+            .uri()
+            .host()
+            .ok_or_else(|| anyhow!("Gateway URL has no host"))?
+            .trim_matches(['[', ']'])
+            .to_string();
+        let port = request
+            .uri()
+            .port_u16()
+            .unwrap_or(match request.uri().scheme_str() {
+                Some("wss") => 443,
+                _ => 80,
+            });
+
+        // Our own socket, to tune it before the handshake
+        let connect_timeout = Duration::from_secs(config.gateway.timeout_secs);
+        let tcp = tokio::time::timeout(connect_timeout, TcpStream::connect((host.as_str(), port)))
+            .await
+            .map_err(|_| anyhow!("Connecting to Gateway timed out"))?
+            .context("Failed to connect to Gateway")?;
+        tune_socket(&tcp, &config.gateway).context("Failed to set socket options")?;
 
         // Connect with TLS if configured
-        let (ws, response) = if config.tls.enabled {
-            let connector = build_tls_connector(config)?;
-            connect_async_tls_with_config(url, None, false, Some(connector))
-                .await
-                .context("Failed to connect to Gateway")?
+        let connector = if config.tls.enabled {
+            Some(tokio_tungstenite::Connector::NativeTls(tls_connector(
+                config,
+            )?))
         } else {
-            tokio_tungstenite::connect_async(url)
-                .await
-                .context("Failed to connect to Gateway")?
+            None
         };
+        let (ws, response) = tokio::time::timeout(
+            connect_timeout,
+            client_async_tls_with_config(request, tcp, None, connector),
+        )
+        .await
+        .map_err(|_| anyhow!("WebSocket handshake with Gateway timed out"))?
+        .context("Failed to connect to Gateway")?;
 
         debug!(
             status = %response.status(),
             "WebSocket connection established"
         );
+        let (sink, stream) = ws.split();
+        Ok(Transport::WebSocket(Box::new(WsLink {
+            sink: Arc::new(Mutex::new(sink)),
+            stream: Some(stream),
+            broken: Arc::new(Notify::new()),
+        })))
+    }
 
-        let mut connection = Self {
-            ws,
-            agent_id: config.agent.id.clone(),
-        };
+    #[cfg(not(feature = "websocket"))]
+    async fn connect_websocket(_config: &AgentConfig) -> Result<Transport> {
+        Err(anyhow!(
+            "Agent was built without WebSocket support, set gateway.transport: poll"
+        ))
+    }
 
-        // Register with Gateway
-        connection.register(config).await?;
+    /// Whether messages are exchanged in periodic polls
+    fn is_polling(&self) -> bool {
+        matches!(self.transport, Transport::Poll(_))
+    }
 
-        Ok(connection)
+    /// When a polling connection next has something to read; WebSocket
+    /// frames are read as they arrive
+    pub fn next_poll(&self) -> Option<tokio::time::Instant> {
+        match self.transport {
+            Transport::Poll(ref poll) => Some(poll.next_poll()),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) => None,
+        }
+    }
+
+    /// For a polling connection, the next message already received, or the
+    /// poll fetching more, to run without holding the connection and hand
+    /// back to [`Self::finish_poll`]
+    pub fn start_poll(&mut self) -> Result<PollStep> {
+        if let Some(message) = self.early.pop_front() {
+            return Ok(PollStep::Message(message));
+        }
+        match self.transport {
+            Transport::Poll(ref mut poll) => Ok(match poll.pop() {
+                Some(message) => PollStep::Message(message),
+                None => PollStep::Poll(poll.start()?),
+            }),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) => Err(anyhow!("Gateway messages go to the receiver")),
+        }
+    }
+
+    /// Take in the answer to a poll, returning its first message
+    pub fn finish_poll(&mut self, polled: Polled) -> Result<Option<GatewayMessage>> {
+        match self.transport {
+            Transport::Poll(ref mut poll) => {
+                poll.finish(polled)?;
+                Ok(poll.pop())
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) => Err(anyhow!("Not a polling connection")),
+        }
     }
 
     /// Register this agent with the Gateway
     async fn register(&mut self, config: &AgentConfig) -> Result<()> {
-        let hostname = config
-            .agent
-            .hostname
-            .clone()
-            .or_else(|| hostname::get().ok().map(|h| h.to_string_lossy().to_string()))
-            .unwrap_or_else(|| "unknown".to_string());
-
+        let metadata = crate::metadata::collect(config);
         let os = std::env::consts::OS.to_string();
 
         let payload = RegisterPayload {
             agent_id: config.agent.id.clone(),
-            hostname,
-            labels: config.labels.clone(),
+            hostname: metadata.hostname.clone(),
+            labels: metadata.labels.clone(),
+            ip_addresses: metadata.ip_addresses.clone(),
             version: env!("CARGO_PKG_VERSION").to_string(),
             os,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES
+                .iter()
+                // Poll bodies are always JSON
+                .filter(|c| !(self.is_polling() && matches!(**c, "msgpack" | "deflate")))
+                .map(|c| c.to_string())
+                .collect(),
         };
 
         let msg = AgentMessage::Register(payload);
         self.send_message(&msg).await?;
+        self.metadata = metadata;
+        self.await_registered().await;
 
-        info!(agent_id = %config.agent.id, "Registered with Gateway");
+        info!(
+            agent_id = %config.agent.id,
+            protocol_version = self.accepted.protocol_version,
+            capabilities = ?self.accepted.capabilities,
+            "Registered with Gateway"
+        );
         Ok(())
     }
 
-    /// Send a message to the Gateway
+    /// Wait for the Gateway's answer to our registration. Gateways that
+    /// predate negotiation never answer; we keep protocol 1 for those.
+    async fn await_registered(&mut self) {
+        let deadline = tokio::time::Instant::now() + REGISTRATION_TIMEOUT;
+
+        loop {
+            match tokio::time::timeout_at(deadline, self.read_message()).await {
+                Ok(Ok(Some(GatewayMessage::Registered(accepted)))) => {
+                    self.accepted = accepted;
+                    return;
+                }
+                Ok(Ok(Some(message))) => self.early.push_back(message),
+                // Closed, failed or silent: the receive loop sees the rest
+                _ => return,
+            }
+        }
+    }
+
+    /// Whether the Gateway agreed to an optional feature
+    pub fn supports(&self, capability: &str) -> bool {
+        self.accepted.supports(capability)
+    }
+
+    /// Send a message to the Gateway, as MessagePack and deflated if agreed
+    /// on at registration. Polling connections queue it for the next poll.
     pub async fn send_message<T: Serialize>(&mut self, message: &T) -> Result<()> {
-        let json = serde_json::to_string(message)?;
-        self.ws.send(Message::Text(json)).await?;
-        Ok(())
+        match self.transport {
+            Transport::Poll(ref mut poll) => {
+                poll.send(serde_json::to_value(message)?);
+                Ok(())
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ref link) => {
+                if self.broken {
+                    return Err(anyhow!("Connection to Gateway is broken"));
+                }
+                let frame = encode_frame(&self.accepted, self.compress_above, message)?;
+                if let Err(e) = send_frame(&link.sink, frame, self.write_timeout).await {
+                    metrics().inc_send_failures();
+                    self.broken = true;
+                    link.broken.notify_one();
+                    return Err(e);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Send a message the Gateway acks by sequence number
+    pub async fn send_sequenced(&mut self, seq: u64, message: &AgentMessage) -> Result<()> {
+        self.send_message(&Frame { message, seq }).await
+    }
+
+    /// The read half of a WebSocket connection, to wait for Gateway
+    /// messages without holding the connection. Polling connections have
+    /// none: each poll sends as well.
+    #[cfg(feature = "websocket")]
+    pub fn take_receiver(&mut self) -> Option<Receiver> {
+        let Transport::WebSocket(ref mut link) = self.transport else {
+            return None;
+        };
+        Some(Receiver {
+            stream: link.stream.take()?,
+            sink: link.sink.clone(),
+            broken: link.broken.clone(),
+            msgpack: self.accepted.supports("msgpack"),
+            heartbeat: self.heartbeat,
+            write_timeout: self.write_timeout,
+            early: std::mem::take(&mut self.early),
+        })
+    }
+
+    #[cfg(not(feature = "websocket"))]
+    pub fn take_receiver(&mut self) -> Option<Receiver> {
+        None
     }
 
-    /// Receive a message from the Gateway
+    /// Receive a message from the Gateway. A polling connection polls once
+    /// and returns `None` if nothing arrived.
     pub async fn receive_message(&mut self) -> Result<Option<GatewayMessage>> {
-        match self.ws.next().await {
+        if let Some(message) = self.early.pop_front() {
+            return Ok(Some(message));
+        }
+        self.read_message().await
+    }
+
+    async fn read_message(&mut self) -> Result<Option<GatewayMessage>> {
+        match self.transport {
+            Transport::Poll(ref mut poll) => poll.receive().await,
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) if self.broken => {
+                Err(anyhow!("Connection to Gateway is broken"))
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ref mut link) => {
+                let msgpack = self.accepted.supports("msgpack");
+                let stream = link
+                    .stream
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Gateway messages go to the receiver"))?;
+                read_frame(
+                    stream,
+                    &link.sink,
+                    msgpack,
+                    self.heartbeat,
+                    self.write_timeout,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Send a command response
+    pub async fn send_command_response(&mut self, response: CommandResponse) -> Result<()> {
+        let msg = AgentMessage::CommandResponse(response);
+        self.send_message(&msg).await
+    }
+
+    /// Send how a detached job ended
+    pub async fn send_job_update(&mut self, update: JobUpdate) -> Result<()> {
+        let msg = AgentMessage::JobUpdate(update);
+        self.send_message(&msg).await
+    }
+
+    /// Send a discovery report
+    pub async fn send_discovery(&mut self, report: DiscoveryReport) -> Result<()> {
+        let msg = AgentMessage::Discovery(report);
+        self.send_message(&msg).await
+    }
+
+    /// Send part of a support bundle
+    pub async fn send_diagnostics(&mut self, chunk: DiagnosticsChunk) -> Result<()> {
+        let msg = AgentMessage::Diagnostics(chunk);
+        self.send_message(&msg).await
+    }
+
+    /// Send a hardware/software inventory
+    pub async fn send_inventory(&mut self, inventory: Inventory) -> Result<()> {
+        let msg = AgentMessage::Inventory(inventory);
+        self.send_message(&msg).await
+    }
+
+    /// Metadata as the Gateway last heard it
+    pub fn metadata(&self) -> &AgentMetadata {
+        &self.metadata
+    }
+
+    /// Send changed hostname, labels or addresses
+    pub async fn send_metadata(&mut self, metadata: AgentMetadata) -> Result<()> {
+        let msg = AgentMessage::MetadataUpdate(metadata.clone());
+        self.send_message(&msg).await?;
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Send pong
+    pub async fn send_pong(&mut self) -> Result<()> {
+        let msg = AgentMessage::Pong;
+        self.send_message(&msg).await
+    }
+
+    /// Close the connection cleanly, delivering anything still queued
+    pub async fn close(&mut self) {
+        match self.transport {
+            Transport::Poll(ref mut poll) => {
+                if let Err(e) = poll.flush().await {
+                    debug!(error = %e, "Final poll failed");
+                }
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(_) if self.broken => {}
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ref link) => {
+                // A Gateway that is gone would never answer the close
+                let limit = self.write_timeout.unwrap_or(Duration::MAX);
+                let close = async { link.sink.lock().await.close().await };
+                match tokio::time::timeout(limit, close).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!(error = %e, "WebSocket close failed"),
+                    Err(_) => debug!("WebSocket close timed out"),
+                }
+            }
+        }
+    }
+}
+
+/// The read half of a WebSocket connection to the Gateway
+#[cfg(feature = "websocket")]
+pub struct Receiver {
+    stream: SplitStream<WsStream>,
+    sink: WsSink,
+    broken: Arc<Notify>,
+    msgpack: bool,
+    heartbeat: Option<Heartbeat>,
+    write_timeout: Option<Duration>,
+    /// Messages that arrived while waiting for the registration answer
+    early: VecDeque<GatewayMessage>,
+}
+
+/// Without WebSocket support there is nothing to read apart from polls
+#[cfg(not(feature = "websocket"))]
+pub enum Receiver {}
+
+impl Receiver {
+    /// Receive a message from the Gateway; `None` once the connection is
+    /// closed, by the Gateway, a failed send or dropping it
+    pub async fn receive(&mut self) -> Result<Option<GatewayMessage>> {
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(message) = self.early.pop_front() {
+                return Ok(Some(message));
+            }
+            let read = read_frame(
+                &mut self.stream,
+                &self.sink,
+                self.msgpack,
+                self.heartbeat,
+                self.write_timeout,
+            );
+            tokio::select! {
+                _ = self.broken.notified() => Ok(None),
+                read = read => read,
+            }
+        }
+        #[cfg(not(feature = "websocket"))]
+        match *self {}
+    }
+}
+
+/// Encode a message as a WebSocket frame, per the agreed capabilities
+#[cfg(feature = "websocket")]
+fn encode_frame<T: Serialize>(
+    accepted: &Accepted,
+    compress_above: usize,
+    message: &T,
+) -> Result<Message> {
+    let msgpack = accepted.supports("msgpack");
+    let data = if msgpack {
+        rmp_serde::to_vec_named(message)?
+    } else {
+        serde_json::to_vec(message)?
+    };
+    let frame = if accepted.supports("deflate") && data.len() > compress_above {
+        Message::Binary(deflate(&data)?)
+    } else if msgpack {
+        Message::Binary(data)
+    } else {
+        Message::Text(String::from_utf8(data)?)
+    };
+    Ok(frame)
+}
+
+/// Read the next Gateway message from WebSocket frames. With a heartbeat,
+/// a silent Gateway is pinged every interval, and the connection fails once
+/// it has been silent for `misses` intervals: a half-open TCP connection
+/// would otherwise go unnoticed until the kernel gives up, hours later.
+#[cfg(feature = "websocket")]
+async fn read_frame(
+    ws: &mut SplitStream<WsStream>,
+    sink: &WsSink,
+    msgpack: bool,
+    heartbeat: Option<Heartbeat>,
+    write_timeout: Option<Duration>,
+) -> Result<Option<GatewayMessage>> {
+    let mut missed = 0;
+    loop {
+        let frame = match heartbeat {
+            Some(heartbeat) => match tokio::time::timeout(heartbeat.interval, ws.next()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    missed += 1;
+                    if missed >= heartbeat.misses {
+                        metrics().inc_heartbeat_timeouts();
+                        return Err(anyhow!(
+                            "No traffic from Gateway for {}s",
+                            heartbeat.interval.as_secs() * missed as u64
+                        ));
+                    }
+                    debug!(missed = missed, "Gateway silent, sending heartbeat");
+                    send_frame(sink, Message::Ping(Vec::new()), write_timeout).await?;
+                    continue;
+                }
+            },
+            None => ws.next().await,
+        };
+        // Any frame, a pong included, shows the Gateway is there
+        missed = 0;
+
+        match frame {
             Some(Ok(Message::Text(text))) => {
-                let msg: GatewayMessage = serde_json::from_str(&text)
-                    .context("Failed to parse Gateway message")?;
-                Ok(Some(msg))
+                let msg: GatewayMessage =
+                    serde_json::from_str(&text).context("Failed to parse Gateway message")?;
+                return Ok(Some(msg));
             }
             Some(Ok(Message::Binary(data))) => {
-                let msg: GatewayMessage = serde_json::from_slice(&data)
-                    .context("Failed to parse Gateway message")?;
-                Ok(Some(msg))
+                let data = inflate(data).context("Failed to inflate Gateway message")?;
+                let msg: GatewayMessage = if msgpack {
+                    rmp_serde::from_slice(&data).context("Failed to parse Gateway message")?
+                } else {
+                    serde_json::from_slice(&data).context("Failed to parse Gateway message")?
+                };
+                return Ok(Some(msg));
             }
             Some(Ok(Message::Ping(_))) => {
                 // Respond to ping
-                self.ws.send(Message::Pong(vec![])).await?;
-                Ok(None)
+                send_frame(sink, Message::Pong(vec![]), write_timeout).await?;
             }
-            Some(Ok(Message::Pong(_))) => Ok(None),
+            Some(Ok(Message::Pong(_))) | Some(Ok(Message::Frame(_))) => {}
             Some(Ok(Message::Close(_))) => {
                 info!("Gateway closed connection");
-                Ok(None)
+                return Ok(None);
             }
-            Some(Ok(Message::Frame(_))) => Ok(None),
-            Some(Err(e)) => Err(anyhow!("WebSocket error: {}", e)),
+            Some(Err(e)) => return Err(anyhow!("WebSocket error: {}", e)),
             None => {
                 info!("WebSocket stream ended");
-                Ok(None)
+                return Ok(None);
             }
         }
     }
+}
 
-    /// Send a status delta
-    pub async fn send_status_delta(&mut self, delta: StatusDelta) -> Result<()> {
-        let msg = AgentMessage::StatusDelta(delta);
-        self.send_message(&msg).await
+/// Send a frame, giving up after `write_timeout`: with the peer gone, a
+/// send waits forever once the socket buffer is full
+#[cfg(feature = "websocket")]
+async fn send_frame(sink: &WsSink, frame: Message, write_timeout: Option<Duration>) -> Result<()> {
+    let send = async { sink.lock().await.send(frame).await };
+    match write_timeout {
+        Some(limit) => tokio::time::timeout(limit, send)
+            .await
+            .map_err(|_| anyhow!("Sending to Gateway timed out after {}s", limit.as_secs()))?
+            .map_err(Into::into),
+        None => send.await.map_err(Into::into),
     }
+}
 
-    /// Send a batch of status updates
-    pub async fn send_status_batch(&mut self, deltas: Vec<StatusDelta>) -> Result<()> {
-        let msg = AgentMessage::StatusBatch(StatusBatch { deltas });
-        self.send_message(&msg).await
+/// Have the kernel notice a dead Gateway: keepalive probes on an idle
+/// connection and, on Linux, a limit on how long sent data may go
+/// unacknowledged, which fails reads and writes alike
+#[cfg(feature = "websocket")]
+fn tune_socket(tcp: &TcpStream, gateway: &GatewaySettings) -> std::io::Result<()> {
+    let socket = socket2::SockRef::from(tcp);
+    if gateway.tcp_keepalive_secs > 0 {
+        let idle = Duration::from_secs(gateway.tcp_keepalive_secs);
+        let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "windows"
+        ))]
+        let keepalive = keepalive.with_interval(idle);
+        #[cfg(target_os = "linux")]
+        let keepalive = keepalive.with_retries(3);
+        socket.set_tcp_keepalive(&keepalive)?;
     }
-
-    /// Send a command response
-    pub async fn send_command_response(&mut self, response: CommandResponse) -> Result<()> {
-        let msg = AgentMessage::CommandResponse(response);
-        self.send_message(&msg).await
+    #[cfg(target_os = "linux")]
+    if gateway.write_timeout_secs > 0 {
+        socket.set_tcp_user_timeout(Some(Duration::from_secs(gateway.write_timeout_secs)))?;
     }
+    Ok(())
+}
 
-    /// Send pong
-    pub async fn send_pong(&mut self) -> Result<()> {
-        let msg = AgentMessage::Pong;
-        self.send_message(&msg).await
-    }
+/// Check that the configured certificate files build a TLS connector
+pub fn check_tls(config: &AgentConfig) -> Result<()> {
+    tls_connector(config).map(|_| ())
 }
 
 /// Build TLS connector with mTLS support
-fn build_tls_connector(config: &AgentConfig) -> Result<tokio_tungstenite::Connector> {
+fn tls_connector(config: &AgentConfig) -> Result<native_tls::TlsConnector> {
     use native_tls::{Identity, TlsConnector};
 
     let mut builder = TlsConnector::builder();
@@ -285,8 +1028,8 @@ fn build_tls_connector(config: &AgentConfig) -> Result<tokio_tungstenite::Connec
     if let (Some(cert_file), Some(key_file)) = (&config.tls.cert_file, &config.tls.key_file) {
         let cert_pem = std::fs::read(cert_file)
             .with_context(|| format!("Failed to read certificate: {}", cert_file))?;
-        let key_pem = std::fs::read(key_file)
-            .with_context(|| format!("Failed to read key: {}", key_file))?;
+        let key_pem =
+            std::fs::read(key_file).with_context(|| format!("Failed to read key: {}", key_file))?;
 
         // Combine cert and key for PKCS12
         let identity = Identity::from_pkcs8(&cert_pem, &key_pem)
@@ -298,8 +1041,8 @@ fn build_tls_connector(config: &AgentConfig) -> Result<tokio_tungstenite::Connec
     if let Some(ca_file) = &config.tls.ca_file {
         let ca_pem = std::fs::read(ca_file)
             .with_context(|| format!("Failed to read CA certificate: {}", ca_file))?;
-        let ca_cert = native_tls::Certificate::from_pem(&ca_pem)
-            .context("Failed to parse CA certificate")?;
+        let ca_cert =
+            native_tls::Certificate::from_pem(&ca_pem).context("Failed to parse CA certificate")?;
         builder.add_root_certificate(ca_cert);
     }
 
@@ -309,7 +1052,181 @@ fn build_tls_connector(config: &AgentConfig) -> Result<tokio_tungstenite::Connec
         builder.danger_accept_invalid_certs(true);
     }
 
-    let connector = builder.build().context("Failed to build TLS connector")?;
+    builder.build().context("Failed to build TLS connector")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequenced_frame_and_ack() {
+        let message = AgentMessage::StatusBatch(StatusBatch { deltas: Vec::new() });
+        let frame = serde_json::to_value(Frame {
+            message: &message,
+            seq: 5,
+        })
+        .unwrap();
+        assert_eq!(frame["type"], "status_batch");
+        assert_eq!(frame["seq"], 5);
+
+        let ack: GatewayMessage =
+            serde_json::from_str(r#"{"type":"ack","payload":{"seq":5}}"#).unwrap();
+        assert!(matches!(ack, GatewayMessage::Ack { seq: 5 }));
+    }
+
+    #[test]
+    fn test_registered_answer() {
+        let registered: GatewayMessage = serde_json::from_str(
+            r#"{"type":"registered","payload":{"protocol_version":2,"capabilities":["acks"]}}"#,
+        )
+        .unwrap();
+        match registered {
+            GatewayMessage::Registered(accepted) => {
+                assert_eq!(accepted.protocol_version, 2);
+                assert_eq!(accepted.capabilities, vec!["acks"]);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let heartbeat = Some(Heartbeat {
+            interval: Duration::from_millis(100),
+            misses: 2,
+        });
+
+        tokio::spawn(async move {
+            // The first Gateway answers pings, then speaks after a while
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            let speak = tokio::time::sleep(Duration::from_millis(500));
+            tokio::pin!(speak);
+            loop {
+                tokio::select! {
+                    frame = ws.next() => match frame {
+                        Some(Ok(Message::Ping(data))) => ws.send(Message::Pong(data)).await.unwrap(),
+                        _ => break,
+                    },
+                    _ = &mut speak => {
+                        ws.send(Message::Text(r#"{"type":"ping"}"#.to_string())).await.unwrap();
+                        break;
+                    }
+                }
+            }
+
+            // The second never reads, like a peer that went away
+            let (tcp, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let (ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (sink, mut stream) = ws.split();
+        let sink = Arc::new(Mutex::new(sink));
+        let message = read_frame(&mut stream, &sink, false, heartbeat, None)
+            .await
+            .unwrap();
+        assert!(matches!(message, Some(GatewayMessage::Ping)));
+
+        let (ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (sink, mut stream) = ws.split();
+        let sink = Arc::new(Mutex::new(sink));
+        let error = read_frame(&mut stream, &sink, false, heartbeat, None)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("No traffic from Gateway"),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn test_send_stalled() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A Gateway that stops reading after the handshake
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        tune_socket(&tcp, &AgentConfig::default().gateway).unwrap();
+        assert!(socket2::SockRef::from(&tcp).keepalive().unwrap());
+        let (ws, _) = tokio_tungstenite::client_async_tls_with_config(
+            format!("ws://{}", addr),
+            tcp,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let sink = Arc::new(Mutex::new(ws.split().0));
+
+        // Frames pile up in the socket buffers until a send cannot finish
+        let write_timeout = Some(Duration::from_millis(200));
+        let error = loop {
+            let frame = Message::Binary(vec![0; 1024 * 1024]);
+            if let Err(e) = send_frame(&sink, frame, write_timeout).await {
+                break e;
+            }
+        };
+        assert!(error.to_string().contains("timed out"), "{}", error);
+    }
 
-    Ok(tokio_tungstenite::Connector::NativeTls(connector))
+    #[test]
+    fn test_msgpack_frames() {
+        let delta = StatusDelta {
+            component_id: "db".to_string(),
+            check_name: "port".to_string(),
+            status: "ok".to_string(),
+            message: None,
+            metrics: Some(serde_json::json!({"latency_ms": 12})),
+            timestamp: chrono::Utc::now(),
+        };
+        let message = AgentMessage::StatusDelta(delta);
+        let data = rmp_serde::to_vec_named(&Frame {
+            message: &message,
+            seq: 9,
+        })
+        .unwrap();
+
+        // The Gateway sees the same fields it would in JSON
+        let frame: serde_json::Value = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(frame["type"], "status_delta");
+        assert_eq!(frame["seq"], 9);
+        assert_eq!(frame["payload"]["metrics"]["latency_ms"], 12);
+
+        let ack =
+            rmp_serde::to_vec_named(&serde_json::json!({"type": "ack", "payload": {"seq": 9}}))
+                .unwrap();
+        let ack: GatewayMessage = rmp_serde::from_slice(&ack).unwrap();
+        assert!(matches!(ack, GatewayMessage::Ack { seq: 9 }));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn test_deflate_round_trip() {
+        let json = serde_json::to_vec(&serde_json::json!({
+            "type": "snapshot",
+            "payload": {"version": 1, "components": []},
+        }))
+        .unwrap();
+        let deflated = deflate(&json).unwrap();
+        assert_eq!(deflated[0], 0x78);
+
+        let snapshot: GatewayMessage = serde_json::from_slice(&inflate(deflated).unwrap()).unwrap();
+        assert!(matches!(snapshot, GatewayMessage::Snapshot(ref s) if s.version == 1));
+
+        // Frames that are not deflated pass through
+        assert_eq!(inflate(json.clone()).unwrap(), json);
+    }
 }
@@ -0,0 +1,383 @@
+//! HTTPS polling transport
+//!
+//! For networks that do not let WebSockets through, and builds without
+//! WebSocket support. Every `poll_interval_secs` the agent POSTs the messages
+//! it queued to the Gateway's `/poll` endpoint and gets back those waiting
+//! for it:
+//!
+//! ```json
+//! > {"session": "3f2c...", "messages": [{"type": "status_batch", "payload": {...}, "seq": 7}]}
+//! < {"session": "3f2c...", "messages": [{"type": "ack", "payload": {"seq": 7}}]}
+//! ```
+//!
+//! The first request carries no session and starts with the `register`
+//! message. Messages are always JSON. An expired session is an error, after
+//! which the agent reconnects and registers again, as it would after a
+//! dropped WebSocket.
+//!
+//! A poll is taken out of the transport with [`PollTransport::start`] and
+//! its answer handed back with [`PollTransport::finish`], so the round trip
+//! runs without holding the connection: messages queued meanwhile go in the
+//! next poll.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use super::GatewayMessage;
+use crate::config::AgentConfig;
+use crate::metrics::metrics;
+
+/// Largest response body accepted from the Gateway
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// HTTP status the Gateway answers to agents below the minimum version
+const UPGRADE_REQUIRED: u16 = 426;
+
+#[derive(Serialize)]
+struct PollRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<&'a str>,
+    messages: &'a [serde_json::Value],
+}
+
+#[derive(Deserialize)]
+struct PollResponse {
+    session: String,
+    #[serde(default)]
+    messages: Vec<serde_json::Value>,
+}
+
+/// Where `/poll` is served, derived from `gateway.url`
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Invalid gateway.url: {}", url))?;
+        let tls = match scheme {
+            "wss" | "https" => true,
+            "ws" | "http" => false,
+            _ => bail!("Unsupported gateway.url scheme: {}", scheme),
+        };
+        let authority = rest.split(['/', '?']).next().unwrap_or_default();
+
+        // [v6]:port, host:port or a bare host
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, port) = v6
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("Invalid gateway.url: {}", url))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            bail!("Invalid gateway.url: {}", url);
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("Invalid port in gateway.url: {}", url))?,
+            None if tls => 443,
+            None => 80,
+        };
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// How polls reach the Gateway
+#[derive(Clone)]
+struct Poster {
+    endpoint: Endpoint,
+    tls: Option<tokio_native_tls::TlsConnector>,
+    timeout: Duration,
+}
+
+/// A poll on its way, run without holding the transport
+pub struct Poll {
+    poster: Poster,
+    body: Vec<u8>,
+    /// Queued messages it carries
+    sent: usize,
+}
+
+/// The answer to a [`Poll`], for [`PollTransport::finish`]
+pub struct Polled {
+    sent: usize,
+    exchanged: Result<(u16, Vec<u8>)>,
+}
+
+impl Poll {
+    /// Exchange the messages with the Gateway
+    pub async fn run(self) -> Polled {
+        let timeout = self.poster.timeout;
+        let exchanged = tokio::time::timeout(timeout, self.poster.post(&self.body))
+            .await
+            .map_err(|_| anyhow!("Poll timed out after {}s", timeout.as_secs()))
+            .and_then(|result| result);
+        Polled {
+            sent: self.sent,
+            exchanged,
+        }
+    }
+}
+
+/// Polling state for one session with the Gateway
+pub struct PollTransport {
+    poster: Poster,
+    interval: Duration,
+    session: Option<String>,
+    outgoing: Vec<serde_json::Value>,
+    incoming: VecDeque<GatewayMessage>,
+    last_poll: Option<Instant>,
+}
+
+impl PollTransport {
+    pub fn new(config: &AgentConfig) -> Result<Self> {
+        let endpoint = Endpoint::parse(&config.gateway.url)?;
+        let tls = if !endpoint.tls {
+            None
+        } else if config.tls.enabled {
+            Some(super::tls_connector(config)?.into())
+        } else {
+            Some(native_tls::TlsConnector::new()?.into())
+        };
+
+        Ok(Self {
+            poster: Poster {
+                endpoint,
+                tls,
+                timeout: Duration::from_secs(config.gateway.timeout_secs),
+            },
+            interval: Duration::from_secs(config.gateway.poll_interval_secs),
+            session: None,
+            outgoing: Vec::new(),
+            incoming: VecDeque::new(),
+            last_poll: None,
+        })
+    }
+
+    /// Queue a message for the next poll
+    pub fn send(&mut self, message: serde_json::Value) {
+        self.outgoing.push(message);
+    }
+
+    /// The next message from the Gateway, polling if none is waiting.
+    /// `None` means the poll brought nothing.
+    pub async fn receive(&mut self) -> Result<Option<GatewayMessage>> {
+        if self.incoming.is_empty() {
+            self.poll().await?;
+        }
+        Ok(self.incoming.pop_front())
+    }
+
+    /// A message the last poll brought and not read yet
+    pub fn pop(&mut self) -> Option<GatewayMessage> {
+        self.incoming.pop_front()
+    }
+
+    /// When the next poll is due
+    pub fn next_poll(&self) -> Instant {
+        match self.last_poll {
+            Some(last) if self.incoming.is_empty() => last + self.interval,
+            _ => Instant::now(),
+        }
+    }
+
+    /// Deliver whatever is still queued, e.g. a disconnect notice
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        self.poll().await
+    }
+
+    /// Exchange queued messages with the Gateway
+    async fn poll(&mut self) -> Result<()> {
+        let polled = self.start()?.run().await;
+        self.finish(polled)
+    }
+
+    /// A poll carrying the queued messages
+    pub fn start(&mut self) -> Result<Poll> {
+        let body = serde_json::to_vec(&PollRequest {
+            session: self.session.as_deref(),
+            messages: &self.outgoing,
+        })?;
+        Ok(Poll {
+            poster: self.poster.clone(),
+            body,
+            sent: self.outgoing.len(),
+        })
+    }
+
+    /// Take in the answer to a poll: its messages are read next, and the
+    /// ones it carried are no longer queued
+    pub fn finish(&mut self, polled: Polled) -> Result<()> {
+        let (status, response) = match polled.exchanged {
+            Ok(exchanged) => exchanged,
+            Err(e) => {
+                metrics().inc_send_failures();
+                return Err(e);
+            }
+        };
+        self.last_poll = Some(Instant::now());
+
+        match status {
+            200 => {}
+            UPGRADE_REQUIRED => bail!(
+                "Gateway requires an agent upgrade: {}",
+                String::from_utf8_lossy(&response)
+            ),
+            _ => bail!(
+                "Poll failed ({}): {}",
+                status,
+                String::from_utf8_lossy(&response)
+            ),
+        }
+
+        let reply: PollResponse =
+            serde_json::from_slice(&response).context("Failed to parse poll response")?;
+        debug!(
+            sent = polled.sent,
+            received = reply.messages.len(),
+            "Polled Gateway"
+        );
+        self.outgoing.drain(..polled.sent.min(self.outgoing.len()));
+        self.session = Some(reply.session);
+
+        for message in reply.messages {
+            match serde_json::from_value(message) {
+                Ok(message) => self.incoming.push_back(message),
+                Err(e) => warn!(error = %e, "Ignoring unknown message from Gateway"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Poster {
+    /// POST to `/poll` over a fresh connection, returning status and body
+    async fn post(&self, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let stream = TcpStream::connect((self.endpoint.host.as_str(), self.endpoint.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.endpoint.host_header()))?;
+
+        // HTTP/1.0 keeps the response unchunked and ends it at close
+        let head = format!(
+            "POST /poll HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.endpoint.host_header(),
+            body.len()
+        );
+
+        match self.tls {
+            Some(ref tls) => {
+                let stream = tls
+                    .connect(&self.endpoint.host, stream)
+                    .await
+                    .context("TLS handshake with Gateway failed")?;
+                exchange(stream, head.as_bytes(), body).await
+            }
+            None => exchange(stream, head.as_bytes(), body).await,
+        }
+    }
+}
+
+async fn exchange<S>(mut stream: S, head: &[u8], body: &[u8]) -> Result<(u16, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(head).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        bail!("Poll response exceeds {} bytes", MAX_RESPONSE_BYTES);
+    }
+    parse_response(response)
+}
+
+/// Split an HTTP response into its status code and body
+fn parse_response(mut response: Vec<u8>) -> Result<(u16, Vec<u8>)> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("Malformed HTTP response from Gateway"))?;
+    let status = std::str::from_utf8(&response[..end])
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("Malformed HTTP status line from Gateway"))?;
+
+    Ok((status, response.split_off(end + 4)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            Endpoint::parse("wss://gateway.opsmap.local:443/ws").unwrap(),
+            Endpoint {
+                tls: true,
+                host: "gateway.opsmap.local".to_string(),
+                port: 443
+            }
+        );
+        let plain = Endpoint::parse("ws://10.0.0.5").unwrap();
+        assert!(!plain.tls);
+        assert_eq!(plain.port, 80);
+        let v6 = Endpoint::parse("wss://[fd00::1]:8443").unwrap();
+        assert_eq!(v6.host, "fd00::1");
+        assert_eq!(v6.host_header(), "[fd00::1]:8443");
+        assert!(Endpoint::parse("gateway:443").is_err());
+        assert!(Endpoint::parse("ftp://gateway").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let raw = b"HTTP/1.0 200 OK\r\ncontent-type: application/json\r\n\r\n{\"session\":\"s1\"}"
+            .to_vec();
+        let (status, body) = parse_response(raw).unwrap();
+        assert_eq!(status, 200);
+        let reply: PollResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply.session, "s1");
+        assert!(reply.messages.is_empty());
+
+        assert!(parse_response(b"garbage".to_vec()).is_err());
+    }
+}
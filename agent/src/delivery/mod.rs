@@ -0,0 +1,11 @@
+//! At-least-once delivery
+//!
+//! Status deltas are sent with a sequence number and kept until the Gateway
+//! acks them, which it does once the backend has them. Whatever is unacked
+//! when the connection drops is sent again after reconnecting. Acks are
+//! cumulative.
+
+#[path = "../../../shared/outbox.rs"]
+mod outbox;
+
+pub use outbox::Outbox;
@@ -0,0 +1,330 @@
+//! Support bundles
+//!
+//! A `diagnostics` command collects what is asked for first when an agent
+//! misbehaves in the field, as one JSON document:
+//!
+//! - `agent`: version, connection state, log level, snapshot size;
+//! - `config`: the running configuration, with secrets masked;
+//! - `checks`: the latest results, newest first (`params.checks`, 50);
+//! - `buffer`: offline buffer depth, expired and unacked messages;
+//! - `jobs`: recent commands with the tail of their logs (`params.jobs`,
+//!   10, and `params.log_bytes`, 64 KiB, of each);
+//! - `host`: OS, kernel, CPU and memory facts.
+//!
+//! With `params.upload_url` (a presigned URL) the bundle is gzipped and
+//! PUT there. Otherwise it goes over the Gateway connection as `diagnostics`
+//! messages of at most `params.chunk_bytes` (256 KiB) of its text each,
+//! which the Gateway forwards to the backend; joined in `index` order they
+//! make the bundle. The Gateway only accepts the `diagnostics` capability
+//! while its backend takes these messages; without it, the command fails
+//! and asks for an `upload_url`. The command's result tells which way it
+//! went.
+//!
+//! Everything in the bundle passes through `redact`, like command results.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::config::AgentConfig;
+use crate::connection::{Command, CommandResult, DiagnosticsChunk};
+use crate::{executor, facts, logging, redact, AgentState};
+
+/// Capability the Gateway must have accepted for bundles sent in chunks
+const CAPABILITY: &str = "diagnostics";
+
+const DEFAULT_CHECKS: usize = 50;
+const DEFAULT_JOBS: usize = 10;
+const DEFAULT_LOG_BYTES: u64 = 64 * 1024;
+const DEFAULT_CHUNK_BYTES: usize = 256 * 1024;
+
+/// Between chunks, to stay clear of the Gateway's rate limits
+const CHUNK_PAUSE: Duration = Duration::from_millis(100);
+
+/// Time allowed for an upload to a presigned URL
+#[cfg(feature = "http")]
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Host facts put in a bundle
+const HOST_FACTS: &[&str] = &[
+    "hostname",
+    "os",
+    "arch",
+    "os_name",
+    "os_version",
+    "kernel_version",
+    "cpu_count",
+    "memory_mb",
+];
+
+/// What goes in a bundle, from a command's params
+#[derive(Debug)]
+struct Options {
+    checks: usize,
+    jobs: usize,
+    log_bytes: u64,
+}
+
+impl Options {
+    fn from_params(params: &Value) -> Self {
+        let number = |name: &str| params.get(name).and_then(|v| v.as_u64());
+        Self {
+            checks: number("checks").map_or(DEFAULT_CHECKS, |n| n as usize),
+            jobs: number("jobs").map_or(DEFAULT_JOBS, |n| n as usize),
+            log_bytes: number("log_bytes").unwrap_or(DEFAULT_LOG_BYTES),
+        }
+    }
+}
+
+/// Collect a support bundle and upload or send it
+pub async fn run(state: &Arc<RwLock<AgentState>>, cmd: &Command) -> Result<CommandResult> {
+    let options = Options::from_params(&cmd.params);
+    let bundle = collect(&*state.read().await, &options);
+    let text = serde_json::to_string(&bundle)?;
+
+    let upload_url = cmd.params.get("upload_url").and_then(|v| v.as_str());
+    let summary = match upload_url {
+        Some(url) => upload(url, &text).await?,
+        None => {
+            let chunk_bytes = cmd
+                .params
+                .get("chunk_bytes")
+                .and_then(|v| v.as_u64())
+                .map_or(DEFAULT_CHUNK_BYTES, |n| n as usize);
+            send(state, &cmd.id, &text, chunk_bytes).await?
+        }
+    };
+    info!(command_id = %cmd.id, bytes = text.len(), "Support bundle delivered");
+
+    Ok(CommandResult {
+        exit_code: 0,
+        stdout: summary.to_string(),
+        stderr: String::new(),
+        duration_ms: 0,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+/// The bundle, redacted
+fn collect(state: &AgentState, options: &Options) -> Value {
+    let (components, checks) = state.scheduler.snapshot_size();
+    let mut results = state.scheduler.last_results();
+    results.sort_by_key(|result| std::cmp::Reverse(result.timestamp));
+    results.truncate(options.checks);
+
+    let jobs: Vec<Value> = executor::recent_jobs()
+        .into_iter()
+        .take(options.jobs)
+        .map(|job| {
+            let log = job
+                .log_file
+                .as_deref()
+                .and_then(|path| tail(path, options.log_bytes));
+            let mut job = json!(job);
+            job["log_tail"] = json!(log);
+            job
+        })
+        .collect();
+
+    let host: serde_json::Map<String, Value> = HOST_FACTS
+        .iter()
+        .filter_map(|name| match facts::get(name) {
+            Ok(Some(value)) => Some((name.to_string(), Value::String(value))),
+            _ => None,
+        })
+        .collect();
+
+    let mut bundle = json!({
+        "collected_at": chrono::Utc::now(),
+        "agent": {
+            "agent_id": state.config.agent.id,
+            "version": env!("CARGO_PKG_VERSION"),
+            "log_level": logging::level(),
+            "gateway_url": state.config.gateway.url,
+            "connected": state.is_connected,
+            "snapshot_version": state.scheduler.snapshot_version(),
+            "components": components,
+            "checks": checks,
+        },
+        "config": config(&state.config),
+        "checks": results,
+        "buffer": {
+            "depth": state.buffer.len(),
+            "expired": state.buffer.expired(),
+            "unacked": state.outbox.len(),
+        },
+        "jobs": jobs,
+        "host": host,
+    });
+    redact::value(&mut bundle);
+    bundle
+}
+
+/// The configuration with secret fields masked
+fn config(config: &AgentConfig) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    mask_secret_fields(&mut value);
+    value
+}
+
+fn mask_secret_fields(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (name, field) in map.iter_mut() {
+                if field.is_string() && redact::secret_name(name) {
+                    *field = Value::String(redact::MASK.to_string());
+                } else {
+                    mask_secret_fields(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(mask_secret_fields),
+        _ => {}
+    }
+}
+
+/// The last `max_bytes` of a file
+fn tail(path: &str, max_bytes: u64) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(max_bytes)))
+        .ok()?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).ok()?;
+    Some(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// PUT the gzipped bundle to a presigned URL
+#[cfg(feature = "http")]
+async fn upload(url: &str, text: &str) -> Result<Value> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes())?;
+    let body = encoder.finish()?;
+    let bytes = body.len();
+
+    // The URL's signature is a credential; keep it out of errors
+    let response = reqwest::Client::builder()
+        .timeout(UPLOAD_TIMEOUT)
+        .build()?
+        .put(url)
+        .header("content-type", "application/gzip")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Bundle upload failed: {}", e.without_url()))?;
+    if !response.status().is_success() {
+        bail!("Bundle upload refused: HTTP {}", response.status());
+    }
+    Ok(json!({ "uploaded": true, "bytes": bytes }))
+}
+
+#[cfg(not(feature = "http"))]
+async fn upload(_url: &str, _text: &str) -> Result<Value> {
+    bail!("Agent was built without HTTP support; leave out upload_url to send the bundle to the Gateway")
+}
+
+/// Send the bundle to the Gateway in chunks
+async fn send(
+    state: &Arc<RwLock<AgentState>>,
+    job_id: &str,
+    text: &str,
+    chunk_bytes: usize,
+) -> Result<Value> {
+    let chunks = split(text, chunk_bytes);
+    let total = chunks.len();
+
+    for (index, data) in chunks.into_iter().enumerate() {
+        let mut state = state.write().await;
+        let agent_id = state.config.agent.id.clone();
+        let conn = match state.connection {
+            Some(ref mut conn) if conn.supports(CAPABILITY) => conn,
+            Some(_) => bail!("Gateway does not take diagnostics messages; give an upload_url"),
+            None => bail!("Not connected to the Gateway"),
+        };
+        let chunk = DiagnosticsChunk {
+            job_id: job_id.to_string(),
+            agent_id,
+            index,
+            total,
+            data: data.to_string(),
+        };
+        conn.send_diagnostics(chunk).await?;
+        drop(state);
+
+        if index + 1 < total {
+            tokio::time::sleep(CHUNK_PAUSE).await;
+        }
+    }
+    Ok(json!({ "bytes": text.len(), "chunks": total }))
+}
+
+/// `text` in parts of at most `max_bytes`, split between characters
+fn split(text: &str, max_bytes: usize) -> Vec<&str> {
+    // Room for any character
+    let max_bytes = max_bytes.max(4);
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = max_bytes.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, remainder) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = remainder;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let text = "héllo wörld";
+        let chunks = split(text, 5);
+        assert!(chunks.iter().all(|c| c.len() <= 5));
+        assert_eq!(chunks.concat(), text);
+        assert_eq!(split("", 5).len(), 0);
+    }
+
+    #[test]
+    fn test_collect_masks_secrets() {
+        let mut config = AgentConfig::default();
+        config.enrollment.token = Some("enroll-1234".to_string());
+        let state = AgentState::new(config);
+
+        let bundle = collect(&state, &Options::from_params(&json!({})));
+        assert_eq!(bundle["config"]["enrollment"]["token"], redact::MASK);
+        assert_eq!(bundle["agent"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(bundle["buffer"]["depth"], 0);
+        assert!(bundle["checks"].as_array().unwrap().is_empty());
+        assert!(!bundle.to_string().contains("enroll-1234"));
+    }
+
+    #[test]
+    fn test_mask_secret_fields() {
+        let mut value = json!({
+            "headers": {"X-Token": "t0k3n", "Accept": "text/plain"},
+            "auth": [{"api_key": "k3y", "DB_PASSWORD": "s3cret", "user": "app"}],
+        });
+        mask_secret_fields(&mut value);
+        assert_eq!(value["headers"]["X-Token"], redact::MASK);
+        assert_eq!(value["headers"]["Accept"], "text/plain");
+        assert_eq!(value["auth"][0]["api_key"], redact::MASK);
+        assert_eq!(value["auth"][0]["DB_PASSWORD"], redact::MASK);
+        assert_eq!(value["auth"][0]["user"], "app");
+    }
+}
@@ -0,0 +1,502 @@
+//! Service auto-discovery
+//!
+//! Periodically scans the host for listening TCP sockets, running systemd
+//! services and docker containers, and reports them to the Gateway together
+//! with suggested checks, so onboarding a host doesn't require hand-writing
+//! every check.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Command;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
+use tracing::{debug, info, warn};
+
+use crate::connection::CheckDefinition;
+use crate::AgentState;
+
+/// Result of a discovery scan
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryReport {
+    pub listeners: Vec<Listener>,
+    pub services: Vec<SystemdService>,
+    pub containers: Vec<Container>,
+    pub suggested_checks: Vec<SuggestedCheck>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// A listening TCP socket
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Listener {
+    pub address: IpAddr,
+    pub port: u16,
+    pub pid: Option<u32>,
+    pub process: Option<String>,
+}
+
+/// A running systemd service unit
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SystemdService {
+    pub unit: String,
+    pub description: String,
+}
+
+/// A running docker container
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Container {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub status: String,
+    pub ports: String,
+}
+
+/// A check proposed for something that was discovered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuggestedCheck {
+    /// What the suggestion is based on, e.g. "listener:5432" or "systemd:nginx.service"
+    pub source: String,
+    pub check: CheckDefinition,
+}
+
+/// Run discovery scans on the configured interval and send reports
+pub async fn run(state: Arc<RwLock<AgentState>>) {
+    let settings = state.read().await.config.discovery.clone();
+    if !settings.enabled {
+        debug!("Service discovery disabled");
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_secs(settings.interval_secs.max(60)));
+
+    loop {
+        ticker.tick().await;
+
+        let report = match tokio::task::spawn_blocking(scan).await {
+            Ok(report) => report,
+            Err(e) => {
+                warn!(error = %e, "Discovery scan failed");
+                continue;
+            }
+        };
+
+        info!(
+            listeners = report.listeners.len(),
+            services = report.services.len(),
+            containers = report.containers.len(),
+            suggestions = report.suggested_checks.len(),
+            "Discovery scan complete"
+        );
+
+        let mut state = state.write().await;
+        if let Some(ref mut conn) = state.connection {
+            if let Err(e) = conn.send_discovery(report).await {
+                warn!(error = %e, "Failed to send discovery report");
+            }
+        }
+    }
+}
+
+/// Scan the host; sources that are unavailable are skipped
+pub fn scan() -> DiscoveryReport {
+    let listeners = scan_listeners().unwrap_or_else(|e| {
+        debug!(error = %e, "Listening socket scan unavailable");
+        Vec::new()
+    });
+    let services = scan_systemd().unwrap_or_else(|e| {
+        debug!(error = %e, "systemd scan unavailable");
+        Vec::new()
+    });
+    #[cfg(feature = "docker")]
+    let containers = scan_docker().unwrap_or_else(|e| {
+        debug!(error = %e, "docker scan unavailable");
+        Vec::new()
+    });
+    #[cfg(not(feature = "docker"))]
+    let containers = Vec::new();
+
+    let suggested_checks = suggest_checks(&listeners, &services, &containers);
+
+    DiscoveryReport {
+        listeners,
+        services,
+        containers,
+        suggested_checks,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+/// Read listening TCP sockets from /proc/net/tcp and /proc/net/tcp6
+fn scan_listeners() -> Result<Vec<Listener>> {
+    let owners = socket_owners();
+    let mut listeners = Vec::new();
+
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) if path.ends_with('6') => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path)),
+        };
+
+        for (address, port, inode) in parse_proc_net_tcp(&content) {
+            let owner = owners.get(&inode);
+            listeners.push(Listener {
+                address,
+                port,
+                pid: owner.map(|(pid, _)| *pid),
+                process: owner.map(|(_, name)| name.clone()),
+            });
+        }
+    }
+
+    listeners.sort_by_key(|l| (l.port, l.address));
+    listeners.dedup_by(|a, b| a.port == b.port && a.address == b.address);
+    Ok(listeners)
+}
+
+/// Parse LISTEN entries from a /proc/net/tcp{,6} table into (address, port, inode)
+fn parse_proc_net_tcp(content: &str) -> Vec<(IpAddr, u16, u64)> {
+    const TCP_LISTEN: &str = "0A";
+
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != TCP_LISTEN {
+                return None;
+            }
+
+            let (addr_hex, port_hex) = fields[1].split_once(':')?;
+            let port = u16::from_str_radix(port_hex, 16).ok()?;
+            let address = parse_hex_address(addr_hex)?;
+            let inode = fields[9].parse().ok()?;
+            Some((address, port, inode))
+        })
+        .collect()
+}
+
+/// Decode a kernel hex address (host byte order per 32-bit word)
+fn parse_hex_address(hex: &str) -> Option<IpAddr> {
+    match hex.len() {
+        8 => {
+            let value = u32::from_str_radix(hex, 16).ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(value.to_le_bytes())))
+        }
+        32 => {
+            let mut octets = [0u8; 16];
+            for i in 0..4 {
+                let value = u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16).ok()?;
+                octets[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// Map socket inodes to the (pid, process name) owning them
+///
+/// Best effort: without privileges only the agent's own processes are visible.
+fn socket_owners() -> HashMap<u64, (u32, String)> {
+    let mut owners = HashMap::new();
+
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return owners,
+    };
+
+    for entry in entries.flatten() {
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+
+        let fds = match std::fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+
+        let name = std::fs::read_to_string(entry.path().join("comm"))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+
+        for fd in fds.flatten() {
+            let target = match std::fs::read_link(fd.path()) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            let target = target.to_string_lossy();
+            if let Some(inode) = target
+                .strip_prefix("socket:[")
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|inode| inode.parse().ok())
+            {
+                owners.insert(inode, (pid, name.clone()));
+            }
+        }
+    }
+
+    owners
+}
+
+/// List running systemd services
+fn scan_systemd() -> Result<Vec<SystemdService>> {
+    let output = Command::new("systemctl")
+        .args([
+            "list-units",
+            "--type=service",
+            "--state=running",
+            "--no-legend",
+            "--plain",
+            "--no-pager",
+        ])
+        .output()
+        .context("Failed to run systemctl")?;
+
+    Ok(parse_systemd_units(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse `systemctl list-units --plain --no-legend` output
+fn parse_systemd_units(output: &str) -> Vec<SystemdService> {
+    output
+        .lines()
+        .filter_map(|line| {
+            // UNIT LOAD ACTIVE SUB DESCRIPTION...
+            let mut fields = line.split_whitespace();
+            let unit = fields.next()?;
+            if !unit.ends_with(".service") {
+                return None;
+            }
+            let description = fields.skip(3).collect::<Vec<_>>().join(" ");
+            Some(SystemdService {
+                unit: unit.to_string(),
+                description,
+            })
+        })
+        .collect()
+}
+
+/// List running docker containers
+#[cfg(feature = "docker")]
+fn scan_docker() -> Result<Vec<Container>> {
+    let output = Command::new("docker")
+        .args(["ps", "--no-trunc", "--format", "{{json .}}"])
+        .output()
+        .context("Failed to run docker")?;
+
+    Ok(parse_docker_ps(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `docker ps --format '{{json .}}'` output (one JSON object per line)
+#[cfg(feature = "docker")]
+fn parse_docker_ps(output: &str) -> Vec<Container> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .map(|entry| {
+            let field = |key: &str| {
+                entry
+                    .get(key)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            Container {
+                id: field("ID"),
+                name: field("Names"),
+                image: field("Image"),
+                status: field("Status"),
+                ports: field("Ports"),
+            }
+        })
+        .collect()
+}
+
+/// Propose checks for what was discovered
+fn suggest_checks(
+    listeners: &[Listener],
+    services: &[SystemdService],
+    containers: &[Container],
+) -> Vec<SuggestedCheck> {
+    let mut suggestions = Vec::new();
+
+    // One suggestion per port, preferring a specific bind address over a wildcard
+    let mut ports: BTreeMap<u16, &Listener> = BTreeMap::new();
+    for listener in listeners {
+        ports.entry(listener.port).or_insert(listener);
+    }
+
+    for (port, listener) in ports {
+        let host = if listener.address.is_unspecified() {
+            "127.0.0.1".to_string()
+        } else if listener.address.is_ipv6() {
+            format!("[{}]", listener.address)
+        } else {
+            listener.address.to_string()
+        };
+        let source = format!("listener:{}", port);
+
+        let scheme = match port {
+            80 | 8000 | 8080 => Some("http"),
+            443 | 8443 => Some("https"),
+            _ => None,
+        };
+
+        let check = match scheme {
+            Some(scheme) => suggested(
+                format!("http_{}", port),
+                "http",
+                json!({ "url": format!("{}://{}:{}/", scheme, host, port) }),
+            ),
+            None => suggested(
+                format!("tcp_{}", port),
+                "tcp_port",
+                json!({ "host": host, "port": port }),
+            ),
+        };
+        suggestions.push(SuggestedCheck { source, check });
+
+        if let Some(ref process) = listener.process {
+            suggestions.push(SuggestedCheck {
+                source: format!("listener:{}", port),
+                check: suggested(
+                    format!("process_{}", process),
+                    "process",
+                    json!({ "name": process }),
+                ),
+            });
+        }
+    }
+
+    for service in services {
+        let name = service.unit.trim_end_matches(".service");
+        suggestions.push(SuggestedCheck {
+            source: format!("systemd:{}", service.unit),
+            check: suggested(
+                format!("systemd_{}", name),
+                "shell:systemd",
+                json!({ "command": format!("systemctl is-active --quiet {}", service.unit) }),
+            ),
+        });
+    }
+
+    for container in containers {
+        suggestions.push(SuggestedCheck {
+            source: format!("docker:{}", container.name),
+            check: suggested(
+                format!("docker_{}", container.name),
+                "shell:docker",
+                json!({
+                    "command": format!(
+                        "[ \"$(docker inspect -f '{{{{.State.Running}}}}' {})\" = true ]",
+                        container.name
+                    )
+                }),
+            ),
+        });
+    }
+
+    let mut seen = HashSet::new();
+    suggestions.retain(|s| seen.insert(s.check.name.clone()));
+    suggestions
+}
+
+fn suggested(name: String, check_type: &str, config: serde_json::Value) -> CheckDefinition {
+    CheckDefinition {
+        name,
+        check_type: check_type.to_string(),
+        config,
+        interval_secs: 60,
+        timeout_secs: 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_net_tcp() {
+        let content = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000   999        0 31245 1 0000000000000000 100 0 0 10 0
+   1: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:1538 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000   999        0 0 1 0000000000000000 20 4 30 10 -1";
+
+        let entries = parse_proc_net_tcp(content);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ("127.0.0.1".parse().unwrap(), 5432, 31245));
+        assert_eq!(entries[1], ("0.0.0.0".parse().unwrap(), 22, 1234));
+    }
+
+    #[test]
+    fn test_parse_ipv6_address() {
+        assert_eq!(
+            parse_hex_address("00000000000000000000000001000000"),
+            Some("::1".parse().unwrap())
+        );
+        assert_eq!(
+            parse_hex_address("00000000000000000000000000000000"),
+            Some("::".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_systemd_units() {
+        let output = "nginx.service  loaded active running A high performance web server\n\
+                      ssh.service    loaded active running OpenBSD Secure Shell server\n";
+        let services = parse_systemd_units(output);
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].unit, "nginx.service");
+        assert_eq!(services[0].description, "A high performance web server");
+    }
+
+    #[cfg(feature = "docker")]
+    #[test]
+    fn test_parse_docker_ps() {
+        let output = r#"{"ID":"abc123","Image":"redis:7","Names":"cache","Ports":"0.0.0.0:6379->6379/tcp","Status":"Up 2 hours"}"#;
+        let containers = parse_docker_ps(output);
+        assert_eq!(containers.len(), 1);
+        assert_eq!(containers[0].name, "cache");
+        assert_eq!(containers[0].image, "redis:7");
+    }
+
+    #[test]
+    fn test_suggest_checks() {
+        let listeners = vec![
+            Listener {
+                address: "0.0.0.0".parse().unwrap(),
+                port: 80,
+                pid: Some(10),
+                process: Some("nginx".to_string()),
+            },
+            Listener {
+                address: "127.0.0.1".parse().unwrap(),
+                port: 5432,
+                pid: None,
+                process: None,
+            },
+        ];
+        let services = vec![SystemdService {
+            unit: "nginx.service".to_string(),
+            description: String::new(),
+        }];
+
+        let suggestions = suggest_checks(&listeners, &services, &[]);
+        let names: Vec<&str> = suggestions.iter().map(|s| s.check.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["http_80", "process_nginx", "tcp_5432", "systemd_nginx"]
+        );
+
+        assert_eq!(suggestions[0].check.config["url"], "http://127.0.0.1:80/");
+        assert_eq!(suggestions[2].check.check_type, "tcp_port");
+        assert_eq!(suggestions[2].check.config["port"], 5432);
+    }
+}
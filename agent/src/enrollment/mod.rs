@@ -0,0 +1,221 @@
+//! Agent enrollment
+//!
+//! Bootstrap mode for new agents: with only an enrollment token configured
+//! and no client certificate on disk yet, the agent generates a keypair,
+//! submits a CSR to the Gateway's `/enroll` endpoint and stores the issued
+//! certificate, the CA certificate and its assigned agent ID.
+
+use anyhow::{anyhow, Context, Result};
+use rcgen::{CertificateParams, DnType, KeyPair};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tracing::info;
+
+use crate::config::AgentConfig;
+
+#[derive(Debug, Serialize)]
+struct EnrollRequest<'a> {
+    token: &'a str,
+    csr: String,
+    hostname: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct EnrollResponse {
+    agent_id: String,
+    certificate: String,
+    ca_certificate: String,
+}
+
+/// Whether the agent has a token but no client certificate yet
+pub fn needed(config: &AgentConfig) -> bool {
+    let missing = |file: &Option<String>| file.as_ref().is_some_and(|f| !Path::new(f).exists());
+
+    config.enrollment.token.is_some()
+        && config.tls.enabled
+        && (missing(&config.tls.cert_file) || missing(&config.tls.key_file))
+}
+
+/// Use the agent ID assigned at enrollment when none is configured
+pub fn load_agent_id(config: &mut AgentConfig) {
+    if !(config.agent.id.is_empty() || config.agent.id == "auto") {
+        return;
+    }
+    if let Ok(id) = std::fs::read_to_string(&config.enrollment.id_file) {
+        let id = id.trim();
+        if !id.is_empty() {
+            config.agent.id = id.to_string();
+        }
+    }
+}
+
+/// Enroll with the Gateway and write the issued identity to disk
+pub async fn enroll(config: &mut AgentConfig) -> Result<()> {
+    let token = config
+        .enrollment
+        .token
+        .clone()
+        .ok_or_else(|| anyhow!("enrollment.token is not set"))?;
+    let (cert_file, key_file) = match (&config.tls.cert_file, &config.tls.key_file) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        _ => anyhow::bail!("tls.cert_file and tls.key_file are required for enrollment"),
+    };
+
+    let hostname = config.agent.hostname.clone().unwrap_or_else(|| {
+        hostname::get()
+            .map(|h| h.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "unknown".to_string())
+    });
+    let url = enrollment_url(config)?;
+    info!(url = %url, hostname = %hostname, "Enrolling with Gateway");
+
+    // Generate the keypair locally; only the CSR leaves the host
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![hostname.clone()])?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, hostname.as_str());
+    let csr = params.serialize_request(&key)?.pem()?;
+
+    let response = http_client(config)?
+        .post(url)
+        .json(&EnrollRequest {
+            token: &token,
+            csr,
+            hostname: &hostname,
+        })
+        .send()
+        .await
+        .context("Failed to reach the Gateway enrollment endpoint")?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Enrollment refused ({}): {}", status, body);
+    }
+    let issued: EnrollResponse = response.json().await?;
+
+    write_file(&key_file, &key.serialize_pem(), 0o600)?;
+    write_file(&cert_file, &issued.certificate, 0o644)?;
+    if let Some(ref ca_file) = config.tls.ca_file {
+        if !Path::new(ca_file).exists() {
+            write_file(ca_file, &issued.ca_certificate, 0o644)?;
+        }
+    }
+    write_file(
+        &config.enrollment.id_file,
+        &format!("{}\n", issued.agent_id),
+        0o644,
+    )?;
+
+    info!(agent_id = %issued.agent_id, "Enrollment complete");
+    config.agent.id = issued.agent_id;
+    Ok(())
+}
+
+/// The configured enrollment URL, or `/enroll` on the Gateway's host
+fn enrollment_url(config: &AgentConfig) -> Result<reqwest::Url> {
+    if let Some(ref url) = config.enrollment.url {
+        return Ok(reqwest::Url::parse(url)?);
+    }
+
+    let mut url = reqwest::Url::parse(&config.gateway.url)
+        .with_context(|| format!("Invalid gateway.url: {}", config.gateway.url))?;
+    let scheme = if url.scheme() == "ws" {
+        "http"
+    } else {
+        "https"
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("Cannot derive enrollment URL from {}", config.gateway.url))?;
+    url.set_path("/enroll");
+    url.set_query(None);
+    Ok(url)
+}
+
+fn http_client(config: &AgentConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().timeout(std::time::Duration::from_secs(30));
+
+    // The CA may be provisioned along with the token
+    if let Some(ref ca_file) = config.tls.ca_file {
+        if let Ok(pem) = std::fs::read(ca_file) {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+    }
+    if !config.tls.verify_server {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn write_file(path: &str, content: &str, mode: u32) -> Result<()> {
+    if let Some(parent) = Path::new(path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(path, content).with_context(|| format!("Failed to write {}", path))?;
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    // On Windows the file inherits the ACL of its directory
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enrollment_url() {
+        let mut config = AgentConfig::default();
+        config.gateway.url = "wss://gateway.example.com:8443/ws?zone=a".to_string();
+        assert_eq!(
+            enrollment_url(&config).unwrap().as_str(),
+            "https://gateway.example.com:8443/enroll"
+        );
+
+        config.gateway.url = "ws://127.0.0.1:8080/ws".to_string();
+        assert_eq!(
+            enrollment_url(&config).unwrap().as_str(),
+            "http://127.0.0.1:8080/enroll"
+        );
+
+        config.enrollment.url = Some("https://enroll.example.com/enroll".to_string());
+        assert_eq!(
+            enrollment_url(&config).unwrap().as_str(),
+            "https://enroll.example.com/enroll"
+        );
+    }
+
+    #[test]
+    fn test_needed_and_assigned_id() {
+        let dir = std::env::temp_dir().join(format!("opsmap-enroll-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = AgentConfig::default();
+        config.tls.cert_file = Some(dir.join("agent.crt").to_string_lossy().to_string());
+        config.tls.key_file = Some(dir.join("agent.key").to_string_lossy().to_string());
+        config.enrollment.id_file = dir.join("agent_id").to_string_lossy().to_string();
+        assert!(!needed(&config));
+
+        config.enrollment.token = Some("token".to_string());
+        assert!(needed(&config));
+
+        std::fs::write(dir.join("agent.crt"), "cert").unwrap();
+        std::fs::write(dir.join("agent.key"), "key").unwrap();
+        assert!(!needed(&config));
+
+        std::fs::write(dir.join("agent_id"), "web-01-1a2b3c4d\n").unwrap();
+        load_agent_id(&mut config);
+        assert_eq!(config.agent.id, "web-01-1a2b3c4d");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
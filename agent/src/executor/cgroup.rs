@@ -0,0 +1,206 @@
+//! Resource limits for detached jobs
+//!
+//! A job with limits runs in a transient systemd scope (`systemd-run --scope
+//! -p CPUQuota=...`) or, without systemd, in its own cgroup v2 directory
+//! under `jobs.cgroup_root`: the agent creates it with `cpu.max`,
+//! `memory.max` and `pids.max` set and the job joins it before exec. A
+//! runaway action then hits its own limits, and the OOM killer picks from
+//! the job only, instead of the host it is meant to heal.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{JobLimits, JobSettings};
+
+/// Kernel default CFS period, in microseconds
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// How a detached job is held to its limits
+#[derive(Debug)]
+pub enum Confinement {
+    None,
+    /// Exec the command through `systemd-run` with these arguments
+    Scope(Vec<String>),
+    /// Join this cgroup before exec
+    Cgroup(PathBuf),
+}
+
+/// Set up the confinement for a job, in the agent before forking
+pub fn confine(
+    limits: &JobLimits,
+    settings: &JobSettings,
+    job_id: &str,
+    run_as_user: Option<&str>,
+) -> Result<Confinement> {
+    if limits.is_empty() {
+        return Ok(Confinement::None);
+    }
+    if !cfg!(target_os = "linux") {
+        bail!("Job resource limits need Linux with cgroup v2");
+    }
+    if limits.cpu_percent == Some(0) || limits.memory_mb == Some(0) || limits.pids_max == Some(0) {
+        bail!("Job resource limits must be greater than 0");
+    }
+
+    let manager = match settings.cgroup_manager.as_str() {
+        "auto" if Path::new("/run/systemd/system").is_dir() => "systemd",
+        "auto" => "cgroupfs",
+        manager => manager,
+    };
+    match manager {
+        "systemd" => Ok(Confinement::Scope(scope_args(limits, job_id, run_as_user))),
+        "cgroupfs" => {
+            create_cgroup(limits, Path::new(&settings.cgroup_root), job_id).map(Confinement::Cgroup)
+        }
+        manager => Err(anyhow!("Unknown cgroup manager: {}", manager)),
+    }
+}
+
+/// `systemd-run` arguments for a scope named after the job. systemd-run
+/// switches to `run_as_user` itself, after moving into the scope.
+fn scope_args(limits: &JobLimits, job_id: &str, run_as_user: Option<&str>) -> Vec<String> {
+    let mut args = vec![
+        "systemd-run".to_string(),
+        "--scope".to_string(),
+        "--quiet".to_string(),
+        "--collect".to_string(),
+        format!("--unit=opsmap-job-{}", job_id),
+    ];
+    if let Some(user) = run_as_user {
+        args.push(format!("--uid={}", user));
+    }
+    if let Some(cpu) = limits.cpu_percent {
+        args.push(format!("--property=CPUQuota={}%", cpu));
+    }
+    if let Some(memory) = limits.memory_mb {
+        args.push(format!("--property=MemoryMax={}M", memory));
+    }
+    if let Some(pids) = limits.pids_max {
+        args.push(format!("--property=TasksMax={}", pids));
+    }
+    args.push("--".to_string());
+    args
+}
+
+/// Controller files and values for the limits that are set
+fn cgroup_files(limits: &JobLimits) -> Vec<(&'static str, String)> {
+    let mut files = Vec::new();
+    if let Some(cpu) = limits.cpu_percent {
+        let quota = u64::from(cpu) * CPU_PERIOD_USEC / 100;
+        files.push(("cpu.max", format!("{} {}", quota, CPU_PERIOD_USEC)));
+    }
+    if let Some(memory) = limits.memory_mb {
+        files.push(("memory.max", (memory * 1024 * 1024).to_string()));
+    }
+    if let Some(pids) = limits.pids_max {
+        files.push(("pids.max", pids.to_string()));
+    }
+    files
+}
+
+/// Create the job's cgroup under `root`, with its limits written
+fn create_cgroup(limits: &JobLimits, root: &Path, job_id: &str) -> Result<PathBuf> {
+    let parent = root
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid cgroup root: {}", root.display()))?;
+    if !parent.join("cgroup.controllers").is_file() {
+        bail!("{} is not in a cgroup v2 hierarchy", parent.display());
+    }
+    fs::create_dir_all(root)
+        .with_context(|| format!("Failed to create cgroup {}", root.display()))?;
+
+    // Controllers must be enabled for children all the way down
+    let files = cgroup_files(limits);
+    let controllers = files
+        .iter()
+        .map(|(file, _)| format!("+{}", file.split('.').next().unwrap_or_default()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    for dir in [parent, root] {
+        fs::write(dir.join("cgroup.subtree_control"), &controllers)
+            .with_context(|| format!("Failed to enable {} in {}", controllers, dir.display()))?;
+    }
+
+    remove_finished(root);
+
+    let dir = root.join(format!("job-{}", job_id));
+    fs::create_dir(&dir).with_context(|| format!("Failed to create cgroup {}", dir.display()))?;
+    for (file, value) in files {
+        fs::write(dir.join(file), &value)
+            .with_context(|| format!("Failed to set {} to {}", file, value))?;
+    }
+    Ok(dir)
+}
+
+/// Remove the cgroups of jobs that have exited; the kernel refuses to
+/// remove those still holding processes
+fn remove_finished(root: &Path) {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with("job-") {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+}
+
+/// Move the calling process into a job cgroup; runs in the forked job
+pub fn join(dir: &Path) -> std::io::Result<()> {
+    fs::write(dir.join("cgroup.procs"), std::process::id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> JobLimits {
+        JobLimits {
+            cpu_percent: Some(50),
+            memory_mb: Some(256),
+            pids_max: None,
+        }
+    }
+
+    #[test]
+    fn test_scope_args() {
+        let args = scope_args(&limits(), "42", Some("app"));
+        assert_eq!(args[0], "systemd-run");
+        assert!(args.contains(&"--unit=opsmap-job-42".to_string()));
+        assert!(args.contains(&"--uid=app".to_string()));
+        assert!(args.contains(&"--property=CPUQuota=50%".to_string()));
+        assert!(args.contains(&"--property=MemoryMax=256M".to_string()));
+        assert!(!args.iter().any(|a| a.contains("TasksMax")));
+        assert_eq!(args.last().unwrap(), "--");
+    }
+
+    #[test]
+    fn test_create_cgroup() {
+        let base = std::env::temp_dir().join(format!("opsmap-cgroup-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("cgroup.controllers"), "cpu memory pids").unwrap();
+        let root = base.join("opsmap");
+
+        let dir = create_cgroup(&limits(), &root, "42").unwrap();
+        assert_eq!(dir, root.join("job-42"));
+        assert_eq!(
+            fs::read_to_string(dir.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("memory.max")).unwrap(),
+            "268435456"
+        );
+        assert!(!dir.join("pids.max").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("cgroup.subtree_control")).unwrap(),
+            "+cpu +memory"
+        );
+
+        // Outside a cgroup v2 hierarchy
+        assert!(create_cgroup(&limits(), &dir.join("nested"), "43").is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Container targets
+//!
+//! A command with `params.container` runs inside that container, as
+//! `docker exec` would run it, or `podman exec` with `params.container_runtime`
+//! (`jobs.container_runtime` by default). The runtime's client is run
+//! directly with its arguments, no host shell involved; the command line
+//! goes to `sh -c` in the container. `run_as_user` and `cwd` apply inside
+//! it, and env variables are handed over by name only, so their values,
+//! secrets included, never appear on a command line.
+
+use anyhow::{anyhow, bail, Result};
+
+use super::ActionEnv;
+use crate::config::JobSettings;
+
+/// Runtimes whose `exec` takes docker's options
+pub const RUNTIMES: &[&str] = &["docker", "podman"];
+
+/// A container a command runs in
+#[derive(Debug)]
+pub struct Container {
+    runtime: String,
+    name: String,
+}
+
+impl Container {
+    /// The container named in `params`, if any
+    pub fn from_params(params: &serde_json::Value, jobs: &JobSettings) -> Result<Option<Self>> {
+        let name = match params.get("container").and_then(|v| v.as_str()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        // Names as docker and podman accept them; not an option either
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if name.is_empty() || !valid || name.starts_with('-') {
+            bail!("Invalid container name: {}", name);
+        }
+        let runtime = params
+            .get("container_runtime")
+            .and_then(|v| v.as_str())
+            .unwrap_or(&jobs.container_runtime);
+        if !RUNTIMES.contains(&runtime) {
+            return Err(anyhow!("Unknown container runtime: {}", runtime));
+        }
+        if cfg!(windows) {
+            bail!("Container targets are not supported on Windows");
+        }
+        // It would only reach the runtime's client, not the container
+        if params.get("priority").is_some_and(|p| !p.is_null()) {
+            bail!("Process priority does not apply to container targets");
+        }
+
+        Ok(Some(Self {
+            runtime: runtime.to_string(),
+            name: name.to_string(),
+        }))
+    }
+
+    /// The host argv running `command_line` in the container. Option
+    /// values are attached with `=`, so none can pass for another option.
+    /// `cwd` moves into the container, so `env` keeps only the variables.
+    pub fn argv(
+        &self,
+        command_line: &str,
+        run_as_user: Option<&str>,
+        env: &mut ActionEnv,
+    ) -> Vec<String> {
+        let mut argv = vec![self.runtime.clone(), "exec".to_string()];
+        if let Some(user) = run_as_user {
+            argv.push(format!("--user={}", user));
+        }
+        if let Some(cwd) = env.cwd.take() {
+            argv.push(format!("--workdir={}", cwd));
+        }
+        let mut names: Vec<&String> = env.vars.keys().collect();
+        names.sort();
+        for name in names {
+            argv.push(format!("--env={}", name));
+        }
+        argv.extend([
+            self.name.clone(),
+            "sh".to_string(),
+            "-c".to_string(),
+            command_line.to_string(),
+        ]);
+        argv
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_argv() {
+        let jobs = JobSettings::default();
+        assert!(Container::from_params(&serde_json::json!({}), &jobs)
+            .unwrap()
+            .is_none());
+        for params in [
+            serde_json::json!({"container": "api; rm -rf /"}),
+            serde_json::json!({"container": "--privileged"}),
+            serde_json::json!({"container": "api", "container_runtime": "lxc"}),
+            serde_json::json!({"container": "api", "priority": {"nice": 10}}),
+        ] {
+            assert!(
+                Container::from_params(&params, &jobs).is_err(),
+                "{}",
+                params
+            );
+        }
+
+        let params = serde_json::json!({"container": "api", "container_runtime": "podman"});
+        let container = Container::from_params(&params, &jobs).unwrap().unwrap();
+        let mut env = ActionEnv {
+            vars: [("TOKEN".to_string(), "s3cret".to_string())].into(),
+            cwd: Some("/srv/api".to_string()),
+            ..Default::default()
+        };
+        let argv = container.argv("echo 'it''s' $TOKEN", Some("app; reboot"), &mut env);
+        assert_eq!(
+            argv,
+            [
+                "podman",
+                "exec",
+                "--user=app; reboot",
+                "--workdir=/srv/api",
+                "--env=TOKEN",
+                "api",
+                "sh",
+                "-c",
+                "echo 'it''s' $TOKEN",
+            ]
+        );
+        assert!(env.cwd.is_none());
+        assert!(argv.iter().all(|arg| !arg.contains("s3cret")));
+    }
+}
@@ -0,0 +1,145 @@
+//! Duplicate command detection
+//!
+//! The Gateway retries commands it could not confirm, e.g. after a
+//! reconnect, so the agent can receive the same command twice. Command ids
+//! are remembered for `jobs.dedup_ttl_secs` with the final response once
+//! there is one: a duplicate is answered with that response, or ignored
+//! while the original is still running, instead of executing again.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::connection::CommandResponse;
+
+/// What is known about a command id
+#[derive(Debug)]
+pub enum Duplicate {
+    /// First time seen; now recorded as in progress
+    New,
+    /// Still executing or queued
+    InProgress,
+    /// Finished, with the response that was sent
+    Completed(Box<CommandResponse>),
+}
+
+struct Entry {
+    seen_at: Instant,
+    response: Option<CommandResponse>,
+}
+
+#[derive(Default)]
+struct Seen {
+    entries: HashMap<String, Entry>,
+}
+
+static SEEN: Mutex<Option<Seen>> = Mutex::new(None);
+
+impl Seen {
+    fn check(&mut self, command_id: &str, ttl: Duration) -> Duplicate {
+        let now = Instant::now();
+        self.entries
+            .retain(|_, entry| now.duration_since(entry.seen_at) < ttl);
+
+        match self.entries.get(command_id) {
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => Duplicate::Completed(Box::new(response.clone())),
+            Some(_) => Duplicate::InProgress,
+            None => {
+                self.entries.insert(
+                    command_id.to_string(),
+                    Entry {
+                        seen_at: now,
+                        response: None,
+                    },
+                );
+                Duplicate::New
+            }
+        }
+    }
+
+    fn forget(&mut self, command_id: &str) {
+        self.entries.remove(command_id);
+    }
+
+    fn remember(&mut self, response: &CommandResponse) {
+        if let Some(entry) = self.entries.get_mut(&response.job_id) {
+            entry.response = Some(response.clone());
+        }
+    }
+}
+
+/// Look a command up before executing it; a ttl of 0 disables the check
+pub fn check_duplicate(command_id: &str, ttl_secs: u64) -> Duplicate {
+    if ttl_secs == 0 {
+        return Duplicate::New;
+    }
+    SEEN.lock()
+        .unwrap()
+        .get_or_insert_with(Seen::default)
+        .check(command_id, Duration::from_secs(ttl_secs))
+}
+
+/// Forget a command that was checked but never started, so that its
+/// redelivery runs it
+pub fn forget_command(command_id: &str) {
+    if let Some(seen) = SEEN.lock().unwrap().as_mut() {
+        seen.forget(command_id);
+    }
+}
+
+/// Keep the final response of a command, to answer duplicates with
+pub fn remember_response(response: &CommandResponse) {
+    if let Some(seen) = SEEN.lock().unwrap().as_mut() {
+        seen.remember(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(job_id: &str) -> CommandResponse {
+        CommandResponse {
+            job_id: job_id.to_string(),
+            agent_id: "agent-1".to_string(),
+            status: "completed".to_string(),
+            result: None,
+            error: None,
+            timestamp: chrono::Utc::now(),
+            queue_position: None,
+            scheduled_for: None,
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut seen = Seen::default();
+        let ttl = Duration::from_secs(60);
+
+        assert!(matches!(seen.check("cmd-1", ttl), Duplicate::New));
+        assert!(matches!(seen.check("cmd-1", ttl), Duplicate::InProgress));
+
+        seen.remember(&response("cmd-1"));
+        assert!(matches!(
+            seen.check("cmd-1", ttl),
+            Duplicate::Completed(r) if r.status == "completed"
+        ));
+        // Never checked, so not kept
+        seen.remember(&response("cmd-2"));
+        assert!(matches!(seen.check("cmd-2", ttl), Duplicate::New));
+
+        // Never started, so its redelivery runs
+        seen.forget("cmd-2");
+        assert!(matches!(seen.check("cmd-2", ttl), Duplicate::New));
+
+        // Expired
+        assert!(matches!(
+            seen.check("cmd-1", Duration::ZERO),
+            Duplicate::New
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+//! Exit status of detached jobs
+//!
+//! The double fork leaves the agent with no way to wait for a detached job.
+//! With `jobs.track_exit`, the job runs under a small supervisor process
+//! that waits for it and writes how it ended to `<job_id>.exit` in the job
+//! log directory, next to `<job_id>.job` naming the command that started
+//! it. The agent picks these files up, also after a restart, and reports
+//! each as a `job_update` message; both are removed once it is sent.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::warn;
+
+use super::{job_log_dir, JOBS};
+use crate::config::paths;
+use crate::connection::{Command, JobUpdate};
+
+/// The command a detached job belongs to
+#[derive(Debug, Serialize, Deserialize)]
+struct JobMeta {
+    command_id: String,
+    component_id: String,
+}
+
+/// How the job's process ended, as its supervisor saw it
+#[derive(Debug, Serialize, Deserialize)]
+struct Exit {
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    finished_at: DateTime<Utc>,
+}
+
+fn job_file(dir: &str, job_id: &str, extension: &str) -> String {
+    paths::join(dir, &format!("{}.{}", job_id, extension))
+}
+
+/// Note which command started a job, before spawning it
+pub fn write_meta(job_id: &str, cmd: &Command) -> Result<()> {
+    write_meta_in(&job_log_dir(), job_id, cmd)
+}
+
+fn write_meta_in(dir: &str, job_id: &str, cmd: &Command) -> Result<()> {
+    std::fs::create_dir_all(dir).ok();
+    let meta = JobMeta {
+        command_id: cmd.id.clone(),
+        component_id: cmd.component_id.clone(),
+    };
+    let path = job_file(dir, job_id, "job");
+    std::fs::write(&path, serde_json::to_vec(&meta)?)
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+/// Record how a job ended; called by its supervisor
+#[cfg(unix)]
+pub fn record_exit(job_id: &str, exit_code: Option<i32>, signal: Option<i32>) {
+    record_exit_in(&job_log_dir(), job_id, exit_code, signal);
+}
+
+#[cfg(any(unix, test))]
+fn record_exit_in(dir: &str, job_id: &str, exit_code: Option<i32>, signal: Option<i32>) {
+    let exit = Exit {
+        exit_code,
+        signal,
+        finished_at: Utc::now(),
+    };
+    // Written aside and renamed, so a half-written file is never read
+    let path = job_file(dir, job_id, "exit");
+    let tmp = format!("{}.tmp", path);
+    let written = serde_json::to_vec(&exit)
+        .map_err(std::io::Error::from)
+        .and_then(|content| std::fs::write(&tmp, content))
+        .and_then(|()| std::fs::rename(&tmp, &path));
+    if let Err(e) = written {
+        eprintln!("Failed to record exit status in {}: {}", path, e);
+    }
+}
+
+/// Updates for the jobs that ended and are not reported yet
+pub fn collect_exits(agent_id: &str) -> Vec<JobUpdate> {
+    collect_exits_in(&job_log_dir(), agent_id)
+}
+
+fn collect_exits_in(dir: &str, agent_id: &str) -> Vec<JobUpdate> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut updates = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("exit") {
+            continue;
+        }
+        let job_id = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(job_id) => job_id.to_string(),
+            None => continue,
+        };
+        let exit: Exit = match read_json(&path) {
+            Ok(exit) => exit,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Ignoring unreadable exit status");
+                continue;
+            }
+        };
+        // Without its meta file the command is unknown; report the job alone
+        let command_id = read_json::<JobMeta>(Path::new(&job_file(dir, &job_id, "job")))
+            .map(|meta| meta.command_id)
+            .unwrap_or_default();
+
+        let status = match (exit.exit_code, exit.signal) {
+            (Some(0), _) => "exited",
+            (_, Some(_)) => "killed",
+            _ => "failed",
+        };
+        if let Some(record) = JOBS
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|record| record.job_id == job_id)
+        {
+            record.status = status.to_string();
+            record.exit_code = exit.exit_code;
+        }
+
+        updates.push(JobUpdate {
+            job_id: command_id,
+            agent_id: agent_id.to_string(),
+            detached_job_id: job_id,
+            status: status.to_string(),
+            exit_code: exit.exit_code,
+            signal: exit.signal,
+            finished_at: exit.finished_at,
+        });
+    }
+    updates.sort_by(|a, b| {
+        (a.finished_at, &a.detached_job_id).cmp(&(b.finished_at, &b.detached_job_id))
+    });
+    updates
+}
+
+/// Drop a reported job's status files
+pub fn forget_exit(job_id: &str) {
+    forget_exit_in(&job_log_dir(), job_id);
+}
+
+fn forget_exit_in(dir: &str, job_id: &str) {
+    let _ = std::fs::remove_file(job_file(dir, job_id, "exit"));
+    let _ = std::fs::remove_file(job_file(dir, job_id, "job"));
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let content = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&content)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_exits() {
+        let dir = std::env::temp_dir().join(format!("opsmap-exits-{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_string_lossy().to_string();
+        let cmd = Command {
+            id: "cmd-1".to_string(),
+            command_type: "start".to_string(),
+            component_id: "app".to_string(),
+            action_name: Some("start".to_string()),
+            params: serde_json::json!({}),
+            timeout_secs: 60,
+            trace_context: None,
+            correlation_id: None,
+            run_at: None,
+            run_after_secs: None,
+        };
+
+        assert!(collect_exits_in(&dir, "agent-1").is_empty());
+        write_meta_in(&dir, "job-1", &cmd).unwrap();
+        write_meta_in(&dir, "job-2", &cmd).unwrap();
+        // Still running
+        assert!(collect_exits_in(&dir, "agent-1").is_empty());
+
+        record_exit_in(&dir, "job-1", Some(3), None);
+        record_exit_in(&dir, "job-2", None, Some(9));
+        let updates = collect_exits_in(&dir, "agent-1");
+        let summary: Vec<(&str, &str, &str)> = updates
+            .iter()
+            .map(|u| {
+                (
+                    u.job_id.as_str(),
+                    u.detached_job_id.as_str(),
+                    u.status.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("cmd-1", "job-1", "failed"), ("cmd-1", "job-2", "killed")]
+        );
+        assert_eq!(updates[0].exit_code, Some(3));
+        assert_eq!(updates[1].signal, Some(9));
+
+        // Reported until forgotten
+        forget_exit_in(&dir, "job-1");
+        assert_eq!(collect_exits_in(&dir, "agent-1").len(), 1);
+        forget_exit_in(&dir, "job-2");
+        assert!(collect_exits_in(&dir, "agent-1").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Job log rotation and retention
+//!
+//! Detached jobs and spilled output write to the job log directory, which
+//! would otherwise grow forever. Every `jobs.log_cleanup_interval_secs`
+//! the agent:
+//!
+//! - rotates each log over `jobs.log_max_bytes` to `<name>.log.1`, shifting
+//!   older parts up to `jobs.log_keep`. The log is copied, then truncated in
+//!   place, since the job keeps writing to the file it has open;
+//! - removes logs and parts not written to for `jobs.log_max_age_secs`;
+//! - removes the oldest logs and parts until the directory holds at most
+//!   `jobs.log_max_total_bytes`. A log still being written to has a recent
+//!   modification time, so it goes last.
+//!
+//! Exit status files of detached jobs are left alone.
+
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+use super::job_log_dir;
+use crate::config::JobSettings;
+use crate::metrics::metrics;
+
+/// What one cleanup did
+#[derive(Debug, Default, PartialEq)]
+pub struct Cleanup {
+    pub rotated: usize,
+    pub removed: usize,
+    pub reclaimed_bytes: u64,
+    /// Size of the logs left
+    pub total_bytes: u64,
+}
+
+/// A log, or a rotated part of one, in the job log directory
+struct LogFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    /// The log itself rather than a rotated part
+    current: bool,
+}
+
+/// Rotate and expire job logs as `jobs` says
+pub fn clean_job_logs(jobs: &JobSettings) -> Cleanup {
+    let cleanup = clean_in(Path::new(&job_log_dir()), jobs, SystemTime::now());
+    metrics().set_job_logs_size(cleanup.total_bytes);
+    cleanup
+}
+
+fn clean_in(dir: &Path, jobs: &JobSettings, now: SystemTime) -> Cleanup {
+    let mut cleanup = Cleanup::default();
+
+    if jobs.log_max_bytes > 0 {
+        let oversized = log_files(dir)
+            .into_iter()
+            .filter(|log| log.current && log.size > jobs.log_max_bytes);
+        for log in oversized {
+            match rotate(&log.path, log.size, jobs.log_keep) {
+                Ok(dropped) => {
+                    debug!(path = %log.path.display(), size = log.size, "Rotated job log");
+                    cleanup.rotated += 1;
+                    cleanup.reclaimed_bytes += dropped;
+                    metrics().add_job_logs_reclaimed("rotation", dropped);
+                }
+                Err(e) => warn!(path = %log.path.display(), error = %e, "Failed to rotate job log"),
+            }
+        }
+    }
+
+    // Oldest first: expired files come first, then the ones to drop for space
+    let mut files = log_files(dir);
+    files.sort_by_key(|file| file.modified);
+    let max_age = Duration::from_secs(jobs.log_max_age_secs);
+    let mut total: u64 = files.iter().map(|file| file.size).sum();
+
+    for file in files {
+        let age = now.duration_since(file.modified).unwrap_or_default();
+        let expired = jobs.log_max_age_secs > 0 && age > max_age;
+        let over = jobs.log_max_total_bytes > 0 && total > jobs.log_max_total_bytes;
+        if !expired && !over {
+            break;
+        }
+        if let Err(e) = fs::remove_file(&file.path) {
+            warn!(path = %file.path.display(), error = %e, "Failed to remove job log");
+            continue;
+        }
+        total -= file.size;
+        cleanup.removed += 1;
+        cleanup.reclaimed_bytes += file.size;
+        metrics().add_job_logs_reclaimed(if expired { "age" } else { "size" }, file.size);
+    }
+
+    cleanup.total_bytes = total;
+    cleanup
+}
+
+/// Logs and rotated parts in `dir`
+fn log_files(dir: &Path) -> Vec<LogFile> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let current = name.ends_with(".log");
+            let part = name
+                .rsplit_once(".log.")
+                .is_some_and(|(_, n)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+            if !current && !part {
+                return None;
+            }
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            Some(LogFile {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified().ok()?,
+                current,
+            })
+        })
+        .collect()
+}
+
+/// Move a log's content to its first rotated part, shifting the older
+/// ones; returns the bytes dropped past `keep` parts
+fn rotate(path: &Path, size: u64, keep: usize) -> Result<u64> {
+    let part = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+    let dropped = if keep == 0 {
+        size
+    } else {
+        let dropped = fs::metadata(part(keep)).map(|m| m.len()).unwrap_or(0);
+        for n in (1..keep).rev() {
+            if part(n).exists() {
+                fs::rename(part(n), part(n + 1))?;
+            }
+        }
+        fs::copy(path, part(1))?;
+        dropped
+    };
+    // The job appends, so it carries on at the start of the emptied file
+    OpenOptions::new().write(true).open(path)?.set_len(0)?;
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, size: usize, age_secs: u64) {
+        let path = dir.join(name);
+        fs::write(&path, vec![b'x'; size]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age_secs);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_clean_job_logs() {
+        let dir = std::env::temp_dir().join(format!("opsmap-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let jobs = JobSettings {
+            log_max_bytes: 100,
+            log_keep: 2,
+            log_max_total_bytes: 300,
+            log_max_age_secs: 3600,
+            ..JobSettings::default()
+        };
+
+        write(&dir, "big.log", 150, 0);
+        write(&dir, "big.log.1", 120, 60);
+        write(&dir, "big.log.2", 110, 120);
+        write(&dir, "small.log", 50, 30);
+        write(&dir, "cmd-1.stdout.log", 80, 600);
+        write(&dir, "stale.log", 10, 7200);
+        // Exit tracking files are not logs
+        write(&dir, "job-1.exit", 10, 7200);
+
+        // Rotation drops the oldest part, stale.log goes for its age, then
+        // the spilled output and the shifted part for space
+        let cleanup = clean_in(&dir, &jobs, SystemTime::now());
+        assert_eq!(
+            cleanup,
+            Cleanup {
+                rotated: 1,
+                removed: 3,
+                reclaimed_bytes: 110 + 10 + 80 + 120,
+                total_bytes: 200,
+            }
+        );
+        assert_eq!(fs::metadata(dir.join("big.log")).unwrap().len(), 0);
+        assert_eq!(fs::metadata(dir.join("big.log.1")).unwrap().len(), 150);
+        assert!(!dir.join("big.log.2").exists());
+        assert!(!dir.join("cmd-1.stdout.log").exists());
+        assert!(dir.join("small.log").exists());
+        assert!(dir.join("job-1.exit").exists());
+
+        // Nothing left to do
+        let again = clean_in(&dir, &jobs, SystemTime::now());
+        assert_eq!((again.rotated, again.removed), (0, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
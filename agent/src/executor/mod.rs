@@ -1,54 +1,216 @@
 //! Command executor module
 //!
-//! CRITICAL: This module implements process detachment using double-fork.
+//! CRITICAL: This module implements process detachment using double-fork
+//! (on Windows: a detached process outside the agent's job object).
 //! A crash of the agent MUST NOT affect running processes.
 
-use anyhow::{anyhow, Context, Result};
-use nix::sys::signal::{self, Signal};
-use nix::sys::wait::waitpid;
-use nix::unistd::{self, ForkResult, Pid};
-use std::ffi::CString;
-use std::os::unix::io::RawFd;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::sync::Mutex;
+use tokio::io::AsyncReadExt;
 use tokio::process::Command as TokioCommand;
 use tokio::time::{timeout, Duration};
-use tracing::{debug, error, info, warn};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+use crate::config::{paths, JobLimits, JobSettings};
 use crate::connection::{Command, CommandResult};
+use crate::redact;
+use crate::secrets;
+use container::Container;
+use output::Capture;
+use script::Script;
+
+#[cfg(unix)]
+mod cgroup;
+mod container;
+mod dedup;
+mod exits;
+mod logs;
+mod output;
+mod pipeline;
+mod priority;
+mod queue;
+mod scheduled;
+mod script;
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use unix::{process_alive, spawn_detached};
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows::{process_alive, spawn_detached};
+
+pub use container::RUNTIMES as CONTAINER_RUNTIMES;
+pub use dedup::{check_duplicate, forget_command, remember_response, Duplicate};
+pub use exits::{collect_exits, forget_exit};
+pub use logs::clean_job_logs;
+pub use priority::Priority;
+pub use queue::{admit, next_ready, release, Admission};
+pub use scheduled::{
+    hold_response, load_schedule, schedule, scheduled_time, take_due, take_undelivered,
+};
+
+/// Environment, working directory and priority of an action, from
+/// `params.env`, `params.cwd` and `params.priority`
+#[derive(Debug, Default)]
+pub struct ActionEnv {
+    pub vars: HashMap<String, String>,
+    pub cwd: Option<String>,
+    pub priority: Priority,
+}
+
+impl ActionEnv {
+    /// Read from command params, resolving secret references in the env
+    /// with `secrets`
+    async fn from_params(params: &serde_json::Value, secrets: &secrets::Provider) -> Result<Self> {
+        let mut vars = HashMap::new();
+        if let Some(env) = params.get("env").filter(|v| !v.is_null()) {
+            let env: HashMap<String, String> =
+                serde_json::from_value(env.clone()).context("Invalid env in params")?;
+            for (name, value) in env {
+                if name.is_empty() || name.contains(['=', '\0']) {
+                    return Err(anyhow!("Invalid environment variable name: {:?}", name));
+                }
+                let value = secrets::expand(&value, secrets)
+                    .await
+                    .with_context(|| format!("Failed to resolve env {}", name))?;
+                if value.contains('\0') {
+                    return Err(anyhow!("Environment variable {} contains a NUL byte", name));
+                }
+                vars.insert(name, value);
+            }
+        }
+
+        let cwd = params
+            .get("cwd")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+        if let Some(ref cwd) = cwd {
+            if !Path::new(cwd).is_dir() {
+                return Err(anyhow!("Working directory does not exist: {}", cwd));
+            }
+        }
+
+        let priority = Priority::from_params(params)?;
+
+        Ok(Self {
+            vars,
+            cwd,
+            priority,
+        })
+    }
+}
+
+/// Directory where detached jobs write their output
+fn job_log_dir() -> String {
+    paths::join(paths::LOG_DIR, "jobs")
+}
+
+/// Number of recent jobs kept for the admin socket
+const MAX_JOB_RECORDS: usize = 100;
+
+/// Recently executed commands, newest last
+static JOBS: Mutex<VecDeque<JobRecord>> = Mutex::new(VecDeque::new());
+
+/// A command executed by this agent
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub command_id: String,
+    pub command_type: String,
+    pub command: String,
+    /// "detached", "completed", "failed" or "timeout"; a detached job with
+    /// a recorded exit becomes "exited", "failed" or "killed"
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub log_file: Option<String>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub duration_ms: Option<u64>,
+}
+
+/// Recently executed commands, newest first
+pub fn recent_jobs() -> Vec<JobRecord> {
+    JOBS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+fn record_job(record: JobRecord) {
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.len() >= MAX_JOB_RECORDS {
+        jobs.pop_front();
+    }
+    jobs.push_back(record);
+}
 
 /// Execute a command
 ///
 /// For sync commands: execute and wait for result
-/// For async commands: detach process and return job_id immediately
-pub async fn execute_command(cmd: &Command) -> Result<CommandResult> {
+/// For async commands: detach process and return job_id immediately; they
+/// must have been admitted by the job queue first
+///
+/// Values marked secret in the params are revealed for the run, and masked
+/// with any redaction pattern match in the result or error.
+pub async fn execute_command(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
+    let mut cmd = cmd.clone();
+    let _secrets = redact::reveal(&mut cmd.params);
+
+    match dispatch(&cmd, jobs).await {
+        Ok(mut result) => {
+            result.stdout = redact::text(&result.stdout);
+            result.stderr = redact::text(&result.stderr);
+            for step in &mut result.steps {
+                step.stdout = redact::text(&step.stdout);
+                step.stderr = redact::text(&step.stderr);
+            }
+            Ok(result)
+        }
+        Err(e) => {
+            let message = e.to_string();
+            let masked = redact::text(&message);
+            Err(if masked == message {
+                e
+            } else {
+                anyhow!(masked)
+            })
+        }
+    }
+}
+
+async fn dispatch(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
     match cmd.command_type.as_str() {
         "start" | "stop" | "restart" | "action" => {
             // Async commands - detach the process
-            execute_async_command(cmd).await
+            let result = execute_async_command(cmd, jobs).await;
+            if result.is_err() {
+                queue::release(&cmd.id);
+            }
+            result
         }
-        "check" | "native" => {
+        "check" | "native" | "script" => {
             // Sync commands - wait for result
-            execute_sync_command(cmd).await
+            execute_sync_command(cmd, jobs).await
+        }
+        "pipeline" => pipeline::execute(cmd, jobs).await,
+        "job_logs" => read_job_log(cmd, jobs),
+        #[cfg(feature = "wasm")]
+        "wasm" => {
+            // Sync command - run an action exported by a WASM module
+            crate::wasm::execute_command(cmd).await
         }
         _ => Err(anyhow!("Unknown command type: {}", cmd.command_type)),
     }
 }
 
 /// Execute a synchronous command (blocks until completion)
-async fn execute_sync_command(cmd: &Command) -> Result<CommandResult> {
-    let action_name = cmd
-        .action_name
+async fn execute_sync_command(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
+    cmd.action_name
         .as_ref()
         .ok_or_else(|| anyhow!("Missing action name"))?;
 
-    let command_str = cmd
-        .params
-        .get("command")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("Missing command in params"))?;
-
     let args: Vec<&str> = cmd
         .params
         .get("args")
@@ -56,38 +218,112 @@ async fn execute_sync_command(cmd: &Command) -> Result<CommandResult> {
         .map(|arr| arr.iter().filter_map(|v| v.as_str()).collect())
         .unwrap_or_default();
 
+    let mut env = ActionEnv::from_params(&cmd.params, &secrets::provider()).await?;
+    let container = Container::from_params(&cmd.params, jobs)?;
+
+    // An inline script runs from a temporary file, removed on return
+    let script = match cmd.command_type.as_str() {
+        "script" if container.is_some() => bail!("Scripts cannot target a container"),
+        "script" => Some(Script::write(&cmd.params, jobs)?),
+        _ => None,
+    };
+    let (command_str, process) = match script {
+        // The script sets its priority itself, before switching user
+        Some(ref script) => (
+            format!("{} script", script.interpreter),
+            script.command(&args, std::mem::take(&mut env.priority)),
+        ),
+        None => {
+            let command_str = cmd
+                .params
+                .get("command")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("Missing command in params"))?;
+            let command_line = if args.is_empty() {
+                command_str.to_string()
+            } else {
+                format!("{} {}", command_str, args.join(" "))
+            };
+            let process = match container {
+                // The runtime's client is run directly, without a shell
+                Some(ref container) => {
+                    let run_as_user = cmd.params.get("run_as_user").and_then(|v| v.as_str());
+                    let argv = container.argv(&command_line, run_as_user, &mut env);
+                    let mut process = TokioCommand::new(&argv[0]);
+                    process.args(&argv[1..]);
+                    process
+                }
+                None => shell(&command_line),
+            };
+            (command_str.to_string(), process)
+        }
+    };
+
     info!(
         command_id = %cmd.id,
         command = %command_str,
         "Executing sync command"
     );
 
+    // Output beyond the limit is cut, and kept in full in the job log
+    // directory with `spill_output`
+    let capture = |stream: &str| {
+        let spill = jobs.spill_output.then(|| {
+            std::fs::create_dir_all(job_log_dir()).ok();
+            job_log_file(&format!("{}.{}", cmd.id, stream))
+        });
+        Capture::new(jobs.max_output_bytes, spill)
+    };
+
+    let started_at = chrono::Utc::now();
     let start = std::time::Instant::now();
 
     // Execute with timeout
     let result = timeout(
         Duration::from_secs(cmd.timeout_secs),
-        execute_with_output(command_str, &args),
+        execute_with_output(process, &env, capture("stdout"), capture("stderr")),
     )
     .await;
 
     let duration_ms = start.elapsed().as_millis() as u64;
 
+    let (status, exit_code) = match &result {
+        Ok(Ok(Output { exit_code: 0, .. })) => ("completed", Some(0)),
+        Ok(Ok(Output { exit_code, .. })) => ("failed", Some(*exit_code)),
+        Ok(Err(_)) => ("failed", None),
+        Err(_) => ("timeout", None),
+    };
+    record_job(JobRecord {
+        job_id: cmd.id.clone(),
+        command_id: cmd.id.clone(),
+        command_type: cmd.command_type.clone(),
+        command: command_str.clone(),
+        status: status.to_string(),
+        exit_code,
+        log_file: None,
+        started_at,
+        duration_ms: Some(duration_ms),
+    });
+
     match result {
-        Ok(Ok((exit_code, stdout, stderr))) => {
+        Ok(Ok(output)) => {
             info!(
                 command_id = %cmd.id,
-                exit_code = exit_code,
+                exit_code = output.exit_code,
                 duration_ms = duration_ms,
+                truncated = output.truncated,
                 "Command completed"
             );
 
             Ok(CommandResult {
-                exit_code,
-                stdout,
-                stderr,
+                exit_code: output.exit_code,
+                stdout: output.stdout,
+                stderr: output.stderr,
                 duration_ms,
                 timed_out: false,
+                truncated: output.truncated,
+                steps: Vec::new(),
+                check: None,
             })
         }
         Ok(Err(e)) => {
@@ -96,16 +332,22 @@ async fn execute_sync_command(cmd: &Command) -> Result<CommandResult> {
         }
         Err(_) => {
             error!(command_id = %cmd.id, "Command timed out");
-            Err(anyhow!("Command timed out after {} seconds", cmd.timeout_secs))
+            Err(anyhow!(
+                "Command timed out after {} seconds",
+                cmd.timeout_secs
+            ))
         }
     }
 }
 
 /// Execute an asynchronous command (detaches process)
 ///
-/// CRITICAL: Uses double-fork to completely detach the process.
+/// CRITICAL: Uses double-fork (or its Windows equivalent) to completely detach the process.
 /// The process will survive agent crash/restart.
-async fn execute_async_command(cmd: &Command) -> Result<CommandResult> {
+///
+/// `params.limits` (cpu_percent, memory_mb, pids_max) override `jobs.limits`.
+/// The job keeps the slot it was admitted with until its process exits.
+async fn execute_async_command(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
     let command_str = cmd
         .params
         .get("command")
@@ -123,12 +365,36 @@ async fn execute_async_command(cmd: &Command) -> Result<CommandResult> {
         })
         .unwrap_or_default();
 
-    let run_as_user = cmd
+    let mut run_as_user = cmd
         .params
         .get("run_as_user")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string());
 
+    let mut env = ActionEnv::from_params(&cmd.params, &secrets::provider()).await?;
+
+    let command_line = if args.is_empty() {
+        command_str.to_string()
+    } else {
+        format!("{} {}", command_str, args.join(" "))
+    };
+    // In a container, the whole line runs there and the user applies inside
+    let program = match Container::from_params(&cmd.params, jobs)? {
+        Some(container) => {
+            Program::Exec(container.argv(&command_line, run_as_user.take().as_deref(), &mut env))
+        }
+        None => Program::Shell(command_line),
+    };
+
+    let limits = match cmd.params.get("limits") {
+        Some(overrides) => {
+            let overrides: JobLimits =
+                serde_json::from_value(overrides.clone()).context("Invalid limits in params")?;
+            jobs.limits.merge(&overrides)
+        }
+        None => jobs.limits.clone(),
+    };
+
     let job_id = Uuid::new_v4().to_string();
 
     info!(
@@ -138,8 +404,33 @@ async fn execute_async_command(cmd: &Command) -> Result<CommandResult> {
         "Starting detached async command"
     );
 
-    // Execute detached process using double-fork
-    spawn_detached(command_str, &args, run_as_user.as_deref(), &job_id)?;
+    // The supervisor reports the exit against the command
+    if jobs.track_exit && cfg!(unix) {
+        exits::write_meta(&job_id, cmd)?;
+    }
+
+    // Execute detached process
+    let pid = spawn_detached(
+        &program,
+        run_as_user.as_deref(),
+        &job_id,
+        &env,
+        &limits,
+        jobs,
+    )?;
+    queue::started(&cmd.id, pid);
+
+    record_job(JobRecord {
+        job_id: job_id.clone(),
+        command_id: cmd.id.clone(),
+        command_type: cmd.command_type.clone(),
+        command: redact::text(command_str),
+        status: "detached".to_string(),
+        exit_code: None,
+        log_file: Some(job_log_file(&job_id)),
+        started_at: chrono::Utc::now(),
+        duration_ms: None,
+    });
 
     // Return immediately - process is detached
     Ok(CommandResult {
@@ -148,270 +439,265 @@ async fn execute_async_command(cmd: &Command) -> Result<CommandResult> {
         stderr: String::new(),
         duration_ms: 0,
         timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
     })
 }
 
-/// Execute a command and capture output
-async fn execute_with_output(command: &str, args: &[&str]) -> Result<(i32, String, String)> {
-    let mut child = TokioCommand::new("sh")
-        .arg("-c")
-        .arg(if args.is_empty() {
-            command.to_string()
-        } else {
-            format!("{} {}", command, args.join(" "))
-        })
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn command")?;
-
-    let stdout = child.stdout.take().expect("stdout not captured");
-    let stderr = child.stderr.take().expect("stderr not captured");
-
-    let mut stdout_lines = Vec::new();
-    let mut stderr_lines = Vec::new();
-
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
-
-    // Read stdout and stderr concurrently
-    loop {
-        tokio::select! {
-            line = stdout_reader.next_line() => {
-                match line {
-                    Ok(Some(l)) => stdout_lines.push(l),
-                    Ok(None) => break,
-                    Err(e) => {
-                        warn!(error = %e, "Error reading stdout");
-                        break;
-                    }
-                }
-            }
-            line = stderr_reader.next_line() => {
-                match line {
-                    Ok(Some(l)) => stderr_lines.push(l),
-                    Ok(None) => {},
-                    Err(e) => {
-                        warn!(error = %e, "Error reading stderr");
-                    }
-                }
-            }
-        }
-    }
-
-    // Drain remaining stderr
-    while let Ok(Some(line)) = stderr_reader.next_line().await {
-        stderr_lines.push(line);
-    }
-
-    let status = child.wait().await.context("Failed to wait for command")?;
-    let exit_code = status.code().unwrap_or(-1);
-
-    Ok((
-        exit_code,
-        stdout_lines.join("\n"),
-        stderr_lines.join("\n"),
-    ))
+/// What a detached job runs
+#[derive(Debug)]
+enum Program {
+    /// A command line for the shell
+    Shell(String),
+    /// A program and its arguments, run without a shell
+    Exec(Vec<String>),
 }
 
-/// Spawn a completely detached process using double-fork
-///
-/// This is the CRITICAL function for process detachment.
-/// The spawned process will:
-/// 1. First fork -> intermediate child
-/// 2. setsid() -> new session (detach from terminal)
-/// 3. Second fork -> grandchild becomes orphan
-/// 4. Intermediate child exits -> grandchild reparented to init/systemd
-/// 5. Close ALL file descriptors
-/// 6. Redirect stdin/stdout/stderr to /dev/null or log file
-fn spawn_detached(
-    command: &str,
-    args: &[String],
-    run_as_user: Option<&str>,
-    job_id: &str,
-) -> Result<()> {
-    // Log file for the detached process
-    let log_dir = "/var/log/opsmap/jobs";
-    std::fs::create_dir_all(log_dir).ok();
-    let log_file = format!("{}/{}.log", log_dir, job_id);
-
-    // FIRST FORK
-    match unsafe { unistd::fork() } {
-        Ok(ForkResult::Parent { child }) => {
-            // Parent: wait for intermediate child to exit
-            debug!(pid = child.as_raw(), "First fork - waiting for intermediate child");
-            let _ = waitpid(child, None);
-            return Ok(());
-        }
-        Ok(ForkResult::Child) => {
-            // Intermediate child: continue to second fork
-        }
-        Err(e) => {
-            return Err(anyhow!("First fork failed: {}", e));
+impl Program {
+    /// As the job log shows it, secrets masked
+    fn shown(&self) -> String {
+        match self {
+            Program::Shell(command_line) => redact::text(command_line),
+            Program::Exec(argv) => redact::text(&argv.join(" ")),
         }
     }
+}
 
-    // INTERMEDIATE CHILD
-    // Create new session - detach from terminal
-    if let Err(e) = unistd::setsid() {
-        error!(error = %e, "setsid failed");
-        std::process::exit(1);
-    }
-
-    // Ignore SIGHUP so the grandchild isn't killed when session leader exits
-    unsafe {
-        signal::signal(Signal::SIGHUP, signal::SigHandler::SigIgn).ok();
+/// The platform shell, set to run `command_line`
+pub fn shell(command_line: &str) -> TokioCommand {
+    #[cfg(unix)]
+    {
+        let mut command = TokioCommand::new("sh");
+        command.arg("-c").arg(command_line);
+        command
     }
-
-    // SECOND FORK
-    match unsafe { unistd::fork() } {
-        Ok(ForkResult::Parent { .. }) => {
-            // Intermediate child: exit immediately
-            // This orphans the grandchild, which gets reparented to init
-            std::process::exit(0);
-        }
-        Ok(ForkResult::Child) => {
-            // Grandchild: this is the actual detached process
-        }
-        Err(e) => {
-            error!(error = %e, "Second fork failed");
-            std::process::exit(1);
-        }
+    #[cfg(windows)]
+    {
+        // cmd.exe does its own parsing; pass the line through untouched
+        let mut command = TokioCommand::new("cmd");
+        command.arg("/C").raw_arg(command_line);
+        command
     }
+}
 
-    // GRANDCHILD (detached process)
-
-    // Close all file descriptors
-    close_all_fds();
-
-    // Redirect stdin/stdout/stderr
-    redirect_std_streams(&log_file);
-
-    // Change to root directory to avoid holding mount points
-    let _ = unistd::chdir("/");
-
-    // Clear umask
-    let _ = nix::sys::stat::umask(nix::sys::stat::Mode::empty());
-
-    // Change user if specified
-    if let Some(user) = run_as_user {
-        if let Err(e) = switch_user(user) {
-            eprintln!("Failed to switch user to {}: {}", user, e);
-            std::process::exit(1);
-        }
-    }
+/// Where a detached job writes its output
+fn job_log_file(job_id: &str) -> String {
+    paths::join(&job_log_dir(), &format!("{}.log", job_id))
+}
 
-    // Execute the command
-    let c_command = CString::new(command).expect("CString::new failed");
+/// Exit code and captured output of a command
+#[derive(Debug)]
+struct Output {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    /// Either stream exceeded its capture limit
+    truncated: bool,
+}
 
-    // Build args with command as first element
-    let mut c_args: Vec<CString> = vec![c_command.clone()];
-    for arg in args {
-        c_args.push(CString::new(arg.as_str()).expect("CString::new failed"));
+/// Read a job's log, or the spilled output of a command
+///
+/// `params.job_id` names the job, `params.stream` ("stdout" or "stderr")
+/// picks a spilled stream instead of a detached job's log. At most
+/// `params.max_bytes` are returned from `params.offset`, capped at (and
+/// defaulting to) `jobs.max_output_bytes`; `truncated` says there is more.
+fn read_job_log(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let job_id = cmd
+        .params
+        .get("job_id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing job_id in params"))?;
+    if job_id.is_empty() || job_id.contains(['/', '\\']) || job_id.starts_with('.') {
+        return Err(anyhow!("Invalid job_id: {}", job_id));
     }
-
-    // Execute via sh -c for better compatibility
-    let sh = CString::new("/bin/sh").unwrap();
-    let sh_c = CString::new("-c").unwrap();
-    let full_command = if args.is_empty() {
-        command.to_string()
-    } else {
-        format!("{} {}", command, args.join(" "))
+    let name = match cmd.params.get("stream").and_then(|v| v.as_str()) {
+        None => job_id.to_string(),
+        Some(stream @ ("stdout" | "stderr")) => format!("{}.{}", job_id, stream),
+        Some(stream) => return Err(anyhow!("Unknown stream: {}", stream)),
     };
-    let c_full_command = CString::new(full_command).unwrap();
-
-    // Log start
-    eprintln!("[{}] Starting command: {}", chrono::Utc::now(), command);
-
-    // execvp replaces the current process
-    let _ = unistd::execvp(&sh, &[sh.clone(), sh_c, c_full_command]);
+    let offset = cmd
+        .params
+        .get("offset")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    let max_bytes = cmd
+        .params
+        .get("max_bytes")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(u64::MAX)
+        .min(jobs.max_output_bytes as u64);
+
+    let path = job_log_file(&name);
+    let mut file =
+        std::fs::File::open(&path).with_context(|| format!("No log for job {}", job_id))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut content = Vec::new();
+    file.take(max_bytes).read_to_end(&mut content)?;
 
-    // If we get here, exec failed
-    eprintln!("exec failed");
-    std::process::exit(1);
+    Ok(CommandResult {
+        exit_code: 0,
+        stdout: String::from_utf8_lossy(&content).to_string(),
+        stderr: String::new(),
+        duration_ms: 0,
+        timed_out: false,
+        truncated: offset + (content.len() as u64) < size,
+        steps: Vec::new(),
+        check: None,
+    })
 }
 
-/// Close all file descriptors except stdin/stdout/stderr
-fn close_all_fds() {
-    // Get max fd from /proc/self/fd or use a reasonable default
-    let max_fd = std::fs::read_dir("/proc/self/fd")
-        .map(|entries| {
-            entries
-                .filter_map(|e| e.ok())
-                .filter_map(|e| e.file_name().to_str().and_then(|s| s.parse::<RawFd>().ok()))
-                .max()
-                .unwrap_or(1024)
-        })
-        .unwrap_or(1024);
-
-    // Close all fds above stderr
-    for fd in 3..=max_fd {
-        unsafe {
-            libc::close(fd);
-        }
+/// Execute a command and capture output
+async fn execute_with_output(
+    mut process: TokioCommand,
+    env: &ActionEnv,
+    mut stdout_capture: Capture,
+    mut stderr_capture: Capture,
+) -> Result<Output> {
+    if let Some(ref cwd) = env.cwd {
+        process.current_dir(cwd);
     }
-}
-
-/// Redirect stdin/stdout/stderr to log file
-fn redirect_std_streams(log_file: &str) {
-    use std::os::unix::io::AsRawFd;
-
-    // Open /dev/null for stdin
-    let dev_null = std::fs::File::open("/dev/null").ok();
-    if let Some(f) = dev_null {
+    #[cfg(unix)]
+    if !env.priority.is_empty() {
+        let priority = env.priority.clone();
         unsafe {
-            libc::dup2(f.as_raw_fd(), 0);
+            process.pre_exec(move || priority.apply());
         }
     }
+    let mut child = process
+        .envs(&env.vars)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn command")?;
 
-    // Open log file for stdout/stderr
-    let log = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_file)
-        .ok();
+    let mut stdout = child.stdout.take().expect("stdout not captured");
+    let mut stderr = child.stderr.take().expect("stderr not captured");
 
-    if let Some(f) = log {
-        let fd = f.as_raw_fd();
-        unsafe {
-            libc::dup2(fd, 1); // stdout
-            libc::dup2(fd, 2); // stderr
+    let mut stdout_buf = [0u8; 8192];
+    let mut stderr_buf = [0u8; 8192];
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+
+    // Read stdout and stderr concurrently, keeping only what the captures allow
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = stdout.read(&mut stdout_buf), if stdout_open => {
+                match read {
+                    Ok(0) => stdout_open = false,
+                    Ok(n) => stdout_capture.push(&stdout_buf[..n]),
+                    Err(e) => {
+                        warn!(error = %e, "Error reading stdout");
+                        stdout_open = false;
+                    }
+                }
+            }
+            read = stderr.read(&mut stderr_buf), if stderr_open => {
+                match read {
+                    Ok(0) => stderr_open = false,
+                    Ok(n) => stderr_capture.push(&stderr_buf[..n]),
+                    Err(e) => {
+                        warn!(error = %e, "Error reading stderr");
+                        stderr_open = false;
+                    }
+                }
+            }
         }
     }
-}
-
-/// Switch to a different user
-fn switch_user(username: &str) -> Result<()> {
-    use nix::unistd::{setgid, setuid, Gid, Uid};
-
-    // Get user info
-    let user = nix::unistd::User::from_name(username)
-        .context("Failed to lookup user")?
-        .ok_or_else(|| anyhow!("User not found: {}", username))?;
 
-    // Set group first (must be done before dropping root)
-    setgid(Gid::from_raw(user.gid.as_raw())).context("Failed to set GID")?;
-
-    // Set user
-    setuid(Uid::from_raw(user.uid.as_raw())).context("Failed to set UID")?;
+    let status = child.wait().await.context("Failed to wait for command")?;
 
-    Ok(())
+    Ok(Output {
+        exit_code: status.code().unwrap_or(-1),
+        truncated: stdout_capture.truncated() || stderr_capture.truncated(),
+        stdout: stdout_capture.finish(),
+        stderr: stderr_capture.finish(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn capture() -> Capture {
+        Capture::new(1024, None)
+    }
+
     #[tokio::test]
     async fn test_execute_with_output() {
-        let (exit_code, stdout, _) = execute_with_output("echo", &["hello"]).await.unwrap();
-        assert_eq!(exit_code, 0);
-        assert_eq!(stdout.trim(), "hello");
+        let output = execute_with_output(
+            shell("echo hello"),
+            &ActionEnv::default(),
+            capture(),
+            capture(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout.trim(), "hello");
+        assert!(!output.truncated);
     }
 
     #[tokio::test]
     async fn test_execute_with_output_error() {
-        let (exit_code, _, _) = execute_with_output("false", &[]).await.unwrap();
-        assert_ne!(exit_code, 0);
+        let output =
+            execute_with_output(shell("false"), &ActionEnv::default(), capture(), capture())
+                .await
+                .unwrap();
+        assert_ne!(output.exit_code, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_output_truncated() {
+        let output = execute_with_output(
+            shell("seq 1 100000"),
+            &ActionEnv::default(),
+            Capture::new(16, None),
+            capture(),
+        )
+        .await
+        .unwrap();
+        assert!(output.truncated);
+        assert!(output.stdout.starts_with("1\n2\n3\n4\n"));
+        assert!(output.stdout.ends_with("\n100000"));
+        assert!(output.stdout.contains("bytes truncated"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_action_env() {
+        let secrets = std::env::temp_dir().join(format!("opsmap-secrets-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&secrets).unwrap();
+        std::fs::write(secrets.join("db-password"), "s3cret\n").unwrap();
+        let provider = secrets::Provider::File {
+            dir: secrets.to_string_lossy().to_string(),
+        };
+
+        let params = serde_json::json!({
+            "env": {"DB_URL": "postgres://app:${secret:db-password}@db/app", "MODE": "repair"},
+            "cwd": "/tmp",
+        });
+        let env = ActionEnv::from_params(&params, &provider).await.unwrap();
+        assert_eq!(env.vars["DB_URL"], "postgres://app:s3cret@db/app");
+
+        let output = execute_with_output(shell("echo $MODE $(pwd)"), &env, capture(), capture())
+            .await
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "repair /tmp");
+
+        for bad in [
+            serde_json::json!({"env": {"X": "${secret:missing}"}}),
+            serde_json::json!({"env": {"X": "${secret:../etc/passwd}"}}),
+            serde_json::json!({"env": {"A=B": "x"}}),
+            serde_json::json!({"cwd": "/nonexistent/dir"}),
+        ] {
+            let env = ActionEnv::from_params(&bad, &provider).await;
+            assert!(env.is_err(), "{}", bad);
+        }
+        std::fs::remove_dir_all(&secrets).unwrap();
     }
 }
@@ -0,0 +1,150 @@
+//! Bounded capture of command output
+//!
+//! Each stream keeps at most `jobs.max_output_bytes`: the first half and the
+//! last half of what the command printed, with a marker in between saying
+//! how much was cut. With `jobs.spill_output`, a stream that overflows is
+//! also written in full to the job log directory, from where the `job_logs`
+//! command reads it back.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use tracing::warn;
+
+/// One captured stream
+pub struct Capture {
+    limit: usize,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    total: u64,
+    spill_path: Option<String>,
+    spill: Option<File>,
+}
+
+impl Capture {
+    /// Keep at most `limit` bytes; overflowing output goes to `spill_path`
+    /// when set
+    pub fn new(limit: usize, spill_path: Option<String>) -> Self {
+        Self {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            total: 0,
+            spill_path,
+            spill: None,
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        if self.spill.is_none() && self.total + data.len() as u64 > self.limit as u64 {
+            self.start_spill();
+        }
+        if let Some(ref mut spill) = self.spill {
+            if let Err(e) = spill.write_all(data) {
+                warn!(error = %e, "Failed to spill command output");
+                self.spill = None;
+                self.spill_path = None;
+            }
+        }
+        self.total += data.len() as u64;
+
+        let head_limit = self.limit / 2;
+        if self.head.len() < head_limit {
+            let n = (head_limit - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        self.tail.extend(data);
+        let tail_limit = self.limit - head_limit;
+        if self.tail.len() > tail_limit {
+            self.tail.drain(..self.tail.len() - tail_limit);
+        }
+    }
+
+    /// Open the spill file with everything captured so far, which is all
+    /// of it until the limit is first exceeded
+    fn start_spill(&mut self) {
+        let path = match self.spill_path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let spilled = File::create(path).and_then(|mut file| {
+            let (front, back) = self.tail.as_slices();
+            file.write_all(&self.head)?;
+            file.write_all(front)?;
+            file.write_all(back)?;
+            Ok(file)
+        });
+        match spilled {
+            Ok(file) => self.spill = Some(file),
+            Err(e) => {
+                warn!(path = %path, error = %e, "Failed to spill command output");
+                self.spill_path = None;
+            }
+        }
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.total > self.limit as u64
+    }
+
+    /// The captured text, lines joined with `\n`
+    pub fn finish(self) -> String {
+        let mut bytes = self.head;
+        if self.total > self.limit as u64 {
+            let dropped = self.total - bytes.len() as u64 - self.tail.len() as u64;
+            let spilled = match self.spill_path {
+                Some(ref path) if self.spill.is_some() => format!(", full output in {}", path),
+                _ => String::new(),
+            };
+            bytes.extend_from_slice(
+                format!("\n[... {} bytes truncated{} ...]\n", dropped, spilled).as_bytes(),
+            );
+        }
+        bytes.extend(self.tail);
+
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let mut small = Capture::new(100, None);
+        small.push(b"one\r\ntwo\n");
+        assert!(!small.truncated());
+        assert_eq!(small.finish(), "one\ntwo");
+
+        let mut large = Capture::new(8, None);
+        large.push(b"0123");
+        large.push(b"456789abcdef");
+        assert!(large.truncated());
+        assert_eq!(large.finish(), "0123\n[... 8 bytes truncated ...]\ncdef");
+    }
+
+    #[test]
+    fn test_spill() {
+        let path = std::env::temp_dir().join(format!("opsmap-spill-{}.log", uuid::Uuid::new_v4()));
+        let path = path.to_string_lossy().to_string();
+
+        let mut capture = Capture::new(4, Some(path.clone()));
+        capture.push(b"ab");
+        assert!(!std::path::Path::new(&path).exists());
+        capture.push(b"cdef");
+        capture.push(b"gh");
+        let text = capture.finish();
+        assert!(
+            text.contains(&format!("full output in {}", path)),
+            "{}",
+            text
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "abcdefgh");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
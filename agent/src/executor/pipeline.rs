@@ -0,0 +1,361 @@
+//! Multi-step actions
+//!
+//! A "pipeline" command runs `params.steps` in order, e.g. stop, wait for
+//! the port to close, clear a cache, start, verify health:
+//!
+//! ```json
+//! { "command_type": "pipeline", "params": { "steps": [
+//!     { "name": "stop", "command": "systemctl stop app" },
+//!     { "name": "port closed", "native": "tcp_port", "config": { "port": 8080 }, "expect": "error", "timeout_secs": 30 },
+//!     { "name": "clear cache", "command": "rm -rf /var/cache/app/*", "on_failure": "continue" },
+//!     { "name": "start", "command": "systemctl start app", "rollback": "systemctl start app-previous", "on_failure": "rollback" },
+//!     { "name": "healthy", "native": "http", "config": { "url": "http://localhost:8080/health" }, "timeout_secs": 60 }
+//! ] } }
+//! ```
+//!
+//! A step runs a shell `command`, which must exit 0, or a `native` check,
+//! repeated every `retry_interval_secs` until its status is `expect` ("ok"
+//! by default). Either gets `timeout_secs` (60). When a step fails,
+//! `on_failure` decides: "abort" (the default) skips the remaining steps,
+//! "continue" carries on, and "rollback" runs the `rollback` commands of
+//! that step and of those completed before it, newest first, then aborts.
+//! `params.env`, `params.cwd` and `params.priority` apply to every command.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::time::Instant;
+use tokio::time::{timeout, Duration};
+use tracing::{info, warn};
+
+use super::{execute_with_output, shell, ActionEnv, Capture};
+use crate::config::JobSettings;
+use crate::connection::{Command, CommandResult, StepResult};
+use crate::native_commands;
+use crate::secrets;
+
+#[derive(Debug, Deserialize)]
+struct Step {
+    name: String,
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    native: Option<String>,
+    #[serde(default)]
+    config: serde_json::Value,
+    #[serde(default = "default_expect")]
+    expect: String,
+    #[serde(default = "default_retry_interval")]
+    retry_interval_secs: u64,
+    #[serde(default = "default_step_timeout")]
+    timeout_secs: u64,
+    #[serde(default)]
+    on_failure: OnFailure,
+    #[serde(default)]
+    rollback: Option<String>,
+}
+
+fn default_expect() -> String {
+    "ok".to_string()
+}
+
+fn default_retry_interval() -> u64 {
+    2
+}
+
+fn default_step_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OnFailure {
+    #[default]
+    Abort,
+    Continue,
+    Rollback,
+}
+
+/// Run the steps of a pipeline command
+pub async fn execute(cmd: &Command, jobs: &JobSettings) -> Result<CommandResult> {
+    let steps: Vec<Step> = serde_json::from_value(
+        cmd.params
+            .get("steps")
+            .cloned()
+            .ok_or_else(|| anyhow!("Missing steps in params"))?,
+    )
+    .context("Invalid steps in params")?;
+    if steps.is_empty() {
+        bail!("Pipeline has no steps");
+    }
+    for step in &steps {
+        if step.command.is_some() == step.native.is_some() {
+            bail!(
+                "Step '{}' needs either a command or a native check",
+                step.name
+            );
+        }
+    }
+    let env = ActionEnv::from_params(&cmd.params, &secrets::provider()).await?;
+
+    info!(command_id = %cmd.id, steps = steps.len(), "Running pipeline");
+    let start = Instant::now();
+    let mut results: Vec<StepResult> = Vec::new();
+    let mut exit_code = 0;
+
+    for (i, step) in steps.iter().enumerate() {
+        if exit_code != 0 {
+            results.push(skipped(&step.name));
+            continue;
+        }
+
+        let result = run_step(step, &env, jobs).await;
+        let completed = result.status == "completed";
+        info!(
+            command_id = %cmd.id,
+            step = %step.name,
+            status = %result.status,
+            "Pipeline step finished"
+        );
+        let failed_code = result.exit_code.filter(|code| *code != 0).unwrap_or(1);
+        results.push(result);
+        if completed || step.on_failure == OnFailure::Continue {
+            continue;
+        }
+
+        exit_code = failed_code;
+        if step.on_failure == OnFailure::Rollback {
+            let done = steps[..i]
+                .iter()
+                .zip(&results)
+                .filter(|(_, result)| result.status == "completed")
+                .map(|(step, _)| step);
+            let undo: Vec<&Step> = done.chain(std::iter::once(step)).collect();
+            for step in undo.into_iter().rev() {
+                if let Some(ref rollback) = step.rollback {
+                    warn!(command_id = %cmd.id, step = %step.name, "Rolling back pipeline step");
+                    let mut result =
+                        run_command(&step.name, rollback, step.timeout_secs, &env, jobs).await;
+                    result.rollback = true;
+                    results.push(result);
+                }
+            }
+        }
+    }
+
+    let summary = results
+        .iter()
+        .map(|r| {
+            let rollback = if r.rollback { " (rollback)" } else { "" };
+            format!("{}{}: {}", r.name, rollback, r.status)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(CommandResult {
+        exit_code,
+        stdout: summary,
+        stderr: String::new(),
+        duration_ms: start.elapsed().as_millis() as u64,
+        timed_out: false,
+        truncated: results.iter().any(|r| r.truncated),
+        steps: results,
+        check: None,
+    })
+}
+
+async fn run_step(step: &Step, env: &ActionEnv, jobs: &JobSettings) -> StepResult {
+    match (&step.command, &step.native) {
+        (Some(command), _) => run_command(&step.name, command, step.timeout_secs, env, jobs).await,
+        (None, Some(native)) => wait_for_native(step, native).await,
+        (None, None) => unreachable!("steps are validated before running"),
+    }
+}
+
+async fn run_command(
+    name: &str,
+    command_line: &str,
+    timeout_secs: u64,
+    env: &ActionEnv,
+    jobs: &JobSettings,
+) -> StepResult {
+    let start = Instant::now();
+    let result = timeout(
+        Duration::from_secs(timeout_secs),
+        execute_with_output(
+            shell(command_line),
+            env,
+            Capture::new(jobs.max_output_bytes, None),
+            Capture::new(jobs.max_output_bytes, None),
+        ),
+    )
+    .await;
+
+    let mut step = StepResult {
+        name: name.to_string(),
+        status: "failed".to_string(),
+        exit_code: None,
+        stdout: String::new(),
+        stderr: String::new(),
+        duration_ms: start.elapsed().as_millis() as u64,
+        truncated: false,
+        rollback: false,
+    };
+    match result {
+        Ok(Ok(output)) => {
+            if output.exit_code == 0 {
+                step.status = "completed".to_string();
+            }
+            step.exit_code = Some(output.exit_code);
+            step.truncated = output.truncated;
+            step.stdout = output.stdout;
+            step.stderr = output.stderr;
+        }
+        Ok(Err(e)) => step.stderr = e.to_string(),
+        Err(_) => {
+            step.status = "timeout".to_string();
+            step.stderr = format!("Step timed out after {} seconds", timeout_secs);
+        }
+    }
+    step
+}
+
+/// Run a native check until it reports the expected status
+async fn wait_for_native(step: &Step, native: &str) -> StepResult {
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(step.timeout_secs);
+    let interval = Duration::from_secs(step.retry_interval_secs.max(1));
+
+    loop {
+        let command = native.to_string();
+        let config = step.config.clone();
+        let outcome =
+            tokio::task::spawn_blocking(move || native_commands::execute_native(&command, &config))
+                .await
+                .unwrap_or_else(|e| Err(e.into()));
+        let (status, message) = match outcome {
+            Ok(result) => (result.status, result.message.unwrap_or_default()),
+            Err(e) => ("error".to_string(), e.to_string()),
+        };
+
+        let reached = status == step.expect;
+        if reached || Instant::now() + interval > deadline {
+            return StepResult {
+                name: step.name.clone(),
+                status: if reached { "completed" } else { "timeout" }.to_string(),
+                exit_code: None,
+                stdout: message,
+                stderr: String::new(),
+                duration_ms: start.elapsed().as_millis() as u64,
+                truncated: false,
+                rollback: false,
+            };
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn skipped(name: &str) -> StepResult {
+    StepResult {
+        name: name.to_string(),
+        status: "skipped".to_string(),
+        exit_code: None,
+        stdout: String::new(),
+        stderr: String::new(),
+        duration_ms: 0,
+        truncated: false,
+        rollback: false,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn pipeline(steps: serde_json::Value) -> Command {
+        Command {
+            id: "cmd-1".to_string(),
+            command_type: "pipeline".to_string(),
+            component_id: "app".to_string(),
+            action_name: None,
+            params: serde_json::json!({ "steps": steps }),
+            timeout_secs: 60,
+            trace_context: None,
+            correlation_id: None,
+            run_at: None,
+            run_after_secs: None,
+        }
+    }
+
+    fn statuses(result: &CommandResult) -> Vec<(String, String, bool)> {
+        result
+            .steps
+            .iter()
+            .map(|s| (s.name.clone(), s.status.clone(), s.rollback))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let jobs = JobSettings::default();
+
+        let cmd = pipeline(serde_json::json!([
+            {"name": "stop", "command": "echo stopped"},
+            {"name": "cache", "command": "exit 3", "on_failure": "continue"},
+            {"name": "start", "command": "echo started"},
+        ]));
+        let result = execute(&cmd, &jobs).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.steps[0].stdout, "stopped");
+        assert_eq!(result.steps[1].exit_code, Some(3));
+        assert_eq!(
+            result.stdout,
+            "stop: completed\ncache: failed\nstart: completed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_rollback() {
+        let jobs = JobSettings::default();
+
+        let cmd = pipeline(serde_json::json!([
+            {"name": "backup", "command": "true", "rollback": "echo restore"},
+            {"name": "plain", "command": "true"},
+            {"name": "deploy", "command": "exit 2", "rollback": "echo undeploy", "on_failure": "rollback"},
+            {"name": "verify", "command": "true"},
+        ]));
+        let result = execute(&cmd, &jobs).await.unwrap();
+        assert_eq!(result.exit_code, 2);
+        let expect = |name: &str, status: &str, rollback: bool| {
+            (name.to_string(), status.to_string(), rollback)
+        };
+        assert_eq!(
+            statuses(&result),
+            vec![
+                expect("backup", "completed", false),
+                expect("plain", "completed", false),
+                expect("deploy", "failed", false),
+                expect("deploy", "completed", true),
+                expect("backup", "completed", true),
+                expect("verify", "skipped", false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_native_wait() {
+        let jobs = JobSettings::default();
+
+        // Nothing listens on port 1: waiting for it to close succeeds at once
+        let cmd = pipeline(serde_json::json!([
+            {"name": "closed", "native": "tcp_port", "config": {"port": 1}, "expect": "error"},
+            {"name": "open", "native": "tcp_port", "config": {"port": 1}, "timeout_secs": 0},
+        ]));
+        let result = execute(&cmd, &jobs).await.unwrap();
+        assert_eq!(result.steps[0].status, "completed");
+        assert_eq!(result.steps[1].status, "timeout");
+        assert_ne!(result.exit_code, 0);
+
+        let invalid = pipeline(serde_json::json!([{"name": "nothing"}]));
+        assert!(execute(&invalid, &jobs).await.is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Process priority
+//!
+//! An action's `params.priority` sets how its process competes with the
+//! rest of the host, so a heavy maintenance job leaves room for the
+//! production workload next to it:
+//!
+//! ```json
+//! { "priority": { "nice": 10, "io_class": "idle", "oom_score_adj": 500 } }
+//! ```
+//!
+//! `nice` goes from -20 to 19, `io_class` is "realtime", "best-effort" or
+//! "idle" with `io_level` from 0 (highest) to 7, and `oom_score_adj` from
+//! -1000 to 1000. They are set in the child between fork and exec, before
+//! it changes user; raising priority (a negative nice or OOM score, the
+//! realtime class) needs a privileged agent. I/O class and OOM score are
+//! Linux only, and none of it is available on Windows.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Priority of an action's process
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Priority {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_class: Option<IoClass>,
+    /// Level within the I/O class; best-effort when no class is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_level: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oom_score_adj: Option<i32>,
+}
+
+/// I/O scheduling class, as `ionice -c` takes it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl Priority {
+    /// Read and check `params.priority`
+    pub fn from_params(params: &serde_json::Value) -> Result<Self> {
+        let priority: Self = match params.get("priority").filter(|v| !v.is_null()) {
+            Some(priority) => {
+                serde_json::from_value(priority.clone()).context("Invalid priority in params")?
+            }
+            None => return Ok(Self::default()),
+        };
+        priority.validate()?;
+        Ok(priority)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if cfg!(windows) {
+            bail!("Process priority is not supported on Windows");
+        }
+        if self.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+            bail!("priority.nice must be between -20 and 19");
+        }
+        if self.io_level.is_some_and(|level| level > 7) {
+            bail!("priority.io_level must be between 0 and 7");
+        }
+        if self.io_class == Some(IoClass::Idle) && self.io_level.is_some() {
+            bail!("priority.io_level does not apply to the idle I/O class");
+        }
+        if self
+            .oom_score_adj
+            .is_some_and(|adj| !(-1000..=1000).contains(&adj))
+        {
+            bail!("priority.oom_score_adj must be between -1000 and 1000");
+        }
+        let linux_only =
+            self.io_class.is_some() || self.io_level.is_some() || self.oom_score_adj.is_some();
+        if linux_only && !cfg!(target_os = "linux") {
+            bail!("priority.io_class, io_level and oom_score_adj need Linux");
+        }
+        Ok(())
+    }
+
+    /// Apply to the current process, in the child before exec
+    #[cfg(unix)]
+    pub fn apply(&self) -> std::io::Result<()> {
+        if let Some(nice) = self.nice {
+            // setpriority, unlike nice(), sets the value rather than adding to it
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.io_class.is_some() || self.io_level.is_some() {
+                self.set_io_priority()?;
+            }
+            if let Some(adj) = self.oom_score_adj {
+                std::fs::write("/proc/self/oom_score_adj", adj.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_io_priority(&self) -> std::io::Result<()> {
+        // From linux/ioprio.h
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+        let class = match self.io_class.unwrap_or(IoClass::BestEffort) {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        };
+        let level = self.io_level.unwrap_or(4) as libc::c_int;
+        let ioprio = (class << IOPRIO_CLASS_SHIFT) | level;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::super::{execute_with_output, shell, ActionEnv, Capture};
+    use super::*;
+
+    #[test]
+    fn test_priority_from_params() {
+        let params = serde_json::json!({
+            "priority": {"nice": 10, "io_class": "best-effort", "io_level": 6},
+        });
+        let priority = Priority::from_params(&params).unwrap();
+        assert_eq!(priority.nice, Some(10));
+        assert_eq!(priority.io_class, Some(IoClass::BestEffort));
+        assert!(Priority::from_params(&serde_json::json!({}))
+            .unwrap()
+            .is_empty());
+
+        for priority in [
+            serde_json::json!({"nice": 20}),
+            serde_json::json!({"io_class": "batch"}),
+            serde_json::json!({"io_level": 8}),
+            serde_json::json!({"io_class": "idle", "io_level": 0}),
+            serde_json::json!({"oom_score_adj": -1001}),
+        ] {
+            let params = serde_json::json!({ "priority": priority });
+            assert!(Priority::from_params(&params).is_err(), "{}", params);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority_applied() {
+        // Lowering priority needs no privilege
+        let params = serde_json::json!({
+            "priority": {"nice": 19, "io_class": "idle", "oom_score_adj": 1000},
+        });
+        let secrets = crate::secrets::Provider::File {
+            dir: "/nonexistent".to_string(),
+        };
+        let env = ActionEnv::from_params(&params, &secrets).await.unwrap();
+        let capture = || Capture::new(1024, None);
+        let output = execute_with_output(
+            shell("nice; cat /proc/self/oom_score_adj"),
+            &env,
+            capture(),
+            capture(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout.trim(), "19\n1000");
+    }
+}
@@ -0,0 +1,233 @@
+//! Concurrency limits for detached jobs
+//!
+//! At most `jobs.max_concurrent` detached jobs run at once, and
+//! `jobs.max_concurrent_per_component` for any one component. Commands
+//! beyond that wait in a FIFO queue of up to `jobs.max_queued`, and their
+//! "started" response carries their position in it. A job holds its slot
+//! until its process exits or the command's `timeout_secs` elapse, so an
+//! action that leaves a daemon running does not hold it forever.
+
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::JobSettings;
+use crate::connection::Command;
+
+/// What happens to a command asking for a slot
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    /// Run it now
+    Run,
+    /// Waiting, at this 1-based position
+    Queued(usize),
+}
+
+/// A running job
+struct Slot {
+    command_id: String,
+    component_id: String,
+    /// Not known until the process is spawned
+    pid: Option<u32>,
+    deadline: Instant,
+}
+
+struct Queue {
+    running: Vec<Slot>,
+    waiting: VecDeque<Command>,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue::new());
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            running: Vec::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn has_capacity(&self, component_id: &str, jobs: &JobSettings) -> bool {
+        let component = self
+            .running
+            .iter()
+            .filter(|slot| slot.component_id == component_id)
+            .count();
+        self.running.len() < jobs.max_concurrent && component < jobs.max_concurrent_per_component
+    }
+
+    fn take_slot(&mut self, cmd: &Command) {
+        self.running.push(Slot {
+            command_id: cmd.id.clone(),
+            component_id: cmd.component_id.clone(),
+            pid: None,
+            deadline: Instant::now() + Duration::from_secs(cmd.timeout_secs),
+        });
+    }
+
+    fn admit(&mut self, cmd: &Command, jobs: &JobSettings) -> Result<Admission> {
+        // Jobs waiting for the same component go first
+        let behind = self
+            .waiting
+            .iter()
+            .any(|waiting| waiting.component_id == cmd.component_id);
+        if !behind && self.has_capacity(&cmd.component_id, jobs) {
+            self.take_slot(cmd);
+            return Ok(Admission::Run);
+        }
+
+        if self.waiting.len() >= jobs.max_queued {
+            bail!(
+                "Job queue is full ({} waiting), command refused",
+                self.waiting.len()
+            );
+        }
+        self.waiting.push_back(cmd.clone());
+        Ok(Admission::Queued(self.waiting.len()))
+    }
+
+    fn started(&mut self, command_id: &str, pid: u32) {
+        if let Some(slot) = self.running.iter_mut().find(|s| s.command_id == command_id) {
+            slot.pid = Some(pid);
+        }
+    }
+
+    fn release(&mut self, command_id: &str) {
+        self.running.retain(|slot| slot.command_id != command_id);
+    }
+
+    /// Free the slots of jobs that exited or ran out of time
+    fn reap(&mut self) {
+        let now = Instant::now();
+        self.running
+            .retain(|slot| now < slot.deadline && slot.pid.is_none_or(super::process_alive));
+    }
+
+    /// Waiting commands that can now run, in queue order
+    fn next_ready(&mut self, jobs: &JobSettings) -> Vec<Command> {
+        self.reap();
+
+        let mut ready = Vec::new();
+        let mut waiting = VecDeque::new();
+        while let Some(cmd) = self.waiting.pop_front() {
+            // A component's jobs start in the order they came
+            let blocked = waiting
+                .iter()
+                .any(|w: &Command| w.component_id == cmd.component_id);
+            if !blocked && self.has_capacity(&cmd.component_id, jobs) {
+                self.take_slot(&cmd);
+                ready.push(cmd);
+            } else {
+                waiting.push_back(cmd);
+            }
+        }
+        self.waiting = waiting;
+        ready
+    }
+}
+
+/// Ask for a slot for a detached job; fails when the queue is full
+pub fn admit(cmd: &Command, jobs: &JobSettings) -> Result<Admission> {
+    QUEUE.lock().unwrap().admit(cmd, jobs)
+}
+
+/// Record the PID of a job, to free its slot when it exits
+pub fn started(command_id: &str, pid: u32) {
+    QUEUE.lock().unwrap().started(command_id, pid);
+}
+
+/// Free a job's slot, e.g. when it failed to spawn
+pub fn release(command_id: &str) {
+    QUEUE.lock().unwrap().release(command_id);
+}
+
+/// Take the queued commands that can run now; their slots are held
+pub fn next_ready(jobs: &JobSettings) -> Vec<Command> {
+    QUEUE.lock().unwrap().next_ready(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: &str, component_id: &str) -> Command {
+        Command {
+            id: id.to_string(),
+            command_type: "start".to_string(),
+            component_id: component_id.to_string(),
+            action_name: None,
+            params: serde_json::json!({}),
+            timeout_secs: 60,
+            trace_context: None,
+            correlation_id: None,
+            run_at: None,
+            run_after_secs: None,
+        }
+    }
+
+    fn jobs() -> JobSettings {
+        JobSettings {
+            max_concurrent: 2,
+            max_concurrent_per_component: 1,
+            max_queued: 2,
+            ..JobSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_admission() {
+        let mut queue = Queue::new();
+        let jobs = jobs();
+
+        assert_eq!(
+            queue.admit(&command("1", "web"), &jobs).unwrap(),
+            Admission::Run
+        );
+        // Same component
+        assert_eq!(
+            queue.admit(&command("2", "web"), &jobs).unwrap(),
+            Admission::Queued(1)
+        );
+        assert_eq!(
+            queue.admit(&command("3", "db"), &jobs).unwrap(),
+            Admission::Run
+        );
+        // Global limit
+        assert_eq!(
+            queue.admit(&command("4", "cache"), &jobs).unwrap(),
+            Admission::Queued(2)
+        );
+        assert!(queue.admit(&command("5", "mq"), &jobs).is_err());
+
+        assert!(queue.next_ready(&jobs).is_empty());
+
+        // "db" done: "2" still waits for "web", "4" takes the slot
+        queue.release("3");
+        let ready: Vec<String> = queue.next_ready(&jobs).into_iter().map(|c| c.id).collect();
+        assert_eq!(ready, ["4"]);
+
+        queue.release("1");
+        let ready: Vec<String> = queue.next_ready(&jobs).into_iter().map(|c| c.id).collect();
+        assert_eq!(ready, ["2"]);
+        assert!(queue.waiting.is_empty());
+    }
+
+    #[test]
+    fn test_expired_slot() {
+        let mut queue = Queue::new();
+        let jobs = jobs();
+
+        let mut daemon = command("1", "web");
+        daemon.timeout_secs = 0;
+        assert_eq!(queue.admit(&daemon, &jobs).unwrap(), Admission::Run);
+        assert_eq!(
+            queue.admit(&command("2", "web"), &jobs).unwrap(),
+            Admission::Queued(1)
+        );
+
+        let ready = queue.next_ready(&jobs);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].id, "2");
+    }
+}
@@ -0,0 +1,230 @@
+//! Scheduled commands
+//!
+//! A command with `run_at`, or `run_after_secs`, is answered "scheduled"
+//! instead of running when received. It is kept in `jobs.scheduled_file`,
+//! so it still runs at that time after an agent restart or while the
+//! Gateway is unreachable. A final response produced while disconnected is
+//! kept in the same file and sent after reconnecting.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::connection::{Command, CommandResponse};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Schedule {
+    #[serde(default)]
+    pending: Vec<Scheduled>,
+    #[serde(default)]
+    undelivered: Vec<CommandResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Scheduled {
+    due: DateTime<Utc>,
+    command: Command,
+}
+
+struct Store {
+    path: String,
+    schedule: Schedule,
+}
+
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+impl Schedule {
+    fn load(path: &str) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!(path = %path, error = %e, "Ignoring unreadable scheduled commands");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Write to a temporary file first so a crash never leaves half a file
+    fn save(&self, path: &str) {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(dir).ok();
+        }
+        let tmp = format!("{}.tmp", path);
+        let written = serde_json::to_vec(self)
+            .map_err(std::io::Error::from)
+            .and_then(|content| std::fs::write(&tmp, content))
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if let Err(e) = written {
+            warn!(path = %path, error = %e, "Failed to save scheduled commands");
+        }
+    }
+
+    /// Add a command, replacing one with the same id
+    fn add(&mut self, command: Command, due: DateTime<Utc>, max: usize) -> Result<()> {
+        self.pending.retain(|s| s.command.id != command.id);
+        if self.pending.len() >= max {
+            bail!(
+                "Too many scheduled commands (jobs.max_scheduled is {})",
+                max
+            );
+        }
+        self.pending.push(Scheduled { due, command });
+        Ok(())
+    }
+
+    /// Remove and return the commands due at `now`, earliest first
+    fn take_due(&mut self, now: DateTime<Utc>) -> Vec<Command> {
+        let (mut due, pending): (Vec<Scheduled>, Vec<Scheduled>) =
+            std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|s| s.due <= now);
+        self.pending = pending;
+        due.sort_by_key(|s| s.due);
+        due.into_iter().map(|s| s.command).collect()
+    }
+}
+
+/// Load what an earlier run left in the scheduled file
+pub fn load_schedule(path: &str) {
+    let schedule = Schedule::load(path);
+    if !schedule.pending.is_empty() || !schedule.undelivered.is_empty() {
+        info!(
+            pending = schedule.pending.len(),
+            undelivered = schedule.undelivered.len(),
+            "Loaded scheduled commands"
+        );
+    }
+    *STORE.lock().unwrap() = Some(Store {
+        path: path.to_string(),
+        schedule,
+    });
+}
+
+/// When a command asks to run, if not right away; an error for a delay
+/// past what a date can hold
+pub fn scheduled_time(cmd: &Command) -> Result<Option<DateTime<Utc>>> {
+    if cmd.run_at.is_some() {
+        return Ok(cmd.run_at);
+    }
+    let Some(secs) = cmd.run_after_secs else {
+        return Ok(None);
+    };
+    i64::try_from(secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .and_then(|delay| Utc::now().checked_add_signed(delay))
+        .map(Some)
+        .ok_or_else(|| anyhow!("run_after_secs {} is out of range", secs))
+}
+
+/// Keep a command until `due`
+pub fn schedule(cmd: Command, due: DateTime<Utc>, max: usize) -> Result<()> {
+    let mut store = STORE.lock().unwrap();
+    let store = match store.as_mut() {
+        Some(store) => store,
+        None => bail!("Scheduled commands are not available"),
+    };
+    store.schedule.add(cmd, due, max)?;
+    store.schedule.save(&store.path);
+    Ok(())
+}
+
+/// Scheduled commands whose time has come
+pub fn take_due() -> Vec<Command> {
+    let mut store = STORE.lock().unwrap();
+    match store.as_mut() {
+        Some(store) => {
+            let due = store.schedule.take_due(Utc::now());
+            if !due.is_empty() {
+                store.schedule.save(&store.path);
+            }
+            due
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Keep a final response that could not be sent
+pub fn hold_response(response: CommandResponse) {
+    if let Some(store) = STORE.lock().unwrap().as_mut() {
+        store.schedule.undelivered.push(response);
+        store.schedule.save(&store.path);
+    }
+}
+
+/// Responses waiting to be sent, removed from the file
+pub fn take_undelivered() -> Vec<CommandResponse> {
+    let mut store = STORE.lock().unwrap();
+    match store.as_mut() {
+        Some(store) if !store.schedule.undelivered.is_empty() => {
+            let undelivered = std::mem::take(&mut store.schedule.undelivered);
+            store.schedule.save(&store.path);
+            undelivered
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: &str) -> Command {
+        Command {
+            id: id.to_string(),
+            command_type: "restart".to_string(),
+            component_id: "app".to_string(),
+            action_name: Some("restart".to_string()),
+            params: serde_json::json!({}),
+            timeout_secs: 60,
+            trace_context: None,
+            correlation_id: None,
+            run_at: None,
+            run_after_secs: Some(60),
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        let now = Utc::now();
+        let at = |secs: i64| now + chrono::Duration::seconds(secs);
+
+        let mut schedule = Schedule::default();
+        schedule.add(command("late"), at(20), 3).unwrap();
+        schedule.add(command("early"), at(10), 3).unwrap();
+        schedule.add(command("later"), at(60), 3).unwrap();
+        // Same id again replaces it
+        schedule.add(command("later"), at(30), 3).unwrap();
+        assert!(schedule.add(command("more"), at(5), 3).is_err());
+
+        assert!(schedule.take_due(now).is_empty());
+        let due: Vec<String> = schedule
+            .take_due(at(25))
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(due, vec!["early", "late"]);
+
+        // Survives a restart
+        let path =
+            std::env::temp_dir().join(format!("opsmap-scheduled-{}.json", uuid::Uuid::new_v4()));
+        let path = path.to_string_lossy().to_string();
+        schedule.save(&path);
+        let mut loaded = Schedule::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.pending.len(), 1);
+        assert_eq!(loaded.take_due(at(30))[0].id, "later");
+
+        assert!(scheduled_time(&command("relative")).unwrap().unwrap() >= at(60));
+        let mut now_cmd = command("now");
+        now_cmd.run_after_secs = None;
+        assert!(scheduled_time(&now_cmd).unwrap().is_none());
+        let mut far = command("far");
+        far.run_after_secs = Some(u64::MAX / 2);
+        assert!(scheduled_time(&far).is_err());
+        far.run_after_secs = Some(i64::MAX as u64 / 1000);
+        assert!(scheduled_time(&far).is_err());
+    }
+}
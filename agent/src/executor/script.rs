@@ -0,0 +1,269 @@
+//! Inline scripts
+//!
+//! A "script" command carries its script in `params.script` instead of a
+//! command line, and names its interpreter in `params.interpreter`, one of
+//! `jobs.interpreters`. The agent writes the script to a temporary file only
+//! the user running it can read, runs the interpreter on that file with
+//! `params.args`, as `params.run_as_user` if set, and removes it afterwards.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::process::Command as TokioCommand;
+use uuid::Uuid;
+
+#[cfg(unix)]
+use super::unix::RunAs;
+use super::Priority;
+use crate::config::JobSettings;
+
+#[cfg(unix)]
+const DEFAULT_INTERPRETER: &str = "sh";
+#[cfg(windows)]
+const DEFAULT_INTERPRETER: &str = "powershell";
+
+/// A script written out for one run; the file is removed on drop
+pub struct Script {
+    pub interpreter: String,
+    /// Interpreter program and the arguments it takes before the file
+    program: Vec<String>,
+    path: PathBuf,
+    #[cfg(unix)]
+    run_as: Option<RunAs>,
+}
+
+impl Script {
+    pub fn write(params: &serde_json::Value, jobs: &JobSettings) -> Result<Self> {
+        let body = params
+            .get("script")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing script in params"))?;
+        let interpreter = params
+            .get("interpreter")
+            .and_then(|v| v.as_str())
+            .unwrap_or(DEFAULT_INTERPRETER);
+        let program: Vec<String> = jobs
+            .interpreters
+            .get(interpreter)
+            .ok_or_else(|| anyhow!("Unknown interpreter: {}", interpreter))?
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+        if program.is_empty() {
+            return Err(anyhow!("Empty command for interpreter {}", interpreter));
+        }
+        let run_as_user = params.get("run_as_user").and_then(|v| v.as_str());
+
+        #[cfg(unix)]
+        let run_as = run_as_user.map(RunAs::lookup).transpose()?;
+        #[cfg(windows)]
+        if let Some(user) = run_as_user {
+            return Err(anyhow!(
+                "run_as_user ({}) is not supported on Windows",
+                user
+            ));
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "opsmap-script-{}.{}",
+            Uuid::new_v4(),
+            extension(interpreter)
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o700);
+        }
+        let mut file = options
+            .open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        let script = Self {
+            interpreter: interpreter.to_string(),
+            program,
+            path,
+            #[cfg(unix)]
+            run_as,
+        };
+        file.write_all(body.as_bytes())
+            .context("Failed to write script")?;
+        #[cfg(unix)]
+        if let Some(ref run_as) = script.run_as {
+            nix::unistd::chown(&script.path, Some(run_as.uid), Some(run_as.gid))
+                .context("Failed to hand the script to run_as_user")?;
+        }
+        Ok(script)
+    }
+
+    /// The interpreter set to run the script with `args`, at `priority`
+    pub fn command(&self, args: &[&str], priority: Priority) -> TokioCommand {
+        let mut command = TokioCommand::new(&self.program[0]);
+        command.args(&self.program[1..]).arg(&self.path).args(args);
+        #[cfg(unix)]
+        {
+            let run_as = self.run_as.clone();
+            if let Some(ref run_as) = run_as {
+                command.envs(run_as.env());
+            }
+            // Priority while still privileged, then groups and ids after
+            // forking; uid() would drop supplementary groups
+            if run_as.is_some() || !priority.is_empty() {
+                unsafe {
+                    command.pre_exec(move || {
+                        priority.apply()?;
+                        match run_as {
+                            Some(ref run_as) => run_as.switch().map_err(std::io::Error::from),
+                            None => Ok(()),
+                        }
+                    });
+                }
+            }
+        }
+        #[cfg(windows)]
+        let _ = priority;
+        command
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// File extension the interpreter expects; Windows picks handlers by it
+fn extension(interpreter: &str) -> &'static str {
+    match interpreter {
+        "powershell" | "pwsh" => "ps1",
+        "cmd" => "cmd",
+        "python" | "python3" => "py",
+        _ => "sh",
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_script() {
+        let jobs = JobSettings::default();
+        let params = serde_json::json!({
+            "script": "#!/bin/sh\necho \"$1 from $(basename \"$0\" .sh | cut -c1-13)\"\n",
+            "interpreter": "sh",
+        });
+
+        let script = Script::write(&params, &jobs).unwrap();
+        let path = script.path.clone();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        let output = script
+            .command(&["hello"], Priority::default())
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "hello from opsmap-script"
+        );
+        drop(script);
+        assert!(!path.exists());
+
+        let unknown = serde_json::json!({"script": "x", "interpreter": "perl"});
+        assert!(Script::write(&unknown, &jobs).is_err());
+        assert!(Script::write(&serde_json::json!({}), &jobs).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_script_priority() {
+        let params = serde_json::json!({"script": "nice"});
+        let script = Script::write(&params, &JobSettings::default()).unwrap();
+        let priority = Priority {
+            nice: Some(19),
+            ..Default::default()
+        };
+        let output = script.command(&[], priority).output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "19");
+    }
+
+    /// A user and a supplementary group made for one test, removed on drop
+    #[cfg(target_os = "linux")]
+    struct ScratchUser {
+        name: String,
+        group: String,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl ScratchUser {
+        fn create() -> Option<Self> {
+            use std::process::Command;
+
+            let name = format!("opsmap-t{}", std::process::id());
+            let group = format!("{}-g", name);
+            let ok = |command: &mut Command| command.status().is_ok_and(|s| s.success());
+            if !ok(Command::new("groupadd").arg(&group)) {
+                return None;
+            }
+            let user = Self { name, group };
+            let created = ok(Command::new("useradd")
+                .args(["-M", "-d", "/nonexistent", "-s", "/bin/sh", "-G"])
+                .args([&user.group, &user.name]));
+            created.then_some(user)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Drop for ScratchUser {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("userdel")
+                .arg(&self.name)
+                .status();
+            let _ = std::process::Command::new("groupdel")
+                .arg(&self.group)
+                .status();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_script_run_as_user() {
+        // Switching users needs root, and a user of our own to switch to
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let Some(user) = ScratchUser::create() else {
+            return;
+        };
+        let run_as = RunAs::lookup(&user.name).unwrap();
+        let supplementary = nix::unistd::Group::from_name(&user.group)
+            .unwrap()
+            .unwrap()
+            .gid;
+        let params = serde_json::json!({
+            "script": "echo \"$(id -u) $(id -G) $HOME $USER $LOGNAME\"",
+            "run_as_user": user.name,
+        });
+
+        let script = Script::write(&params, &JobSettings::default()).unwrap();
+        let output = script
+            .command(&[], Priority::default())
+            .output()
+            .await
+            .unwrap();
+        // The user's primary and supplementary groups, none left over from root
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            format!(
+                "{} {} {} /nonexistent {} {}",
+                run_as.uid, run_as.gid, supplementary, user.name, user.name
+            )
+        );
+    }
+}
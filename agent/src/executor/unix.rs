@@ -0,0 +1,393 @@
+//! Process detachment on Unix: double fork, new session, reparented to init
+
+use anyhow::{anyhow, Context, Result};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, ForkResult};
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use tracing::{debug, error};
+
+use super::cgroup::{self, Confinement};
+use super::{ActionEnv, Program};
+use crate::config::{JobLimits, JobSettings};
+
+/// Spawn a completely detached process using double-fork
+///
+/// This is the CRITICAL function for process detachment.
+/// The spawned process will:
+/// 1. First fork -> intermediate child
+/// 2. setsid() -> new session (detach from terminal)
+/// 3. Second fork -> grandchild becomes orphan
+/// 4. Intermediate child exits -> grandchild reparented to init/systemd
+/// 5. Close ALL file descriptors
+/// 6. Redirect stdin/stdout/stderr to /dev/null or log file
+/// 7. Join the job's cgroup or systemd scope when it has resource limits
+/// 8. Take the action's priority, then its user
+///
+/// Returns the grandchild's PID, which the intermediate child reports
+/// through a pipe before exiting.
+pub fn spawn_detached(
+    program: &Program,
+    run_as_user: Option<&str>,
+    job_id: &str,
+    env: &ActionEnv,
+    limits: &JobLimits,
+    settings: &JobSettings,
+) -> Result<u32> {
+    // Log file for the detached process
+    std::fs::create_dir_all(super::job_log_dir()).ok();
+    let log_file = super::job_log_file(job_id);
+
+    // Look the user up and set up limits while errors can still be reported
+    let run_as = run_as_user.map(RunAs::lookup).transpose()?;
+    let confinement = cgroup::confine(limits, settings, job_id, run_as_user)?;
+    debug!(job_id = %job_id, confinement = ?confinement, "Job confinement");
+    // Masked before forking, the job log shows no secret
+    let shown_command = program.shown();
+
+    let (pid_read, pid_write) = unistd::pipe().context("Failed to create PID pipe")?;
+
+    // FIRST FORK
+    match unsafe { unistd::fork() } {
+        Ok(ForkResult::Parent { child }) => {
+            // Parent: wait for intermediate child to exit
+            debug!(
+                pid = child.as_raw(),
+                "First fork - waiting for intermediate child"
+            );
+            let _ = unistd::close(pid_write);
+            let _ = waitpid(child, None);
+
+            let mut pid = [0u8; 4];
+            unsafe { std::fs::File::from_raw_fd(pid_read) }
+                .read_exact(&mut pid)
+                .map_err(|_| anyhow!("Detached process was not started"))?;
+            return Ok(u32::from_ne_bytes(pid));
+        }
+        Ok(ForkResult::Child) => {
+            // Intermediate child: continue to second fork
+            let _ = unistd::close(pid_read);
+        }
+        Err(e) => {
+            let _ = unistd::close(pid_read);
+            let _ = unistd::close(pid_write);
+            return Err(anyhow!("First fork failed: {}", e));
+        }
+    }
+
+    // INTERMEDIATE CHILD
+    // Create new session - detach from terminal
+    if let Err(e) = unistd::setsid() {
+        error!(error = %e, "setsid failed");
+        std::process::exit(1);
+    }
+
+    // Ignore SIGHUP so the grandchild isn't killed when session leader exits
+    unsafe {
+        signal::signal(Signal::SIGHUP, signal::SigHandler::SigIgn).ok();
+    }
+
+    // SECOND FORK
+    match unsafe { unistd::fork() } {
+        Ok(ForkResult::Parent { child }) => {
+            // Intermediate child: report the grandchild and exit immediately
+            // This orphans the grandchild, which gets reparented to init
+            let pid = (child.as_raw() as u32).to_ne_bytes();
+            let _ = unsafe { std::fs::File::from_raw_fd(pid_write) }.write_all(&pid);
+            std::process::exit(0);
+        }
+        Ok(ForkResult::Child) => {
+            // Grandchild: this is the actual detached process
+        }
+        Err(e) => {
+            error!(error = %e, "Second fork failed");
+            std::process::exit(1);
+        }
+    }
+
+    // GRANDCHILD (detached process)
+
+    // Close all file descriptors
+    close_all_fds();
+
+    // Redirect stdin/stdout/stderr
+    redirect_std_streams(&log_file);
+
+    // Change to the action's directory, or root to avoid holding mount points
+    let _ = unistd::chdir(env.cwd.as_deref().unwrap_or("/"));
+
+    // The user's own HOME and the like, which the action's env may override
+    if let Some(ref run_as) = run_as {
+        for (name, value) in run_as.env() {
+            std::env::set_var(name, value);
+        }
+    }
+    for (name, value) in &env.vars {
+        std::env::set_var(name, value);
+    }
+
+    // Clear umask
+    let _ = nix::sys::stat::umask(nix::sys::stat::Mode::empty());
+
+    // Stay behind as the supervisor, waiting for the job to record its exit
+    if settings.track_exit {
+        match unsafe { unistd::fork() } {
+            Ok(ForkResult::Parent { child }) => supervise(child, job_id),
+            Ok(ForkResult::Child) => {}
+            Err(e) => eprintln!(
+                "Supervisor fork failed, exit status will not be recorded: {}",
+                e
+            ),
+        }
+    }
+
+    // Join the job cgroup while still privileged
+    if let Confinement::Cgroup(ref dir) = confinement {
+        if let Err(e) = cgroup::join(dir) {
+            eprintln!("Failed to join cgroup {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    // Priority while still privileged, inherited through systemd-run
+    if let Err(e) = env.priority.apply() {
+        eprintln!("Failed to set process priority: {}", e);
+        std::process::exit(1);
+    }
+
+    // Change user if specified (systemd-run does it for scopes)
+    if let Some(ref run_as) = run_as {
+        if !matches!(confinement, Confinement::Scope(_)) {
+            if let Err(e) = run_as.switch() {
+                eprintln!("Failed to switch user to {}: {}", run_as.name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // A command line goes through sh -c for better compatibility, or the
+    // user's login shell so their profile sets up the environment
+    let argv: Vec<CString> = match program {
+        Program::Shell(command_line) => {
+            let (sh, sh_c) = match run_as {
+                Some(ref run_as) if settings.login_shell => (
+                    CString::new(run_as.shell.as_str()).expect("CString::new failed"),
+                    CString::new("-lc").unwrap(),
+                ),
+                _ => (
+                    CString::new("/bin/sh").unwrap(),
+                    CString::new("-c").unwrap(),
+                ),
+            };
+            vec![
+                sh,
+                sh_c,
+                CString::new(command_line.as_str()).expect("CString::new failed"),
+            ]
+        }
+        Program::Exec(argv) => argv
+            .iter()
+            .map(|arg| CString::new(arg.as_str()).expect("CString::new failed"))
+            .collect(),
+    };
+
+    // Log start
+    eprintln!(
+        "[{}] Starting command: {}",
+        chrono::Utc::now(),
+        shown_command
+    );
+
+    // execvp replaces the current process
+    match confinement {
+        Confinement::Scope(ref scope) => {
+            let mut scoped: Vec<CString> = scope
+                .iter()
+                .map(|arg| CString::new(arg.as_str()).expect("CString::new failed"))
+                .collect();
+            scoped.extend(argv);
+            let _ = unistd::execvp(&scoped[0], &scoped);
+        }
+        _ => {
+            let _ = unistd::execvp(&argv[0], &argv);
+        }
+    }
+
+    // If we get here, exec failed
+    eprintln!("exec failed");
+    std::process::exit(1);
+}
+
+/// Wait for a detached job and record how it ended, then exit
+fn supervise(child: unistd::Pid, job_id: &str) -> ! {
+    let (exit_code, signal) = loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => break (Some(code), None),
+            Ok(WaitStatus::Signaled(_, signal, _)) => break (None, Some(signal as i32)),
+            Ok(_) | Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => break (None, None),
+        }
+    };
+    eprintln!(
+        "[{}] Job ended: exit code {:?}, signal {:?}",
+        chrono::Utc::now(),
+        exit_code,
+        signal
+    );
+    super::exits::record_exit(job_id, exit_code, signal);
+    std::process::exit(0);
+}
+
+/// Whether the process is still running; an exited job not yet reaped by
+/// init counts as finished
+pub fn process_alive(pid: u32) -> bool {
+    let pid = unistd::Pid::from_raw(pid as i32);
+    if signal::kill(pid, None).is_err() {
+        return false;
+    }
+    // Field 3 of /proc/<pid>/stat is the state, Z for a zombie
+    #[cfg(target_os = "linux")]
+    if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        let state = stat.rsplit_once(") ").map(|(_, rest)| rest.chars().next());
+        return state != Some(Some('Z'));
+    }
+    true
+}
+
+/// Close all file descriptors except stdin/stdout/stderr
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+fn close_all_fds() {
+    // /dev/fd only lists 0-2 unless fdescfs is mounted; closefrom needs no listing
+    unsafe {
+        libc::closefrom(3);
+    }
+}
+
+/// Close all file descriptors except stdin/stdout/stderr
+#[cfg(not(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+fn close_all_fds() {
+    use std::os::unix::io::RawFd;
+
+    // Get max fd from /proc/self/fd (/dev/fd on macOS) or use a reasonable default
+    #[cfg(target_os = "linux")]
+    let fd_dir = "/proc/self/fd";
+    #[cfg(not(target_os = "linux"))]
+    let fd_dir = "/dev/fd";
+
+    let max_fd = std::fs::read_dir(fd_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().to_str().and_then(|s| s.parse::<RawFd>().ok()))
+                .max()
+                .unwrap_or(1024)
+        })
+        .unwrap_or(1024);
+
+    // Close all fds above stderr
+    for fd in 3..=max_fd {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+/// Redirect stdin/stdout/stderr to log file
+fn redirect_std_streams(log_file: &str) {
+    use std::os::unix::io::AsRawFd;
+
+    // Open /dev/null for stdin
+    let dev_null = std::fs::File::open("/dev/null").ok();
+    if let Some(f) = dev_null {
+        unsafe {
+            libc::dup2(f.as_raw_fd(), 0);
+        }
+    }
+
+    // Open log file for stdout/stderr
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .ok();
+
+    if let Some(f) = log {
+        let fd = f.as_raw_fd();
+        unsafe {
+            libc::dup2(fd, 1); // stdout
+            libc::dup2(fd, 2); // stderr
+        }
+    }
+}
+
+/// A user to run commands as, looked up before forking
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub name: String,
+    pub uid: unistd::Uid,
+    pub gid: unistd::Gid,
+    pub home: String,
+    pub shell: String,
+}
+
+impl RunAs {
+    pub fn lookup(username: &str) -> Result<Self> {
+        let user = unistd::User::from_name(username)
+            .context("Failed to lookup user")?
+            .ok_or_else(|| anyhow!("User not found: {}", username))?;
+        let shell = user.shell.to_string_lossy().to_string();
+        Ok(Self {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            home: user.dir.to_string_lossy().to_string(),
+            shell: if shell.is_empty() {
+                "/bin/sh".to_string()
+            } else {
+                shell
+            },
+        })
+    }
+
+    /// Environment of a login as this user, as su and login set it
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let path = if self.uid.is_root() {
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+        } else {
+            "/usr/local/bin:/usr/bin:/bin"
+        };
+        vec![
+            ("HOME", self.home.clone()),
+            ("USER", self.name.clone()),
+            ("LOGNAME", self.name.clone()),
+            ("SHELL", self.shell.clone()),
+            ("PATH", path.to_string()),
+        ]
+    }
+
+    /// Take on this user's groups, supplementary ones included, then uid
+    pub fn switch(&self) -> nix::Result<()> {
+        let name = CString::new(self.name.as_str()).map_err(|_| nix::Error::EINVAL)?;
+        #[cfg(not(target_os = "macos"))]
+        unistd::initgroups(&name, self.gid)?;
+        #[cfg(target_os = "macos")]
+        if unsafe { libc::initgroups(name.as_ptr(), self.gid.as_raw() as libc::c_int) } != 0 {
+            return Err(nix::Error::last());
+        }
+        // Group first, while still allowed to
+        unistd::setgid(self.gid)?;
+        unistd::setuid(self.uid)
+    }
+}
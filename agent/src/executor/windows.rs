@@ -0,0 +1,261 @@
+//! Process detachment on Windows
+//!
+//! There is no fork: the command is started through `cmd /C` as a detached
+//! process in its own process group, so it gets no console and no Ctrl+C
+//! from the agent. Services run inside a job object that is typically set to
+//! kill its processes when the service stops; the command breaks away from
+//! that job so it survives an agent restart.
+//!
+//! Resource limits put the command in a job object of its own, created
+//! without kill-on-close so it outlives the agent's handle: `memory_mb` caps
+//! the job's committed memory, `pids_max` its processes (threads are not
+//! counted) and `cpu_percent` is a hard cap on its CPU rate. The command
+//! starts suspended and only runs once it is in the job, so nothing it
+//! starts escapes the limits.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::OpenOptions;
+use std::os::windows::io::AsRawHandle;
+use std::os::windows::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use tracing::debug;
+
+use super::{ActionEnv, Program};
+use crate::config::{JobLimits, JobSettings};
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JobObjectCpuRateControlInformation,
+    JobObjectExtendedLimitInformation, SetInformationJobObject,
+    JOBOBJECT_CPU_RATE_CONTROL_INFORMATION, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_CPU_RATE_CONTROL_ENABLE, JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+    JOB_OBJECT_LIMIT_ACTIVE_PROCESS, JOB_OBJECT_LIMIT_JOB_MEMORY,
+};
+use windows_sys::Win32::System::Threading::{
+    OpenThread, ResumeThread, CREATE_BREAKAWAY_FROM_JOB, CREATE_NEW_PROCESS_GROUP,
+    CREATE_SUSPENDED, DETACHED_PROCESS, THREAD_SUSPEND_RESUME,
+};
+
+/// Closes a kernel handle on drop
+struct Handle(HANDLE);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// Start a detached process writing to the job's log file, returning its PID
+///
+/// `run_as_user` is not supported: it would need the user's credentials,
+/// which the agent does not hold. Neither is priority.
+pub fn spawn_detached(
+    program: &Program,
+    run_as_user: Option<&str>,
+    job_id: &str,
+    env: &ActionEnv,
+    limits: &JobLimits,
+    _settings: &JobSettings,
+) -> Result<u32> {
+    if let Some(user) = run_as_user {
+        return Err(anyhow!(
+            "run_as_user ({}) is not supported on Windows",
+            user
+        ));
+    }
+    if !env.priority.is_empty() {
+        return Err(anyhow!("Process priority is not supported on Windows"));
+    }
+
+    std::fs::create_dir_all(super::job_log_dir()).ok();
+    let log_file = super::job_log_file(job_id);
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_file)
+        .with_context(|| format!("Failed to open {}", log_file))?;
+
+    let cwd = match env.cwd {
+        Some(ref cwd) => cwd.clone(),
+        None => std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\".to_string()),
+    };
+
+    let spawn = |flags: u32| -> std::io::Result<Child> {
+        let mut command = match program {
+            Program::Shell(command_line) => {
+                let mut command = Command::new("cmd");
+                command.arg("/C").raw_arg(command_line);
+                command
+            }
+            Program::Exec(argv) => {
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+        };
+        command
+            .current_dir(&cwd)
+            .envs(&env.vars)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log.try_clone()?)
+            .creation_flags(flags)
+            .spawn()
+    };
+
+    let mut flags = DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP;
+    if !limits.is_empty() {
+        flags |= CREATE_SUSPENDED;
+    }
+    // Breaking away fails if the agent's job does not allow it; the process
+    // is then still detached from the console, but tied to the job (its own
+    // job, for limits, is nested in it)
+    let mut child = spawn(flags | CREATE_BREAKAWAY_FROM_JOB)
+        .or_else(|e| {
+            debug!(error = %e, "Could not break away from the agent's job object");
+            spawn(flags)
+        })
+        .context("Failed to start detached process")?;
+    let pid = child.id();
+
+    if !limits.is_empty() {
+        if let Err(e) = limit(&child, limits) {
+            let _ = child.kill();
+            return Err(e.context("Failed to apply job resource limits"));
+        }
+    }
+    // Dropping the handle does not affect the process
+
+    debug!(pid = pid, job_id = %job_id, "Started detached process");
+    Ok(pid)
+}
+
+/// Put a suspended process in a new job object enforcing `limits`, then
+/// resume it
+fn limit(child: &Child, limits: &JobLimits) -> Result<()> {
+    let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+    if job == 0 {
+        bail!(
+            "Failed to create a job object: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let job = Handle(job);
+
+    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+    if let Some(memory_mb) = limits.memory_mb {
+        info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_JOB_MEMORY;
+        info.JobMemoryLimit = (memory_mb * 1024 * 1024) as usize;
+    }
+    if let Some(pids_max) = limits.pids_max {
+        info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_ACTIVE_PROCESS;
+        info.BasicLimitInformation.ActiveProcessLimit = pids_max.min(u32::MAX as u64) as u32;
+    }
+    if info.BasicLimitInformation.LimitFlags != 0 {
+        set_information(
+            &job,
+            JobObjectExtendedLimitInformation,
+            &info,
+            "memory and process limits",
+        )?;
+    }
+
+    if let Some(cpu_percent) = limits.cpu_percent {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+        let mut rate: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION = unsafe { std::mem::zeroed() };
+        rate.ControlFlags =
+            JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+        // In hundredths of a percent of all the CPUs
+        rate.Anonymous.CpuRate = (cpu_percent.saturating_mul(100) / cpus).clamp(1, 10_000);
+        set_information(&job, JobObjectCpuRateControlInformation, &rate, "CPU limit")?;
+    }
+
+    if unsafe { AssignProcessToJobObject(job.0, child.as_raw_handle() as HANDLE) } == 0 {
+        bail!(
+            "Failed to assign the process to its job object: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    resume(child.id())
+}
+
+fn set_information<T>(job: &Handle, class: i32, info: &T, what: &str) -> Result<()> {
+    let set = unsafe {
+        SetInformationJobObject(
+            job.0,
+            class,
+            info as *const T as *const std::ffi::c_void,
+            std::mem::size_of::<T>() as u32,
+        )
+    };
+    if set == 0 {
+        bail!(
+            "Failed to set the job's {}: {}",
+            what,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Resume the only thread of a process started suspended
+fn resume(pid: u32) -> Result<()> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        bail!(
+            "Failed to list threads: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let snapshot = Handle(snapshot);
+
+    let mut entry: THREADENTRY32 = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+    let mut more = unsafe { Thread32First(snapshot.0, &mut entry) } != 0;
+    while more {
+        if entry.th32OwnerProcessID == pid {
+            let thread = unsafe { OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID) };
+            if thread == 0 {
+                bail!(
+                    "Failed to open thread of process {}: {}",
+                    pid,
+                    std::io::Error::last_os_error()
+                );
+            }
+            let thread = Handle(thread);
+            if unsafe { ResumeThread(thread.0) } == u32::MAX {
+                bail!(
+                    "Failed to resume process {}: {}",
+                    pid,
+                    std::io::Error::last_os_error()
+                );
+            }
+            return Ok(());
+        }
+        more = unsafe { Thread32Next(snapshot.0, &mut entry) } != 0;
+    }
+    bail!("No thread found for process {}", pid)
+}
+
+/// Whether the process is still running
+pub fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::STILL_ACTIVE;
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle == 0 {
+            return false;
+        }
+        let mut code = 0u32;
+        let alive = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE as u32;
+        CloseHandle(handle);
+        alive
+    }
+}
@@ -0,0 +1,204 @@
+//! Host facts
+//!
+//! Label values may reference facts about the host as `${fact:NAME}`, so
+//! routing at the Gateway can use what the host really is rather than
+//! hand-edited YAML:
+//!
+//! ```yaml
+//! labels:
+//!   datacenter: "${fact:dmi.chassis_asset_tag}"
+//!   kernel: "${fact:kernel_version}"
+//!   os: "${fact:os_name}-${fact:os_version}"
+//! ```
+//!
+//! Facts are `hostname`, `os` and `arch` (as Rust names them), `os_name`,
+//! `os_version`, `kernel_version`, `cpu_count`, `memory_mb`, `machine_id`,
+//! and `dmi.FIELD` for any file in `/sys/class/dmi/id` (Linux). They are
+//! read each time the labels are sent, so a label follows a kernel upgrade
+//! without a config change. A label whose facts the host does not have,
+//! or only as a vendor placeholder, is left out.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use sysinfo::System;
+use tracing::debug;
+
+const OPEN: &str = "${fact:";
+const CLOSE: &str = "}";
+
+/// Facts other than `dmi.FIELD`
+const NAMES: [&str; 9] = [
+    "hostname",
+    "os",
+    "arch",
+    "os_name",
+    "os_version",
+    "kernel_version",
+    "cpu_count",
+    "memory_mb",
+    "machine_id",
+];
+
+/// What firmware reports for a field nobody filled in
+const PLACEHOLDERS: [&str; 5] = [
+    "Not Specified",
+    "Not Applicable",
+    "Default string",
+    "To be filled by O.E.M.",
+    "None",
+];
+
+/// Whether `text` references a fact
+pub fn has_references(text: &str) -> bool {
+    text.contains(OPEN)
+}
+
+/// The value of fact `name`, or `None` when the host does not have it
+pub fn get(name: &str) -> Result<Option<String>> {
+    let value = match name {
+        "hostname" => hostname::get()
+            .ok()
+            .map(|h| h.to_string_lossy().to_string()),
+        "os" => Some(std::env::consts::OS.to_string()),
+        "arch" => Some(std::env::consts::ARCH.to_string()),
+        "os_name" => System::name(),
+        "os_version" => System::os_version(),
+        "kernel_version" => System::kernel_version(),
+        "cpu_count" => std::thread::available_parallelism()
+            .ok()
+            .map(|n| n.to_string()),
+        "memory_mb" => {
+            let mut sys = System::new();
+            sys.refresh_memory();
+            Some((sys.total_memory() / (1024 * 1024)).to_string())
+        }
+        "machine_id" => std::fs::read_to_string("/etc/machine-id").ok(),
+        _ => match name.strip_prefix("dmi.") {
+            Some(field) if valid_dmi_field(field) => {
+                std::fs::read_to_string(format!("/sys/class/dmi/id/{}", field))
+                    .ok()
+                    .filter(|value| !PLACEHOLDERS.contains(&value.trim()))
+            }
+            _ => bail!("Unknown fact: {:?}", name),
+        },
+    };
+    Ok(value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty()))
+}
+
+fn valid_dmi_field(field: &str) -> bool {
+    !field.is_empty()
+        && field
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Check that `text` only references known facts
+pub fn check(text: &str) -> Result<()> {
+    expand_with(text, |name| {
+        known(name)?;
+        Ok(Some(String::new()))
+    })
+    .map(|_| ())
+}
+
+fn known(name: &str) -> Result<()> {
+    let dmi = name.strip_prefix("dmi.").is_some_and(valid_dmi_field);
+    if !dmi && !NAMES.contains(&name) {
+        bail!("Unknown fact: {:?}", name);
+    }
+    Ok(())
+}
+
+/// `text` with its fact references replaced, or `None` when a fact is
+/// missing on this host
+pub fn expand(text: &str) -> Result<Option<String>> {
+    expand_with(text, get)
+}
+
+fn expand_with(text: &str, get: impl Fn(&str) -> Result<Option<String>>) -> Result<Option<String>> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(pos) = rest.find(OPEN) {
+        output.push_str(&rest[..pos]);
+        let after = &rest[pos + OPEN.len()..];
+        let end = after
+            .find(CLOSE)
+            .ok_or_else(|| anyhow!("Unterminated fact reference in {:?}", text))?;
+        match get(&after[..end])? {
+            Some(value) => output.push_str(&value),
+            None => return Ok(None),
+        }
+        rest = &after[end + CLOSE.len()..];
+    }
+
+    output.push_str(rest);
+    Ok(Some(output))
+}
+
+/// Labels with their fact references replaced
+pub fn labels(labels: &HashMap<String, String>) -> HashMap<String, String> {
+    labels
+        .iter()
+        .filter_map(|(key, value)| {
+            if !has_references(value) {
+                return Some((key.clone(), value.clone()));
+            }
+            match expand(value) {
+                Ok(Some(value)) => Some((key.clone(), value)),
+                Ok(None) => {
+                    debug!(label = %key, "Label left out, a fact it uses is missing");
+                    None
+                }
+                Err(e) => {
+                    debug!(label = %key, error = %e, "Label left out");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let facts = |name: &str| -> Result<Option<String>> {
+            known(name)?;
+            Ok(match name {
+                "os_name" => Some("Debian".to_string()),
+                "os_version" => Some("12".to_string()),
+                _ => None,
+            })
+        };
+
+        assert_eq!(
+            expand_with("${fact:os_name}-${fact:os_version}", facts).unwrap(),
+            Some("Debian-12".to_string())
+        );
+        assert_eq!(
+            expand_with("dc-${fact:dmi.chassis_asset_tag}", facts).unwrap(),
+            None
+        );
+        assert_eq!(
+            expand_with("plain", facts).unwrap(),
+            Some("plain".to_string())
+        );
+        assert!(expand_with("${fact:kernel}", facts).is_err());
+        assert!(expand_with("${fact:os_name", facts).is_err());
+
+        assert!(check("${fact:dmi.sys_vendor} ${fact:cpu_count}").is_ok());
+        assert!(check("${fact:dmi.../../etc/shadow}").is_err());
+
+        let labels = labels(&HashMap::from([
+            ("arch".to_string(), "${fact:arch}".to_string()),
+            ("role".to_string(), "database".to_string()),
+        ]));
+        assert_eq!(labels["arch"], std::env::consts::ARCH);
+        assert_eq!(labels["role"], "database");
+    }
+}
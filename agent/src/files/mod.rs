@@ -0,0 +1,390 @@
+//! File transfer
+//!
+//! `file_push` and `file_fetch` move files over the existing Gateway
+//! connection, one command per chunk of at most `files.chunk_bytes`, with
+//! data base64-encoded in the params and the result's stdout.
+//!
+//! A push sends its chunks in order, each with the file's total `size` and
+//! SHA-256:
+//!
+//! ```json
+//! { "command_type": "file_push", "params": { "path": "/etc/app/app.conf", "offset": 0, "size": 2048, "sha256": "9f86...", "data": "W2FwcF0K..." } }
+//! ```
+//!
+//! Chunks go to `<path>.opsmap-part`; once `size` bytes arrived the
+//! checksum is verified and the file renamed into place. A fetch asks for
+//! `offset` and `length` and gets back the chunk, the file's size, and its
+//! SHA-256 with the last chunk.
+//!
+//! Only files under `files.writable_dirs` can be pushed and under
+//! `files.readable_dirs` fetched, after resolving symlinks, and neither may
+//! exceed `files.max_file_bytes`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use ring::digest::{Context as Digest, SHA256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::config::FileSettings;
+use crate::connection::{Command, CommandResult};
+
+/// Suffix of a file being pushed
+const PART_SUFFIX: &str = ".opsmap-part";
+
+/// Run a `file_push` or `file_fetch` command
+pub fn execute_command(cmd: &Command, settings: &FileSettings) -> Result<CommandResult> {
+    let path = cmd
+        .params
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing path in params"))?;
+    let offset = cmd
+        .params
+        .get("offset")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+
+    let reply = match cmd.command_type.as_str() {
+        "file_push" => push(&cmd.params, path, offset, settings)?,
+        "file_fetch" => fetch(&cmd.params, path, offset, settings)?,
+        other => bail!("Unknown file command: {}", other),
+    };
+
+    Ok(CommandResult {
+        exit_code: 0,
+        stdout: reply.to_string(),
+        stderr: String::new(),
+        duration_ms: 0,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+fn push(
+    params: &serde_json::Value,
+    path: &str,
+    offset: u64,
+    settings: &FileSettings,
+) -> Result<serde_json::Value> {
+    let size = params
+        .get("size")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow!("Missing size in params"))?;
+    let sha256 = params
+        .get("sha256")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing sha256 in params"))?;
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(
+            params
+                .get("data")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default(),
+        )
+        .context("Chunk data is not valid base64")?;
+
+    if size > settings.max_file_bytes {
+        bail!(
+            "File of {} bytes exceeds files.max_file_bytes ({})",
+            size,
+            settings.max_file_bytes
+        );
+    }
+    if data.len() > settings.chunk_bytes {
+        bail!(
+            "Chunk of {} bytes exceeds files.chunk_bytes ({})",
+            data.len(),
+            settings.chunk_bytes
+        );
+    }
+    let received = offset
+        .checked_add(data.len() as u64)
+        .filter(|end| *end <= size)
+        .ok_or_else(|| anyhow!("Chunk ends past the declared size of {} bytes", size))?;
+
+    let target = allowed_target(path, &settings.writable_dirs)?;
+    let part = PathBuf::from(format!("{}{}", target.display(), PART_SUFFIX));
+    if fs::symlink_metadata(&part).is_ok_and(|m| m.file_type().is_symlink()) {
+        bail!("Refusing to write through symlink {}", part.display());
+    }
+
+    // The first chunk starts the file over, later ones must follow on;
+    // neither follows a symlink put in place of the part file
+    let mut options = OpenOptions::new();
+    if offset == 0 {
+        options.write(true).create(true).truncate(true);
+    } else {
+        options.append(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = if offset == 0 {
+        options
+            .open(&part)
+            .with_context(|| format!("Failed to create {}", part.display()))?
+    } else {
+        let file = options
+            .open(&part)
+            .map_err(|_| anyhow!("No transfer in progress for {}", path))?;
+        let received = file.metadata()?.len();
+        if received != offset {
+            bail!("Expected the chunk at offset {}, got {}", received, offset);
+        }
+        file
+    };
+    file.write_all(&data)?;
+
+    let complete = received == size;
+    if complete {
+        drop(file);
+        let actual = file_sha256(&part)?;
+        if !actual.eq_ignore_ascii_case(sha256) {
+            let _ = fs::remove_file(&part);
+            bail!("Checksum mismatch for {}: got {}", path, actual);
+        }
+        fs::rename(&part, &target)
+            .with_context(|| format!("Failed to move {} into place", part.display()))?;
+        info!(path = %target.display(), size = size, "File received");
+    }
+
+    Ok(serde_json::json!({
+        "path": target,
+        "received": received,
+        "complete": complete,
+    }))
+}
+
+fn fetch(
+    params: &serde_json::Value,
+    path: &str,
+    offset: u64,
+    settings: &FileSettings,
+) -> Result<serde_json::Value> {
+    let length = params
+        .get("length")
+        .and_then(|v| v.as_u64())
+        .map_or(settings.chunk_bytes, |length| {
+            (length as usize).min(settings.chunk_bytes)
+        });
+
+    let resolved = fs::canonicalize(path).with_context(|| format!("Cannot read {}", path))?;
+    if !is_within(&resolved, &settings.readable_dirs) {
+        bail!("{} is not under files.readable_dirs", path);
+    }
+    let mut file = File::open(&resolved)?;
+    let size = file.metadata()?.len();
+    if size > settings.max_file_bytes {
+        bail!(
+            "File of {} bytes exceeds files.max_file_bytes ({})",
+            size,
+            settings.max_file_bytes
+        );
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(length as u64).read_to_end(&mut data)?;
+    let end = offset
+        .checked_add(data.len() as u64)
+        .ok_or_else(|| anyhow!("Offset {} is out of range", offset))?;
+    let eof = end >= size;
+
+    let mut reply = serde_json::json!({
+        "path": resolved,
+        "offset": offset,
+        "size": size,
+        "data": base64::engine::general_purpose::STANDARD.encode(&data),
+        "eof": eof,
+    });
+    if eof {
+        reply["sha256"] = file_sha256(&resolved)?.into();
+    }
+    Ok(reply)
+}
+
+/// Resolve where a pushed file goes: its directory must exist under one of
+/// `dirs`, and the file itself must not be a symlink
+fn allowed_target(path: &str, dirs: &[String]) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        bail!("Path must be absolute: {}", path.display());
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    let parent = fs::canonicalize(parent)
+        .with_context(|| format!("Directory of {} does not exist", path.display()))?;
+    if !is_within(&parent, dirs) {
+        bail!("{} is not under files.writable_dirs", path.display());
+    }
+
+    let target = parent.join(name);
+    if fs::symlink_metadata(&target).is_ok_and(|m| m.file_type().is_symlink()) {
+        bail!("Refusing to replace symlink {}", target.display());
+    }
+    Ok(target)
+}
+
+/// Whether a resolved path is inside one of `dirs`
+fn is_within(path: &Path, dirs: &[String]) -> bool {
+    dirs.iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir))
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut digest = Digest::new(&SHA256);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    Ok(digest
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(command_type: &str, params: serde_json::Value) -> Command {
+        Command {
+            id: "cmd-1".to_string(),
+            command_type: command_type.to_string(),
+            component_id: String::new(),
+            action_name: None,
+            params,
+            timeout_secs: 60,
+            trace_context: None,
+            correlation_id: None,
+            run_at: None,
+            run_after_secs: None,
+        }
+    }
+
+    fn encode(data: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(data)
+    }
+
+    #[test]
+    fn test_push_and_fetch() {
+        let dir = std::env::temp_dir().join(format!("opsmap-files-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_string_lossy().to_string();
+        let settings = FileSettings {
+            readable_dirs: vec![dir_str.clone()],
+            writable_dirs: vec![dir_str.clone()],
+            max_file_bytes: 1024,
+            chunk_bytes: 4,
+        };
+
+        let content = b"hello world";
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let path = dir.join("app.conf").to_string_lossy().to_string();
+        let chunk = |offset: usize| {
+            let end = (offset + 4).min(content.len());
+            command(
+                "file_push",
+                serde_json::json!({
+                    "path": path, "offset": offset, "size": content.len(),
+                    "sha256": sha256, "data": encode(&content[offset..end]),
+                }),
+            )
+        };
+
+        execute_command(&chunk(0), &settings).unwrap();
+        // Out of order
+        assert!(execute_command(&chunk(8), &settings).is_err());
+        execute_command(&chunk(4), &settings).unwrap();
+        let last = execute_command(&chunk(8), &settings).unwrap();
+        let last: serde_json::Value = serde_json::from_str(&last.stdout).unwrap();
+        assert_eq!(last["complete"], true);
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        let fetched = execute_command(
+            &command("file_fetch", serde_json::json!({"path": path, "offset": 8})),
+            &settings,
+        )
+        .unwrap();
+        let fetched: serde_json::Value = serde_json::from_str(&fetched.stdout).unwrap();
+        assert_eq!(fetched["data"], encode(b"rld"));
+        assert_eq!(fetched["eof"], true);
+        assert_eq!(fetched["sha256"], sha256);
+
+        // Outside the allowed directories
+        let outside = command("file_fetch", serde_json::json!({"path": "/etc/hostname"}));
+        assert!(execute_command(&outside, &settings).is_err());
+        let escape = format!("{}/../escape.conf", dir_str);
+        let mut push = chunk(0);
+        push.params["path"] = escape.into();
+        assert!(execute_command(&push, &settings).is_err());
+
+        // Bad checksum
+        let mut push = chunk(0);
+        push.params["size"] = 4.into();
+        push.params["sha256"] = "00".into();
+        assert!(execute_command(&push, &settings).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_push_refused() {
+        let dir = std::env::temp_dir().join(format!("opsmap-files-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_string_lossy().to_string();
+        let settings = FileSettings {
+            readable_dirs: vec![dir_str.clone()],
+            writable_dirs: vec![dir_str],
+            max_file_bytes: 1024,
+            chunk_bytes: 4,
+        };
+        let push = |path: &str, offset: u64| {
+            command(
+                "file_push",
+                serde_json::json!({
+                    "path": path, "offset": offset, "size": 4,
+                    "sha256": "00", "data": encode(b"data"),
+                }),
+            )
+        };
+        let path = dir.join("app.conf").to_string_lossy().to_string();
+
+        // Relative paths would resolve against the agent's working directory
+        let error = execute_command(&push("app.conf", 0), &settings).unwrap_err();
+        assert!(error.to_string().contains("must be absolute"));
+
+        // An offset that overflows is refused, not wrapped
+        assert!(execute_command(&push(&path, u64::MAX - 1), &settings).is_err());
+
+        // A symlink placed where the part file goes is not written through
+        #[cfg(unix)]
+        {
+            let elsewhere = dir.join("elsewhere");
+            std::os::unix::fs::symlink(&elsewhere, format!("{}{}", path, PART_SUFFIX)).unwrap();
+            assert!(execute_command(&push(&path, 0), &settings).is_err());
+            assert!(!elsewhere.exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! Hardware and software inventory
+//!
+//! Periodically collects CMDB-style host data (CPU, memory, disks, network
+//! interfaces and optionally installed OS packages) and sends it to the
+//! Gateway as an inventory message.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::net::{SocketAddrV4, SocketAddrV6};
+use std::process::Command;
+use std::sync::Arc;
+use sysinfo::{Disks, Networks, System};
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
+use tracing::{debug, info, warn};
+
+use crate::AgentState;
+
+/// Inventory of the host the agent runs on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Inventory {
+    pub hostname: String,
+    pub os: OsInfo,
+    pub cpu: CpuInfo,
+    pub memory: MemoryInfo,
+    pub disks: Vec<DiskInfo>,
+    pub interfaces: Vec<InterfaceInfo>,
+    /// Installed OS packages, only when `include_packages` is enabled
+    pub packages: Option<Vec<Package>>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub kernel: Option<String>,
+    pub arch: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CpuInfo {
+    pub model: String,
+    pub vendor: String,
+    pub logical_cores: usize,
+    pub physical_cores: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryInfo {
+    pub total_bytes: u64,
+    pub swap_total_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskInfo {
+    pub name: String,
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_bytes: u64,
+    pub removable: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub mac: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+}
+
+/// Collect and send inventory on the configured interval
+pub async fn run(state: Arc<RwLock<AgentState>>) {
+    let settings = state.read().await.config.inventory.clone();
+    if !settings.enabled {
+        debug!("Inventory reporting disabled");
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_secs(settings.interval_secs.max(60)));
+
+    loop {
+        ticker.tick().await;
+
+        let include_packages = settings.include_packages;
+        let inventory = match tokio::task::spawn_blocking(move || collect(include_packages)).await {
+            Ok(inventory) => inventory,
+            Err(e) => {
+                warn!(error = %e, "Inventory collection failed");
+                continue;
+            }
+        };
+
+        info!(
+            disks = inventory.disks.len(),
+            interfaces = inventory.interfaces.len(),
+            packages = inventory.packages.as_ref().map(|p| p.len()),
+            "Inventory collected"
+        );
+
+        let mut state = state.write().await;
+        if let Some(ref mut conn) = state.connection {
+            if let Err(e) = conn.send_inventory(inventory).await {
+                warn!(error = %e, "Failed to send inventory");
+            }
+        }
+    }
+}
+
+/// Collect the host inventory
+pub fn collect(include_packages: bool) -> Inventory {
+    let mut sys = System::new();
+    sys.refresh_cpu();
+    sys.refresh_memory();
+
+    let (model, vendor) = sys
+        .cpus()
+        .first()
+        .map(|cpu| (cpu.brand().trim().to_string(), cpu.vendor_id().to_string()))
+        .unwrap_or_default();
+
+    let disks = Disks::new_with_refreshed_list()
+        .iter()
+        .map(|disk| DiskInfo {
+            name: disk.name().to_string_lossy().to_string(),
+            mount_point: disk.mount_point().to_string_lossy().to_string(),
+            file_system: disk.file_system().to_string_lossy().to_string(),
+            total_bytes: disk.total_space(),
+            removable: disk.is_removable(),
+        })
+        .collect();
+
+    let mut addresses = interface_addresses().unwrap_or_else(|e| {
+        debug!(error = %e, "Interface addresses unavailable");
+        BTreeMap::new()
+    });
+
+    let mut interfaces: Vec<InterfaceInfo> = Networks::new_with_refreshed_list()
+        .iter()
+        .map(|(name, data)| InterfaceInfo {
+            name: name.clone(),
+            mac: data.mac_address().to_string(),
+            addresses: addresses.remove(name).unwrap_or_default(),
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let packages = if include_packages {
+        list_packages()
+            .map_err(|e| warn!(error = %e, "Failed to list installed packages"))
+            .ok()
+    } else {
+        None
+    };
+
+    Inventory {
+        hostname: System::host_name().unwrap_or_else(|| "unknown".to_string()),
+        os: OsInfo {
+            name: System::name(),
+            version: System::os_version(),
+            kernel: System::kernel_version(),
+            arch: std::env::consts::ARCH.to_string(),
+        },
+        cpu: CpuInfo {
+            model,
+            vendor,
+            logical_cores: sys.cpus().len(),
+            physical_cores: sys.physical_core_count(),
+        },
+        memory: MemoryInfo {
+            total_bytes: sys.total_memory(),
+            swap_total_bytes: sys.total_swap(),
+        },
+        disks,
+        interfaces,
+        packages,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+/// IP addresses per interface name
+#[cfg(unix)]
+pub(crate) fn interface_addresses() -> Result<BTreeMap<String, Vec<IpAddr>>> {
+    let mut addresses: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+
+    for ifaddr in nix::ifaddrs::getifaddrs()? {
+        let address = match ifaddr.address {
+            Some(address) => address,
+            None => continue,
+        };
+
+        let ip = if let Some(v4) = address.as_sockaddr_in() {
+            IpAddr::V4(*SocketAddrV4::from(*v4).ip())
+        } else if let Some(v6) = address.as_sockaddr_in6() {
+            IpAddr::V6(*SocketAddrV6::from(*v6).ip())
+        } else {
+            continue;
+        };
+
+        addresses.entry(ifaddr.interface_name).or_default().push(ip);
+    }
+
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn interface_addresses() -> Result<BTreeMap<String, Vec<IpAddr>>> {
+    Ok(BTreeMap::new())
+}
+
+/// List installed packages with whichever package manager is present
+fn list_packages() -> Result<Vec<Package>> {
+    let queries: [(&str, &[&str]); 2] = [
+        ("dpkg-query", &["-W", "-f", "${Package}\\t${Version}\\n"]),
+        (
+            "rpm",
+            &["-qa", "--qf", "%{NAME}\\t%{VERSION}-%{RELEASE}\\n"],
+        ),
+    ];
+
+    for (program, args) in queries {
+        let output = match Command::new(program).args(args).output() {
+            Ok(output) => output,
+            Err(_) => continue,
+        };
+
+        if !output.status.success() {
+            continue;
+        }
+
+        return Ok(parse_packages(&String::from_utf8_lossy(&output.stdout)));
+    }
+
+    Err(anyhow!("No supported package manager found (dpkg, rpm)"))
+}
+
+/// Parse `name<TAB>version` lines
+fn parse_packages(output: &str) -> Vec<Package> {
+    let mut packages: Vec<Package> = output
+        .lines()
+        .filter_map(|line| {
+            let (name, version) = line.split_once('\t')?;
+            Some(Package {
+                name: name.trim().to_string(),
+                version: version.trim().to_string(),
+            })
+        })
+        .filter(|p| !p.name.is_empty())
+        .collect();
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_packages() {
+        let output = "openssl\t3.0.2-0ubuntu1.15\nbash\t5.1-6ubuntu1\n\nbroken line\n";
+        let packages = parse_packages(output);
+        assert_eq!(
+            packages,
+            vec![
+                Package {
+                    name: "bash".to_string(),
+                    version: "5.1-6ubuntu1".to_string(),
+                },
+                Package {
+                    name: "openssl".to_string(),
+                    version: "3.0.2-0ubuntu1.15".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_inventory() {
+        let inventory = collect(false);
+        assert!(inventory.memory.total_bytes > 0);
+        assert!(inventory.cpu.logical_cores > 0);
+        assert!(inventory.packages.is_none());
+        assert_eq!(inventory.os.arch, std::env::consts::ARCH);
+    }
+}
@@ -0,0 +1,130 @@
+//! Log level control
+//!
+//! The log filter is installed behind a reload handle, so the level given
+//! with `--log-level` (or `RUST_LOG`, which takes precedence) can be changed
+//! while the agent runs, by a config update from the Gateway or a
+//! `set_log_level` command. Levels take `RUST_LOG` syntax: "debug", or
+//! "info,opsmap_agent::scheduler=trace".
+//!
+//! A level set for a while reverts on its own, to the level from before
+//! the first of the temporary changes; any later change cancels the revert.
+
+use anyhow::{anyhow, Context, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Handle to the installed filter
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// The filter's directives, as given
+static LEVEL: Mutex<String> = Mutex::new(String::new());
+
+/// Bumped on every change, so a revert knows it was overtaken
+static CHANGES: AtomicU64 = AtomicU64::new(0);
+
+/// The level a pending revert goes back to
+static REVERT_TO: Mutex<Option<String>> = Mutex::new(None);
+
+/// The filter layer for the subscriber, starting at `level`
+pub fn filter(level: &str) -> reload::Layer<EnvFilter, Registry> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .filter(|l| EnvFilter::try_new(l).is_ok())
+        .unwrap_or_else(|| level.to_string());
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(&level));
+    let _ = FILTER.set(handle);
+    *LEVEL.lock().unwrap() = level;
+    layer
+}
+
+/// Log at `level` from now on, returning the level it replaces
+pub fn set_level(level: &str) -> Result<String> {
+    let mut revert_to = REVERT_TO.lock().unwrap();
+    let previous = reload(level)?;
+    *revert_to = None;
+    Ok(previous)
+}
+
+/// Log at `level` for `duration`, returning the level it reverts to
+pub fn set_level_for(level: &str, duration: Duration) -> Result<String> {
+    let mut revert_to = REVERT_TO.lock().unwrap();
+    let previous = reload(level)?;
+    let back = revert_to.get_or_insert(previous).clone();
+    let change = CHANGES.load(Ordering::SeqCst);
+
+    let level = back.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let mut revert_to = REVERT_TO.lock().unwrap();
+        if CHANGES.load(Ordering::SeqCst) != change {
+            return;
+        }
+        match reload(&level) {
+            Ok(temporary) => info!(from = %temporary, to = %level, "Log level reverted"),
+            Err(e) => warn!(error = %e, "Failed to revert the log level"),
+        }
+        *revert_to = None;
+    });
+    Ok(back)
+}
+
+/// The current level
+pub fn level() -> String {
+    LEVEL.lock().unwrap().clone()
+}
+
+/// Swap the filter for one at `level`
+fn reload(level: &str) -> Result<String> {
+    let filter =
+        EnvFilter::try_new(level).with_context(|| format!("Invalid log level '{}'", level))?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow!("Logging is not initialized"))?;
+    handle
+        .reload(filter)
+        .context("Failed to change the log level")?;
+    CHANGES.fetch_add(1, Ordering::SeqCst);
+    Ok(std::mem::replace(
+        &mut *LEVEL.lock().unwrap(),
+        level.to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_level() {
+        let err = set_level("opsmap_agent=loud").unwrap_err();
+        assert!(err.to_string().contains("Invalid log level"));
+    }
+
+    #[tokio::test]
+    async fn test_revert() {
+        let _layer = filter("info");
+        let initial = level();
+
+        // A second temporary level still reverts to the first one's
+        assert_eq!(
+            set_level_for("debug", Duration::from_secs(60)).unwrap(),
+            initial
+        );
+        assert_eq!(
+            set_level_for("trace", Duration::from_millis(50)).unwrap(),
+            initial
+        );
+        assert_eq!(level(), "trace");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(level(), initial);
+
+        // A later change cancels the revert
+        set_level_for("debug", Duration::from_millis(50)).unwrap();
+        set_level("warn").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(level(), "warn");
+    }
+}
@@ -6,24 +6,52 @@
 //! - Sends status deltas to the Gateway
 //! - Executes commands (start/stop/restart) with process detachment
 
+mod admin;
+mod buffer;
 mod config;
 mod connection;
+mod delivery;
+mod diagnostics;
+mod discovery;
+#[cfg(feature = "enrollment")]
+mod enrollment;
 mod executor;
-mod scheduler;
+mod facts;
+#[cfg(feature = "files")]
+mod files;
+mod inventory;
+mod logging;
+mod metadata;
+mod metrics;
 mod native_commands;
-mod buffer;
+mod plugins;
+mod redact;
+mod reload;
+mod scheduler;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod secrets;
+mod shutdown;
+mod standalone;
+mod systemd;
+mod telemetry;
+#[cfg(feature = "upgrade")]
+mod upgrade;
+#[cfg(feature = "wasm")]
+mod wasm;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, error, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
+use crate::buffer::OfflineBuffer;
 use crate::config::AgentConfig;
-use crate::connection::GatewayConnection;
+use crate::connection::{AgentMessage, GatewayConnection, StatusBatch, StatusDelta};
+use crate::delivery::Outbox;
 use crate::scheduler::CheckScheduler;
-use crate::buffer::OfflineBuffer;
 
 /// OpsMap Agent CLI
 #[derive(Parser, Debug)]
@@ -31,8 +59,11 @@ use crate::buffer::OfflineBuffer;
 #[command(about = "OpsMap Agent - Monitoring and control agent")]
 #[command(version)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Path to configuration file
-    #[arg(short, long, default_value = "/etc/opsmap/agent.yaml")]
+    #[arg(short, long, default_value = config::paths::CONFIG_FILE, global = true)]
     config: PathBuf,
 
     /// Override gateway URL
@@ -52,31 +83,208 @@ struct Args {
     log_level: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Validate the configuration file and the paths it references
+    Validate,
+    /// Print the effective configuration, after overrides and interpolation
+    PrintConfig,
+    /// Run a single check locally and print its result as JSON
+    ///
+    /// Exits 0/1/2/3 for ok/warning/error/unknown, like a Nagios plugin,
+    /// and 4 when the check could not be run.
+    Check {
+        /// Check type, e.g. disk_space, http, plugin, script, command_metric
+        check_type: String,
+        /// Check configuration as a JSON object
+        #[arg(long, default_value = "{}")]
+        config_json: String,
+        /// Timeout in seconds
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+}
+
 /// Agent state shared across components
 pub struct AgentState {
     pub config: AgentConfig,
     pub connection: Option<GatewayConnection>,
     pub scheduler: CheckScheduler,
     pub buffer: OfflineBuffer,
+    /// Status messages sent but not yet acked by the Gateway
+    pub outbox: Outbox<AgentMessage>,
     pub is_connected: bool,
 }
 
 impl AgentState {
     pub fn new(config: AgentConfig) -> Self {
+        let mut buffer = OfflineBuffer::new(config.buffer.max_size);
+        buffer.set_coalesce(config.buffer.coalesce);
+        buffer.set_max_age(config.buffer.max_age_secs);
+        if let Some(ref path) = config.buffer.file_path {
+            buffer.set_file(path);
+        }
+
         Self {
-            scheduler: CheckScheduler::new(),
-            buffer: OfflineBuffer::new(config.buffer.max_size),
+            scheduler: CheckScheduler::from_config(&config),
+            buffer,
+            outbox: Outbox::with_capacity(config.buffer.max_size),
             config,
             connection: None,
             is_connected: false,
         }
     }
+
+    /// Send status deltas, keeping them until the Gateway acks them;
+    /// buffered instead while disconnected
+    pub async fn send_status(&mut self, mut deltas: Vec<StatusDelta>) {
+        let conn = match self.connection {
+            Some(ref mut conn) if !deltas.is_empty() => conn,
+            Some(_) => return,
+            None => {
+                self.buffer_deltas(deltas);
+                return;
+            }
+        };
+
+        let message = if deltas.len() == 1 {
+            AgentMessage::StatusDelta(deltas.remove(0))
+        } else {
+            AgentMessage::StatusBatch(StatusBatch { deltas })
+        };
+        if !conn.supports("acks") {
+            // The Gateway cannot ack, so a failed send is all we can tell
+            if let Err(e) = conn.send_message(&message).await {
+                warn!(error = %e, "Failed to send status, buffering until reconnected");
+                self.disconnect();
+                self.buffer_deltas(status_deltas(message));
+            }
+            return;
+        }
+        let seq = self.outbox.track(message.clone());
+
+        if let Err(e) = conn.send_sequenced(seq, &message).await {
+            warn!(error = %e, seq = seq, "Failed to send status, buffering until reconnected");
+            self.disconnect();
+        }
+    }
+
+    /// Drop a failed connection. What the Gateway has not acked moves to
+    /// the offline buffer, which survives a restart and is replayed once
+    /// connected again.
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+        self.is_connected = false;
+        for message in self.outbox.ack(u64::MAX) {
+            self.buffer_deltas(status_deltas(message));
+        }
+    }
+
+    /// A scheduler for running a check on demand, so the state is not
+    /// locked while checking
+    fn check_runner(&self) -> CheckScheduler {
+        CheckScheduler::from_config(&self.config)
+    }
+
+    fn buffer_deltas(&mut self, deltas: Vec<StatusDelta>) {
+        for delta in deltas {
+            self.buffer.push(serde_json::to_value(&delta).unwrap());
+        }
+    }
+
+    /// Send the offline buffer while connected, in batches, each kept
+    /// until acked
+    async fn replay_buffer(&mut self) {
+        if !self.is_connected || self.connection.is_none() {
+            return;
+        }
+        let expired = self.buffer.expired();
+        // A failed send drops the connection, and the rest waits
+        while self.connection.is_some() && !self.buffer.is_empty() {
+            let deltas: Vec<StatusDelta> = std::iter::from_fn(|| self.buffer.pop())
+                .take(BUFFER_REPLAY_BATCH)
+                .filter_map(|data| serde_json::from_value(data).ok())
+                .collect();
+            self.send_status(deltas).await;
+        }
+        let dropped = self.buffer.expired() - expired;
+        if dropped > 0 {
+            warn!(
+                dropped = dropped,
+                "Dropped expired buffered items instead of replaying them"
+            );
+        }
+    }
+
+    /// Send again what the Gateway has not acked
+    async fn retransmit(&mut self) -> Result<()> {
+        let conn = match self.connection {
+            Some(ref mut conn) => conn,
+            None => return Ok(()),
+        };
+
+        if !self.outbox.is_empty() {
+            info!(
+                count = self.outbox.len(),
+                "Retransmitting unacknowledged status messages"
+            );
+        }
+        let acks = conn.supports("acks");
+        for (seq, message) in self.outbox.pending() {
+            if acks {
+                conn.send_sequenced(seq, message).await?;
+            } else {
+                conn.send_message(message).await?;
+            }
+        }
+        if !acks {
+            self.outbox.ack(u64::MAX);
+        }
+
+        let undelivered = executor::take_undelivered();
+        if !undelivered.is_empty() {
+            info!(
+                count = undelivered.len(),
+                "Sending command responses held while disconnected"
+            );
+        }
+        for response in undelivered {
+            if let Err(e) = conn.send_command_response(response.clone()).await {
+                warn!(error = %e, job_id = %response.job_id, "Failed to send held command response");
+                executor::hold_response(response);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    let overrides = reload::ConfigOverrides {
+        gateway_url: args.gateway_url.clone(),
+        agent_id: args.agent_id.clone(),
+    };
+
+    match args.command {
+        Some(Commands::Validate) => return validate_config(&args.config, &overrides),
+        Some(Commands::PrintConfig) => {
+            let mut config = config::load_config(&args.config)?;
+            overrides.apply(&mut config);
+            print!("{}", serde_yaml::to_string(&config)?);
+            return Ok(());
+        }
+        Some(Commands::Check {
+            ref check_type,
+            ref config_json,
+            timeout,
+        }) => {
+            return run_check(&args.config, check_type, config_json, timeout).await;
+        }
+        None => {}
+    }
+
     // Initialize logging
     init_logging(&args.log_level)?;
 
@@ -90,11 +298,18 @@ async fn main() -> Result<()> {
     let mut config = config::load_config(&args.config)?;
 
     // Apply CLI overrides
-    if let Some(url) = args.gateway_url {
-        config.gateway.url = url;
-    }
-    if let Some(id) = args.agent_id {
-        config.agent.id = id;
+    overrides.apply(&mut config);
+
+    redact::configure(&config.redaction)?;
+    secrets::configure(&config);
+
+    // Bootstrap a client certificate from an enrollment token
+    #[cfg(feature = "enrollment")]
+    {
+        if enrollment::needed(&config) {
+            enrollment::enroll(&mut config).await?;
+        }
+        enrollment::load_agent_id(&mut config);
     }
 
     // Auto-generate agent ID if not set
@@ -103,20 +318,59 @@ async fn main() -> Result<()> {
         info!(agent_id = %config.agent.id, "Generated agent ID");
     }
 
+    #[cfg(feature = "wasm")]
+    wasm::init(&config.wasm)?;
+
+    let shutdown_timeout = std::time::Duration::from_secs(config.agent.shutdown_timeout_secs);
+
+    executor::load_schedule(&config.jobs.scheduled_file);
+
     // Create shared state
     let state = Arc::new(RwLock::new(AgentState::new(config)));
 
-    // Start main loop
-    run_agent(state).await
+    // Reload configuration on SIGHUP or when the file changes
+    tokio::spawn(reload::watch(args.config.clone(), overrides, state.clone()));
+    tokio::spawn(reload::watch_tls(state.clone()));
+
+    // Local status and debug endpoints
+    tokio::spawn(admin::serve(state.clone()));
+    tokio::spawn(admin::serve_metrics(state.clone()));
+
+    // Supervision by systemd, if it asked for it
+    tokio::spawn(systemd::watchdog(state.clone()));
+
+    tokio::spawn(run_scheduled_commands(state.clone()));
+    tokio::spawn(run_log_retention(state.clone()));
+
+    // Run until told to stop
+    let standalone = state.read().await.config.standalone.enabled;
+    let run = async {
+        if standalone {
+            standalone::run(state.clone()).await
+        } else {
+            run_agent(state.clone()).await
+        }
+    };
+    tokio::select! {
+        result = run => result,
+        signal = shutdown::signal_received() => {
+            info!(signal = signal, "Shutting down");
+            shutdown::run(state, shutdown_timeout).await;
+            Ok(())
+        }
+    }
 }
 
 /// Main agent loop
 async fn run_agent(state: Arc<RwLock<AgentState>>) -> Result<()> {
+    let mut failures: u32 = 0;
     loop {
         // Try to connect to Gateway
         match connect_to_gateway(state.clone()).await {
             Ok(()) => {
+                failures = 0;
                 info!("Connected to Gateway");
+                systemd::ready("Connected to Gateway");
 
                 // Run while connected
                 if let Err(e) = run_connected(state.clone()).await {
@@ -124,26 +378,35 @@ async fn run_agent(state: Arc<RwLock<AgentState>>) -> Result<()> {
                 }
             }
             Err(e) => {
+                failures += 1;
                 warn!(error = %e, "Failed to connect to Gateway");
+                // Checks still run and results are buffered until we connect
+                systemd::ready("Gateway unreachable, buffering offline");
             }
         }
 
-        // Update connection status
-        {
-            let mut state = state.write().await;
-            state.is_connected = false;
-        }
+        // Nothing is sent on a dead connection; unacked deltas are buffered
+        state.write().await.disconnect();
+        metrics::metrics().set_connected(false);
+        metrics::metrics().inc_reconnects();
 
-        // Wait before reconnecting
+        // Wait before reconnecting, longer while attempts keep failing
         let reconnect_interval = {
             let state = state.read().await;
             state.config.gateway.reconnect_interval_secs
         };
+        let reconnect_interval = reconnect_interval
+            .saturating_mul(1 << failures.saturating_sub(1).min(RECONNECT_BACKOFF_STEPS))
+            .min(RECONNECT_BACKOFF_MAX_SECS.max(reconnect_interval));
 
         info!(
             interval_secs = reconnect_interval,
             "Waiting before reconnection attempt"
         );
+        systemd::status(&format!(
+            "Disconnected, reconnecting in {}s",
+            reconnect_interval
+        ));
         tokio::time::sleep(tokio::time::Duration::from_secs(reconnect_interval)).await;
     }
 }
@@ -161,7 +424,11 @@ async fn connect_to_gateway(state: Arc<RwLock<AgentState>>) -> Result<()> {
         let mut state = state.write().await;
         state.connection = Some(connection);
         state.is_connected = true;
+        state.retransmit().await?;
+        // What was checked offline goes out before newer results
+        state.replay_buffer().await;
     }
+    metrics::metrics().set_connected(true);
 
     Ok(())
 }
@@ -169,22 +436,49 @@ async fn connect_to_gateway(state: Arc<RwLock<AgentState>>) -> Result<()> {
 /// Run while connected to Gateway
 async fn run_connected(state: Arc<RwLock<AgentState>>) -> Result<()> {
     // Start scheduler
-    let scheduler_state = state.clone();
-    let scheduler_handle = tokio::spawn(async move {
-        let state = scheduler_state.read().await;
-        state.scheduler.run(scheduler_state.clone()).await
-    });
+    let scheduler_handle = tokio::spawn(CheckScheduler::run(state.clone()));
 
     // Handle messages from Gateway
     let message_state = state.clone();
     let message_handle = tokio::spawn(async move {
+        // WebSocket messages are waited for without holding the state
+        let mut receiver = {
+            let mut state = message_state.write().await;
+            state
+                .connection
+                .as_mut()
+                .and_then(|conn| conn.take_receiver())
+        };
         loop {
-            let result = {
-                let mut state = message_state.write().await;
-                if let Some(ref mut conn) = state.connection {
-                    conn.receive_message().await
-                } else {
-                    break;
+            let (result, polling) = match receiver {
+                Some(ref mut receiver) => (receiver.receive().await, false),
+                None => {
+                    // A polling connection has nothing to read before its next poll
+                    let next_poll = {
+                        let state = message_state.read().await;
+                        state.connection.as_ref().and_then(|conn| conn.next_poll())
+                    };
+                    if let Some(at) = next_poll {
+                        tokio::time::sleep_until(at).await;
+                    }
+
+                    let step = match message_state.write().await.connection {
+                        Some(ref mut conn) => conn.start_poll(),
+                        None => break,
+                    };
+                    let result = match step {
+                        Ok(connection::PollStep::Message(msg)) => Ok(Some(msg)),
+                        // The round trip runs without holding the state
+                        Ok(connection::PollStep::Poll(poll)) => {
+                            let polled = poll.run().await;
+                            match message_state.write().await.connection {
+                                Some(ref mut conn) => conn.finish_poll(polled),
+                                None => break,
+                            }
+                        }
+                        Err(e) => Err(e),
+                    };
+                    (result, true)
                 }
             };
 
@@ -194,6 +488,8 @@ async fn run_connected(state: Arc<RwLock<AgentState>>) -> Result<()> {
                         error!(error = %e, "Failed to handle message");
                     }
                 }
+                // Nothing for us at this poll
+                Ok(None) if polling => {}
                 Ok(None) => {
                     // Connection closed
                     break;
@@ -211,23 +507,20 @@ async fn run_connected(state: Arc<RwLock<AgentState>>) -> Result<()> {
     let buffer_handle = tokio::spawn(async move {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-
-            let mut state = buffer_state.write().await;
-            if state.is_connected {
-                if let Some(ref mut conn) = state.connection {
-                    while let Some(data) = state.buffer.pop() {
-                        if let Err(e) = conn.send_message(&data).await {
-                            // Put back in buffer and break
-                            state.buffer.push(data);
-                            error!(error = %e, "Failed to send buffered data");
-                            break;
-                        }
-                    }
-                }
+            if shutdown::requested() {
+                break;
             }
+
+            buffer_state.write().await.replay_buffer().await;
         }
     });
 
+    // Report discovered services and inventory while connected
+    let discovery_handle = tokio::spawn(discovery::run(state.clone()));
+    let inventory_handle = tokio::spawn(inventory::run(state.clone()));
+    let metadata_handle = tokio::spawn(metadata::run(state.clone()));
+    let job_queue_handle = tokio::spawn(run_job_queue(state.clone()));
+
     // Wait for any task to complete (indicates disconnection)
     tokio::select! {
         _ = scheduler_handle => {},
@@ -235,9 +528,374 @@ async fn run_connected(state: Arc<RwLock<AgentState>>) -> Result<()> {
         _ = buffer_handle => {},
     }
 
+    discovery_handle.abort();
+    inventory_handle.abort();
+    metadata_handle.abort();
+    job_queue_handle.abort();
+
     Ok(())
 }
 
+/// The deltas a status message carries, none for any other message
+fn status_deltas(message: AgentMessage) -> Vec<StatusDelta> {
+    match message {
+        AgentMessage::StatusDelta(delta) => vec![delta],
+        AgentMessage::StatusBatch(batch) => batch.deltas,
+        _ => Vec::new(),
+    }
+}
+
+/// Buffered deltas sent per status batch when replaying the offline buffer
+const BUFFER_REPLAY_BATCH: usize = 100;
+
+/// Consecutive failed connection attempts double the reconnect interval
+/// this many times at most, and never past `RECONNECT_BACKOFF_MAX_SECS`
+const RECONNECT_BACKOFF_STEPS: u32 = 5;
+const RECONNECT_BACKOFF_MAX_SECS: u64 = 300;
+
+/// The response reporting how a command ended
+fn command_response(
+    cmd: &connection::Command,
+    agent_id: String,
+    exec_result: Result<connection::CommandResult>,
+) -> connection::CommandResponse {
+    let (status, result, error) = match exec_result {
+        Ok(cmd_result) => {
+            let result = connection::CommandResult {
+                exit_code: cmd_result.exit_code,
+                stdout: cmd_result.stdout,
+                stderr: cmd_result.stderr,
+                duration_ms: cmd_result.duration_ms,
+                timed_out: false,
+                truncated: cmd_result.truncated,
+                steps: cmd_result.steps,
+                check: cmd_result.check,
+            };
+            let status = if cmd_result.exit_code == 0 {
+                "completed"
+            } else {
+                "failed"
+            };
+            (status.to_string(), Some(result), None)
+        }
+        Err(e) => {
+            let error_msg = e.to_string();
+            let timed_out = error_msg.contains("timed out");
+            let status = if timed_out { "timeout" } else { "failed" };
+            (status.to_string(), None, Some(error_msg))
+        }
+    };
+
+    connection::CommandResponse {
+        job_id: cmd.id.clone(),
+        agent_id,
+        status,
+        result,
+        error,
+        timestamp: chrono::Utc::now(),
+        queue_position: None,
+        scheduled_for: None,
+        correlation_id: cmd.correlation_id.clone(),
+    }
+}
+
+/// Run the verification check of the action a command ran, once it
+/// succeeded; a check that does not pass in time fails the command
+async fn verify_action(
+    state: &Arc<RwLock<AgentState>>,
+    cmd: &connection::Command,
+    exec_result: Result<connection::CommandResult>,
+) -> Result<connection::CommandResult> {
+    let mut result = match exec_result {
+        Ok(result) if result.exit_code == 0 => result,
+        other => return other,
+    };
+    let action_name = match cmd.action_name {
+        Some(ref name) => name,
+        None => return Ok(result),
+    };
+    let (verification, scheduler) = {
+        let s = state.read().await;
+        (
+            s.scheduler
+                .action_verification(&cmd.component_id, action_name),
+            s.check_runner(),
+        )
+    };
+    let (spec, check) = match verification {
+        Some(verification) => verification,
+        None => return Ok(result),
+    };
+
+    info!(command_id = %cmd.id, check = %check.name, "Verifying action");
+    let message = scheduler.verify(&spec, &check).await.map_err(|e| {
+        warn!(command_id = %cmd.id, error = %e, "Action verification failed");
+        anyhow::anyhow!(e)
+    })?;
+    if !result.stdout.is_empty() {
+        result.stdout.push('\n');
+    }
+    result
+        .stdout
+        .push_str(&format!("Verified by check '{}': {}", check.name, message));
+    Ok(result)
+}
+
+/// Run a check of the snapshot now, outside its interval, for a
+/// "run_check" command naming it in `params.check`. Its status goes out at
+/// once as well, so the map reflects a fix without waiting for the check's
+/// next run.
+async fn run_check_now(
+    state: &Arc<RwLock<AgentState>>,
+    cmd: &connection::Command,
+) -> Result<connection::CommandResult> {
+    let check_name = cmd
+        .params
+        .get("check")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Missing check in params"))?;
+    let (check, scheduler) = {
+        let s = state.read().await;
+        (
+            s.scheduler.check(&cmd.component_id, check_name),
+            s.check_runner(),
+        )
+    };
+    let check = check.ok_or_else(|| {
+        anyhow::anyhow!(
+            "No check '{}' on component {} in the snapshot",
+            check_name,
+            cmd.component_id
+        )
+    })?;
+
+    info!(command_id = %cmd.id, check = %check.name, "Running check on demand");
+    let start = std::time::Instant::now();
+    let result =
+        scheduler
+            .execute_check(&check)
+            .await
+            .unwrap_or_else(|e| native_commands::NativeResult {
+                status: "error".to_string(),
+                message: Some(format!("Check failed: {}", e)),
+                metrics: serde_json::Value::Null,
+            });
+    let duration_ms = start.elapsed().as_millis() as u64;
+    metrics::metrics().observe_check(&check.check_type, &result.status, start.elapsed());
+
+    let delta = StatusDelta {
+        component_id: cmd.component_id.clone(),
+        check_name: check.name.clone(),
+        status: result.status.clone(),
+        message: result.message.clone(),
+        metrics: Some(result.metrics.clone()),
+        timestamp: chrono::Utc::now(),
+    };
+    state.write().await.send_status(vec![delta]).await;
+
+    Ok(connection::CommandResult {
+        exit_code: check_exit_code(&result.status),
+        stdout: match result.message {
+            Some(ref message) => format!("{}: {}", result.status, message),
+            None => result.status.clone(),
+        },
+        stderr: String::new(),
+        duration_ms,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: Some(result),
+    })
+}
+
+/// Pause or resume the checks of a command's component, or only the one
+/// named in `params.check`; a pause lasts `params.duration_secs` when given
+async fn pause_checks(
+    state: &Arc<RwLock<AgentState>>,
+    cmd: &connection::Command,
+) -> Result<connection::CommandResult> {
+    let check = cmd.params.get("check").and_then(|v| v.as_str());
+    let duration = cmd
+        .params
+        .get("duration_secs")
+        .and_then(|v| v.as_u64())
+        .map(std::time::Duration::from_secs);
+    let target = match check {
+        Some(check) => format!("check '{}' of component {}", check, cmd.component_id),
+        None => format!("checks of component {}", cmd.component_id),
+    };
+
+    let s = state.read().await;
+    let stdout = if cmd.command_type == "pause_checks" {
+        s.scheduler.pause(&cmd.component_id, check, duration);
+        match duration {
+            Some(duration) => format!("Paused {} for {}s", target, duration.as_secs()),
+            None => format!("Paused {} until resumed", target),
+        }
+    } else if s.scheduler.resume(&cmd.component_id, check) {
+        format!("Resumed {}", target)
+    } else {
+        format!("No pause to end for {}", target)
+    };
+    info!(command_id = %cmd.id, "{}", stdout);
+
+    Ok(connection::CommandResult {
+        exit_code: 0,
+        stdout,
+        stderr: String::new(),
+        duration_ms: 0,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+/// Log at `params.level` from now on, or for `params.revert_after_secs`
+fn set_log_level(cmd: &connection::Command) -> Result<connection::CommandResult> {
+    let level = cmd
+        .params
+        .get("level")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("set_log_level needs params.level"))?;
+    let revert_after = cmd
+        .params
+        .get("revert_after_secs")
+        .and_then(|v| v.as_u64())
+        .filter(|secs| *secs > 0)
+        .map(std::time::Duration::from_secs);
+
+    let stdout = match revert_after {
+        Some(duration) => {
+            let back = logging::set_level_for(level, duration)?;
+            format!(
+                "Log level is {} for {}s, then {}",
+                logging::level(),
+                duration.as_secs(),
+                back
+            )
+        }
+        None => {
+            let previous = logging::set_level(level)?;
+            format!("Log level is {} (was {})", logging::level(), previous)
+        }
+    };
+    info!(command_id = %cmd.id, "{}", stdout);
+
+    Ok(connection::CommandResult {
+        exit_code: 0,
+        stdout,
+        stderr: String::new(),
+        duration_ms: 0,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+/// How often scheduled commands are checked for being due
+const SCHEDULE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Run scheduled commands when their time comes, connected or not
+async fn run_scheduled_commands(state: Arc<RwLock<AgentState>>) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        if shutdown::requested() {
+            break;
+        }
+
+        for cmd in executor::take_due() {
+            info!(command_id = %cmd.id, "Running scheduled command");
+            let agent_id = state.read().await.config.agent.id.clone();
+            if let Err(e) = run_command(state.clone(), cmd, agent_id).await {
+                error!(error = %e, "Failed to run scheduled command");
+            }
+        }
+    }
+}
+
+/// Rotate job logs and remove old ones, every `jobs.log_cleanup_interval_secs`
+async fn run_log_retention(state: Arc<RwLock<AgentState>>) {
+    loop {
+        let jobs = state.read().await.config.jobs.clone();
+        let interval = std::time::Duration::from_secs(jobs.log_cleanup_interval_secs.max(1));
+        match tokio::task::spawn_blocking(move || executor::clean_job_logs(&jobs)).await {
+            Ok(cleanup) if cleanup.removed > 0 || cleanup.rotated > 0 => info!(
+                rotated = cleanup.rotated,
+                removed = cleanup.removed,
+                reclaimed_bytes = cleanup.reclaimed_bytes,
+                total_bytes = cleanup.total_bytes,
+                "Cleaned up job logs"
+            ),
+            Ok(_) => {}
+            Err(e) => warn!(error = %e, "Job log cleanup failed"),
+        }
+
+        tokio::time::sleep(interval).await;
+        if shutdown::requested() {
+            break;
+        }
+    }
+}
+
+/// How often queued jobs are checked for a free slot
+const JOB_QUEUE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Start queued async commands as slots free up, and report detached jobs
+/// that ended, while connected
+async fn run_job_queue(state: Arc<RwLock<AgentState>>) {
+    let mut interval = tokio::time::interval(JOB_QUEUE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let (jobs, agent_id) = {
+            let s = state.read().await;
+            (s.config.jobs.clone(), s.config.agent.id.clone())
+        };
+        for cmd in executor::next_ready(&jobs) {
+            let span = info_span!(
+                "execute_command",
+                command_id = %cmd.id,
+                command_type = %cmd.command_type,
+                component_id = %cmd.component_id,
+                correlation_id = cmd.correlation_id.as_deref(),
+            );
+            telemetry::set_parent(&span, cmd.trace_context.as_ref());
+            info!(command_id = %cmd.id, "Starting queued command");
+            let exec_result = executor::execute_command(&cmd, &jobs)
+                .instrument(span)
+                .await;
+
+            // The next queued jobs do not wait for the verification
+            let (state, agent_id) = (state.clone(), agent_id.clone());
+            tokio::spawn(async move {
+                let exec_result = verify_action(&state, &cmd, exec_result).await;
+                if let Err(e) = send_final_response(&state, &cmd, agent_id, exec_result).await {
+                    warn!(command_id = %cmd.id, error = %e, "Failed to send command response");
+                }
+            });
+        }
+
+        for update in executor::collect_exits(&agent_id) {
+            let (detached_job_id, status) = (update.detached_job_id.clone(), update.status.clone());
+            let mut s = state.write().await;
+            if let Some(ref mut conn) = s.connection {
+                match conn.send_job_update(update).await {
+                    Ok(()) => {
+                        info!(job_id = %detached_job_id, status = %status, "Reported end of detached job");
+                        executor::forget_exit(&detached_job_id);
+                    }
+                    Err(e) => warn!(error = %e, "Failed to send job update"),
+                }
+            }
+        }
+    }
+}
+
 /// Handle a message from the Gateway
 async fn handle_gateway_message(
     state: Arc<RwLock<AgentState>>,
@@ -247,81 +905,117 @@ async fn handle_gateway_message(
 
     match message {
         GatewayMessage::Snapshot(snapshot) => {
+            info!(components = snapshot.components.len(), "Received snapshot");
+
+            let mut state = state.write().await;
+            state.scheduler.update_snapshot(snapshot);
+        }
+        GatewayMessage::SnapshotDelta(delta) => {
             info!(
-                components = snapshot.components.len(),
-                "Received snapshot"
+                base_version = delta.base_version,
+                version = delta.version,
+                operations = delta.operations.len(),
+                "Received snapshot delta"
             );
 
             let mut state = state.write().await;
-            state.scheduler.update_snapshot(snapshot);
+            let base_version = delta.base_version;
+            if !state.scheduler.apply_snapshot_delta(delta) {
+                let version = state.scheduler.snapshot_version();
+                warn!(
+                    base_version = base_version,
+                    version = ?version,
+                    "Snapshot delta does not apply, requesting a full snapshot"
+                );
+                if let Some(ref mut conn) = state.connection {
+                    conn.send_message(&AgentMessage::SnapshotRequest { version })
+                        .await?;
+                }
+            }
         }
         GatewayMessage::Command(cmd) => {
             info!(
                 command_id = %cmd.id,
                 command_type = %cmd.command_type,
+                correlation_id = cmd.correlation_id.as_deref(),
                 "Received command"
             );
 
             // Get agent_id for response
-            let agent_id = {
+            let (agent_id, dedup_ttl) = {
                 let s = state.read().await;
-                s.config.agent.id.clone()
+                (s.config.agent.id.clone(), s.config.jobs.dedup_ttl_secs)
             };
 
-            // Send "started" response immediately for async commands
-            if matches!(cmd.command_type.as_str(), "start" | "stop" | "restart" | "action") {
-                let mut s = state.write().await;
-                if let Some(ref mut conn) = s.connection {
-                    let started_response = connection::CommandResponse {
-                        job_id: cmd.id.clone(),
-                        agent_id: agent_id.clone(),
-                        status: "started".to_string(),
-                        result: None,
-                        error: None,
-                        timestamp: chrono::Utc::now(),
-                    };
-                    conn.send_command_response(started_response).await?;
+            // A redelivered command gets its first answer, not a second run
+            match executor::check_duplicate(&cmd.id, dedup_ttl) {
+                executor::Duplicate::New => {}
+                executor::Duplicate::InProgress => {
+                    info!(command_id = %cmd.id, "Ignoring duplicate of a running command");
+                    return Ok(());
+                }
+                executor::Duplicate::Completed(response) => {
+                    info!(command_id = %cmd.id, "Answering duplicate command with its first response");
+                    let mut s = state.write().await;
+                    if let Some(ref mut conn) = s.connection {
+                        conn.send_command_response(*response).await?;
+                    }
+                    return Ok(());
                 }
             }
 
-            // Execute command
-            let exec_result = executor::execute_command(&cmd).await;
-
-            // Build response based on result
-            let (status, result, error) = match exec_result {
-                Ok(cmd_result) => {
-                    let result = connection::CommandResult {
-                        exit_code: cmd_result.exit_code,
-                        stdout: cmd_result.stdout,
-                        stderr: cmd_result.stderr,
-                        duration_ms: cmd_result.duration_ms,
-                        timed_out: false,
-                    };
-                    let status = if cmd_result.exit_code == 0 { "completed" } else { "failed" };
-                    (status.to_string(), Some(result), None)
-                }
-                Err(e) => {
-                    let error_msg = e.to_string();
-                    let timed_out = error_msg.contains("timed out");
-                    let status = if timed_out { "timeout" } else { "failed" };
-                    (status.to_string(), None, Some(error_msg))
+            // A command for later is kept until its time, even across restarts
+            if let Some(due) = executor::scheduled_time(&cmd).transpose() {
+                let max_scheduled = state.read().await.config.jobs.max_scheduled;
+                let scheduled = due.and_then(|due| {
+                    executor::schedule(cmd.clone(), due, max_scheduled).map(|()| due)
+                });
+                let response = match scheduled {
+                    Ok(due) => {
+                        info!(command_id = %cmd.id, run_at = %due, "Command scheduled");
+                        connection::CommandResponse {
+                            job_id: cmd.id.clone(),
+                            agent_id,
+                            status: "scheduled".to_string(),
+                            result: None,
+                            error: None,
+                            timestamp: chrono::Utc::now(),
+                            queue_position: None,
+                            scheduled_for: Some(due),
+                            correlation_id: cmd.correlation_id.clone(),
+                        }
+                    }
+                    Err(e) => {
+                        warn!(command_id = %cmd.id, error = %e, "Command refused");
+                        let response = command_response(&cmd, agent_id, Err(e));
+                        executor::remember_response(&response);
+                        response
+                    }
+                };
+                let mut s = state.write().await;
+                if let Some(ref mut conn) = s.connection {
+                    conn.send_command_response(response).await?;
                 }
-            };
-
-            // Send final result
-            let mut s = state.write().await;
-            let response = connection::CommandResponse {
-                job_id: cmd.id,
-                agent_id,
-                status,
-                result,
-                error,
-                timestamp: chrono::Utc::now(),
-            };
-
-            if let Some(ref mut conn) = s.connection {
-                conn.send_command_response(response).await?;
+                return Ok(());
             }
+
+            run_command(state, cmd, agent_id).await?;
+        }
+        GatewayMessage::Ack { seq } => {
+            state.write().await.outbox.ack(seq);
+        }
+        GatewayMessage::Registered(accepted) => {
+            debug!(accepted = ?accepted, "Late registration answer from Gateway");
+        }
+        GatewayMessage::ProtocolError(error) => {
+            // A sequenced message refused is not acked, and is sent again
+            warn!(
+                kind = %error.kind,
+                message_type = ?error.message_type,
+                field = ?error.field,
+                error = %error.message,
+                "Gateway refused a message"
+            );
         }
         GatewayMessage::Ping => {
             let mut state = state.write().await;
@@ -329,34 +1023,304 @@ async fn handle_gateway_message(
                 conn.send_pong().await?;
             }
         }
-        GatewayMessage::ConfigUpdate(new_config) => {
+        GatewayMessage::ConfigUpdate(update) => {
             info!("Received configuration update");
             let mut state = state.write().await;
-            // Apply relevant config updates
-            if let Some(interval) = new_config.check_interval_secs {
-                state.config.scheduler.default_check_interval_secs = interval;
+            if let Err(e) = reload::apply_update(&mut state, &update).await {
+                warn!(error = %e, "Configuration update not applied");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute a command and send its responses
+async fn run_command(
+    state: Arc<RwLock<AgentState>>,
+    cmd: connection::Command,
+    agent_id: String,
+) -> Result<()> {
+    // Send "started" response immediately for async commands, once
+    // the job queue lets them run or has queued them
+    if matches!(
+        cmd.command_type.as_str(),
+        "start" | "stop" | "restart" | "action"
+    ) {
+        let mut s = state.write().await;
+        let (response, run) = match executor::admit(&cmd, &s.config.jobs) {
+            Ok(admission) => {
+                let queue_position = match admission {
+                    executor::Admission::Run => None,
+                    executor::Admission::Queued(position) => {
+                        info!(command_id = %cmd.id, position = position, "Command queued");
+                        Some(position)
+                    }
+                };
+                let response = connection::CommandResponse {
+                    job_id: cmd.id.clone(),
+                    agent_id: agent_id.clone(),
+                    status: "started".to_string(),
+                    result: None,
+                    error: None,
+                    timestamp: chrono::Utc::now(),
+                    queue_position,
+                    scheduled_for: None,
+                    correlation_id: cmd.correlation_id.clone(),
+                };
+                (response, queue_position.is_none())
+            }
+            Err(e) => {
+                warn!(command_id = %cmd.id, error = %e, "Command refused");
+                let response = command_response(&cmd, agent_id.clone(), Err(e));
+                executor::remember_response(&response);
+                (response, false)
+            }
+        };
+        if let Some(ref mut conn) = s.connection {
+            if let Err(e) = conn.send_command_response(response).await {
+                // Not started: free its slot, and let the Gateway's
+                // redelivery run it rather than take it for a duplicate
+                if run {
+                    executor::release(&cmd.id);
+                    executor::forget_command(&cmd.id);
+                }
+                return Err(e);
+            }
+        }
+        if !run {
+            return Ok(());
+        }
+    }
+
+    // Execute command, as part of the trace that requested it
+    let span = info_span!(
+        "execute_command",
+        command_id = %cmd.id,
+        command_type = %cmd.command_type,
+        component_id = %cmd.component_id,
+        correlation_id = cmd.correlation_id.as_deref(),
+    );
+    telemetry::set_parent(&span, cmd.trace_context.as_ref());
+    let reload_tls = cmd.command_type == "reload_tls";
+    let upgrade = cmd.command_type == "upgrade";
+    let exec_result = if upgrade {
+        #[cfg(feature = "upgrade")]
+        let result = {
+            let config = state.read().await.config.clone();
+            upgrade::install(&cmd, &config).instrument(span).await
+        };
+        #[cfg(not(feature = "upgrade"))]
+        let result = Err(anyhow::anyhow!(
+            "Agent was built without self-update support"
+        ));
+        result
+    } else if matches!(cmd.command_type.as_str(), "file_push" | "file_fetch") {
+        #[cfg(feature = "files")]
+        let result = {
+            let settings = state.read().await.config.files.clone();
+            let file_cmd = cmd.clone();
+            // Checksums read whole files; keep them off the runtime
+            tokio::task::spawn_blocking(move || files::execute_command(&file_cmd, &settings))
+                .instrument(span)
+                .await
+                .unwrap_or_else(|e| Err(e.into()))
+        };
+        #[cfg(not(feature = "files"))]
+        let result = Err(anyhow::anyhow!(
+            "Agent was built without file transfer support"
+        ));
+        result
+    } else if cmd.command_type == "run_check" {
+        run_check_now(&state, &cmd).instrument(span).await
+    } else if matches!(cmd.command_type.as_str(), "pause_checks" | "resume_checks") {
+        pause_checks(&state, &cmd).await
+    } else if cmd.command_type == "set_log_level" {
+        set_log_level(&cmd)
+    } else if cmd.command_type == "diagnostics" {
+        diagnostics::run(&state, &cmd).instrument(span).await
+    } else if reload_tls {
+        reload::check_tls(&state)
+            .await
+            .map(|()| connection::CommandResult {
+                exit_code: 0,
+                stdout: "TLS certificates valid, reconnecting".to_string(),
+                stderr: String::new(),
+                duration_ms: 0,
+                timed_out: false,
+                truncated: false,
+                steps: Vec::new(),
+                check: None,
+            })
+    } else {
+        let jobs = state.read().await.config.jobs.clone();
+        let exec_result = executor::execute_command(&cmd, &jobs)
+            .instrument(span)
+            .await;
+        // The message loop does not wait for the verification
+        tokio::spawn(async move {
+            let exec_result = verify_action(&state, &cmd, exec_result).await;
+            if let Err(e) = send_final_response(&state, &cmd, agent_id, exec_result).await {
+                warn!(command_id = %cmd.id, error = %e, "Failed to send command response");
+            }
+        });
+        return Ok(());
+    };
+
+    let completed = send_final_response(&state, &cmd, agent_id, exec_result).await?;
+
+    // Reconnect only once the response is on its way
+    if reload_tls && completed {
+        reload::reload_tls(&state).await?;
+    }
+    #[cfg(feature = "upgrade")]
+    if upgrade && completed {
+        upgrade::restart(state).await;
+    }
+    Ok(())
+}
+
+/// Send the final response of a command, or hold it until reconnected
+/// when disconnected or the send fails. Returns whether the command
+/// completed.
+async fn send_final_response(
+    state: &Arc<RwLock<AgentState>>,
+    cmd: &connection::Command,
+    agent_id: String,
+    exec_result: Result<connection::CommandResult>,
+) -> Result<bool> {
+    let mut s = state.write().await;
+    let response = command_response(cmd, agent_id, exec_result);
+    executor::remember_response(&response);
+
+    let completed = response.status == "completed";
+
+    match s.connection {
+        Some(ref mut conn) => {
+            if let Err(e) = conn.send_command_response(response.clone()).await {
+                // Sent once reconnected
+                executor::hold_response(response);
+                return Err(e);
             }
         }
+        None => executor::hold_response(response),
     }
+    Ok(completed)
+}
 
+/// Validate the configuration and report problems, failing on errors
+fn validate_config(path: &std::path::Path, overrides: &reload::ConfigOverrides) -> Result<()> {
+    if !path.exists() {
+        anyhow::bail!("Config file not found: {}", path.display());
+    }
+
+    let mut config = config::load_config(path)?;
+    overrides.apply(&mut config);
+
+    let validation = config::validate::validate(&config);
+    for warning in &validation.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &validation.errors {
+        eprintln!("error: {}", error);
+    }
+
+    if !validation.is_ok() {
+        anyhow::bail!(
+            "{} has {} error(s)",
+            path.display(),
+            validation.errors.len()
+        );
+    }
+
+    println!("{} is valid", path.display());
     Ok(())
 }
 
+/// Exit code of `check` when the check could not be run, apart from the
+/// statuses of [`check_exit_code`]
+const CHECK_NOT_RUN_EXIT_CODE: i32 = 4;
+
+/// Run one check with the local configuration, print the result and exit
+async fn run_check(
+    path: &std::path::Path,
+    check_type: &str,
+    config_json: &str,
+    timeout: u64,
+) -> Result<()> {
+    match check_once(path, check_type, config_json, timeout).await {
+        Ok(result) => {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+            std::process::exit(check_exit_code(&result.status));
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(CHECK_NOT_RUN_EXIT_CODE);
+        }
+    }
+}
+
+async fn check_once(
+    path: &std::path::Path,
+    check_type: &str,
+    config_json: &str,
+    timeout: u64,
+) -> Result<native_commands::NativeResult> {
+    let config = config::load_config(path)?;
+
+    let check_config: serde_json::Value = serde_json::from_str(config_json)
+        .map_err(|e| anyhow::anyhow!("Invalid --config-json: {}", e))?;
+
+    #[cfg(feature = "wasm")]
+    wasm::init(&config.wasm)?;
+
+    let check = connection::CheckDefinition {
+        name: check_type.to_string(),
+        check_type: check_type.to_string(),
+        config: check_config,
+        interval_secs: 0,
+        timeout_secs: timeout,
+    };
+
+    let scheduler = CheckScheduler::from_config(&config);
+    scheduler
+        .execute_check(&check)
+        .await
+        .map_err(|e| anyhow::anyhow!("Check failed: {}", e))
+}
+
+/// Exit code for a check status, as monitoring plugins use them
+fn check_exit_code(status: &str) -> i32 {
+    match status {
+        "ok" => 0,
+        "warning" => 1,
+        "error" => 2,
+        _ => 3,
+    }
+}
+
 /// Initialize logging
 fn init_logging(level: &str) -> Result<()> {
-    use tracing_subscriber::{fmt, EnvFilter};
-
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new(level));
-
-    fmt()
-        .with_env_filter(filter)
-        .with_target(true)
-        .with_thread_ids(false)
-        .with_file(true)
-        .with_line_number(true)
-        .json()
+    use tracing_subscriber::{fmt, prelude::*};
+
+    let subscriber = tracing_subscriber::registry()
+        .with(logging::filter(level))
+        .with(
+            fmt::layer()
+                .with_target(true)
+                .with_thread_ids(false)
+                .with_file(true)
+                .with_line_number(true)
+                .with_writer(redact::LogWriter)
+                .json(),
+        );
+
+    #[cfg(feature = "otel")]
+    subscriber
+        .with(telemetry::layer(telemetry::SERVICE_NAME)?)
         .init();
+    #[cfg(not(feature = "otel"))]
+    subscriber.init();
 
     Ok(())
 }
@@ -0,0 +1,142 @@
+//! Agent metadata updates
+//!
+//! Hostname, labels and IP addresses go to the Gateway at registration.
+//! When they change later (labels edited and reloaded, a new DHCP lease, a
+//! renamed host) the agent sends a `metadata_update` message, so the
+//! Gateway and backend see it without a reconnection. Hostname and
+//! addresses are checked every `agent.metadata_interval_secs`; a config
+//! reload checks at once. Labels computed from host facts are resolved
+//! each time, so a changed fact is sent like a changed label.
+
+use anyhow::Result;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+use crate::connection::AgentMetadata;
+use crate::inventory::interface_addresses;
+use crate::AgentState;
+
+/// Gateway capability for metadata updates
+const CAPABILITY: &str = "metadata";
+
+/// The agent's current metadata
+pub fn collect(config: &AgentConfig) -> AgentMetadata {
+    let hostname = config
+        .agent
+        .hostname
+        .clone()
+        .or_else(|| {
+            hostname::get()
+                .ok()
+                .map(|h| h.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let addresses = interface_addresses().unwrap_or_else(|e| {
+        debug!(error = %e, "Interface addresses unavailable");
+        Default::default()
+    });
+
+    AgentMetadata {
+        hostname,
+        labels: crate::facts::labels(&config.labels),
+        ip_addresses: host_addresses(addresses.into_values().flatten()),
+    }
+}
+
+/// Addresses others may reach the host at, sorted so a change in
+/// interface order is no change
+fn host_addresses(addresses: impl IntoIterator<Item = IpAddr>) -> Vec<String> {
+    let mut addresses: Vec<IpAddr> = addresses
+        .into_iter()
+        .filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+        .filter(|ip| match ip {
+            IpAddr::V4(v4) => !v4.is_link_local(),
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses.iter().map(|ip| ip.to_string()).collect()
+}
+
+/// Check for changes on the configured interval while connected
+pub async fn run(state: Arc<RwLock<AgentState>>) {
+    let interval_secs = state.read().await.config.agent.metadata_interval_secs;
+    if interval_secs == 0 {
+        debug!("Metadata updates disabled");
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_secs(interval_secs));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Registration has just sent it
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let mut state = state.write().await;
+        if let Err(e) = refresh(&mut state).await {
+            warn!(error = %e, "Failed to send metadata update");
+        }
+    }
+}
+
+/// Send the metadata if it changed since the Gateway last heard it;
+/// returns whether an update was sent
+pub async fn refresh(state: &mut AgentState) -> Result<bool> {
+    let metadata = collect(&state.config);
+    let conn = match state.connection {
+        Some(ref mut conn) if conn.supports(CAPABILITY) => conn,
+        _ => return Ok(false),
+    };
+    if *conn.metadata() == metadata {
+        return Ok(false);
+    }
+
+    info!(
+        hostname = %metadata.hostname,
+        addresses = ?metadata.ip_addresses,
+        "Agent metadata changed, updating Gateway"
+    );
+    conn.send_metadata(metadata).await?;
+    Ok(true)
+}
+
+/// Whether the Gateway takes metadata updates, rather than needing a new
+/// registration to learn of changed labels
+pub fn supported(state: &AgentState) -> bool {
+    state
+        .connection
+        .as_ref()
+        .is_some_and(|conn| conn.supports(CAPABILITY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_addresses() {
+        let addresses = [
+            "10.0.0.5",
+            "127.0.0.1",
+            "::1",
+            "fe80::1",
+            "169.254.10.1",
+            "2001:db8::5",
+            "192.168.1.20",
+            "10.0.0.5",
+        ]
+        .map(|ip| ip.parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            host_addresses(addresses),
+            ["10.0.0.5", "192.168.1.20", "2001:db8::5"]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Agent self-metrics
+//!
+//! Counters and histograms describing the agent itself, rendered in the
+//! Prometheus text format by the admin endpoints, so node-level Prometheus
+//! can alert on an agent that stopped checking or sending.
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Agent metrics, registered in their own registry
+pub struct Metrics {
+    registry: Registry,
+    checks_total: IntCounterVec,
+    check_duration: HistogramVec,
+    buffer_size: IntGauge,
+    buffer_expired_total: IntCounter,
+    connected: IntGauge,
+    reconnects_total: IntCounter,
+    send_failures_total: IntCounter,
+    heartbeat_timeouts_total: IntCounter,
+    job_logs_bytes: IntGauge,
+    job_logs_reclaimed_bytes_total: IntCounterVec,
+    check_status: IntGaugeVec,
+}
+
+/// Process-wide agent metrics
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("agent metrics are valid"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let checks_total = IntCounterVec::new(
+            Opts::new(
+                "opsmap_agent_checks_total",
+                "Checks executed, by type and result status",
+            ),
+            &["check_type", "status"],
+        )?;
+        let check_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "opsmap_agent_check_duration_seconds",
+                "Check execution time",
+            )
+            .buckets(vec![
+                0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+            &["check_type"],
+        )?;
+        let buffer_size = IntGauge::new(
+            "opsmap_agent_buffer_size",
+            "Messages held in the offline buffer",
+        )?;
+        let buffer_expired_total = IntCounter::new(
+            "opsmap_agent_buffer_expired_total",
+            "Buffered messages dropped for exceeding the buffer's max age",
+        )?;
+        let connected = IntGauge::new(
+            "opsmap_agent_connected",
+            "Whether the agent is connected to the Gateway",
+        )?;
+        let reconnects_total = IntCounter::new(
+            "opsmap_agent_reconnects_total",
+            "Reconnection attempts after the Gateway session ended or failed",
+        )?;
+        let send_failures_total = IntCounter::new(
+            "opsmap_agent_send_failures_total",
+            "WebSocket messages that could not be sent to the Gateway",
+        )?;
+        let heartbeat_timeouts_total = IntCounter::new(
+            "opsmap_agent_heartbeat_timeouts_total",
+            "Connections given up after the Gateway missed heartbeats",
+        )?;
+
+        registry.register(Box::new(checks_total.clone()))?;
+        registry.register(Box::new(check_duration.clone()))?;
+        registry.register(Box::new(buffer_size.clone()))?;
+        registry.register(Box::new(buffer_expired_total.clone()))?;
+        registry.register(Box::new(connected.clone()))?;
+        registry.register(Box::new(reconnects_total.clone()))?;
+        let job_logs_bytes = IntGauge::new(
+            "opsmap_agent_job_logs_bytes",
+            "Size of the job log directory after the last cleanup",
+        )?;
+        let job_logs_reclaimed_bytes_total = IntCounterVec::new(
+            Opts::new(
+                "opsmap_agent_job_logs_reclaimed_bytes_total",
+                "Bytes of job logs removed, by reason: rotation, age or size",
+            ),
+            &["reason"],
+        )?;
+
+        registry.register(Box::new(send_failures_total.clone()))?;
+        registry.register(Box::new(heartbeat_timeouts_total.clone()))?;
+        registry.register(Box::new(job_logs_bytes.clone()))?;
+        registry.register(Box::new(job_logs_reclaimed_bytes_total.clone()))?;
+        let check_status = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_agent_check_status",
+                "Last status of each check in standalone mode: 0 ok, 1 warning, 2 error, 3 unknown",
+            ),
+            &["component_id", "check"],
+        )?;
+        registry.register(Box::new(check_status.clone()))?;
+
+        Ok(Self {
+            registry,
+            checks_total,
+            check_duration,
+            buffer_size,
+            buffer_expired_total,
+            connected,
+            reconnects_total,
+            send_failures_total,
+            heartbeat_timeouts_total,
+            job_logs_bytes,
+            job_logs_reclaimed_bytes_total,
+            check_status,
+        })
+    }
+
+    /// Record one check execution
+    pub fn observe_check(&self, check_type: &str, status: &str, duration: Duration) {
+        self.checks_total
+            .with_label_values(&[check_type, status])
+            .inc();
+        self.check_duration
+            .with_label_values(&[check_type])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn set_buffer_size(&self, size: usize) {
+        self.buffer_size.set(size as i64);
+    }
+
+    pub fn inc_buffer_expired(&self) {
+        self.buffer_expired_total.inc();
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.set(connected as i64);
+    }
+
+    pub fn inc_reconnects(&self) {
+        self.reconnects_total.inc();
+    }
+
+    pub fn inc_send_failures(&self) {
+        self.send_failures_total.inc();
+    }
+
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    pub fn inc_heartbeat_timeouts(&self) {
+        self.heartbeat_timeouts_total.inc();
+    }
+
+    pub fn set_job_logs_size(&self, bytes: u64) {
+        self.job_logs_bytes.set(bytes as i64);
+    }
+
+    pub fn add_job_logs_reclaimed(&self, reason: &str, bytes: u64) {
+        self.job_logs_reclaimed_bytes_total
+            .with_label_values(&[reason])
+            .inc_by(bytes);
+    }
+
+    pub fn set_check_status(&self, component_id: &str, check: &str, code: i32) {
+        self.check_status
+            .with_label_values(&[component_id, check])
+            .set(code as i64);
+    }
+
+    /// Forget the status of checks, before setting those still defined
+    pub fn clear_check_status(&self) {
+        self.check_status.reset();
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::warn!(error = %e, "Failed to encode metrics");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let m = Metrics::new().unwrap();
+        m.observe_check("tcp_port", "ok", Duration::from_millis(20));
+        m.observe_check("tcp_port", "ok", Duration::from_millis(40));
+        m.set_buffer_size(3);
+        m.inc_send_failures();
+        m.inc_heartbeat_timeouts();
+        m.add_job_logs_reclaimed("age", 2048);
+        m.set_check_status("db", "port", 2);
+
+        let text = m.render();
+        assert!(text.contains("opsmap_agent_checks_total{check_type=\"tcp_port\",status=\"ok\"} 2"));
+        assert!(
+            text.contains("opsmap_agent_check_duration_seconds_count{check_type=\"tcp_port\"} 2")
+        );
+        assert!(text.contains("opsmap_agent_buffer_size 3"));
+        assert!(text.contains("opsmap_agent_send_failures_total 1"));
+        assert!(text.contains("opsmap_agent_heartbeat_timeouts_total 1"));
+        assert!(text.contains("opsmap_agent_job_logs_reclaimed_bytes_total{reason=\"age\"} 2048"));
+        assert!(text.contains("opsmap_agent_check_status{check=\"port\",component_id=\"db\"} 2"));
+    }
+}
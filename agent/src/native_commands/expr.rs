@@ -0,0 +1,408 @@
+//! Threshold expression evaluator
+//!
+//! Small boolean expression language evaluated against a check's metrics
+//! object, e.g. `used_percent > 80 && available_bytes < 5e9`.
+//!
+//! Supported syntax:
+//! - numbers (`80`, `0.5`, `5e9`), strings (`"running"`), `true` / `false`
+//! - metric references, with dots for nested fields (`load.one`)
+//! - arithmetic `+ - * /`, comparisons `> >= < <= == !=`
+//! - boolean `&& || !` and parentheses
+
+use anyhow::{anyhow, Result};
+
+/// Parsed expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Metric(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+/// Runtime value produced while evaluating an expression
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+/// Parse and evaluate an expression against a metrics object
+pub fn evaluate(expr: &str, metrics: &serde_json::Value) -> Result<bool> {
+    let parsed = parse(expr)?;
+    match eval(&parsed, metrics)? {
+        Value::Bool(b) => Ok(b),
+        other => Err(anyhow!(
+            "Expression '{}' must evaluate to a boolean, got {:?}",
+            expr,
+            other
+        )),
+    }
+}
+
+/// Parse an expression
+pub fn parse(input: &str) -> Result<Expr> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_or()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(anyhow!(
+            "Unexpected token {:?} in expression '{}'",
+            parser.tokens[parser.pos],
+            input
+        ));
+    }
+    Ok(expr)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    const OPERATORS: [&str; 14] = [
+        "&&", "||", ">=", "<=", "==", "!=", ">", "<", "!", "+", "-", "*", "/", "=",
+    ];
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit()))
+        {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                // Allow a sign directly after an exponent marker (5e-3)
+                if (chars[i] == 'e' || chars[i] == 'E')
+                    && matches!(chars.get(i + 1), Some('+') | Some('-'))
+                {
+                    i += 1;
+                }
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .parse::<f64>()
+                .map_err(|_| anyhow!("Invalid number '{}' in expression", text))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' || c == '\'' {
+            let quote = c;
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != quote {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(anyhow!("Unterminated string in expression"));
+            }
+            tokens.push(Token::Str(chars[start..i].iter().collect()));
+            i += 1;
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| anyhow!("Unexpected character '{}' in expression", c))?;
+            // A lone '=' is accepted as equality
+            tokens.push(Token::Op(if *op == "=" { "==" } else { op }));
+            i += op.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.peek_op() == Some("||") {
+            self.pos += 1;
+            let right = self.parse_and()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::Or, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_comparison()?;
+        while self.peek_op() == Some("&&") {
+            self.pos += 1;
+            let right = self.parse_comparison()?;
+            left = Expr::Binary(Box::new(left), BinaryOp::And, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let left = self.parse_additive()?;
+        let op = match self.peek_op() {
+            Some(">") => BinaryOp::Gt,
+            Some(">=") => BinaryOp::Ge,
+            Some("<") => BinaryOp::Lt,
+            Some("<=") => BinaryOp::Le,
+            Some("==") => BinaryOp::Eq,
+            Some("!=") => BinaryOp::Ne,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.parse_additive()?;
+        Ok(Expr::Binary(Box::new(left), op, Box::new(right)))
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek_op() {
+                Some("+") => BinaryOp::Add,
+                Some("-") => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_multiplicative()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek_op() {
+                Some("*") => BinaryOp::Mul,
+                Some("/") => BinaryOp::Div,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = Expr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.peek_op() {
+            Some("!") => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some("-") => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of expression"))?;
+        self.pos += 1;
+
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Str(s) => Ok(Expr::Str(s)),
+            Token::Ident(name) => Ok(match name.as_str() {
+                "true" => Expr::Bool(true),
+                "false" => Expr::Bool(false),
+                _ => Expr::Metric(name),
+            }),
+            Token::LParen => {
+                let expr = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err(anyhow!("Missing closing parenthesis")),
+                }
+            }
+            other => Err(anyhow!("Unexpected token {:?}", other)),
+        }
+    }
+}
+
+fn eval(expr: &Expr, metrics: &serde_json::Value) -> Result<Value> {
+    match expr {
+        Expr::Number(n) => Ok(Value::Number(*n)),
+        Expr::Str(s) => Ok(Value::Str(s.clone())),
+        Expr::Bool(b) => Ok(Value::Bool(*b)),
+        Expr::Metric(name) => lookup_metric(metrics, name),
+        Expr::Not(inner) => match eval(inner, metrics)? {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            other => Err(anyhow!("Cannot negate non-boolean value {:?}", other)),
+        },
+        Expr::Neg(inner) => Ok(Value::Number(-as_number(eval(inner, metrics)?)?)),
+        Expr::Binary(left, BinaryOp::And, right) => {
+            // Short-circuit so `a > 0 && b / a > 2` is safe
+            if !as_bool(eval(left, metrics)?)? {
+                return Ok(Value::Bool(false));
+            }
+            Ok(Value::Bool(as_bool(eval(right, metrics)?)?))
+        }
+        Expr::Binary(left, BinaryOp::Or, right) => {
+            if as_bool(eval(left, metrics)?)? {
+                return Ok(Value::Bool(true));
+            }
+            Ok(Value::Bool(as_bool(eval(right, metrics)?)?))
+        }
+        Expr::Binary(left, op, right) => {
+            let left = eval(left, metrics)?;
+            let right = eval(right, metrics)?;
+            match op {
+                BinaryOp::Eq => Ok(Value::Bool(left == right)),
+                BinaryOp::Ne => Ok(Value::Bool(left != right)),
+                _ => {
+                    let (l, r) = (as_number(left)?, as_number(right)?);
+                    Ok(match op {
+                        BinaryOp::Add => Value::Number(l + r),
+                        BinaryOp::Sub => Value::Number(l - r),
+                        BinaryOp::Mul => Value::Number(l * r),
+                        BinaryOp::Div => Value::Number(l / r),
+                        BinaryOp::Gt => Value::Bool(l > r),
+                        BinaryOp::Ge => Value::Bool(l >= r),
+                        BinaryOp::Lt => Value::Bool(l < r),
+                        _ => Value::Bool(l <= r),
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn lookup_metric(metrics: &serde_json::Value, name: &str) -> Result<Value> {
+    let value = name
+        .split('.')
+        .try_fold(metrics, |current, segment| match current {
+            serde_json::Value::Array(items) => {
+                segment.parse::<usize>().ok().and_then(|i| items.get(i))
+            }
+            _ => current.get(segment),
+        })
+        .ok_or_else(|| anyhow!("Unknown metric '{}' in expression", name))?;
+
+    match value {
+        serde_json::Value::Number(n) => Ok(Value::Number(n.as_f64().unwrap_or(f64::NAN))),
+        serde_json::Value::Bool(b) => Ok(Value::Bool(*b)),
+        serde_json::Value::String(s) => Ok(Value::Str(s.clone())),
+        _ => Err(anyhow!("Metric '{}' is not a scalar value", name)),
+    }
+}
+
+fn as_number(value: Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => Ok(n),
+        other => Err(anyhow!("Expected a number, got {:?}", other)),
+    }
+}
+
+fn as_bool(value: Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(anyhow!("Expected a boolean, got {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_evaluate_combined() {
+        let metrics = json!({ "used_percent": 85.0, "available_bytes": 4_000_000_000u64 });
+        assert!(evaluate("used_percent > 80 && available_bytes < 5e9", &metrics).unwrap());
+        assert!(!evaluate("used_percent > 90 || available_bytes < 1e9", &metrics).unwrap());
+        assert!(evaluate("!(used_percent < 50)", &metrics).unwrap());
+    }
+
+    #[test]
+    fn test_evaluate_arithmetic_and_nesting() {
+        let metrics = json!({
+            "used_bytes": 90,
+            "total_bytes": 100,
+            "load": { "one": 3.5 },
+            "state": "running",
+        });
+        assert!(evaluate("used_bytes / total_bytes >= 0.9", &metrics).unwrap());
+        assert!(evaluate("load.one * 2 == 7", &metrics).unwrap());
+        assert!(evaluate("state == \"running\"", &metrics).unwrap());
+        assert!(evaluate("state != 'stopped'", &metrics).unwrap());
+        assert!(evaluate("-used_bytes < 0", &metrics).unwrap());
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let metrics = json!({ "value": 1 });
+        assert!(evaluate("missing > 1", &metrics).is_err());
+        assert!(evaluate("value + 1", &metrics).is_err());
+        assert!(evaluate("(value > 1", &metrics).is_err());
+        assert!(evaluate("value > 1 )", &metrics).is_err());
+        assert!(evaluate("value # 1", &metrics).is_err());
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let metrics = json!({ "value": 0 });
+        assert!(!evaluate("value > 0 && missing > 1", &metrics).unwrap());
+        assert!(evaluate("value == 0 || missing > 1", &metrics).unwrap());
+    }
+}
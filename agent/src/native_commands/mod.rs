@@ -7,39 +7,128 @@ use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::path::Path;
-use sysinfo::{CpuExt, DiskExt, NetworkExt, ProcessExt, System, SystemExt};
+#[cfg(not(windows))]
+use sysinfo::Disks;
+use sysinfo::{Networks, System};
 use tracing::debug;
 
+mod expr;
+#[cfg(unix)]
+mod service;
+#[cfg(windows)]
+mod windows;
+
+/// Default path for disk_space checks
+#[cfg(unix)]
+const DEFAULT_DISK_PATH: &str = "/";
+#[cfg(windows)]
+const DEFAULT_DISK_PATH: &str = r"C:\";
+
 /// Native command result
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NativeResult {
-    pub status: String,  // ok, warning, error
+    pub status: String, // ok, warning, error
     pub message: Option<String>,
     pub metrics: serde_json::Value,
 }
 
 /// Execute a native command
+///
+/// If the config carries `warning_expr` / `critical_expr` expressions, they
+/// are evaluated against the result metrics and can raise the status.
 pub fn execute_native(command: &str, config: &serde_json::Value) -> Result<NativeResult> {
+    let result = run_native(command, config)?;
+    apply_threshold_expressions(config, result)
+}
+
+fn run_native(command: &str, config: &serde_json::Value) -> Result<NativeResult> {
     match command {
+        #[cfg(not(windows))]
         "disk_space" => check_disk_space(config),
+        #[cfg(not(windows))]
         "memory" => check_memory(config),
+        #[cfg(not(windows))]
         "cpu" => check_cpu(config),
+        // From performance counters
+        #[cfg(windows)]
+        "disk_space" => windows::check_disk_space(config),
+        #[cfg(windows)]
+        "memory" => windows::check_memory(config),
+        #[cfg(windows)]
+        "cpu" => windows::check_cpu(config),
         "process" => check_process(config),
         "tcp_port" => check_tcp_port(config),
         "file_exists" => check_file_exists(config),
+        "file_age" => check_file_age(config),
+        "dir_size" => check_dir_size(config),
+        #[cfg(feature = "http")]
         "http" => check_http(config),
+        #[cfg(not(feature = "http"))]
+        "http" => Err(anyhow!("Agent was built without HTTP check support")),
         "load_average" => check_load_average(config),
         "network" => check_network(config),
+        #[cfg(unix)]
+        "service" => service::check_service(config),
+        #[cfg(windows)]
+        "service" => windows::check_windows_service(config),
+        #[cfg(windows)]
+        "windows_service" => windows::check_windows_service(config),
+        #[cfg(not(windows))]
+        "windows_service" => Err(anyhow!(
+            "windows_service checks are only available on Windows"
+        )),
         _ => Err(anyhow!("Unknown native command: {}", command)),
     }
 }
 
+/// Raise a result's status based on the configured threshold expressions
+///
+/// Expressions only escalate: a check already in error stays in error.
+pub fn apply_threshold_expressions(
+    config: &serde_json::Value,
+    mut result: NativeResult,
+) -> Result<NativeResult> {
+    let expression = |key: &str| config.get(key).and_then(|v| v.as_str());
+
+    let escalated = match (expression("critical_expr"), expression("warning_expr")) {
+        (Some(critical), _) if expr::evaluate(critical, &result.metrics)? => {
+            Some(("error", critical))
+        }
+        (_, Some(warning)) if expr::evaluate(warning, &result.metrics)? => {
+            Some(("warning", warning))
+        }
+        _ => None,
+    };
+
+    if let Some((status, matched)) = escalated {
+        if severity(status) > severity(&result.status) {
+            result.status = status.to_string();
+            result.message = Some(match result.message {
+                Some(message) => format!("{} [{}]", message, matched),
+                None => format!("Threshold matched: {}", matched),
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Ordering of check statuses from best to worst
+fn severity(status: &str) -> u8 {
+    match status {
+        "ok" => 0,
+        "warning" => 1,
+        _ => 2,
+    }
+}
+
 /// Check disk space
+#[cfg(not(windows))]
 fn check_disk_space(config: &serde_json::Value) -> Result<NativeResult> {
     let path = config
         .get("path")
         .and_then(|v| v.as_str())
-        .unwrap_or("/");
+        .unwrap_or(DEFAULT_DISK_PATH);
 
     let warning_threshold = config
         .get("warning_percent")
@@ -51,13 +140,10 @@ fn check_disk_space(config: &serde_json::Value) -> Result<NativeResult> {
         .and_then(|v| v.as_f64())
         .unwrap_or(90.0);
 
-    let mut sys = System::new();
-    sys.refresh_disks_list();
-    sys.refresh_disks();
+    let disks = Disks::new_with_refreshed_list();
 
     // Find the disk that contains the given path
-    let disk = sys
-        .disks()
+    let disk = disks
         .iter()
         .filter(|d| path.starts_with(d.mount_point().to_str().unwrap_or("")))
         .max_by_key(|d| d.mount_point().to_str().unwrap_or("").len());
@@ -99,6 +185,7 @@ fn check_disk_space(config: &serde_json::Value) -> Result<NativeResult> {
 }
 
 /// Check memory usage
+#[cfg(not(windows))]
 fn check_memory(config: &serde_json::Value) -> Result<NativeResult> {
     let warning_threshold = config
         .get("warning_percent")
@@ -149,6 +236,7 @@ fn check_memory(config: &serde_json::Value) -> Result<NativeResult> {
 }
 
 /// Check CPU usage
+#[cfg(not(windows))]
 fn check_cpu(config: &serde_json::Value) -> Result<NativeResult> {
     let warning_threshold = config
         .get("warning_percent")
@@ -230,7 +318,10 @@ fn check_process(config: &serde_json::Value) -> Result<NativeResult> {
 
     Ok(NativeResult {
         status: status.to_string(),
-        message: Some(format!("Found {} process(es) matching '{}'", count, process_name)),
+        message: Some(format!(
+            "Found {} process(es) matching '{}'",
+            count, process_name
+        )),
         metrics: json!({
             "process_name": process_name,
             "count": count,
@@ -304,17 +395,22 @@ fn check_file_exists(config: &serde_json::Value) -> Result<NativeResult> {
 
     let metadata = std::fs::metadata(path).ok();
     let size = metadata.as_ref().map(|m| m.len()).unwrap_or(0);
-    let modified = metadata
-        .and_then(|m| m.modified().ok())
-        .map(|t| {
-            chrono::DateTime::<chrono::Utc>::from(t)
-                .format("%Y-%m-%dT%H:%M:%SZ")
-                .to_string()
-        });
-
-    let should_exist = config.get("should_exist").and_then(|v| v.as_bool()).unwrap_or(true);
-
-    let status = if exists == should_exist { "ok" } else { "error" };
+    let modified = metadata.and_then(|m| m.modified().ok()).map(|t| {
+        chrono::DateTime::<chrono::Utc>::from(t)
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string()
+    });
+
+    let should_exist = config
+        .get("should_exist")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+
+    let status = if exists == should_exist {
+        "ok"
+    } else {
+        "error"
+    };
 
     Ok(NativeResult {
         status: status.to_string(),
@@ -334,7 +430,317 @@ fn check_file_exists(config: &serde_json::Value) -> Result<NativeResult> {
     })
 }
 
+/// Check that a file has been modified recently
+fn check_file_age(config: &serde_json::Value) -> Result<NativeResult> {
+    let path = config
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'path' in file_age check config"))?;
+
+    let max_age_secs = config
+        .get("max_age_secs")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow!("Missing 'max_age_secs' in file_age check config"))?;
+
+    let warning_age_secs = config.get("warning_age_secs").and_then(|v| v.as_u64());
+
+    let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(e) => {
+            return Ok(NativeResult {
+                status: "error".to_string(),
+                message: Some(format!("Cannot stat file '{}': {}", path, e)),
+                metrics: json!({
+                    "path": path,
+                    "exists": false,
+                    "error": e.to_string(),
+                }),
+            });
+        }
+    };
+
+    // A modification time in the future (clock skew) counts as fresh
+    let age_secs = std::time::SystemTime::now()
+        .duration_since(modified)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let status = if age_secs >= max_age_secs {
+        "error"
+    } else if warning_age_secs.is_some_and(|w| age_secs >= w) {
+        "warning"
+    } else {
+        "ok"
+    };
+
+    Ok(NativeResult {
+        status: status.to_string(),
+        message: Some(format!(
+            "File '{}' last modified {}s ago (max {}s)",
+            path, age_secs, max_age_secs
+        )),
+        metrics: json!({
+            "path": path,
+            "exists": true,
+            "age_secs": age_secs,
+            "max_age_secs": max_age_secs,
+            "modified_at": chrono::DateTime::<chrono::Utc>::from(modified)
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string(),
+        }),
+    })
+}
+
+/// Check directory size and file count
+fn check_dir_size(config: &serde_json::Value) -> Result<NativeResult> {
+    let path = config
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'path' in dir_size check config"))?;
+
+    let max_depth = config
+        .get("max_depth")
+        .and_then(|v| v.as_u64())
+        .map(|d| d as usize);
+
+    let pattern = config
+        .get("pattern")
+        .and_then(|v| v.as_str())
+        .map(glob::Pattern::new)
+        .transpose()
+        .map_err(|e| anyhow!("Invalid 'pattern' in dir_size check config: {}", e))?;
+
+    let warning_bytes = config.get("warning_bytes").and_then(|v| v.as_u64());
+    let critical_bytes = config.get("critical_bytes").and_then(|v| v.as_u64());
+    let warning_count = config.get("warning_count").and_then(|v| v.as_u64());
+    let critical_count = config.get("critical_count").and_then(|v| v.as_u64());
+
+    let root = Path::new(path);
+    if !root.is_dir() {
+        return Err(anyhow!("Directory not found: {}", path));
+    }
+
+    let mut usage = DirUsage::default();
+    walk_dir(root, 0, max_depth, pattern.as_ref(), &mut usage);
+
+    let exceeds = |value: u64, threshold: Option<u64>| threshold.is_some_and(|t| value >= t);
+
+    let status = if exceeds(usage.total_bytes, critical_bytes)
+        || exceeds(usage.file_count, critical_count)
+    {
+        "error"
+    } else if exceeds(usage.total_bytes, warning_bytes) || exceeds(usage.file_count, warning_count)
+    {
+        "warning"
+    } else {
+        "ok"
+    };
+
+    Ok(NativeResult {
+        status: status.to_string(),
+        message: Some(format!(
+            "Directory '{}': {} in {} file(s)",
+            path,
+            format_bytes(usage.total_bytes),
+            usage.file_count
+        )),
+        metrics: json!({
+            "path": path,
+            "total_bytes": usage.total_bytes,
+            "file_count": usage.file_count,
+            "dir_count": usage.dir_count,
+            "unreadable_count": usage.unreadable_count,
+        }),
+    })
+}
+
+/// Accumulated totals for a directory walk
+#[derive(Debug, Default)]
+struct DirUsage {
+    total_bytes: u64,
+    file_count: u64,
+    dir_count: u64,
+    unreadable_count: u64,
+}
+
+/// Recursively walk a directory, counting files whose name matches the pattern
+///
+/// Symlinks are not followed to avoid loops and double counting.
+fn walk_dir(
+    dir: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    pattern: Option<&glob::Pattern>,
+    usage: &mut DirUsage,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!(path = %dir.display(), error = %e, "Cannot read directory");
+            usage.unreadable_count += 1;
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => {
+                usage.unreadable_count += 1;
+                continue;
+            }
+        };
+
+        if file_type.is_dir() {
+            usage.dir_count += 1;
+            if max_depth.is_none_or(|max| depth < max) {
+                walk_dir(&entry.path(), depth + 1, max_depth, pattern, usage);
+            }
+        } else if file_type.is_file() {
+            let name = entry.file_name();
+            let matches = pattern.is_none_or(|p| p.matches(&name.to_string_lossy()));
+            if !matches {
+                continue;
+            }
+
+            match entry.metadata() {
+                Ok(metadata) => {
+                    usage.total_bytes += metadata.len();
+                    usage.file_count += 1;
+                }
+                Err(_) => usage.unreadable_count += 1,
+            }
+        }
+    }
+}
+
+/// Evaluate the output of a command_metric check
+///
+/// Extracts a number from stdout (via `regex` capture group or `json_path`)
+/// and evaluates the `warning`/`critical` thresholds against it, as
+/// expressions over `value`.
+pub fn evaluate_command_metric(
+    config: &serde_json::Value,
+    exit_code: i32,
+    stdout: &str,
+) -> Result<NativeResult> {
+    let metric_name = config
+        .get("metric_name")
+        .and_then(|v| v.as_str())
+        .unwrap_or("value");
+
+    if exit_code != 0 {
+        return Ok(NativeResult {
+            status: "error".to_string(),
+            message: Some(format!("Command exited with code {}", exit_code)),
+            metrics: json!({ "exit_code": exit_code }),
+        });
+    }
+
+    let value = extract_metric_value(config, stdout)?;
+
+    let exceeds = |key: &str| -> Result<bool> {
+        match config.get(key).and_then(threshold_expr) {
+            Some(e) => expr::evaluate(&e, &json!({ "value": value })),
+            None => Ok(false),
+        }
+    };
+    let status = if exceeds("critical")? {
+        "error"
+    } else if exceeds("warning")? {
+        "warning"
+    } else {
+        "ok"
+    };
+
+    let result = NativeResult {
+        status: status.to_string(),
+        message: Some(format!("{} = {}", metric_name, value)),
+        metrics: json!({
+            metric_name: value,
+            "exit_code": exit_code,
+        }),
+    };
+
+    apply_threshold_expressions(config, result)
+}
+
+/// The expression a `warning`/`critical` threshold stands for: `"> 80"` is
+/// `value > 80`, and a bare number `value >= 80`
+fn threshold_expr(threshold: &serde_json::Value) -> Option<String> {
+    let threshold = match threshold {
+        serde_json::Value::String(s) => s.trim().to_string(),
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    let compared = [">=", "<=", "==", "!=", ">", "<"]
+        .iter()
+        .any(|op| threshold.starts_with(op));
+    Some(if compared {
+        format!("value {}", threshold)
+    } else {
+        format!("value >= {}", threshold)
+    })
+}
+
+/// Extract a numeric value from command output
+fn extract_metric_value(config: &serde_json::Value, stdout: &str) -> Result<f64> {
+    if let Some(pattern) = config.get("regex").and_then(|v| v.as_str()) {
+        let re = regex::Regex::new(pattern)
+            .map_err(|e| anyhow!("Invalid 'regex' in command_metric config: {}", e))?;
+        let captures = re
+            .captures(stdout)
+            .ok_or_else(|| anyhow!("Pattern '{}' did not match command output", pattern))?;
+        // Use the first capture group if present, otherwise the whole match
+        let text = captures
+            .get(1)
+            .or_else(|| captures.get(0))
+            .map(|m| m.as_str())
+            .unwrap_or_default();
+        return text
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Matched text '{}' is not a number", text));
+    }
+
+    if let Some(json_path) = config.get("json_path").and_then(|v| v.as_str()) {
+        let document: serde_json::Value = serde_json::from_str(stdout)
+            .map_err(|e| anyhow!("Command output is not valid JSON: {}", e))?;
+        let found = lookup_json_path(&document, json_path)
+            .ok_or_else(|| anyhow!("JSON path '{}' not found in command output", json_path))?;
+        return match found {
+            serde_json::Value::Number(n) => n
+                .as_f64()
+                .ok_or_else(|| anyhow!("Value at '{}' is not a number", json_path)),
+            serde_json::Value::String(s) => s
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| anyhow!("Value at '{}' is not a number", json_path)),
+            _ => Err(anyhow!("Value at '{}' is not a number", json_path)),
+        };
+    }
+
+    stdout
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Command output is not a number: '{}'", stdout.trim()))
+}
+
+/// Resolve a dotted JSON path such as `$.data.items.0.value`
+fn lookup_json_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.split('.')
+        .filter(|segment| !segment.is_empty())
+        .try_fold(value, |current, segment| match current {
+            serde_json::Value::Array(items) => {
+                segment.parse::<usize>().ok().and_then(|i| items.get(i))
+            }
+            _ => current.get(segment),
+        })
+}
+
 /// Check HTTP endpoint
+#[cfg(feature = "http")]
 fn check_http(config: &serde_json::Value) -> Result<NativeResult> {
     let url = config
         .get("url")
@@ -400,10 +806,15 @@ fn check_http(config: &serde_json::Value) -> Result<NativeResult> {
 
 /// Check system load average
 fn check_load_average(config: &serde_json::Value) -> Result<NativeResult> {
-    let sys = System::new();
-    let load = sys.load_average();
+    if cfg!(windows) {
+        return Err(anyhow!(
+            "Load average is not available on Windows, use a cpu check"
+        ));
+    }
+
+    let load = System::load_average();
 
-    let cpu_count = sys.cpus().len() as f64;
+    let cpu_count = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
     let warning_per_cpu = config
         .get("warning_per_cpu")
         .and_then(|v| v.as_f64())
@@ -441,20 +852,11 @@ fn check_load_average(config: &serde_json::Value) -> Result<NativeResult> {
 
 /// Check network interface stats
 fn check_network(config: &serde_json::Value) -> Result<NativeResult> {
-    let interface = config
-        .get("interface")
-        .and_then(|v| v.as_str());
+    let interface = config.get("interface").and_then(|v| v.as_str());
 
-    let mut sys = System::new();
-    sys.refresh_networks_list();
-    sys.refresh_networks();
-
-    let networks: Vec<_> = sys
-        .networks()
+    let networks: Vec<_> = Networks::new_with_refreshed_list()
         .iter()
-        .filter(|(name, _)| {
-            interface.map_or(true, |i| *name == i)
-        })
+        .filter(|(name, _)| interface.is_none_or(|i| *name == i))
         .map(|(name, data)| {
             json!({
                 "name": name,
@@ -468,8 +870,8 @@ fn check_network(config: &serde_json::Value) -> Result<NativeResult> {
         })
         .collect();
 
-    if networks.is_empty() && interface.is_some() {
-        return Err(anyhow!("Network interface not found: {}", interface.unwrap()));
+    if let (true, Some(interface)) = (networks.is_empty(), interface) {
+        return Err(anyhow!("Network interface not found: {}", interface));
     }
 
     Ok(NativeResult {
@@ -528,4 +930,156 @@ mod tests {
         let result = check_load_average(&json!({})).unwrap();
         assert!(!result.status.is_empty());
     }
+
+    #[test]
+    fn test_threshold_expressions() {
+        let result = || NativeResult {
+            status: "ok".to_string(),
+            message: Some("Disk usage: 85.0%".to_string()),
+            metrics: json!({ "used_percent": 85.0, "available_bytes": 4e9 }),
+        };
+
+        let config = json!({ "warning_expr": "used_percent > 80 && available_bytes < 5e9" });
+        let escalated = apply_threshold_expressions(&config, result()).unwrap();
+        assert_eq!(escalated.status, "warning");
+
+        let config = json!({
+            "warning_expr": "used_percent > 80",
+            "critical_expr": "available_bytes < 5e9",
+        });
+        let escalated = apply_threshold_expressions(&config, result()).unwrap();
+        assert_eq!(escalated.status, "error");
+
+        let config = json!({ "warning_expr": "used_percent > 90" });
+        let unchanged = apply_threshold_expressions(&config, result()).unwrap();
+        assert_eq!(unchanged.status, "ok");
+
+        let mut failed = result();
+        failed.status = "error".to_string();
+        let config = json!({ "warning_expr": "used_percent > 80" });
+        let kept = apply_threshold_expressions(&config, failed).unwrap();
+        assert_eq!(kept.status, "error");
+
+        let config = json!({ "warning_expr": "unknown_metric > 1" });
+        assert!(apply_threshold_expressions(&config, result()).is_err());
+    }
+
+    #[test]
+    fn test_command_metric_regex() {
+        let config = json!({
+            "regex": "queue_depth=(\\d+)",
+            "warning": "> 100",
+            "critical": ">= 500",
+            "metric_name": "queue_depth",
+        });
+
+        let result = evaluate_command_metric(&config, 0, "queue_depth=42\n").unwrap();
+        assert_eq!(result.status, "ok");
+        assert_eq!(result.metrics["queue_depth"], 42.0);
+
+        let result = evaluate_command_metric(&config, 0, "queue_depth=101").unwrap();
+        assert_eq!(result.status, "warning");
+
+        let result = evaluate_command_metric(&config, 0, "queue_depth=500").unwrap();
+        assert_eq!(result.status, "error");
+
+        assert!(evaluate_command_metric(&config, 0, "nothing here").is_err());
+
+        let result = evaluate_command_metric(&config, 2, "").unwrap();
+        assert_eq!(result.status, "error");
+    }
+
+    #[test]
+    fn test_command_metric_json_path() {
+        let config = json!({ "json_path": "$.stats.0.lag", "critical": 10 });
+        let output = r#"{"stats": [{"lag": 3.5}, {"lag": 99}]}"#;
+
+        let result = evaluate_command_metric(&config, 0, output).unwrap();
+        assert_eq!(result.status, "ok");
+        assert_eq!(result.metrics["value"], 3.5);
+
+        let config = json!({ "json_path": "stats.1.lag", "critical": 10 });
+        let result = evaluate_command_metric(&config, 0, output).unwrap();
+        assert_eq!(result.status, "error");
+    }
+
+    #[test]
+    fn test_threshold_expr() {
+        let exceeds = |threshold: serde_json::Value, value: f64| {
+            expr::evaluate(
+                &threshold_expr(&threshold).unwrap(),
+                &json!({ "value": value }),
+            )
+        };
+        assert!(exceeds(json!("> 1"), 2.0).unwrap());
+        assert!(!exceeds(json!("<1"), 2.0).unwrap());
+        assert!(exceeds(json!("!= 0"), 2.0).unwrap());
+        assert!(exceeds(json!("2"), 2.0).unwrap());
+        assert!(exceeds(json!(2), 2.0).unwrap());
+        assert!(!exceeds(json!("> -1"), -2.0).unwrap());
+        assert!(exceeds(json!("gt 2"), 2.0).is_err());
+        assert_eq!(threshold_expr(&json!(true)), None);
+    }
+
+    #[test]
+    fn test_file_age() {
+        let file = std::env::temp_dir().join(format!("opsmap-file-age-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&file, b"heartbeat").unwrap();
+        let path = file.to_str().unwrap();
+
+        let result = check_file_age(&json!({ "path": path, "max_age_secs": 3600 })).unwrap();
+        assert_eq!(result.status, "ok");
+
+        let result = check_file_age(&json!({
+            "path": path,
+            "max_age_secs": 3600,
+            "warning_age_secs": 0,
+        }))
+        .unwrap();
+        assert_eq!(result.status, "warning");
+
+        let result = check_file_age(&json!({ "path": path, "max_age_secs": 0 })).unwrap();
+        assert_eq!(result.status, "error");
+
+        std::fs::remove_file(&file).unwrap();
+
+        let result = check_file_age(&json!({ "path": path, "max_age_secs": 3600 })).unwrap();
+        assert_eq!(result.status, "error");
+        assert_eq!(result.metrics["exists"], false);
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let dir = std::env::temp_dir().join(format!("opsmap-dir-size-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.log"), vec![0u8; 100]).unwrap();
+        std::fs::write(dir.join("b.txt"), vec![0u8; 50]).unwrap();
+        std::fs::write(dir.join("nested/c.log"), vec![0u8; 200]).unwrap();
+
+        let path = dir.to_str().unwrap();
+
+        let result = check_dir_size(&json!({ "path": path })).unwrap();
+        assert_eq!(result.status, "ok");
+        assert_eq!(result.metrics["total_bytes"], 350);
+        assert_eq!(result.metrics["file_count"], 3);
+
+        let result = check_dir_size(&json!({ "path": path, "pattern": "*.log" })).unwrap();
+        assert_eq!(result.metrics["total_bytes"], 300);
+
+        let result = check_dir_size(&json!({ "path": path, "max_depth": 0 })).unwrap();
+        assert_eq!(result.metrics["file_count"], 2);
+
+        let result = check_dir_size(&json!({
+            "path": path,
+            "warning_count": 2,
+            "critical_bytes": 1000,
+        }))
+        .unwrap();
+        assert_eq!(result.status, "warning");
+
+        let result = check_dir_size(&json!({ "path": path, "critical_bytes": 300 })).unwrap();
+        assert_eq!(result.status, "error");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
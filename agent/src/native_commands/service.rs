@@ -0,0 +1,240 @@
+//! Service manager checks on Unix
+//!
+//! `service` asks the platform's service manager about a service: systemd
+//! (`systemctl is-active`) on Linux, launchd (`launchctl print`) on macOS,
+//! rc.d (`service <name> onestatus`) on FreeBSD, NetBSD and DragonFly, and
+//! `rcctl check` on OpenBSD. `manager` in the check config overrides the
+//! platform default.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::process::Command;
+
+use super::NativeResult;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Manager {
+    Systemd,
+    Launchd,
+    RcD,
+    Rcctl,
+}
+
+impl Manager {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "systemd" => Ok(Self::Systemd),
+            "launchd" => Ok(Self::Launchd),
+            "rc.d" => Ok(Self::RcD),
+            "rcctl" => Ok(Self::Rcctl),
+            _ => Err(anyhow!("Unknown service manager: {}", name)),
+        }
+    }
+
+    /// The service manager of the platform the agent was built for
+    fn native() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::Launchd
+        } else if cfg!(target_os = "openbsd") {
+            Self::Rcctl
+        } else if cfg!(any(
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        )) {
+            Self::RcD
+        } else {
+            Self::Systemd
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Systemd => "systemd",
+            Self::Launchd => "launchd",
+            Self::RcD => "rc.d",
+            Self::Rcctl => "rcctl",
+        }
+    }
+}
+
+/// Normalized service state, with the main PID when the manager reports it
+#[derive(Debug, PartialEq)]
+struct ServiceState {
+    state: &'static str,
+    pid: Option<u32>,
+}
+
+/// Check that a service is in `expected_state` ("running" by default)
+///
+/// States are normalized across managers to running, stopped, starting,
+/// stopping, failed, not_found or unknown. For launchd, `name` is a label
+/// in the system domain, or a full service target such as `gui/501/<label>`.
+pub fn check_service(config: &serde_json::Value) -> Result<NativeResult> {
+    let name = config
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'name' in service check config"))?;
+    let expected = config
+        .get("expected_state")
+        .and_then(|v| v.as_str())
+        .unwrap_or("running");
+    let manager = match config.get("manager").and_then(|v| v.as_str()) {
+        Some(manager) => Manager::parse(manager)?,
+        None => Manager::native(),
+    };
+
+    let current = query(manager, name)?;
+
+    let status = if current.state == expected {
+        "ok"
+    } else if matches!(current.state, "starting" | "stopping") {
+        "warning"
+    } else {
+        "error"
+    };
+
+    Ok(NativeResult {
+        status: status.to_string(),
+        message: Some(format!("Service '{}' is {}", name, current.state)),
+        metrics: json!({
+            "service": name,
+            "manager": manager.name(),
+            "state": current.state,
+            "expected_state": expected,
+            "pid": current.pid,
+        }),
+    })
+}
+
+fn query(manager: Manager, name: &str) -> Result<ServiceState> {
+    let (program, args): (&str, Vec<String>) = match manager {
+        Manager::Systemd => ("systemctl", vec!["is-active".into(), name.into()]),
+        Manager::Launchd => {
+            let target = if name.contains('/') {
+                name.to_string()
+            } else {
+                format!("system/{}", name)
+            };
+            ("launchctl", vec!["print".into(), target])
+        }
+        Manager::RcD => ("service", vec![name.into(), "onestatus".into()]),
+        Manager::Rcctl => ("rcctl", vec!["check".into(), name.into()]),
+    };
+
+    let output = Command::new(program)
+        .args(&args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let success = output.status.success();
+
+    Ok(match manager {
+        Manager::Systemd => parse_systemctl(&text),
+        Manager::Launchd if !success => ServiceState {
+            state: "not_found",
+            pid: None,
+        },
+        Manager::Launchd => parse_launchctl(&text),
+        Manager::RcD | Manager::Rcctl => parse_rc(success, &text),
+    })
+}
+
+/// `systemctl is-active` prints one word; unknown units are "inactive"
+fn parse_systemctl(output: &str) -> ServiceState {
+    let state = match output.trim() {
+        "active" | "reloading" => "running",
+        "inactive" => "stopped",
+        "activating" => "starting",
+        "deactivating" => "stopping",
+        "failed" => "failed",
+        _ => "unknown",
+    };
+    ServiceState { state, pid: None }
+}
+
+/// `launchctl print` lists `state = ...` and, while running, `pid = ...`
+fn parse_launchctl(output: &str) -> ServiceState {
+    let field = |key: &str| {
+        output.lines().find_map(|line| {
+            let (k, v) = line.split_once(" = ")?;
+            (k.trim() == key).then(|| v.trim())
+        })
+    };
+
+    let state = match field("state") {
+        Some("running") => "running",
+        Some("not running") | Some("waiting") | Some("exited") => "stopped",
+        Some("spawn scheduled") => "starting",
+        _ => "unknown",
+    };
+    let pid = field("pid").and_then(|pid| pid.parse().ok());
+    ServiceState { state, pid }
+}
+
+/// rc.d and rcctl answer with their exit code; FreeBSD also names the PID
+/// ("sshd is running as pid 812.")
+fn parse_rc(success: bool, output: &str) -> ServiceState {
+    if output.contains("does not exist") {
+        return ServiceState {
+            state: "not_found",
+            pid: None,
+        };
+    }
+
+    let pid = output.split("as pid ").nth(1).and_then(|rest| {
+        rest.split(|c: char| !c.is_ascii_digit())
+            .next()
+            .and_then(|pid| pid.parse().ok())
+    });
+    let state = if success { "running" } else { "stopped" };
+    ServiceState { state, pid }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_systemctl() {
+        assert_eq!(parse_systemctl("active\n").state, "running");
+        assert_eq!(parse_systemctl("inactive\n").state, "stopped");
+        assert_eq!(parse_systemctl("activating\n").state, "starting");
+        assert_eq!(parse_systemctl("failed\n").state, "failed");
+    }
+
+    #[test]
+    fn test_parse_launchctl() {
+        let output = "system/com.example.builder = {\n\tactive count = 1\n\tpath = /Library/LaunchDaemons/com.example.builder.plist\n\tstate = running\n\n\tprogram = /usr/local/bin/builder\n\tpid = 4242\n}\n";
+        assert_eq!(
+            parse_launchctl(output),
+            ServiceState {
+                state: "running",
+                pid: Some(4242)
+            }
+        );
+        assert_eq!(parse_launchctl("\tstate = not running\n").state, "stopped");
+        assert_eq!(parse_launchctl("").state, "unknown");
+    }
+
+    #[test]
+    fn test_parse_rc() {
+        assert_eq!(
+            parse_rc(true, "sshd is running as pid 812.\n"),
+            ServiceState {
+                state: "running",
+                pid: Some(812)
+            }
+        );
+        assert_eq!(parse_rc(true, "smtpd(ok)\n").state, "running");
+        assert_eq!(parse_rc(false, "nfsd is not running.\n").state, "stopped");
+        assert_eq!(
+            parse_rc(false, "rcctl: service foo does not exist\n").state,
+            "not_found"
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Windows-only native checks
+//!
+//! `cpu`, `memory` and `disk_space` read performance counters (by their
+//! English names, whatever the system language) instead of sysinfo, with the
+//! same thresholds and metrics as elsewhere apart from `per_cpu_percent`,
+//! and the commit charge standing in for swap.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::json;
+use std::time::Duration;
+use windows_sys::Win32::System::Performance::{
+    PdhAddEnglishCounterW, PdhCloseQuery, PdhCollectQueryData, PdhGetFormattedCounterValue,
+    PdhOpenQueryW, PDH_FMT_COUNTERVALUE, PDH_FMT_DOUBLE,
+};
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, OpenSCManagerW, OpenServiceW, QueryServiceStatusEx, SC_MANAGER_CONNECT,
+    SC_STATUS_PROCESS_INFO, SERVICE_CONTINUE_PENDING, SERVICE_PAUSED, SERVICE_PAUSE_PENDING,
+    SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STATUS_PROCESS,
+    SERVICE_STOPPED, SERVICE_STOP_PENDING,
+};
+
+use super::{format_bytes, NativeResult, DEFAULT_DISK_PATH};
+
+/// Time between the two samples of a rate counter
+const CPU_SAMPLE: Duration = Duration::from_millis(200);
+
+/// PDH statuses of a counter value that hold data
+const PDH_CSTATUS_NEW_DATA: u32 = 1;
+
+/// Closes a service control manager handle on drop
+struct ScHandle(isize);
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseServiceHandle(self.0);
+        }
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Closes a PDH query, and its counters, on drop
+struct Query(isize);
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        unsafe {
+            PdhCloseQuery(self.0);
+        }
+    }
+}
+
+/// Read performance counters, e.g. `\Memory\Available Bytes`; rates such
+/// as `% Processor Time` need a `sample` to be measured over
+fn read_counters(paths: &[String], sample: Option<Duration>) -> Result<Vec<f64>> {
+    let mut query = 0;
+    let status = unsafe { PdhOpenQueryW(std::ptr::null(), 0, &mut query) };
+    if status != 0 {
+        bail!("Failed to open a performance counter query: {:#x}", status);
+    }
+    let query = Query(query);
+
+    let counters = paths
+        .iter()
+        .map(|path| {
+            let mut counter = 0;
+            let status =
+                unsafe { PdhAddEnglishCounterW(query.0, wide(path).as_ptr(), 0, &mut counter) };
+            if status != 0 {
+                bail!("Performance counter {} not available: {:#x}", path, status);
+            }
+            Ok(counter)
+        })
+        .collect::<Result<Vec<isize>>>()?;
+
+    let collect = || {
+        let status = unsafe { PdhCollectQueryData(query.0) };
+        if status != 0 {
+            bail!("Failed to collect performance counters: {:#x}", status);
+        }
+        Ok(())
+    };
+    collect()?;
+    if let Some(sample) = sample {
+        std::thread::sleep(sample);
+        collect()?;
+    }
+
+    counters
+        .iter()
+        .zip(paths)
+        .map(|(&counter, path)| {
+            let mut value: PDH_FMT_COUNTERVALUE = unsafe { std::mem::zeroed() };
+            let status = unsafe {
+                PdhGetFormattedCounterValue(
+                    counter,
+                    PDH_FMT_DOUBLE,
+                    std::ptr::null_mut(),
+                    &mut value,
+                )
+            };
+            if status != 0 || value.CStatus > PDH_CSTATUS_NEW_DATA {
+                bail!(
+                    "Failed to read performance counter {}: {:#x}",
+                    path,
+                    if status != 0 { status } else { value.CStatus }
+                );
+            }
+            Ok(unsafe { value.Anonymous.doubleValue })
+        })
+        .collect()
+}
+
+/// "ok", "warning" or "error" for a percentage, by the check's
+/// `warning_percent` and `critical_percent`
+fn percent_status(config: &serde_json::Value, percent: f64) -> &'static str {
+    let threshold =
+        |name: &str, default: f64| config.get(name).and_then(|v| v.as_f64()).unwrap_or(default);
+    if percent >= threshold("critical_percent", 90.0) {
+        "error"
+    } else if percent >= threshold("warning_percent", 80.0) {
+        "warning"
+    } else {
+        "ok"
+    }
+}
+
+/// Check CPU usage from `\Processor(_Total)\% Processor Time`
+pub fn check_cpu(config: &serde_json::Value) -> Result<NativeResult> {
+    let values = read_counters(
+        &[r"\Processor(_Total)\% Processor Time".to_string()],
+        Some(CPU_SAMPLE),
+    )?;
+    let cpu_usage = values[0];
+    let cpu_count = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    Ok(NativeResult {
+        status: percent_status(config, cpu_usage).to_string(),
+        message: Some(format!("CPU usage: {:.1}%", cpu_usage)),
+        metrics: json!({
+            "cpu_percent": cpu_usage,
+            "cpu_count": cpu_count,
+        }),
+    })
+}
+
+/// Check memory usage from the `\Memory` counters
+pub fn check_memory(config: &serde_json::Value) -> Result<NativeResult> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    // Physical memory has no counter of its own
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        bail!(
+            "Failed to read the memory size: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let total = status.ullTotalPhys;
+
+    let values = read_counters(
+        &[
+            r"\Memory\Available Bytes".to_string(),
+            r"\Memory\Committed Bytes".to_string(),
+            r"\Memory\Commit Limit".to_string(),
+        ],
+        None,
+    )?;
+    let available = (values[0] as u64).min(total);
+    let used = total - available;
+    let used_percent = (used as f64 / total as f64) * 100.0;
+
+    Ok(NativeResult {
+        status: percent_status(config, used_percent).to_string(),
+        message: Some(format!(
+            "Memory usage: {:.1}% ({} / {})",
+            used_percent,
+            format_bytes(used),
+            format_bytes(total)
+        )),
+        metrics: json!({
+            "total_bytes": total,
+            "used_bytes": used,
+            "available_bytes": available,
+            "used_percent": used_percent,
+            "committed_bytes": values[1] as u64,
+            "commit_limit_bytes": values[2] as u64,
+        }),
+    })
+}
+
+/// Check the space left on the volume holding `path` (a drive letter path,
+/// `C:\` by default) from the `\LogicalDisk` counters
+pub fn check_disk_space(config: &serde_json::Value) -> Result<NativeResult> {
+    let path = config
+        .get("path")
+        .and_then(|v| v.as_str())
+        .unwrap_or(DEFAULT_DISK_PATH);
+    let drive = match path.as_bytes() {
+        [letter, b':', ..] if letter.is_ascii_alphabetic() => {
+            format!("{}:", (*letter as char).to_ascii_uppercase())
+        }
+        _ => bail!(
+            "Disk not found for path: {} (expected a drive letter)",
+            path
+        ),
+    };
+
+    let values = read_counters(
+        &[
+            format!(r"\LogicalDisk({})\% Free Space", drive),
+            format!(r"\LogicalDisk({})\Free Megabytes", drive),
+        ],
+        None,
+    )
+    .map_err(|e| anyhow!("Disk not found for path: {} ({})", path, e))?;
+    let free_percent = values[0];
+    let available = values[1] as u64 * 1024 * 1024;
+    let total = if free_percent > 0.0 {
+        (available as f64 * 100.0 / free_percent) as u64
+    } else {
+        0
+    };
+    let used = total.saturating_sub(available);
+    let used_percent = 100.0 - free_percent;
+
+    Ok(NativeResult {
+        status: percent_status(config, used_percent).to_string(),
+        message: Some(format!(
+            "Disk usage: {:.1}% ({} / {})",
+            used_percent,
+            format_bytes(used),
+            format_bytes(total)
+        )),
+        metrics: json!({
+            "path": path,
+            "total_bytes": total,
+            "used_bytes": used,
+            "available_bytes": available,
+            "used_percent": used_percent,
+        }),
+    })
+}
+
+/// Check the state of a Windows service through the Service Control Manager
+///
+/// `name` is the service name (not its display name). The check is ok when
+/// the service is in `expected_state` ("running" by default).
+pub fn check_windows_service(config: &serde_json::Value) -> Result<NativeResult> {
+    let name = config
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'name' in windows_service check config"))?;
+    let expected = config
+        .get("expected_state")
+        .and_then(|v| v.as_str())
+        .unwrap_or("running");
+
+    let manager = unsafe { OpenSCManagerW(std::ptr::null(), std::ptr::null(), SC_MANAGER_CONNECT) };
+    if manager == 0 {
+        return Err(anyhow!(
+            "Failed to open the service control manager: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let manager = ScHandle(manager);
+
+    let service_name = wide(name);
+    let service = unsafe { OpenServiceW(manager.0, service_name.as_ptr(), SERVICE_QUERY_STATUS) };
+    if service == 0 {
+        return Ok(NativeResult {
+            status: "error".to_string(),
+            message: Some(format!(
+                "Service '{}' not found: {}",
+                name,
+                std::io::Error::last_os_error()
+            )),
+            metrics: json!({ "service": name, "state": "not_found" }),
+        });
+    }
+    let service = ScHandle(service);
+
+    let mut status: SERVICE_STATUS_PROCESS = unsafe { std::mem::zeroed() };
+    let mut needed = 0u32;
+    let ok = unsafe {
+        QueryServiceStatusEx(
+            service.0,
+            SC_STATUS_PROCESS_INFO,
+            &mut status as *mut _ as *mut u8,
+            std::mem::size_of::<SERVICE_STATUS_PROCESS>() as u32,
+            &mut needed,
+        )
+    };
+    if ok == 0 {
+        return Err(anyhow!(
+            "Failed to query service '{}': {}",
+            name,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let state = match status.dwCurrentState {
+        SERVICE_RUNNING => "running",
+        SERVICE_STOPPED => "stopped",
+        SERVICE_START_PENDING => "start_pending",
+        SERVICE_STOP_PENDING => "stop_pending",
+        SERVICE_PAUSED => "paused",
+        SERVICE_PAUSE_PENDING => "pause_pending",
+        SERVICE_CONTINUE_PENDING => "continue_pending",
+        _ => "unknown",
+    };
+
+    let check_status = if state == expected {
+        "ok"
+    } else if state.ends_with("_pending") {
+        "warning"
+    } else {
+        "error"
+    };
+
+    Ok(NativeResult {
+        status: check_status.to_string(),
+        message: Some(format!("Service '{}' is {}", name, state)),
+        metrics: json!({
+            "service": name,
+            "state": state,
+            "expected_state": expected,
+            "pid": status.dwProcessId,
+            "exit_code": status.dwWin32ExitCode,
+        }),
+    })
+}
@@ -0,0 +1,290 @@
+//! External plugin checks
+//!
+//! Runs Nagios-compatible plugin executables from the configured plugins
+//! directory and translates their exit code and perfdata into a NativeResult.
+//!
+//! Exit codes: 0 = OK, 1 = WARNING, 2 = CRITICAL, 3 = UNKNOWN.
+//! Output: `TEXT | 'label'=value[UOM];[warn];[crit];[min];[max] ...`
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+use tracing::debug;
+
+use crate::config::PluginSettings;
+use crate::connection::CheckDefinition;
+use crate::native_commands::{apply_threshold_expressions, NativeResult};
+
+/// Execute a plugin check
+pub async fn execute_plugin(
+    settings: &PluginSettings,
+    check: &CheckDefinition,
+) -> Result<NativeResult> {
+    let name = check
+        .config
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'name' in plugin check config"))?;
+
+    let args: Vec<String> = check
+        .config
+        .get("args")
+        .and_then(|v| v.as_array())
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let path = resolve_plugin(Path::new(&settings.directory), name)?;
+
+    debug!(plugin = %path.display(), "Executing plugin");
+
+    let start = std::time::Instant::now();
+
+    let output = timeout(
+        Duration::from_secs(check.timeout_secs),
+        Command::new(&path)
+            .args(&args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| anyhow!("Plugin timed out after {}s", check.timeout_secs))?
+    .with_context(|| format!("Failed to execute plugin: {}", path.display()))?;
+
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let exit_code = output.status.code().unwrap_or(-1);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    let parsed = parse_output(&stdout);
+
+    let result = NativeResult {
+        status: status_from_exit_code(exit_code).to_string(),
+        message: Some(parsed.text),
+        metrics: json!({
+            "plugin": name,
+            "exit_code": exit_code,
+            "duration_ms": duration_ms,
+            "perfdata": parsed.perfdata,
+        }),
+    };
+
+    apply_threshold_expressions(&check.config, result)
+}
+
+/// Resolve a plugin name to an executable inside the plugins directory
+///
+/// Names containing path separators are rejected so checks cannot escape
+/// the plugins directory.
+fn resolve_plugin(directory: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name == "." || name == ".." {
+        return Err(anyhow!("Invalid plugin name: {}", name));
+    }
+
+    let path = directory.join(name);
+    if !path.is_file() {
+        return Err(anyhow!("Plugin not found: {}", path.display()));
+    }
+
+    Ok(path)
+}
+
+/// Map a Nagios exit code to a check status
+fn status_from_exit_code(exit_code: i32) -> &'static str {
+    match exit_code {
+        0 => "ok",
+        1 => "warning",
+        2 => "error",
+        _ => "unknown",
+    }
+}
+
+/// Parsed plugin output
+#[derive(Debug)]
+struct PluginOutput {
+    text: String,
+    perfdata: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Parse plugin output into its text and perfdata parts
+///
+/// The first line is `TEXT | PERFDATA`; subsequent lines are long text and
+/// may carry more perfdata after a `|`.
+fn parse_output(stdout: &str) -> PluginOutput {
+    let mut lines = stdout.lines();
+    let first = lines.next().unwrap_or_default();
+
+    let (text, mut perf) = match first.split_once('|') {
+        Some((text, perf)) => (text.trim().to_string(), perf.to_string()),
+        None => (first.trim().to_string(), String::new()),
+    };
+
+    let mut in_perf = false;
+    for line in lines {
+        if in_perf {
+            perf.push(' ');
+            perf.push_str(line);
+        } else if let Some((_, rest)) = line.split_once('|') {
+            in_perf = true;
+            perf.push(' ');
+            perf.push_str(rest);
+        }
+    }
+
+    PluginOutput {
+        text,
+        perfdata: parse_perfdata(&perf),
+    }
+}
+
+/// Parse a perfdata string: `'label'=value[UOM];[warn];[crit];[min];[max]`
+fn parse_perfdata(perf: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut metrics = serde_json::Map::new();
+    let mut rest = perf.trim();
+
+    while !rest.is_empty() {
+        // Labels may be single-quoted and contain spaces
+        let (label, after_label) = if let Some(quoted) = rest.strip_prefix('\'') {
+            match quoted.split_once("'=") {
+                Some((label, after)) => (label.to_string(), after),
+                None => break,
+            }
+        } else {
+            match rest.split_once('=') {
+                Some((label, after)) => (label.trim().to_string(), after),
+                None => break,
+            }
+        };
+
+        let (data, remaining) = after_label
+            .split_once(char::is_whitespace)
+            .unwrap_or((after_label, ""));
+        rest = remaining.trim_start();
+
+        let mut fields = data.split(';');
+        let raw_value = fields.next().unwrap_or_default();
+        let split_at = raw_value
+            .find(|c: char| {
+                !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e' || c == 'E')
+            })
+            .unwrap_or(raw_value.len());
+        let (number, uom) = raw_value.split_at(split_at);
+
+        let value = match number.parse::<f64>() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        let mut threshold = || {
+            fields
+                .next()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+        };
+        let (warn, crit, min, max) = (threshold(), threshold(), threshold(), threshold());
+        let as_number = |s: &Option<String>| s.as_deref().and_then(|v| v.parse::<f64>().ok());
+
+        metrics.insert(
+            label,
+            json!({
+                "value": value,
+                "uom": uom,
+                "warn": warn,
+                "crit": crit,
+                "min": as_number(&min),
+                "max": as_number(&max),
+            }),
+        );
+    }
+
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_from_exit_code() {
+        assert_eq!(status_from_exit_code(0), "ok");
+        assert_eq!(status_from_exit_code(1), "warning");
+        assert_eq!(status_from_exit_code(2), "error");
+        assert_eq!(status_from_exit_code(3), "unknown");
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let output = "DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968\n\
+                      / 15272 MB (77%);\n\
+                      /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n\
+                      'home dir'=69.5%;80;90";
+
+        let parsed = parse_output(output);
+        assert_eq!(parsed.text, "DISK OK - free space: / 3326 MB (56%);");
+
+        let root = &parsed.perfdata["/"];
+        assert_eq!(root["value"], 2643.0);
+        assert_eq!(root["uom"], "MB");
+        assert_eq!(root["warn"], "5948");
+        assert_eq!(root["max"], 5968.0);
+
+        assert_eq!(parsed.perfdata["/boot"]["value"], 68.0);
+        assert_eq!(parsed.perfdata["home dir"]["value"], 69.5);
+        assert_eq!(parsed.perfdata["home dir"]["uom"], "%");
+    }
+
+    #[test]
+    fn test_parse_output_without_perfdata() {
+        let parsed = parse_output("PROCS OK: 12 processes\n");
+        assert_eq!(parsed.text, "PROCS OK: 12 processes");
+        assert!(parsed.perfdata.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_plugin_rejects_traversal() {
+        let dir = Path::new("/usr/lib/opsmap/plugins");
+        assert!(resolve_plugin(dir, "../bin/sh").is_err());
+        assert!(resolve_plugin(dir, "..").is_err());
+        assert!(resolve_plugin(dir, "").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_plugin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("opsmap-plugins-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plugin = dir.join("check_dummy");
+        std::fs::write(
+            &plugin,
+            "#!/bin/sh\necho \"WARNING - $1 | load=$1;1;2\"\nexit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let settings = PluginSettings {
+            directory: dir.to_string_lossy().to_string(),
+        };
+        let check = CheckDefinition {
+            name: "dummy".to_string(),
+            check_type: "plugin".to_string(),
+            config: json!({ "name": "check_dummy", "args": ["1.5"] }),
+            interval_secs: 60,
+            timeout_secs: 5,
+        };
+
+        let result = execute_plugin(&settings, &check).await.unwrap();
+        assert_eq!(result.status, "warning");
+        assert_eq!(result.message.as_deref(), Some("WARNING - 1.5"));
+        assert_eq!(result.metrics["perfdata"]["load"]["value"], 1.5);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Secret redaction
+//!
+//! Passwords and tokens are masked as `********` in log lines, in command
+//! results (stdout, stderr, pipeline steps and errors) before they are sent
+//! or held for a later delivery, and in messages put in the offline buffer.
+//!
+//! Two things are masked:
+//!
+//! - matches of `redaction.patterns`, or only their first capture group
+//!   when they have one, so `password=hunter2` becomes `password=********`;
+//! - values the agent knows to be secret: any `{"value": ..., "secret": true}`
+//!   in a command's params, which the command sees as the bare value and
+//!   which is masked while it runs and in its result, and the secrets that
+//!   check configs and action env resolve through the secrets provider.
+
+use regex::{Captures, Regex};
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock, RwLock};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::RedactionSettings;
+
+/// What a secret is replaced with
+pub const MASK: &str = "********";
+
+/// Names of fields holding secrets, as regex alternatives; the default
+/// `redaction.patterns` mask their values too
+pub const SECRET_NAMES: &[&str] = &[
+    "password",
+    "passwd",
+    "pwd",
+    "secret",
+    "token",
+    "api[_-]?key",
+];
+
+/// Shorter values would mask too much unrelated text to be useful
+const MIN_SECRET_LEN: usize = 4;
+
+static PATTERNS: RwLock<Vec<Regex>> = RwLock::new(Vec::new());
+
+/// Secrets of running commands, once for each command holding them
+static ACTIVE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Secrets resolved through the agent's secrets provider
+static KNOWN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Use the patterns of `settings` from now on
+pub fn configure(settings: &RedactionSettings) -> anyhow::Result<()> {
+    let patterns = compile(&settings.patterns)?;
+    *PATTERNS.write().unwrap() = patterns;
+    Ok(())
+}
+
+/// Whether a field's name ends with one of `SECRET_NAMES`, in any case
+pub fn secret_name(name: &str) -> bool {
+    static NAMES: OnceLock<Regex> = OnceLock::new();
+    NAMES
+        .get_or_init(|| {
+            Regex::new(&format!("(?i)(?:{})$", SECRET_NAMES.join("|")))
+                .expect("secret names are a valid pattern")
+        })
+        .is_match(name)
+}
+
+/// Compile redaction patterns, failing on the first invalid one
+pub fn compile(patterns: &[String]) -> anyhow::Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid redaction pattern '{}': {}", pattern, e))
+        })
+        .collect()
+}
+
+/// Mask a secret resolved on the agent from now on
+pub fn remember(secret: &str) {
+    let mut known = KNOWN.lock().unwrap();
+    if !known.iter().any(|s| s == secret) {
+        known.push(secret.to_string());
+    }
+}
+
+/// Secret values of a command, masked until dropped
+#[derive(Debug)]
+pub struct Secrets(Vec<String>);
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap();
+        for secret in &self.0 {
+            if let Some(pos) = active.iter().position(|s| s == secret) {
+                active.swap_remove(pos);
+            }
+        }
+    }
+}
+
+/// Replace each value marked secret in `params` by the value itself, and
+/// mask it as long as the returned `Secrets` live
+pub fn reveal(params: &mut Value) -> Secrets {
+    let mut secrets = Vec::new();
+    unwrap_marked(params, &mut secrets);
+    ACTIVE.lock().unwrap().extend(secrets.iter().cloned());
+    Secrets(secrets)
+}
+
+fn unwrap_marked(value: &mut Value, secrets: &mut Vec<String>) {
+    match value {
+        Value::Object(map) if map.get("secret") == Some(&Value::Bool(true)) => {
+            let inner = map.remove("value").unwrap_or(Value::Null);
+            match inner {
+                Value::String(ref s) => secrets.push(s.clone()),
+                Value::Number(ref n) => secrets.push(n.to_string()),
+                _ => {}
+            }
+            *value = inner;
+        }
+        Value::Object(map) => map.values_mut().for_each(|v| unwrap_marked(v, secrets)),
+        Value::Array(items) => items.iter_mut().for_each(|v| unwrap_marked(v, secrets)),
+        _ => {}
+    }
+}
+
+/// `text` with every known secret and pattern match masked
+pub fn text(text: &str) -> String {
+    let mut secrets = KNOWN.lock().unwrap().clone();
+    secrets.extend(ACTIVE.lock().unwrap().iter().cloned());
+    mask(text, &secrets, &PATTERNS.read().unwrap())
+}
+
+/// Mask every string in a JSON value
+pub fn value(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = text(s),
+        Value::Object(map) => map.values_mut().for_each(self::value),
+        Value::Array(items) => items.iter_mut().for_each(self::value),
+        _ => {}
+    }
+}
+
+fn mask(text: &str, secrets: &[String], patterns: &[Regex]) -> String {
+    let mut secrets: Vec<&String> = secrets
+        .iter()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+        .collect();
+    // Longest first, so a secret containing another is masked whole
+    secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+
+    let mut masked = text.to_string();
+    for secret in secrets {
+        if masked.contains(secret.as_str()) {
+            masked = masked.replace(secret.as_str(), MASK);
+        }
+    }
+    for pattern in patterns {
+        masked = pattern
+            .replace_all(&masked, |caps: &Captures| {
+                let whole = caps.get(0).expect("group 0 is the match");
+                match caps.get(1) {
+                    Some(group) => format!(
+                        "{}{}{}",
+                        &whole.as_str()[..group.start() - whole.start()],
+                        MASK,
+                        &whole.as_str()[group.end() - whole.start()..]
+                    ),
+                    None => MASK.to_string(),
+                }
+            })
+            .into_owned();
+    }
+    masked
+}
+
+/// Log output with secrets masked. The formatter writes each event in one
+/// piece, so a secret is never split across writes.
+pub struct LogWriter;
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = MaskedStdout;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        MaskedStdout
+    }
+}
+
+pub struct MaskedStdout;
+
+impl Write for MaskedStdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = text(&String::from_utf8_lossy(buf));
+        io::stdout().write_all(line.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        let patterns = compile(&RedactionSettings::default().patterns).unwrap();
+        let masked = mask(
+            "connect postgres://app:hunter22@db/app with PASSWORD=s3cr3t token: abc.def \
+             and Authorization: Bearer eyJhbGci, key sk-live-1234 but not id=42",
+            &["sk-live-1234".to_string(), "42".to_string()],
+            &patterns,
+        );
+        assert_eq!(
+            masked,
+            "connect postgres://app:********@db/app with PASSWORD=******** token: ******** \
+             and Authorization: Bearer ********, key ******** but not id=42"
+        );
+        // As a JSON log line escapes it
+        assert_eq!(
+            mask(
+                r#"{"message":"body {\"api_key\": \"k3y\"}"}"#,
+                &[],
+                &patterns
+            ),
+            r#"{"message":"body {\"api_key\": \"********\"}"}"#
+        );
+        assert!(compile(&["(unclosed".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_reveal() {
+        let mut params = serde_json::json!({
+            "command": "mysqladmin",
+            "args": ["--user", "root", {"value": "p4ssw0rd-reveal", "secret": true}],
+            "env": {"PIN": {"value": "987654", "secret": true}, "MODE": "repair"},
+        });
+        let secrets = reveal(&mut params);
+        assert_eq!(params["args"][2], "p4ssw0rd-reveal");
+        assert_eq!(params["env"]["PIN"], "987654");
+        assert_eq!(
+            text("pin 987654, pw p4ssw0rd-reveal"),
+            "pin ********, pw ********"
+        );
+
+        // Masked only while the command holds them
+        drop(secrets);
+        assert_eq!(text("pw p4ssw0rd-reveal"), "pw p4ssw0rd-reveal");
+    }
+}
@@ -0,0 +1,370 @@
+//! Configuration hot-reload
+//!
+//! Re-reads the config file on SIGHUP or when the file changes on disk, and
+//! applies the differences to the running agent. Scheduler, plugin, scripting
+//! and buffer settings are applied in place; changes that affect the Gateway
+//! session (agent identity, gateway URL, TLS) reconnect the agent. Changed
+//! labels go out in a metadata update, or with a new registration to a
+//! Gateway without metadata updates.
+//!
+//! The check overrides file is watched too, and re-read on every reload.
+//! In standalone mode, changed components and checks apply at once.
+//!
+//! Renewed TLS certificate files (or a `reload_tls` command) are checked and
+//! then presented on a fresh connection, without restarting the agent.
+//!
+//! Settings pushed by the Gateway in a `config_update` are applied the same
+//! way, and hold until the file is next reloaded or the agent restarts.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{self, AgentConfig};
+use crate::{connection, logging, metadata, redact, secrets, standalone, AgentState};
+
+#[path = "../../../shared/watch.rs"]
+mod watch;
+use watch::watch_files;
+
+/// Sections that are sent at registration or used to connect
+const RECONNECT_SECTIONS: &[&str] = &["agent", "gateway", "tls"];
+
+/// Sections only read when the agent starts
+const RESTART_SECTIONS: &[&str] = &["wasm"];
+
+/// Values given on the command line, which take precedence over the file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub gateway_url: Option<String>,
+    pub agent_id: Option<String>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut AgentConfig) {
+        if let Some(ref url) = self.gateway_url {
+            config.gateway.url = url.clone();
+        }
+        if let Some(ref id) = self.agent_id {
+            config.agent.id = id.clone();
+        }
+    }
+}
+
+/// Watch for SIGHUP and config file changes, reloading on either
+pub async fn watch(path: PathBuf, overrides: ConfigOverrides, state: Arc<RwLock<AgentState>>) {
+    #[cfg(unix)]
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "Failed to install SIGHUP handler, config reload disabled");
+            return;
+        }
+    };
+    #[cfg(not(unix))]
+    let mut hangup = ();
+
+    let overrides_file = state.read().await.config.scheduler.overrides_file.clone();
+    let mut files = vec![path.clone()];
+    // Its directory must exist to be watched
+    let overrides_file = PathBuf::from(overrides_file);
+    if overrides_file.parent().is_some_and(|dir| dir.is_dir()) {
+        files.push(overrides_file);
+    }
+
+    let (tx, mut changes) = mpsc::channel::<()>(16);
+    // Keep the watcher alive for as long as we are watching
+    let _watcher = match watch_files(&files, tx) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!(error = %e, path = %path.display(), "Config file watch unavailable, reload on SIGHUP only");
+            None
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = hangup_received(&mut hangup) => {
+                info!("Received SIGHUP, reloading configuration");
+            }
+            Some(()) = changes.recv() => {
+                // Editors often write in several steps; let them settle
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                while changes.try_recv().is_ok() {}
+                info!(path = %path.display(), "Config or overrides file changed, reloading configuration");
+            }
+        }
+
+        if let Err(e) = reload(&path, &overrides, &state).await {
+            error!(error = %e, "Config reload failed, keeping current configuration");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn hangup_received(hangup: &mut tokio::signal::unix::Signal) {
+    hangup.recv().await;
+}
+
+/// No SIGHUP on Windows; config changes are picked up from the file
+#[cfg(not(unix))]
+async fn hangup_received(_hangup: &mut ()) {
+    std::future::pending::<()>().await
+}
+
+/// Watch the TLS certificate, key and CA files, reconnecting when renewed
+pub async fn watch_tls(state: Arc<RwLock<AgentState>>) {
+    let files: Vec<PathBuf> = {
+        let state = state.read().await;
+        let tls = &state.config.tls;
+        if !tls.enabled {
+            return;
+        }
+        [&tls.cert_file, &tls.key_file, &tls.ca_file]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect()
+    };
+
+    let (tx, mut changes) = mpsc::channel::<()>(16);
+    let _watcher = match watch_files(&files, tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!(error = %e, "Certificate file watch unavailable, use the reload_tls command");
+            return;
+        }
+    };
+
+    while changes.recv().await.is_some() {
+        // Renewals usually replace the cert and key one after the other
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        while changes.try_recv().is_ok() {}
+        info!("Certificate files changed, reloading TLS certificates");
+
+        if let Err(e) = reload_tls(&state).await {
+            error!(error = %e, "TLS reload failed, keeping current connection");
+        }
+    }
+}
+
+/// Check that the TLS files on disk make a usable client identity
+pub async fn check_tls(state: &Arc<RwLock<AgentState>>) -> Result<()> {
+    let state = state.read().await;
+    connection::check_tls(&state.config)
+}
+
+/// Reconnect with the TLS files on disk, if they are usable
+pub async fn reload_tls(state: &Arc<RwLock<AgentState>>) -> Result<()> {
+    check_tls(state).await?;
+
+    let mut state = state.write().await;
+    if state.connection.is_some() {
+        info!("Reconnecting to Gateway with reloaded TLS certificates");
+        // run_agent builds a new connector from the files when reconnecting
+        state.disconnect();
+    }
+    Ok(())
+}
+
+/// Re-read the config file and apply what changed
+pub async fn reload(
+    path: &Path,
+    overrides: &ConfigOverrides,
+    state: &Arc<RwLock<AgentState>>,
+) -> Result<()> {
+    let mut new_config = config::load_config(path)?;
+    overrides.apply(&mut new_config);
+
+    let mut state = state.write().await;
+
+    // Keep the generated ID rather than registering as a new agent
+    if new_config.agent.id.is_empty() || new_config.agent.id == "auto" {
+        new_config.agent.id = state.config.agent.id.clone();
+    }
+
+    // The file may have changed on its own
+    if let Err(e) = state.scheduler.reload_overrides(&new_config) {
+        warn!(error = %e, "Check overrides file ignored, keeping current overrides");
+    }
+
+    let changed = config::changed_sections(&state.config, &new_config);
+    if changed.is_empty() {
+        debug!("Configuration unchanged");
+        return Ok(());
+    }
+
+    redact::configure(&new_config.redaction)?;
+    secrets::configure(&new_config);
+    state.scheduler.apply_config(&new_config);
+    state.buffer.set_max_size(new_config.buffer.max_size);
+    state.buffer.set_coalesce(new_config.buffer.coalesce);
+    state.buffer.set_max_age(new_config.buffer.max_age_secs);
+
+    let reconnect = changed
+        .iter()
+        .any(|s| RECONNECT_SECTIONS.contains(&s.as_str()))
+        || (changed.iter().any(|s| s == "labels") && !metadata::supported(&state));
+    let restart: Vec<&String> = changed
+        .iter()
+        .filter(|s| RESTART_SECTIONS.contains(&s.as_str()))
+        .collect();
+
+    if !restart.is_empty() {
+        warn!(sections = ?restart, "Some changes only take effect after a restart");
+    }
+
+    if new_config.standalone.enabled != state.config.standalone.enabled {
+        warn!("Switching standalone mode on or off only takes effect after a restart");
+    } else if new_config.standalone.enabled
+        && changed
+            .iter()
+            .any(|s| s == "standalone" || s == "scheduler")
+    {
+        standalone::apply(&mut state, &new_config);
+    }
+
+    state.config = new_config;
+
+    if reconnect && state.connection.is_some() {
+        info!(sections = ?changed, "Configuration reloaded, reconnecting to Gateway");
+        // Dropping the connection ends the session; run_agent reconnects
+        // using the new configuration
+        state.disconnect();
+    } else {
+        info!(sections = ?changed, "Configuration reloaded");
+        if let Err(e) = metadata::refresh(&mut state).await {
+            warn!(error = %e, "Failed to send metadata update");
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply settings pushed by the Gateway
+pub async fn apply_update(state: &mut AgentState, update: &connection::ConfigUpdate) -> Result<()> {
+    let mut config = state.config.clone();
+    if let Some(secs) = update.check_interval_secs {
+        config.scheduler.default_check_interval_secs = secs;
+    }
+    if let Some(secs) = update.batch_send_interval_secs {
+        config.scheduler.batch_send_interval_secs = secs;
+    }
+    if let Some(max_size) = update.buffer_max_size {
+        config.buffer.max_size = max_size;
+    }
+    if let Some(secs) = update.buffer_max_age_secs {
+        config.buffer.max_age_secs = Some(secs);
+    }
+    for key in &update.remove_labels {
+        config.labels.remove(key);
+    }
+    config.labels.extend(update.labels.clone());
+
+    if let Some(ref level) = update.log_level {
+        let previous = logging::set_level(level)?;
+        info!(from = %previous, to = %level, "Log level changed");
+    }
+
+    let changed = config::changed_sections(&state.config, &config);
+    state.scheduler.apply_config(&config);
+    state.buffer.set_max_size(config.buffer.max_size);
+    state.buffer.set_max_age(config.buffer.max_age_secs);
+    state.config = config;
+    info!(sections = ?changed, "Configuration update applied");
+
+    if changed.iter().any(|s| s == "labels") {
+        if metadata::supported(state) {
+            metadata::refresh(state).await?;
+        } else {
+            warn!("Gateway does not take metadata updates, changed labels are sent at the next registration");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let overrides = ConfigOverrides {
+            gateway_url: Some("wss://other:443".to_string()),
+            agent_id: None,
+        };
+        let mut config = AgentConfig::default();
+        overrides.apply(&mut config);
+
+        assert_eq!(config.gateway.url, "wss://other:443");
+        assert_eq!(config.agent.id, "auto");
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_changes() {
+        let dir = std::env::temp_dir().join(format!("opsmap-reload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.yaml");
+
+        let mut config = AgentConfig::default();
+        config.agent.id = "agent-1".to_string();
+        let state = Arc::new(RwLock::new(AgentState::new(config.clone())));
+
+        config.agent.id = "auto".to_string();
+        config.buffer.max_size = 42;
+        std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+
+        reload(&path, &ConfigOverrides::default(), &state)
+            .await
+            .unwrap();
+
+        let state = state.read().await;
+        assert_eq!(state.config.buffer.max_size, 42);
+        assert_eq!(state.config.agent.id, "agent-1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_apply_update() {
+        let mut config = AgentConfig::default();
+        config
+            .labels
+            .insert("canary".to_string(), "true".to_string());
+        let mut state = AgentState::new(config);
+
+        let update: connection::ConfigUpdate = serde_json::from_value(serde_json::json!({
+            "batch_send_interval_secs": 15,
+            "buffer_max_size": 500,
+            "labels": {"tier": "gold"},
+            "remove_labels": ["canary"],
+        }))
+        .unwrap();
+        apply_update(&mut state, &update).await.unwrap();
+
+        assert_eq!(state.config.scheduler.batch_send_interval_secs, 15);
+        assert_eq!(state.config.buffer.max_size, 500);
+        assert_eq!(
+            state.config.labels.get("tier").map(String::as_str),
+            Some("gold")
+        );
+        assert!(!state.config.labels.contains_key("canary"));
+        // Left out, so unchanged
+        assert_eq!(state.config.scheduler.default_check_interval_secs, 30);
+    }
+
+    #[tokio::test]
+    async fn test_reload_tls_rejects_unusable_files() {
+        let mut config = AgentConfig::default();
+        config.tls.cert_file = Some("/nonexistent/agent.crt".to_string());
+        let state = Arc::new(RwLock::new(AgentState::new(config)));
+
+        let err = reload_tls(&state).await.unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/agent.crt"));
+    }
+}
@@ -0,0 +1,273 @@
+//! Anomaly-based thresholds
+//!
+//! Learns an exponentially weighted mean and variance per numeric metric and
+//! flags a warning when the current value deviates from the baseline by more
+//! than the configured number of standard deviations.
+//!
+//! Enabled per check with an `anomaly` section in the check config:
+//!
+//! ```yaml
+//! anomaly:
+//!   metrics: [cpu_percent]
+//!   sigmas: 3.0        # deviation that triggers a warning
+//!   alpha: 0.1         # EWMA smoothing factor
+//!   min_samples: 20    # warm-up before flagging anything
+//!   direction: above   # above, below or both
+//! ```
+
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::native_commands::NativeResult;
+
+/// Per-check anomaly detection settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnomalyConfig {
+    pub metrics: Vec<String>,
+    #[serde(default = "default_sigmas")]
+    pub sigmas: f64,
+    #[serde(default = "default_alpha")]
+    pub alpha: f64,
+    #[serde(default = "default_min_samples")]
+    pub min_samples: u64,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+fn default_sigmas() -> f64 {
+    3.0
+}
+
+fn default_alpha() -> f64 {
+    0.1
+}
+
+fn default_min_samples() -> u64 {
+    20
+}
+
+/// Which deviations count as anomalous
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Above,
+    Below,
+    #[default]
+    Both,
+}
+
+/// Rolling EWMA baseline for one metric
+#[derive(Debug, Clone, Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: u64,
+}
+
+impl Baseline {
+    fn update(&mut self, value: f64, alpha: f64) {
+        if self.samples == 0 {
+            self.mean = value;
+            self.variance = 0.0;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        }
+        self.samples += 1;
+    }
+
+    fn stddev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+}
+
+/// Baselines for every check/metric pair, keyed by component_id:check_name
+#[derive(Debug, Default)]
+pub struct AnomalyDetector {
+    baselines: HashMap<String, HashMap<String, Baseline>>,
+}
+
+impl AnomalyDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget checks that are no longer in the snapshot
+    pub fn retain_keys(&mut self, keep: impl Fn(&str) -> bool) {
+        self.baselines.retain(|key, _| keep(key));
+    }
+
+    /// Compare a result against its baselines, then learn from it
+    ///
+    /// Only escalates "ok" to "warning"; results already failing are left
+    /// alone but still feed the baseline.
+    pub fn evaluate(
+        &mut self,
+        key: &str,
+        check_config: &serde_json::Value,
+        mut result: NativeResult,
+    ) -> Result<NativeResult, String> {
+        let config: AnomalyConfig = match check_config.get("anomaly") {
+            Some(section) => serde_json::from_value(section.clone())
+                .map_err(|e| format!("Invalid anomaly config: {}", e))?,
+            None => return Ok(result),
+        };
+
+        let baselines = self.baselines.entry(key.to_string()).or_default();
+        let mut report = serde_json::Map::new();
+        let mut anomalies = Vec::new();
+
+        for name in &config.metrics {
+            let value = match lookup_metric(&result.metrics, name) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let baseline = baselines.entry(name.clone()).or_default();
+            let stddev = baseline.stddev();
+
+            if baseline.samples >= config.min_samples && stddev > 0.0 {
+                let zscore = (value - baseline.mean) / stddev;
+                let deviates = match config.direction {
+                    Direction::Above => zscore >= config.sigmas,
+                    Direction::Below => -zscore >= config.sigmas,
+                    Direction::Both => zscore.abs() >= config.sigmas,
+                };
+
+                report.insert(
+                    name.clone(),
+                    json!({
+                        "mean": baseline.mean,
+                        "stddev": stddev,
+                        "zscore": zscore,
+                    }),
+                );
+
+                if deviates {
+                    anomalies.push(format!(
+                        "{}={} is {:.1}σ from baseline {:.2}",
+                        name, value, zscore, baseline.mean
+                    ));
+                }
+            }
+
+            baseline.update(value, config.alpha);
+        }
+
+        if !report.is_empty() {
+            if let Some(metrics) = result.metrics.as_object_mut() {
+                metrics.insert("anomaly".to_string(), serde_json::Value::Object(report));
+            }
+        }
+
+        if !anomalies.is_empty() && result.status == "ok" {
+            let detail = anomalies.join(", ");
+            result.status = "warning".to_string();
+            result.message = Some(match result.message {
+                Some(message) => format!("{} [anomaly: {}]", message, detail),
+                None => format!("Anomaly detected: {}", detail),
+            });
+        }
+
+        Ok(result)
+    }
+}
+
+/// Resolve a dotted metric name to a number
+fn lookup_metric(metrics: &serde_json::Value, name: &str) -> Option<f64> {
+    name.split('.')
+        .try_fold(metrics, |current, segment| match current {
+            serde_json::Value::Array(items) => {
+                segment.parse::<usize>().ok().and_then(|i| items.get(i))
+            }
+            _ => current.get(segment),
+        })
+        .and_then(|v| v.as_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(value: f64) -> NativeResult {
+        NativeResult {
+            status: "ok".to_string(),
+            message: None,
+            metrics: json!({ "cpu": { "percent": value } }),
+        }
+    }
+
+    fn config(direction: &str) -> serde_json::Value {
+        json!({
+            "anomaly": {
+                "metrics": ["cpu.percent"],
+                "sigmas": 3.0,
+                "alpha": 0.2,
+                "min_samples": 10,
+                "direction": direction,
+            }
+        })
+    }
+
+    fn warm_up(detector: &mut AnomalyDetector, config: &serde_json::Value) {
+        for i in 0..30 {
+            let value = 20.0 + (i % 3) as f64;
+            let out = detector.evaluate("c:cpu", config, result(value)).unwrap();
+            assert_eq!(out.status, "ok");
+        }
+    }
+
+    #[test]
+    fn test_spike_flags_warning() {
+        let mut detector = AnomalyDetector::new();
+        let config = config("both");
+        warm_up(&mut detector, &config);
+
+        let out = detector.evaluate("c:cpu", &config, result(95.0)).unwrap();
+        assert_eq!(out.status, "warning");
+        assert!(out.message.unwrap().contains("cpu.percent=95"));
+        assert!(
+            out.metrics["anomaly"]["cpu.percent"]["zscore"]
+                .as_f64()
+                .unwrap()
+                > 3.0
+        );
+    }
+
+    #[test]
+    fn test_direction_filter() {
+        let mut detector = AnomalyDetector::new();
+        let config = config("above");
+        warm_up(&mut detector, &config);
+
+        let out = detector.evaluate("c:cpu", &config, result(0.0)).unwrap();
+        assert_eq!(out.status, "ok");
+    }
+
+    #[test]
+    fn test_warm_up_and_missing_config() {
+        let mut detector = AnomalyDetector::new();
+        let config = config("both");
+
+        // Too few samples to judge
+        detector.evaluate("c:cpu", &config, result(20.0)).unwrap();
+        let out = detector.evaluate("c:cpu", &config, result(90.0)).unwrap();
+        assert_eq!(out.status, "ok");
+
+        let out = detector
+            .evaluate("c:cpu", &json!({}), result(1000.0))
+            .unwrap();
+        assert_eq!(out.status, "ok");
+        assert!(out.metrics.get("anomaly").is_none());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut detector = AnomalyDetector::new();
+        let config = json!({ "anomaly": { "sigmas": 2 } });
+        assert!(detector.evaluate("c:cpu", &config, result(1.0)).is_err());
+    }
+}
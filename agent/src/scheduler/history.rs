@@ -0,0 +1,177 @@
+//! Per-check result history
+//!
+//! Keeps a ring buffer of the most recent results for each check and derives
+//! trend metrics from it, so the backend gets availability and latency trends
+//! without storing every datapoint.
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+
+/// A single recorded check result
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub at: DateTime<Utc>,
+    pub status: String,
+    pub latency_ms: Option<u64>,
+}
+
+/// Ring buffers of recent results, keyed by component_id:check_name
+#[derive(Debug)]
+pub struct CheckHistory {
+    capacity: usize,
+    samples: HashMap<String, VecDeque<Sample>>,
+}
+
+impl CheckHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Change the number of results kept per check
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        for buffer in self.samples.values_mut() {
+            while buffer.len() > self.capacity {
+                buffer.pop_front();
+            }
+        }
+    }
+
+    /// Record a result, evicting the oldest sample when the buffer is full
+    pub fn record(&mut self, key: &str, sample: Sample) {
+        let buffer = self
+            .samples
+            .entry(key.to_string())
+            .or_insert_with(|| VecDeque::with_capacity(self.capacity));
+
+        if buffer.len() >= self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(sample);
+    }
+
+    /// Forget checks that are no longer in the snapshot
+    pub fn retain_keys(&mut self, keep: impl Fn(&str) -> bool) {
+        self.samples.retain(|key, _| keep(key));
+    }
+
+    /// Derived trend metrics for a check
+    ///
+    /// - `availability_1h`: percentage of "ok" results over the last hour
+    /// - `p95_latency_ms`: 95th percentile latency over the buffer
+    /// - `consecutive_failures`: non-ok results since the last "ok"
+    pub fn trend(&self, key: &str, now: DateTime<Utc>) -> Option<serde_json::Value> {
+        let buffer = self.samples.get(key)?;
+        if buffer.is_empty() {
+            return None;
+        }
+
+        let hour_ago = now - Duration::hours(1);
+        let recent: Vec<&Sample> = buffer.iter().filter(|s| s.at >= hour_ago).collect();
+        let availability = if recent.is_empty() {
+            None
+        } else {
+            let ok = recent.iter().filter(|s| s.status == "ok").count();
+            Some(ok as f64 * 100.0 / recent.len() as f64)
+        };
+
+        let mut latencies: Vec<u64> = buffer.iter().filter_map(|s| s.latency_ms).collect();
+        latencies.sort_unstable();
+        let p95 = percentile(&latencies, 95.0);
+
+        let consecutive_failures = buffer.iter().rev().take_while(|s| s.status != "ok").count();
+
+        Some(json!({
+            "availability_1h": availability,
+            "p95_latency_ms": p95,
+            "consecutive_failures": consecutive_failures,
+            "samples": buffer.len(),
+        }))
+    }
+}
+
+/// Nearest-rank percentile of a sorted slice
+fn percentile(sorted: &[u64], pct: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Extract a latency from a result's metrics, if the check reports one
+pub fn latency_from_metrics(metrics: &serde_json::Value) -> Option<u64> {
+    ["response_time_ms", "duration_ms"]
+        .iter()
+        .find_map(|key| metrics.get(key).and_then(|v| v.as_u64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at: DateTime<Utc>, status: &str, latency_ms: u64) -> Sample {
+        Sample {
+            at,
+            status: status.to_string(),
+            latency_ms: Some(latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer_capacity() {
+        let mut history = CheckHistory::new(3);
+        let now = Utc::now();
+        for i in 0..5 {
+            history.record("c:http", sample(now, "ok", i));
+        }
+        let trend = history.trend("c:http", now).unwrap();
+        assert_eq!(trend["samples"], 3);
+        assert_eq!(trend["p95_latency_ms"], 4);
+
+        history.set_capacity(2);
+        assert_eq!(history.trend("c:http", now).unwrap()["samples"], 2);
+    }
+
+    #[test]
+    fn test_trend_metrics() {
+        let mut history = CheckHistory::new(100);
+        let now = Utc::now();
+
+        // An old failure outside the hour window
+        history.record("c:http", sample(now - Duration::hours(2), "error", 1000));
+        for i in 1..=18 {
+            history.record("c:http", sample(now, "ok", i * 10));
+        }
+        history.record("c:http", sample(now, "error", 500));
+        history.record("c:http", sample(now, "warning", 400));
+
+        let trend = history.trend("c:http", now).unwrap();
+        assert_eq!(trend["availability_1h"], 90.0);
+        assert_eq!(trend["consecutive_failures"], 2);
+        assert_eq!(trend["p95_latency_ms"], 500);
+        assert!(history.trend("c:other", now).is_none());
+    }
+
+    #[test]
+    fn test_retain_keys() {
+        let mut history = CheckHistory::new(10);
+        let now = Utc::now();
+        history.record("a:http", sample(now, "ok", 1));
+        history.record("b:http", sample(now, "ok", 1));
+        history.retain_keys(|key| key.starts_with("a:"));
+        assert!(history.trend("a:http", now).is_some());
+        assert!(history.trend("b:http", now).is_none());
+    }
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[], 95.0), None);
+        assert_eq!(percentile(&[7], 95.0), Some(7));
+        assert_eq!(percentile(&[1, 2, 3, 4], 50.0), Some(2));
+    }
+}
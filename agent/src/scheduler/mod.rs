@@ -2,31 +2,108 @@
 //!
 //! Executes checks locally on a schedule and sends deltas to the Gateway.
 //! Only sends data when status changes or periodically for metrics.
+//! Periodic deltas carry trend metrics derived from a local result history.
+//! Checks of a component, or a single check, can be paused for a while by
+//! command, e.g. during manual work on the service, and adjusted or
+//! disabled on this host by a local overrides file.
+
+mod anomaly;
+mod history;
+mod overrides;
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::RwLock;
-use tokio::time::{interval, Duration, Instant};
-use tracing::{debug, error, info, warn};
+use tokio::time::{interval, interval_at, Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::connection::{CheckDefinition, ComponentSnapshot, Snapshot, StatusDelta};
-use crate::native_commands::{execute_native, NativeResult};
+use crate::config::{AgentConfig, PluginSettings, ScriptSettings};
+use crate::connection::{
+    CheckDefinition, ComponentSnapshot, Snapshot, SnapshotDelta, SnapshotOperation, StatusDelta,
+    VerifySpec,
+};
+use crate::executor;
+use crate::metrics::metrics;
+use crate::native_commands::{evaluate_command_metric, execute_native, NativeResult};
+use crate::redact;
+use crate::secrets;
+use crate::shutdown;
 use crate::AgentState;
 
+use anomaly::AnomalyDetector;
+use history::{latency_from_metrics, CheckHistory, Sample};
+pub use overrides::Overrides;
+
 /// Check scheduler
 pub struct CheckScheduler {
     snapshot: Option<Snapshot>,
-    last_status: HashMap<String, String>, // component_id:check_name -> status
-    last_sent: HashMap<String, Instant>,  // component_id:check_name -> last sent time
+    last_status: Mutex<HashMap<String, String>>, // component_id:check_name -> status
+    last_sent: Mutex<HashMap<String, Instant>>,  // component_id:check_name -> last run time
+    plugins: PluginSettings,
+    scripting: ScriptSettings,
+    history: Mutex<CheckHistory>,
+    anomalies: Mutex<AnomalyDetector>,
+    last_results: Mutex<HashMap<String, StatusDelta>>, // component_id:check_name -> last result
+    paused: Mutex<HashMap<PauseKey, Option<Instant>>>, // -> paused until, or until resumed
+    overrides: Overrides,
+    batch_interval_secs: AtomicU64,
 }
 
+/// A paused component, or a check of it
+type PauseKey = (String, Option<String>);
+
 impl CheckScheduler {
     pub fn new() -> Self {
+        Self::from_config(&AgentConfig::default())
+    }
+
+    /// Create a scheduler using the scheduler, plugin and scripting settings from config
+    pub fn from_config(config: &AgentConfig) -> Self {
         Self {
             snapshot: None,
-            last_status: HashMap::new(),
-            last_sent: HashMap::new(),
+            last_status: Mutex::new(HashMap::new()),
+            last_sent: Mutex::new(HashMap::new()),
+            plugins: config.plugins.clone(),
+            scripting: config.scripting.clone(),
+            history: Mutex::new(CheckHistory::new(config.scheduler.history_size)),
+            anomalies: Mutex::new(AnomalyDetector::new()),
+            last_results: Mutex::new(HashMap::new()),
+            paused: Mutex::new(HashMap::new()),
+            overrides: Overrides::load(&config.scheduler.overrides_file).unwrap_or_else(|e| {
+                warn!(error = %e, "Check overrides not applied");
+                Overrides::default()
+            }),
+            batch_interval_secs: AtomicU64::new(config.scheduler.batch_send_interval_secs.max(1)),
+        }
+    }
+
+    /// Apply reloaded or pushed settings; a running scheduler picks up the
+    /// batch interval within a second
+    pub fn apply_config(&mut self, config: &AgentConfig) {
+        self.plugins = config.plugins.clone();
+        self.scripting = config.scripting.clone();
+        self.batch_interval_secs.store(
+            config.scheduler.batch_send_interval_secs.max(1),
+            Ordering::Relaxed,
+        );
+        self.history
+            .lock()
+            .unwrap()
+            .set_capacity(config.scheduler.history_size);
+    }
+
+    /// Re-read the check overrides file, keeping the current overrides if
+    /// it is invalid
+    pub fn reload_overrides(&mut self, config: &AgentConfig) -> anyhow::Result<()> {
+        self.overrides = Overrides::load(&config.scheduler.overrides_file)?;
+        if !self.overrides.checks.is_empty() {
+            info!(
+                rules = self.overrides.checks.len(),
+                "Loaded check overrides"
+            );
         }
+        Ok(())
     }
 
     /// Update the snapshot of components to manage
@@ -36,46 +113,233 @@ impl CheckScheduler {
             components = snapshot.components.len(),
             "Updated snapshot"
         );
+
+        let keys: std::collections::HashSet<String> = snapshot
+            .components
+            .iter()
+            .flat_map(|c| {
+                c.checks
+                    .iter()
+                    .map(move |check| format!("{}:{}", c.id, check.name))
+            })
+            .collect();
+        self.history
+            .lock()
+            .unwrap()
+            .retain_keys(|key| keys.contains(key));
+        self.anomalies
+            .lock()
+            .unwrap()
+            .retain_keys(|key| keys.contains(key));
+        self.last_results
+            .lock()
+            .unwrap()
+            .retain(|key, _| keys.contains(key));
+        self.last_status
+            .lock()
+            .unwrap()
+            .retain(|key, _| keys.contains(key));
+        self.last_sent
+            .lock()
+            .unwrap()
+            .retain(|key, _| keys.contains(key));
+
         self.snapshot = Some(snapshot);
     }
 
-    /// Run the scheduler
-    pub async fn run(&self, state: Arc<RwLock<AgentState>>) {
+    /// Apply a snapshot delta. Returns false, leaving the snapshot as is, when
+    /// the delta is not based on the version held; a full snapshot is needed.
+    pub fn apply_snapshot_delta(&mut self, delta: SnapshotDelta) -> bool {
+        let mut snapshot = match self.snapshot {
+            Some(ref snapshot) if snapshot.version == delta.base_version => snapshot.clone(),
+            _ => return false,
+        };
+
+        for operation in delta.operations {
+            match operation {
+                SnapshotOperation::Add { component } | SnapshotOperation::Update { component } => {
+                    match snapshot
+                        .components
+                        .iter_mut()
+                        .find(|c| c.id == component.id)
+                    {
+                        Some(existing) => *existing = component,
+                        None => snapshot.components.push(component),
+                    }
+                }
+                SnapshotOperation::Remove { component_id } => {
+                    snapshot.components.retain(|c| c.id != component_id);
+                }
+            }
+        }
+        snapshot.version = delta.version;
+
+        self.update_snapshot(snapshot);
+        true
+    }
+
+    /// Version of the current snapshot, if one has been received
+    pub fn snapshot_version(&self) -> Option<u64> {
+        self.snapshot.as_ref().map(|s| s.version)
+    }
+
+    /// Number of components and checks in the current snapshot
+    pub fn snapshot_size(&self) -> (usize, usize) {
+        self.snapshot
+            .as_ref()
+            .map(|s| {
+                let checks = s.components.iter().map(|c| c.checks.len()).sum();
+                (s.components.len(), checks)
+            })
+            .unwrap_or((0, 0))
+    }
+
+    /// A check of a component in the current snapshot
+    pub fn check(&self, component_id: &str, check_name: &str) -> Option<CheckDefinition> {
+        let component = self
+            .snapshot
+            .as_ref()?
+            .components
+            .iter()
+            .find(|c| c.id == component_id)?;
+        let check = component.checks.iter().find(|c| c.name == check_name)?;
+        Some(self.overrides.apply(component, check))
+    }
+
+    /// The verification an action of a component asks for, with the check
+    /// it names
+    pub fn action_verification(
+        &self,
+        component_id: &str,
+        action_name: &str,
+    ) -> Option<(VerifySpec, CheckDefinition)> {
+        let component = self
+            .snapshot
+            .as_ref()?
+            .components
+            .iter()
+            .find(|c| c.id == component_id)?;
+        let verify = component
+            .actions
+            .iter()
+            .find(|a| a.name == action_name)?
+            .verify
+            .clone()?;
+        let check = component.checks.iter().find(|c| c.name == verify.check)?;
+        Some((verify, self.overrides.apply(component, check)))
+    }
+
+    /// Run a verification check until it is ok. Returns its last message,
+    /// or why it did not pass in time.
+    pub async fn verify(
+        &self,
+        spec: &VerifySpec,
+        check: &CheckDefinition,
+    ) -> Result<String, String> {
+        tokio::time::sleep(Duration::from_secs(spec.delay_secs)).await;
+        let deadline = Instant::now() + Duration::from_secs(spec.timeout_secs);
+        let retry = Duration::from_secs(spec.interval_secs.max(1));
+
+        loop {
+            let (status, message) = match self.execute_check(check).await {
+                Ok(result) => (result.status, result.message.unwrap_or_default()),
+                Err(e) => ("error".to_string(), e),
+            };
+            if status == "ok" {
+                return Ok(message);
+            }
+            if Instant::now() + retry > deadline {
+                return Err(format!(
+                    "Verification failed: check '{}' is {} after {}s: {}",
+                    check.name, status, spec.timeout_secs, message
+                ));
+            }
+            debug!(check = %check.name, status = %status, "Verification pending");
+            tokio::time::sleep(retry).await;
+        }
+    }
+
+    /// Most recent result of each check, ordered by component and check name
+    pub fn last_results(&self) -> Vec<StatusDelta> {
+        let results = self.last_results.lock().unwrap();
+        let mut keys: Vec<&String> = results.keys().collect();
+        keys.sort();
+        keys.into_iter().map(|key| results[key].clone()).collect()
+    }
+
+    /// Stop running the checks of a component, or only `check`, for
+    /// `duration` or until resumed
+    pub fn pause(&self, component_id: &str, check: Option<&str>, duration: Option<Duration>) {
+        let key = (component_id.to_string(), check.map(|c| c.to_string()));
+        // Too long to fit an Instant is as good as until resumed
+        let until = duration.and_then(|d| Instant::now().checked_add(d));
+        self.paused.lock().unwrap().insert(key, until);
+    }
+
+    /// Run paused checks again: all of a component's without `check`, or
+    /// only `check` when it was paused by itself. Returns whether any were
+    /// paused.
+    pub fn resume(&self, component_id: &str, check: Option<&str>) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        let before = paused.len();
+        paused.retain(|(component, paused_check), _| {
+            component != component_id || (check.is_some() && paused_check.as_deref() != check)
+        });
+        paused.len() < before
+    }
+
+    /// Whether a check is paused, forgetting pauses that have run out
+    fn is_paused(&self, component_id: &str, check_name: &str) -> bool {
+        let now = Instant::now();
+        let mut paused = self.paused.lock().unwrap();
+        paused.retain(|(component, check), until| {
+            let expired = until.is_some_and(|until| until <= now);
+            if expired {
+                info!(component_id = %component, check = ?check, "Check pause ended");
+            }
+            !expired
+        });
+        paused.keys().any(|(component, check)| {
+            component == component_id && (check.is_none() || check.as_deref() == Some(check_name))
+        })
+    }
+
+    /// Run the scheduler of `state` until shutdown. The state is only read
+    /// while checks run, so messages and sends wait at most for one round.
+    pub async fn run(state: Arc<RwLock<AgentState>>) {
         let mut ticker = interval(Duration::from_secs(1));
-        let mut batch_ticker = interval(Duration::from_secs(60));
+        let batch_interval =
+            |state: &AgentState| state.scheduler.batch_interval_secs.load(Ordering::Relaxed);
+        let mut batch_secs = batch_interval(&*state.read().await);
+        let mut batch_ticker = interval(Duration::from_secs(batch_secs));
         let mut pending_deltas: Vec<StatusDelta> = Vec::new();
+        let _drain = shutdown::DrainGuard::acquire();
 
         loop {
             tokio::select! {
+                _ = shutdown::wait() => {
+                    // Stop scheduling; what was batched still goes out
+                    if !pending_deltas.is_empty() {
+                        state.write().await.send_status(pending_deltas).await;
+                    }
+                    return;
+                }
                 _ = ticker.tick() => {
-                    // Check which checks need to run
-                    let checks_to_run = self.get_due_checks().await;
-
-                    for (component, check) in checks_to_run {
-                        let result = self.execute_check(&check).await;
-
-                        if let Some(delta) = self.process_result(&component, &check, result).await {
-                            // Check if status changed
-                            let key = format!("{}:{}", component.id, check.name);
-                            let status_changed = self.last_status.get(&key)
-                                .map(|s| s != &delta.status)
-                                .unwrap_or(true);
-
-                            if status_changed {
-                                // Send immediately on status change
-                                let mut state = state.write().await;
-                                if let Some(ref mut conn) = state.connection {
-                                    if let Err(e) = conn.send_status_delta(delta.clone()).await {
-                                        warn!(error = %e, "Failed to send delta, buffering");
-                                        state.buffer.push(serde_json::to_value(&delta).unwrap());
-                                    }
-                                } else {
-                                    state.buffer.push(serde_json::to_value(&delta).unwrap());
-                                }
-                            } else {
-                                // Buffer for batch sending
-                                pending_deltas.push(delta);
-                            }
+                    let secs = batch_interval(&*state.read().await);
+                    if secs != batch_secs {
+                        debug!(batch_send_interval_secs = secs, "Batch interval changed");
+                        batch_secs = secs;
+                        let period = Duration::from_secs(secs);
+                        batch_ticker = interval_at(Instant::now() + period, period);
+                    }
+                    let results = state.read().await.scheduler.run_due_checks().await;
+                    for (delta, status_changed) in results {
+                        if status_changed {
+                            // Send immediately on status change
+                            state.write().await.send_status(vec![delta]).await;
+                        } else {
+                            // Buffer for batch sending
+                            pending_deltas.push(delta);
                         }
                     }
                 }
@@ -83,26 +347,84 @@ impl CheckScheduler {
                     // Send batched deltas
                     if !pending_deltas.is_empty() {
                         let deltas = std::mem::take(&mut pending_deltas);
-
-                        let mut state = state.write().await;
-                        if let Some(ref mut conn) = state.connection {
-                            if let Err(e) = conn.send_status_batch(deltas.clone()).await {
-                                warn!(error = %e, "Failed to send batch, buffering");
-                                for delta in deltas {
-                                    state.buffer.push(serde_json::to_value(&delta).unwrap());
-                                }
-                            }
-                        } else {
-                            for delta in deltas {
-                                state.buffer.push(serde_json::to_value(&delta).unwrap());
-                            }
-                        }
+                        state.write().await.send_status(deltas).await;
                     }
                 }
             }
         }
     }
 
+    /// Run the checks that are due, returning their results and whether
+    /// each changed status; unchanged ones carry trends
+    pub async fn run_due_checks(&self) -> Vec<(StatusDelta, bool)> {
+        let mut results = Vec::new();
+
+        for (component, check) in self.get_due_checks().await {
+            let key = format!("{}:{}", component.id, check.name);
+            self.last_sent
+                .lock()
+                .unwrap()
+                .insert(key.clone(), Instant::now());
+            let started = std::time::Instant::now();
+            let result = self.execute_check(&check).await.and_then(|result| {
+                self.anomalies
+                    .lock()
+                    .unwrap()
+                    .evaluate(&key, &check.config, result)
+            });
+            let elapsed = started.elapsed();
+
+            if let Some(mut delta) = self.process_result(&component, &check, result).await {
+                metrics().observe_check(&check.check_type, &delta.status, elapsed);
+                self.record_history(&key, &delta);
+
+                // Check if status changed
+                let status_changed = self
+                    .last_status
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), delta.status.clone())
+                    .map(|s| s != delta.status)
+                    .unwrap_or(true);
+                if !status_changed {
+                    self.attach_trend(&key, &mut delta);
+                }
+                results.push((delta, status_changed));
+            }
+        }
+
+        results
+    }
+
+    /// Record a result in the per-check history and as the check's last result
+    fn record_history(&self, key: &str, delta: &StatusDelta) {
+        let sample = Sample {
+            at: delta.timestamp,
+            status: delta.status.clone(),
+            latency_ms: delta.metrics.as_ref().and_then(latency_from_metrics),
+        };
+        self.history.lock().unwrap().record(key, sample);
+        self.last_results
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), delta.clone());
+    }
+
+    /// Add availability, p95 latency and failure streak to a delta's metrics
+    fn attach_trend(&self, key: &str, delta: &mut StatusDelta) {
+        let trend = match self.history.lock().unwrap().trend(key, delta.timestamp) {
+            Some(trend) => trend,
+            None => return,
+        };
+
+        match delta.metrics {
+            Some(serde_json::Value::Object(ref mut metrics)) => {
+                metrics.insert("trend".to_string(), trend);
+            }
+            _ => delta.metrics = Some(serde_json::json!({ "trend": trend })),
+        }
+    }
+
     /// Get checks that are due to run
     async fn get_due_checks(&self) -> Vec<(ComponentSnapshot, CheckDefinition)> {
         let mut due = Vec::new();
@@ -112,8 +434,14 @@ impl CheckScheduler {
 
             for component in &snapshot.components {
                 for check in &component.checks {
+                    if self.is_paused(&component.id, &check.name)
+                        || self.overrides.disabled(component, check)
+                    {
+                        continue;
+                    }
+                    let check = &self.overrides.apply(component, check);
                     let key = format!("{}:{}", component.id, check.name);
-                    let last_run = self.last_sent.get(&key).copied();
+                    let last_run = self.last_sent.lock().unwrap().get(&key).copied();
 
                     let should_run = match last_run {
                         None => true,
@@ -130,13 +458,65 @@ impl CheckScheduler {
         due
     }
 
-    /// Execute a single check
-    async fn execute_check(&self, check: &CheckDefinition) -> Result<NativeResult, String> {
+    /// Execute a single check, resolving the secrets its config references
+    pub async fn execute_check(&self, check: &CheckDefinition) -> Result<NativeResult, String> {
+        if !secrets::has_references(&check.config.to_string()) {
+            return self.run_check(check).await;
+        }
+
+        let mut resolved = check.clone();
+        resolved.config = secrets::expand_value(&check.config, &secrets::provider())
+            .await
+            .map_err(|e| format!("{:#}", e))?;
+        // A failing check may echo its config, secrets included
+        let mut result = self
+            .run_check(&resolved)
+            .await
+            .map_err(|e| redact::text(&e))?;
+        result.message = result.message.map(|message| redact::text(&message));
+        Ok(result)
+    }
+
+    async fn run_check(&self, check: &CheckDefinition) -> Result<NativeResult, String> {
         debug!(check = %check.name, check_type = %check.check_type, "Executing check");
 
+        if check.check_type == "command_metric" {
+            return self
+                .execute_command_metric_check(check)
+                .await
+                .map_err(|e| e.to_string());
+        }
+
+        if check.check_type == "plugin" {
+            return crate::plugins::execute_plugin(&self.plugins, check)
+                .await
+                .map_err(|e| e.to_string());
+        }
+
+        if check.check_type == "wasm" {
+            #[cfg(feature = "wasm")]
+            return crate::wasm::execute_check(check)
+                .await
+                .map_err(|e| e.to_string());
+            #[cfg(not(feature = "wasm"))]
+            return Err("Agent was built without WASM support".to_string());
+        }
+
+        if check.check_type == "script" {
+            #[cfg(feature = "scripting")]
+            return crate::scripting::execute_script(&self.scripting, check)
+                .await
+                .map_err(|e| e.to_string());
+            #[cfg(not(feature = "scripting"))]
+            return Err("Agent was built without script support".to_string());
+        }
+
         // For native checks, use the native_commands module
         if check.check_type.starts_with("native:") || !check.check_type.contains(':') {
-            let native_type = check.check_type.strip_prefix("native:").unwrap_or(&check.check_type);
+            let native_type = check
+                .check_type
+                .strip_prefix("native:")
+                .unwrap_or(&check.check_type);
             match execute_native(native_type, &check.config) {
                 Ok(result) => Ok(result),
                 Err(e) => Err(e.to_string()),
@@ -152,10 +532,10 @@ impl CheckScheduler {
 
     /// Execute a shell-based check
     async fn execute_shell_check(&self, check: &CheckDefinition) -> anyhow::Result<NativeResult> {
-        use tokio::process::Command;
         use tokio::time::timeout;
 
-        let command = check.config
+        let command = check
+            .config
             .get("command")
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow::anyhow!("Missing command in check config"))?;
@@ -164,11 +544,9 @@ impl CheckScheduler {
 
         let result = timeout(
             Duration::from_secs(check.timeout_secs),
-            Command::new("sh")
-                .arg("-c")
-                .arg(command)
-                .output()
-        ).await;
+            executor::shell(command).kill_on_drop(true).output(),
+        )
+        .await;
 
         let duration_ms = start.elapsed().as_millis() as u64;
 
@@ -212,6 +590,40 @@ impl CheckScheduler {
         }
     }
 
+    /// Execute a command and evaluate a numeric value extracted from its output
+    async fn execute_command_metric_check(
+        &self,
+        check: &CheckDefinition,
+    ) -> anyhow::Result<NativeResult> {
+        use tokio::time::timeout;
+
+        let command = check
+            .config
+            .get("command")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!("Missing command in check config"))?;
+
+        let start = std::time::Instant::now();
+
+        let output = timeout(
+            Duration::from_secs(check.timeout_secs),
+            executor::shell(command).kill_on_drop(true).output(),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Check timed out after {}s", check.timeout_secs))??;
+
+        let duration_ms = start.elapsed().as_millis() as u64;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let exit_code = output.status.code().unwrap_or(-1);
+
+        let mut result = evaluate_command_metric(&check.config, exit_code, &stdout)?;
+        if let Some(metrics) = result.metrics.as_object_mut() {
+            metrics.insert("duration_ms".to_string(), serde_json::json!(duration_ms));
+        }
+
+        Ok(result)
+    }
+
     /// Process a check result and create a delta if needed
     async fn process_result(
         &self,
@@ -237,7 +649,7 @@ impl CheckScheduler {
             check_name: check.name.clone(),
             status,
             message,
-            metrics: metrics.unwrap_or(serde_json::Value::Null),
+            metrics,
             timestamp: chrono::Utc::now(),
         })
     }
@@ -248,3 +660,152 @@ impl Default for CheckScheduler {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(id: &str) -> ComponentSnapshot {
+        ComponentSnapshot {
+            id: id.to_string(),
+            name: id.to_string(),
+            component_type: "service".to_string(),
+            checks: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    fn delta(base_version: u64, operations: Vec<SnapshotOperation>) -> SnapshotDelta {
+        SnapshotDelta {
+            base_version,
+            version: base_version + 1,
+            operations,
+        }
+    }
+
+    #[test]
+    fn test_apply_snapshot_delta() {
+        let mut scheduler = CheckScheduler::new();
+        assert!(!scheduler.apply_snapshot_delta(delta(1, Vec::new())));
+
+        scheduler.update_snapshot(Snapshot {
+            version: 1,
+            components: vec![component("web"), component("db")],
+        });
+
+        let mut renamed = component("db");
+        renamed.name = "postgres".to_string();
+        assert!(scheduler.apply_snapshot_delta(delta(
+            1,
+            vec![
+                SnapshotOperation::Remove {
+                    component_id: "web".to_string()
+                },
+                SnapshotOperation::Update { component: renamed },
+                SnapshotOperation::Add {
+                    component: component("cache")
+                },
+            ],
+        )));
+        assert_eq!(scheduler.snapshot_version(), Some(2));
+        assert_eq!(scheduler.snapshot_size(), (2, 0));
+
+        // A gap leaves the snapshot untouched
+        assert!(!scheduler.apply_snapshot_delta(delta(5, Vec::new())));
+        assert_eq!(scheduler.snapshot_version(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let check = |name: &str| CheckDefinition {
+            name: name.to_string(),
+            check_type: "tcp_port".to_string(),
+            config: serde_json::json!({ "port": 1 }),
+            interval_secs: 30,
+            timeout_secs: 5,
+        };
+        let mut web = component("web");
+        web.checks = vec![check("port"), check("health")];
+        let mut db = component("db");
+        db.checks = vec![check("port")];
+        let mut scheduler = CheckScheduler::new();
+        scheduler.update_snapshot(Snapshot {
+            version: 1,
+            components: vec![web, db],
+        });
+        async fn due(scheduler: &CheckScheduler) -> Vec<String> {
+            let due = scheduler.get_due_checks().await;
+            due.into_iter()
+                .map(|(component, check)| format!("{}:{}", component.id, check.name))
+                .collect()
+        }
+
+        scheduler.pause("web", None, None);
+        scheduler.pause("db", Some("port"), Some(Duration::from_secs(3600)));
+        assert!(due(&scheduler).await.is_empty());
+
+        // Resuming a check leaves its component's pause
+        assert!(!scheduler.resume("web", Some("health")));
+        assert!(due(&scheduler).await.is_empty());
+        assert!(scheduler.resume("web", None));
+        assert_eq!(due(&scheduler).await, ["web:port", "web:health"]);
+
+        // A pause that ran out
+        scheduler.pause("db", Some("port"), Some(Duration::ZERO));
+        assert_eq!(due(&scheduler).await.len(), 3);
+
+        // Too long to reach an end is until resumed
+        scheduler.pause("web", None, Some(Duration::MAX));
+        assert_eq!(due(&scheduler).await, ["db:port"]);
+    }
+
+    #[tokio::test]
+    async fn test_action_verification() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let check = |name: &str, port: u16| CheckDefinition {
+            name: name.to_string(),
+            check_type: "tcp_port".to_string(),
+            config: serde_json::json!({ "port": port }),
+            interval_secs: 30,
+            timeout_secs: 5,
+        };
+        let action = serde_json::from_value(serde_json::json!({
+            "name": "restart",
+            "command": "systemctl restart app",
+            "verify": { "check": "listening" },
+        }))
+        .unwrap();
+        let mut web = component("web");
+        web.checks = vec![check("listening", port), check("closed", 1)];
+        web.actions = vec![action];
+
+        let mut scheduler = CheckScheduler::new();
+        scheduler.update_snapshot(Snapshot {
+            version: 1,
+            components: vec![web],
+        });
+        assert!(scheduler.action_verification("web", "stop").is_none());
+        assert!(scheduler.action_verification("db", "restart").is_none());
+        assert_eq!(
+            scheduler.check("web", "closed").map(|c| c.interval_secs),
+            Some(30)
+        );
+        assert!(scheduler.check("web", "missing").is_none());
+        let (mut spec, listening) = scheduler.action_verification("web", "restart").unwrap();
+        assert_eq!((spec.timeout_secs, spec.interval_secs), (60, 5));
+        assert!(scheduler.verify(&spec, &listening).await.is_ok());
+
+        // Nothing listens on port 1
+        spec.timeout_secs = 0;
+        let error = scheduler
+            .verify(&spec, &check("closed", 1))
+            .await
+            .unwrap_err();
+        assert!(
+            error.starts_with("Verification failed: check 'closed' is error"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Local check overrides
+//!
+//! Host owners sometimes need exceptions the backend does not model. The
+//! file at `scheduler.overrides_file` adjusts the checks of the snapshot on
+//! this host only:
+//!
+//! ```yaml
+//! checks:
+//!   - component: postgres     # component id or name, "*" for all
+//!     check: disk_space       # check name, "*" (the default) for all
+//!     interval_secs: 300
+//!     config:                 # top-level keys replace the check's own
+//!       warning_threshold: 95
+//!   - component: batch-*      # a trailing "*" matches a prefix
+//!     check: cpu
+//!     disabled: true          # not scheduled, still runs on demand
+//! ```
+//!
+//! Rules apply in order, so a later one wins. The file is read at start and
+//! again on a config reload or when it changes.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::connection::{CheckDefinition, ComponentSnapshot};
+
+/// Check overrides from the local file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    #[serde(default)]
+    pub checks: Vec<CheckOverride>,
+}
+
+/// Changes to the checks a rule matches
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckOverride {
+    #[serde(default = "default_pattern")]
+    pub component: String,
+    #[serde(default = "default_pattern")]
+    pub check: String,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub config: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_pattern() -> String {
+    "*".to_string()
+}
+
+impl Overrides {
+    /// Read the overrides file; there are none when it does not exist
+    pub fn load(path: &str) -> Result<Self> {
+        if path.is_empty() || !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read check overrides: {}", path))?;
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let overrides: Self = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse check overrides: {}", path))?;
+        if let Some(rule) = overrides.checks.iter().find(|r| r.interval_secs == Some(0)) {
+            anyhow::bail!(
+                "{}: interval_secs of {}/{} must be greater than 0, use disabled",
+                path,
+                rule.component,
+                rule.check
+            );
+        }
+        Ok(overrides)
+    }
+
+    /// Whether a rule disables a check from being scheduled
+    pub fn disabled(&self, component: &ComponentSnapshot, check: &CheckDefinition) -> bool {
+        self.matching(component, check).any(|rule| rule.disabled)
+    }
+
+    /// A check with the rules that match it applied
+    pub fn apply(&self, component: &ComponentSnapshot, check: &CheckDefinition) -> CheckDefinition {
+        let rules = self.matching(component, check);
+        let mut check = check.clone();
+        for rule in rules {
+            if let Some(interval) = rule.interval_secs {
+                check.interval_secs = interval;
+            }
+            if let Some(timeout) = rule.timeout_secs {
+                check.timeout_secs = timeout;
+            }
+            if !rule.config.is_empty() {
+                if !check.config.is_object() {
+                    check.config = serde_json::Value::Object(Default::default());
+                }
+                if let Some(config) = check.config.as_object_mut() {
+                    config.extend(rule.config.clone());
+                }
+            }
+        }
+        check
+    }
+
+    fn matching<'a>(
+        &'a self,
+        component: &'a ComponentSnapshot,
+        check: &'a CheckDefinition,
+    ) -> impl Iterator<Item = &'a CheckOverride> {
+        self.checks.iter().filter(move |rule| {
+            (matches(&rule.component, &component.id) || matches(&rule.component, &component.name))
+                && matches(&rule.check, &check.name)
+        })
+    }
+}
+
+/// `pattern` is a name, or a prefix followed by `*`
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let overrides: Overrides = serde_yaml::from_str(
+            r#"
+checks:
+  - component: postgres
+    check: disk_space
+    interval_secs: 300
+    config:
+      warning_threshold: 95
+  - component: batch-*
+    disabled: true
+  - check: disk_space
+    timeout_secs: 20
+"#,
+        )
+        .unwrap();
+
+        let check = CheckDefinition {
+            name: "disk_space".to_string(),
+            check_type: "disk_space".to_string(),
+            config: serde_json::json!({"path": "/", "warning_threshold": 80}),
+            interval_secs: 60,
+            timeout_secs: 10,
+        };
+        let component = |id: &str, name: &str| ComponentSnapshot {
+            id: id.to_string(),
+            name: name.to_string(),
+            component_type: "service".to_string(),
+            checks: vec![check.clone()],
+            actions: Vec::new(),
+        };
+
+        let db = component("c-1", "postgres");
+        let applied = overrides.apply(&db, &check);
+        assert_eq!((applied.interval_secs, applied.timeout_secs), (300, 20));
+        assert_eq!(
+            applied.config,
+            serde_json::json!({"path": "/", "warning_threshold": 95})
+        );
+        assert!(!overrides.disabled(&db, &check));
+
+        let web = component("web", "nginx");
+        assert_eq!(overrides.apply(&web, &check).interval_secs, 60);
+        assert!(overrides.disabled(&component("batch-7", "reports"), &check));
+
+        assert!(serde_yaml::from_str::<Overrides>("checks:\n  - interval: 5\n").is_err());
+        assert!(Overrides::load("/nonexistent/overrides.yaml")
+            .unwrap()
+            .checks
+            .is_empty());
+    }
+}
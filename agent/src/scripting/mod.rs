@@ -0,0 +1,329 @@
+//! Embedded scripting checks
+//!
+//! Runs a sandboxed Rhai script delivered in the check config. Scripts get
+//! a `params` map from the config and a few safe bindings:
+//! - `read_file(path)`: read a file under `allowed_read_paths`
+//! - `http_get(url)`: probe a URL whose host is in `allowed_http_hosts`
+//!
+//! A script returns either a status string (`"ok"`) or a map
+//! `#{ status: "warning", message: "...", metrics: #{ ... } }`.
+
+use anyhow::{anyhow, Result};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::time::Duration;
+use tracing::debug;
+
+use crate::config::ScriptSettings;
+use crate::connection::CheckDefinition;
+use crate::native_commands::{apply_threshold_expressions, NativeResult};
+
+/// Largest file a script may read
+const MAX_READ_BYTES: u64 = 1024 * 1024;
+
+/// Largest HTTP response body handed back to a script
+const MAX_HTTP_BODY_BYTES: usize = 64 * 1024;
+
+/// Map returned by a script
+#[derive(Debug, Deserialize)]
+struct ScriptOutput {
+    status: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    metrics: serde_json::Value,
+}
+
+/// Execute a script check
+pub async fn execute_script(
+    settings: &ScriptSettings,
+    check: &CheckDefinition,
+) -> Result<NativeResult> {
+    if !settings.enabled {
+        return Err(anyhow!("Script checks are disabled on this agent"));
+    }
+
+    let script = check
+        .config
+        .get("script")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'script' in script check config"))?
+        .to_string();
+
+    let params = check
+        .config
+        .get("params")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+
+    let settings = settings.clone();
+    let timeout = Duration::from_secs(check.timeout_secs);
+    let start = Instant::now();
+
+    // Rhai evaluation is synchronous; keep it off the async workers
+    let output =
+        tokio::task::spawn_blocking(move || run_script(&settings, &script, params, timeout))
+            .await
+            .map_err(|e| anyhow!("Script task failed: {}", e))??;
+
+    let duration_ms = start.elapsed().as_millis() as u64;
+
+    let mut metrics = match output.metrics {
+        serde_json::Value::Object(map) => map,
+        serde_json::Value::Null => serde_json::Map::new(),
+        other => {
+            let mut map = serde_json::Map::new();
+            map.insert("value".to_string(), other);
+            map
+        }
+    };
+    metrics.insert("duration_ms".to_string(), serde_json::json!(duration_ms));
+
+    let result = NativeResult {
+        status: output.status,
+        message: output.message,
+        metrics: serde_json::Value::Object(metrics),
+    };
+
+    apply_threshold_expressions(&check.config, result)
+}
+
+/// Evaluate a script in a fresh sandboxed engine
+fn run_script(
+    settings: &ScriptSettings,
+    script: &str,
+    params: serde_json::Value,
+    timeout: Duration,
+) -> Result<ScriptOutput> {
+    let engine = build_engine(settings, Instant::now() + timeout);
+
+    let mut scope = Scope::new();
+    let params =
+        rhai::serde::to_dynamic(params).map_err(|e| anyhow!("Invalid script params: {}", e))?;
+    scope.push_constant("params", params);
+
+    let value: Dynamic = engine
+        .eval_with_scope(&mut scope, script)
+        .map_err(|e| anyhow!("Script error: {}", e))?;
+
+    let output = if value.is_string() {
+        ScriptOutput {
+            status: value.into_string().unwrap_or_default(),
+            message: None,
+            metrics: serde_json::Value::Null,
+        }
+    } else if value.is_map() {
+        rhai::serde::from_dynamic(&value).map_err(|e| anyhow!("Invalid script result: {}", e))?
+    } else {
+        return Err(anyhow!(
+            "Script must return a status string or a map, got {}",
+            value.type_name()
+        ));
+    };
+
+    if !matches!(
+        output.status.as_str(),
+        "ok" | "warning" | "error" | "unknown"
+    ) {
+        return Err(anyhow!("Script returned invalid status: {}", output.status));
+    }
+
+    Ok(output)
+}
+
+/// Build an engine with resource limits and only the safe bindings
+fn build_engine(settings: &ScriptSettings, deadline: Instant) -> Engine {
+    let mut engine = Engine::new();
+
+    // No module imports from disk, no dynamic eval
+    engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+
+    engine.set_max_operations(settings.max_operations);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(MAX_READ_BYTES as usize);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+
+    engine.on_progress(move |_| {
+        if Instant::now() > deadline {
+            Some(Dynamic::from("Script timed out"))
+        } else {
+            None
+        }
+    });
+
+    let read_roots: Vec<PathBuf> = settings
+        .allowed_read_paths
+        .iter()
+        .filter_map(|p| std::fs::canonicalize(p).ok())
+        .collect();
+    engine.register_fn(
+        "read_file",
+        move |path: &str| -> Result<String, Box<EvalAltResult>> {
+            read_file(&read_roots, path).map_err(|e| e.to_string().into())
+        },
+    );
+
+    #[cfg(feature = "http")]
+    {
+        let http_hosts = settings.allowed_http_hosts.clone();
+        engine.register_fn(
+            "http_get",
+            move |url: &str| -> Result<rhai::Map, Box<EvalAltResult>> {
+                http_get(&http_hosts, url, deadline).map_err(|e| e.to_string().into())
+            },
+        );
+    }
+
+    engine
+}
+
+/// Read a file, refusing anything outside the allowed directories
+fn read_file(roots: &[PathBuf], path: &str) -> Result<String> {
+    let canonical =
+        std::fs::canonicalize(path).map_err(|e| anyhow!("Cannot read '{}': {}", path, e))?;
+
+    if !roots.iter().any(|root| canonical.starts_with(root)) {
+        return Err(anyhow!("Reading '{}' is not allowed", path));
+    }
+
+    let size = std::fs::metadata(&canonical)?.len();
+    if size > MAX_READ_BYTES {
+        return Err(anyhow!("File '{}' is too large ({} bytes)", path, size));
+    }
+
+    debug!(path = %canonical.display(), "Script reading file");
+    Ok(std::fs::read_to_string(Path::new(&canonical))?)
+}
+
+/// Perform a GET request against an allowed host
+#[cfg(feature = "http")]
+fn http_get(allowed_hosts: &[String], url: &str, deadline: Instant) -> Result<rhai::Map> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid URL '{}': {}", url, e))?;
+    let host = parsed.host_str().unwrap_or_default();
+
+    if !allowed_hosts.iter().any(|allowed| allowed == host) {
+        return Err(anyhow!("HTTP probes to '{}' are not allowed", host));
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let client = reqwest::blocking::Client::builder()
+        .timeout(remaining.min(Duration::from_secs(10)))
+        .build()?;
+
+    let start = Instant::now();
+    let response = client.get(parsed).send()?;
+    let status = response.status().as_u16();
+    let mut body = response.text()?;
+    let response_time_ms = start.elapsed().as_millis() as i64;
+
+    if body.len() > MAX_HTTP_BODY_BYTES {
+        let mut cut = MAX_HTTP_BODY_BYTES;
+        while !body.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        body.truncate(cut);
+    }
+
+    let mut result = rhai::Map::new();
+    result.insert("status".into(), Dynamic::from(status as i64));
+    result.insert("body".into(), Dynamic::from(body));
+    result.insert("response_time_ms".into(), Dynamic::from(response_time_ms));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn settings() -> ScriptSettings {
+        ScriptSettings::default()
+    }
+
+    #[test]
+    fn test_script_returns_map() {
+        let script = r#"
+            let used = params.used;
+            let status = if used > 80 { "warning" } else { "ok" };
+            #{ status: status, message: `used ${used}%`, metrics: #{ used: used } }
+        "#;
+
+        let output = run_script(
+            &settings(),
+            script,
+            json!({ "used": 85 }),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(output.status, "warning");
+        assert_eq!(output.message.as_deref(), Some("used 85%"));
+        assert_eq!(output.metrics["used"], 85);
+    }
+
+    #[test]
+    fn test_script_returns_status_string() {
+        let output =
+            run_script(&settings(), r#""ok""#, json!(null), Duration::from_secs(5)).unwrap();
+        assert_eq!(output.status, "ok");
+    }
+
+    #[test]
+    fn test_script_invalid_status() {
+        assert!(run_script(
+            &settings(),
+            r#""great""#,
+            json!(null),
+            Duration::from_secs(5)
+        )
+        .is_err());
+        assert!(run_script(&settings(), "42", json!(null), Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_script_operation_limit() {
+        let mut limited = settings();
+        limited.max_operations = 1000;
+        let result = run_script(&limited, "loop {}", json!(null), Duration::from_secs(5));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_script_sandboxed_file_access() {
+        let dir = std::env::temp_dir().join(format!("opsmap-script-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("state"), "ready").unwrap();
+
+        let mut allowed = settings();
+        allowed.allowed_read_paths = vec![dir.to_string_lossy().to_string()];
+
+        let script = format!(
+            r#"if read_file("{}") == "ready" {{ "ok" }} else {{ "error" }}"#,
+            dir.join("state").display()
+        );
+        let output = run_script(&allowed, &script, json!(null), Duration::from_secs(5)).unwrap();
+        assert_eq!(output.status, "ok");
+
+        let denied = run_script(
+            &allowed,
+            r#"read_file("/etc/hostname")"#,
+            json!(null),
+            Duration::from_secs(5),
+        );
+        assert!(denied.is_err());
+
+        let denied = run_script(
+            &allowed,
+            r#"http_get("http://example.com/")"#,
+            json!(null),
+            Duration::from_secs(5),
+        );
+        assert!(denied.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
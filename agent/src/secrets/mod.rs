@@ -0,0 +1,319 @@
+//! Secrets providers
+//!
+//! Check configs and action env may reference a secret as
+//! `{{secret:NAME}}` (or `${secret:NAME}` in action env). The reference is
+//! what the backend stores and the Gateway ships in snapshots; the agent
+//! resolves it when the check or action runs, from `secrets.provider`:
+//!
+//! - `file`: the file `NAME` in `jobs.secrets_dir`, without its trailing
+//!   newline;
+//! - `env`: the variable `secrets.env_prefix` + `NAME` upper-cased, with
+//!   `-` and `.` as `_`;
+//! - `exec`: what `secrets.exec_command NAME` prints, e.g. a wrapper around
+//!   a password manager's CLI;
+//! - `vault`: the field `value` of the KV v2 secret `NAME` under
+//!   `secrets.vault.path`, or the field after `#` in `NAME#field`.
+//!
+//! Values from `exec` and `vault` are cached for `secrets.cache_ttl_secs`.
+//! Every resolved value is masked in logs and results from then on.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{paths, AgentConfig, VaultSettings};
+use crate::redact;
+
+const REFERENCES: [(&str, &str); 2] = [("{{secret:", "}}"), ("${secret:", "}")];
+
+/// Where secrets come from
+#[derive(Debug, Clone)]
+pub enum Provider {
+    File {
+        dir: String,
+    },
+    Env {
+        prefix: String,
+    },
+    Exec {
+        command: Vec<String>,
+        timeout: Duration,
+    },
+    Vault(VaultSettings),
+}
+
+/// The provider and cache lifetime in use
+struct Current {
+    provider: Provider,
+    cache_ttl: Duration,
+}
+
+static CURRENT: RwLock<Option<Current>> = RwLock::new(None);
+
+/// Values from slow providers, with when they were fetched
+static CACHE: Mutex<Option<HashMap<String, (Instant, String)>>> = Mutex::new(None);
+
+impl Provider {
+    /// The provider `config` names; validation has checked its settings
+    pub fn from_config(config: &AgentConfig) -> Self {
+        let secrets = &config.secrets;
+        match secrets.provider.as_str() {
+            "env" => Provider::Env {
+                prefix: secrets.env_prefix.clone(),
+            },
+            "exec" => Provider::Exec {
+                command: secrets
+                    .exec_command
+                    .split_whitespace()
+                    .map(|s| s.to_string())
+                    .collect(),
+                timeout: Duration::from_secs(secrets.exec_timeout_secs),
+            },
+            "vault" => Provider::Vault(secrets.vault.clone()),
+            _ => Provider::File {
+                dir: config.jobs.secrets_dir.clone(),
+            },
+        }
+    }
+
+    /// The value of secret `name`
+    pub async fn get(&self, name: &str) -> Result<String> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Invalid secret name: {:?}", name);
+        }
+        match self {
+            Provider::File { dir } => {
+                let path = paths::join(dir, name);
+                let secret = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read secret {}", path))?;
+                Ok(secret.trim_end_matches(['\n', '\r']).to_string())
+            }
+            Provider::Env { prefix } => {
+                let var = format!("{}{}", prefix, name.to_uppercase().replace(['-', '.'], "_"));
+                std::env::var(&var)
+                    .with_context(|| format!("Secret {} is not set in {}", name, var))
+            }
+            Provider::Exec { command, timeout } => exec(command, name, *timeout).await,
+            Provider::Vault(vault) => vault_get(vault, name).await,
+        }
+    }
+
+    fn cached(&self) -> bool {
+        matches!(self, Provider::Exec { .. } | Provider::Vault(_))
+    }
+}
+
+/// Resolve secrets with what `config` says from now on
+pub fn configure(config: &AgentConfig) {
+    *CURRENT.write().unwrap() = Some(Current {
+        provider: Provider::from_config(config),
+        cache_ttl: Duration::from_secs(config.secrets.cache_ttl_secs),
+    });
+    *CACHE.lock().unwrap() = None;
+}
+
+/// The configured provider
+pub fn provider() -> Provider {
+    match *CURRENT.read().unwrap() {
+        Some(ref current) => current.provider.clone(),
+        None => Provider::from_config(&AgentConfig::default()),
+    }
+}
+
+/// Whether `text` references a secret
+pub fn has_references(text: &str) -> bool {
+    REFERENCES.iter().any(|(open, _)| text.contains(open))
+}
+
+/// `text` with its secret references replaced by their values
+pub async fn expand(text: &str, provider: &Provider) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some((pos, open, close)) = REFERENCES
+        .iter()
+        .filter_map(|(open, close)| rest.find(open).map(|pos| (pos, *open, *close)))
+        .min_by_key(|(pos, _, _)| *pos)
+    {
+        output.push_str(&rest[..pos]);
+        let after = &rest[pos + open.len()..];
+        let end = after
+            .find(close)
+            .ok_or_else(|| anyhow!("Unterminated secret reference"))?;
+        let secret = lookup(&after[..end], provider).await?;
+        redact::remember(&secret);
+        output.push_str(&secret);
+        rest = &after[end + close.len()..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// A JSON value with the secret references in its strings replaced
+pub async fn expand_value(value: &Value, provider: &Provider) -> Result<Value> {
+    let mut expanded = value.clone();
+    let mut pending = vec![&mut expanded];
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::String(s) if has_references(s) => *s = expand(s, provider).await?,
+            Value::Object(map) => pending.extend(map.values_mut()),
+            Value::Array(items) => pending.extend(items.iter_mut()),
+            _ => {}
+        }
+    }
+    Ok(expanded)
+}
+
+async fn lookup(name: &str, provider: &Provider) -> Result<String> {
+    let ttl = match *CURRENT.read().unwrap() {
+        Some(ref current) if provider.cached() => current.cache_ttl,
+        _ => Duration::ZERO,
+    };
+    if !ttl.is_zero() {
+        let cache = CACHE.lock().unwrap();
+        if let Some((fetched, value)) = cache.as_ref().and_then(|c| c.get(name)) {
+            if fetched.elapsed() < ttl {
+                return Ok(value.clone());
+            }
+        }
+    }
+
+    let value = provider.get(name).await?;
+    if !ttl.is_zero() {
+        CACHE
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(name.to_string(), (Instant::now(), value.clone()));
+    }
+    Ok(value)
+}
+
+async fn exec(command: &[String], name: &str, timeout: Duration) -> Result<String> {
+    let program = command
+        .first()
+        .ok_or_else(|| anyhow!("secrets.exec_command is not set"))?;
+    let output = tokio::time::timeout(
+        timeout,
+        tokio::process::Command::new(program)
+            .args(&command[1..])
+            .arg(name)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| anyhow!("Secret command timed out for {}", name))?
+    .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        bail!(
+            "Secret command failed for {}: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let value = String::from_utf8(output.stdout).context("Secret is not valid UTF-8")?;
+    Ok(value.trim_end_matches(['\n', '\r']).to_string())
+}
+
+#[cfg(feature = "http")]
+async fn vault_get(vault: &VaultSettings, name: &str) -> Result<String> {
+    let (path, field) = name.split_once('#').unwrap_or((name, "value"));
+    let token = match vault.token_file {
+        Some(ref file) => std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file))?
+            .trim()
+            .to_string(),
+        None => std::env::var("VAULT_TOKEN").context("VAULT_TOKEN is not set")?,
+    };
+
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+    if let Some(ref ca_file) = vault.ca_file {
+        let pem = std::fs::read(ca_file).with_context(|| format!("Failed to read {}", ca_file))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    let url = format!(
+        "{}/v1/{}/data/{}",
+        vault.addr.trim_end_matches('/'),
+        vault.mount,
+        [vault.path.trim_matches('/'), path]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/")
+    );
+    let response = builder
+        .build()?
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach Vault for {}", name))?;
+    if !response.status().is_success() {
+        bail!("Vault answered {} for {}", response.status(), name);
+    }
+    let body: Value = response.json().await?;
+    match body["data"]["data"].get(field) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(value) if !value.is_null() => Ok(value.to_string()),
+        _ => bail!("Vault secret {} has no field {}", path, field),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+async fn vault_get(_vault: &VaultSettings, _name: &str) -> Result<String> {
+    bail!("Agent was built without HTTP support, needed for Vault")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_expand() {
+        let dir = std::env::temp_dir().join(format!("opsmap-secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("db_password"), "s3cret\n").unwrap();
+        let file = Provider::File {
+            dir: dir.to_string_lossy().to_string(),
+        };
+
+        let config = serde_json::json!({
+            "url": "postgres://app:{{secret:db_password}}@db/app",
+            "headers": [{"X-Token": "${secret:db_password}"}],
+            "port": 5432,
+        });
+        let expanded = expand_value(&config, &file).await.unwrap();
+        assert_eq!(expanded["url"], "postgres://app:s3cret@db/app");
+        assert_eq!(expanded["headers"][0]["X-Token"], "s3cret");
+        assert_eq!(expanded["port"], 5432);
+
+        for bad in [
+            "{{secret:missing}}",
+            "{{secret:../etc/passwd}}",
+            "{{secret:db_password",
+        ] {
+            assert!(expand(bad, &file).await.is_err(), "{}", bad);
+        }
+
+        std::env::set_var("OPSMAP_TEST_SECRET_API_KEY", "k3y");
+        let env = Provider::Env {
+            prefix: "OPSMAP_TEST_SECRET_".to_string(),
+        };
+        assert_eq!(expand("{{secret:api-key}}", &env).await.unwrap(), "k3y");
+
+        #[cfg(unix)]
+        {
+            let exec = Provider::Exec {
+                command: vec!["echo".to_string(), "from".to_string()],
+                timeout: Duration::from_secs(5),
+            };
+            assert_eq!(expand("{{secret:exec}}", &exec).await.unwrap(), "from exec");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
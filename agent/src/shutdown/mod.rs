@@ -0,0 +1,155 @@
+//! Graceful shutdown
+//!
+//! On SIGTERM or SIGINT the agent stops scheduling checks, hands pending
+//! deltas to the send path, tells the Gateway it is leaving and keeps
+//! whatever the Gateway has not acked in the offline buffer, all within
+//! `agent.shutdown_timeout_secs`. Keep that below the unit's TimeoutStopSec
+//! so systemd never has to kill the agent mid-send.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, RwLock};
+use tracing::{info, warn};
+
+use crate::connection::AgentMessage;
+use crate::systemd;
+use crate::AgentState;
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+
+struct Shutdown {
+    requested: watch::Sender<bool>,
+    /// Tasks still handing over their work
+    draining: watch::Sender<usize>,
+}
+
+fn shutdown() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(|| Shutdown {
+        requested: watch::channel(false).0,
+        draining: watch::channel(0).0,
+    })
+}
+
+/// Whether shutdown has begun
+pub fn requested() -> bool {
+    *shutdown().requested.borrow()
+}
+
+/// Resolves once shutdown has begun
+pub async fn wait() {
+    let mut requested = shutdown().requested.subscribe();
+    let _ = requested.wait_for(|requested| *requested).await;
+}
+
+/// Held by a task with work to hand over; shutdown waits for it to be dropped
+pub struct DrainGuard(());
+
+impl DrainGuard {
+    pub fn acquire() -> Self {
+        shutdown().draining.send_modify(|n| *n += 1);
+        Self(())
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        shutdown().draining.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT, with the signal's name
+#[cfg(unix)]
+pub async fn signal_received() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(error = %e, "Failed to install SIGTERM handler");
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+/// Resolves on Ctrl+C, console close or system shutdown, with the event's name
+#[cfg(windows)]
+pub async fn signal_received() -> &'static str {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+
+    match (ctrl_close(), ctrl_shutdown()) {
+        (Ok(mut close), Ok(mut shutdown)) => tokio::select! {
+            _ = tokio::signal::ctrl_c() => "CTRL_C",
+            _ = close.recv() => "CTRL_CLOSE",
+            _ = shutdown.recv() => "CTRL_SHUTDOWN",
+        },
+        _ => {
+            let _ = tokio::signal::ctrl_c().await;
+            "CTRL_C"
+        }
+    }
+}
+
+/// Stop the agent, giving up after `timeout`
+pub async fn run(state: Arc<RwLock<AgentState>>, timeout: Duration) {
+    shutdown().requested.send_replace(true);
+    systemd::stopping();
+
+    if tokio::time::timeout(timeout, finish(&state)).await.is_err() {
+        warn!(
+            timeout_secs = timeout.as_secs(),
+            "Shutdown deadline reached, exiting with work pending"
+        );
+    }
+}
+
+async fn finish(state: &RwLock<AgentState>) {
+    let mut draining = shutdown().draining.subscribe();
+    let _ = draining.wait_for(|n| *n == 0).await;
+
+    let mut state = state.write().await;
+    let state = &mut *state;
+
+    let unacked = state.outbox.len();
+    if let Some(ref mut conn) = state.connection {
+        let notice = AgentMessage::Disconnect {
+            reason: "shutdown".to_string(),
+        };
+        if let Err(e) = conn.send_message(&notice).await {
+            warn!(error = %e, "Failed to send disconnect notice");
+        }
+        conn.close().await;
+    }
+    // Unacked deltas go back to the buffer, and its file, for the next run
+    state.disconnect();
+
+    info!(
+        unacked = unacked,
+        buffered = state.buffer.len(),
+        "Shutdown complete"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_guard() {
+        let guard = DrainGuard::acquire();
+        assert_eq!(*shutdown().draining.borrow(), 1);
+
+        let waiter = tokio::spawn(async {
+            let mut draining = shutdown().draining.subscribe();
+            let _ = draining.wait_for(|n| *n == 0).await;
+        });
+        drop(guard);
+        waiter.await.unwrap();
+        assert_eq!(*shutdown().draining.borrow(), 0);
+    }
+}
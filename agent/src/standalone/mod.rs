@@ -0,0 +1,242 @@
+//! Standalone mode
+//!
+//! With `standalone.enabled`, the agent takes its components and checks
+//! from `standalone.components` in agent.yaml rather than from a Gateway
+//! snapshot, and never connects. Results go to:
+//!
+//! - `standalone.results_file`: the last result of each check as JSON,
+//!   replaced whole on every change, for scripts and file-based collectors;
+//! - `opsmap_agent_check_status{component_id, check}` on the metrics
+//!   endpoints (0 ok, 1 warning, 2 error, 3 unknown);
+//! - `GET /checks` on the admin socket, as in connected mode.
+//!
+//! Small sites and air-gapped hosts get the same check engine without a
+//! Gateway and backend. Reloading the config applies changed components.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+use crate::config::AgentConfig;
+use crate::connection::{CheckDefinition, ComponentSnapshot, Snapshot, StatusDelta};
+use crate::metrics::metrics;
+use crate::{shutdown, systemd, AgentState};
+
+/// Last result of each check, by component and check name
+static RESULTS: Mutex<BTreeMap<(String, String), StatusDelta>> = Mutex::new(BTreeMap::new());
+
+/// The snapshot `standalone.components` describes
+pub fn snapshot(config: &AgentConfig) -> Snapshot {
+    let default_interval = config.scheduler.default_check_interval_secs;
+    let components = config
+        .standalone
+        .components
+        .iter()
+        .map(|component| ComponentSnapshot {
+            id: component.id.clone(),
+            name: component
+                .name
+                .clone()
+                .unwrap_or_else(|| component.id.clone()),
+            component_type: component.component_type.clone(),
+            checks: component
+                .checks
+                .iter()
+                .map(|check| CheckDefinition {
+                    name: check.name.clone(),
+                    check_type: check.check_type.clone(),
+                    config: check.config.clone(),
+                    interval_secs: check.interval_secs.unwrap_or(default_interval),
+                    timeout_secs: check.timeout_secs,
+                })
+                .collect(),
+            actions: Vec::new(),
+        })
+        .collect();
+
+    Snapshot {
+        version: 0,
+        components,
+    }
+}
+
+/// Use the components of `config` from now on
+pub fn apply(state: &mut AgentState, config: &AgentConfig) {
+    let snapshot = snapshot(config);
+    let keys: Vec<(String, String)> = snapshot
+        .components
+        .iter()
+        .flat_map(|c| {
+            c.checks
+                .iter()
+                .map(|check| (c.id.clone(), check.name.clone()))
+        })
+        .collect();
+    state.scheduler.update_snapshot(snapshot);
+
+    let mut results = RESULTS.lock().unwrap();
+    results.retain(|key, _| keys.contains(key));
+    metrics().clear_check_status();
+    for delta in results.values() {
+        metrics().set_check_status(
+            &delta.component_id,
+            &delta.check_name,
+            crate::check_exit_code(&delta.status),
+        );
+    }
+}
+
+/// Run the configured checks until shutdown
+pub async fn run(state: Arc<RwLock<AgentState>>) -> Result<()> {
+    let checks = {
+        let mut state = state.write().await;
+        let config = state.config.clone();
+        apply(&mut state, &config);
+        state.scheduler.snapshot_size().1
+    };
+    info!(
+        checks = checks,
+        "Running in standalone mode, not connecting to a Gateway"
+    );
+    systemd::ready("Standalone mode");
+
+    let mut ticker = interval(Duration::from_secs(1));
+    let _drain = shutdown::DrainGuard::acquire();
+    loop {
+        tokio::select! {
+            _ = shutdown::wait() => return Ok(()),
+            _ = ticker.tick() => {}
+        }
+
+        // The state is only read while checks run, so the admin socket and
+        // reloads wait at most for one round
+        let deltas: Vec<StatusDelta> = {
+            let state = state.read().await;
+            state.scheduler.run_due_checks().await
+        }
+        .into_iter()
+        .map(|(delta, _)| delta)
+        .collect();
+        if deltas.is_empty() {
+            continue;
+        }
+
+        let results_file = state.read().await.config.standalone.results_file.clone();
+        if let Err(e) = record(deltas, results_file.as_deref()) {
+            warn!(error = %e, "Failed to write check results");
+        }
+    }
+}
+
+/// Keep the latest results and publish them
+fn record(deltas: Vec<StatusDelta>, results_file: Option<&str>) -> Result<()> {
+    let mut results = RESULTS.lock().unwrap();
+    for delta in deltas {
+        metrics().set_check_status(
+            &delta.component_id,
+            &delta.check_name,
+            crate::check_exit_code(&delta.status),
+        );
+        results.insert(
+            (delta.component_id.clone(), delta.check_name.clone()),
+            delta,
+        );
+    }
+
+    match results_file {
+        Some(path) => write_results(Path::new(path), results.values()),
+        None => Ok(()),
+    }
+}
+
+/// Replace the results file, so a reader never sees it half written
+fn write_results<'a>(path: &Path, results: impl Iterator<Item = &'a StatusDelta>) -> Result<()> {
+    let results: Vec<&StatusDelta> = results.collect();
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&results)?)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_and_results() {
+        let standalone = serde_yaml::from_str(
+            r#"
+enabled: true
+components:
+  - id: postgres
+    type: database
+    checks:
+      - name: port
+        type: tcp_port
+        config: {port: 5432}
+      - name: disk
+        type: disk_space
+        interval_secs: 300
+"#,
+        )
+        .unwrap();
+        let config = AgentConfig {
+            standalone,
+            ..AgentConfig::default()
+        };
+
+        let snapshot = snapshot(&config);
+        let postgres = &snapshot.components[0];
+        assert_eq!(
+            (postgres.name.as_str(), postgres.component_type.as_str()),
+            ("postgres", "database")
+        );
+        assert_eq!(postgres.checks[0].config["port"], 5432);
+        assert_eq!(
+            (
+                postgres.checks[0].interval_secs,
+                postgres.checks[1].interval_secs
+            ),
+            (config.scheduler.default_check_interval_secs, 300)
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("opsmap-results-{}.json", uuid::Uuid::new_v4()));
+        let delta = |check: &str, status: &str| StatusDelta {
+            component_id: "postgres".to_string(),
+            check_name: check.to_string(),
+            status: status.to_string(),
+            message: None,
+            metrics: None,
+            timestamp: chrono::Utc::now(),
+        };
+        record(vec![delta("port", "error")], path.to_str()).unwrap();
+        record(
+            vec![delta("disk", "ok"), delta("port", "ok")],
+            path.to_str(),
+        )
+        .unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let statuses: Vec<(&str, &str)> = written
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                (
+                    r["check_name"].as_str().unwrap(),
+                    r["status"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(statuses, [("disk", "ok"), ("port", "ok")]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! systemd service notifications
+//!
+//! With `Type=notify` the agent reports `READY=1` once it is connected to the
+//! Gateway, or running offline on its buffer, and keeps `STATUS=` current
+//! with the connection state (`systemctl status opsmap-agent`). With
+//! `WatchdogSec=` set, `WATCHDOG=1` is sent only while the shared agent state
+//! can still be locked, so a hung agent is restarted by systemd.
+//!
+//! Outside systemd (`NOTIFY_SOCKET` unset) every call is a no-op.
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use crate::AgentState;
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Send a notification to the service manager, if there is one
+pub fn notify(state: &str) {
+    let socket = match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) if !socket.is_empty() => socket,
+        _ => return,
+    };
+
+    if let Err(e) = send_to(&socket, state) {
+        debug!(error = %e, "Failed to notify systemd");
+    }
+}
+
+/// Report readiness once, along with the current status
+pub fn ready(status: &str) {
+    if READY.swap(true, Ordering::SeqCst) {
+        notify(&format!("STATUS={}", status));
+    } else {
+        notify(&format!("READY=1\nSTATUS={}", status));
+    }
+}
+
+/// Update the status line shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Tell systemd the agent is stopping
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Shutting down");
+}
+
+/// Ping the watchdog while the agent state stays responsive
+pub async fn watchdog(state: Arc<RwLock<AgentState>>) {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    debug!(
+        interval_ms = interval.as_millis() as u64,
+        "systemd watchdog enabled"
+    );
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // A lock held for this long means a task is stuck; stop pinging
+        match tokio::time::timeout(interval, state.read()).await {
+            Ok(_) => notify("WATCHDOG=1"),
+            Err(_) => warn!("Agent state unresponsive, skipping watchdog ping"),
+        }
+    }
+}
+
+/// Half the configured watchdog timeout, if the watchdog is meant for us
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Send one datagram to a `NOTIFY_SOCKET` address (a path or `@abstract`)
+#[cfg(unix)]
+fn send_to(socket: &str, state: &str) -> Result<()> {
+    let sock = UnixDatagram::unbound()?;
+
+    if let Some(name) = socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)
+                .with_context(|| format!("Failed to send to {}", socket))?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("Abstract socket {} is not supported here", name);
+    }
+
+    sock.send_to(state.as_bytes(), socket)
+        .with_context(|| format!("Failed to send to {}", socket))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_to(_socket: &str, _state: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_to() {
+        let dir = std::env::temp_dir().join(format!("opsmap-notify-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        send_to(path.to_str().unwrap(), "READY=1\nSTATUS=Connected").unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Connected");
+
+        assert!(send_to(dir.join("missing.sock").to_str().unwrap(), "WATCHDOG=1").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
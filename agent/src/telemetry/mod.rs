@@ -0,0 +1,16 @@
+//! Distributed tracing
+//!
+//! Built with the `otel` feature, spans are exported over OTLP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set (`OTEL_SERVICE_NAME` defaults to
+//! `opsmap-agent`). Commands arrive with the W3C `traceparent` of the
+//! gateway's routing span, so execution spans join the backend's trace.
+
+#[path = "../../../shared/telemetry.rs"]
+mod common;
+
+#[cfg(feature = "otel")]
+pub use common::layer;
+pub use common::{set_parent, TraceContext};
+
+#[cfg(feature = "otel")]
+pub const SERVICE_NAME: &str = "opsmap-agent";
@@ -0,0 +1,334 @@
+//! Agent self-update
+//!
+//! An `upgrade` command carries the URL of a new agent binary:
+//!
+//! ```json
+//! { "command_type": "upgrade", "params": { "url": "https://releases.example.com/opsmap-agent-0.2.0" } }
+//! ```
+//!
+//! The agent downloads it, verifies its detached Ed25519 signature
+//! (`params.signature`, or fetched from `params.signature_url`, by default
+//! `<url>.sig`) against the release keys compiled into this build, checks
+//! that it runs, swaps it in with a rename and re-executes itself after a
+//! graceful shutdown. The PID is kept, so systemd sees no restart.
+//!
+//! Windows cannot replace a running executable or exec into another one: the
+//! old binary is renamed aside to `<name>.old` and the new one is started as
+//! a fresh process before this one exits.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::config::AgentConfig;
+use crate::connection::{Command, CommandResult};
+use crate::{shutdown, AgentState};
+
+/// Public keys trusted to sign releases
+const RELEASE_KEYS: &str = include_str!("release-keys.pub");
+
+/// Largest binary we download
+const MAX_BINARY_SIZE: usize = 256 * 1024 * 1024;
+
+/// Largest signature file we download
+const MAX_SIGNATURE_SIZE: usize = 1024;
+
+/// Time allowed for the new binary to print its version
+const VERSION_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Download, verify and install the binary named in an `upgrade` command
+///
+/// The running agent is not affected until [`restart`] is called.
+pub async fn install(cmd: &Command, config: &AgentConfig) -> Result<CommandResult> {
+    if !config.upgrade.enabled {
+        bail!("Upgrades are disabled (upgrade.enabled)");
+    }
+    let keys = parse_keys(RELEASE_KEYS)?;
+    if keys.is_empty() {
+        bail!("This build has no release keys, upgrades are not possible");
+    }
+
+    let url = cmd
+        .params
+        .get("url")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing url in params"))?;
+
+    let start = Instant::now();
+    let client = http_client(config, cmd.timeout_secs).await?;
+
+    info!(url = %url, "Downloading agent upgrade");
+    let binary = fetch(&client, url, MAX_BINARY_SIZE).await?;
+
+    let signature = match cmd.params.get("signature").and_then(|v| v.as_str()) {
+        Some(signature) => signature.to_string(),
+        None => {
+            let signature_url = cmd
+                .params
+                .get("signature_url")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}.sig", url));
+            let body = fetch(&client, &signature_url, MAX_SIGNATURE_SIZE).await?;
+            String::from_utf8(body).context("Signature is not text")?
+        }
+    };
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature.trim())
+        .context("Signature is not valid base64")?;
+
+    let exe = exe_path()?;
+    let staged = staged_path(&exe);
+    let installed = async {
+        // Hashing and writing up to MAX_BINARY_SIZE bytes, off the runtime
+        let path = staged.clone();
+        tokio::task::spawn_blocking(move || {
+            verify(&binary, &signature, &keys)?;
+            info!(size = binary.len(), "Upgrade signature verified");
+            stage(&path, &binary)
+        })
+        .await??;
+
+        let version = check_version(&staged).await?;
+        let (path, exe) = (staged.clone(), exe.clone());
+        tokio::task::spawn_blocking(move || replace(&path, &exe)).await??;
+        Ok::<_, anyhow::Error>(version)
+    };
+    let version = match installed.await {
+        Ok(version) => version,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e);
+        }
+    };
+
+    info!(path = %exe.display(), version = %version, "Agent upgrade installed");
+    Ok(CommandResult {
+        exit_code: 0,
+        stdout: format!("Installed {}, restarting", version),
+        stderr: String::new(),
+        duration_ms: start.elapsed().as_millis() as u64,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+/// Shut down gracefully and re-execute the installed binary
+pub async fn restart(state: Arc<RwLock<AgentState>>) -> ! {
+    let timeout = Duration::from_secs(state.read().await.config.agent.shutdown_timeout_secs);
+    shutdown::run(state, timeout).await;
+
+    info!("Restarting into upgraded agent");
+    let err = match exe_path() {
+        Ok(exe) => reexec(exe),
+        Err(e) => e,
+    };
+
+    // The new binary is in place; let the service manager start it
+    error!(error = %err, "Failed to re-execute agent");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+fn reexec(exe: PathBuf) -> anyhow::Error {
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec()
+        .into()
+}
+
+#[cfg(not(unix))]
+fn reexec(exe: PathBuf) -> anyhow::Error {
+    match std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .spawn()
+    {
+        Ok(_) => std::process::exit(0),
+        Err(e) => e.into(),
+    }
+}
+
+/// Release keys, one base64 Ed25519 public key per line; `#` starts a comment
+fn parse_keys(text: &str) -> Result<Vec<Vec<u8>>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(line)
+                .with_context(|| format!("Invalid release key: {}", line))?;
+            if key.len() != 32 {
+                bail!("Invalid release key length: {}", line);
+            }
+            Ok(key)
+        })
+        .collect()
+}
+
+/// Check the signature against every release key
+fn verify(binary: &[u8], signature: &[u8], keys: &[Vec<u8>]) -> Result<()> {
+    let trusted = keys.iter().any(|key| {
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(binary, signature)
+            .is_ok()
+    });
+    if !trusted {
+        bail!("Signature does not match any release key");
+    }
+    Ok(())
+}
+
+async fn http_client(config: &AgentConfig, timeout_secs: u64) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(timeout_secs));
+
+    // Releases may be served by the Gateway or an internal mirror
+    if let Some(ref ca_file) = config.tls.ca_file {
+        if let Ok(pem) = tokio::fs::read(ca_file).await {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// GET a URL, refusing bodies over `limit` bytes
+async fn fetch(client: &reqwest::Client, url: &str, limit: usize) -> Result<Vec<u8>> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to download {}", url))?;
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            bail!("{} is larger than {} bytes", url, limit);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Path of the running binary
+fn exe_path() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("Cannot locate the agent binary")?;
+
+    // Once replaced, Linux reports the old inode as "<path> (deleted)"
+    let path = exe.to_string_lossy();
+    Ok(match path.strip_suffix(" (deleted)") {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    })
+}
+
+/// Next to the binary, so the final rename stays on one filesystem
+fn staged_path(exe: &Path) -> PathBuf {
+    let name = exe
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "opsmap-agent".to_string());
+    exe.with_file_name(format!(".{}.upgrade", name))
+}
+
+fn stage(path: &Path, binary: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o755);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(binary)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn replace(staged: &Path, exe: &Path) -> Result<()> {
+    // A running executable can be renamed on Windows, but not overwritten
+    #[cfg(windows)]
+    {
+        let old = exe.with_extension("old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(exe, &old)
+            .with_context(|| format!("Failed to move {} aside", exe.display()))?;
+    }
+
+    std::fs::rename(staged, exe).with_context(|| format!("Failed to replace {}", exe.display()))
+}
+
+/// Run `<binary> --version`, so a binary for another platform is never installed
+async fn check_version(path: &Path) -> Result<String> {
+    let child = tokio::process::Command::new(path)
+        .arg("--version")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", path.display()))?;
+
+    // Killed on timeout, as the child is dropped with the future
+    let output = tokio::time::timeout(VERSION_CHECK_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("New binary did not answer --version"))??;
+    if !output.status.success() {
+        bail!("New binary failed to run: {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_verify() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public = base64::engine::general_purpose::STANDARD.encode(pair.public_key());
+
+        let keys = parse_keys(&format!("# release key\n\n{}\n", public)).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(parse_keys("bm90IGEga2V5").is_err());
+        assert!(parse_keys(RELEASE_KEYS).is_ok());
+
+        let binary = b"\x7fELF new agent";
+        let signature = pair.sign(binary);
+        assert!(verify(binary, signature.as_ref(), &keys).is_ok());
+        assert!(verify(b"\x7fELF tampered", signature.as_ref(), &keys).is_err());
+        assert!(verify(binary, signature.as_ref(), &[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_check_version() {
+        assert!(check_version(Path::new("/bin/echo")).await.is_ok());
+        assert!(check_version(Path::new("/bin/false")).await.is_err());
+        assert!(check_version(Path::new("/nonexistent/opsmap-agent"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_staged_path() {
+        assert_eq!(
+            staged_path(Path::new("/usr/local/bin/opsmap-agent")),
+            PathBuf::from("/usr/local/bin/.opsmap-agent.upgrade")
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! WASM plugin runtime
+//!
+//! Custom checks and actions can be shipped as `.wasm` modules dropped into
+//! the configured directory. Modules are compiled on first use and reloaded
+//! automatically when the file changes.
+//!
+//! Guest ABI (all pointers are offsets into the exported `memory`):
+//! - export `opsmap_alloc(len: i32) -> i32`: allocate `len` bytes for input
+//! - export `<entrypoint>(ptr: i32, len: i32) -> i64`: receives JSON input
+//!   and returns `(out_ptr << 32) | out_len` pointing at a JSON result
+//!   `{"status": "...", "message": "...", "metrics": {...}}`
+//!
+//! Host functions (module `opsmap`), each scoped by the agent config:
+//! - `fs_read(path_ptr, path_len, out_ptr, out_cap) -> i32`: bytes read,
+//!   or -1 denied, -2 I/O error, -3 buffer too small
+//! - `tcp_connect(addr_ptr, addr_len, timeout_ms) -> i32`: connect time in
+//!   ms, or -1 denied, -2 connection failed
+//! - `log(ptr, len)`: write a debug log line
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+use wasmtime::{Caller, Config, Engine, Linker, Memory, Module, Store};
+
+use crate::config::WasmSettings;
+use crate::connection::{CheckDefinition, Command, CommandResult};
+use crate::native_commands::{apply_threshold_expressions, NativeResult};
+
+/// Largest input or output payload exchanged with a module
+const MAX_PAYLOAD_BYTES: usize = 4 * 1024 * 1024;
+
+static RUNTIME: OnceLock<WasmRuntime> = OnceLock::new();
+
+/// Initialize the shared runtime from the agent config
+pub fn init(settings: &WasmSettings) -> Result<()> {
+    let runtime = WasmRuntime::new(settings.clone())?;
+    RUNTIME
+        .set(runtime)
+        .map_err(|_| anyhow!("WASM runtime already initialized"))?;
+    info!(directory = %settings.directory, "WASM plugin runtime initialized");
+    Ok(())
+}
+
+fn runtime() -> Result<&'static WasmRuntime> {
+    RUNTIME
+        .get()
+        .ok_or_else(|| anyhow!("WASM runtime not initialized"))
+}
+
+/// Result document returned by a module
+#[derive(Debug, Deserialize)]
+struct ModuleOutput {
+    status: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    metrics: serde_json::Value,
+}
+
+/// Compiled module with the modification time it was loaded from
+struct CachedModule {
+    modified: SystemTime,
+    module: Module,
+}
+
+/// Per-invocation host state exposing only the configured capabilities
+struct HostState {
+    read_roots: Vec<PathBuf>,
+    allowed_connect: Vec<String>,
+}
+
+/// Shared WASM engine and module cache
+pub struct WasmRuntime {
+    engine: Engine,
+    settings: WasmSettings,
+    modules: Mutex<HashMap<String, CachedModule>>,
+}
+
+impl WasmRuntime {
+    pub fn new(settings: WasmSettings) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+
+        Ok(Self {
+            engine,
+            settings,
+            modules: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Load a module by name, recompiling it if the file changed on disk
+    fn load(&self, name: &str) -> Result<Module> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+            return Err(anyhow!("Invalid WASM module name: {}", name));
+        }
+
+        let path = Path::new(&self.settings.directory).join(format!("{}.wasm", name));
+        let modified = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("WASM module not found: {}", path.display()))?;
+
+        let mut modules = self.modules.lock().unwrap();
+        if let Some(cached) = modules.get(name) {
+            if cached.modified == modified {
+                return Ok(cached.module.clone());
+            }
+        }
+
+        debug!(module = %name, path = %path.display(), "Compiling WASM module");
+        let module = Module::from_file(&self.engine, &path)
+            .with_context(|| format!("Failed to compile WASM module: {}", path.display()))?;
+
+        modules.insert(
+            name.to_string(),
+            CachedModule {
+                modified,
+                module: module.clone(),
+            },
+        );
+
+        Ok(module)
+    }
+
+    /// Call `entrypoint` in a module with a JSON input, returning its JSON output
+    pub fn invoke(
+        &self,
+        name: &str,
+        entrypoint: &str,
+        input: &serde_json::Value,
+    ) -> Result<String> {
+        let module = self.load(name)?;
+        self.invoke_module(&module, entrypoint, input)
+    }
+
+    fn invoke_module(
+        &self,
+        module: &Module,
+        entrypoint: &str,
+        input: &serde_json::Value,
+    ) -> Result<String> {
+        let host = HostState {
+            read_roots: self
+                .settings
+                .allowed_read_paths
+                .iter()
+                .filter_map(|p| std::fs::canonicalize(p).ok())
+                .collect(),
+            allowed_connect: self.settings.allowed_connect.clone(),
+        };
+
+        let mut store = Store::new(&self.engine, host);
+        store.set_fuel(self.settings.fuel)?;
+
+        let linker = build_linker(&self.engine)?;
+        let instance = linker.instantiate(&mut store, module)?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("WASM module does not export 'memory'"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "opsmap_alloc")?;
+        let entry = instance.get_typed_func::<(i32, i32), i64>(&mut store, entrypoint)?;
+
+        let input = serde_json::to_vec(input)?;
+        if input.len() > MAX_PAYLOAD_BYTES {
+            return Err(anyhow!("WASM input too large ({} bytes)", input.len()));
+        }
+
+        let in_ptr = alloc.call(&mut store, input.len() as i32)?;
+        memory.write(&mut store, in_ptr as usize, &input)?;
+
+        let packed = entry.call(&mut store, (in_ptr, input.len() as i32))?;
+        let out_ptr = (packed >> 32) as u32 as usize;
+        let out_len = (packed & 0xffff_ffff) as u32 as usize;
+
+        if out_len > MAX_PAYLOAD_BYTES {
+            return Err(anyhow!("WASM output too large ({} bytes)", out_len));
+        }
+
+        let mut output = vec![0u8; out_len];
+        memory.read(&store, out_ptr, &mut output)?;
+
+        String::from_utf8(output).context("WASM output is not valid UTF-8")
+    }
+}
+
+/// Register the capability-scoped host functions
+fn build_linker(engine: &Engine) -> Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "opsmap",
+        "fs_read",
+        |mut caller: Caller<'_, HostState>,
+         path_ptr: i32,
+         path_len: i32,
+         out_ptr: i32,
+         out_cap: i32|
+         -> i32 {
+            let memory = match guest_memory(&mut caller) {
+                Some(m) => m,
+                None => return -2,
+            };
+            let path = match read_guest_string(&caller, memory, path_ptr, path_len) {
+                Some(p) => p,
+                None => return -2,
+            };
+
+            let canonical = match std::fs::canonicalize(&path) {
+                Ok(c) => c,
+                Err(_) => return -2,
+            };
+            if !caller
+                .data()
+                .read_roots
+                .iter()
+                .any(|root| canonical.starts_with(root))
+            {
+                return -1;
+            }
+
+            let content = match std::fs::read(&canonical) {
+                Ok(c) => c,
+                Err(_) => return -2,
+            };
+            if content.len() > out_cap.max(0) as usize {
+                return -3;
+            }
+
+            match memory.write(&mut caller, out_ptr as usize, &content) {
+                Ok(()) => content.len() as i32,
+                Err(_) => -2,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "opsmap",
+        "tcp_connect",
+        |mut caller: Caller<'_, HostState>, addr_ptr: i32, addr_len: i32, timeout_ms: i32| -> i32 {
+            let memory = match guest_memory(&mut caller) {
+                Some(m) => m,
+                None => return -2,
+            };
+            let addr = match read_guest_string(&caller, memory, addr_ptr, addr_len) {
+                Some(a) => a,
+                None => return -2,
+            };
+
+            if !caller.data().allowed_connect.contains(&addr) {
+                return -1;
+            }
+
+            let socket_addr = match std::net::ToSocketAddrs::to_socket_addrs(&addr.as_str())
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+            {
+                Some(a) => a,
+                None => return -2,
+            };
+
+            let timeout = Duration::from_millis(timeout_ms.clamp(1, 30_000) as u64);
+            let start = std::time::Instant::now();
+            match std::net::TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(_) => start.elapsed().as_millis().min(i32::MAX as u128) as i32,
+                Err(_) => -2,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "opsmap",
+        "log",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            if let Some(memory) = guest_memory(&mut caller) {
+                if let Some(line) = read_guest_string(&caller, memory, ptr, len) {
+                    debug!(target: "opsmap_agent::wasm::guest", "{}", line);
+                }
+            }
+        },
+    )?;
+
+    Ok(linker)
+}
+
+fn guest_memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+    caller.get_export("memory").and_then(|e| e.into_memory())
+}
+
+fn read_guest_string(
+    caller: &Caller<'_, HostState>,
+    memory: Memory,
+    ptr: i32,
+    len: i32,
+) -> Option<String> {
+    if ptr < 0 || len < 0 || len as usize > MAX_PAYLOAD_BYTES {
+        return None;
+    }
+    let mut buf = vec![0u8; len as usize];
+    memory.read(caller, ptr as usize, &mut buf).ok()?;
+    String::from_utf8(buf).ok()
+}
+
+/// Execute a "wasm" check
+pub async fn execute_check(check: &CheckDefinition) -> Result<NativeResult> {
+    let module = check
+        .config
+        .get("module")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing 'module' in wasm check config"))?
+        .to_string();
+    let entrypoint = check
+        .config
+        .get("entrypoint")
+        .and_then(|v| v.as_str())
+        .unwrap_or("check")
+        .to_string();
+    let params = check
+        .config
+        .get("params")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+
+    let output = invoke_blocking(module, entrypoint, params, check.timeout_secs).await?;
+    let output: ModuleOutput =
+        serde_json::from_str(&output).context("WASM check returned invalid JSON")?;
+
+    if !matches!(
+        output.status.as_str(),
+        "ok" | "warning" | "error" | "unknown"
+    ) {
+        return Err(anyhow!(
+            "WASM check returned invalid status: {}",
+            output.status
+        ));
+    }
+
+    let result = NativeResult {
+        status: output.status,
+        message: output.message,
+        metrics: output.metrics,
+    };
+
+    apply_threshold_expressions(&check.config, result)
+}
+
+/// Execute a "wasm" action command
+pub async fn execute_command(cmd: &Command) -> Result<CommandResult> {
+    let module = cmd
+        .params
+        .get("module")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing module in params"))?
+        .to_string();
+    let entrypoint = cmd
+        .params
+        .get("entrypoint")
+        .and_then(|v| v.as_str())
+        .unwrap_or("action")
+        .to_string();
+    let input = cmd
+        .params
+        .get("input")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+
+    let start = std::time::Instant::now();
+    let output = invoke_blocking(module, entrypoint, input, cmd.timeout_secs).await?;
+    let duration_ms = start.elapsed().as_millis() as u64;
+
+    let failed = serde_json::from_str::<ModuleOutput>(&output)
+        .map(|o| o.status == "error")
+        .unwrap_or(false);
+
+    Ok(CommandResult {
+        exit_code: if failed { 1 } else { 0 },
+        stdout: output,
+        stderr: String::new(),
+        duration_ms,
+        timed_out: false,
+        truncated: false,
+        steps: Vec::new(),
+        check: None,
+    })
+}
+
+/// Run an invocation on the blocking pool, bounded by a timeout
+async fn invoke_blocking(
+    module: String,
+    entrypoint: String,
+    input: serde_json::Value,
+    timeout_secs: u64,
+) -> Result<String> {
+    let runtime = runtime()?;
+    let task = tokio::task::spawn_blocking(move || runtime.invoke(&module, &entrypoint, &input));
+
+    tokio::time::timeout(Duration::from_secs(timeout_secs), task)
+        .await
+        .map_err(|_| anyhow!("WASM invocation timed out after {}s", timeout_secs))?
+        .map_err(|e| anyhow!("WASM task failed: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Guest that ignores its input and returns a fixed JSON document
+    const FIXED_OUTPUT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 1024) "{\"status\":\"warning\",\"metrics\":{\"x\":1}}")
+          (func (export "opsmap_alloc") (param i32) (result i32) i32.const 0)
+          (func (export "check") (param i32 i32) (result i64)
+            i64.const 4398046511142))
+    "#;
+
+    /// Guest that connects to 127.0.0.1:9 and reports "error" if denied
+    const CONNECT: &str = r#"
+        (module
+          (import "opsmap" "tcp_connect" (func $connect (param i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 1024) "{\"status\":\"ok\"}")
+          (data (i32.const 2048) "127.0.0.1:9")
+          (data (i32.const 3072) "{\"status\":\"error\"}")
+          (func (export "opsmap_alloc") (param i32) (result i32) i32.const 0)
+          (func (export "check") (param i32 i32) (result i64)
+            (if (result i64)
+              (i32.eq (call $connect (i32.const 2048) (i32.const 11) (i32.const 100)) (i32.const -1))
+              (then (i64.const 13194139533330))
+              (else (i64.const 4398046511119)))))
+    "#;
+
+    /// Guest that never returns
+    const SPIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "opsmap_alloc") (param i32) (result i32) i32.const 0)
+          (func (export "check") (param i32 i32) (result i64)
+            (loop $l (br $l))
+            i64.const 0))
+    "#;
+
+    fn runtime_with(settings: WasmSettings) -> WasmRuntime {
+        WasmRuntime::new(settings).unwrap()
+    }
+
+    #[test]
+    fn test_invoke_returns_output() {
+        let runtime = runtime_with(WasmSettings::default());
+        let module = Module::new(&runtime.engine, FIXED_OUTPUT).unwrap();
+
+        let output = runtime.invoke_module(&module, "check", &json!({})).unwrap();
+        let parsed: ModuleOutput = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed.status, "warning");
+        assert_eq!(parsed.metrics["x"], 1);
+    }
+
+    #[test]
+    fn test_tcp_connect_requires_allowlist() {
+        let runtime = runtime_with(WasmSettings::default());
+        let module = Module::new(&runtime.engine, CONNECT).unwrap();
+        let output = runtime.invoke_module(&module, "check", &json!({})).unwrap();
+        assert_eq!(output, r#"{"status":"error"}"#);
+
+        let runtime = runtime_with(WasmSettings {
+            allowed_connect: vec!["127.0.0.1:9".to_string()],
+            ..WasmSettings::default()
+        });
+        let module = Module::new(&runtime.engine, CONNECT).unwrap();
+        let output = runtime.invoke_module(&module, "check", &json!({})).unwrap();
+        assert_eq!(output, r#"{"status":"ok"}"#);
+    }
+
+    #[test]
+    fn test_fuel_limit_stops_runaway_module() {
+        let runtime = runtime_with(WasmSettings {
+            fuel: 10_000,
+            ..WasmSettings::default()
+        });
+        let module = Module::new(&runtime.engine, SPIN).unwrap();
+
+        assert!(runtime.invoke_module(&module, "check", &json!({})).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_names() {
+        let runtime = runtime_with(WasmSettings::default());
+        assert!(runtime.load("../evil").is_err());
+        assert!(runtime.load(".hidden").is_err());
+    }
+
+    #[test]
+    fn test_load_hot_reloads_changed_module() {
+        let dir = std::env::temp_dir().join(format!("opsmap-wasm-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime = runtime_with(WasmSettings {
+            directory: dir.to_string_lossy().to_string(),
+            ..WasmSettings::default()
+        });
+
+        let path = dir.join("probe.wasm");
+        std::fs::write(&path, SPIN).unwrap();
+        runtime.load("probe").unwrap();
+
+        std::fs::write(&path, FIXED_OUTPUT).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let output = runtime.invoke("probe", "check", &json!({})).unwrap();
+        assert!(output.contains("warning"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
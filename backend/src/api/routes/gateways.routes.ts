@@ -1,4 +1,5 @@
 import { Router, Request, Response, NextFunction } from 'express';
+import { z } from 'zod';
 import { authMiddleware } from '../middleware/auth.js';
 import { gatewaysRepository, agentsRepository, jobsRepository } from '../../db/repositories/index.js';
 import { gatewayManager, commandService } from '../../gateway/index.js';
@@ -134,6 +135,76 @@ router.post(
   }
 );
 
+const checksPauseSchema = z.object({
+  componentId: z.string().min(1),
+  check: z.string().min(1).optional(),
+  durationSecs: z.number().int().positive().optional(),
+});
+
+// Pause or resume a component's checks on an agent
+router.post(
+  '/agents/:id/checks/:action(pause|resume)',
+  authMiddleware,
+  async (req: Request, res: Response, next: NextFunction) => {
+    try {
+      const parsed = checksPauseSchema.safeParse(req.body);
+      if (!parsed.success) {
+        res.status(400).json({ error: parsed.error.issues[0]?.message ?? 'Invalid request' });
+        return;
+      }
+      const { componentId, check, durationSecs } = parsed.data;
+
+      const result = await commandService.setChecksPaused(
+        req.params.id,
+        componentId,
+        { paused: req.params.action === 'pause', check, durationSecs },
+        req.user!.id
+      );
+
+      if (!result.success) {
+        res.status(400).json({ error: result.error });
+        return;
+      }
+
+      res.json(result);
+    } catch (error) {
+      next(error);
+    }
+  }
+);
+
+const checkRunSchema = z.object({
+  componentId: z.string().min(1),
+  check: z.string().min(1),
+});
+
+// Run a component's check on an agent now, outside its interval
+router.post(
+  '/agents/:id/checks/run',
+  authMiddleware,
+  async (req: Request, res: Response, next: NextFunction) => {
+    try {
+      const parsed = checkRunSchema.safeParse(req.body);
+      if (!parsed.success) {
+        res.status(400).json({ error: parsed.error.issues[0]?.message ?? 'Invalid request' });
+        return;
+      }
+      const { componentId, check } = parsed.data;
+
+      const result = await commandService.runCheck(req.params.id, componentId, check, req.user!.id);
+
+      if (!result.success) {
+        res.status(400).json({ error: result.error });
+        return;
+      }
+
+      res.json(result);
+    } catch (error) {
+      next(error);
+    }
+  }
+);
+
 // Get job status
 router.get(
   '/jobs/:id',
@@ -29,6 +29,12 @@ const configSchema = z.object({
   logging: z.object({
     level: z.enum(['trace', 'debug', 'info', 'warn', 'error', 'fatal']).default('info'),
   }),
+  agents: z
+    .object({
+      minVersion: z.string().optional(),
+      rejectOutdated: z.enum(['true', 'false']).default('false').transform((v) => v === 'true'),
+    })
+    .default({}),
 });
 
 function createValidConfig(overrides: Record<string, unknown> = {}) {
@@ -155,4 +161,15 @@ describe('Config schema validation', () => {
       expect(result.data.oidc.issuer).toBe('https://login.company.com');
     }
   });
+
+  it('should read AGENT_REJECT_OUTDATED as written', () => {
+    const parse = (rejectOutdated?: string) => {
+      const result = configSchema.safeParse(createValidConfig({ agents: { rejectOutdated } }));
+      return result.success ? result.data.agents.rejectOutdated : undefined;
+    };
+    expect(parse('false')).toBe(false);
+    expect(parse('true')).toBe(true);
+    expect(parse(undefined)).toBe(false);
+    expect(parse('yes')).toBeUndefined();
+  });
 });
@@ -34,6 +34,20 @@ const configSchema = z.object({
   logging: z.object({
     level: z.enum(['trace', 'debug', 'info', 'warn', 'error', 'fatal']).default('info'),
   }),
+
+  agents: z.object({
+    minVersion: z.string().optional(),
+    // z.coerce.boolean() would read "false" as true
+    rejectOutdated: z.enum(['true', 'false']).default('false').transform((v) => v === 'true'),
+  }),
+
+  // Gateway link authentication (see gateway/src/backend_auth)
+  gateways: z.object({
+    token: z.string().optional(),
+    jwtSecret: z.string().optional(),
+    backendToken: z.string().optional(),
+    backendJwt: z.enum(['true', 'false']).default('false').transform((v) => v === 'true'),
+  }),
 });
 
 export type Config = z.infer<typeof configSchema>;
@@ -64,6 +78,16 @@ function loadConfig(): Config {
     logging: {
       level: process.env.LOG_LEVEL,
     },
+    agents: {
+      minVersion: process.env.AGENT_MIN_VERSION,
+      rejectOutdated: process.env.AGENT_REJECT_OUTDATED,
+    },
+    gateways: {
+      token: process.env.GATEWAY_TOKEN,
+      jwtSecret: process.env.GATEWAY_JWT_SECRET,
+      backendToken: process.env.GATEWAY_BACKEND_TOKEN,
+      backendJwt: process.env.GATEWAY_BACKEND_JWT,
+    },
   };
 
   const result = configSchema.safeParse(rawConfig);
@@ -253,6 +253,77 @@ describe('CommandOrchestrator', () => {
       expect(result.mode).toBe('async');
     });
 
+    it('should pass the action priority', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        mapId: 'map-1',
+        name: 'test-component',
+        type: 'service',
+        config: {
+          agentSelector: { agentId: 'agent-1' },
+          actions: [
+            {
+              name: 'start',
+              label: 'Start',
+              command: 'systemctl start app',
+              async: true,
+              priority: { nice: 10, ioClass: 'idle', oomScoreAdj: 500 },
+            },
+          ],
+        },
+        position: { x: 0, y: 0 },
+        createdAt: new Date(),
+        updatedAt: new Date(),
+      });
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      await commandOrchestrator.executeCommand({
+        mapId: 'map-1',
+        componentId: 'comp-1',
+        commandName: 'start',
+        userId: 'user-1',
+      });
+
+      const command = vi.mocked(gatewayManager.sendCommand).mock.calls[0][3]!;
+      expect(command.args.priority).toEqual({
+        nice: 10,
+        io_class: 'idle',
+        io_level: undefined,
+        oom_score_adj: 500,
+      });
+    });
+
+    it('should target the component container', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        mapId: 'map-1',
+        name: 'test-component',
+        type: 'service',
+        config: {
+          agentSelector: { agentId: 'agent-1' },
+          container: { name: 'api', runtime: 'podman' },
+          actions: [{ name: 'start', label: 'Start', command: 'supervisorctl start api', async: true }],
+        },
+        position: { x: 0, y: 0 },
+        createdAt: new Date(),
+        updatedAt: new Date(),
+      });
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      await commandOrchestrator.executeCommand({
+        mapId: 'map-1',
+        componentId: 'comp-1',
+        commandName: 'start',
+        userId: 'user-1',
+      });
+
+      const command = vi.mocked(gatewayManager.sendCommand).mock.calls[0][3]!;
+      expect(command.args.container).toBe('api');
+      expect(command.args.container_runtime).toBe('podman');
+    });
+
     it('should handle failed command send', async () => {
       vi.mocked(componentsRepository.findById).mockResolvedValue({
         id: 'comp-1',
@@ -2,6 +2,7 @@ import { EventEmitter } from 'events';
 import { createChildLogger } from '../config/logger.js';
 import { jobsRepository, componentsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from '../gateway/manager.js';
+import { commandTenant } from '../gateway/tenant.js';
 import { AgentCommand } from '../gateway/types.js';
 import { ComponentConfig } from '../types/index.js';
 import { fsmManager, ComponentEvent } from './fsm/index.js';
@@ -291,6 +292,16 @@ class CommandOrchestrator extends EventEmitter {
         command: action.command,
         args: action.args || [],
         run_as_user: action.runAsUser,
+        env: action.env,
+        cwd: action.cwd,
+        container: config.container?.name,
+        container_runtime: config.container?.runtime,
+        priority: action.priority && {
+          nice: action.priority.nice,
+          io_class: action.priority.ioClass,
+          io_level: action.priority.ioLevel,
+          oom_score_adj: action.priority.oomScoreAdj,
+        },
         component_id: componentId,
         map_id: mapId,
         completion_check: executionMode.completion_check,
@@ -299,6 +310,7 @@ class CommandOrchestrator extends EventEmitter {
       timeout_secs: executionMode.type === 'sync'
         ? Math.ceil((executionMode.timeout_ms || 30000) / 1000)
         : 300,
+      tenant_id: await commandTenant(agentId, { mapId: component.mapId }),
     };
 
     // Send to gateway
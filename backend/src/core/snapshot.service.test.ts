@@ -20,6 +20,9 @@ vi.mock('../db/repositories/index.js', () => ({
   agentSnapshotsRepository: {
     upsert: vi.fn().mockResolvedValue(undefined),
   },
+  mapsRepository: {
+    findTenants: vi.fn().mockResolvedValue({}),
+  },
 }));
 
 vi.mock('../gateway/manager.js', () => ({
@@ -30,7 +33,7 @@ vi.mock('../gateway/manager.js', () => ({
 }));
 
 import { snapshotService } from './snapshot.service.js';
-import { componentsRepository, agentsRepository, agentSnapshotsRepository } from '../db/repositories/index.js';
+import { componentsRepository, agentsRepository, agentSnapshotsRepository, mapsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from '../gateway/manager.js';
 
 const mockAgentsRepo = vi.mocked(agentsRepository);
@@ -163,6 +166,41 @@ describe('SnapshotService', () => {
       const snapshot = await snapshotService.buildSnapshotForAgent('agent-1');
       expect(snapshot.snapshot.components).toHaveLength(0);
     });
+
+    it('should spread the checks of components with a strategy', async () => {
+      mockAgentsRepo.findById.mockResolvedValue({
+        id: 'agent-1',
+        hostname: 'srv-01',
+        labels: { role: 'cache' },
+      } as any);
+      vi.mocked(gatewayManager.getConnectedAgents).mockReturnValue([
+        { id: 'agent-2', hostname: 'srv-02', gatewayId: 'gw-1', version: '1.0', labels: { role: 'cache' } },
+        { id: 'agent-1', hostname: 'srv-01', gatewayId: 'gw-1', version: '1.0', labels: { role: 'cache' } },
+      ]);
+      const check = { name: 'ping', type: 'tcp', config: {}, intervalSecs: 30, timeoutSecs: 5 };
+      mockComponentsRepo.findAll.mockResolvedValue(
+        ['redis-1', 'redis-2', 'redis-3'].map((id) => ({
+          id,
+          name: id,
+          type: 'service',
+          config: {
+            agentSelector: { labels: { role: 'cache' }, strategy: 'round_robin' },
+            checks: [check],
+            actions: [],
+          },
+        })) as any
+      );
+
+      const snapshot = await snapshotService.buildSnapshotForAgent('agent-1');
+
+      // Every component, for its actions; the checks of every other one
+      expect(snapshot.snapshot.components.map((c) => [c.id, c.checks.length])).toEqual([
+        ['redis-1', 1],
+        ['redis-2', 0],
+        ['redis-3', 1],
+      ]);
+      vi.mocked(gatewayManager.getConnectedAgents).mockReturnValue([]);
+    });
   });
 
   describe('buildSnapshotComponent', () => {
@@ -177,7 +215,7 @@ describe('SnapshotService', () => {
             { name: 'health', type: 'http' as const, config: { url: 'http://localhost:8080/health' }, intervalSecs: 30, timeoutSecs: 10 },
           ],
           actions: [
-            { name: 'start', label: 'Start', command: 'systemctl start app', args: ['--flag'], runAsUser: 'app', async: true },
+            { name: 'start', label: 'Start', command: 'systemctl start app', args: ['--flag'], runAsUser: 'app', env: { DB_PASSWORD: '${secret:db}' }, cwd: '/srv/app', verify: { check: 'health', timeoutSecs: 120 }, async: true },
           ],
         },
         position: { x: 0, y: 0 },
@@ -194,6 +232,9 @@ describe('SnapshotService', () => {
       expect(snapshot.actions[0].name).toBe('start');
       expect(snapshot.actions[0].command).toBe('systemctl start app');
       expect(snapshot.actions[0].run_as_user).toBe('app');
+      expect(snapshot.actions[0].env).toEqual({ DB_PASSWORD: '${secret:db}' });
+      expect(snapshot.actions[0].cwd).toBe('/srv/app');
+      expect(snapshot.actions[0].verify).toEqual({ check: 'health', timeout_secs: 120 });
       expect(snapshot.actions[0].async).toBe(true);
       expect(snapshot.actions[0].timeout_secs).toBe(300); // async default
     });
@@ -251,6 +292,31 @@ describe('SnapshotService', () => {
         payload: expect.objectContaining({ agent_id: 'agent-1' }),
       });
     });
+
+    it('should only send a tenant\'s agent the components of its organization\'s maps', async () => {
+      mockAgentsRepo.findById.mockResolvedValue({
+        id: 'agent-t',
+        hostname: 'srv-t',
+        labels: { role: 'web' },
+      } as any);
+      mockComponentsRepo.findAll.mockResolvedValue([
+        { id: 'comp-a', mapId: 'map-acme', name: 'acme-web', type: 'service', config: { agentSelector: { labels: { role: 'web' } } } } as any,
+        { id: 'comp-g', mapId: 'map-globex', name: 'globex-web', type: 'service', config: { agentSelector: { labels: { role: 'web' } } } } as any,
+      ]);
+      vi.mocked(mapsRepository.findTenants).mockResolvedValue({ 'map-acme': 'acme', 'map-globex': 'globex' });
+      vi.mocked(gatewayManager.getConnectedAgents).mockReturnValue([
+        { id: 'agent-t', hostname: 'srv-t', gatewayId: 'gw-1', tenant_id: 'acme' },
+      ] as any);
+      vi.mocked(gatewayManager.sendToGateway).mockReturnValue(true);
+
+      await snapshotService.sendSnapshotToAgent('agent-t', { full: true });
+
+      const [, message] = vi.mocked(gatewayManager.sendToGateway).mock.calls.at(-1)!;
+      expect(message.type).toBe('snapshot');
+      const payload = (message as any).payload;
+      expect(payload.tenant_id).toBe('acme');
+      expect(payload.snapshot.components.map((c: any) => c.external_id)).toEqual(['acme-web']);
+    });
   });
 
   describe('sendSnapshotsToAllAgents', () => {
@@ -345,6 +411,57 @@ describe('SnapshotService', () => {
     });
   });
 
+  describe('sendSnapshotToAgent - deltas', () => {
+    const component = (id: string, command: string) => ({
+      id,
+      mapId: 'map-1',
+      name: id,
+      type: 'service',
+      config: {
+        agentSelector: { agentId: 'agent-delta' },
+        checks: [],
+        actions: [{ name: 'start', label: 'Start', command, args: [], async: false }],
+      },
+    }) as any;
+
+    beforeEach(() => {
+      mockAgentsRepo.findById.mockResolvedValue({ id: 'agent-delta', hostname: 'h', labels: {} } as any);
+      vi.mocked(gatewayManager.getConnectedAgents).mockReturnValue([
+        { id: 'agent-delta', hostname: 'h', gatewayId: 'gw-1', capabilities: ['snapshot_delta'] },
+      ]);
+      vi.mocked(gatewayManager.sendToGateway).mockReturnValue(true);
+    });
+
+    it('should send later snapshots as deltas against the last one sent', async () => {
+      mockComponentsRepo.findAll.mockResolvedValue([
+        component('a', 'start-a'), component('b', 'start-b'), component('c', 'start-c'),
+      ]);
+      await snapshotService.sendSnapshotToAgent('agent-delta');
+      const first = vi.mocked(gatewayManager.sendToGateway).mock.calls[0][1] as any;
+      expect(first.type).toBe('snapshot');
+
+      mockComponentsRepo.findAll.mockResolvedValue([
+        component('a', 'start-a'), component('b', 'restart-b'), component('c', 'start-c'), component('d', 'start-d'),
+      ]);
+      await snapshotService.sendSnapshotToAgent('agent-delta');
+      const second = vi.mocked(gatewayManager.sendToGateway).mock.calls[1][1] as any;
+      expect(second.type).toBe('snapshot_delta');
+      expect(second.payload.delta.base_version).toBe(first.payload.snapshot.version);
+      expect(second.payload.delta.operations.map((o: any) => o.op)).toEqual(['update', 'add']);
+
+      await snapshotService.sendSnapshotToAgent('agent-delta', { full: true });
+      const third = vi.mocked(gatewayManager.sendToGateway).mock.calls[2][1] as any;
+      expect(third.type).toBe('snapshot');
+      expect(third.payload.snapshot.version).toBeGreaterThan(second.payload.delta.version);
+    });
+
+    it('should diff removed components', () => {
+      const built = snapshotService.buildSnapshotComponent(component('a', 'x'));
+      expect(snapshotService.diffSnapshot([built], [])).toEqual([{ op: 'remove', component_id: 'a' }]);
+      expect(snapshotService.diffSnapshot([built], [built])).toEqual([]);
+    });
+  });
+
   describe('onMapUpdated', () => {
     it('should send snapshots to affected agents', async () => {
       mockComponentsRepo.findByMap.mockResolvedValue([
@@ -1,16 +1,36 @@
+import { createHash } from 'node:crypto';
 import { createChildLogger } from '../config/logger.js';
-import { componentsRepository, agentsRepository, agentSnapshotsRepository } from '../db/repositories/index.js';
+import { componentsRepository, agentsRepository, agentSnapshotsRepository, mapsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from '../gateway/manager.js';
 import {
+  BackendToGatewayMessage,
   SnapshotPayload,
   SnapshotComponent,
   SnapshotCheck,
   SnapshotAction,
+  SnapshotOperation,
 } from '../gateway/types.js';
 import { ComponentConfig, Component } from '../types/index.js';
 
 const logger = createChildLogger('snapshot-service');
 
+// Last snapshot sent to each agent; the next one goes out as a delta against it
+const sentSnapshots = new Map<string, { version: number; components: SnapshotComponent[] }>();
+
+function labelsMatch(selector: Record<string, string>, labels: Record<string, string> | undefined): boolean {
+  return Object.entries(selector).every(([key, value]) => labels?.[key] === value);
+}
+
+// Rendezvous weight of an agent for a component, as the gateway's sticky pick computes it
+function weight(componentId: string, agentId: string): bigint {
+  return createHash('sha256')
+    .update(componentId)
+    .update(Buffer.from([0]))
+    .update(agentId)
+    .digest()
+    .readBigUInt64BE(0);
+}
+
 /**
  * SnapshotService builds and sends component configuration snapshots to agents.
  *
@@ -20,12 +40,23 @@ const logger = createChildLogger('snapshot-service');
  * - Commands/actions definitions
  *
  * The agent then schedules checks locally and sends only deltas.
+ *
+ * Agents that support it get later snapshots as add/update/remove operations
+ * against the version they hold, and ask for a full snapshot on a gap.
+ *
+ * The checks of a component selected by labels with a `strategy` run on one
+ * of the connected agents its labels match, picked that way; the others get
+ * the component, for its actions, without its checks.
+ *
+ * An agent of a tenant, as its certificate names it, only gets components of
+ * that organization's maps; the gateway drops a snapshot of another tenant.
  */
 export const snapshotService = {
   /**
-   * Build a snapshot for a specific agent containing all components it manages
+   * Build a snapshot for a specific agent containing all components it
+   * manages, of `tenantId`'s maps only if it belongs to a tenant
    */
-  async buildSnapshotForAgent(agentId: string): Promise<SnapshotPayload> {
+  async buildSnapshotForAgent(agentId: string, tenantId?: string): Promise<SnapshotPayload> {
     // Get agent info
     const agent = await agentsRepository.findById(agentId);
     if (!agent) {
@@ -34,8 +65,11 @@ export const snapshotService = {
 
     // Find all components that target this agent
     const allComponents = await componentsRepository.findAll();
+    const mapTenants = tenantId ? await mapsRepository.findTenants() : {};
 
     const matchingComponents = allComponents.filter((component) => {
+      if (tenantId && mapTenants[component.mapId] !== tenantId) return false;
+
       const config = component.config as ComponentConfig;
       const selector = config.agentSelector;
       if (!selector) return false;
@@ -53,9 +87,15 @@ export const snapshotService = {
       return false;
     });
 
-    const snapshotComponents: SnapshotComponent[] = matchingComponents.map((component) =>
-      this.buildSnapshotComponent(component)
-    );
+    const checkAgents = this.assignChecks(allComponents);
+    const snapshotComponents: SnapshotComponent[] = matchingComponents.map((component) => {
+      const snapshotComponent = this.buildSnapshotComponent(component);
+      const checkAgent = checkAgents.get(component.id);
+      if (checkAgent !== undefined && checkAgent !== agentId) {
+        snapshotComponent.checks = [];
+      }
+      return snapshotComponent;
+    });
 
     logger.info(
       { agentId, componentCount: snapshotComponents.length },
@@ -64,12 +104,90 @@ export const snapshotService = {
 
     return {
       agent_id: agentId,
+      tenant_id: tenantId,
       snapshot: {
         components: snapshotComponents,
       },
     };
   },
 
+  /**
+   * The agent running the checks of each component selected by labels with a
+   * strategy, among the connected agents its labels match:
+   * - first: the lowest agent id;
+   * - round_robin: components with the same labels go to each agent in turn;
+   * - least_loaded: the agent with the fewest checks so far;
+   * - sticky: by rendezvous hashing, as the gateway picks for commands.
+   */
+  assignChecks(components: Component[]): Map<string, string> {
+    const agents = gatewayManager
+      .getConnectedAgents()
+      .filter((agent) => !agent.quarantined)
+      .sort((a, b) => (a.id < b.id ? -1 : a.id > b.id ? 1 : 0));
+    const spread = (component: Component) => {
+      const selector = (component.config as ComponentConfig).agentSelector;
+      return !selector?.agentId && selector?.labels && selector.strategy ? selector : undefined;
+    };
+    const checkCount = (component: Component) =>
+      ((component.config as ComponentConfig).checks ?? []).length;
+
+    // Checks each agent runs already, for least_loaded
+    const load = new Map<string, number>();
+    for (const component of components) {
+      const selector = (component.config as ComponentConfig).agentSelector;
+      if (!selector || spread(component)) continue;
+      for (const agent of agents) {
+        if (selector.agentId === agent.id || (selector.labels && labelsMatch(selector.labels, agent.labels))) {
+          load.set(agent.id, (load.get(agent.id) ?? 0) + checkCount(component));
+        }
+      }
+    }
+
+    const turns = new Map<string, number>();
+    const assigned = new Map<string, string>();
+    const ordered = components.filter(spread).sort((a, b) => (a.id < b.id ? -1 : a.id > b.id ? 1 : 0));
+    for (const component of ordered) {
+      const selector = spread(component)!;
+      const candidates = agents.filter((agent) => labelsMatch(selector.labels!, agent.labels));
+      if (candidates.length === 0) continue;
+
+      let picked = candidates[0];
+      switch (selector.strategy) {
+        case 'round_robin': {
+          const key = JSON.stringify(selector.labels);
+          const turn = turns.get(key) ?? 0;
+          picked = candidates[turn % candidates.length];
+          turns.set(key, turn + 1);
+          break;
+        }
+        case 'least_loaded':
+          picked = candidates.reduce((best, agent) =>
+            (load.get(agent.id) ?? 0) < (load.get(best.id) ?? 0) ? agent : best
+          );
+          break;
+        case 'sticky':
+          picked = candidates.reduce((best, agent) =>
+            weight(component.id, agent.id) > weight(component.id, best.id) ? agent : best
+          );
+          break;
+      }
+      assigned.set(component.id, picked.id);
+      load.set(picked.id, (load.get(picked.id) ?? 0) + checkCount(component));
+    }
+    return assigned;
+  },
+
+  /**
+   * Resend the snapshots when the agents a component's checks are spread
+   * over change; unchanged snapshots send nothing
+   */
+  async rebalanceChecks(): Promise<void> {
+    const components = await componentsRepository.findAll();
+    if (components.some((c) => (c.config as ComponentConfig).agentSelector?.strategy)) {
+      await this.sendSnapshotsToAllAgents();
+    }
+  },
+
   /**
    * Convert a Component to a SnapshotComponent for the agent
    */
@@ -89,6 +207,20 @@ export const snapshotService = {
       command: action.command,
       args: action.args || [],
       run_as_user: action.runAsUser,
+      env: action.env,
+      cwd: action.cwd,
+      verify: action.verify && {
+        check: action.verify.check,
+        timeout_secs: action.verify.timeoutSecs,
+        interval_secs: action.verify.intervalSecs,
+        delay_secs: action.verify.delaySecs,
+      },
+      priority: action.priority && {
+        nice: action.priority.nice,
+        io_class: action.priority.ioClass,
+        io_level: action.priority.ioLevel,
+        oom_score_adj: action.priority.oomScoreAdj,
+      },
       async: action.async,
       timeout_secs: action.async ? 300 : 60,
     }));
@@ -102,12 +234,34 @@ export const snapshotService = {
   },
 
   /**
-   * Send a snapshot to a specific agent via its gateway
+   * Operations turning one snapshot's components into another's
    */
-  async sendSnapshotToAgent(agentId: string): Promise<boolean> {
-    try {
-      const snapshot = await this.buildSnapshotForAgent(agentId);
+  diffSnapshot(previous: SnapshotComponent[], next: SnapshotComponent[]): SnapshotOperation[] {
+    const remaining = new Map(previous.map((c) => [c.id, c]));
+    const operations: SnapshotOperation[] = [];
+
+    for (const component of next) {
+      const old = remaining.get(component.id);
+      if (!old) {
+        operations.push({ op: 'add', component });
+      } else if (JSON.stringify(old) !== JSON.stringify(component)) {
+        operations.push({ op: 'update', component });
+      }
+      remaining.delete(component.id);
+    }
+    for (const componentId of remaining.keys()) {
+      operations.push({ op: 'remove', component_id: componentId });
+    }
 
+    return operations;
+  },
+
+  /**
+   * Send a snapshot to a specific agent via its gateway, as a delta when the
+   * agent supports it, unless a full snapshot is asked for
+   */
+  async sendSnapshotToAgent(agentId: string, options: { full?: boolean } = {}): Promise<boolean> {
+    try {
       // Find the gateway for this agent
       const connectedAgents = gatewayManager.getConnectedAgents();
       const agentInfo = connectedAgents.find((a) => a.id === agentId);
@@ -117,13 +271,39 @@ export const snapshotService = {
         return false;
       }
 
-      const sent = gatewayManager.sendToGateway(agentInfo.gatewayId, {
-        type: 'snapshot',
-        payload: snapshot,
-      });
+      const snapshot = await this.buildSnapshotForAgent(agentId, agentInfo.tenant_id);
+
+      const previous = sentSnapshots.get(agentId);
+      const version = Math.max(Date.now(), (previous?.version ?? 0) + 1);
+      snapshot.snapshot.version = version;
+
+      let message: BackendToGatewayMessage = { type: 'snapshot', payload: snapshot };
+      if (previous && !options.full && agentInfo.capabilities?.includes('snapshot_delta')) {
+        const operations = this.diffSnapshot(previous.components, snapshot.snapshot.components);
+        if (operations.length === 0) {
+          return true;
+        }
+        // Past a point, the full snapshot is the smaller message
+        if (operations.length < snapshot.snapshot.components.length) {
+          message = {
+            type: 'snapshot_delta',
+            payload: {
+              agent_id: agentId,
+              tenant_id: snapshot.tenant_id,
+              delta: { base_version: previous.version, version, operations },
+            },
+          };
+        }
+      }
+
+      const sent = gatewayManager.sendToGateway(agentInfo.gatewayId, message);
 
       if (sent) {
-        logger.info({ agentId, gatewayId: agentInfo.gatewayId }, 'Snapshot sent to agent');
+        sentSnapshots.set(agentId, { version, components: snapshot.snapshot.components });
+        logger.info(
+          { agentId, gatewayId: agentInfo.gatewayId, version, type: message.type },
+          'Snapshot sent to agent'
+        );
 
         // Persist snapshot to DB grouped by map
         const allComponents = await componentsRepository.findAll();
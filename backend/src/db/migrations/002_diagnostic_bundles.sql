@@ -0,0 +1,15 @@
+-- ============================================================================
+-- DIAGNOSTICS
+-- ============================================================================
+
+-- Support bundles agents sent in chunks, answering a diagnostics command
+CREATE TABLE diagnostic_bundles (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    job_id UUID NOT NULL UNIQUE,
+    agent_id UUID REFERENCES agents(id) ON DELETE CASCADE,
+    bundle JSONB NOT NULL,
+    bytes INTEGER NOT NULL,
+    received_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_diagnostic_bundles_agent ON diagnostic_bundles(agent_id, received_at DESC);
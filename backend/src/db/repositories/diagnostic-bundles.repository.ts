@@ -0,0 +1,55 @@
+import { getPool } from '../connection.js';
+
+export interface DiagnosticBundle {
+  id: string;
+  jobId: string;
+  agentId: string;
+  bundle: Record<string, unknown>;
+  bytes: number;
+  receivedAt: Date;
+}
+
+export interface CreateDiagnosticBundleParams {
+  jobId: string;
+  agentId: string;
+  bundle: Record<string, unknown>;
+  bytes: number;
+}
+
+export const diagnosticBundlesRepository = {
+  async create(params: CreateDiagnosticBundleParams): Promise<DiagnosticBundle> {
+    const pool = getPool();
+    const result = await pool.query<DiagnosticBundle>(
+      `INSERT INTO diagnostic_bundles (job_id, agent_id, bundle, bytes)
+       VALUES ($1, $2, $3, $4)
+       ON CONFLICT (job_id) DO UPDATE SET
+         bundle = EXCLUDED.bundle,
+         bytes = EXCLUDED.bytes,
+         received_at = NOW()
+       RETURNING
+         id,
+         job_id as "jobId",
+         agent_id as "agentId",
+         bundle, bytes,
+         received_at as "receivedAt"`,
+      [params.jobId, params.agentId, JSON.stringify(params.bundle), params.bytes]
+    );
+    return result.rows[0];
+  },
+
+  async findByJob(jobId: string): Promise<DiagnosticBundle | null> {
+    const pool = getPool();
+    const result = await pool.query<DiagnosticBundle>(
+      `SELECT
+         id,
+         job_id as "jobId",
+         agent_id as "agentId",
+         bundle, bytes,
+         received_at as "receivedAt"
+       FROM diagnostic_bundles
+       WHERE job_id = $1`,
+      [jobId]
+    );
+    return result.rows[0] || null;
+  },
+};
@@ -10,3 +10,4 @@ export { gatewaysRepository, agentsRepository } from './agents.repository.js';
 export { checkResultsRepository } from './check-results.repository.js';
 export { groupsRepository } from './groups.repository.js';
 export { agentSnapshotsRepository } from './agent-snapshots.repository.js';
+export { diagnosticBundlesRepository } from './diagnostic-bundles.repository.js';
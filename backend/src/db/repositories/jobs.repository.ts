@@ -155,6 +155,15 @@ export const jobsRepository = {
     });
   },
 
+  /** A detached job ended; its command was answered when it detached */
+  async markExited(id: string, result: JobResult, finishedAt: Date): Promise<Job | null> {
+    return this.update(id, {
+      status: result.exitCode === 0 ? 'completed' : 'failed',
+      result,
+      completedAt: finishedAt,
+    });
+  },
+
   async findPendingByAgent(agentId: string): Promise<Job[]> {
     const pool = getPool();
     const result = await pool.query<Job>(
@@ -57,6 +57,29 @@ export async function findAll(): Promise<Map[]> {
   return result.rows.map(rowToMap);
 }
 
+// The tenant a map's commands and checks run for: the slug of the
+// organization owning it
+export async function findTenant(mapId: string): Promise<string | null> {
+  const result = await query<{ slug: string }>(
+    `SELECT o.slug FROM maps m
+     JOIN workspaces w ON w.id = m.workspace_id
+     JOIN organizations o ON o.id = w.organization_id
+     WHERE m.id = $1`,
+    [mapId]
+  );
+  return result.rows[0]?.slug ?? null;
+}
+
+// The tenant of every map, by map id
+export async function findTenants(): Promise<Record<string, string>> {
+  const result = await query<{ id: string; slug: string }>(
+    `SELECT m.id, o.slug FROM maps m
+     JOIN workspaces w ON w.id = m.workspace_id
+     JOIN organizations o ON o.id = w.organization_id`
+  );
+  return Object.fromEntries(result.rows.map((row) => [row.id, row.slug]));
+}
+
 export async function findByWorkspace(workspaceId: string): Promise<Map[]> {
   const result = await query<MapRow>(
     'SELECT * FROM maps WHERE workspace_id = $1 ORDER BY name',
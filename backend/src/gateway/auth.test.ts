@@ -0,0 +1,57 @@
+import { describe, it, expect, vi, beforeEach } from 'vitest';
+import jwt from 'jsonwebtoken';
+
+vi.mock('../config/index.js', () => ({
+  config: {
+    logging: { level: 'silent' },
+    nodeEnv: 'test',
+    gateways: { backendJwt: false },
+  },
+}));
+
+import { config } from '../config/index.js';
+import { verifyGateway, backendToken, gatewayAuthRequired } from './auth.js';
+
+const SECRET = 'gateway-link-secret';
+
+function gatewayJwt(sub: string, secret = SECRET, expiresIn = 60): string {
+  return jwt.sign({ sub, iss: sub, zone: 'prod' }, secret, { algorithm: 'HS256', expiresIn });
+}
+
+describe('Gateway link authentication', () => {
+  beforeEach(() => {
+    config.gateways = { backendJwt: false };
+  });
+
+  it('accepts any gateway when no token or secret is configured', () => {
+    expect(gatewayAuthRequired()).toBe(false);
+    expect(verifyGateway(undefined)).toEqual({});
+  });
+
+  it('checks the static token', () => {
+    config.gateways.token = 's3cret';
+    expect(verifyGateway('Bearer s3cret')).toEqual({});
+    expect(verifyGateway('Bearer guessed')).toBeNull();
+    expect(verifyGateway(undefined)).toBeNull();
+  });
+
+  it('checks the JWT and returns its subject', () => {
+    config.gateways.jwtSecret = SECRET;
+    expect(verifyGateway(`Bearer ${gatewayJwt('gw-1')}`)).toEqual({ gatewayId: 'gw-1', zone: 'prod' });
+    expect(verifyGateway(`Bearer ${gatewayJwt('gw-1', 'other')}`)).toBeNull();
+    expect(verifyGateway(`Bearer ${gatewayJwt('gw-1', SECRET, -10)}`)).toBeNull();
+  });
+
+  it('answers with the backend token or a JWT for the gateway', () => {
+    expect(backendToken({})).toBeUndefined();
+
+    config.gateways.backendToken = 'from-backend';
+    expect(backendToken({ gatewayId: 'gw-1' })).toBe('from-backend');
+
+    config.gateways.jwtSecret = SECRET;
+    config.gateways.backendJwt = true;
+    const token = backendToken({ gatewayId: 'gw-1' })!;
+    const claims = jwt.verify(token, SECRET, { algorithms: ['HS256'] }) as jwt.JwtPayload;
+    expect(claims.aud).toBe('gw-1');
+  });
+});
@@ -0,0 +1,83 @@
+import { createHash, timingSafeEqual } from 'crypto';
+import jwt from 'jsonwebtoken';
+import { config } from '../config/index.js';
+
+/** Header the backend answers the gateway handshake with */
+export const BACKEND_TOKEN_HEADER = 'X-OpsMap-Backend-Token';
+
+/** Who a gateway proved to be in its handshake */
+export interface GatewayIdentity {
+  /** The JWT's subject; unknown when the gateway sent the static token */
+  gatewayId?: string;
+  zone?: string;
+}
+
+interface GatewayClaims {
+  sub?: string;
+  iss?: string;
+  zone?: string;
+}
+
+/** Whether gateways must authenticate at all */
+export function gatewayAuthRequired(): boolean {
+  return Boolean(config.gateways.token || config.gateways.jwtSecret);
+}
+
+function sameToken(presented: string, expected: string): boolean {
+  // Comparing digests tells a timing attacker nothing about the token
+  const a = createHash('sha256').update(presented).digest();
+  const b = createHash('sha256').update(expected).digest();
+  return timingSafeEqual(a, b);
+}
+
+/**
+ * Check the gateway's `Authorization: Bearer` header: the static
+ * GATEWAY_TOKEN, or an HS256 JWT signed with GATEWAY_JWT_SECRET.
+ * Returns null when the gateway is not authenticated.
+ */
+export function verifyGateway(authorization: string | undefined): GatewayIdentity | null {
+  if (!gatewayAuthRequired()) {
+    return {};
+  }
+  const match = /^Bearer\s+(.+)$/i.exec(authorization?.trim() ?? '');
+  if (!match) {
+    return null;
+  }
+  const token = match[1];
+
+  if (config.gateways.token && sameToken(token, config.gateways.token)) {
+    return {};
+  }
+  if (config.gateways.jwtSecret) {
+    try {
+      const claims = jwt.verify(token, config.gateways.jwtSecret, {
+        algorithms: ['HS256'],
+      }) as GatewayClaims;
+      const gatewayId = claims.sub ?? claims.iss;
+      if (!gatewayId) {
+        return null;
+      }
+      return { gatewayId, zone: claims.zone };
+    } catch {
+      return null;
+    }
+  }
+  return null;
+}
+
+/**
+ * The token to answer the handshake with, so the gateway knows it reached
+ * the real backend: GATEWAY_BACKEND_TOKEN, or with GATEWAY_BACKEND_JWT a
+ * short-lived JWT whose `aud` is the gateway's id.
+ */
+export function backendToken(identity: GatewayIdentity): string | undefined {
+  if (config.gateways.backendJwt && config.gateways.jwtSecret && identity.gatewayId) {
+    return jwt.sign({}, config.gateways.jwtSecret, {
+      algorithm: 'HS256',
+      issuer: 'opsmap-backend',
+      audience: identity.gatewayId,
+      expiresIn: 300,
+    });
+  }
+  return config.gateways.backendToken;
+}
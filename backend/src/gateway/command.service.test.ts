@@ -31,9 +31,14 @@ vi.mock('./manager.js', () => ({
   },
 }));
 
+vi.mock('./tenant.js', () => ({
+  commandTenant: vi.fn().mockResolvedValue(undefined),
+}));
+
 import { commandService } from './command.service.js';
 import { componentsRepository, jobsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from './manager.js';
+import { commandTenant } from './tenant.js';
 
 describe('commandService', () => {
   beforeEach(() => {
@@ -158,6 +163,76 @@ describe('commandService', () => {
       expect(result.success).toBe(true);
     });
 
+    it('should target the component container', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        config: {
+          agentSelector: { agentId: 'agent-1' },
+          container: { name: 'api', runtime: 'podman' },
+          actions: [{ name: 'start', command: 'supervisorctl start api' }],
+        },
+      } as any);
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-3' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      await commandService.executeComponentCommand(baseParams);
+
+      const command = vi.mocked(gatewayManager.sendCommand).mock.calls.at(-1)![3];
+      expect(command.args.container).toBe('api');
+      expect(command.args.container_runtime).toBe('podman');
+    });
+
+    it('should run the command for the tenant of the component\'s map', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        mapId: 'map-1',
+        config: {
+          agentSelector: { agentId: 'agent-1' },
+          actions: [{ name: 'start', command: '/usr/bin/start.sh' }],
+        },
+      } as any);
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-t' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+      vi.mocked(commandTenant).mockResolvedValueOnce('acme');
+
+      await commandService.executeComponentCommand(baseParams);
+
+      expect(commandTenant).toHaveBeenCalledWith('agent-1', { mapId: 'map-1' });
+      const command = vi.mocked(gatewayManager.sendCommand).mock.calls.at(-1)![3];
+      expect(command.tenant_id).toBe('acme');
+    });
+
+    it('should pass the action priority', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        config: {
+          agentSelector: { agentId: 'agent-1' },
+          actions: [
+            {
+              name: 'start',
+              command: 'reindex.sh',
+              priority: { nice: 10, ioClass: 'idle', oomScoreAdj: 500 },
+            },
+          ],
+        },
+      } as any);
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-4' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      await commandService.executeComponentCommand(baseParams);
+
+      const command = vi.mocked(gatewayManager.sendCommand).mock.calls.at(-1)![3];
+      expect(command.args.priority).toEqual({
+        nice: 10,
+        io_class: 'idle',
+        io_level: undefined,
+        oom_score_adj: 500,
+      });
+    });
+
     it('should use labels-based agent selector', async () => {
       vi.mocked(componentsRepository.findById).mockResolvedValue({
         id: 'comp-1',
@@ -176,6 +251,28 @@ describe('commandService', () => {
       expect(result.success).toBe(true);
       expect(gatewayManager.findAgentByLabels).toHaveBeenCalledWith({ role: 'web' });
     });
+
+    it('should let the gateway pick the agent when the selector has a strategy', async () => {
+      vi.mocked(componentsRepository.findById).mockResolvedValue({
+        id: 'comp-1',
+        config: {
+          agentSelector: { labels: { role: 'cache' }, strategy: 'sticky' },
+          actions: [{ name: 'start', command: '/usr/bin/start.sh' }],
+        },
+      } as any);
+      vi.mocked(gatewayManager.findAgentByLabels).mockClear();
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-3b' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'broadcast' });
+
+      const result = await commandService.executeComponentCommand(baseParams);
+
+      expect(result.success).toBe(true);
+      expect(gatewayManager.findAgentByLabels).not.toHaveBeenCalled();
+      const [, agentId, labels, , routing] = vi.mocked(gatewayManager.sendCommand).mock.calls.at(-1)!;
+      expect(agentId).toBeUndefined();
+      expect(labels).toEqual({ role: 'cache' });
+      expect(routing).toEqual({ strategy: 'sticky' });
+    });
   });
 
   describe('executeNativeCommand', () => {
@@ -221,6 +318,78 @@ describe('commandService', () => {
     });
   });
 
+  describe('setChecksPaused', () => {
+    it('should send pause_checks for the component', async () => {
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-6' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      const result = await commandService.setChecksPaused(
+        'agent-1',
+        'web',
+        { paused: true, check: 'health', durationSecs: 600 },
+        'user-1'
+      );
+
+      expect(result.success).toBe(true);
+      expect(gatewayManager.sendCommand).toHaveBeenCalledWith(
+        'job-6',
+        'agent-1',
+        undefined,
+        expect.objectContaining({
+          command_type: 'pause_checks',
+          component_id: 'web',
+          params: { check: 'health', duration_secs: 600 },
+        })
+      );
+    });
+
+    it('should send resume_checks without a duration', async () => {
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-7' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      await commandService.setChecksPaused('agent-1', 'web', { paused: false, durationSecs: 600 }, 'user-1');
+
+      expect(gatewayManager.sendCommand).toHaveBeenCalledWith(
+        'job-7',
+        'agent-1',
+        undefined,
+        expect.objectContaining({ command_type: 'resume_checks', params: {} })
+      );
+    });
+  });
+
+  describe('runCheck', () => {
+    it('should send run_check for the named check', async () => {
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(true);
+      vi.mocked(jobsRepository.create).mockResolvedValue({ id: 'job-8' } as any);
+      vi.mocked(gatewayManager.sendCommand).mockResolvedValue({ sent: true, gatewayId: 'gw-1' });
+
+      const result = await commandService.runCheck('agent-1', 'web', 'health', 'user-1');
+
+      expect(result.success).toBe(true);
+      expect(gatewayManager.sendCommand).toHaveBeenCalledWith(
+        'job-8',
+        'agent-1',
+        undefined,
+        expect.objectContaining({
+          command_type: 'run_check',
+          component_id: 'web',
+          params: { check: 'health' },
+        })
+      );
+    });
+
+    it('should refuse an offline agent', async () => {
+      vi.mocked(gatewayManager.isAgentOnline).mockReturnValue(false);
+
+      const result = await commandService.runCheck('agent-1', 'web', 'health', 'user-1');
+
+      expect(result).toEqual({ success: false, error: 'Agent is offline' });
+    });
+  });
+
   describe('getJobStatus', () => {
     it('should return job from repository', async () => {
       const mockJob = { id: 'job-1', status: 'completed' };
@@ -1,6 +1,8 @@
+import { randomUUID } from 'node:crypto';
 import { createChildLogger } from '../config/logger.js';
 import { jobsRepository, componentsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from './manager.js';
+import { commandTenant } from './tenant.js';
 import { AgentCommand } from './types.js';
 import { Job, ComponentConfig } from '../types/index.js';
 
@@ -34,7 +36,17 @@ export const commandService = {
     const config = component.config as ComponentConfig;
 
     // Find action in component config
-    let action: { name: string; command: string; args?: string[]; async?: boolean; runAsUser?: string } | undefined;
+    let action:
+      | {
+          name: string;
+          command: string;
+          args?: string[];
+          async?: boolean;
+          runAsUser?: string;
+          env?: Record<string, string>;
+          cwd?: string;
+        }
+      | undefined;
     // Check if it's a built-in command (start, stop, restart)
     if (['start', 'stop', 'restart'].includes(commandName)) {
       const actions = config.actions || [];
@@ -62,8 +74,8 @@ export const commandService = {
     } else if (agentSelector?.labels) {
       labels = agentSelector.labels;
 
-      // Try to find a specific agent
-      const agent = gatewayManager.findAgentByLabels(labels);
+      // Try to find a specific agent, unless the gateway is to pick one
+      const agent = agentSelector.strategy ? undefined : gatewayManager.findAgentByLabels(labels);
       if (agent) {
         agentId = agent.id;
       }
@@ -104,15 +116,29 @@ export const commandService = {
         command: action.command,
         args: action.args || [],
         run_as_user: action.runAsUser,
+        env: action.env,
+        cwd: action.cwd,
+        priority: action.priority && {
+          nice: action.priority.nice,
+          io_class: action.priority.ioClass,
+          io_level: action.priority.ioLevel,
+          oom_score_adj: action.priority.oomScoreAdj,
+        },
+        container: config.container?.name,
+        container_runtime: config.container?.runtime,
         component_id: componentId,
         map_id: mapId,
         ...commandParams,
       },
       timeout_secs: 300, // 5 minutes default
+      correlation_id: randomUUID(),
+      tenant_id: await commandTenant(agentId, { mapId: component.mapId }),
     };
 
     // Send command to gateway
-    const result = await gatewayManager.sendCommand(job.id, agentId, labels, agentCommand);
+    const result = await gatewayManager.sendCommand(job.id, agentId, labels, agentCommand, {
+      strategy: agentSelector?.strategy,
+    });
 
     if (!result.sent) {
       await jobsRepository.markFailed(job.id, result.error || 'Failed to send command');
@@ -124,7 +150,14 @@ export const commandService = {
     }
 
     logger.info(
-      { jobId: job.id, componentId, commandName, agentId, gatewayId: result.gatewayId },
+      {
+        jobId: job.id,
+        correlationId: agentCommand.correlation_id,
+        componentId,
+        commandName,
+        agentId,
+        gatewayId: result.gatewayId,
+      },
       'Command sent to agent'
     );
 
@@ -162,6 +195,8 @@ export const commandService = {
       name: commandName,
       args,
       timeout_secs: 60,
+      correlation_id: randomUUID(),
+      tenant_id: await commandTenant(agentId, { userId }),
     };
 
     // Send command
@@ -183,6 +218,94 @@ export const commandService = {
     };
   },
 
+  /**
+   * Pause a component's checks on its agent, or only `check`, for
+   * `durationSecs` or until resumed; with `paused: false`, resume them
+   */
+  async setChecksPaused(
+    agentId: string,
+    componentId: string,
+    options: { paused: boolean; check?: string; durationSecs?: number },
+    userId: string
+  ): Promise<ExecuteCommandResult> {
+    if (!gatewayManager.isAgentOnline(agentId)) {
+      return { success: false, error: 'Agent is offline' };
+    }
+
+    const commandType = options.paused ? 'pause_checks' : 'resume_checks';
+    const params: Record<string, unknown> = {};
+    if (options.check) {
+      params.check = options.check;
+    }
+    if (options.paused && options.durationSecs !== undefined) {
+      params.duration_secs = options.durationSecs;
+    }
+
+    return this.sendCheckCommand(agentId, componentId, commandType, params, userId);
+  },
+
+  /**
+   * Run a component's check on its agent now, outside its interval; the
+   * check's result comes back in the command response
+   */
+  async runCheck(
+    agentId: string,
+    componentId: string,
+    check: string,
+    userId: string
+  ): Promise<ExecuteCommandResult> {
+    if (!gatewayManager.isAgentOnline(agentId)) {
+      return { success: false, error: 'Agent is offline' };
+    }
+
+    return this.sendCheckCommand(agentId, componentId, 'run_check', { check }, userId);
+  },
+
+  async sendCheckCommand(
+    agentId: string,
+    componentId: string,
+    commandType: 'pause_checks' | 'resume_checks' | 'run_check',
+    params: Record<string, unknown>,
+    userId: string
+  ): Promise<ExecuteCommandResult> {
+    const job = await jobsRepository.create({
+      type: 'command',
+      agentId,
+      command: commandType,
+      args: Object.values(params).map(String),
+      createdBy: userId,
+    });
+
+    const agentCommand: AgentCommand = {
+      id: job.id,
+      command_type: commandType,
+      name: commandType,
+      args: {},
+      component_id: componentId,
+      params,
+      timeout_secs: 30,
+      correlation_id: randomUUID(),
+      tenant_id: await commandTenant(agentId, { userId }),
+    };
+
+    const result = await gatewayManager.sendCommand(job.id, agentId, undefined, agentCommand);
+
+    if (!result.sent) {
+      await jobsRepository.markFailed(job.id, result.error || 'Failed to send command');
+      return {
+        success: false,
+        error: result.error || 'Failed to send command to agent',
+        jobId: job.id,
+      };
+    }
+
+    return {
+      success: true,
+      jobId: job.id,
+      message: `${commandType} command sent`,
+    };
+  },
+
   async getJobStatus(jobId: string): Promise<Job | null> {
     return jobsRepository.findById(jobId);
   },
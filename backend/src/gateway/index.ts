@@ -1,3 +1,5 @@
 export * from './types.js';
 export * from './manager.js';
+export * from './auth.js';
 export * from './command.service.js';
+export * from './tenant.js';
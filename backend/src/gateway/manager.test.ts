@@ -1,5 +1,6 @@
 import { describe, it, expect, vi, beforeEach, afterEach } from 'vitest';
 import { EventEmitter } from 'events';
+import { deflateSync } from 'zlib';
 
 vi.mock('../config/index.js', () => ({
   config: {
@@ -32,20 +33,25 @@ vi.mock('../db/repositories/index.js', () => ({
     markCompleted: vi.fn().mockResolvedValue(undefined),
     markFailed: vi.fn().mockResolvedValue(undefined),
     markTimeout: vi.fn().mockResolvedValue(undefined),
+    markExited: vi.fn().mockResolvedValue(undefined),
   },
   checkResultsRepository: {
     create: vi.fn().mockResolvedValue(undefined),
   },
+  diagnosticBundlesRepository: {
+    create: vi.fn().mockResolvedValue(undefined),
+  },
 }));
 
 import { gatewayManager } from './manager.js';
-import { gatewaysRepository, agentsRepository, jobsRepository, checkResultsRepository } from '../db/repositories/index.js';
+import { gatewaysRepository, agentsRepository, jobsRepository, checkResultsRepository, diagnosticBundlesRepository } from '../db/repositories/index.js';
 import WebSocket from 'ws';
 
 const mockGatewaysRepo = vi.mocked(gatewaysRepository);
 const mockAgentsRepo = vi.mocked(agentsRepository);
 const mockJobsRepo = vi.mocked(jobsRepository);
 const mockCheckResultsRepo = vi.mocked(checkResultsRepository);
+const mockDiagnosticBundlesRepo = vi.mocked(diagnosticBundlesRepository);
 
 // Helper to create a mock WebSocket
 function createMockWs(): EventEmitter & { send: ReturnType<typeof vi.fn>; close: ReturnType<typeof vi.fn>; readyState: number } {
@@ -71,10 +77,10 @@ async function registerGateway(ws: ReturnType<typeof createMockWs>, gatewayId: s
   await messageHandler(registerMsg);
 }
 
-function getMessageHandler(ws: ReturnType<typeof createMockWs>): (data: string) => Promise<void> {
+function getMessageHandler(ws: ReturnType<typeof createMockWs>): (data: string | Buffer) => Promise<void> {
   // Get the 'message' listener that was registered by handleConnection
   const listeners = ws.listeners('message');
-  return listeners[0] as (data: string) => Promise<void>;
+  return listeners[0] as (data: string | Buffer) => Promise<void>;
 }
 
 describe('GatewayManager', () => {
@@ -167,6 +173,18 @@ describe('GatewayManager', () => {
       ]);
     });
 
+    it('should refuse a gateway registering under an id its token does not name', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any, { gatewayId: 'gw-real' });
+
+      await registerGateway(ws, 'gw-spoofed');
+
+      expect(ws.close).toHaveBeenCalledWith(1008, expect.any(String));
+      expect(mockGatewaysRepo.upsert).not.toHaveBeenCalledWith(
+        expect.objectContaining({ id: 'gw-spoofed' })
+      );
+    });
+
     it('should register agents provided during gateway registration', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -202,6 +220,44 @@ describe('GatewayManager', () => {
       expect(gatewayManager.isAgentOnline('agent-2')).toBe(true);
     });
 
+    it('should handle agent_metadata message', async () => {
+      const ws = createMockWs();
+      const emitSpy = vi.spyOn(gatewayManager, 'emit');
+      gatewayManager.handleConnection(ws as any);
+      const agents = [
+        { id: 'agent-9', hostname: 'host9', labels: { role: 'db' }, version: '1.0', os: 'linux', connected_at: new Date().toISOString(), last_heartbeat: new Date().toISOString() },
+      ];
+      await registerGateway(ws, 'gw-9', agents);
+
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'agent_metadata',
+        payload: { ...agents[0], labels: { role: 'web' }, ip_addresses: ['10.0.0.9'] },
+      }));
+
+      expect(mockAgentsRepo.upsert).toHaveBeenLastCalledWith(
+        expect.objectContaining({ id: 'agent-9', gatewayId: 'gw-9', labels: { role: 'web' } })
+      );
+      expect(gatewayManager.findAgentByLabels({ role: 'web' })).toEqual(
+        expect.objectContaining({ id: 'agent-9' })
+      );
+      expect(emitSpy).toHaveBeenCalledWith('agent:metadata', expect.objectContaining({
+        agentId: 'agent-9',
+        ipAddresses: ['10.0.0.9'],
+      }));
+
+      // Quarantined at the gateway: no longer picked by labels
+      await messageHandler(JSON.stringify({
+        type: 'agent_metadata',
+        payload: { ...agents[0], labels: { role: 'web' }, quarantined: true },
+      }));
+      expect(gatewayManager.findAgentByLabels({ role: 'web' })).toBeUndefined();
+      expect(emitSpy).toHaveBeenLastCalledWith('agent:metadata', expect.objectContaining({
+        agentId: 'agent-9',
+        quarantined: true,
+      }));
+    });
+
     it('should handle agent_disconnected message', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -238,6 +294,89 @@ describe('GatewayManager', () => {
       emitSpy.mockRestore();
     });
 
+    it('should answer registration with the accepted protocol', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'register',
+        payload: {
+          gateway_id: 'gw-proto',
+          zone: 'test-zone',
+          version: '1.0.0',
+          agents: [],
+          protocol_version: 3,
+          capabilities: ['acks', 'compression'],
+        },
+      }));
+
+      expect(ws.send).toHaveBeenCalledWith(JSON.stringify({
+        type: 'registered',
+        payload: { protocol_version: 2, capabilities: ['acks'] },
+      }));
+    });
+
+    it('should push the agent version policy to gateways', async () => {
+      gatewayManager.setAgentVersionPolicy({ min_version: '0.2.0', reject: true });
+
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await getMessageHandler(ws)(JSON.stringify({
+        type: 'register',
+        payload: {
+          gateway_id: 'gw-versions',
+          zone: 'test-zone',
+          version: '1.0.0',
+          agents: [],
+          protocol_version: 2,
+          capabilities: [],
+        },
+      }));
+
+      expect(ws.send).toHaveBeenCalledWith(JSON.stringify({
+        type: 'agent_version_policy',
+        payload: { min_version: '0.2.0', reject: true },
+      }));
+
+      gatewayManager.setAgentVersionPolicy(null);
+      expect(ws.send).toHaveBeenLastCalledWith(JSON.stringify({
+        type: 'agent_version_policy',
+        payload: {},
+      }));
+    });
+
+    it('should inflate deflated frames', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-deflate');
+
+      const emitSpy = vi.spyOn(gatewayManager, 'emit');
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(deflateSync(JSON.stringify({
+        type: 'status_update',
+        payload: { agent_id: 'agent-z', status: 'ok', timestamp: new Date().toISOString() },
+      })));
+
+      expect(emitSpy).toHaveBeenCalledWith('status:update', expect.objectContaining({ agent_id: 'agent-z' }));
+      emitSpy.mockRestore();
+    });
+
+    it('should ack sequenced messages once handled', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-ack');
+
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'status_update',
+        payload: { agent_id: 'agent-1', status: 'ok', timestamp: new Date().toISOString() },
+        seq: 42,
+      }));
+
+      expect(ws.send).toHaveBeenCalledWith(JSON.stringify({ type: 'ack', payload: { seq: 42 } }));
+    });
+
     it('should handle command_response with started status', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -282,6 +421,27 @@ describe('GatewayManager', () => {
       });
     });
 
+    it('should handle each response of a command_responses batch', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-7b');
+
+      mockJobsRepo.findById.mockResolvedValue({ id: 'job-2b', status: 'pending' } as any);
+
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'command_responses',
+        payload: {
+          job_id: 'job-2b',
+          responses: ['agent-1', 'agent-2'].map((agent_id) => ({
+            job_id: 'job-2b', agent_id, status: 'started', timestamp: new Date().toISOString(),
+          })),
+        },
+      }));
+
+      expect(mockJobsRepo.markStarted).toHaveBeenCalledTimes(2);
+    });
+
     it('should handle command_response with failed status', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -314,6 +474,111 @@ describe('GatewayManager', () => {
       expect(mockJobsRepo.markTimeout).toHaveBeenCalledWith('job-4');
     });
 
+    it('should mark the job timed out on command_timeout', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-9b');
+
+      mockJobsRepo.findById.mockResolvedValue({ id: 'job-4b', status: 'running' } as any);
+
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'command_timeout',
+        payload: { job_id: 'job-4b', agent_id: 'agent-1', command_type: 'restart', deliveries: 2, timestamp: new Date().toISOString() },
+      }));
+
+      expect(mockJobsRepo.markTimeout).toHaveBeenCalledWith('job-4b');
+    });
+
+    it('should record the exit of a detached job on job_update', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-9d');
+
+      mockJobsRepo.findById.mockResolvedValue({
+        id: 'job-4d',
+        status: 'completed',
+        result: { exitCode: 0, stdout: 'Process detached with job_id: d-1', stderr: '', durationMs: 0, timedOut: false },
+        startedAt: null,
+      } as any);
+      const listener = vi.fn();
+      gatewayManager.on('job:update', listener);
+
+      const finishedAt = new Date().toISOString();
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({
+        type: 'job_update',
+        payload: {
+          job_id: 'job-4d', agent_id: 'agent-1', detached_job_id: 'd-1',
+          status: 'killed', signal: 9, finished_at: finishedAt,
+        },
+      }));
+
+      expect(mockJobsRepo.markExited).toHaveBeenCalledWith(
+        'job-4d',
+        expect.objectContaining({ exitCode: -1, stderr: 'Detached job killed by signal 9' }),
+        new Date(finishedAt)
+      );
+      expect(listener).toHaveBeenCalledWith(expect.objectContaining({ jobId: 'job-4d', status: 'killed' }));
+      gatewayManager.off('job:update', listener);
+    });
+
+    it('should emit job:summary on command_summary', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-9c');
+
+      const listener = vi.fn();
+      gatewayManager.on('job:summary', listener);
+
+      const summary = {
+        job_id: 'job-4c', targeted: 3, succeeded: 1, failed: 1, skipped: 1, aborted: true,
+        results: [
+          { agent_id: 'agent-1', status: 'failed', error: 'exit 1' },
+          { agent_id: 'agent-2', status: 'skipped' },
+        ],
+        started_at: new Date().toISOString(), finished_at: new Date().toISOString(),
+      };
+      const messageHandler = getMessageHandler(ws);
+      await messageHandler(JSON.stringify({ type: 'command_summary', payload: summary }));
+
+      expect(listener).toHaveBeenCalledWith({ jobId: 'job-4c', summary });
+      gatewayManager.off('job:summary', listener);
+    });
+
+    it('should store a support bundle once all its chunks came', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      await registerGateway(ws, 'gw-diag');
+
+      const listener = vi.fn();
+      gatewayManager.on('diagnostics:bundle', listener);
+
+      const text = JSON.stringify({ agent: { version: '1.0.0' } });
+      const parts = [text.slice(0, 10), text.slice(10)];
+      const messageHandler = getMessageHandler(ws);
+      // Out of order, and once twice
+      for (const index of [1, 1, 0]) {
+        await messageHandler(JSON.stringify({
+          type: 'diagnostics',
+          payload: {
+            agent_id: 'agent-d',
+            chunk: { job_id: 'job-diag', agent_id: 'agent-d', index, total: 2, data: parts[index] },
+          },
+        }));
+      }
+
+      expect(mockDiagnosticBundlesRepo.create).toHaveBeenCalledTimes(1);
+      expect(mockDiagnosticBundlesRepo.create).toHaveBeenCalledWith({
+        jobId: 'job-diag',
+        agentId: 'agent-d',
+        bundle: { agent: { version: '1.0.0' } },
+        bytes: text.length,
+      });
+      expect(listener).toHaveBeenCalledWith({ jobId: 'job-diag', agentId: 'agent-d' });
+      gatewayManager.off('diagnostics:bundle', listener);
+    });
+
     it('should skip command_response if job not found', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -408,6 +673,44 @@ describe('GatewayManager', () => {
       expect(ws.send).toHaveBeenCalled();
     });
 
+    it('should not take the command\'s tenant from the agent', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      const agents = [
+        { id: 'agent-tn', hostname: 'h1', labels: {}, version: '1.0', os: 'linux', tenant_id: 'acme', connected_at: new Date().toISOString(), last_heartbeat: new Date().toISOString() },
+      ];
+      await registerGateway(ws, 'gw-tn', agents);
+
+      await gatewayManager.sendCommand(
+        'job-tn',
+        'agent-tn',
+        undefined,
+        { id: 'cmd-tn', command_type: 'sync', name: 'disk_space', args: {}, timeout_secs: 60 }
+      );
+
+      const sent = JSON.parse(ws.send.mock.calls[ws.send.mock.calls.length - 1][0]);
+      expect(sent.payload.command.tenant_id).toBeUndefined();
+    });
+
+    it('should stamp the command with the gateway\'s zone', async () => {
+      const ws = createMockWs();
+      gatewayManager.handleConnection(ws as any);
+      const agents = [
+        { id: 'agent-zn', hostname: 'h1', labels: {}, version: '1.0', os: 'linux', connected_at: new Date().toISOString(), last_heartbeat: new Date().toISOString() },
+      ];
+      await registerGateway(ws, 'gw-zn', agents);
+
+      await gatewayManager.sendCommand(
+        'job-zn',
+        'agent-zn',
+        undefined,
+        { id: 'cmd-zn', command_type: 'sync', name: 'disk_space', args: {}, timeout_secs: 60 }
+      );
+
+      const sent = JSON.parse(ws.send.mock.calls[ws.send.mock.calls.length - 1][0]);
+      expect(sent.payload.zone).toBe('test-zone');
+    });
+
     it('should lookup agent in database if not found in memory', async () => {
       const ws = createMockWs();
       gatewayManager.handleConnection(ws as any);
@@ -1,10 +1,12 @@
 import WebSocket from 'ws';
 import { EventEmitter } from 'events';
+import { deflateSync, inflateSync } from 'zlib';
 import { createChildLogger } from '../config/logger.js';
 import {
   gatewaysRepository,
   agentsRepository,
   jobsRepository,
+  diagnosticBundlesRepository,
 } from '../db/repositories/index.js';
 import {
   GatewayToBackendMessage,
@@ -12,18 +14,69 @@ import {
   CommandPayload,
   AgentCommand,
   CommandResponse,
+  CommandTimeout,
+  CommandSummary,
+  DiagnosticsChunk,
+  JobUpdate,
   StatusUpdate,
   AgentInfo,
+  AgentVersionPolicy,
+  GatewayRegistration,
+  Rollout,
+  AgentSelectionStrategy,
 } from './types.js';
+import type { GatewayIdentity } from './auth.js';
 import { fsmManager, ComponentEvent } from '../core/fsm/index.js';
 import { checkResultsRepository } from '../db/repositories/index.js';
 
 const logger = createChildLogger('gateway-manager');
 
+// Gateway protocol spoken by this backend (1 predates negotiation)
+const PROTOCOL_VERSION = 2;
+const CAPABILITIES = ['acks', 'deflate', 'diagnostics', 'response_batches'];
+
+// Frames larger than this are deflated for gateways that accepted 'deflate'
+const COMPRESS_ABOVE = 64 * 1024;
+// Refuse to inflate frames beyond this size
+const MAX_INFLATED = 64 * 1024 * 1024;
+
+// Support bundles being reassembled are dropped past this size, or when no
+// chunk came for this long
+const MAX_BUNDLE_BYTES = 64 * 1024 * 1024;
+const BUNDLE_TIMEOUT_MS = 10 * 60 * 1000;
+
+interface PendingBundle {
+  agentId: string;
+  chunks: string[];
+  received: number;
+  bytes: number;
+  updatedAt: number;
+}
+
+// zlib streams start with 0x78; JSON frames never do
+function decodeFrame(data: WebSocket.Data | string): string {
+  if (typeof data === 'string') return data;
+  const buf = Buffer.isBuffer(data)
+    ? data
+    : Array.isArray(data)
+      ? Buffer.concat(data)
+      : Buffer.from(data);
+  if (buf[0] === 0x78) {
+    return inflateSync(buf, { maxOutputLength: MAX_INFLATED }).toString();
+  }
+  return buf.toString();
+}
+
+function encodeFrame(message: BackendToGatewayMessage, compress: boolean): string | Buffer {
+  const json = JSON.stringify(message);
+  return compress && Buffer.byteLength(json) > COMPRESS_ABOVE ? deflateSync(json) : json;
+}
+
 interface ConnectedGateway {
   id: string;
   zone: string;
   ws: WebSocket;
+  compress: boolean;
   agents: Map<string, AgentInfo>;
   connectedAt: Date;
   lastHeartbeat: Date;
@@ -33,6 +86,9 @@ class GatewayManager extends EventEmitter {
   private gateways: Map<string, ConnectedGateway> = new Map();
   private heartbeatInterval: NodeJS.Timeout | null = null;
   private cleanupInterval: NodeJS.Timeout | null = null;
+  private agentVersionPolicy: AgentVersionPolicy | null = null;
+  // Support bundles arriving in chunks, by job id
+  private bundles: Map<string, PendingBundle> = new Map();
 
   constructor() {
     super();
@@ -65,15 +121,24 @@ class GatewayManager extends EventEmitter {
     logger.info('Gateway manager stopped');
   }
 
-  handleConnection(ws: WebSocket): void {
+  handleConnection(ws: WebSocket, identity: GatewayIdentity = {}): void {
     let gatewayId: string | null = null;
 
     ws.on('message', async (data: WebSocket.Data) => {
       try {
-        const message: GatewayToBackendMessage = JSON.parse(data.toString());
+        const message: GatewayToBackendMessage = JSON.parse(decodeFrame(data));
 
         switch (message.type) {
           case 'register':
+            // A gateway that authenticated with a JWT registers under its subject only
+            if (identity.gatewayId && identity.gatewayId !== message.payload.gateway_id) {
+              logger.warn(
+                { authenticated: identity.gatewayId, claimed: message.payload.gateway_id },
+                'Gateway registered under an id its token does not name'
+              );
+              ws.close(1008, 'gateway id does not match token');
+              return;
+            }
             gatewayId = await this.handleRegister(ws, message.payload);
             break;
           case 'agent_connected':
@@ -86,18 +151,55 @@ class GatewayManager extends EventEmitter {
               await this.handleAgentDisconnected(gatewayId, message.payload.agent_id);
             }
             break;
+          case 'agent_metadata':
+            if (gatewayId) {
+              await this.handleAgentMetadata(gatewayId, message.payload);
+            }
+            break;
           case 'status_update':
             await this.handleStatusUpdate(message.payload);
             break;
           case 'command_response':
             await this.handleCommandResponse(message.payload);
             break;
+          case 'command_responses':
+            // Responses to one fan-out, batched by the gateway
+            for (const response of message.payload.responses) {
+              await this.handleCommandResponse(response);
+            }
+            break;
+          case 'job_update':
+            await this.handleJobUpdate(message.payload);
+            break;
+          case 'command_timeout':
+            await this.handleCommandTimeout(message.payload);
+            break;
+          case 'command_summary':
+            this.handleCommandSummary(message.payload);
+            break;
+          case 'protocol_error':
+            logger.warn({ gatewayId, error: message.payload }, 'Gateway refused a message');
+            break;
+          case 'diagnostics':
+            await this.handleDiagnostics(message.payload.chunk);
+            break;
+          case 'snapshot_request':
+            this.emit('snapshot:request', {
+              agentId: message.payload.agent_id,
+              version: message.payload.version,
+            });
+            break;
           case 'pong':
             if (gatewayId) {
               this.handlePong(gatewayId);
             }
             break;
         }
+
+        // Acknowledge only once handled, so a failure leads to a retransmit
+        if (typeof message.seq === 'number') {
+          this.sendAck(ws, message.seq);
+        }
       } catch (error) {
         logger.error({ error }, 'Failed to handle gateway message');
       }
@@ -114,10 +216,7 @@ class GatewayManager extends EventEmitter {
     });
   }
 
-  private async handleRegister(
-    ws: WebSocket,
-    payload: { gateway_id: string; zone: string; version: string; agents: AgentInfo[] }
-  ): Promise<string> {
+  private async handleRegister(ws: WebSocket, payload: GatewayRegistration): Promise<string> {
     const gatewayId = payload.gateway_id;
 
     logger.info(
@@ -125,11 +224,14 @@ class GatewayManager extends EventEmitter {
       'Gateway registered'
     );
 
+    const capabilities = (payload.capabilities ?? []).filter((c) => CAPABILITIES.includes(c));
+
     // Store connection
     const gateway: ConnectedGateway = {
       id: gatewayId,
       zone: payload.zone,
       ws,
+      compress: capabilities.includes('deflate'),
       agents: new Map(),
       connectedAt: new Date(),
       lastHeartbeat: new Date(),
@@ -142,6 +244,22 @@ class GatewayManager extends EventEmitter {
 
     this.gateways.set(gatewayId, gateway);
 
+    // Old gateways do not expect an answer
+    if (payload.protocol_version !== undefined) {
+      const accepted: BackendToGatewayMessage = {
+        type: 'registered',
+        payload: {
+          protocol_version: Math.min(payload.protocol_version, PROTOCOL_VERSION),
+          capabilities,
+        },
+      };
+      ws.send(JSON.stringify(accepted));
+
+      if (this.agentVersionPolicy) {
+        this.sendToGateway(gatewayId, { type: 'agent_version_policy', payload: this.agentVersionPolicy });
+      }
+    }
+
     // Update database
     await gatewaysRepository.upsert({
       id: gatewayId,
@@ -191,6 +309,43 @@ class GatewayManager extends EventEmitter {
     this.emit('agent:connected', { agentId: agent.id, gatewayId, hostname: agent.hostname });
   }
 
+  private async handleAgentMetadata(gatewayId: string, agent: AgentInfo): Promise<void> {
+    const gateway = this.gateways.get(gatewayId);
+    if (!gateway) return;
+
+    const wasQuarantined = gateway.agents.get(agent.id)?.quarantined ?? false;
+    gateway.agents.set(agent.id, agent);
+
+    logger.info(
+      { gatewayId, agentId: agent.id, hostname: agent.hostname, ipAddresses: agent.ip_addresses },
+      'Agent metadata updated'
+    );
+    if ((agent.quarantined ?? false) !== wasQuarantined) {
+      logger.warn(
+        { gatewayId, agentId: agent.id, hostname: agent.hostname, quarantined: agent.quarantined },
+        agent.quarantined ? 'Agent quarantined at gateway' : 'Agent released from quarantine'
+      );
+    }
+
+    await agentsRepository.upsert({
+      id: agent.id,
+      gatewayId,
+      hostname: agent.hostname,
+      labels: agent.labels,
+      version: agent.version,
+      os: agent.os,
+    });
+
+    this.emit('agent:metadata', {
+      agentId: agent.id,
+      gatewayId,
+      hostname: agent.hostname,
+      labels: agent.labels,
+      ipAddresses: agent.ip_addresses ?? [],
+      quarantined: agent.quarantined ?? false,
+    });
+  }
+
   private async handleAgentDisconnected(gatewayId: string, agentId: string): Promise<void> {
     const gateway = this.gateways.get(gatewayId);
     if (!gateway) return;
@@ -249,7 +404,13 @@ class GatewayManager extends EventEmitter {
 
   private async handleCommandResponse(response: CommandResponse): Promise<void> {
     logger.info(
-      { jobId: response.job_id, status: response.status },
+      {
+        jobId: response.job_id,
+        correlationId: response.correlation_id,
+        status: response.status,
+        queuePosition: response.queue_position,
+        scheduledFor: response.scheduled_for,
+      },
       'Command response received'
     );
 
@@ -272,6 +433,16 @@ class GatewayManager extends EventEmitter {
             stderr: response.result.stderr,
             durationMs: response.result.duration_ms,
             timedOut: response.result.timed_out,
+            truncated: response.result.truncated,
+            steps: response.result.steps?.map((step) => ({
+              name: step.name,
+              status: step.status,
+              exitCode: step.exit_code,
+              stdout: step.stdout,
+              stderr: step.stderr,
+              durationMs: step.duration_ms,
+              rollback: step.rollback,
+            })),
           });
         }
         break;
@@ -286,6 +457,130 @@ class GatewayManager extends EventEmitter {
     this.emit('job:update', { jobId: response.job_id, status: response.status, response });
   }
 
+  private async handleCommandTimeout(timeout: CommandTimeout): Promise<void> {
+    logger.warn(
+      { jobId: timeout.job_id, agentId: timeout.agent_id, deliveries: timeout.deliveries },
+      'Command timed out at gateway'
+    );
+
+    const job = await jobsRepository.findById(timeout.job_id);
+    if (!job || ['completed', 'failed', 'timeout'].includes(job.status)) {
+      return;
+    }
+
+    await jobsRepository.markTimeout(timeout.job_id);
+    this.emit('job:update', { jobId: timeout.job_id, status: 'timeout', response: timeout });
+  }
+
+  private handleCommandSummary(summary: CommandSummary): void {
+    logger.info(
+      {
+        jobId: summary.job_id,
+        targeted: summary.targeted,
+        succeeded: summary.succeeded,
+        failed: summary.failed,
+        skipped: summary.skipped,
+        aborted: summary.aborted,
+      },
+      'Fanned out command finished'
+    );
+    this.emit('job:summary', { jobId: summary.job_id, summary });
+  }
+
+  // Join a support bundle's chunks; once all came, store it
+  private async handleDiagnostics(chunk: DiagnosticsChunk): Promise<void> {
+    const now = Date.now();
+    for (const [jobId, pending] of this.bundles) {
+      if (now - pending.updatedAt > BUNDLE_TIMEOUT_MS) {
+        logger.warn({ jobId, agentId: pending.agentId }, 'Support bundle incomplete, dropped');
+        this.bundles.delete(jobId);
+      }
+    }
+
+    if (!Number.isInteger(chunk.index) || chunk.index < 0 || chunk.index >= chunk.total) {
+      logger.warn({ jobId: chunk.job_id, index: chunk.index, total: chunk.total }, 'Invalid support bundle chunk');
+      return;
+    }
+    let pending = this.bundles.get(chunk.job_id);
+    if (!pending) {
+      pending = { agentId: chunk.agent_id, chunks: new Array(chunk.total), received: 0, bytes: 0, updatedAt: now };
+      this.bundles.set(chunk.job_id, pending);
+    }
+    if (pending.chunks[chunk.index] === undefined) {
+      pending.received++;
+      pending.bytes += chunk.data.length;
+    }
+    pending.chunks[chunk.index] = chunk.data;
+    pending.updatedAt = now;
+
+    if (pending.bytes > MAX_BUNDLE_BYTES) {
+      logger.warn({ jobId: chunk.job_id, agentId: pending.agentId }, 'Support bundle too large, dropped');
+      this.bundles.delete(chunk.job_id);
+      return;
+    }
+    if (pending.received < pending.chunks.length) {
+      return;
+    }
+
+    this.bundles.delete(chunk.job_id);
+    const text = pending.chunks.join('');
+    let bundle: Record<string, unknown>;
+    try {
+      bundle = JSON.parse(text);
+    } catch (error) {
+      logger.error({ error, jobId: chunk.job_id, agentId: pending.agentId }, 'Support bundle is not valid JSON');
+      return;
+    }
+    await diagnosticBundlesRepository.create({
+      jobId: chunk.job_id,
+      agentId: pending.agentId,
+      bundle,
+      bytes: text.length,
+    });
+    logger.info({ jobId: chunk.job_id, agentId: pending.agentId, bytes: text.length }, 'Support bundle received');
+    this.emit('diagnostics:bundle', { jobId: chunk.job_id, agentId: pending.agentId });
+  }
+
+  private async handleJobUpdate(update: JobUpdate): Promise<void> {
+    logger.info(
+      {
+        jobId: update.job_id,
+        agentId: update.agent_id,
+        status: update.status,
+        exitCode: update.exit_code,
+        signal: update.signal,
+      },
+      'Detached job ended'
+    );
+
+    const job = await jobsRepository.findById(update.job_id);
+    if (!job) {
+      logger.warn({ jobId: update.job_id }, 'Job not found for job update');
+      return;
+    }
+
+    // The job was answered when it detached: its exit is the real outcome
+    const exitCode = update.status === 'exited' ? (update.exit_code ?? -1) : -1;
+    const ended = update.signal !== undefined
+      ? `Detached job killed by signal ${update.signal}`
+      : `Detached job ${update.status} with exit code ${update.exit_code ?? 'unknown'}`;
+    await jobsRepository.markExited(
+      update.job_id,
+      {
+        exitCode,
+        stdout: job.result?.stdout ?? '',
+        stderr: exitCode === 0
+          ? job.result?.stderr ?? ''
+          : [job.result?.stderr, ended].filter(Boolean).join('\n'),
+        durationMs: job.startedAt ? Date.parse(update.finished_at) - job.startedAt.getTime() : 0,
+        timedOut: false,
+      },
+      new Date(update.finished_at)
+    );
+
+    this.emit('job:update', { jobId: update.job_id, status: update.status, response: update });
+  }
+
   private handlePong(gatewayId: string): void {
     const gateway = this.gateways.get(gatewayId);
     if (gateway) {
@@ -293,6 +588,13 @@ class GatewayManager extends EventEmitter {
     }
   }
 
+  private sendAck(ws: WebSocket, seq: number): void {
+    if (ws.readyState !== WebSocket.OPEN) return;
+
+    const ack: BackendToGatewayMessage = { type: 'ack', payload: { seq } };
+    ws.send(JSON.stringify(ack));
+  }
+
   private async handleDisconnect(gatewayId: string): Promise<void> {
     const gateway = this.gateways.get(gatewayId);
     if (!gateway) return;
@@ -350,7 +652,7 @@ class GatewayManager extends EventEmitter {
     }
 
     try {
-      gateway.ws.send(JSON.stringify(message));
+      gateway.ws.send(encodeFrame(message, gateway.compress));
       return true;
     } catch (error) {
       logger.error({ error, gatewayId }, 'Failed to send message to gateway');
@@ -361,8 +663,9 @@ class GatewayManager extends EventEmitter {
   async sendCommand(
     jobId: string,
     agentId: string | undefined,
-    labels: Record<string, string> | undefined,
-    command: AgentCommand
+    labels: Record<string, string> | string | undefined,
+    command: AgentCommand,
+    routing: { rollout?: Rollout; strategy?: AgentSelectionStrategy } = {}
   ): Promise<{ sent: boolean; gatewayId?: string; error?: string }> {
     // Find the gateway for this agent
     let targetGateway: ConnectedGateway | undefined;
@@ -390,11 +693,15 @@ class GatewayManager extends EventEmitter {
         job_id: jobId,
         labels,
         command,
+        rollout: routing.rollout,
+        strategy: routing.strategy,
       };
 
+      // Each gateway gets it for its own zone
       let sent = false;
       for (const gateway of this.gateways.values()) {
-        if (this.sendToGateway(gateway.id, { type: 'command', payload: commandPayload })) {
+        const payload = { ...commandPayload, zone: gateway.zone };
+        if (this.sendToGateway(gateway.id, { type: 'command', payload })) {
           sent = true;
         }
       }
@@ -410,12 +717,22 @@ class GatewayManager extends EventEmitter {
       job_id: jobId,
       agent_id: agentId,
       command,
+      // A gateway of another zone refuses it rather than reach its agents
+      zone: targetGateway.zone,
     };
 
     const sent = this.sendToGateway(targetGateway.id, { type: 'command', payload: commandPayload });
     return { sent, gatewayId: targetGateway.id };
   }
 
+  // Push a minimum agent version to every gateway, now and on registration; null lifts it
+  setAgentVersionPolicy(policy: AgentVersionPolicy | null): void {
+    this.agentVersionPolicy = policy;
+    for (const gatewayId of this.gateways.keys()) {
+      this.sendToGateway(gatewayId, { type: 'agent_version_policy', payload: policy ?? {} });
+    }
+  }
+
   getConnectedGateways(): Array<{ id: string; zone: string; agentCount: number }> {
     return Array.from(this.gateways.values()).map((gw) => ({
       id: gw.id,
@@ -424,14 +741,20 @@ class GatewayManager extends EventEmitter {
     }));
   }
 
-  getConnectedAgents(): Array<{ id: string; hostname: string; gatewayId: string }> {
-    const agents: Array<{ id: string; hostname: string; gatewayId: string }> = [];
+  getConnectedAgents(): Array<{ id: string; hostname: string; gatewayId: string; version: string; labels?: Record<string, string>; outdated?: boolean; quarantined?: boolean; capabilities?: string[]; tenant_id?: string }> {
+    const agents: Array<{ id: string; hostname: string; gatewayId: string; version: string; labels?: Record<string, string>; outdated?: boolean; quarantined?: boolean; capabilities?: string[]; tenant_id?: string }> = [];
     for (const gateway of this.gateways.values()) {
       for (const agent of gateway.agents.values()) {
         agents.push({
           id: agent.id,
           hostname: agent.hostname,
           gatewayId: gateway.id,
+          version: agent.version,
+          labels: agent.labels,
+          outdated: agent.outdated,
+          quarantined: agent.quarantined,
+          capabilities: agent.capabilities,
+          tenant_id: agent.tenant_id,
         });
       }
     }
@@ -450,6 +773,10 @@ class GatewayManager extends EventEmitter {
   findAgentByLabels(labels: Record<string, string>): AgentInfo | undefined {
     for (const gateway of this.gateways.values()) {
       for (const agent of gateway.agents.values()) {
+        // The gateway would refuse its commands
+        if (agent.quarantined) {
+          continue;
+        }
         let matches = true;
         for (const [key, value] of Object.entries(labels)) {
           if (agent.labels[key] !== value) {
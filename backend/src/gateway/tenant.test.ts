@@ -0,0 +1,56 @@
+import { describe, it, expect, vi, beforeEach } from 'vitest';
+
+vi.mock('../db/repositories/index.js', () => ({
+  mapsRepository: {
+    findTenant: vi.fn(),
+  },
+  organizationsRepository: {
+    findUserOrganizations: vi.fn(),
+  },
+}));
+
+vi.mock('./manager.js', () => ({
+  gatewayManager: {
+    getConnectedAgents: vi.fn().mockReturnValue([]),
+  },
+}));
+
+import { commandTenant } from './tenant.js';
+import { mapsRepository, organizationsRepository } from '../db/repositories/index.js';
+import { gatewayManager } from './manager.js';
+
+describe('commandTenant', () => {
+  beforeEach(() => {
+    vi.clearAllMocks();
+    vi.mocked(gatewayManager.getConnectedAgents).mockReturnValue([
+      { id: 'agent-acme', hostname: 'h1', gatewayId: 'gw-1', version: '1.0', tenant_id: 'acme' },
+      { id: 'agent-shared', hostname: 'h2', gatewayId: 'gw-1', version: '1.0' },
+    ]);
+  });
+
+  it('should give commands to agents of no tenant no tenant', async () => {
+    expect(await commandTenant('agent-shared', { mapId: 'map-1' })).toBeUndefined();
+    expect(await commandTenant(undefined, { mapId: 'map-1' })).toBeUndefined();
+    expect(mapsRepository.findTenant).not.toHaveBeenCalled();
+  });
+
+  it('should take the tenant from the organization owning the map', async () => {
+    vi.mocked(mapsRepository.findTenant).mockResolvedValue('globex');
+
+    // Not the agent's: the gateway refuses it
+    expect(await commandTenant('agent-acme', { mapId: 'map-1' })).toBe('globex');
+    expect(mapsRepository.findTenant).toHaveBeenCalledWith('map-1');
+  });
+
+  it('should take the tenant from the operator\'s organizations', async () => {
+    vi.mocked(organizationsRepository.findUserOrganizations).mockResolvedValue([
+      { id: 'org-1', name: 'Acme', slug: 'acme' } as any,
+    ]);
+    expect(await commandTenant('agent-acme', { userId: 'user-1' })).toBe('acme');
+
+    vi.mocked(organizationsRepository.findUserOrganizations).mockResolvedValue([
+      { id: 'org-2', name: 'Globex', slug: 'globex' } as any,
+    ]);
+    expect(await commandTenant('agent-acme', { userId: 'user-2' })).toBeUndefined();
+  });
+});
@@ -0,0 +1,30 @@
+import { mapsRepository, organizationsRepository } from '../db/repositories/index.js';
+import { gatewayManager } from './manager.js';
+
+/**
+ * The tenant a command to an agent runs for. The gateway only lets an agent
+ * run commands of the tenant its certificate names, so this comes from the
+ * backend's own records, never from the agent: the organization owning the
+ * map the command is for, or else the operator's organization of the
+ * agent's tenant. Agents of no tenant get commands of no tenant.
+ */
+export async function commandTenant(
+  agentId: string | undefined,
+  source: { mapId?: string; userId?: string }
+): Promise<string | undefined> {
+  const agentTenant = agentId
+    ? gatewayManager.getConnectedAgents().find((a) => a.id === agentId)?.tenant_id
+    : undefined;
+  if (!agentTenant) {
+    return undefined;
+  }
+
+  if (source.mapId) {
+    return (await mapsRepository.findTenant(source.mapId)) ?? undefined;
+  }
+  if (source.userId) {
+    const organizations = await organizationsRepository.findUserOrganizations(source.userId);
+    return organizations.find((o) => o.slug === agentTenant)?.slug;
+  }
+  return undefined;
+}
@@ -5,26 +5,118 @@ export interface GatewayRegistration {
   zone: string;
   version: string;
   agents: AgentInfo[];
+  // Absent from gateways that predate negotiation (protocol 1)
+  protocol_version?: number;
+  capabilities?: string[];
+}
+
+// Protocol version and optional features both sides support
+export interface ProtocolAccepted {
+  protocol_version: number;
+  capabilities: string[];
 }
 
 export interface AgentInfo {
   id: string;
   hostname: string;
   labels: Record<string, string>;
+  // Addresses of the agent's host, without loopback and link-local
+  ip_addresses?: string[];
   version: string;
   os: string;
+  capabilities?: string[];
+  // Older than the gateway's minimum agent version
+  outdated?: boolean;
+  // Quarantined at the gateway: connected, but commands to it are refused
+  quarantined?: boolean;
+  // Customer the agent belongs to, as its certificate names it
+  tenant_id?: string;
   connected_at: string;
   last_heartbeat: string;
+  // From the heartbeat's age and failed sends; an agent_metadata follows each change
+  health?: 'healthy' | 'degraded' | 'stale';
 }
 
 // Messages from Gateway to Backend
-export type GatewayToBackendMessage =
+// Messages carrying a seq are retransmitted by the gateway until acked
+export type GatewayToBackendMessage = (
   | { type: 'register'; payload: GatewayRegistration }
   | { type: 'agent_connected'; payload: AgentInfo }
   | { type: 'agent_disconnected'; payload: { agent_id: string } }
+  // A connected agent's hostname, labels or addresses changed
+  | { type: 'agent_metadata'; payload: AgentInfo }
   | { type: 'status_update'; payload: StatusUpdate }
   | { type: 'command_response'; payload: CommandResponse }
-  | { type: 'pong' };
+  // With 'response_batches': responses to one fan-out (checks on many agents)
+  | { type: 'command_responses'; payload: { job_id: string; responses: CommandResponse[] } }
+  | { type: 'job_update'; payload: JobUpdate }
+  | { type: 'snapshot_request'; payload: SnapshotRequest }
+  // With 'diagnostics': part of an agent's support bundle
+  | { type: 'diagnostics'; payload: { agent_id: string; chunk: DiagnosticsChunk } }
+  | { type: 'command_timeout'; payload: CommandTimeout }
+  | { type: 'command_summary'; payload: CommandSummary }
+  | { type: 'protocol_error'; payload: ProtocolError }
+  | { type: 'pong' }
+) & { seq?: number };
+
+// Joined in index order, the chunks of one job make the bundle's JSON text
+export interface DiagnosticsChunk {
+  // Id of the diagnostics command
+  job_id: string;
+  agent_id: string;
+  index: number;
+  total: number;
+  data: string;
+}
+
+// The gateway gave up on a command: no final response from the agent within
+// its timeout plus a grace period, even after redelivery on reconnect
+export interface CommandTimeout {
+  job_id: string;
+  agent_id: string;
+  command_type: string;
+  deliveries: number;
+  timestamp: string;
+  correlation_id?: string;
+}
+
+// Every agent a label-routed command reached on one gateway settled
+export interface CommandSummary {
+  job_id: string;
+  targeted: number;
+  succeeded: number;
+  failed: number;
+  skipped: number;
+  /** A canary failed, so the agents after it were skipped */
+  aborted: boolean;
+  results: Array<{ agent_id: string; status: string; error?: string }>;
+  started_at: string;
+  finished_at: string;
+  correlation_id?: string;
+}
+
+// The gateway refused a message from the backend, and why
+export interface ProtocolError {
+  kind:
+    | 'oversized'
+    | 'invalid_encoding'
+    | 'invalid_json'
+    | 'missing_type'
+    | 'unknown_type'
+    | 'unknown_field'
+    | 'invalid_payload';
+  message: string;
+  message_type?: string;
+  field?: string;
+  size?: number;
+  limit?: number;
+}
+
+// An agent that cannot apply a snapshot delta asks for a full snapshot
+export interface SnapshotRequest {
+  agent_id: string;
+  version?: number;
+}
 
 export interface StatusUpdate {
   agent_id: string;
@@ -36,49 +128,151 @@ export interface StatusUpdate {
   timestamp: string;
 }
 
+export interface PipelineStepResult {
+  name: string;
+  status: 'completed' | 'failed' | 'timeout' | 'skipped';
+  exit_code?: number | null;
+  stdout: string;
+  stderr: string;
+  duration_ms: number;
+  truncated?: boolean;
+  rollback?: boolean;
+}
+
+// A detached job ended after its command was answered
+export interface JobUpdate {
+  job_id: string;
+  agent_id: string;
+  detached_job_id: string;
+  status: 'exited' | 'failed' | 'killed';
+  exit_code?: number;
+  signal?: number;
+  finished_at: string;
+}
+
 export interface CommandResponse {
   job_id: string;
   agent_id: string;
-  status: 'started' | 'completed' | 'failed' | 'timeout';
+  status: 'started' | 'scheduled' | 'completed' | 'failed' | 'timeout';
   result?: {
     exit_code: number;
     stdout: string;
     stderr: string;
     duration_ms: number;
     timed_out: boolean;
+    truncated?: boolean;
+    steps?: PipelineStepResult[];
+    /** Result of the check a run_check command ran */
+    check?: {
+      status: 'ok' | 'warning' | 'error';
+      message?: string;
+      metrics: unknown;
+    };
   };
   error?: string;
   timestamp: string;
+  /** Position in the agent's job queue, while a started command waits to run */
+  queue_position?: number;
+  /** When a scheduled command will run on the agent */
+  scheduled_for?: string;
+  /** The command's correlation_id */
+  correlation_id?: string;
 }
 
 // Messages from Backend to Gateway
 export type BackendToGatewayMessage =
   | { type: 'command'; payload: CommandPayload }
   | { type: 'snapshot'; payload: SnapshotPayload }
+  | { type: 'snapshot_delta'; payload: SnapshotDeltaPayload }
+  | { type: 'ack'; payload: { seq: number } }
+  | { type: 'registered'; payload: ProtocolAccepted }
+  | { type: 'agent_version_policy'; payload: AgentVersionPolicy }
   | { type: 'ping' };
 
+// Minimum agent version enforced by gateways; older agents are flagged,
+// or rejected with reject set
+export interface AgentVersionPolicy {
+  min_version?: string;
+  reject?: boolean;
+}
+
 export interface CommandPayload {
   job_id: string;
   agent_id?: string;
-  labels?: Record<string, string>;
+  /** Labels all equal, or a selector expression: `role=db,env!=prod,tier in (web,api),!maint` */
+  labels?: Record<string, string> | string;
   command: AgentCommand;
+  /** How a label-routed command spreads over the matching agents */
+  rollout?: Rollout;
+  /** Run a label-routed command on one matching agent, picked this way */
+  strategy?: AgentSelectionStrategy;
+  /** Zone the command is meant for; a gateway of another zone rejects it */
+  zone?: string;
+  /** Named agent group on the gateway; its members get the command as with labels */
+  group?: string;
+}
+
+// first: lowest agent id; sticky: the same agent for a component while it matches
+export type AgentSelectionStrategy = 'first' | 'round_robin' | 'least_loaded' | 'sticky';
+
+export interface Rollout {
+  /** Agents that run the command first; the others are skipped if one fails */
+  canary?: number;
+  /** Agents running the command at a time */
+  max_parallel?: number;
 }
 
 export interface AgentCommand {
   id: string;
-  command_type: 'sync' | 'async';
+  /** pause_checks / resume_checks / run_check act on the checks of `component_id` */
+  command_type: 'sync' | 'async' | 'pause_checks' | 'resume_checks' | 'run_check';
   name: string;
+  /** Component the command is about, as the agent's snapshot names it */
+  component_id?: string;
+  /** Parameters of built-in agent commands such as pause_checks */
+  params?: Record<string, unknown>;
+  /** Values wrapped as SecretParam reach the command as is, and are masked in agent logs and results */
   args: Record<string, unknown>;
   timeout_secs: number;
+  /** Run on the agent at this time, even if the gateway is unreachable then */
+  run_at?: string;
+  run_after_secs?: number;
+  /** Only agents of this tenant run it; set for tenants' agents, which refuse anything else (see commandTenant) */
+  tenant_id?: string;
+  /** Echoed on every message about the command; the gateway sets one if absent */
+  correlation_id?: string;
+}
+
+export interface SecretParam {
+  value: string;
+  secret: true;
 }
 
 export interface SnapshotPayload {
   agent_id: string;
+  tenant_id?: string;
   snapshot: {
+    version?: number;
     components: SnapshotComponent[];
   };
 }
 
+// Changes to the snapshot the agent holds at base_version
+export interface SnapshotDeltaPayload {
+  agent_id: string;
+  tenant_id?: string;
+  delta: {
+    base_version: number;
+    version: number;
+    operations: SnapshotOperation[];
+  };
+}
+
+export type SnapshotOperation =
+  | { op: 'add'; component: SnapshotComponent }
+  | { op: 'update'; component: SnapshotComponent }
+  | { op: 'remove'; component_id: string };
+
 export interface SnapshotComponent {
   id: string;
   external_id: string;
@@ -99,6 +293,24 @@ export interface SnapshotAction {
   command: string;
   args: string[];
   run_as_user?: string;
+  env?: Record<string, string>;
+  cwd?: string;
+  verify?: SnapshotActionVerify;
+  priority?: SnapshotActionPriority;
   async: boolean;
   timeout_secs: number;
 }
+
+export interface SnapshotActionVerify {
+  check: string;
+  timeout_secs?: number;
+  interval_secs?: number;
+  delay_secs?: number;
+}
+
+export interface SnapshotActionPriority {
+  nice?: number;
+  io_class?: 'realtime' | 'best-effort' | 'idle';
+  io_level?: number;
+  oom_score_adj?: number;
+}
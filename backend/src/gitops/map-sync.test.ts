@@ -89,6 +89,8 @@ describe('mapSyncService', () => {
                 command: 'systemctl start api',
                 args: ['--force'],
                 run_as_user: 'root',
+                env: { LOG_LEVEL: 'debug', API_TOKEN: '${secret:api-token}' },
+                cwd: '/opt/api',
                 async: true,
                 confirmation_required: true,
               },
@@ -163,6 +165,8 @@ describe('mapSyncService', () => {
                 command: 'systemctl restart nginx',
                 args: [],
                 runAsUser: 'root',
+                env: { NGINX_CONF: '/etc/nginx/nginx.conf' },
+                cwd: '/etc/nginx',
                 async: true,
                 confirmationRequired: false,
               },
@@ -197,6 +201,8 @@ describe('mapSyncService', () => {
       expect(comp.actions).toHaveLength(1);
       expect(comp.actions![0].name).toBe('restart');
       expect(comp.actions![0].run_as_user).toBe('root');
+      expect(comp.actions![0].env).toEqual({ NGINX_CONF: '/etc/nginx/nginx.conf' });
+      expect(comp.actions![0].cwd).toBe('/etc/nginx');
       expect(comp.actions![0].async).toBe(true);
       expect(comp.metadata).toEqual({ tier: 'frontend' });
     });
@@ -499,6 +505,8 @@ describe('mapSyncService', () => {
                 command: 'systemctl start api',
                 args: ['--no-block'],
                 run_as_user: 'app',
+                env: { API_KEY: '${secret:api-key}' },
+                cwd: '/srv/api',
                 async: true,
                 confirmation_required: true,
               },
@@ -528,6 +536,8 @@ describe('mapSyncService', () => {
                 command: 'systemctl start api',
                 args: ['--no-block'],
                 runAsUser: 'app',
+                env: { API_KEY: '${secret:api-key}' },
+                cwd: '/srv/api',
                 async: true,
                 confirmationRequired: true,
               },
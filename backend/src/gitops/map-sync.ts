@@ -23,8 +23,13 @@ export interface MapComponentYAML {
   agent_selector?: {
     agent_id?: string;
     labels?: Record<string, string>;
+    strategy?: 'first' | 'round_robin' | 'least_loaded' | 'sticky';
   };
   dependencies?: string[];
+  container?: {
+    name: string;
+    runtime?: 'docker' | 'podman';
+  };
   checks?: Array<{
     name: string;
     type: string;
@@ -38,6 +43,20 @@ export interface MapComponentYAML {
     command: string;
     args?: string[];
     run_as_user?: string;
+    env?: Record<string, string>;
+    cwd?: string;
+    verify?: {
+      check: string;
+      timeout_secs?: number;
+      interval_secs?: number;
+      delay_secs?: number;
+    };
+    priority?: {
+      nice?: number;
+      io_class?: 'realtime' | 'best-effort' | 'idle';
+      io_level?: number;
+      oom_score_adj?: number;
+    };
     async: boolean;
     confirmation_required?: boolean;
   }>;
@@ -87,9 +106,11 @@ export const mapSyncService = {
             ? {
                 agent_id: config.agentSelector.agentId,
                 labels: config.agentSelector.labels,
+                strategy: config.agentSelector.strategy,
               }
             : undefined,
           dependencies: config.dependencies,
+          container: config.container,
           checks: config.checks?.map((check) => ({
             name: check.name,
             type: check.type,
@@ -103,6 +124,20 @@ export const mapSyncService = {
             command: action.command,
             args: action.args,
             run_as_user: action.runAsUser,
+            env: action.env,
+            cwd: action.cwd,
+            verify: action.verify && {
+              check: action.verify.check,
+              timeout_secs: action.verify.timeoutSecs,
+              interval_secs: action.verify.intervalSecs,
+              delay_secs: action.verify.delaySecs,
+            },
+            priority: action.priority && {
+              nice: action.priority.nice,
+              io_class: action.priority.ioClass,
+              io_level: action.priority.ioLevel,
+              oom_score_adj: action.priority.oomScoreAdj,
+            },
             async: action.async,
             confirmation_required: action.confirmationRequired,
           })),
@@ -132,9 +167,14 @@ export const mapSyncService = {
 
       const config: ComponentConfig = {
         agentSelector: comp.agent_selector
-          ? { agentId: comp.agent_selector.agent_id, labels: comp.agent_selector.labels }
+          ? {
+              agentId: comp.agent_selector.agent_id,
+              labels: comp.agent_selector.labels,
+              strategy: comp.agent_selector.strategy,
+            }
           : undefined,
         dependencies: comp.dependencies,
+        container: comp.container,
         checks: comp.checks?.map((c) => ({
           name: c.name,
           type: c.type as 'http' | 'tcp' | 'command' | 'process' | 'service',
@@ -148,6 +188,20 @@ export const mapSyncService = {
           command: a.command,
           args: a.args,
           runAsUser: a.run_as_user,
+          env: a.env,
+          cwd: a.cwd,
+          verify: a.verify && {
+            check: a.verify.check,
+            timeoutSecs: a.verify.timeout_secs,
+            intervalSecs: a.verify.interval_secs,
+            delaySecs: a.verify.delay_secs,
+          },
+          priority: a.priority && {
+            nice: a.priority.nice,
+            ioClass: a.priority.io_class,
+            ioLevel: a.priority.io_level,
+            oomScoreAdj: a.priority.oom_score_adj,
+          },
           async: a.async,
           confirmationRequired: a.confirmation_required,
         })),
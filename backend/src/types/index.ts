@@ -81,17 +81,27 @@ export interface ComponentConfig {
   checks?: Check[];
   actions?: Action[];
   dependencies?: string[];
+  /** Container the component's actions run in, on the selected agent */
+  container?: ComponentContainer;
   metadata?: Record<string, unknown>;
 }
 
+export interface ComponentContainer {
+  name: string;
+  runtime?: 'docker' | 'podman';
+}
+
 export interface AgentSelector {
   agentId?: string;
   labels?: Record<string, string>;
+  /** How one of several matching agents is picked */
+  strategy?: 'first' | 'round_robin' | 'least_loaded' | 'sticky';
 }
 
 export interface Check {
   name: string;
   type: 'http' | 'tcp' | 'command' | 'process' | 'service';
+  /** String values may reference agent-side secrets as {{secret:NAME}} */
   config: Record<string, unknown>;
   intervalSecs: number;
   timeoutSecs: number;
@@ -103,9 +113,33 @@ export interface Action {
   command: string;
   args?: string[];
   runAsUser?: string;
+  /** Extra environment; values may reference agent-side secrets as ${secret:NAME} */
+  env?: Record<string, string>;
+  cwd?: string;
   async: boolean;
   confirmationRequired?: boolean;
   completionCheck?: Check;
+  /** Check of the component the agent runs once the action succeeded */
+  verify?: ActionVerify;
+  /** Priority of the action's process on the agent host */
+  priority?: ActionPriority;
+}
+
+export interface ActionVerify {
+  check: string;
+  timeoutSecs?: number;
+  intervalSecs?: number;
+  delaySecs?: number;
+}
+
+export interface ActionPriority {
+  /** -20 (highest) to 19 */
+  nice?: number;
+  ioClass?: 'realtime' | 'best-effort' | 'idle';
+  /** 0 (highest) to 7, within the I/O class */
+  ioLevel?: number;
+  /** -1000 to 1000 */
+  oomScoreAdj?: number;
 }
 
 // Permissions
@@ -224,6 +258,18 @@ export interface JobResult {
   stderr: string;
   durationMs: number;
   timedOut: boolean;
+  /** Output exceeded the agent's capture limit and was cut in the middle */
+  truncated?: boolean;
+  /** Per-step results of a pipeline action */
+  steps?: Array<{
+    name: string;
+    status: string;
+    exitCode?: number | null;
+    stdout: string;
+    stderr: string;
+    durationMs: number;
+    rollback?: boolean;
+  }>;
 }
 
 // API Request/Response types
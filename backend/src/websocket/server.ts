@@ -1,10 +1,19 @@
 import { WebSocketServer, WebSocket } from 'ws';
-import { Server } from 'http';
+import { Server, IncomingMessage } from 'http';
 import { verifyToken, type JwtPayload } from '../auth/jwt.js';
+import { config } from '../config/index.js';
 import { createChildLogger } from '../config/logger.js';
 import { permissionsRepository } from '../db/repositories/index.js';
 import { gatewayManager } from '../gateway/manager.js';
+import {
+  BACKEND_TOKEN_HEADER,
+  backendToken,
+  gatewayAuthRequired,
+  verifyGateway,
+  type GatewayIdentity,
+} from '../gateway/auth.js';
 import { StatusUpdate } from '../gateway/types.js';
+import { snapshotService } from '../core/snapshot.service.js';
 
 const logger = createChildLogger('websocket');
 
@@ -52,15 +61,38 @@ export class OpsMapWebSocketServer {
       logger.error({ error }, 'WebSocket server error');
     });
 
-    // Gateway WebSocket server (no auth verification - gateways self-identify)
+    // Gateway WebSocket server: bearer token or JWT in the handshake,
+    // answered with the backend's own token (gateway/src/backend_auth)
+    const gatewayIdentities = new WeakMap<IncomingMessage, GatewayIdentity>();
+    if (!gatewayAuthRequired()) {
+      logger.warn('GATEWAY_TOKEN and GATEWAY_JWT_SECRET are unset: any gateway may connect');
+    }
     const gatewayWss = new WebSocketServer({
       server,
       path: '/gateway',
+      verifyClient: (info: { req: IncomingMessage }, callback) => {
+        const identity = verifyGateway(info.req.headers.authorization);
+        if (!identity) {
+          logger.warn({ address: info.req.socket.remoteAddress }, 'Gateway failed to authenticate');
+          callback(false, 401, 'Invalid gateway token');
+          return;
+        }
+        gatewayIdentities.set(info.req, identity);
+        callback(true);
+      },
+    });
+
+    gatewayWss.on('headers', (headers: string[], req: IncomingMessage) => {
+      const token = backendToken(gatewayIdentities.get(req) ?? {});
+      if (token) {
+        headers.push(`${BACKEND_TOKEN_HEADER}: ${token}`);
+      }
     });
 
-    gatewayWss.on('connection', (ws) => {
-      logger.info('Gateway connection attempt');
-      gatewayManager.handleConnection(ws);
+    gatewayWss.on('connection', (ws, req) => {
+      const identity = gatewayIdentities.get(req) ?? {};
+      logger.info({ gatewayId: identity.gatewayId }, 'Gateway connection attempt');
+      gatewayManager.handleConnection(ws, identity);
     });
 
     gatewayWss.on('error', (error) => {
@@ -85,6 +117,18 @@ export class OpsMapWebSocketServer {
 
     // Start gateway manager
     gatewayManager.start();
+    if (config.agents.minVersion) {
+      gatewayManager.setAgentVersionPolicy({
+        min_version: config.agents.minVersion,
+        reject: config.agents.rejectOutdated,
+      });
+    }
+
+    // Agents that missed a snapshot delta ask for the full snapshot
+    gatewayManager.on('snapshot:request', (data: { agentId: string; version?: number }) => {
+      logger.info(data, 'Agent requested a full snapshot');
+      snapshotService.sendSnapshotToAgent(data.agentId, { full: true }).catch(() => {});
+    });
 
     logger.info('WebSocket servers initialized (frontend + gateway)');
   }
@@ -118,12 +162,22 @@ export class OpsMapWebSocketServer {
       });
     });
 
+    // Forward the outcome of label-routed commands
+    gatewayManager.on('job:summary', (data: { jobId: string; summary: unknown }) => {
+      this.broadcast({
+        type: 'job_summary',
+        payload: data,
+      });
+    });
+
     // Forward agent connection events
     gatewayManager.on('agent:connected', (data: { agentId: string; gatewayId: string; hostname: string }) => {
       this.broadcast({
         type: 'agent_connected',
         payload: data,
       });
+      // Checks spread by strategy may move to the new agent
+      snapshotService.rebalanceChecks().catch(() => {});
     });
 
     gatewayManager.on('agent:disconnected', (data: { agentId: string; gatewayId: string }) => {
@@ -131,6 +185,15 @@ export class OpsMapWebSocketServer {
         type: 'agent_disconnected',
         payload: data,
       });
+      // Its checks spread by strategy go to another agent
+      snapshotService.rebalanceChecks().catch(() => {});
+    });
+
+    gatewayManager.on('agent:metadata', (data: { agentId: string; gatewayId: string; hostname: string }) => {
+      this.broadcast({
+        type: 'agent_metadata',
+        payload: data,
+      });
     });
   }
 
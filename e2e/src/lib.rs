@@ -0,0 +1,305 @@
+//! OpsMap end-to-end tests
+//!
+//! Runs the gateway and agent binaries as they are deployed, on 127.0.0.1
+//! without TLS, against a mock backend held by the test. The binaries are
+//! built beforehand and found through `OPSMAP_GATEWAY_BIN` and
+//! `OPSMAP_AGENT_BIN`, else in the `target/debug` of their crates:
+//!
+//! ```text
+//! ./scripts/integration-tests.sh
+//! # or, with both binaries built:
+//! cd e2e && cargo test -- --include-ignored
+//! ```
+//!
+//! Each harness works in its own temporary directory, which holds the
+//! configuration, state and logs of both processes; the logs are printed
+//! when an expected frame does not come.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Longest wait for an expected frame
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const GATEWAY_ID: &str = "gw-e2e";
+pub const AGENT_ID: &str = "agent-e2e";
+pub const AGENT_HOSTNAME: &str = "e2e-host";
+
+/// Protocol version the mock backend speaks
+const PROTOCOL_VERSION: u32 = 2;
+
+/// The backend end of the gateway link
+///
+/// Answers registrations, acks every sequenced frame, and hands the test
+/// every frame the gateway sends, across gateway restarts.
+pub struct Backend {
+    addr: SocketAddr,
+    frames: mpsc::UnboundedReceiver<Value>,
+    /// The gateway connected last
+    gateway: Arc<Mutex<Option<mpsc::UnboundedSender<Value>>>>,
+}
+
+impl Backend {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind the mock backend");
+        let addr = listener.local_addr().unwrap();
+        let (frames_tx, frames) = mpsc::unbounded_channel();
+        let gateway = Arc::new(Mutex::new(None));
+
+        let current = gateway.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(ws) = tokio_tungstenite::accept_async(stream).await else {
+                    continue;
+                };
+                let (tx, rx) = mpsc::unbounded_channel();
+                *current.lock().unwrap() = Some(tx.clone());
+                tokio::spawn(serve(ws, tx, rx, frames_tx.clone()));
+            }
+        });
+
+        Self {
+            addr,
+            frames,
+            gateway,
+        }
+    }
+
+    /// What the gateway's `backend.url` is set to
+    pub fn url(&self) -> String {
+        format!("ws://{}/gateway", self.addr)
+    }
+
+    /// Send a frame to the gateway connected last
+    pub fn send(&self, kind: &str, payload: Value) {
+        let gateway = self.gateway.lock().unwrap();
+        let tx = gateway
+            .as_ref()
+            .expect("No gateway connected to the backend");
+        tx.send(json!({"type": kind, "payload": payload}))
+            .expect("Gateway link closed");
+    }
+
+    /// The payload of the next frame of a type that `accept`s it, skipping
+    /// the others; None after `TIMEOUT`
+    pub async fn expect(&mut self, kind: &str, accept: impl Fn(&Value) -> bool) -> Option<Value> {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            let frame = tokio::time::timeout_at(deadline, self.frames.recv())
+                .await
+                .ok()??;
+            if frame["type"] == kind && accept(&frame["payload"]) {
+                return Some(frame["payload"].clone());
+            }
+        }
+    }
+}
+
+/// Carry one gateway connection until either side closes it
+async fn serve(
+    ws: WebSocketStream<TcpStream>,
+    tx: mpsc::UnboundedSender<Value>,
+    mut rx: mpsc::UnboundedReceiver<Value>,
+    frames: mpsc::UnboundedSender<Value>,
+) {
+    let (mut sink, mut stream) = ws.split();
+    loop {
+        tokio::select! {
+            Some(frame) = rx.recv() => {
+                if sink.send(Message::Text(frame.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            message = stream.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => return,
+                };
+                let Ok(frame) = serde_json::from_str::<Value>(&text) else {
+                    continue;
+                };
+                if frame["type"] == "register" {
+                    let acks = frame["payload"]["capabilities"]
+                        .as_array()
+                        .is_some_and(|c| c.iter().any(|c| c == "acks"));
+                    let _ = tx.send(json!({
+                        "type": "registered",
+                        "payload": {
+                            "protocol_version": PROTOCOL_VERSION,
+                            "capabilities": if acks { vec!["acks"] } else { vec![] },
+                        },
+                    }));
+                }
+                if let Some(seq) = frame["seq"].as_u64() {
+                    let _ = tx.send(json!({"type": "ack", "payload": {"seq": seq}}));
+                }
+                let _ = frames.send(frame);
+            }
+        }
+    }
+}
+
+/// A mock backend, a gateway connected to it and an agent connected to the
+/// gateway, each process killed when the harness is dropped
+pub struct Harness {
+    pub backend: Backend,
+    dir: TempDir,
+    gateway: Option<Child>,
+    agent: Option<Child>,
+}
+
+impl Harness {
+    /// Start the backend and the gateway; the agent is started apart so the
+    /// test sees the gateway register first
+    pub async fn start() -> Self {
+        let backend = Backend::start().await;
+        let dir = tempfile::Builder::new()
+            .prefix("opsmap-e2e-")
+            .tempdir()
+            .expect("Failed to create the test directory");
+        let gateway_port = free_port().await;
+
+        let gateway = json!({
+            "gateway": {
+                "id": GATEWAY_ID,
+                "zone": "e2e",
+                "listen_addr": "127.0.0.1",
+                "listen_port": gateway_port,
+            },
+            "backend": {"url": backend.url(), "reconnect_interval_secs": 1},
+            "tls": {"enabled": false, "cert_file": null, "key_file": null, "ca_file": null},
+            "audit": {"file_path": dir.path().join("audit.jsonl")},
+            "persistence": {"file_path": dir.path().join("gateway-state.db")},
+            "dead_letters": {"file_path": dir.path().join("gateway-dead-letters.db")},
+            "enrollment": {"tokens_file": dir.path().join("enrollment-tokens.json")},
+        });
+        let agent = json!({
+            "agent": {"id": AGENT_ID, "hostname": AGENT_HOSTNAME},
+            "gateway": {
+                "url": format!("ws://127.0.0.1:{}/ws", gateway_port),
+                "reconnect_interval_secs": 1,
+            },
+            "tls": {"enabled": false},
+            "scheduler": {
+                "batch_send_interval_secs": 1,
+                "overrides_file": dir.path().join("overrides.yaml"),
+            },
+            "buffer": {"file_path": dir.path().join("buffer.json")},
+            "admin": {"enabled": false},
+            "enrollment": {"id_file": dir.path().join("agent_id")},
+            "jobs": {"scheduled_file": dir.path().join("scheduled.json")},
+        });
+        // JSON is YAML, which both binaries read
+        std::fs::write(dir.path().join("gateway.yaml"), gateway.to_string()).unwrap();
+        std::fs::write(dir.path().join("agent.yaml"), agent.to_string()).unwrap();
+
+        let mut harness = Self {
+            backend,
+            dir,
+            gateway: None,
+            agent: None,
+        };
+        harness.start_gateway();
+        harness
+    }
+
+    /// Start the gateway again after `stop_gateway`, on the same port
+    pub fn start_gateway(&mut self) {
+        let bin = binary("OPSMAP_GATEWAY_BIN", "gateway/target/debug/opsmap-gateway");
+        self.gateway = Some(self.spawn(&bin, "gateway", &[]));
+    }
+
+    /// Kill the gateway, dropping its agent and backend links at once
+    pub async fn stop_gateway(&mut self) {
+        if let Some(mut gateway) = self.gateway.take() {
+            let _ = gateway.kill().await;
+        }
+    }
+
+    pub fn start_agent(&mut self) {
+        let bin = binary("OPSMAP_AGENT_BIN", "agent/target/debug/opsmap-agent");
+        self.agent = Some(self.spawn(&bin, "agent", &["--foreground"]));
+    }
+
+    /// The payload of the next frame the backend gets of a type that
+    /// `accept`s it, failing the test with both logs when none comes
+    pub async fn expect(&mut self, kind: &str, accept: impl Fn(&Value) -> bool) -> Value {
+        match self.backend.expect(kind, accept).await {
+            Some(payload) => payload,
+            None => panic!(
+                "The backend got no matching {} within {:?}\n{}",
+                kind,
+                TIMEOUT,
+                self.logs()
+            ),
+        }
+    }
+
+    /// Run a binary on its configuration, appending its output to its log
+    fn spawn(&self, bin: &Path, name: &str, args: &[&str]) -> Child {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.path().join(format!("{}.log", name)))
+            .expect("Failed to open the log");
+        Command::new(bin)
+            .arg("--config")
+            .arg(self.dir.path().join(format!("{}.yaml", name)))
+            .args(args)
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to run {}: {} (build it first, or set its path in the environment)",
+                    bin.display(),
+                    e
+                )
+            })
+    }
+
+    /// The end of both logs
+    fn logs(&self) -> String {
+        ["gateway", "agent"]
+            .iter()
+            .map(|name| {
+                let text = std::fs::read_to_string(self.dir.path().join(format!("{}.log", name)))
+                    .unwrap_or_default();
+                let lines: Vec<&str> = text.lines().collect();
+                let tail = lines[lines.len().saturating_sub(40)..].join("\n");
+                format!("--- {}.log\n{}", name, tail)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The binary named by an environment variable, else the one built in the
+/// repository
+fn binary(var: &str, built: &str) -> PathBuf {
+    match std::env::var_os(var) {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(built),
+    }
+}
+
+/// A port nothing listens on, for the gateway to keep across restarts
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
@@ -0,0 +1,109 @@
+//! A check and a command on their whole path: backend, gateway, agent and
+//! back, across a gateway restart
+
+use chrono::{DateTime, Utc};
+use opsmap_e2e::{Harness, AGENT_HOSTNAME, AGENT_ID, GATEWAY_ID};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// A component whose check connects to `port`
+fn snapshot(port: u16) -> Value {
+    json!({
+        "version": 1,
+        "components": [{
+            "id": "web",
+            "name": "Web",
+            "component_type": "service",
+            "checks": [{
+                "name": "port",
+                "check_type": "tcp_port",
+                "config": {"port": port},
+                "interval_secs": 1,
+                "timeout_secs": 1,
+            }],
+            "actions": [{"name": "hello", "command": "echo", "args": ["hello"]}],
+        }],
+    })
+}
+
+#[tokio::test]
+#[ignore = "runs the gateway and agent binaries, see scripts/integration-tests.sh"]
+async fn test_full_flow() {
+    let mut e2e = Harness::start().await;
+
+    // Registration: the gateway with the backend, the agent with the gateway
+    let register = e2e.expect("register", |_| true).await;
+    assert_eq!(register["gateway_id"], GATEWAY_ID);
+    e2e.start_agent();
+    let agent = e2e.expect("agent_connected", |a| a["id"] == AGENT_ID).await;
+    assert_eq!(agent["hostname"], AGENT_HOSTNAME);
+
+    // Snapshot delivery, then scheduled check deltas
+    let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = service.local_addr().unwrap().port();
+    e2e.backend.send(
+        "snapshot",
+        json!({"agent_id": AGENT_ID, "snapshot": snapshot(port)}),
+    );
+    let update = e2e
+        .expect("status_update", |u| u["check_name"] == "port")
+        .await;
+    assert_eq!(update["component_id"], "web");
+    assert_eq!(update["status"], "ok");
+
+    // Command round-trip
+    let job_id = uuid::Uuid::new_v4().to_string();
+    e2e.backend.send(
+        "command",
+        json!({
+            "agent_id": AGENT_ID,
+            "labels": null,
+            "command": {
+                "id": job_id,
+                "command_type": "check",
+                "component_id": "web",
+                "action_name": "hello",
+                "params": {"command": "echo", "args": ["hello from e2e"]},
+                "timeout_secs": 30,
+            },
+            "requested_by": "e2e",
+        }),
+    );
+    let response = e2e
+        .expect("command_response", |r| {
+            r["job_id"] == job_id.as_str() && r["status"] != "started"
+        })
+        .await;
+    assert_eq!(response["status"], "completed");
+    assert_eq!(
+        response["result"]["stdout"].as_str().map(str::trim),
+        Some("hello from e2e")
+    );
+
+    // Reconnect and buffer replay: the check fails while the gateway is
+    // down, and the agent delivers that result once it is back
+    e2e.stop_gateway().await;
+    drop(service);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let restarted = Utc::now();
+    e2e.start_gateway();
+
+    e2e.expect("register", |_| true).await;
+    e2e.expect("agent_connected", |a| a["id"] == AGENT_ID).await;
+    let update = e2e
+        .expect("status_update", |u| {
+            u["check_name"] == "port" && u["status"] != "ok"
+        })
+        .await;
+    assert_eq!(update["status"], "error");
+    let checked: DateTime<Utc> = update["timestamp"]
+        .as_str()
+        .and_then(|t| t.parse().ok())
+        .expect("status update without a timestamp");
+    assert!(
+        checked < restarted,
+        "the failure was not buffered: {}",
+        update
+    );
+}
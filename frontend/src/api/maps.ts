@@ -96,6 +96,21 @@ export function useComponentAction() {
   });
 }
 
+// Run a component's check on its agent now, outside its interval
+export function useRunCheck() {
+  return useMutation({
+    mutationFn: ({
+      agentId,
+      componentId,
+      check,
+    }: {
+      agentId: string;
+      componentId: string;
+      check: string;
+    }) => api.post(`/api/v1/agents/${agentId}/checks/run`, { componentId, check }),
+  });
+}
+
 // Permissions
 export function useMapPermissions(mapId: string) {
   return useQuery({
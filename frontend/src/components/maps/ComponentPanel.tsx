@@ -1,4 +1,4 @@
-import { Play, Square, RotateCcw, X, Terminal, Activity } from 'lucide-react';
+import { Play, Square, RotateCcw, X, Terminal, Activity, RefreshCw } from 'lucide-react';
 import { Button } from '@/components/ui/button';
 import { cn, getStatusColor, getStatusText } from '@/lib/utils';
 import type { Component } from '@/types';
@@ -6,6 +6,7 @@ import type { Component } from '@/types';
 interface ComponentPanelProps {
   component: Component;
   onAction: (componentId: string, action: 'start' | 'stop' | 'restart') => void;
+  onRunCheck?: (agentId: string, componentId: string, check: string) => void;
   onClose: () => void;
   isLoading: boolean;
 }
@@ -13,12 +14,14 @@ interface ComponentPanelProps {
 export function ComponentPanel({
   component,
   onAction,
+  onRunCheck,
   onClose,
   isLoading,
 }: ComponentPanelProps) {
   const status = component.status || 'unknown';
   const actions = component.config.actions || [];
   const checks = component.config.checks || [];
+  const agentId = component.config.agentSelector?.agentId;
 
   return (
     <div className="space-y-6">
@@ -114,9 +117,23 @@ export function ComponentPanel({
                 className="flex items-center justify-between p-2 rounded bg-muted/50 text-sm"
               >
                 <span>{check.name}</span>
-                <span className="text-xs text-muted-foreground">
-                  {check.type} / {check.intervalSecs}s
-                </span>
+                <div className="flex items-center space-x-2">
+                  <span className="text-xs text-muted-foreground">
+                    {check.type} / {check.intervalSecs}s
+                  </span>
+                  {agentId && onRunCheck && (
+                    <Button
+                      variant="ghost"
+                      size="icon"
+                      className="h-6 w-6"
+                      title="Run now"
+                      onClick={() => onRunCheck(agentId, component.id, check.name)}
+                      disabled={isLoading}
+                    >
+                      <RefreshCw className="h-3 w-3" />
+                    </Button>
+                  )}
+                </div>
               </div>
             ))}
           </div>
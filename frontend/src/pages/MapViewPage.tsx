@@ -6,7 +6,7 @@ import {
   Share2,
   RefreshCw,
 } from 'lucide-react';
-import { useMap, useComponents, useComponentAction } from '@/api/maps';
+import { useMap, useComponents, useComponentAction, useRunCheck } from '@/api/maps';
 import { useWebSocket } from '@/hooks/use-websocket';
 import { Button } from '@/components/ui/button';
 import { Card, CardContent, CardHeader, CardTitle } from '@/components/ui/card';
@@ -67,6 +67,7 @@ export function MapViewPage() {
   const { data: map, isLoading: mapLoading } = useMap(mapId!);
   const { data: components, isLoading: componentsLoading, refetch } = useComponents(mapId!);
   const componentAction = useComponentAction();
+  const runCheck = useRunCheck();
 
   // WebSocket for real-time updates
   const { componentStatuses } = useWebSocket(mapId!);
@@ -115,6 +116,10 @@ export function MapViewPage() {
     await componentAction.mutateAsync({ mapId, componentId, action });
   };
 
+  const handleRunCheck = async (agentId: string, componentId: string, check: string) => {
+    await runCheck.mutateAsync({ agentId, componentId, check });
+  };
+
   if (mapLoading || componentsLoading) {
     return (
       <div className="flex items-center justify-center h-64">
@@ -189,8 +194,9 @@ export function MapViewPage() {
               <ComponentPanel
                 component={selectedComponent}
                 onAction={handleAction}
+                onRunCheck={handleRunCheck}
                 onClose={() => setSelectedComponent(null)}
-                isLoading={componentAction.isPending}
+                isLoading={componentAction.isPending || runCheck.isPending}
               />
             ) : (
               <div className="space-y-2">
@@ -2,7 +2,7 @@
 //!
 //! Handles WebSocket connections from agents.
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use chrono::Utc;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
@@ -11,7 +11,16 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 
+use crate::audit::AuditEvent;
+use crate::backend_client::GatewayToBackendMessage;
+use crate::delivery::Delivery;
+use crate::limits::{AgentLimiter, LimitAction};
+use crate::outbound::{forward, Connection};
+use crate::protocol::{self, Accepted};
 use crate::registry::{AgentCommand, AgentInfo};
+use crate::schema::{self, ProtocolError};
+use crate::tls::PeerCertificate;
+use crate::versions::{self, Verdict};
 use crate::{BackendMessage, GatewayState};
 
 /// Messages from agents
@@ -28,6 +37,61 @@ pub enum AgentMessage {
     CommandResponse(serde_json::Value),
     #[serde(rename = "pong")]
     Pong,
+    #[serde(rename = "discovery")]
+    Discovery(serde_json::Value),
+    #[serde(rename = "inventory")]
+    Inventory(serde_json::Value),
+    /// Part of a support bundle, answering a `diagnostics` command
+    #[serde(rename = "diagnostics")]
+    Diagnostics(serde_json::Value),
+    /// A detached job ended after its command was answered
+    #[serde(rename = "job_update")]
+    JobUpdate(serde_json::Value),
+    /// The agent could not apply a snapshot delta and wants a full snapshot
+    #[serde(rename = "snapshot_request")]
+    SnapshotRequest(SnapshotRequest),
+    /// The agent is shutting down on purpose
+    #[serde(rename = "disconnect")]
+    Disconnect(DisconnectNotice),
+    /// Hostname, labels or addresses changed since registration
+    #[serde(rename = "metadata_update")]
+    MetadataUpdate(AgentMetadata),
+}
+
+/// Wire type names of `AgentMessage`
+pub(crate) const AGENT_MESSAGE_TYPES: &[&str] = &[
+    "register",
+    "status_delta",
+    "status_batch",
+    "command_response",
+    "pong",
+    "discovery",
+    "inventory",
+    "diagnostics",
+    "job_update",
+    "snapshot_request",
+    "disconnect",
+    "metadata_update",
+];
+
+impl AgentMessage {
+    /// Wire type name, used as a metrics label
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            AgentMessage::Register(_) => "register",
+            AgentMessage::StatusDelta(_) => "status_delta",
+            AgentMessage::StatusBatch(_) => "status_batch",
+            AgentMessage::CommandResponse(_) => "command_response",
+            AgentMessage::Pong => "pong",
+            AgentMessage::Discovery(_) => "discovery",
+            AgentMessage::Inventory(_) => "inventory",
+            AgentMessage::Diagnostics(_) => "diagnostics",
+            AgentMessage::JobUpdate(_) => "job_update",
+            AgentMessage::SnapshotRequest(_) => "snapshot_request",
+            AgentMessage::Disconnect(_) => "disconnect",
+            AgentMessage::MetadataUpdate(_) => "metadata_update",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,8 +99,36 @@ pub struct RegisterPayload {
     pub agent_id: String,
     pub hostname: String,
     pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub ip_addresses: Vec<String>,
     pub version: String,
     pub os: String,
+    #[serde(default = "protocol::legacy_version")]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentMetadata {
+    pub hostname: String,
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub ip_addresses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    /// Snapshot version the agent holds, if any
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisconnectNotice {
+    /// Why the agent is leaving, e.g. "shutdown"
+    #[serde(default)]
+    pub reason: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,38 +142,135 @@ pub struct StatusBatch {
 pub enum GatewayToAgentMessage {
     #[serde(rename = "snapshot")]
     Snapshot(serde_json::Value),
+    /// Changes to the snapshot version the agent holds
+    #[serde(rename = "snapshot_delta")]
+    SnapshotDelta(serde_json::Value),
     #[serde(rename = "command")]
-    Command(AgentCommand),
+    Command(Box<AgentCommand>),
     #[serde(rename = "ping")]
     Ping,
     #[serde(rename = "config_update")]
-    ConfigUpdate(serde_json::Value),
+    ConfigUpdate(crate::config_update::ConfigUpdate),
+    /// The agent's messages up to `seq` reached the backend
+    #[serde(rename = "ack")]
+    Ack { seq: u64 },
+    /// Answer to a registration that announced a protocol version
+    #[serde(rename = "registered")]
+    Registered(Accepted),
+    /// A message from the agent was refused, and why
+    #[serde(rename = "protocol_error")]
+    ProtocolError(ProtocolError),
+}
+
+/// A frame from an agent; status messages carry a sequence number to ack
+#[derive(Debug, Deserialize)]
+pub(crate) struct AgentFrame {
+    #[serde(flatten)]
+    message: AgentMessage,
+    #[serde(default)]
+    seq: Option<u64>,
 }
 
-/// Handle an agent WebSocket connection
-pub async fn handle_agent(socket: WebSocket, state: Arc<GatewayState>) {
+/// Handle an agent WebSocket connection; `peer` is its client certificate
+pub async fn handle_agent(socket: WebSocket, state: Arc<GatewayState>, peer: PeerCertificate) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
-    // Wait for registration message
-    let agent_info = match wait_for_registration(&mut ws_receiver).await {
-        Some(info) => info,
+    let _slot = match state.connections.open(state.polls.count()) {
+        Some(slot) => slot,
         None => {
+            warn!("Agent connection refused, gateway at limits.max_connections");
+            let close = CloseFrame {
+                code: close_code::AGAIN,
+                reason: "gateway at capacity".into(),
+            };
+            let _ = ws_sender.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
+    // Wait for registration message
+    let registration =
+        wait_for_registration(&mut ws_receiver, state.config.limits.max_register_bytes).await;
+    let mut agent_info = match registration {
+        Ok(Registration::Agent(info)) => info,
+        Ok(Registration::Gateway(payload)) => {
+            crate::downstream::serve(state.clone(), ws_sender, ws_receiver, payload, &peer).await;
+            return;
+        }
+        Err(Some(close)) => {
+            let _ = ws_sender.send(Message::Close(Some(close))).await;
+            return;
+        }
+        Err(None) => {
             warn!("Agent disconnected before registration");
             return;
         }
     };
 
+    if let Err(reason) = check_identity(&state, &mut agent_info, &peer) {
+        let close = CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return;
+    }
+
+    if let Err(reason) = check_version(&state, &mut agent_info) {
+        let close = CloseFrame {
+            code: versions::UPGRADE_REQUIRED,
+            reason: reason.into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return;
+    }
+
+    backend_capabilities(&state, &mut agent_info);
     let agent_id = agent_info.id.clone();
-    info!(agent_id = %agent_id, hostname = %agent_info.hostname, "Agent connected");
+    info!(
+        agent_id = %agent_id,
+        hostname = %agent_info.hostname,
+        protocol_version = agent_info.protocol_version,
+        capabilities = ?agent_info.capabilities,
+        "Agent connected"
+    );
+
+    // Agents from before negotiation would not understand the answer
+    if agent_info.protocol_version > 1 {
+        let accepted = GatewayToAgentMessage::Registered(Accepted {
+            protocol_version: agent_info.protocol_version,
+            capabilities: agent_info.capabilities.clone(),
+        });
+        if let Ok(json) = serde_json::to_string(&accepted) {
+            if ws_sender.send(Message::Text(json)).await.is_err() {
+                warn!(agent_id = %agent_id, "Agent disconnected during registration");
+                return;
+            }
+        }
+    }
+
+    let framing = Framing::new(&agent_info, state.config.gateway.compress_above);
+    let mut limiter = AgentLimiter::new(&state.config.limits);
 
-    // Create command channel
-    let (cmd_tx, mut cmd_rx) = mpsc::channel::<AgentCommand>(100);
+    // Create the channel for commands and acks; the registry holds the only
+    // sender, so it closes when the agent is disconnected or replaced
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<GatewayToAgentMessage>(100);
+    let registration = cmd_tx.downgrade();
 
-    // Register agent
+    // Register agent, then send it what it missed while away
     state.registry.register(agent_info.clone(), cmd_tx);
+    crate::ha::announce(&state, &agent_id);
+    crate::commands::redeliver(&state, &agent_id);
 
-    // Notify backend
-    let _ = state.backend_tx.send(BackendMessage::AgentConnected(agent_info.clone()));
+    // Notify backend; what the agent sends shares this connection's room in
+    // the backend queue
+    let queue = state.outbound.connection("agent");
+    forward(
+        &state,
+        &queue,
+        BackendMessage::AgentConnected(agent_info.clone()),
+    )
+    .await;
 
     // Send initial snapshot (if available)
     // TODO: Get snapshot from backend for this agent
@@ -93,18 +282,57 @@ pub async fn handle_agent(socket: WebSocket, state: Arc<GatewayState>) {
             msg = ws_receiver.next() => {
                 match msg {
                     Some(Ok(Message::Text(text))) => {
-                        if let Err(e) = handle_agent_message(&text, &state, &agent_id).await {
-                            error!(error = %e, agent_id = %agent_id, "Failed to handle agent message");
+                        state.registry.received_bytes(&agent_id, text.len());
+                        match over_limit(&state, &agent_id, &mut limiter, text.len()) {
+                            Some(LimitAction::Close) => {
+                                let _ = ws_sender.send(Message::Close(Some(rate_limited()))).await;
+                                break;
+                            }
+                            Some(LimitAction::Drop) => continue,
+                            None => {}
+                        }
+                        let max_bytes = state.config.limits.max_message_bytes;
+                        let frame = schema::check_size(text.len(), max_bytes)
+                            .and_then(|()| schema::parse(text.as_bytes(), AGENT_MESSAGE_TYPES));
+                        let refused = match frame {
+                            Ok(frame) => {
+                                handle_agent_message(frame, &state, &agent_id, &queue).await;
+                                continue;
+                            }
+                            Err(error) => rejected(&state, &agent_id, error),
+                        };
+                        if let Ok(frame) = framing.encode(&refused) {
+                            if ws_sender.send(frame).await.is_err() {
+                                break;
+                            }
                         }
                     }
                     Some(Ok(Message::Binary(data))) => {
-                        if let Ok(text) = String::from_utf8(data) {
-                            if let Err(e) = handle_agent_message(&text, &state, &agent_id).await {
-                                error!(error = %e, agent_id = %agent_id, "Failed to handle agent message");
+                        state.registry.received_bytes(&agent_id, data.len());
+                        match over_limit(&state, &agent_id, &mut limiter, data.len()) {
+                            Some(LimitAction::Close) => {
+                                let _ = ws_sender.send(Message::Close(Some(rate_limited()))).await;
+                                break;
+                            }
+                            Some(LimitAction::Drop) => continue,
+                            None => {}
+                        }
+                        let refused = match framing.decode(data, state.config.limits.max_message_bytes) {
+                            Ok(frame) => {
+                                handle_agent_message(frame, &state, &agent_id, &queue).await;
+                                continue;
+                            }
+                            Err(error) => rejected(&state, &agent_id, error),
+                        };
+                        if let Ok(frame) = framing.encode(&refused) {
+                            if ws_sender.send(frame).await.is_err() {
+                                break;
                             }
                         }
                     }
                     Some(Ok(Message::Ping(data))) => {
+                        // Agents ping when we have been silent a while
+                        state.registry.heartbeat(&agent_id);
                         if ws_sender.send(Message::Pong(data)).await.is_err() {
                             break;
                         }
@@ -118,6 +346,13 @@ pub async fn handle_agent(socket: WebSocket, state: Arc<GatewayState>) {
                     }
                     Some(Err(e)) => {
                         error!(error = %e, agent_id = %agent_id, "WebSocket error");
+                        if too_big(&e) {
+                            let close = CloseFrame {
+                                code: close_code::SIZE,
+                                reason: "frame exceeds limits.max_frame_bytes".into(),
+                            };
+                            let _ = ws_sender.send(Message::Close(Some(close))).await;
+                        }
                         break;
                     }
                     None => {
@@ -126,63 +361,315 @@ pub async fn handle_agent(socket: WebSocket, state: Arc<GatewayState>) {
                 }
             }
 
-            // Send command to agent
-            cmd = cmd_rx.recv() => {
-                if let Some(command) = cmd {
-                    let msg = GatewayToAgentMessage::Command(command);
-                    if let Ok(json) = serde_json::to_string(&msg) {
-                        if ws_sender.send(Message::Text(json)).await.is_err() {
-                            break;
+            // Send command or ack to agent
+            msg = cmd_rx.recv() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        let close = CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "disconnected by gateway".into(),
+                        };
+                        let _ = ws_sender.send(Message::Close(Some(close))).await;
+                        break;
+                    }
+                };
+                if let Ok(frame) = framing.encode(&msg) {
+                    let started = std::time::Instant::now();
+                    if ws_sender.send(frame).await.is_err() {
+                        if matches!(msg, GatewayToAgentMessage::Command(_)) {
+                            state.metrics.command_routing_failed();
                         }
+                        break;
                     }
+                    state.metrics.observe_send("agent", started.elapsed());
                 }
             }
         }
     }
 
-    // Cleanup
-    state.registry.unregister(&agent_id);
-    let _ = state.backend_tx.send(BackendMessage::AgentDisconnected(agent_id.clone()));
+    // Cleanup, unless the agent was dropped from the registry already
+    if registration.upgrade().is_some() {
+        state.registry.unregister(&agent_id);
+        forward(
+            &state,
+            &queue,
+            BackendMessage::AgentDisconnected(agent_id.clone()),
+        )
+        .await;
+    }
 
     info!(agent_id = %agent_id, "Agent disconnected");
 }
 
-/// Wait for agent registration message
+/// Apply an agent's rate limits to a message of `len` bytes, returning
+/// what to do with one over them
+pub(crate) fn over_limit(
+    state: &GatewayState,
+    agent_id: &str,
+    limiter: &mut AgentLimiter,
+    len: usize,
+) -> Option<LimitAction> {
+    let limit = match limiter.check(len) {
+        Ok(()) => {
+            let dropped = limiter.take_dropped();
+            if dropped > 0 {
+                info!(agent_id = %agent_id, dropped = dropped, "Agent back under its rate limit");
+            }
+            return None;
+        }
+        Err(limit) => limit,
+    };
+
+    let action = limiter.action();
+    match action {
+        LimitAction::Close => {
+            warn!(agent_id = %agent_id, limit = limit, "Agent over its rate limit, closing");
+            state.metrics.rate_limited(limit, "close");
+        }
+        LimitAction::Drop => {
+            // Once per episode, not once per message
+            if limiter.dropped() == 1 {
+                warn!(agent_id = %agent_id, limit = limit, "Agent over its rate limit, dropping messages");
+            }
+            state.metrics.rate_limited(limit, "drop");
+        }
+    }
+    Some(action)
+}
+
+/// Close frame for an agent over its rate limit
+fn rate_limited() -> CloseFrame<'static> {
+    CloseFrame {
+        code: close_code::POLICY,
+        reason: "rate limit exceeded".into(),
+    }
+}
+
+/// Who registered on a connection
+enum Registration {
+    Agent(AgentInfo),
+    /// A downstream gateway, connecting as it would to the backend
+    Gateway(crate::backend_client::RegisterPayload),
+}
+
+/// Wait for agent registration message; fails with the close frame to
+/// send, if any
 async fn wait_for_registration(
     receiver: &mut futures_util::stream::SplitStream<WebSocket>,
-) -> Option<AgentInfo> {
+    max_bytes: usize,
+) -> Result<Registration, Option<CloseFrame<'static>>> {
     // Wait up to 30 seconds for registration
     let timeout = tokio::time::Duration::from_secs(30);
 
     match tokio::time::timeout(timeout, receiver.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) if text.len() > max_bytes => {
+            warn!(
+                size = text.len(),
+                "Registration exceeds limits.max_register_bytes"
+            );
+            Err(Some(CloseFrame {
+                code: close_code::SIZE,
+                reason: "registration too large".into(),
+            }))
+        }
         Ok(Some(Ok(Message::Text(text)))) => {
             if let Ok(AgentMessage::Register(payload)) = serde_json::from_str(&text) {
-                Some(AgentInfo {
-                    id: payload.agent_id,
-                    hostname: payload.hostname,
-                    labels: payload.labels,
-                    version: payload.version,
-                    os: payload.os,
-                    connected_at: Utc::now(),
-                    last_heartbeat: Utc::now(),
-                    tx: None,
-                })
+                Ok(Registration::Agent(registered_agent(payload)))
+            } else if let Ok(GatewayToBackendMessage::Register(payload)) =
+                serde_json::from_str(&text)
+            {
+                Ok(Registration::Gateway(payload))
             } else {
                 warn!("First message was not registration");
-                None
+                Err(None)
             }
         }
-        _ => None,
+        _ => Err(None),
+    }
+}
+
+/// Whether a WebSocket error is a frame or message over the size limit
+fn too_big(error: &axum::Error) -> bool {
+    // axum's tungstenite is not ours, so only its message tells
+    error.to_string().contains("Message too long")
+}
+
+/// Drop what the agent may only use when the backend takes it as well:
+/// a support bundle sent in chunks would otherwise be reported delivered
+/// to a backend that discards it
+pub(crate) fn backend_capabilities(state: &GatewayState, agent_info: &mut AgentInfo) {
+    if !state.outbound.backend_supports(protocol::DIAGNOSTICS) {
+        agent_info
+            .capabilities
+            .retain(|c| c != protocol::DIAGNOSTICS);
+    }
+}
+
+/// The agent a registration describes, with the protocol settled on
+pub(crate) fn registered_agent(payload: RegisterPayload) -> AgentInfo {
+    let accepted = protocol::negotiate(payload.protocol_version, &payload.capabilities);
+    AgentInfo {
+        id: payload.agent_id,
+        hostname: payload.hostname,
+        labels: payload.labels,
+        ip_addresses: payload.ip_addresses,
+        version: payload.version,
+        os: payload.os,
+        protocol_version: accepted.protocol_version,
+        capabilities: accepted.capabilities,
+        outdated: false,
+        quarantined: false,
+        // Only ever the one its certificate names, see check_identity
+        tenant_id: None,
+        connected_at: Utc::now(),
+        last_heartbeat: Utc::now(),
+        health: Default::default(),
+        tx: None,
     }
 }
 
+/// Hold a registering agent to the id its client certificate was issued
+/// for, so that one enrolled agent cannot take over another's commands,
+/// and give it the certificate's tenant. Without client certificates there
+/// is no identity to check, and no agent belongs to a tenant.
+pub(crate) fn check_identity(
+    state: &GatewayState,
+    agent_info: &mut AgentInfo,
+    peer: &PeerCertificate,
+) -> Result<(), String> {
+    let tls = &state.config.tls;
+    if !(tls.enabled && tls.verify_clients) {
+        return Ok(());
+    }
+    if peer.names(&agent_info.id) {
+        agent_info.tenant_id = peer.tenant.clone();
+        return Ok(());
+    }
+    warn!(
+        agent_id = %agent_info.id,
+        certificate = ?peer.names,
+        "Agent refused, its certificate was issued for another id"
+    );
+    Err(format!(
+        "client certificate does not name agent {}",
+        agent_info.id
+    ))
+}
+
+/// Apply the minimum version policy to a registering agent, flagging it as
+/// outdated or returning the reason it is turned away
+pub(crate) fn check_version(
+    state: &GatewayState,
+    agent_info: &mut AgentInfo,
+) -> Result<(), String> {
+    match state.versions.check(&agent_info.version) {
+        Verdict::Supported => Ok(()),
+        Verdict::Outdated => {
+            warn!(
+                agent_id = %agent_info.id,
+                version = %agent_info.version,
+                "Agent is older than the minimum supported version"
+            );
+            agent_info.outdated = true;
+            Ok(())
+        }
+        Verdict::Rejected => {
+            let minimum = state.versions.policy().min_version.unwrap_or_default();
+            warn!(
+                agent_id = %agent_info.id,
+                version = %agent_info.version,
+                min_version = %minimum,
+                "Rejecting agent, upgrade required"
+            );
+            state.metrics.agent_rejected(&agent_info.version);
+            Err(format!(
+                "upgrade required: minimum agent version is {}",
+                minimum
+            ))
+        }
+    }
+}
+
+/// How frames to and from an agent are encoded past registration
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    msgpack: bool,
+    /// Deflate frames above this size
+    compress_above: Option<usize>,
+}
+
+impl Framing {
+    fn new(agent: &AgentInfo, compress_above: usize) -> Self {
+        Self {
+            msgpack: agent.supports(protocol::MSGPACK),
+            compress_above: agent.supports(protocol::DEFLATE).then_some(compress_above),
+        }
+    }
+
+    fn encode(&self, message: &GatewayToAgentMessage) -> anyhow::Result<Message> {
+        let data = if self.msgpack {
+            protocol::to_msgpack(message)?
+        } else {
+            serde_json::to_vec(message)?
+        };
+
+        Ok(match protocol::compress(&data, self.compress_above)? {
+            Some(deflated) => Message::Binary(deflated),
+            None if self.msgpack => Message::Binary(data),
+            None => Message::Text(String::from_utf8(data)?),
+        })
+    }
+
+    /// Decode a binary frame of at most `max_bytes` once inflated; it is
+    /// JSON unless MessagePack was agreed on
+    fn decode(&self, data: Vec<u8>, max_bytes: usize) -> Result<AgentFrame, ProtocolError> {
+        let data = protocol::inflate(data).map_err(ProtocolError::encoding)?;
+        schema::check_size(data.len(), max_bytes)?;
+        if !self.msgpack {
+            return schema::parse(&data, AGENT_MESSAGE_TYPES);
+        }
+        let value: serde_json::Value =
+            protocol::from_msgpack(&data).map_err(ProtocolError::encoding)?;
+        schema::from_value(&value, AGENT_MESSAGE_TYPES)
+    }
+}
+
+/// Count a message from an agent that failed validation, returning the
+/// answer telling the agent why
+pub(crate) fn rejected(
+    state: &GatewayState,
+    agent_id: &str,
+    error: ProtocolError,
+) -> GatewayToAgentMessage {
+    state.registry.received(agent_id);
+    state.metrics.message_received("invalid");
+    state.metrics.protocol_error("agent", error.kind.as_str());
+    warn!(
+        agent_id = %agent_id,
+        kind = error.kind.as_str(),
+        message_type = ?error.message_type,
+        field = ?error.field,
+        error = %error.message,
+        "Invalid message from agent"
+    );
+    GatewayToAgentMessage::ProtocolError(error)
+}
+
 /// Handle a message from an agent
-async fn handle_agent_message(
-    text: &str,
+pub(crate) async fn handle_agent_message(
+    frame: AgentFrame,
     state: &GatewayState,
     agent_id: &str,
-) -> anyhow::Result<()> {
-    let msg: AgentMessage = serde_json::from_str(text)?;
+    queue: &Connection,
+) {
+    state.registry.received(agent_id);
+    let AgentFrame { message: msg, seq } = frame;
+    state.metrics.message_received(msg.message_type());
+    let delivery = seq.map(|seq| Delivery {
+        agent_id: agent_id.to_string(),
+        seq,
+    });
 
     match msg {
         AgentMessage::Register(_) => {
@@ -191,7 +678,8 @@ async fn handle_agent_message(
         }
         AgentMessage::StatusDelta(delta) => {
             debug!(agent_id = %agent_id, "Received status delta");
-            let _ = state.backend_tx.send(BackendMessage::StatusUpdate(delta));
+            state.registry.received_deltas(agent_id, 1, false);
+            forward_status(state, agent_id, queue, vec![delta], delivery).await;
         }
         AgentMessage::StatusBatch(batch) => {
             debug!(
@@ -199,18 +687,301 @@ async fn handle_agent_message(
                 count = batch.deltas.len(),
                 "Received status batch"
             );
-            for delta in batch.deltas {
-                let _ = state.backend_tx.send(BackendMessage::StatusUpdate(delta));
-            }
+            state
+                .registry
+                .received_deltas(agent_id, batch.deltas.len(), true);
+            forward_status(state, agent_id, queue, batch.deltas, delivery).await;
         }
         AgentMessage::CommandResponse(response) => {
             debug!(agent_id = %agent_id, "Received command response");
-            let _ = state.backend_tx.send(BackendMessage::CommandResponse(response));
+            state.registry.received_response(agent_id);
+            command_response(state, agent_id, queue, response).await;
+        }
+        AgentMessage::JobUpdate(update) => {
+            debug!(agent_id = %agent_id, "Received job update");
+            forward(state, queue, BackendMessage::JobUpdate(update)).await;
         }
         AgentMessage::Pong => {
             state.registry.heartbeat(agent_id);
         }
+        AgentMessage::Disconnect(notice) => {
+            info!(agent_id = %agent_id, reason = %notice.reason, "Agent disconnecting");
+        }
+        AgentMessage::MetadataUpdate(metadata) => {
+            let updated = state.registry.update_metadata(
+                agent_id,
+                metadata.hostname,
+                metadata.labels,
+                metadata.ip_addresses,
+            );
+            if let Some(info) = updated {
+                forward(state, queue, BackendMessage::AgentMetadata(info)).await;
+            }
+        }
+        AgentMessage::Discovery(report) => {
+            debug!(agent_id = %agent_id, "Received discovery report");
+            let discovery = BackendMessage::Discovery {
+                agent_id: agent_id.to_string(),
+                report,
+            };
+            forward(state, queue, discovery).await;
+        }
+        AgentMessage::Inventory(inventory) => {
+            debug!(agent_id = %agent_id, "Received inventory");
+            let inventory = BackendMessage::Inventory {
+                agent_id: agent_id.to_string(),
+                inventory,
+            };
+            forward(state, queue, inventory).await;
+        }
+        AgentMessage::Diagnostics(chunk) => {
+            debug!(agent_id = %agent_id, index = ?chunk.get("index"), "Received diagnostics chunk");
+            let diagnostics = BackendMessage::Diagnostics {
+                agent_id: agent_id.to_string(),
+                chunk,
+            };
+            forward(state, queue, diagnostics).await;
+        }
+        AgentMessage::SnapshotRequest(request) => {
+            info!(agent_id = %agent_id, version = ?request.version, "Agent requested a full snapshot");
+            let request = BackendMessage::SnapshotRequest {
+                agent_id: agent_id.to_string(),
+                version: request.version,
+            };
+            forward(state, queue, request).await;
+        }
     }
+}
+
+/// Record an agent's command response and pass it on to the backend
+pub(crate) async fn command_response(
+    state: &GatewayState,
+    agent_id: &str,
+    queue: &Connection,
+    mut response: serde_json::Value,
+) {
+    state.commands.correlate(agent_id, &mut response);
+    let field = |name: &str| response.get(name).and_then(|v| v.as_str());
+    info!(
+        agent_id = %agent_id,
+        command_id = field("job_id"),
+        correlation_id = field("correlation_id"),
+        status = field("status"),
+        "Command response forwarded"
+    );
+    state.audit.record(&AuditEvent::result(agent_id, &response));
+    state.commands.answered(agent_id, &response);
+    crate::webhooks::command(state, agent_id, &response);
+    // Responses to a check on hundreds of agents reach the backend together
+    let message = match state.fanouts.batched(&response) {
+        Some(job_id) => BackendMessage::BatchedResponse {
+            job_id,
+            response: response.clone(),
+        },
+        None => BackendMessage::CommandResponse(response.clone()),
+    };
+    forward(state, queue, message).await;
+    crate::fanout::answered(state, agent_id, &response).await;
+}
+
+/// Forward status updates, the agent's ack riding on the last one
+async fn forward_status(
+    state: &GatewayState,
+    agent_id: &str,
+    queue: &Connection,
+    deltas: Vec<serde_json::Value>,
+    delivery: Option<Delivery>,
+) {
+    if deltas.is_empty() {
+        if let Some(delivery) = delivery {
+            state.registry.ack(&delivery.agent_id, delivery.seq);
+        }
+        return;
+    }
+
+    let last = deltas.len() - 1;
+    for (i, update) in deltas.into_iter().enumerate() {
+        let delivery = if i == last { delivery.clone() } else { None };
+        let previous = state.registry.record_check(agent_id, &update);
+        crate::webhooks::status(state, agent_id, previous.as_deref(), &update);
+        crate::events::status(state, agent_id, previous.as_deref(), &update);
+        forward(
+            state,
+            queue,
+            BackendMessage::StatusUpdate { update, delivery },
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn test_frame_sequence_number() {
+        let frame: AgentFrame = serde_json::from_str(
+            r#"{"type":"status_batch","payload":{"deltas":[{"status":"ok"}]},"seq":7}"#,
+        )
+        .unwrap();
+        assert_eq!(frame.seq, Some(7));
+        assert!(matches!(frame.message, AgentMessage::StatusBatch(ref b) if b.deltas.len() == 1));
+
+        let frame: AgentFrame = serde_json::from_str(r#"{"type":"pong"}"#).unwrap();
+        assert_eq!(frame.seq, None);
+        assert!(matches!(frame.message, AgentMessage::Pong));
+
+        let frame: AgentFrame =
+            serde_json::from_str(r#"{"type":"disconnect","payload":{"reason":"shutdown"}}"#)
+                .unwrap();
+        assert!(matches!(frame.message, AgentMessage::Disconnect(ref n) if n.reason == "shutdown"));
+
+        let frame: AgentFrame = serde_json::from_str(
+            r#"{"type":"metadata_update","payload":{"hostname":"db-1","labels":{"role":"db"},"ip_addresses":["10.0.0.5"]}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            frame.message,
+            AgentMessage::MetadataUpdate(ref m) if m.hostname == "db-1" && m.ip_addresses == ["10.0.0.5"]
+        ));
+
+        let frame: AgentFrame = serde_json::from_str(
+            r#"{"type":"diagnostics","payload":{"job_id":"cmd-1","index":0,"total":2,"data":"{\"agent\""}}"#,
+        )
+        .unwrap();
+        assert_eq!(frame.message.message_type(), "diagnostics");
+        assert!(AGENT_MESSAGE_TYPES.contains(&"diagnostics"));
+    }
+
+    #[test]
+    fn test_snapshot_resync() {
+        let frame: AgentFrame =
+            serde_json::from_str(r#"{"type":"snapshot_request","payload":{"version":41}}"#)
+                .unwrap();
+        assert!(matches!(
+            frame.message,
+            AgentMessage::SnapshotRequest(SnapshotRequest { version: Some(41) })
+        ));
+
+        let delta = GatewayToAgentMessage::SnapshotDelta(serde_json::json!({
+            "base_version": 41,
+            "version": 42,
+            "operations": [{"op": "remove", "component_id": "db"}],
+        }));
+        let json = serde_json::to_value(&delta).unwrap();
+        assert_eq!(json["type"], "snapshot_delta");
+        assert_eq!(json["payload"]["base_version"], 41);
+    }
+
+    #[test]
+    fn test_msgpack_frames() {
+        let data = protocol::to_msgpack(&serde_json::json!({
+            "type": "status_delta",
+            "payload": {"component_id": "db", "status": "ok", "metrics": {"load": 0.5}},
+            "seq": 3,
+        }))
+        .unwrap();
+        let msgpack = Framing {
+            msgpack: true,
+            compress_above: None,
+        };
+        let frame = msgpack.decode(data, usize::MAX).unwrap();
+        assert_eq!(frame.seq, Some(3));
+        match frame.message {
+            AgentMessage::StatusDelta(delta) => assert_eq!(delta["metrics"]["load"], 0.5),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // Without agreement, binary frames are still JSON
+        let json = Framing {
+            msgpack: false,
+            compress_above: None,
+        };
+        assert!(json
+            .decode(br#"{"type":"pong"}"#.to_vec(), usize::MAX)
+            .is_ok());
+
+        let ack = msgpack
+            .encode(&GatewayToAgentMessage::Ack { seq: 3 })
+            .unwrap();
+        assert!(matches!(ack, Message::Binary(_)));
+    }
+
+    #[test]
+    fn test_deflated_frames() {
+        let framing = Framing {
+            msgpack: false,
+            compress_above: Some(64),
+        };
+        let snapshot = GatewayToAgentMessage::Snapshot(serde_json::json!({
+            "version": 1,
+            "components": vec![serde_json::json!({"id": "db"}); 100],
+        }));
+        match framing.encode(&snapshot).unwrap() {
+            Message::Binary(data) => {
+                let json = protocol::inflate(data).unwrap();
+                assert!(json.starts_with(br#"{"type":"snapshot""#));
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        let ack = framing
+            .encode(&GatewayToAgentMessage::Ack { seq: 1 })
+            .unwrap();
+        assert!(matches!(ack, Message::Text(_)));
+
+        let pong = protocol::compress(br#"{"type":"pong"}"#, Some(0))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            framing.decode(pong.clone(), usize::MAX).unwrap().message,
+            AgentMessage::Pong
+        ));
+        // The limit holds for what a frame inflates to
+        let refused = framing.decode(pong, 8).unwrap_err();
+        assert_eq!(refused.kind, schema::ErrorKind::Oversized);
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let mut config = crate::GatewayConfig::default();
+        let certificate = |names: &[&str], tenant: Option<&str>| PeerCertificate {
+            verified: true,
+            names: names.iter().map(|n| n.to_string()).collect(),
+            tenant: tenant.map(str::to_string),
+        };
+        // The tenant an agent claims for itself is no longer read
+        let mut agent = registered_agent(
+            serde_json::from_value(serde_json::json!({
+                "agent_id": "agent-b",
+                "hostname": "host-b",
+                "labels": {},
+                "version": "1.0.0",
+                "os": "linux",
+                "tenant_id": "globex",
+            }))
+            .unwrap(),
+        );
+        assert_eq!(agent.tenant_id, None);
+
+        let state = GatewayState::for_tests(config.clone());
+        // A certificate issued for agent A registering as agent B
+        let agent_a = certificate(&["agent-a"], Some("acme"));
+        assert!(check_identity(&state, &mut agent, &agent_a).is_err());
+        assert!(check_identity(&state, &mut agent, &PeerCertificate::default()).is_err());
+        assert_eq!(agent.tenant_id, None);
+
+        let agent_b = certificate(&["agent-b"], Some("acme"));
+        assert!(check_identity(&state, &mut agent, &agent_b).is_ok());
+        assert_eq!(agent.tenant_id.as_deref(), Some("acme"));
+        let untenanted = certificate(&["agent-b"], None);
+        assert!(check_identity(&state, &mut agent, &untenanted).is_ok());
+        assert_eq!(agent.tenant_id, None);
+
+        // Nothing to check agents against without client certificates
+        config.tls.verify_clients = false;
+        let state = GatewayState::for_tests(config);
+        assert!(check_identity(&state, &mut agent, &agent_a).is_ok());
+        assert_eq!(agent.tenant_id, None);
+    }
 }
@@ -0,0 +1,374 @@
+//! Command audit trail
+//!
+//! Appends one JSON line per routed command and per command result to an
+//! append-only file, and answers queries over it for `GET /audit`. Params
+//! are stored as a SHA-256 hash so secrets passed to actions never reach
+//! the log.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::config_update::ConfigUpdate;
+use crate::registry::AgentCommand;
+
+/// Default number of events returned by a query
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// Most events a query returns, whatever its limit
+const MAX_QUERY_LIMIT: usize = 1000;
+
+/// A query reads at most this much of the end of the file
+const MAX_SCAN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// An audit record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    /// "command_routed", "command_failed", "command_denied", "command_result",
+    /// "config_update" or "config_update_failed"
+    pub event: String,
+    pub command_id: String,
+    pub agent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl AuditEvent {
+    /// A command sent (or not) to an agent
+    pub fn routed(
+        command: &AgentCommand,
+        agent_id: &str,
+        requested_by: Option<&str>,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event: if error.is_some() {
+                "command_failed"
+            } else {
+                "command_routed"
+            }
+            .to_string(),
+            command_id: command.id.clone(),
+            agent_id: Some(agent_id.to_string()),
+            requested_by: requested_by.map(str::to_string),
+            command_type: Some(command.command_type.clone()),
+            component_id: Some(command.component_id.clone()),
+            action_name: command.action_name.clone(),
+            params_hash: Some(params_hash(&command.params)),
+            status: None,
+            error,
+            correlation_id: command.correlation_id.clone(),
+        }
+    }
+
+    /// A command refused by the RBAC policies
+    pub fn denied(
+        command: &AgentCommand,
+        agent_id: &str,
+        requested_by: Option<&str>,
+        reason: &str,
+    ) -> Self {
+        Self {
+            event: "command_denied".to_string(),
+            ..Self::routed(command, agent_id, requested_by, Some(reason.to_string()))
+        }
+    }
+
+    /// A config update sent (or not) to an agent
+    pub fn config_update(
+        update_id: &str,
+        agent_id: &str,
+        requested_by: Option<&str>,
+        update: &ConfigUpdate,
+        error: Option<String>,
+    ) -> Self {
+        let params = serde_json::to_value(update).unwrap_or_default();
+        Self {
+            timestamp: Utc::now(),
+            event: if error.is_some() {
+                "config_update_failed"
+            } else {
+                "config_update"
+            }
+            .to_string(),
+            command_id: update_id.to_string(),
+            agent_id: Some(agent_id.to_string()),
+            requested_by: requested_by.map(str::to_string),
+            command_type: Some("config_update".to_string()),
+            component_id: None,
+            action_name: None,
+            params_hash: Some(params_hash(&params)),
+            status: None,
+            error,
+            correlation_id: None,
+        }
+    }
+
+    /// A command response reported by an agent
+    pub fn result(agent_id: &str, response: &serde_json::Value) -> Self {
+        let field = |name: &str| {
+            response
+                .get(name)
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+
+        Self {
+            timestamp: Utc::now(),
+            event: "command_result".to_string(),
+            command_id: field("job_id").unwrap_or_default(),
+            agent_id: Some(agent_id.to_string()),
+            requested_by: None,
+            command_type: None,
+            component_id: None,
+            action_name: None,
+            params_hash: None,
+            status: field("status"),
+            error: field("error"),
+            correlation_id: field("correlation_id"),
+        }
+    }
+}
+
+/// Filters for `GET /audit`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub agent_id: Option<String>,
+    pub command_id: Option<String>,
+    pub requested_by: Option<String>,
+    pub command_type: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        fn eq(filter: &Option<String>, value: &Option<String>) -> bool {
+            filter.as_ref().is_none_or(|f| value.as_ref() == Some(f))
+        }
+
+        eq(&self.agent_id, &event.agent_id)
+            && self
+                .command_id
+                .as_ref()
+                .is_none_or(|id| &event.command_id == id)
+            && eq(&self.requested_by, &event.requested_by)
+            && eq(&self.command_type, &event.command_type)
+            && self.since.is_none_or(|since| event.timestamp >= since)
+            && self.until.is_none_or(|until| event.timestamp <= until)
+    }
+}
+
+/// Append-only audit log file
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// Open the audit file, creating it and its directory if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            file: Mutex::new(Some(file)),
+        })
+    }
+
+    /// An audit log that records nothing
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Append an event
+    pub fn record(&self, event: &AuditEvent) {
+        let mut file = self.file.lock().unwrap();
+        let file = match file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let result = serde_json::to_string(event)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(file, "{}", line)?));
+        if let Err(e) = result {
+            error!(error = %e, command_id = %event.command_id, "Failed to write audit event");
+        }
+    }
+
+    /// Matching events, newest first, among the last `MAX_SCAN_BYTES` of
+    /// the file; the file is read on a blocking thread
+    pub async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        // Lines are written whole under the lock: everything before this
+        // length is complete, whatever gets appended while we read
+        let len = match self.file.lock().unwrap().as_ref() {
+            Some(file) => file.metadata()?.len(),
+            None => return Ok(Vec::new()),
+        };
+
+        tokio::task::spawn_blocking(move || scan(&path, len, &query)).await?
+    }
+}
+
+/// The newest events matching `query` in the tail of the first `len` bytes
+/// of the file
+fn scan(path: &Path, len: u64, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .min(MAX_QUERY_LIMIT);
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    let start = len.saturating_sub(MAX_SCAN_BYTES);
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut lines = BufReader::new(file.take(len - start)).lines();
+    if start > 0 {
+        // The first line was most likely cut in half
+        lines.next();
+    }
+
+    let mut events = VecDeque::with_capacity(limit);
+    for event in lines
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<AuditEvent>(&line).ok())
+        .filter(|event| query.matches(event))
+    {
+        if events.len() == limit {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+    Ok(events.into_iter().rev().collect())
+}
+
+/// SHA-256 of the params, as hex
+pub fn params_hash(params: &serde_json::Value) -> String {
+    let digest = Sha256::digest(params.to_string().as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: &str) -> AgentCommand {
+        AgentCommand {
+            id: id.to_string(),
+            command_type: "restart".to_string(),
+            component_id: "nginx".to_string(),
+            action_name: None,
+            params: serde_json::json!({"password": "secret"}),
+            timeout_secs: 60,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_query() {
+        let dir = std::env::temp_dir().join(format!("opsmap-audit-{}", uuid::Uuid::new_v4()));
+        let log = AuditLog::open(&dir.join("audit.jsonl")).unwrap();
+
+        log.record(&AuditEvent::routed(
+            &command("cmd-1"),
+            "agent-1",
+            Some("alice"),
+            None,
+        ));
+        log.record(&AuditEvent::routed(
+            &command("cmd-2"),
+            "agent-2",
+            None,
+            None,
+        ));
+        log.record(&AuditEvent::result(
+            "agent-1",
+            &serde_json::json!({"job_id": "cmd-1", "status": "completed"}),
+        ));
+
+        let all = log.query(AuditQuery::default()).await.unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].event, "command_result");
+
+        let query = AuditQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let newest = log.query(query).await.unwrap();
+        assert_eq!(newest.len(), 2);
+        assert_eq!(newest[0].event, "command_result");
+        assert_eq!(newest[1].command_id, "cmd-2");
+
+        let query = AuditQuery {
+            agent_id: Some("agent-1".to_string()),
+            ..Default::default()
+        };
+        let events = log.query(query).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.command_id == "cmd-1"));
+
+        let query = AuditQuery {
+            requested_by: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(log.query(query).await.unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_params_are_hashed() {
+        let event = AuditEvent::routed(&command("cmd-1"), "agent-1", None, None);
+        let line = serde_json::to_string(&event).unwrap();
+
+        assert!(!line.contains("secret"));
+        assert_eq!(event.params_hash.unwrap().len(), 64);
+    }
+}
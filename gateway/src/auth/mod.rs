@@ -0,0 +1,266 @@
+//! API authentication
+//!
+//! With `api.tokens` set, the operator endpoints want an
+//! `Authorization: Bearer <token>` header from a token with the scope they
+//! need:
+//!
+//! - `read`: `/agents`, `/agents/:id`, `/metrics`, `/audit`, `/peers`,
+//!   `/dashboard/agents`, `/events`, `/summary`, `/dead-letters`,
+//!   `/groups` and `/schedules` (with their `/:name`, `/schedules/:name/runs`);
+//! - `command`: `POST /agents/:id/disconnect`,
+//!   `/agents/:id/quarantine`, `/agents/:id/command`, `/config-updates`,
+//!   `/dead-letters/:id/retry`, `/groups/:name/command` and
+//!   `/schedules/:name/run`, `PUT` `/groups/:name` and `/schedules/:name`,
+//!   and `DELETE` `/agents/:id/quarantine`, `/dead-letters`,
+//!   `/dead-letters/:id`, `/groups/:name` and `/schedules/:name`.
+//!
+//! A token has only the scopes it lists, so a Prometheus scraper can read
+//! without being able to act. Without tokens the read endpoints stay open, as
+//! before they existed, but the command endpoints are refused: nobody who
+//! merely reaches the port may act on agents. `/health`, `/enroll`, the `/dashboard` page (not its
+//! data) and the agent endpoints are never affected: agents authenticate
+//! with their certificate, and peer gateways on `/peer` with `ha.token`.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+use crate::GatewayState;
+
+/// API settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiSettings {
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+}
+
+/// A bearer token and what it may do
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Who holds it, for the logs
+    pub name: String,
+    /// The token itself, best given as `${file:...}` or `${VAR}`
+    pub token: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Read]
+}
+
+/// What a token may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read agents, metrics and the audit trail
+    Read,
+    /// Act on agents
+    Command,
+}
+
+/// Name of the token a request was authorized with, for the handlers
+#[derive(Debug, Clone)]
+pub struct Caller(pub String);
+
+/// Why a request is refused
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// No token, or not one we know
+    Unauthenticated,
+    /// A valid token without the scope
+    Forbidden,
+    /// A command endpoint while no tokens are configured
+    Disabled,
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        match self {
+            Denied::Unauthenticated => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "valid bearer token required",
+            )
+                .into_response(),
+            Denied::Forbidden => {
+                (StatusCode::FORBIDDEN, "token lacks the required scope").into_response()
+            }
+            Denied::Disabled => (
+                StatusCode::FORBIDDEN,
+                "command endpoints are disabled until api.tokens is configured",
+            )
+                .into_response(),
+        }
+    }
+}
+
+impl ApiSettings {
+    /// Whether the read endpoints are open to anyone, and the command
+    /// endpoints closed to everyone
+    pub fn open(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The token of a request, if it may use `scope`
+    pub fn authorize(
+        &self,
+        headers: &HeaderMap,
+        scope: Scope,
+    ) -> Result<Option<&ApiToken>, Denied> {
+        if self.open() {
+            return match scope {
+                Scope::Read => Ok(None),
+                Scope::Command => Err(Denied::Disabled),
+            };
+        }
+
+        let token = bearer_matches(headers, &self.tokens).ok_or(Denied::Unauthenticated)?;
+
+        if !token.scopes.contains(&scope) {
+            return Err(Denied::Forbidden);
+        }
+        Ok(Some(token))
+    }
+}
+
+impl AsRef<str> for ApiToken {
+    fn as_ref(&self) -> &str {
+        &self.token
+    }
+}
+
+/// The one of `tokens` a request's `Authorization: Bearer` header carries
+pub fn bearer_matches<T: AsRef<str>>(
+    headers: &HeaderMap,
+    tokens: impl IntoIterator<Item = T>,
+) -> Option<T> {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))?;
+    token_matches(presented.trim(), tokens)
+}
+
+/// The one of `tokens` that was presented
+pub fn token_matches<T: AsRef<str>>(
+    presented: &str,
+    tokens: impl IntoIterator<Item = T>,
+) -> Option<T> {
+    // Comparing digests tells a timing attacker nothing about the tokens
+    let digest = Sha256::digest(presented.as_bytes());
+    tokens
+        .into_iter()
+        .find(|t| Sha256::digest(t.as_ref().as_bytes()) == digest)
+}
+
+/// Middleware for the read-only endpoints
+pub async fn read(
+    State(state): State<Arc<GatewayState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Denied> {
+    require(&state, request, next, Scope::Read).await
+}
+
+/// Middleware for the endpoints acting on agents
+pub async fn command(
+    State(state): State<Arc<GatewayState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Denied> {
+    require(&state, request, next, Scope::Command).await
+}
+
+async fn require(
+    state: &GatewayState,
+    mut request: Request,
+    next: Next,
+    scope: Scope,
+) -> Result<Response, Denied> {
+    match state.config.api.authorize(request.headers(), scope) {
+        Ok(token) => {
+            if let Some(token) = token {
+                debug!(token = %token.name, path = %request.uri().path(), "API request authorized");
+                request.extensions_mut().insert(Caller(token.name.clone()));
+            }
+            Ok(next.run(request).await)
+        }
+        Err(denied) => {
+            warn!(path = %request.uri().path(), scope = ?scope, reason = ?denied, "API request refused");
+            Err(denied)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let settings: ApiSettings = serde_yaml::from_str(
+            r#"
+tokens:
+  - name: prometheus
+    token: scrape-token-0123456789
+  - name: operator
+    token: operator-token-0123456789
+    scopes: [read, command]
+"#,
+        )
+        .unwrap();
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        let scraper = headers("Bearer scrape-token-0123456789");
+        assert_eq!(
+            settings
+                .authorize(&scraper, Scope::Read)
+                .unwrap()
+                .unwrap()
+                .name,
+            "prometheus"
+        );
+        assert_eq!(
+            settings.authorize(&scraper, Scope::Command).unwrap_err(),
+            Denied::Forbidden
+        );
+
+        let operator = headers("Bearer operator-token-0123456789");
+        assert!(settings.authorize(&operator, Scope::Command).is_ok());
+
+        for denied in [
+            headers("Bearer wrong"),
+            headers("Basic b3BlcmF0b3I="),
+            HeaderMap::new(),
+        ] {
+            assert_eq!(
+                settings.authorize(&denied, Scope::Read).unwrap_err(),
+                Denied::Unauthenticated
+            );
+        }
+
+        // No tokens: reading is open, acting is refused
+        assert!(ApiSettings::default()
+            .authorize(&HeaderMap::new(), Scope::Read)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            ApiSettings::default()
+                .authorize(&HeaderMap::new(), Scope::Command)
+                .unwrap_err(),
+            Denied::Disabled
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Backend link authentication
+//!
+//! The gateway authenticates to the backend in the WebSocket handshake,
+//! with `Authorization: Bearer <token>`: a static `token`, or an HS256 JWT
+//! signed with `jwt_secret` and made fresh for each connection (`iss` and
+//! `sub` the gateway id, `zone`, `iat`, `exp`).
+//!
+//! The backend can be made to authenticate too, so that a spoofed backend
+//! cannot feed commands to the zone: it answers the handshake with an
+//! `X-OpsMap-Backend-Token` header holding `backend_token`, or, with
+//! `require_backend_jwt`, a JWT signed with `jwt_secret` whose `aud` is this
+//! gateway's id. A backend that does not is disconnected before the gateway
+//! registers.
+//!
+//! ```yaml
+//! backend:
+//!   auth:
+//!     jwt_secret: ${file:/etc/opsmap/secrets/backend-jwt}  # or token: ...
+//!     jwt_ttl_secs: 300
+//!     require_backend_jwt: true   # or backend_token: ...
+//! ```
+
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderValue};
+
+use crate::GatewayConfig;
+
+/// Header the backend answers the handshake with
+pub const BACKEND_TOKEN_HEADER: &str = "x-opsmap-backend-token";
+
+/// Backend link authentication settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackendAuth {
+    /// Static token sent as a bearer token
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Secret signing the JWT sent when there is no `token`, and verifying
+    /// the backend's with `require_backend_jwt`
+    #[serde(default)]
+    pub jwt_secret: Option<String>,
+    #[serde(default = "default_jwt_ttl")]
+    pub jwt_ttl_secs: u64,
+    /// Token the backend must present back
+    #[serde(default)]
+    pub backend_token: Option<String>,
+    /// The backend must present a JWT for this gateway, signed with
+    /// `jwt_secret`
+    #[serde(default)]
+    pub require_backend_jwt: bool,
+}
+
+fn default_jwt_ttl() -> u64 {
+    300
+}
+
+impl Default for BackendAuth {
+    fn default() -> Self {
+        Self {
+            token: None,
+            jwt_secret: None,
+            jwt_ttl_secs: default_jwt_ttl(),
+            backend_token: None,
+            require_backend_jwt: false,
+        }
+    }
+}
+
+/// What the gateway claims in its JWT, and checks in the backend's
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    zone: Option<String>,
+    #[serde(default)]
+    iat: i64,
+    exp: i64,
+}
+
+/// The `Authorization` header value to send, if any
+pub fn authorization(config: &GatewayConfig) -> Result<Option<HeaderValue>> {
+    let auth = &config.backend.auth;
+    let token = match (&auth.token, &auth.jwt_secret) {
+        (Some(token), _) => token.clone(),
+        (None, Some(secret)) => {
+            let now = Utc::now().timestamp();
+            let claims = Claims {
+                iss: Some(config.gateway.id.clone()),
+                sub: Some(config.gateway.id.clone()),
+                aud: None,
+                zone: Some(config.gateway.zone.clone()),
+                iat: now,
+                exp: now + auth.jwt_ttl_secs.min(i64::MAX as u64 / 2) as i64,
+            };
+            sign(&claims, secret)?
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(HeaderValue::from_str(&format!("Bearer {}", token))?))
+}
+
+/// Check the token the backend answered the handshake with
+pub fn verify_backend(config: &GatewayConfig, headers: &HeaderMap) -> Result<()> {
+    let auth = &config.backend.auth;
+    if auth.backend_token.is_none() && !auth.require_backend_jwt {
+        return Ok(());
+    }
+    let presented = headers
+        .get(BACKEND_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("backend presented no {} header", BACKEND_TOKEN_HEADER))?;
+
+    if crate::auth::token_matches(presented, &auth.backend_token).is_some() {
+        return Ok(());
+    }
+    if auth.require_backend_jwt {
+        let secret = auth
+            .jwt_secret
+            .as_deref()
+            .ok_or_else(|| anyhow!("backend.auth.require_backend_jwt needs jwt_secret"))?;
+        let claims = verify(presented, secret)?;
+        if claims.aud.as_deref() != Some(config.gateway.id.as_str()) {
+            bail!("backend token is not for this gateway");
+        }
+        return Ok(());
+    }
+    bail!("backend presented a wrong token")
+}
+
+fn mac(secret: &str) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC key")
+}
+
+/// An HS256 JWT of `claims`
+fn sign(claims: &Claims, secret: &str) -> Result<String> {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let signing_input = format!("{}.{}", header, payload);
+    let mut mac = mac(secret);
+    mac.update(signing_input.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{}.{}", signing_input, signature))
+}
+
+/// The claims of an HS256 JWT signed with `secret` and not expired
+fn verify(token: &str, secret: &str) -> Result<Claims> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("backend token is not a JWT");
+    };
+
+    let fields: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    if fields["alg"] != "HS256" {
+        bail!("backend JWT is not signed with HS256");
+    }
+    let mut mac = mac(secret);
+    mac.update(format!("{}.{}", header, payload).as_bytes());
+    mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature)?)
+        .map_err(|_| anyhow!("backend JWT signature does not match"))?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    if claims.exp <= Utc::now().timestamp() {
+        bail!("backend JWT expired");
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(auth: BackendAuth) -> GatewayConfig {
+        let mut config = GatewayConfig::default();
+        config.gateway.id = "gw-1".to_string();
+        config.backend.auth = auth;
+        config
+    }
+
+    fn backend_jwt(aud: &str, exp_in: i64, secret: &str) -> HeaderMap {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            iss: Some("backend".to_string()),
+            sub: None,
+            aud: Some(aud.to_string()),
+            zone: None,
+            iat: now,
+            exp: now + exp_in,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            BACKEND_TOKEN_HEADER,
+            HeaderValue::from_str(&sign(&claims, secret).unwrap()).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_authorization() {
+        assert!(authorization(&config(BackendAuth::default()))
+            .unwrap()
+            .is_none());
+
+        let token = authorization(&config(BackendAuth {
+            token: Some("s3cret".to_string()),
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(token.unwrap(), "Bearer s3cret");
+
+        let jwt = authorization(&config(BackendAuth {
+            jwt_secret: Some("shared".to_string()),
+            ..Default::default()
+        }))
+        .unwrap()
+        .unwrap();
+        let jwt = jwt.to_str().unwrap().strip_prefix("Bearer ").unwrap();
+        let claims = verify(jwt, "shared").unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("gw-1"));
+        assert!(claims.exp > claims.iat);
+        assert!(verify(jwt, "other").is_err());
+    }
+
+    #[test]
+    fn test_verify_backend() {
+        // Nothing required, nothing checked
+        assert!(verify_backend(&config(BackendAuth::default()), &HeaderMap::new()).is_ok());
+
+        let static_token = config(BackendAuth {
+            backend_token: Some("from-backend".to_string()),
+            ..Default::default()
+        });
+        assert!(verify_backend(&static_token, &HeaderMap::new()).is_err());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            BACKEND_TOKEN_HEADER,
+            HeaderValue::from_static("from-backend"),
+        );
+        assert!(verify_backend(&static_token, &headers).is_ok());
+        headers.insert(BACKEND_TOKEN_HEADER, HeaderValue::from_static("spoofed"));
+        assert!(verify_backend(&static_token, &headers).is_err());
+
+        let jwt = config(BackendAuth {
+            jwt_secret: Some("shared".to_string()),
+            require_backend_jwt: true,
+            ..Default::default()
+        });
+        assert!(verify_backend(&jwt, &backend_jwt("gw-1", 60, "shared")).is_ok());
+        assert!(verify_backend(&jwt, &backend_jwt("gw-2", 60, "shared")).is_err());
+        assert!(verify_backend(&jwt, &backend_jwt("gw-1", -1, "shared")).is_err());
+        assert!(verify_backend(&jwt, &backend_jwt("gw-1", 60, "guessed")).is_err());
+    }
+}
@@ -2,38 +2,113 @@
 //!
 //! Maintains WebSocket connection to the backend.
 
+use anyhow::Context;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::time::{interval, Duration};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use tracing::{debug, error, info, warn};
+use tokio::sync::mpsc;
+use tokio::time::{interval, interval_at, Duration};
+use tokio_tungstenite::{
+    connect_async, connect_async_tls_with_config, tungstenite::client::IntoClientRequest,
+    tungstenite::protocol::Message, Connector,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-use crate::{BackendMessage, GatewayState};
+use crate::agent_server::GatewayToAgentMessage;
+use crate::audit::AuditEvent;
+use crate::backend_auth;
+use crate::delivery::{Delivery, Outbox, MAX_PENDING};
+use crate::fanout::{self, Batches, FanOut, ResponseBatch, Rollout};
+use crate::ha;
+use crate::policy::Principal;
+use crate::protocol::{self, Accepted};
+use crate::registry::{AgentCommand, AgentInfo};
+use crate::router::{self, AgentSelector, Strategy};
+use crate::schema::{self, ProtocolError};
+use crate::selector::Selector;
+use crate::telemetry;
+use crate::versions::VersionPolicy;
+use crate::{BackendMessage, GatewayConfig, GatewayState};
+
+/// Wire type names of `BackendToGatewayMessage`
+const BACKEND_MESSAGE_TYPES: &[&str] = &[
+    "command",
+    "snapshot",
+    "snapshot_delta",
+    "ping",
+    "ack",
+    "registered",
+    "agent_version_policy",
+];
 
 /// Messages from backend
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload")]
 pub enum BackendToGatewayMessage {
     #[serde(rename = "command")]
-    Command(CommandPayload),
+    Command(Box<CommandPayload>),
     #[serde(rename = "snapshot")]
     Snapshot(SnapshotPayload),
+    #[serde(rename = "snapshot_delta")]
+    SnapshotDelta(SnapshotDeltaPayload),
     #[serde(rename = "ping")]
     Ping,
+    /// Sequenced messages up to `seq` were handled
+    #[serde(rename = "ack")]
+    Ack { seq: u64 },
+    /// Answer to our registration
+    #[serde(rename = "registered")]
+    Registered(Accepted),
+    /// Minimum agent version, replacing `agent_versions` from the config
+    #[serde(rename = "agent_version_policy")]
+    AgentVersionPolicy(VersionPolicy),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandPayload {
     pub agent_id: Option<String>,
-    pub labels: Option<std::collections::HashMap<String, String>>,
-    pub command: crate::registry::AgentCommand,
+    /// Label selector: an expression, or an object of labels all equal
+    pub labels: Option<Selector>,
+    pub command: AgentCommand,
+    /// User who requested the command, for the audit trail
+    #[serde(default)]
+    pub requested_by: Option<String>,
+    /// Roles of the requesting user, checked against the RBAC policies
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// How a command routed by labels is rolled out to the agents
+    #[serde(default)]
+    pub rollout: Rollout,
+    /// Run a command routed by labels on one of the agents, picked this
+    /// way, rather than on all of them
+    #[serde(default)]
+    pub strategy: Option<Strategy>,
+    /// Zone the command is meant for; refused outright by a gateway that
+    /// does not command agents of that zone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// Named agent group whose members get the command, fanned out as for
+    /// `labels`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SnapshotPayload {
     pub agent_id: String,
     pub snapshot: serde_json::Value,
+    /// Tenant the snapshot is for, that the agent must belong to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDeltaPayload {
+    pub agent_id: String,
+    pub delta: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
 }
 
 /// Messages to backend
@@ -46,12 +121,97 @@ pub enum GatewayToBackendMessage {
     AgentConnected(crate::registry::AgentInfo),
     #[serde(rename = "agent_disconnected")]
     AgentDisconnected { agent_id: String },
+    #[serde(rename = "agent_metadata")]
+    AgentMetadata(crate::registry::AgentInfo),
     #[serde(rename = "status_update")]
     StatusUpdate(serde_json::Value),
     #[serde(rename = "command_response")]
     CommandResponse(serde_json::Value),
+    #[serde(rename = "job_update")]
+    JobUpdate(serde_json::Value),
+    #[serde(rename = "discovery")]
+    Discovery {
+        agent_id: String,
+        report: serde_json::Value,
+    },
+    #[serde(rename = "inventory")]
+    Inventory {
+        agent_id: String,
+        inventory: serde_json::Value,
+    },
+    /// Part of an agent's support bundle
+    #[serde(rename = "diagnostics")]
+    Diagnostics {
+        agent_id: String,
+        chunk: serde_json::Value,
+    },
+    #[serde(rename = "snapshot_request")]
+    SnapshotRequest {
+        agent_id: String,
+        version: Option<u64>,
+    },
+    /// A command got no final response in time
+    #[serde(rename = "command_timeout")]
+    CommandTimeout(crate::commands::CommandTimeout),
+    /// Responses to one fan-out, with `response_batches`
+    #[serde(rename = "command_responses")]
+    CommandResponses(crate::fanout::ResponseBatch),
+    /// How a command fanned out to several agents went
+    #[serde(rename = "command_summary")]
+    CommandSummary(crate::fanout::CommandSummary),
     #[serde(rename = "pong")]
     Pong,
+    /// A message from the backend was refused, and why
+    #[serde(rename = "protocol_error")]
+    ProtocolError(ProtocolError),
+}
+
+impl GatewayToBackendMessage {
+    /// Wire type name, used as a metrics label
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            GatewayToBackendMessage::Register(_) => "register",
+            GatewayToBackendMessage::AgentConnected(_) => "agent_connected",
+            GatewayToBackendMessage::AgentDisconnected { .. } => "agent_disconnected",
+            GatewayToBackendMessage::AgentMetadata(_) => "agent_metadata",
+            GatewayToBackendMessage::StatusUpdate(_) => "status_update",
+            GatewayToBackendMessage::CommandResponse(_) => "command_response",
+            GatewayToBackendMessage::JobUpdate(_) => "job_update",
+            GatewayToBackendMessage::Discovery { .. } => "discovery",
+            GatewayToBackendMessage::Inventory { .. } => "inventory",
+            GatewayToBackendMessage::Diagnostics { .. } => "diagnostics",
+            GatewayToBackendMessage::SnapshotRequest { .. } => "snapshot_request",
+            GatewayToBackendMessage::CommandTimeout(_) => "command_timeout",
+            GatewayToBackendMessage::CommandResponses(_) => "command_responses",
+            GatewayToBackendMessage::CommandSummary(_) => "command_summary",
+            GatewayToBackendMessage::Pong => "pong",
+            GatewayToBackendMessage::ProtocolError(_) => "protocol_error",
+        }
+    }
+}
+
+/// A frame to the backend; status updates carry a sequence number to ack
+#[derive(Debug, Serialize)]
+struct Frame<'a> {
+    #[serde(flatten)]
+    message: &'a GatewayToBackendMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+}
+
+/// Status updates sent but not yet acked, with the agent message each acks
+type Pending = Outbox<(GatewayToBackendMessage, Option<Delivery>)>;
+
+fn encode(
+    message: &GatewayToBackendMessage,
+    seq: Option<u64>,
+    compress_above: Option<usize>,
+) -> anyhow::Result<Message> {
+    let json = serde_json::to_string(&Frame { message, seq })?;
+    Ok(match protocol::compress(json.as_bytes(), compress_above)? {
+        Some(data) => Message::Binary(data),
+        None => Message::Text(json),
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,16 +220,47 @@ pub struct RegisterPayload {
     pub zone: String,
     pub version: String,
     pub agents: Vec<crate::registry::AgentInfo>,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
 }
 
+type BackendStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// How long to wait for the backend to answer our registration
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a backend before the active one gets to answer a fail-back probe
+const FAILBACK_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Run the backend client
 pub async fn run(state: Arc<GatewayState>) {
-    let mut rx = state.backend_tx.subscribe();
+    let Some(mut rx) = state.outbound.take_receiver() else {
+        error!("Backend client already running");
+        return;
+    };
+    // Survives reconnects so unacked updates are sent again
+    let mut pending: Pending = Outbox::with_capacity(MAX_PENDING);
+    let urls = state.config.backend.urls();
+    // Priority of the backend of the last connection
+    let mut last_active: Option<usize> = None;
+    // Fan-out responses waiting for their batch, kept across reconnects
+    let mut batches = Batches::default();
+    let batch_size = state.config.fanout.batch_size.max(1);
+    let batch_period = Duration::from_millis(state.config.fanout.batch_interval_ms.max(1));
 
     loop {
-        match connect_to_backend(&state).await {
-            Ok((mut ws_sender, mut ws_receiver)) => {
-                info!(url = %state.config.backend.url, "Connected to backend");
+        let mut failing_back = false;
+        match connect_to_any(&state, &urls).await {
+            Ok((active, (mut ws_sender, mut ws_receiver))) => {
+                info!(url = %urls[active], priority = active, "Connected to backend");
+                state.metrics.set_backend_connected(true);
+                state.metrics.set_backend_active(&urls, Some(active));
+                if last_active.is_some_and(|last| last != active) {
+                    warn!(url = %urls[active], priority = active, "Switched to another backend");
+                    state.metrics.backend_failover();
+                }
+                last_active = Some(active);
 
                 // Register with backend
                 let register_msg = GatewayToBackendMessage::Register(RegisterPayload {
@@ -77,6 +268,8 @@ pub async fn run(state: Arc<GatewayState>) {
                     zone: state.config.gateway.zone.clone(),
                     version: env!("CARGO_PKG_VERSION").to_string(),
                     agents: state.registry.list(),
+                    protocol_version: protocol::PROTOCOL_VERSION,
+                    capabilities: protocol::capabilities(),
                 });
 
                 if let Ok(json) = serde_json::to_string(&register_msg) {
@@ -86,8 +279,62 @@ pub async fn run(state: Arc<GatewayState>) {
                     }
                 }
 
+                let accepted = await_registered(&mut ws_receiver, &state, &mut pending).await;
+                info!(
+                    protocol_version = accepted.protocol_version,
+                    capabilities = ?accepted.capabilities,
+                    "Registered with backend"
+                );
+                let acks = accepted.supports(protocol::ACKS);
+                let compress_above = accepted
+                    .supports(protocol::DEFLATE)
+                    .then_some(state.config.gateway.compress_above);
+                let batching = accepted.supports(protocol::RESPONSE_BATCHES);
+                state
+                    .outbound
+                    .set_backend_capabilities(&accepted.capabilities);
+
+                // Retransmit what the previous connection left unacked
+                if !pending.is_empty() {
+                    info!(
+                        count = pending.len(),
+                        "Retransmitting unacknowledged status updates"
+                    );
+                }
+                let mut retransmitted = true;
+                for (seq, (message, _)) in pending.pending() {
+                    if let Ok(frame) = encode(message, acks.then_some(seq), compress_above) {
+                        if ws_sender.send(frame).await.is_err() {
+                            retransmitted = false;
+                            break;
+                        }
+                    }
+                }
+                if !retransmitted {
+                    error!("Failed to retransmit status updates to backend");
+                    continue;
+                }
+                if !acks {
+                    // This backend never acks; sent is as delivered as it gets
+                    for (_, delivery) in pending.ack(u64::MAX) {
+                        ack_agent(&state, delivery);
+                    }
+                }
+
                 // Heartbeat ticker
                 let mut heartbeat = interval(Duration::from_secs(30));
+                // On a standby, the preferred backends are tried now and then
+                let failback_period =
+                    Duration::from_secs(state.config.backend.failback_interval_secs.max(1));
+                let mut failback = interval_at(
+                    tokio::time::Instant::now() + failback_period,
+                    failback_period,
+                );
+                let mut batch_flush = interval(batch_period);
+                // Probes run on their own: they take seconds, during which
+                // the link goes on reading and forwarding
+                let (failback_tx, mut failback_rx) = mpsc::channel::<usize>(1);
+                let mut probing: Option<tokio::task::JoinHandle<()>> = None;
 
                 loop {
                     tokio::select! {
@@ -95,8 +342,13 @@ pub async fn run(state: Arc<GatewayState>) {
                         msg = ws_receiver.next() => {
                             match msg {
                                 Some(Ok(Message::Text(text))) => {
-                                    if let Err(e) = handle_backend_message(&text, &state).await {
-                                        error!(error = %e, "Failed to handle backend message");
+                                    if let Some(msg) = backend_frame(text.into_bytes(), false, &state) {
+                                        handle_backend_message(msg, &state, &mut pending).await;
+                                    }
+                                }
+                                Some(Ok(Message::Binary(data))) => {
+                                    if let Some(msg) = backend_frame(data, true, &state) {
+                                        handle_backend_message(msg, &state, &mut pending).await;
                                     }
                                 }
                                 Some(Ok(Message::Ping(data))) => {
@@ -118,8 +370,12 @@ pub async fn run(state: Arc<GatewayState>) {
                         }
 
                         // Forward messages to backend
-                        result = rx.recv() => {
-                            if let Ok(msg) = result {
+                        queued = rx.recv() => {
+                            if let Some(queued) = queued {
+                                let msg = queued.into_message();
+                                state.metrics.set_backend_queue_depth(state.outbound.depth());
+                                let mut delivery = None;
+                                let mut reliable = false;
                                 let backend_msg = match msg {
                                     BackendMessage::AgentConnected(info) => {
                                         GatewayToBackendMessage::AgentConnected(info)
@@ -127,18 +383,88 @@ pub async fn run(state: Arc<GatewayState>) {
                                     BackendMessage::AgentDisconnected(agent_id) => {
                                         GatewayToBackendMessage::AgentDisconnected { agent_id }
                                     }
-                                    BackendMessage::StatusUpdate(data) => {
-                                        GatewayToBackendMessage::StatusUpdate(data)
+                                    BackendMessage::AgentMetadata(info) => {
+                                        GatewayToBackendMessage::AgentMetadata(info)
+                                    }
+                                    BackendMessage::StatusUpdate { update, delivery: from } => {
+                                        delivery = from;
+                                        reliable = true;
+                                        GatewayToBackendMessage::StatusUpdate(update)
                                     }
                                     BackendMessage::CommandResponse(data) => {
                                         GatewayToBackendMessage::CommandResponse(data)
                                     }
+                                    BackendMessage::BatchedResponse { response, .. } if !batching => {
+                                        GatewayToBackendMessage::CommandResponse(response)
+                                    }
+                                    BackendMessage::BatchedResponse { job_id, response } => {
+                                        match batches.push(job_id, response, batch_size) {
+                                            Some(batch) => GatewayToBackendMessage::CommandResponses(batch),
+                                            None => continue,
+                                        }
+                                    }
+                                    BackendMessage::JobUpdate(data) => {
+                                        GatewayToBackendMessage::JobUpdate(data)
+                                    }
+                                    BackendMessage::Discovery { agent_id, report } => {
+                                        GatewayToBackendMessage::Discovery { agent_id, report }
+                                    }
+                                    BackendMessage::Inventory { agent_id, inventory } => {
+                                        GatewayToBackendMessage::Inventory { agent_id, inventory }
+                                    }
+                                    // Offered to agents registered before the backend changed
+                                    BackendMessage::Diagnostics { agent_id, .. } if !accepted.supports(protocol::DIAGNOSTICS) => {
+                                        warn!(agent_id = %agent_id, "Backend does not take support bundles, chunk dropped");
+                                        continue;
+                                    }
+                                    BackendMessage::Diagnostics { agent_id, chunk } => {
+                                        GatewayToBackendMessage::Diagnostics { agent_id, chunk }
+                                    }
+                                    BackendMessage::SnapshotRequest { agent_id, version } => {
+                                        GatewayToBackendMessage::SnapshotRequest { agent_id, version }
+                                    }
+                                    BackendMessage::CommandTimeout(timeout) => {
+                                        GatewayToBackendMessage::CommandTimeout(timeout)
+                                    }
+                                    BackendMessage::CommandSummary(summary) => {
+                                        // The fan-out's last responses go before its summary
+                                        if let Some(batch) = batches.take(&summary.job_id) {
+                                            let messages = unbatched(batch, batching);
+                                            if !send_all(&mut ws_sender, &state, messages, compress_above).await {
+                                                break;
+                                            }
+                                        }
+                                        GatewayToBackendMessage::CommandSummary(summary)
+                                    }
+                                    BackendMessage::Redelivery(message) => *message,
+                                    BackendMessage::ProtocolError(error) => {
+                                        GatewayToBackendMessage::ProtocolError(error)
+                                    }
                                 };
 
-                                if let Ok(json) = serde_json::to_string(&backend_msg) {
-                                    if ws_sender.send(Message::Text(json)).await.is_err() {
-                                        break;
+                                let seq = (reliable && acks)
+                                    .then(|| pending.track((backend_msg.clone(), delivery.clone())));
+
+                                let frame = match encode(&backend_msg, seq, compress_above) {
+                                    Ok(frame) => frame,
+                                    Err(e) => {
+                                        error!(error = %e, "Failed to encode message for the backend");
+                                        dead_letter(&state, &backend_msg, &format!("not encodable: {}", e));
+                                        continue;
+                                    }
+                                };
+                                let started = std::time::Instant::now();
+                                if let Err(e) = ws_sender.send(frame).await {
+                                    // Sequenced updates are sent again on reconnect
+                                    if seq.is_none() {
+                                        dead_letter(&state, &backend_msg, &format!("backend link failed: {}", e));
                                     }
+                                    break;
+                                }
+                                state.metrics.observe_send("backend", started.elapsed());
+                                state.metrics.message_forwarded(backend_msg.message_type());
+                                if seq.is_none() {
+                                    ack_agent(&state, delivery);
                                 }
                             }
                         }
@@ -152,80 +478,813 @@ pub async fn run(state: Arc<GatewayState>) {
                                 }
                             }
                         }
+
+                        // Responses that waited long enough for their batch
+                        _ = batch_flush.tick(), if !batches.is_empty() => {
+                            let messages = batches
+                                .drain()
+                                .into_iter()
+                                .flat_map(|batch| unbatched(batch, batching))
+                                .collect();
+                            if !send_all(&mut ws_sender, &state, messages, compress_above).await {
+                                break;
+                            }
+                        }
+
+                        // Go back to a preferred backend once it answers
+                        _ = failback.tick(), if active > 0 => {
+                            if probing.as_ref().is_none_or(|probe| probe.is_finished()) {
+                                let state = state.clone();
+                                let preferred: Vec<String> =
+                                    urls[..active].iter().map(|url| url.to_string()).collect();
+                                let failback_tx = failback_tx.clone();
+                                probing = Some(tokio::spawn(async move {
+                                    if let Some(priority) = reachable_among(&state, &preferred).await {
+                                        let _ = failback_tx.send(priority).await;
+                                    }
+                                }));
+                            }
+                        }
+                        Some(preferred) = failback_rx.recv() => {
+                            info!(url = %urls[preferred], "Preferred backend is back, failing back");
+                            failing_back = true;
+                            break;
+                        }
                     }
                 }
+                if let Some(probe) = probing {
+                    probe.abort();
+                }
             }
             Err(e) => {
                 error!(error = %e, "Failed to connect to backend");
             }
         }
 
+        state.metrics.set_backend_connected(false);
+        state.metrics.set_backend_active(&urls, None);
+        state.metrics.backend_reconnect();
+        if failing_back {
+            continue;
+        }
+
         // Wait before reconnecting
         let wait_secs = state.config.backend.reconnect_interval_secs;
-        warn!(
-            wait_secs = wait_secs,
-            "Reconnecting to backend..."
-        );
+        warn!(wait_secs = wait_secs, "Reconnecting to backend...");
         tokio::time::sleep(Duration::from_secs(wait_secs)).await;
     }
 }
 
-/// Connect to the backend
+/// Connect to the first backend that answers, by priority
+async fn connect_to_any(
+    state: &GatewayState,
+    urls: &[&str],
+) -> anyhow::Result<(
+    usize,
+    (
+        SplitSink<BackendStream, Message>,
+        SplitStream<BackendStream>,
+    ),
+)> {
+    let mut last_error = None;
+    for (priority, url) in urls.iter().enumerate() {
+        match connect_to_backend(state, url).await {
+            Ok(streams) => return Ok((priority, streams)),
+            Err(e) => {
+                if urls.len() > 1 {
+                    warn!(url = %url, priority = priority, error = %e, "Backend unreachable");
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no backend configured")))
+}
+
+/// The first of `urls` that accepts a connection, which is closed again
+async fn reachable_among(state: &GatewayState, urls: &[String]) -> Option<usize> {
+    for (priority, url) in urls.iter().enumerate() {
+        let probe = tokio::time::timeout(FAILBACK_PROBE_TIMEOUT, connect_to_backend(state, url));
+        match probe.await {
+            Ok(Ok((mut sender, _))) => {
+                let _ = sender.close().await;
+                return Some(priority);
+            }
+            Ok(Err(e)) => debug!(url = %url, error = %e, "Preferred backend still unreachable"),
+            Err(_) => debug!(url = %url, "Preferred backend did not answer in time"),
+        }
+    }
+    None
+}
+
+/// Connect to a backend
 async fn connect_to_backend(
     state: &GatewayState,
+    url: &str,
 ) -> anyhow::Result<(
-    futures_util::stream::SplitSink<
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-        Message,
-    >,
-    futures_util::stream::SplitStream<
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-    >,
+    SplitSink<BackendStream, Message>,
+    SplitStream<BackendStream>,
 )> {
-    let (ws_stream, _) = connect_async(&state.config.backend.url).await?;
+    let mut request = url.into_client_request()?;
+    // A JWT is signed again for each connection
+    if let Some(authorization) = backend_auth::authorization(&state.config)? {
+        request.headers_mut().insert(
+            tokio_tungstenite::tungstenite::http::header::AUTHORIZATION,
+            authorization,
+        );
+    }
+    let (ws_stream, response) = if url.starts_with("wss://") {
+        // Built per connection so renewed certificates are picked up
+        let ca_file = state.config.backend.ca_file.as_deref();
+        let connector = build_tls_connector(&state.config, ca_file)?;
+        connect_async_tls_with_config(request, None, false, Some(connector)).await?
+    } else {
+        connect_async(request).await?
+    };
+    // Dropped before registering, nothing it sent was read
+    backend_auth::verify_backend(&state.config, response.headers())
+        .context("Backend failed authentication")?;
     Ok(ws_stream.split())
 }
 
-/// Handle a message from the backend
-async fn handle_backend_message(text: &str, state: &GatewayState) -> anyhow::Result<()> {
-    let msg: BackendToGatewayMessage = serde_json::from_str(text)?;
+/// TLS connector presenting the gateway certificate, pinned to `ca_file`
+/// (the backend's or the peers' CA) when set
+pub(crate) fn build_tls_connector(
+    config: &GatewayConfig,
+    ca_file: Option<&str>,
+) -> anyhow::Result<Connector> {
+    // Same identity the agents see, for the backend to verify
+    let identity = match (&config.tls.cert_file, &config.tls.key_file) {
+        (Some(cert_file), Some(key_file)) if config.tls.enabled => {
+            Some((cert_file.as_str(), key_file.as_str()))
+        }
+        _ => None,
+    };
+    let config = crate::tls::client::client_config(ca_file, identity)?;
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+/// Wait for the backend's answer to our registration, handling whatever
+/// else arrives meanwhile; backends that predate negotiation never answer
+async fn await_registered(
+    ws_receiver: &mut SplitStream<BackendStream>,
+    state: &GatewayState,
+    pending: &mut Pending,
+) -> Accepted {
+    let deadline = tokio::time::Instant::now() + REGISTRATION_TIMEOUT;
+
+    loop {
+        let msg = match tokio::time::timeout_at(deadline, ws_receiver.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => backend_frame(text.into_bytes(), false, state),
+            Ok(Some(Ok(Message::Binary(data)))) => backend_frame(data, true, state),
+            Ok(Some(Ok(_))) => continue,
+            // Closed, failed or silent: the main loop sees the rest
+            _ => return Accepted::legacy(),
+        };
+
+        match msg {
+            Some(BackendToGatewayMessage::Registered(accepted)) => return accepted,
+            Some(msg) => handle_backend_message(msg, state, pending).await,
+            None => {}
+        }
+    }
+}
 
+/// Validate a message from the backend; binary frames are deflated JSON.
+/// One refused is reported, and answered with a protocol error
+fn backend_frame(
+    data: Vec<u8>,
+    binary: bool,
+    state: &GatewayState,
+) -> Option<BackendToGatewayMessage> {
+    let data = if binary {
+        protocol::inflate(data).map_err(ProtocolError::encoding)
+    } else {
+        Ok(data)
+    };
+    let max_bytes = state.config.limits.max_backend_message_bytes;
+    let parsed = data.and_then(|data| {
+        schema::check_size(data.len(), max_bytes)?;
+        schema::parse(&data, BACKEND_MESSAGE_TYPES)
+    });
+    match parsed {
+        Ok(msg) => Some(msg),
+        Err(error) => {
+            warn!(
+                kind = error.kind.as_str(),
+                message_type = ?error.message_type,
+                field = ?error.field,
+                error = %error.message,
+                "Invalid message from backend"
+            );
+            state.metrics.protocol_error("backend", error.kind.as_str());
+            crate::outbound::post(state, BackendMessage::ProtocolError(error));
+            None
+        }
+    }
+}
+
+/// Ack the agent message an update came from
+fn ack_agent(state: &GatewayState, delivery: Option<Delivery>) {
+    if let Some(delivery) = delivery {
+        state.registry.ack(&delivery.agent_id, delivery.seq);
+    }
+}
+
+/// Handle a message from the backend
+async fn handle_backend_message(
+    msg: BackendToGatewayMessage,
+    state: &GatewayState,
+    pending: &mut Pending,
+) {
     match msg {
-        BackendToGatewayMessage::Command(payload) => {
+        BackendToGatewayMessage::Command(mut payload) => {
             debug!("Received command from backend");
 
-            // Route to specific agent or by labels
-            if let Some(agent_id) = payload.agent_id {
-                if let Err(e) = state.registry.send_command(&agent_id, payload.command).await {
-                    error!(error = %e, "Failed to send command to agent");
-                }
-            } else if let Some(labels) = payload.labels {
-                let results = state
-                    .registry
-                    .send_command_to_labels(&labels, payload.command)
-                    .await;
+            let correlation_id = payload.command.correlate().to_string();
+            let span = info_span!(
+                "route_command",
+                command_id = %payload.command.id,
+                command_type = %payload.command.command_type,
+                agent_id = ?payload.agent_id,
+                correlation_id = %correlation_id,
+            );
+            telemetry::set_parent(&span, payload.command.trace_context.as_ref());
 
-                for (agent_id, result) in results {
-                    if let Err(e) = result {
-                        error!(agent_id = %agent_id, error = %e, "Failed to send command");
-                    }
-                }
-            }
+            route_command(*payload, state).instrument(span).await;
         }
         BackendToGatewayMessage::Snapshot(payload) => {
             debug!(agent_id = %payload.agent_id, "Received snapshot for agent");
+            if !of_tenant(state, &payload.agent_id, payload.tenant_id.as_deref()) {
+                return;
+            }
 
-            // Forward snapshot to agent
-            // TODO: Implement snapshot forwarding
+            let message = GatewayToAgentMessage::Snapshot(payload.snapshot);
+            if let Err(e) = state.registry.try_send(&payload.agent_id, message) {
+                warn!(agent_id = %payload.agent_id, error = %e, "Failed to forward snapshot");
+            }
+        }
+        BackendToGatewayMessage::SnapshotDelta(payload) => {
+            debug!(agent_id = %payload.agent_id, "Received snapshot delta for agent");
+            if !of_tenant(state, &payload.agent_id, payload.tenant_id.as_deref()) {
+                return;
+            }
+
+            // A delta the agent never sees leaves it on the old version; it
+            // asks for a full snapshot when the next delta does not apply
+            let message = GatewayToAgentMessage::SnapshotDelta(payload.delta);
+            if let Err(e) = state.registry.try_send(&payload.agent_id, message) {
+                warn!(agent_id = %payload.agent_id, error = %e, "Failed to forward snapshot delta");
+            }
         }
         BackendToGatewayMessage::Ping => {
             debug!("Received ping from backend");
         }
+        BackendToGatewayMessage::Ack { seq } => {
+            // The backend has the updates; now the agents can drop them
+            for (_, delivery) in pending.ack(seq) {
+                ack_agent(state, delivery);
+            }
+        }
+        BackendToGatewayMessage::Registered(accepted) => {
+            debug!(accepted = ?accepted, "Late registration answer from backend");
+        }
+        BackendToGatewayMessage::AgentVersionPolicy(policy) => {
+            // Applies to agents as they register; connected ones stay
+            info!(
+                min_version = ?policy.min_version,
+                reject = policy.reject,
+                "Agent version policy updated by backend"
+            );
+            state.versions.set(policy.clone());
+            // Down the chain, gateways register agents too
+            state
+                .downstreams
+                .broadcast(&BackendToGatewayMessage::AgentVersionPolicy(policy));
+        }
+    }
+}
+
+/// Route a backend command to a specific agent or by labels
+pub(crate) async fn route_command(payload: CommandPayload, state: &GatewayState) {
+    let mut command = payload.command;
+    telemetry::inject(&Span::current(), &mut command.trace_context);
+
+    let requested_by = payload.requested_by.as_deref();
+    let tenant_id = command.tenant_id.clone();
+
+    // A backend mixing up its gateways must not reach this zone's agents
+    if let Some(ref zone) = payload.zone {
+        if !state.config.gateway.commands_zone(zone) {
+            let reason = format!(
+                "command is for zone '{}', this gateway is in zone '{}'",
+                zone, state.config.gateway.zone
+            );
+            let agent_id = payload.agent_id.as_deref().unwrap_or_default();
+            warn!(command_id = %command.id, zone = %zone, "Cross-zone command refused");
+            state.audit.record(&AuditEvent::denied(
+                &command,
+                agent_id,
+                requested_by,
+                &reason,
+            ));
+            reject_command(state, &command, agent_id, reason);
+            return;
+        }
+    }
+
+    if let Some(agent_id) = payload.agent_id {
+        // An agent on a peer gateway is served there
+        if state.registry.get(&agent_id).is_none()
+            && ha::forward(state, &agent_id, &command, requested_by, &payload.roles)
+        {
+            return;
+        }
+        let _ = dispatch(state, &command, &agent_id, requested_by, &payload.roles).await;
+    } else if let (Some(labels), Some(strategy)) = (&payload.labels, payload.strategy) {
+        let selector = AgentSelector {
+            agent_id: None,
+            labels: Some(labels.clone()),
+            strategy,
+        };
+        let agent = router::find_agent_for_component(
+            &state.registry,
+            &state.commands,
+            &command.component_id,
+            &selector,
+            tenant_id.as_deref(),
+        );
+        match agent {
+            Some(agent) => {
+                let _ = dispatch(state, &command, &agent.id, requested_by, &payload.roles).await;
+            }
+            None => {
+                warn!(
+                    command_id = %command.id,
+                    labels = %labels,
+                    "No agent here matches the command's labels"
+                );
+                let payload = CommandPayload { command, ..payload };
+                state.dead_letters.command(
+                    None,
+                    &payload,
+                    "no agent here matches the command's labels",
+                );
+                state.metrics.dead_lettered("command");
+            }
+        }
+    } else if let Some(ref group) = payload.group {
+        let Some(members) = state.groups.members(group, &state.registry) else {
+            warn!(command_id = %command.id, group = %group, "Command for a group not defined here");
+            let payload = CommandPayload { command, ..payload };
+            state
+                .dead_letters
+                .command(None, &payload, "no such group on this gateway");
+            state.metrics.dead_lettered("command");
+            return;
+        };
+        let targets = members
+            .into_iter()
+            .filter(|agent_id| {
+                state
+                    .registry
+                    .get(agent_id)
+                    .is_some_and(|agent| agent.of_tenant(tenant_id.as_deref()))
+            })
+            .collect();
+        let fan_out = FanOut::new(
+            command,
+            targets,
+            payload.rollout,
+            payload.requested_by,
+            payload.roles,
+        );
+        fanout::start(state, fan_out).await;
+    } else if let Some(labels) = payload.labels {
+        let targets = state
+            .registry
+            .find_matching(&labels)
+            .into_iter()
+            // Other tenants' agents are not even counted as targets
+            .filter(|agent| agent.of_tenant(tenant_id.as_deref()))
+            .map(|agent| agent.id)
+            .collect();
+        let fan_out = FanOut::new(
+            command,
+            targets,
+            payload.rollout,
+            payload.requested_by,
+            payload.roles,
+        );
+        fanout::start(state, fan_out).await;
     }
+}
 
-    Ok(())
+/// Keep a message the backend did not get
+/// A batch as sent to this backend: as is, or one response at a time to a
+/// backend without `response_batches`
+fn unbatched(batch: ResponseBatch, batching: bool) -> Vec<GatewayToBackendMessage> {
+    match batching {
+        true => vec![GatewayToBackendMessage::CommandResponses(batch)],
+        false => batch
+            .responses
+            .into_iter()
+            .map(GatewayToBackendMessage::CommandResponse)
+            .collect(),
+    }
+}
+
+/// Send messages that are not sequenced; false once the link failed, the
+/// messages left then being dead-lettered
+async fn send_all(
+    ws_sender: &mut SplitSink<BackendStream, Message>,
+    state: &GatewayState,
+    messages: Vec<GatewayToBackendMessage>,
+    compress_above: Option<usize>,
+) -> bool {
+    let mut link_failed: Option<String> = None;
+    for message in messages {
+        if let Some(ref reason) = link_failed {
+            dead_letter(state, &message, reason);
+            continue;
+        }
+        let frame = match encode(&message, None, compress_above) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!(error = %e, "Failed to encode message for the backend");
+                dead_letter(state, &message, &format!("not encodable: {}", e));
+                continue;
+            }
+        };
+        let started = std::time::Instant::now();
+        if let Err(e) = ws_sender.send(frame).await {
+            let reason = format!("backend link failed: {}", e);
+            dead_letter(state, &message, &reason);
+            link_failed = Some(reason);
+            continue;
+        }
+        state.metrics.observe_send("backend", started.elapsed());
+        state.metrics.message_forwarded(message.message_type());
+    }
+    link_failed.is_none()
+}
+
+fn dead_letter(state: &GatewayState, message: &GatewayToBackendMessage, reason: &str) {
+    state.dead_letters.backend_message(message, reason);
+    state.metrics.dead_lettered("backend_message");
+}
+
+/// Authorize a command for one agent and send it; fails with the reason
+/// when the agent is never going to run it
+pub(crate) async fn dispatch(
+    state: &GatewayState,
+    command: &AgentCommand,
+    agent_id: &str,
+    requested_by: Option<&str>,
+    roles: &[String],
+) -> Result<(), String> {
+    let principal = Principal {
+        name: requested_by,
+        roles,
+    };
+    let agent = state.registry.get(agent_id);
+    let authorized = authorize(
+        state,
+        agent.as_ref(),
+        &command.command_type,
+        command.tenant_id.as_deref(),
+        &principal,
+    );
+    if let Err(reason) = authorized {
+        warn!(agent_id = %agent_id, reason = %reason, "Command denied by policy");
+        state.audit.record(&AuditEvent::denied(
+            command,
+            agent_id,
+            requested_by,
+            &reason,
+        ));
+        reject_command(state, command, agent_id, reason.clone());
+        return Err(format!("Denied by gateway policy: {}", reason));
+    }
+
+    // Tracked before it is sent, so a quick answer finds it
+    state.commands.track(agent_id, command);
+    // Never wait on an agent's channel: the backend link would stall behind
+    // the one agent that is not reading
+    let result = state.registry.try_send_command(agent_id, command.clone());
+    let mut refused = None;
+    match result {
+        Ok(()) => {
+            info!(
+                agent_id = %agent_id,
+                command_id = %command.id,
+                correlation_id = command.correlation_id.as_deref(),
+                "Command routed to agent"
+            );
+            state.commands.delivered(agent_id, &command.id);
+        }
+        Err(ref e) => {
+            state.metrics.command_routing_failed();
+            error!(
+                agent_id = %agent_id,
+                command_id = %command.id,
+                correlation_id = command.correlation_id.as_deref(),
+                error = %e,
+                "Failed to send command to agent"
+            );
+            match state.registry.get(agent_id) {
+                // An agent away for now gets it when it registers again
+                None => {}
+                Some(agent) if agent.tx.is_none() => {}
+                Some(agent) if agent.quarantined => {
+                    state.commands.forget(agent_id, &command.id);
+                    refused = Some(e.clone());
+                }
+                // Connected, but its channel is full
+                Some(_) => {
+                    state.commands.forget(agent_id, &command.id);
+                    let payload = CommandPayload {
+                        agent_id: Some(agent_id.to_string()),
+                        labels: None,
+                        command: command.clone(),
+                        requested_by: requested_by.map(str::to_string),
+                        roles: roles.to_vec(),
+                        rollout: Default::default(),
+                        strategy: None,
+                        zone: None,
+                        group: None,
+                    };
+                    state.dead_letters.command(Some(agent_id), &payload, e);
+                    state.metrics.dead_lettered("command");
+                    fail_command(state, command, agent_id, e);
+                    refused = Some(e.clone());
+                }
+            }
+        }
+    }
+    state.audit.record(&AuditEvent::routed(
+        command,
+        agent_id,
+        requested_by,
+        result.err(),
+    ));
+    refused.map_or(Ok(()), Err)
+}
+
+/// Whether `principal` may have `command_type` run on an agent of this
+/// gateway for `tenant_id`: the tenant, the zone, then the RBAC policies
+pub(crate) fn authorize(
+    state: &GatewayState,
+    agent: Option<&AgentInfo>,
+    command_type: &str,
+    tenant_id: Option<&str>,
+    principal: &Principal,
+) -> Result<(), String> {
+    let gateway = &state.config.gateway;
+    match agent {
+        Some(agent) if !agent.of_tenant(tenant_id) => {
+            Err("agent belongs to another tenant".to_string())
+        }
+        Some(agent) if !gateway.commands_zone(agent.zone(&gateway.zone)) => Err(format!(
+            "agent is in zone '{}', outside the zones this gateway commands",
+            agent.zone(&gateway.zone)
+        )),
+        _ => {
+            let agent_labels = agent.map(|agent| agent.labels.clone()).unwrap_or_default();
+            state
+                .policy
+                .authorize(principal, command_type, &agent_labels)
+        }
+    }
+}
+
+/// Whether a snapshot of `tenant_id` may reach an agent; an agent not
+/// connected here is let through to the registry, which reports it
+fn of_tenant(state: &GatewayState, agent_id: &str, tenant_id: Option<&str>) -> bool {
+    let allowed = state
+        .registry
+        .get(agent_id)
+        .is_none_or(|agent| agent.of_tenant(tenant_id));
+    if !allowed {
+        warn!(agent_id = %agent_id, tenant_id = ?tenant_id, "Snapshot for another tenant dropped");
+    }
+    allowed
+}
+
+/// Report a denied command to the backend as a rejected command response
+fn reject_command(state: &GatewayState, command: &AgentCommand, agent_id: &str, reason: String) {
+    let response = serde_json::json!({
+        "job_id": command.id,
+        "agent_id": agent_id,
+        "status": "rejected",
+        "result": null,
+        "error": format!("Denied by gateway policy: {}", reason),
+        "timestamp": chrono::Utc::now(),
+    });
+    crate::outbound::post(state, BackendMessage::CommandResponse(response));
+}
+
+/// Report a command its agent could not be sent as a failed response
+fn fail_command(state: &GatewayState, command: &AgentCommand, agent_id: &str, error: &str) {
+    let response = serde_json::json!({
+        "job_id": command.id,
+        "agent_id": agent_id,
+        "status": "failed",
+        "result": null,
+        "error": error,
+        "timestamp": chrono::Utc::now(),
+    });
+    crate::outbound::post(state, BackendMessage::CommandResponse(response));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_server::{registered_agent, RegisterPayload};
+    use tokio::sync::mpsc;
+
+    fn gateway_state(allowed_zones: &[&str]) -> GatewayState {
+        let mut config = GatewayConfig::default();
+        config.gateway.zone = "prod".to_string();
+        config.gateway.allowed_zones = allowed_zones.iter().map(|z| z.to_string()).collect();
+        GatewayState::for_tests(config)
+    }
+
+    /// Connect an agent labeled with `zone`, if any
+    fn connect(
+        state: &GatewayState,
+        id: &str,
+        zone: Option<&str>,
+    ) -> mpsc::Receiver<GatewayToAgentMessage> {
+        let agent = registered_agent(RegisterPayload {
+            agent_id: id.to_string(),
+            hostname: id.to_string(),
+            labels: zone
+                .map(|z| ("zone".to_string(), z.to_string()))
+                .into_iter()
+                .collect(),
+            ip_addresses: Vec::new(),
+            version: "1.0.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 2,
+            capabilities: Vec::new(),
+        });
+        let (tx, rx) = mpsc::channel(4);
+        state.registry.register(agent, tx);
+        rx
+    }
+
+    fn command(agent_id: &str, zone: Option<&str>) -> CommandPayload {
+        serde_json::from_value(serde_json::json!({
+            "agent_id": agent_id,
+            "labels": null,
+            "zone": zone,
+            "command": {
+                "id": format!("cmd-{}", agent_id),
+                "command_type": "restart",
+                "component_id": "postgres",
+                "action_name": null,
+                "params": {},
+                "timeout_secs": 60,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cross_zone_commands() {
+        let state = gateway_state(&[]);
+        let mut backend = state.backend_tx.subscribe();
+        let mut local = connect(&state, "local", None);
+        let mut labeled = connect(&state, "labeled", Some("prod"));
+        let mut dmz = connect(&state, "dmz", Some("dmz"));
+
+        route_command(command("local", None), &state).await;
+        route_command(command("labeled", Some("prod")), &state).await;
+        assert!(matches!(
+            local.try_recv(),
+            Ok(GatewayToAgentMessage::Command(_))
+        ));
+        assert!(matches!(
+            labeled.try_recv(),
+            Ok(GatewayToAgentMessage::Command(_))
+        ));
+
+        // Meant for another zone, or reaching an agent of another zone
+        route_command(command("local", Some("staging")), &state).await;
+        route_command(command("dmz", None), &state).await;
+        assert!(local.try_recv().is_err());
+        assert!(dmz.try_recv().is_err());
+        for agent_id in ["local", "dmz"] {
+            match backend.try_recv() {
+                Ok(BackendMessage::CommandResponse(response)) => {
+                    assert_eq!(response["agent_id"], agent_id);
+                    assert_eq!(response["status"], "rejected");
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        let state = gateway_state(&["dmz"]);
+        let mut dmz = connect(&state, "dmz", Some("dmz"));
+        route_command(command("dmz", Some("dmz")), &state).await;
+        assert!(matches!(
+            dmz.try_recv(),
+            Ok(GatewayToAgentMessage::Command(_))
+        ));
+        assert!(state.config.gateway.commands_zone("prod"));
+        assert!(!state.config.gateway.commands_zone("staging"));
+    }
+
+    #[tokio::test]
+    async fn test_full_agent_channel() {
+        let state = gateway_state(&[]);
+        let mut backend = state.backend_tx.subscribe();
+        let _agent = connect(&state, "slow", None);
+
+        // The link is never held up by an agent that does not read
+        for n in 0..5 {
+            let mut payload = command("slow", None);
+            payload.command.id = format!("cmd-{}", n);
+            route_command(payload, &state).await;
+        }
+        match backend.try_recv() {
+            Ok(BackendMessage::CommandResponse(response)) => {
+                assert_eq!(response["job_id"], "cmd-4");
+                assert_eq!(response["status"], "failed");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert_eq!(state.commands.for_agent("slow").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_batched_check_responses() {
+        let state = gateway_state(&[]);
+        let mut backend = state.backend_tx.subscribe();
+        let _a = connect(&state, "a", Some("prod"));
+        let _b = connect(&state, "b", Some("prod"));
+        let mut payload = command("a", None);
+        payload.agent_id = None;
+        payload.labels = Some(Selector::parse("zone=prod").unwrap());
+        payload.command.command_type = "check".to_string();
+        route_command(payload, &state).await;
+
+        let queue = state.outbound.connection("agent");
+        for agent_id in ["a", "b"] {
+            let response =
+                serde_json::json!({"job_id": "cmd-a", "agent_id": agent_id, "status": "completed"});
+            crate::agent_server::command_response(&state, agent_id, &queue, response).await;
+            match backend.try_recv() {
+                Ok(BackendMessage::BatchedResponse { job_id, response }) => {
+                    assert_eq!(job_id, "cmd-a");
+                    assert_eq!(response["agent_id"], agent_id);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert!(matches!(
+            backend.try_recv(),
+            Ok(BackendMessage::CommandSummary(_))
+        ));
+
+        // A backend without response_batches gets them one by one
+        let batch = ResponseBatch {
+            job_id: "cmd-a".to_string(),
+            responses: vec![
+                serde_json::json!({"agent_id": "a"}),
+                serde_json::json!({"agent_id": "b"}),
+            ],
+        };
+        assert_eq!(unbatched(batch.clone(), true).len(), 1);
+        let single = unbatched(batch, false);
+        assert!(matches!(
+            single[1],
+            GatewayToBackendMessage::CommandResponse(ref r) if r["agent_id"] == "b"
+        ));
+    }
+
+    #[test]
+    fn test_sequenced_frame() {
+        let message = GatewayToBackendMessage::StatusUpdate(serde_json::json!({"status": "ok"}));
+        let frame: serde_json::Value = match encode(&message, Some(3), None).unwrap() {
+            Message::Text(json) => serde_json::from_str(&json).unwrap(),
+            other => panic!("unexpected frame: {:?}", other),
+        };
+        assert_eq!(frame["type"], "status_update");
+        assert_eq!(frame["payload"]["status"], "ok");
+        assert_eq!(frame["seq"], 3);
+
+        let frame = encode(&GatewayToBackendMessage::Pong, None, Some(1024)).unwrap();
+        assert_eq!(frame, Message::Text(r#"{"type":"pong"}"#.to_string()));
+
+        let frame = encode(&message, Some(3), Some(8)).unwrap();
+        assert!(matches!(frame, Message::Binary(_)));
+        let json = protocol::inflate(frame.into_data()).unwrap();
+        assert!(json.starts_with(br#"{"type":"status_update""#));
+
+        let ack: BackendToGatewayMessage =
+            serde_json::from_str(r#"{"type":"ack","payload":{"seq":3}}"#).unwrap();
+        assert!(matches!(ack, BackendToGatewayMessage::Ack { seq: 3 }));
+    }
 }
@@ -0,0 +1,499 @@
+//! opsmap-backend-sim - Stand-in backend for local development and demos
+//!
+//! Accepts gateways on `ws://<listen>/gateway`, sends each agent that
+//! connects (or asks for one) the snapshot found in the snapshot
+//! directory, prints what the gateways send, and reads commands from stdin:
+//!
+//! ```text
+//! opsmap-backend-sim --listen 127.0.0.1:8080 --snapshots ./snapshots
+//! # gateway.yaml: backend: {url: "ws://127.0.0.1:8080/gateway"}
+//! ```
+//!
+//! An agent's snapshot is `<agent id>.yaml`, else `<hostname>.yaml`, else
+//! `default.yaml`, read when it is sent:
+//!
+//! ```yaml
+//! version: 1
+//! components:
+//!   - id: postgres
+//!     name: PostgreSQL
+//!     component_type: database
+//!     checks:
+//!       - {name: port, check_type: tcp_port, config: {port: 5432}, interval_secs: 30, timeout_secs: 5}
+//!     actions:
+//!       - {name: restart, command: systemctl, args: [restart, postgresql]}
+//! ```
+//!
+//! Status updates are acked as soon as they are printed. Nothing is kept
+//! across restarts.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Protocol version spoken; acks are the only capability taken
+const PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Parser, Debug)]
+#[command(name = "opsmap-backend-sim")]
+#[command(about = "Stand-in OpsMap backend for gateways, for development and demos")]
+#[command(version)]
+struct Args {
+    /// Address gateways connect to
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Directory of agent snapshots (<agent id>.yaml, <hostname>.yaml, default.yaml)
+    #[arg(long, default_value = "snapshots")]
+    snapshots: PathBuf,
+
+    /// Print every frame from the gateways as received
+    #[arg(long)]
+    raw: bool,
+}
+
+/// A connected agent, and the gateway it is on
+#[derive(Debug, Clone)]
+struct Agent {
+    gateway_id: String,
+    hostname: String,
+    labels: BTreeMap<String, String>,
+}
+
+/// Gateways and agents currently connected
+struct Sim {
+    snapshots: PathBuf,
+    raw: bool,
+    gateways: Mutex<HashMap<String, mpsc::UnboundedSender<Value>>>,
+    agents: Mutex<BTreeMap<String, Agent>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    println!(
+        "Listening on ws://{}/gateway; type `help` for commands",
+        args.listen
+    );
+
+    let sim = Arc::new(Sim {
+        snapshots: args.snapshots,
+        raw: args.raw,
+        gateways: Mutex::new(HashMap::new()),
+        agents: Mutex::new(BTreeMap::new()),
+    });
+
+    tokio::spawn(console(sim.clone()));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let sim = sim.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gateway(&sim, stream).await {
+                println!("[{}] {:#}", addr, e);
+            }
+        });
+    }
+}
+
+/// Refuses WebSocket upgrades on other paths than `/gateway`
+struct GatewayPath;
+
+impl Callback for GatewayPath {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        if request.uri().path() == "/gateway" {
+            return Ok(response);
+        }
+        let mut refused = ErrorResponse::new(Some("not found".to_string()));
+        *refused.status_mut() = StatusCode::NOT_FOUND;
+        Err(refused)
+    }
+}
+
+/// Serve one gateway connection until it closes
+async fn gateway(sim: &Sim, stream: TcpStream) -> Result<()> {
+    let ws = tokio_tungstenite::accept_hdr_async(stream, GatewayPath).await?;
+    let (mut ws_sender, mut ws_receiver) = ws.split();
+
+    // The first message registers the gateway
+    let register = match ws_receiver.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<Value>(&text)?,
+        _ => bail!("Closed before registering"),
+    };
+    if register["type"] != "register" {
+        bail!("Expected a registration, got {}", register["type"]);
+    }
+    let payload = &register["payload"];
+    let gateway_id = str_of(payload, "gateway_id");
+    let acks = payload["capabilities"]
+        .as_array()
+        .is_some_and(|c| c.iter().any(|c| c == "acks"));
+    println!(
+        "Gateway {} registered (zone {}, version {})",
+        gateway_id,
+        str_of(payload, "zone"),
+        str_of(payload, "version")
+    );
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    tx.send(json!({
+        "type": "registered",
+        "payload": {
+            "protocol_version": PROTOCOL_VERSION,
+            "capabilities": if acks { vec!["acks"] } else { vec![] },
+        },
+    }))?;
+    sim.gateways
+        .lock()
+        .unwrap()
+        .insert(gateway_id.clone(), tx.clone());
+    for agent in payload["agents"].as_array().into_iter().flatten() {
+        agent_connected(sim, &gateway_id, agent, &tx);
+    }
+
+    let result = loop {
+        tokio::select! {
+            outgoing = rx.recv() => {
+                let Some(message) = outgoing else { break Ok(()) };
+                if let Err(e) = ws_sender.send(Message::Text(message.to_string())).await {
+                    break Err(e.into());
+                }
+            }
+            incoming = ws_receiver.next() => match incoming {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<Value>(&text) {
+                    Ok(frame) => {
+                        if sim.raw {
+                            println!("<- {}", text);
+                        }
+                        handle(sim, &gateway_id, &frame, &tx);
+                    }
+                    Err(e) => println!("[{}] Invalid frame: {}", gateway_id, e),
+                },
+                Some(Ok(Message::Ping(data))) => {
+                    let _ = ws_sender.send(Message::Pong(data)).await;
+                }
+                Some(Ok(Message::Close(_))) | None => break Ok(()),
+                Some(Err(e)) => break Err(e.into()),
+                Some(Ok(_)) => {}
+            }
+        }
+    };
+
+    println!("Gateway {} disconnected", gateway_id);
+    sim.gateways.lock().unwrap().remove(&gateway_id);
+    sim.agents
+        .lock()
+        .unwrap()
+        .retain(|_, agent| agent.gateway_id != gateway_id);
+    result
+}
+
+/// Print a message from a gateway and answer what needs answering
+fn handle(sim: &Sim, gateway_id: &str, frame: &Value, tx: &mpsc::UnboundedSender<Value>) {
+    let payload = &frame["payload"];
+    match frame["type"].as_str().unwrap_or_default() {
+        "agent_connected" => agent_connected(sim, gateway_id, payload, tx),
+        "agent_metadata" => {
+            remember(sim, gateway_id, payload);
+            println!("[{}] Agent {} updated", gateway_id, str_of(payload, "id"));
+        }
+        "agent_disconnected" => {
+            let agent_id = str_of(payload, "agent_id");
+            sim.agents.lock().unwrap().remove(&agent_id);
+            println!("[{}] Agent {} disconnected", gateway_id, agent_id);
+        }
+        "status_update" => println!(
+            "[{}] {}/{}: {}{}",
+            gateway_id,
+            str_of(payload, "component_id"),
+            str_of(payload, "check_name"),
+            str_of(payload, "status"),
+            payload["message"]
+                .as_str()
+                .map(|m| format!(" ({})", m))
+                .unwrap_or_default()
+        ),
+        "command_response" => {
+            let mut line = format!(
+                "[{}] Command {} on {}: {}",
+                gateway_id,
+                str_of(payload, "job_id"),
+                str_of(payload, "agent_id"),
+                str_of(payload, "status")
+            );
+            if let Some(error) = payload["error"].as_str() {
+                line.push_str(&format!(" - {}", error));
+            } else if !payload["result"].is_null() {
+                line.push_str(&format!(" {}", payload["result"]));
+            }
+            println!("{}", line);
+        }
+        "command_timeout" => println!(
+            "[{}] Command {} on {} timed out",
+            gateway_id,
+            str_of(payload, "job_id"),
+            str_of(payload, "agent_id")
+        ),
+        "command_summary" => println!("[{}] Command summary: {}", gateway_id, payload),
+        "job_update" => println!("[{}] Job update: {}", gateway_id, payload),
+        "discovery" | "inventory" => println!(
+            "[{}] {} from {}",
+            gateway_id,
+            frame["type"].as_str().unwrap_or_default(),
+            str_of(payload, "agent_id")
+        ),
+        "snapshot_request" => {
+            let agent_id = str_of(payload, "agent_id");
+            println!("[{}] Agent {} asked for its snapshot", gateway_id, agent_id);
+            send_snapshot(sim, &agent_id, tx);
+        }
+        "pong" => {}
+        other => println!("[{}] Unhandled {}", gateway_id, other),
+    }
+
+    if let Some(seq) = frame["seq"].as_u64() {
+        let _ = tx.send(json!({"type": "ack", "payload": {"seq": seq}}));
+    }
+}
+
+fn agent_connected(sim: &Sim, gateway_id: &str, info: &Value, tx: &mpsc::UnboundedSender<Value>) {
+    remember(sim, gateway_id, info);
+    let agent_id = str_of(info, "id");
+    println!(
+        "[{}] Agent {} connected ({}, {} {})",
+        gateway_id,
+        agent_id,
+        str_of(info, "hostname"),
+        str_of(info, "os"),
+        str_of(info, "version")
+    );
+    send_snapshot(sim, &agent_id, tx);
+}
+
+fn remember(sim: &Sim, gateway_id: &str, info: &Value) {
+    let labels = info["labels"]
+        .as_object()
+        .map(|l| {
+            l.iter()
+                .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    sim.agents.lock().unwrap().insert(
+        str_of(info, "id"),
+        Agent {
+            gateway_id: gateway_id.to_string(),
+            hostname: str_of(info, "hostname"),
+            labels,
+        },
+    );
+}
+
+/// Send an agent its snapshot from the directory, if there is one
+fn send_snapshot(sim: &Sim, agent_id: &str, tx: &mpsc::UnboundedSender<Value>) {
+    let hostname = sim
+        .agents
+        .lock()
+        .unwrap()
+        .get(agent_id)
+        .map(|a| a.hostname.clone());
+    let snapshot = match load_snapshot(&sim.snapshots, agent_id, hostname.as_deref()) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            println!(
+                "  no snapshot for {} in {}",
+                agent_id,
+                sim.snapshots.display()
+            );
+            return;
+        }
+        Err(e) => {
+            println!("  snapshot for {} not sent: {:#}", agent_id, e);
+            return;
+        }
+    };
+    let _ = tx.send(json!({
+        "type": "snapshot",
+        "payload": {"agent_id": agent_id, "snapshot": snapshot},
+    }));
+}
+
+/// The first of `<agent id>.yaml`, `<hostname>.yaml` and `default.yaml`
+fn load_snapshot(dir: &Path, agent_id: &str, hostname: Option<&str>) -> Result<Option<Value>> {
+    let candidates = [Some(agent_id), hostname, Some("default")];
+    let Some(path) = candidates
+        .into_iter()
+        .flatten()
+        .map(|name| dir.join(format!("{}.yaml", name)))
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut snapshot: Value = serde_yaml::from_str(&text)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    if snapshot.get("version").is_none() {
+        snapshot["version"] = json!(1);
+    }
+    if snapshot.get("components").is_none() {
+        snapshot["components"] = json!([]);
+    }
+    Ok(Some(snapshot))
+}
+
+const HELP: &str = "\
+agents                                      list connected agents
+command <agent|labels:SEL> <type> <component> [action=NAME] [key=value...]
+                                            send a command (start, stop, restart, action...)
+snapshot <agent>                            send an agent its snapshot again
+help                                        this list
+quit                                        stop";
+
+/// Read commands from stdin until it closes
+async fn console(sim: Arc<Sim>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["help"] => println!("{}", HELP),
+            ["quit" | "exit"] => std::process::exit(0),
+            ["agents"] => {
+                for (id, agent) in sim.agents.lock().unwrap().iter() {
+                    let labels: Vec<String> = agent
+                        .labels
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, v))
+                        .collect();
+                    println!(
+                        "{}  {}  via {}  {}",
+                        id,
+                        agent.hostname,
+                        agent.gateway_id,
+                        labels.join(",")
+                    );
+                }
+            }
+            ["snapshot", agent_id] => {
+                match gateway_of(&sim, agent_id) {
+                    Some(tx) => send_snapshot(&sim, agent_id, &tx),
+                    None => println!("Agent {} is not connected", agent_id),
+                };
+            }
+            ["command", target, command_type, component_id, rest @ ..] => {
+                if let Err(e) = command(&sim, target, command_type, component_id, rest) {
+                    println!("{:#}", e);
+                }
+            }
+            _ => println!("Unknown command; type `help`"),
+        }
+    }
+}
+
+/// Send a command to an agent's gateway, or by labels to every gateway
+fn command(
+    sim: &Sim,
+    target: &str,
+    command_type: &str,
+    component_id: &str,
+    rest: &[&str],
+) -> Result<()> {
+    let mut action_name = None;
+    let mut params = serde_json::Map::new();
+    for word in rest {
+        let (key, value) = word
+            .split_once('=')
+            .with_context(|| format!("'{}' is not key=value", word))?;
+        if key == "action" {
+            action_name = Some(value.to_string());
+        } else {
+            params.insert(key.to_string(), json!(value));
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut payload = json!({
+        "agent_id": null,
+        "labels": null,
+        "command": {
+            "id": id,
+            "command_type": command_type,
+            "component_id": component_id,
+            "action_name": action_name,
+            "params": params,
+            "timeout_secs": 300,
+        },
+        "requested_by": "backend-sim",
+    });
+    let gateways: Vec<mpsc::UnboundedSender<Value>> = match target.strip_prefix("labels:") {
+        Some(selector) => {
+            payload["labels"] = json!(selector);
+            sim.gateways.lock().unwrap().values().cloned().collect()
+        }
+        None => {
+            payload["agent_id"] = json!(target);
+            let tx = gateway_of(sim, target)
+                .with_context(|| format!("Agent {} is not connected", target))?;
+            vec![tx]
+        }
+    };
+
+    for tx in gateways {
+        let _ = tx.send(json!({"type": "command", "payload": payload}));
+    }
+    println!("Command {} sent", id);
+    Ok(())
+}
+
+fn gateway_of(sim: &Sim, agent_id: &str) -> Option<mpsc::UnboundedSender<Value>> {
+    let gateway_id = sim.agents.lock().unwrap().get(agent_id)?.gateway_id.clone();
+    sim.gateways.lock().unwrap().get(&gateway_id).cloned()
+}
+
+fn str_of(value: &Value, field: &str) -> String {
+    value[field].as_str().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_snapshot() {
+        let dir = std::env::temp_dir().join(format!("opsmap-sim-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(load_snapshot(&dir, "agent-1", Some("db-01"))
+            .unwrap()
+            .is_none());
+
+        std::fs::write(dir.join("default.yaml"), "components: []\n").unwrap();
+        std::fs::write(dir.join("db-01.yaml"), "version: 7\ncomponents: []\n").unwrap();
+        let by_hostname = load_snapshot(&dir, "agent-1", Some("db-01"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_hostname["version"], 7);
+        let default = load_snapshot(&dir, "agent-2", Some("web-01"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(default["version"], 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
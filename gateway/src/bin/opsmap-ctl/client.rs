@@ -0,0 +1,113 @@
+//! Gateway API client
+
+use anyhow::{bail, Context, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use std::path::Path;
+use url::Url;
+
+#[path = "../../tls/client.rs"]
+mod tls;
+
+/// The operator API of one gateway
+pub struct Client {
+    base: Url,
+    token: Option<String>,
+    http: hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl Client {
+    pub fn new(gateway: &str, token: Option<String>, ca_file: Option<&Path>) -> Result<Self> {
+        let base =
+            Url::parse(gateway).with_context(|| format!("Invalid gateway URL: {}", gateway))?;
+        if !matches!(base.scheme(), "https" | "http") {
+            bail!("Gateway URL must be http:// or https://");
+        }
+        let ca_file = ca_file.map(|path| path.to_string_lossy());
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls::client_config(ca_file.as_deref(), None)?)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let http =
+            hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(connector);
+        Ok(Self { base, token, http })
+    }
+
+    /// The URL of an endpoint, with its query
+    pub fn url(&self, path: &str, query: &[(&str, Option<&str>)]) -> Result<Url> {
+        let mut url = self.base.join(path)?;
+        for (name, value) in query {
+            if let Some(value) = value {
+                url.query_pairs_mut().append_pair(name, value);
+            }
+        }
+        Ok(url)
+    }
+
+    /// GET a JSON document
+    pub async fn get(&self, url: Url) -> Result<serde_json::Value> {
+        let response = self.send(Method::GET, url, None).await?;
+        json(response).await
+    }
+
+    /// POST a JSON document, returning the status and the JSON answer
+    pub async fn post(
+        &self,
+        url: Url,
+        body: &serde_json::Value,
+    ) -> Result<(StatusCode, serde_json::Value)> {
+        let response = self.send(Method::POST, url, Some(body.to_string())).await?;
+        let status = response.status();
+        Ok((status, json(response).await?))
+    }
+
+    /// GET a response whose body is read as it comes
+    pub async fn stream(&self, url: Url) -> Result<Incoming> {
+        let response = self.send(Method::GET, url, None).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            bail!("{}: {}", status, String::from_utf8_lossy(&body).trim());
+        }
+        Ok(response.into_body())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<String>,
+    ) -> Result<Response<Incoming>> {
+        let mut request = Request::builder().method(method).uri(url.as_str()).header(
+            header::USER_AGENT,
+            concat!("opsmap-ctl/", env!("CARGO_PKG_VERSION")),
+        );
+        if let Some(ref token) = self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let request = request.body(Full::new(Bytes::from(body.unwrap_or_default())))?;
+
+        self.http
+            .request(request)
+            .await
+            .with_context(|| format!("Failed to reach {}", self.base))
+    }
+}
+
+/// The JSON body of a response, or its error
+async fn json(response: Response<Incoming>) -> Result<serde_json::Value> {
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        bail!("{}: {}", status, String::from_utf8_lossy(&body).trim());
+    }
+    serde_json::from_slice(&body).context("Invalid JSON from the gateway")
+}
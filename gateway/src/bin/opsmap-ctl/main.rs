@@ -0,0 +1,448 @@
+//! opsmap-ctl - Operator CLI for the gateway API
+//!
+//! Lists a zone's agents and their latest check statuses, sends commands to
+//! an agent or to the agents matching a label selector, and tails the
+//! gateway's event stream:
+//!
+//! ```text
+//! export OPSMAP_GATEWAY=https://gw-1.dmz:8443 OPSMAP_TOKEN=... OPSMAP_CA_FILE=/etc/opsmap/certs/ca.crt
+//! opsmap-ctl agents --labels 'role=database'
+//! opsmap-ctl status agent-1
+//! opsmap-ctl command restart postgres --labels 'role=database,env=prod'
+//! opsmap-ctl events --zone dmz
+//! ```
+//!
+//! Reading needs a token with the `read` scope, `command` the `command`
+//! scope; the gateway audits commands under the token's name.
+
+mod client;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use std::path::PathBuf;
+
+use client::Client;
+
+#[derive(Parser, Debug)]
+#[command(name = "opsmap-ctl")]
+#[command(about = "OpsMap gateway operator CLI")]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Gateway base URL
+    #[arg(
+        long,
+        env = "OPSMAP_GATEWAY",
+        default_value = "https://localhost:8443",
+        global = true
+    )]
+    gateway: String,
+
+    /// API token
+    #[arg(long, env = "OPSMAP_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+
+    /// CA certificate of the gateway, when not trusted by the system
+    #[arg(long, env = "OPSMAP_CA_FILE", global = true)]
+    ca_file: Option<PathBuf>,
+
+    /// Print the gateway's JSON instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// List connected agents
+    Agents(Filters),
+    /// Show the latest check statuses of an agent, or of matching agents
+    Status {
+        agent: Option<String>,
+        #[command(flatten)]
+        filters: Filters,
+    },
+    /// Send a command and wait for the responses
+    Command {
+        /// Command type (start, stop, restart, action...)
+        command_type: String,
+        component_id: String,
+        /// Agent to run it on
+        #[arg(long, conflicts_with = "labels", required_unless_present = "labels")]
+        agent: Option<String>,
+        /// Run it on every agent matching this label selector
+        #[arg(long)]
+        labels: Option<String>,
+        /// Action name, for `action` commands
+        #[arg(long)]
+        action: Option<String>,
+        /// Parameter, as key=value (repeatable)
+        #[arg(long = "param", value_parser = parse_param)]
+        params: Vec<(String, String)>,
+        /// Seconds the agent may take
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+        /// Seconds to wait for each response; 0 returns once sent
+        #[arg(long, default_value_t = 60)]
+        wait: u64,
+    },
+    /// Print agent and status events as they happen
+    Events(Filters),
+}
+
+/// Agent filters, as `GET /agents` takes them
+#[derive(Args, Debug)]
+struct Filters {
+    /// Label selector (k=v, k!=v, k in (a,b), ...)
+    #[arg(long)]
+    labels: Option<String>,
+    #[arg(long)]
+    zone: Option<String>,
+    /// Hostname glob
+    #[arg(long)]
+    hostname: Option<String>,
+}
+
+impl Filters {
+    fn query(&self) -> [(&str, Option<&str>); 3] {
+        [
+            ("labels", self.labels.as_deref()),
+            ("zone", self.zone.as_deref()),
+            ("hostname", self.hostname.as_deref()),
+        ]
+    }
+}
+
+fn parse_param(param: &str) -> Result<(String, String), String> {
+    param
+        .split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("'{}' is not key=value", param))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let client = Client::new(&cli.gateway, cli.token.clone(), cli.ca_file.as_deref())?;
+
+    match cli.command {
+        Commands::Agents(ref filters) => agents(&client, filters, cli.json).await,
+        Commands::Status {
+            ref agent,
+            ref filters,
+        } => status(&client, agent.as_deref(), filters, cli.json).await,
+        Commands::Command {
+            ref command_type,
+            ref component_id,
+            ref agent,
+            ref labels,
+            ref action,
+            ref params,
+            timeout,
+            wait,
+        } => {
+            let targets = match agent {
+                Some(agent) => vec![agent.clone()],
+                None => {
+                    let filters = Filters {
+                        labels: labels.clone(),
+                        zone: None,
+                        hostname: None,
+                    };
+                    list(&client, &filters)
+                        .await?
+                        .iter()
+                        .map(|a| str_of(a, "id"))
+                        .collect()
+                }
+            };
+            if targets.is_empty() {
+                bail!("No connected agent matches");
+            }
+            let body = serde_json::json!({
+                "command_type": command_type,
+                "component_id": component_id,
+                "action_name": action,
+                "params": params
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                    .collect::<serde_json::Map<String, Value>>(),
+                "timeout_secs": timeout,
+                "wait_secs": wait,
+            });
+            command(&client, &targets, &body, cli.json).await
+        }
+        Commands::Events(ref filters) => events(&client, filters, cli.json).await,
+    }
+}
+
+async fn list(client: &Client, filters: &Filters) -> Result<Vec<Value>> {
+    let agents = client.get(client.url("agents", &filters.query())?).await?;
+    Ok(agents.as_array().cloned().unwrap_or_default())
+}
+
+async fn agents(client: &Client, filters: &Filters, json: bool) -> Result<()> {
+    let agents = list(client, filters).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&agents)?);
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 5]> = agents
+        .iter()
+        .map(|agent| {
+            let mut labels: Vec<String> = agent["labels"]
+                .as_object()
+                .map(|l| {
+                    l.iter()
+                        .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            labels.sort();
+            [
+                str_of(agent, "id"),
+                str_of(agent, "hostname"),
+                str_of(agent, "version"),
+                age(agent["last_heartbeat"].as_str()),
+                labels.join(","),
+            ]
+        })
+        .collect();
+    table(["ID", "HOSTNAME", "VERSION", "HEARTBEAT", "LABELS"], &rows);
+    Ok(())
+}
+
+async fn status(client: &Client, agent: Option<&str>, filters: &Filters, json: bool) -> Result<()> {
+    let ids: Vec<String> = match agent {
+        Some(agent) => vec![agent.to_string()],
+        None => list(client, filters)
+            .await?
+            .iter()
+            .map(|a| str_of(a, "id"))
+            .collect(),
+    };
+
+    let mut details = Vec::new();
+    for id in ids {
+        details.push(
+            client
+                .get(client.url(&format!("agents/{}", id), &[])?)
+                .await?,
+        );
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&details)?);
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 5]> = details
+        .iter()
+        .flat_map(|agent| {
+            let checks = agent["checks"].as_array().cloned().unwrap_or_default();
+            checks.into_iter().map(move |check| {
+                [
+                    str_of(agent, "hostname"),
+                    format!(
+                        "{}/{}",
+                        str_of(&check, "component_id"),
+                        str_of(&check, "check_name")
+                    ),
+                    str_of(&check, "status"),
+                    age(check["updated_at"].as_str()),
+                    str_of(&check, "message"),
+                ]
+            })
+        })
+        .collect();
+    table(["HOSTNAME", "CHECK", "STATUS", "UPDATED", "MESSAGE"], &rows);
+    Ok(())
+}
+
+async fn command(client: &Client, targets: &[String], body: &Value, json: bool) -> Result<()> {
+    let sends = targets.iter().map(|agent_id| async move {
+        let url = client.url(&format!("agents/{}/command", agent_id), &[])?;
+        client.post(url, body).await
+    });
+    let results = futures_util::future::join_all(sends).await;
+
+    let mut failed = 0;
+    for (agent_id, result) in targets.iter().zip(results) {
+        let line = match result {
+            Ok((status, response)) if json => {
+                if status != hyper::StatusCode::OK || response["status"] != "completed" {
+                    failed += 1;
+                }
+                serde_json::json!({"agent_id": agent_id, "response": response}).to_string()
+            }
+            Ok((hyper::StatusCode::ACCEPTED, response)) => {
+                format!(
+                    "{}: sent as {}, no response yet",
+                    agent_id,
+                    str_of(&response, "job_id")
+                )
+            }
+            Ok((_, response)) => {
+                let status = str_of(&response, "status");
+                if status != "completed" {
+                    failed += 1;
+                }
+                let detail = match response.get("error").and_then(Value::as_str) {
+                    Some(error) => error.to_string(),
+                    None => match response.get("result") {
+                        Some(Value::Null) | None => String::new(),
+                        Some(result) => result.to_string(),
+                    },
+                };
+                format!("{}: {} {}", agent_id, status, detail)
+                    .trim_end()
+                    .to_string()
+            }
+            Err(e) => {
+                failed += 1;
+                format!("{}: {:#}", agent_id, e)
+            }
+        };
+        println!("{}", line);
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} agents did not complete the command",
+            failed,
+            targets.len()
+        );
+    }
+    Ok(())
+}
+
+async fn events(client: &Client, filters: &Filters, json: bool) -> Result<()> {
+    let mut body = client
+        .stream(client.url("events", &filters.query())?)
+        .await?;
+    let mut buffer = String::new();
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.context("Event stream interrupted")?;
+        let Some(data) = frame.data_ref() else {
+            continue;
+        };
+        buffer.push_str(&String::from_utf8_lossy(data));
+
+        while let Some(end) = buffer.find("\n\n") {
+            let message: String = buffer.drain(..end + 2).collect();
+            let mut kind = "";
+            let mut data = String::new();
+            for line in message.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    kind = value.trim();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push_str(value.trim_start());
+                }
+            }
+            // Keep-alives are comments, with neither
+            if data.is_empty() {
+                continue;
+            }
+            if json {
+                println!("{}", data);
+            } else if kind == "lagged" {
+                eprintln!("{} events missed, the stream could not keep up", data);
+            } else {
+                println!("{}", describe(kind, &serde_json::from_str(&data)?));
+            }
+        }
+    }
+    bail!("The gateway closed the event stream")
+}
+
+/// One line for an event
+fn describe(kind: &str, event: &Value) -> String {
+    let agent = &event["agent"];
+    let mut line = format!(
+        "{} {:<18} {} ({})",
+        str_of(event, "timestamp"),
+        kind,
+        str_of(agent, "hostname"),
+        str_of(agent, "id")
+    );
+    if let Some(check) = event.get("check").filter(|c| !c.is_null()) {
+        line.push_str(&format!(
+            " {}/{} {} -> {}",
+            str_of(check, "component_id"),
+            str_of(check, "check_name"),
+            check["previous"].as_str().unwrap_or("-"),
+            str_of(check, "status")
+        ));
+        if let Some(message) = check["message"].as_str() {
+            line.push_str(&format!(": {}", message));
+        }
+    }
+    line
+}
+
+fn str_of(value: &Value, field: &str) -> String {
+    value[field].as_str().unwrap_or_default().to_string()
+}
+
+/// How long ago a timestamp was, as `42s`, `5m` or `3h`
+fn age(timestamp: Option<&str>) -> String {
+    let Some(at) = timestamp.and_then(|t| t.parse::<DateTime<Utc>>().ok()) else {
+        return "-".to_string();
+    };
+    let secs = (Utc::now() - at).num_seconds().max(0);
+    match secs {
+        0..=119 => format!("{}s", secs),
+        120..=7199 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+fn table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print(header.to_vec());
+    for row in rows {
+        print(row.iter().map(String::as_str).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let event = serde_json::json!({
+            "type": "status_changed",
+            "agent": {"id": "agent-1", "hostname": "db-01"},
+            "check": {"component_id": "postgres", "check_name": "port", "previous": "ok", "status": "error", "message": "refused"},
+            "timestamp": "2024-01-01T00:00:00Z",
+        });
+        assert_eq!(
+            describe("status_changed", &event),
+            "2024-01-01T00:00:00Z status_changed     db-01 (agent-1) postgres/port ok -> error: refused"
+        );
+        assert_eq!(
+            parse_param("env=prod=1").unwrap(),
+            ("env".into(), "prod=1".into())
+        );
+        assert!(parse_param("env").is_err());
+    }
+}
@@ -0,0 +1,578 @@
+//! opsmap-loadgen - Simulated agent fleet for gateway load tests
+//!
+//! Opens `--agents` WebSocket connections to a gateway, each registering as
+//! an agent and sending status deltas at `--rate` per second, while
+//! `--churn` connections per second are closed and opened again. Every
+//! `--report-secs` it prints the fleet's throughput and the latency of the
+//! gateway's acks, then a summary for the whole run:
+//!
+//! ```text
+//! opsmap-loadgen --gateway wss://gw-1.dmz:8443/ws --agents 10000 --rate 0.2 --churn 5 \
+//!     --cert-file agent.crt --key-file agent.key --ca-file ca.crt --duration-secs 300
+//! ```
+//!
+//! Deltas carry a sequence number, acked by the gateway once the backend
+//! has them (at once when the backend does not ack): the latency measured
+//! is the whole agent → gateway → backend path, so run it against a
+//! backend, e.g. `opsmap-backend-sim`. Deltas still unacked when their
+//! connection closes are counted as such. Commands are answered
+//! `completed` at once.
+//!
+//! Each connection is a file descriptor: raise `ulimit -n` on both sides,
+//! and the gateway's `limits` (`max_connections`,
+//! `connections_per_ip_per_min`) for large fleets.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+
+#[path = "../../tls/client.rs"]
+mod tls;
+
+/// Protocol version spoken; acks are the only capability asked for
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Deltas awaiting an ack on one connection, beyond which the oldest are
+/// given up on (a gateway whose backend is away never acks)
+const MAX_UNACKED: usize = 10_000;
+
+/// How long a connection may take to be registered
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser, Debug)]
+#[command(name = "opsmap-loadgen")]
+#[command(about = "Simulated OpsMap agent fleet, for gateway load tests")]
+#[command(version)]
+struct Args {
+    /// Gateway agent endpoint
+    #[arg(long, default_value = "ws://127.0.0.1:8443/ws")]
+    gateway: String,
+
+    /// Simulated agents
+    #[arg(long, default_value_t = 100)]
+    agents: usize,
+
+    /// Status deltas per second, per agent
+    #[arg(long, default_value_t = 1.0)]
+    rate: f64,
+
+    /// Connections closed and opened again per second, across the fleet
+    #[arg(long, default_value_t = 0.0)]
+    churn: f64,
+
+    /// Seconds over which the agents first connect
+    #[arg(long, default_value_t = 10)]
+    ramp_secs: u64,
+
+    /// Seconds to run, ramp included
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+
+    /// Seconds between reports
+    #[arg(long, default_value_t = 10)]
+    report_secs: u64,
+
+    /// Components per agent, each with one check
+    #[arg(long, default_value_t = 5)]
+    components: usize,
+
+    /// One delta in this many reports an error, so statuses change
+    #[arg(long, default_value_t = 10)]
+    error_every: u64,
+
+    /// Agent ids are `<prefix>-<n>`
+    #[arg(long, default_value = "loadgen")]
+    id_prefix: String,
+
+    /// Label of every agent, as key=value (repeatable)
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Client certificate, for gateways verifying agents
+    #[arg(long, requires = "key_file")]
+    cert_file: Option<PathBuf>,
+
+    /// Its private key (PKCS#8 PEM)
+    #[arg(long, requires = "cert_file")]
+    key_file: Option<PathBuf>,
+
+    /// CA certificate of the gateway, when not trusted by the system
+    #[arg(long)]
+    ca_file: Option<PathBuf>,
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("'{}' is not key=value", label))
+}
+
+/// Counters shared by the agents, read by the reports
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    connects: AtomicU64,
+    connect_failures: AtomicU64,
+    /// Connections closed by the gateway or failing
+    dropped: AtomicU64,
+    churned: AtomicU64,
+    sent: AtomicU64,
+    acked: AtomicU64,
+    unacked: AtomicU64,
+    commands: AtomicU64,
+    /// Since the last report
+    ack_latencies: Mutex<Vec<Duration>>,
+    connect_latencies: Mutex<Vec<Duration>>,
+}
+
+/// Counter values at the last report, for rates
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    connects: u64,
+    connect_failures: u64,
+    dropped: u64,
+    churned: u64,
+    sent: u64,
+    acked: u64,
+    commands: u64,
+}
+
+impl Stats {
+    fn totals(&self) -> Totals {
+        Totals {
+            connects: self.connects.load(Ordering::Relaxed),
+            connect_failures: self.connect_failures.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            churned: self.churned.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One simulated agent
+struct Agent {
+    id: String,
+    /// Asked to close its connection and open a new one
+    churn: Notify,
+}
+
+/// Why a connection ended without failing
+enum End {
+    Churned,
+    Stopped,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Arc::new(Args::parse());
+    if args.agents == 0 || args.rate <= 0.0 {
+        bail!("--agents and --rate must be greater than 0");
+    }
+    let connector = tls_connector(&args)?;
+
+    let stats = Arc::new(Stats::default());
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let agents: Vec<Arc<Agent>> = (0..args.agents)
+        .map(|i| {
+            Arc::new(Agent {
+                id: format!("{}-{:05}", args.id_prefix, i),
+                churn: Notify::new(),
+            })
+        })
+        .collect();
+
+    println!(
+        "{} agents on {}, {} deltas/s each, {} reconnects/s, for {}s",
+        args.agents, args.gateway, args.rate, args.churn, args.duration_secs
+    );
+
+    let started = Instant::now();
+    let mut tasks = Vec::new();
+    for (i, agent) in agents.iter().enumerate() {
+        let delay = Duration::from_secs(args.ramp_secs).mul_f64(i as f64 / args.agents as f64);
+        let (args, stats, agent, connector, stop) = (
+            args.clone(),
+            stats.clone(),
+            agent.clone(),
+            connector.clone(),
+            stop_rx.clone(),
+        );
+        tasks.push(tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            run_agent(&args, &stats, &agent, connector, stop).await;
+        }));
+    }
+
+    if args.churn > 0.0 {
+        tokio::spawn(churn(agents.clone(), args.churn));
+    }
+    let reporter = tokio::spawn(report(args.clone(), stats.clone(), started));
+
+    tokio::time::sleep(Duration::from_secs(args.duration_secs)).await;
+    let _ = stop_tx.send(true);
+    for task in tasks {
+        let _ = task.await;
+    }
+    reporter.abort();
+
+    summary(&stats, started.elapsed());
+    Ok(())
+}
+
+/// TLS for `wss://` gateways, presenting the client certificate if given
+fn tls_connector(args: &Args) -> Result<Option<Connector>> {
+    if !args.gateway.starts_with("wss://") {
+        if !args.gateway.starts_with("ws://") {
+            bail!("--gateway must be a ws:// or wss:// URL");
+        }
+        return Ok(None);
+    }
+
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|p| p.to_string_lossy().into_owned());
+    let (cert_file, key_file, ca_file) = (
+        path(&args.cert_file),
+        path(&args.key_file),
+        path(&args.ca_file),
+    );
+    let identity = cert_file.as_deref().zip(key_file.as_deref());
+    let config = tls::client_config(ca_file.as_deref(), identity)?;
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+/// Keep an agent connected until the run ends
+async fn run_agent(
+    args: &Args,
+    stats: &Stats,
+    agent: &Agent,
+    connector: Option<Connector>,
+    mut stop: watch::Receiver<bool>,
+) {
+    while !*stop.borrow() {
+        match session(args, stats, agent, connector.clone(), &mut stop).await {
+            Ok(End::Stopped) => return,
+            Ok(End::Churned) => {
+                stats.churned.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                if stats.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    println!("{}: {:#}", agent.id, e);
+                }
+                // Not all at once after a gateway restart
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                    _ = stop.changed() => return,
+                }
+            }
+        }
+    }
+}
+
+/// One connection of an agent: register, then send deltas until churned,
+/// stopped or closed
+async fn session(
+    args: &Args,
+    stats: &Stats,
+    agent: &Agent,
+    connector: Option<Connector>,
+    stop: &mut watch::Receiver<bool>,
+) -> Result<End> {
+    let connecting = Instant::now();
+    let ws = tokio::time::timeout(REGISTER_TIMEOUT, async {
+        let (mut ws, _) =
+            connect_async_tls_with_config(args.gateway.as_str(), None, false, connector).await?;
+        ws.send(Message::Text(register(args, &agent.id).to_string()))
+            .await?;
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let frame: Value = serde_json::from_str(&text)?;
+                    if frame["type"] == "registered" {
+                        return Ok(ws);
+                    }
+                }
+                Some(Ok(Message::Close(close))) => {
+                    bail!(
+                        "Refused: {}",
+                        close.map(|c| c.reason.to_string()).unwrap_or_default()
+                    )
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!("Closed before registering"),
+            }
+        }
+    })
+    .await
+    .context("Registration timed out")
+    .and_then(|ws| ws);
+    let mut ws = match ws {
+        Ok(ws) => ws,
+        Err(e) => {
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    };
+    stats.connects.fetch_add(1, Ordering::Relaxed);
+    stats
+        .connect_latencies
+        .lock()
+        .unwrap()
+        .push(connecting.elapsed());
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    let result = deltas(args, stats, agent, &mut ws, stop).await;
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+    if result.is_ok() {
+        let _ = ws.close(None).await;
+    }
+    result
+}
+
+type GatewayStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Send deltas on a registered connection, timing their acks
+async fn deltas(
+    args: &Args,
+    stats: &Stats,
+    agent: &Agent,
+    ws: &mut GatewayStream,
+    stop: &mut watch::Receiver<bool>,
+) -> Result<End> {
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / args.rate));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut seq = 0u64;
+    let mut unacked: BTreeMap<u64, Instant> = BTreeMap::new();
+
+    let result = loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                seq += 1;
+                let frame = json!({
+                    "type": "status_delta",
+                    "payload": delta(args, seq),
+                    "seq": seq,
+                });
+                if let Err(e) = ws.send(Message::Text(frame.to_string())).await {
+                    break Err(e.into());
+                }
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+                unacked.insert(seq, Instant::now());
+                while unacked.len() > MAX_UNACKED {
+                    unacked.pop_first();
+                    stats.unacked.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            incoming = ws.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    let Ok(frame) = serde_json::from_str::<Value>(&text) else { continue };
+                    match frame["type"].as_str().unwrap_or_default() {
+                        "ack" => {
+                            let acked = frame["payload"]["seq"].as_u64().unwrap_or_default();
+                            let now = Instant::now();
+                            let mut latencies = Vec::new();
+                            while let Some(entry) = unacked.first_entry() {
+                                if *entry.key() > acked {
+                                    break;
+                                }
+                                latencies.push(now - entry.remove());
+                            }
+                            stats.acked.fetch_add(latencies.len() as u64, Ordering::Relaxed);
+                            stats.ack_latencies.lock().unwrap().extend(latencies);
+                        }
+                        "ping" => {
+                            if let Err(e) = ws.send(Message::Text(json!({"type": "pong"}).to_string())).await {
+                                break Err(e.into());
+                            }
+                        }
+                        "command" => {
+                            stats.commands.fetch_add(1, Ordering::Relaxed);
+                            let response = json!({
+                                "type": "command_response",
+                                "payload": {
+                                    "job_id": frame["payload"]["id"],
+                                    "agent_id": agent.id,
+                                    "status": "completed",
+                                    "result": {"loadgen": true},
+                                    "error": null,
+                                    "timestamp": chrono::Utc::now(),
+                                },
+                            });
+                            if let Err(e) = ws.send(Message::Text(response.to_string())).await {
+                                break Err(e.into());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Ok(Message::Close(close))) => {
+                    break Err(anyhow::anyhow!(
+                        "Closed by the gateway: {}",
+                        close.map(|c| c.reason.to_string()).unwrap_or_default()
+                    ));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e.into()),
+                None => break Err(anyhow::anyhow!("Connection lost")),
+            },
+            _ = agent.churn.notified() => break Ok(End::Churned),
+            _ = stop.changed() => break Ok(End::Stopped),
+        }
+    };
+
+    stats
+        .unacked
+        .fetch_add(unacked.len() as u64, Ordering::Relaxed);
+    result
+}
+
+fn register(args: &Args, agent_id: &str) -> Value {
+    let mut labels: serde_json::Map<String, Value> = args
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    labels.insert("loadgen".to_string(), json!("true"));
+    json!({
+        "type": "register",
+        "payload": {
+            "agent_id": agent_id,
+            "hostname": agent_id,
+            "labels": labels,
+            "ip_addresses": [],
+            "version": env!("CARGO_PKG_VERSION"),
+            "os": "loadgen",
+            "protocol_version": PROTOCOL_VERSION,
+            "capabilities": ["acks"],
+        },
+    })
+}
+
+/// The `seq`th delta of an agent, cycling through its components
+fn delta(args: &Args, seq: u64) -> Value {
+    let component = seq % args.components.max(1) as u64;
+    let error = args.error_every > 0 && seq.is_multiple_of(args.error_every);
+    json!({
+        "component_id": format!("component-{}", component),
+        "check_name": "health",
+        "status": if error { "error" } else { "ok" },
+        "message": if error { Some("simulated failure") } else { None },
+        "timestamp": chrono::Utc::now(),
+    })
+}
+
+/// Ask the agents to reconnect, `per_sec` a second, each in turn
+async fn churn(agents: Vec<Arc<Agent>>, per_sec: f64) {
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / per_sec));
+    ticker.tick().await;
+    for agent in agents.iter().cycle() {
+        ticker.tick().await;
+        agent.churn.notify_one();
+    }
+}
+
+/// Print throughput and latencies every `report_secs`
+async fn report(args: Arc<Args>, stats: Arc<Stats>, started: Instant) {
+    let period = Duration::from_secs(args.report_secs.max(1));
+    let mut ticker = tokio::time::interval(period);
+    ticker.tick().await;
+    let mut last = Totals::default();
+
+    loop {
+        ticker.tick().await;
+        let now = stats.totals();
+        let acks = std::mem::take(&mut *stats.ack_latencies.lock().unwrap());
+        let rate = |now: u64, last: u64| (now - last) as f64 / period.as_secs_f64();
+        println!(
+            "[{:>4}s] connected {}  connects {} ({} failed, {} dropped, {} churned)  sent {:.0}/s  acked {:.0}/s  commands {}  ack {}",
+            started.elapsed().as_secs(),
+            stats.connected.load(Ordering::Relaxed),
+            now.connects - last.connects,
+            now.connect_failures - last.connect_failures,
+            now.dropped - last.dropped,
+            now.churned - last.churned,
+            rate(now.sent, last.sent),
+            rate(now.acked, last.acked),
+            now.commands - last.commands,
+            percentiles(acks),
+        );
+        last = now;
+    }
+}
+
+/// Totals for the whole run
+fn summary(stats: &Stats, elapsed: Duration) {
+    let totals = stats.totals();
+    let secs = elapsed.as_secs_f64();
+    let connects = std::mem::take(&mut *stats.connect_latencies.lock().unwrap());
+    println!("--- {:.0}s", secs);
+    println!(
+        "connections  {} opened, {} failed, {} dropped, {} churned; register {}",
+        totals.connects,
+        totals.connect_failures,
+        totals.dropped,
+        totals.churned,
+        percentiles(connects)
+    );
+    println!(
+        "deltas       {} sent ({:.0}/s), {} acked ({:.0}/s), {} never acked",
+        totals.sent,
+        totals.sent as f64 / secs,
+        totals.acked,
+        totals.acked as f64 / secs,
+        stats.unacked.load(Ordering::Relaxed)
+    );
+    println!("commands     {} answered", totals.commands);
+}
+
+/// p50/p90/p99/max of some latencies, in milliseconds
+fn percentiles(mut latencies: Vec<Duration>) -> String {
+    if latencies.is_empty() {
+        return "-".to_string();
+    }
+    latencies.sort_unstable();
+    let at = |p: f64| {
+        let i = ((latencies.len() as f64 * p).ceil() as usize).clamp(1, latencies.len()) - 1;
+        latencies[i].as_secs_f64() * 1000.0
+    };
+    format!(
+        "p50 {:.1}ms p90 {:.1}ms p99 {:.1}ms max {:.1}ms",
+        at(0.50),
+        at(0.90),
+        at(0.99),
+        at(1.0)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        assert_eq!(percentiles(Vec::new()), "-");
+
+        let latencies = (1..=100).rev().map(Duration::from_millis).collect();
+        assert_eq!(
+            percentiles(latencies),
+            "p50 50.0ms p90 90.0ms p99 99.0ms max 100.0ms"
+        );
+        assert_eq!(
+            percentiles(vec![Duration::from_micros(1500)]),
+            "p50 1.5ms p90 1.5ms p99 1.5ms max 1.5ms"
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Pending commands
+//!
+//! Commands routed to an agent are tracked until it gives a final response.
+//! A command that could not be sent, or was sent on a connection that then
+//! closed, is sent again when the agent registers, up to
+//! `commands.max_redeliveries` times; the agent answers a duplicate of a
+//! command it already has with that command's response rather than running
+//! it twice. A command still unanswered `commands.response_grace_secs`
+//! after its own `timeout_secs` is dropped and reported to the backend as a
+//! `command_timeout`, and kept as a dead letter if it never reached the
+//! agent. A `scheduled` answer hands the command over to the
+//! agent, which keeps it across restarts.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::audit::AuditEvent;
+use crate::backend_client::CommandPayload;
+use crate::registry::AgentCommand;
+use crate::{BackendMessage, GatewayState};
+
+/// How often timed out commands are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest wait for a response, whatever the command's timeout
+const MAX_WAIT_SECS: u64 = 365 * 24 * 3600;
+
+/// Pending command settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandSettings {
+    /// Time past a command's `timeout_secs` for its final response to arrive
+    #[serde(default = "default_response_grace")]
+    pub response_grace_secs: u64,
+    /// Times a command is sent again to an agent that reconnected
+    #[serde(default = "default_max_redeliveries")]
+    pub max_redeliveries: u32,
+}
+
+fn default_response_grace() -> u64 {
+    30
+}
+
+fn default_max_redeliveries() -> u32 {
+    3
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        Self {
+            response_grace_secs: default_response_grace(),
+            max_redeliveries: default_max_redeliveries(),
+        }
+    }
+}
+
+/// A command sent to an agent and not answered yet
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingCommand {
+    pub id: String,
+    pub command_type: String,
+    pub routed_at: DateTime<Utc>,
+    /// Times it was sent; 0 while the agent was not connected
+    pub deliveries: u32,
+    /// The agent answered `started`
+    pub started: bool,
+}
+
+struct Entry {
+    command: AgentCommand,
+    routed_at: DateTime<Utc>,
+    deadline: DateTime<Utc>,
+    deliveries: u32,
+    started: bool,
+}
+
+/// A command given up on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandTimeout {
+    pub job_id: String,
+    pub agent_id: String,
+    pub command_type: String,
+    pub deliveries: u32,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+/// Commands awaiting a final response, by agent and command id
+pub struct PendingCommands {
+    settings: CommandSettings,
+    entries: DashMap<(String, String), Entry>,
+}
+
+impl PendingCommands {
+    pub fn new(settings: CommandSettings) -> Self {
+        Self {
+            settings,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// Start tracking a command routed to an agent
+    pub fn track(&self, agent_id: &str, command: &AgentCommand) {
+        let wait = command
+            .timeout_secs
+            .saturating_add(self.settings.response_grace_secs)
+            .min(MAX_WAIT_SECS);
+        let now = Utc::now();
+        self.entries.insert(
+            (agent_id.to_string(), command.id.clone()),
+            Entry {
+                command: command.clone(),
+                routed_at: now,
+                deadline: now + chrono::Duration::seconds(wait as i64),
+                deliveries: 0,
+                started: false,
+            },
+        );
+    }
+
+    /// Record that a command reached the agent's connection
+    pub fn delivered(&self, agent_id: &str, command_id: &str) {
+        if let Some(mut entry) = self
+            .entries
+            .get_mut(&(agent_id.to_string(), command_id.to_string()))
+        {
+            entry.deliveries += 1;
+        }
+    }
+
+    /// Stop tracking a command
+    pub fn forget(&self, agent_id: &str, command_id: &str) {
+        self.entries
+            .remove(&(agent_id.to_string(), command_id.to_string()));
+    }
+
+    /// Add its command's correlation id to a response from an agent that
+    /// does not echo it
+    pub fn correlate(&self, agent_id: &str, response: &mut serde_json::Value) {
+        if response.get("correlation_id").is_some() {
+            return;
+        }
+        let Some(job_id) = response.get("job_id").and_then(|v| v.as_str()) else {
+            return;
+        };
+        let correlation_id = self
+            .entries
+            .get(&(agent_id.to_string(), job_id.to_string()))
+            .and_then(|entry| entry.command.correlation_id.clone());
+        if let (Some(id), Some(response)) = (correlation_id, response.as_object_mut()) {
+            response.insert("correlation_id".to_string(), id.into());
+        }
+    }
+
+    /// Take note of a command response from an agent
+    pub fn answered(&self, agent_id: &str, response: &serde_json::Value) {
+        let field = |name: &str| response.get(name).and_then(|v| v.as_str());
+        let job_id = match field("job_id") {
+            Some(id) => id,
+            None => return,
+        };
+        let key = (agent_id.to_string(), job_id.to_string());
+
+        if field("status") == Some("started") {
+            if let Some(mut entry) = self.entries.get_mut(&key) {
+                entry.started = true;
+            }
+        } else {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Stop tracking an agent's commands and return them, oldest first
+    pub fn take(&self, agent_id: &str) -> Vec<AgentCommand> {
+        let keys: Vec<(String, String)> = self
+            .entries
+            .iter()
+            .filter(|e| e.key().0 == agent_id)
+            .map(|e| e.key().clone())
+            .collect();
+        let mut commands: Vec<(DateTime<Utc>, AgentCommand)> = keys
+            .into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .map(|(_, entry)| (entry.routed_at, entry.command))
+            .collect();
+        commands.sort_by_key(|(routed_at, _)| *routed_at);
+        commands.into_iter().map(|(_, command)| command).collect()
+    }
+
+    /// Commands to send again to an agent that registered, oldest first;
+    /// those sent `max_redeliveries` times already are left to time out
+    pub fn redeliveries(&self, agent_id: &str) -> Vec<AgentCommand> {
+        let mut commands: Vec<(DateTime<Utc>, AgentCommand)> = self
+            .entries
+            .iter()
+            .filter(|e| e.key().0 == agent_id)
+            .filter(|e| e.deliveries <= self.settings.max_redeliveries)
+            .map(|e| (e.routed_at, e.command.clone()))
+            .collect();
+        commands.sort_by_key(|(routed_at, _)| *routed_at);
+        commands.into_iter().map(|(_, command)| command).collect()
+    }
+
+    /// An agent's pending commands, oldest first
+    pub fn for_agent(&self, agent_id: &str) -> Vec<PendingCommand> {
+        let mut pending: Vec<PendingCommand> = self
+            .entries
+            .iter()
+            .filter(|e| e.key().0 == agent_id)
+            .map(|e| PendingCommand {
+                id: e.command.id.clone(),
+                command_type: e.command.command_type.clone(),
+                routed_at: e.routed_at,
+                deliveries: e.deliveries,
+                started: e.started,
+            })
+            .collect();
+        pending.sort_by_key(|p| p.routed_at);
+        pending
+    }
+
+    /// How many commands each agent has not answered yet
+    pub fn count_by_agent(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.entries.iter() {
+            *counts.entry(entry.key().0.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Drop the commands past their deadline, with what timed out
+    pub fn expire(&self, now: DateTime<Utc>) -> Vec<(CommandTimeout, AgentCommand)> {
+        let expired: Vec<(String, String)> = self
+            .entries
+            .iter()
+            .filter(|e| e.deadline <= now)
+            .map(|e| e.key().clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .map(|((agent_id, job_id), entry)| {
+                let timeout = CommandTimeout {
+                    job_id,
+                    agent_id,
+                    command_type: entry.command.command_type.clone(),
+                    deliveries: entry.deliveries,
+                    timestamp: now,
+                    correlation_id: entry.command.correlation_id.clone(),
+                };
+                (timeout, entry.command)
+            })
+            .collect()
+    }
+}
+
+/// Send an agent that just registered the commands it has not answered
+pub fn redeliver(state: &GatewayState, agent_id: &str) {
+    for command in state.commands.redeliveries(agent_id) {
+        // A fresh connection has room; what does not fit waits for the next
+        match state.registry.try_send_command(agent_id, command.clone()) {
+            Ok(()) => {
+                info!(agent_id = %agent_id, command_id = %command.id, "Command redelivered");
+                state.metrics.command_redelivered();
+                state.commands.delivered(agent_id, &command.id);
+            }
+            Err(e) => {
+                debug!(agent_id = %agent_id, command_id = %command.id, error = %e, "Command not redelivered");
+                break;
+            }
+        }
+    }
+}
+
+/// Report commands that timed out, until the gateway stops
+pub async fn expire(state: Arc<GatewayState>) {
+    loop {
+        tokio::time::sleep(EXPIRE_INTERVAL).await;
+
+        for (timeout, command) in state.commands.expire(Utc::now()) {
+            warn!(
+                agent_id = %timeout.agent_id,
+                command_id = %timeout.job_id,
+                correlation_id = timeout.correlation_id.as_deref(),
+                deliveries = timeout.deliveries,
+                "Command timed out without a final response"
+            );
+            state.metrics.command_timed_out();
+            state.audit.record(&AuditEvent::result(
+                &timeout.agent_id,
+                &serde_json::json!({
+                    "job_id": timeout.job_id,
+                    "status": "timeout",
+                    "error": "no final response from the agent",
+                    "correlation_id": timeout.correlation_id,
+                }),
+            ));
+            // Never reached its agent: kept to be sent again
+            if timeout.deliveries == 0 {
+                let payload = CommandPayload {
+                    agent_id: Some(timeout.agent_id.clone()),
+                    labels: None,
+                    command,
+                    requested_by: None,
+                    roles: Vec::new(),
+                    rollout: Default::default(),
+                    strategy: None,
+                    zone: None,
+                    group: None,
+                };
+                state.dead_letters.command(
+                    Some(&timeout.agent_id),
+                    &payload,
+                    "the agent did not connect before the command timed out",
+                );
+                state.metrics.dead_lettered("command");
+            }
+            let (agent_id, job_id) = (timeout.agent_id.clone(), timeout.job_id.clone());
+            crate::outbound::post(&state, BackendMessage::CommandTimeout(timeout));
+            crate::fanout::settle(&state, &agent_id, &job_id, "timeout", None).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: &str, timeout_secs: u64) -> AgentCommand {
+        AgentCommand {
+            id: id.to_string(),
+            command_type: "restart".to_string(),
+            component_id: "postgres".to_string(),
+            action_name: None,
+            params: serde_json::Value::Null,
+            timeout_secs,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: Some(format!("corr-{}", id)),
+        }
+    }
+
+    #[test]
+    fn test_pending_commands() {
+        let pending = PendingCommands::new(CommandSettings {
+            response_grace_secs: 10,
+            max_redeliveries: 1,
+        });
+        let response = |id: &str, status: &str| serde_json::json!({"job_id": id, "status": status});
+
+        pending.track("agent-1", &command("cmd-1", 60));
+        pending.track("agent-1", &command("cmd-2", 60));
+        pending.track("agent-2", &command("cmd-3", 300));
+        pending.delivered("agent-1", "cmd-1");
+
+        let mut started = response("cmd-1", "started");
+        pending.correlate("agent-1", &mut started);
+        assert_eq!(started["correlation_id"], "corr-cmd-1");
+        pending.answered("agent-1", &started);
+        pending.answered("agent-1", &response("cmd-2", "completed"));
+        let agent_1 = pending.for_agent("agent-1");
+        assert_eq!(agent_1.len(), 1);
+        assert_eq!(
+            pending.count_by_agent(),
+            HashMap::from([("agent-1".to_string(), 1), ("agent-2".to_string(), 1)])
+        );
+        assert!(agent_1[0].started);
+        // The other agent's answer is not this one's
+        pending.answered("agent-2", &response("cmd-1", "completed"));
+        assert_eq!(pending.for_agent("agent-1").len(), 1);
+
+        // Sent once and redelivered once, then left to time out
+        assert_eq!(pending.redeliveries("agent-1").len(), 1);
+        pending.delivered("agent-1", "cmd-1");
+        assert!(pending.redeliveries("agent-1").is_empty());
+
+        let expired = pending.expire(Utc::now() + chrono::Duration::seconds(71));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(
+            (expired[0].0.job_id.as_str(), expired[0].0.deliveries),
+            ("cmd-1", 2)
+        );
+        assert_eq!(expired[0].1.command_type, "restart");
+        assert!(pending.for_agent("agent-1").is_empty());
+        assert_eq!(pending.for_agent("agent-2").len(), 1);
+
+        pending.answered("agent-2", &response("cmd-3", "scheduled"));
+        assert!(pending.for_agent("agent-2").is_empty());
+
+        pending.track("agent-3", &command("cmd-4", 60));
+        pending.track("agent-3", &command("cmd-5", 60));
+        let taken: Vec<String> = pending.take("agent-3").into_iter().map(|c| c.id).collect();
+        assert_eq!(taken, ["cmd-4", "cmd-5"]);
+        assert!(pending.for_agent("agent-3").is_empty());
+    }
+}
@@ -0,0 +1,404 @@
+//! Agent configuration updates
+//!
+//! `POST /config-updates` pushes settings to connected agents without
+//! editing their config files: the default check interval, the status
+//! batch interval, the offline buffer's size and age limits, the log level,
+//! and labels to set or remove. Agents are picked like a command's: by
+//! `agent_id`, a `labels` selector or a `group`.
+//!
+//! ```json
+//! {
+//!   "labels": "role=db,env=prod",
+//!   "config": {"batch_send_interval_secs": 30, "log_level": "debug", "labels": {"tier": "gold"}}
+//! }
+//! ```
+//!
+//! Only agents that accepted the `config_update` capability get it, and
+//! only those a command of type `config_update` from the caller would reach:
+//! in a zone this gateway commands, granted by the RBAC policies and not
+//! quarantined. The others are listed in `failed`, with the reason. The
+//! `zone` label and the labels RBAC policies grant on cannot be pushed, or a
+//! caller could move an agent into its own reach. Pushed settings hold until the agent reloads
+//! its config file or restarts. Changed labels come back in the agent's
+//! `metadata_update`. Each push is in the audit log as `config_update`.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::agent_server::GatewayToAgentMessage;
+use crate::audit::AuditEvent;
+use crate::auth::Caller;
+use crate::backend_client;
+use crate::policy::{PolicyEngine, Principal};
+use crate::protocol;
+use crate::registry::AgentInfo;
+use crate::selector::Selector;
+use crate::GatewayState;
+
+/// Settings for agents to apply; those left out stay as they are
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    /// For checks that do not set their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_interval_secs: Option<u64>,
+    /// How often unchanged check results go out together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_send_interval_secs: Option<u64>,
+    /// Status updates kept while disconnected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_max_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_max_age_secs: Option<u64>,
+    /// A level or `RUST_LOG` directives, e.g. "info,opsmap_agent::scheduler=debug"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    /// Labels to add or change
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_labels: Vec<String>,
+}
+
+/// Longest `buffer_max_age_secs` taken: a year
+pub const MAX_BUFFER_AGE_SECS: u64 = 365 * 24 * 3600;
+
+impl ConfigUpdate {
+    /// What is wrong with the update, if anything
+    pub fn check(&self) -> Result<(), String> {
+        if *self == Self::default() {
+            return Err("config update changes nothing".to_string());
+        }
+        if self.check_interval_secs == Some(0) || self.batch_send_interval_secs == Some(0) {
+            return Err("intervals must be greater than 0".to_string());
+        }
+        if self.buffer_max_size == Some(0) {
+            return Err("buffer_max_size must be greater than 0".to_string());
+        }
+        if self
+            .buffer_max_age_secs
+            .is_some_and(|secs| secs > MAX_BUFFER_AGE_SECS)
+        {
+            return Err(format!(
+                "buffer_max_age_secs must be at most {}",
+                MAX_BUFFER_AGE_SECS
+            ));
+        }
+        if self
+            .log_level
+            .as_deref()
+            .is_some_and(|l| l.trim().is_empty())
+        {
+            return Err("log_level must not be empty".to_string());
+        }
+        if self.labels.keys().any(|k| k.is_empty()) {
+            return Err("label names must not be empty".to_string());
+        }
+        if let Some(key) = self
+            .remove_labels
+            .iter()
+            .find(|k| self.labels.contains_key(*k))
+        {
+            return Err(format!("label '{}' is both set and removed", key));
+        }
+        Ok(())
+    }
+
+    /// A label the update may not change: `zone`, or one RBAC grants on
+    fn guarded_label<'a>(&'a self, policy: &PolicyEngine) -> Option<&'a str> {
+        self.labels
+            .keys()
+            .chain(&self.remove_labels)
+            .map(String::as_str)
+            .find(|key| *key == "zone" || policy.guards_label(key))
+    }
+}
+
+/// `POST /config-updates`: an update and the agents to send it to
+#[derive(Debug, Deserialize)]
+pub struct ConfigUpdateRequest {
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    #[serde(default)]
+    pub labels: Option<Selector>,
+    #[serde(default)]
+    pub group: Option<String>,
+    pub config: ConfigUpdate,
+}
+
+/// Who got an update, and why the others did not
+#[derive(Debug, Serialize)]
+pub struct ConfigUpdateResult {
+    pub update_id: String,
+    pub sent: Vec<String>,
+    pub failed: BTreeMap<String, String>,
+}
+
+/// `POST /config-updates`
+pub async fn push(
+    caller: Option<Extension<Caller>>,
+    State(state): State<Arc<GatewayState>>,
+    axum::Json(request): axum::Json<ConfigUpdateRequest>,
+) -> Result<axum::Json<ConfigUpdateResult>, (StatusCode, String)> {
+    request
+        .config
+        .check()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if let Some(label) = request.config.guarded_label(&state.policy) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("label '{}' decides who may command the agent", label),
+        ));
+    }
+    let targets = targets(&state, &request)?;
+    if targets.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            "No matching agent is connected".to_string(),
+        ));
+    }
+
+    let requested_by = caller.map(|Extension(Caller(name))| name);
+    let mut result = ConfigUpdateResult {
+        update_id: uuid::Uuid::new_v4().to_string(),
+        sent: Vec::new(),
+        failed: BTreeMap::new(),
+    };
+    for agent in targets {
+        let sent = match refusal(&state, &agent, requested_by.as_deref()) {
+            Some(reason) => Err(reason),
+            None => {
+                let message = GatewayToAgentMessage::ConfigUpdate(request.config.clone());
+                state.registry.send(&agent.id, message).await
+            }
+        };
+        let event = AuditEvent::config_update(
+            &result.update_id,
+            &agent.id,
+            requested_by.as_deref(),
+            &request.config,
+            sent.as_ref().err().cloned(),
+        );
+        state.audit.record(&event);
+        match sent {
+            Ok(()) => {
+                info!(
+                    agent_id = %agent.id,
+                    update_id = %result.update_id,
+                    requested_by = requested_by.as_deref(),
+                    "Config update sent"
+                );
+                result.sent.push(agent.id);
+            }
+            Err(error) => {
+                warn!(agent_id = %agent.id, error = %error, "Config update not sent");
+                result.failed.insert(agent.id, error);
+            }
+        }
+    }
+    Ok(axum::Json(result))
+}
+
+/// The connected agents a request names, sorted by id
+fn targets(
+    state: &GatewayState,
+    request: &ConfigUpdateRequest,
+) -> Result<Vec<AgentInfo>, (StatusCode, String)> {
+    let mut agents = match (&request.agent_id, &request.labels, &request.group) {
+        (Some(agent_id), None, None) => vec![state.registry.get(agent_id).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Agent not connected: {}", agent_id),
+        ))?],
+        (None, Some(selector), None) => state.registry.find_matching(selector),
+        (None, None, Some(group)) => state
+            .groups
+            .members(group, &state.registry)
+            .ok_or((StatusCode::NOT_FOUND, format!("No group {}", group)))?
+            .iter()
+            .filter_map(|id| state.registry.get(id))
+            .collect(),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "give one of agent_id, labels or group".to_string(),
+            ))
+        }
+    };
+    agents.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(agents)
+}
+
+/// Why an agent cannot be sent an update, if it cannot; checked like a
+/// command's; API callers have no tenant, so the agent's own is used
+fn refusal(state: &GatewayState, agent: &AgentInfo, requested_by: Option<&str>) -> Option<String> {
+    let principal = Principal {
+        name: requested_by,
+        roles: &[],
+    };
+    if let Err(reason) = backend_client::authorize(
+        state,
+        Some(agent),
+        protocol::CONFIG_UPDATE,
+        agent.tenant_id.as_deref(),
+        &principal,
+    ) {
+        return Some(reason);
+    }
+    if agent.quarantined {
+        return Some("agent is quarantined".to_string());
+    }
+    if !agent.supports(protocol::CONFIG_UPDATE) {
+        return Some("agent does not take config updates".to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_server::{self, RegisterPayload};
+    use crate::GatewayConfig;
+    use tokio::sync::mpsc;
+
+    /// Connect a `role=db` agent with these capabilities and `env`,
+    /// returning its channel
+    fn connect(
+        state: &GatewayState,
+        id: &str,
+        capabilities: &[&str],
+        env: &str,
+    ) -> mpsc::Receiver<GatewayToAgentMessage> {
+        let payload: RegisterPayload = serde_json::from_value(serde_json::json!({
+            "agent_id": id,
+            "hostname": id,
+            "labels": {"role": "db", "env": env},
+            "version": "1.0.0",
+            "os": "linux",
+            "protocol_version": 2,
+            "capabilities": capabilities,
+        }))
+        .unwrap();
+        let (tx, rx) = mpsc::channel(8);
+        state
+            .registry
+            .register(agent_server::registered_agent(payload), tx);
+        rx
+    }
+
+    fn request(body: serde_json::Value) -> axum::Json<ConfigUpdateRequest> {
+        axum::Json(serde_json::from_value(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let state = Arc::new(GatewayState::for_tests(GatewayConfig::default()));
+        let mut current = connect(&state, "agent-1", &["acks", "config_update"], "prod");
+        let _older = connect(&state, "agent-2", &["acks"], "prod");
+
+        let body = serde_json::json!({
+            "labels": "role=db",
+            "config": {"log_level": "debug"},
+        });
+        let axum::Json(result) = push(None, State(state.clone()), request(body))
+            .await
+            .unwrap();
+        assert_eq!(result.sent, vec!["agent-1"]);
+        assert_eq!(
+            result.failed["agent-2"],
+            "agent does not take config updates"
+        );
+        assert!(matches!(
+            current.try_recv(),
+            Ok(GatewayToAgentMessage::ConfigUpdate(update)) if update.log_level.as_deref() == Some("debug")
+        ));
+
+        let unknown = serde_json::json!({"agent_id": "agent-9", "config": {"log_level": "debug"}});
+        let missing = push(None, State(state.clone()), request(unknown)).await;
+        assert!(matches!(missing, Err((StatusCode::NOT_FOUND, _))));
+        let nobody = serde_json::json!({"labels": "role=web", "config": {"log_level": "debug"}});
+        let none = push(None, State(state.clone()), request(nobody)).await;
+        assert!(matches!(none, Err((StatusCode::CONFLICT, _))));
+        let both = serde_json::json!({"agent_id": "agent-1", "group": "db", "config": {"log_level": "debug"}});
+        let ambiguous = push(None, State(state), request(both)).await;
+        assert!(matches!(ambiguous, Err((StatusCode::BAD_REQUEST, _))));
+    }
+
+    #[test]
+    fn test_check() {
+        let update: ConfigUpdate = serde_json::from_value(serde_json::json!({
+            "batch_send_interval_secs": 30,
+            "log_level": "debug",
+            "labels": {"tier": "gold"},
+            "remove_labels": ["canary"],
+        }))
+        .unwrap();
+        assert!(update.check().is_ok());
+
+        assert!(ConfigUpdate::default().check().is_err());
+        let zero = ConfigUpdate {
+            batch_send_interval_secs: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.check().is_err());
+        let both = ConfigUpdate {
+            remove_labels: vec!["tier".to_string()],
+            ..update
+        };
+        assert_eq!(
+            both.check().unwrap_err(),
+            "label 'tier' is both set and removed"
+        );
+        assert!(
+            serde_json::from_value::<ConfigUpdate>(serde_json::json!({"interval": 5})).is_err()
+        );
+        let forever = ConfigUpdate {
+            buffer_max_age_secs: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(forever.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_push_authorized() {
+        let config = GatewayConfig {
+            rbac: serde_yaml::from_str(
+                "enabled: true
+policies:
+  - {name: dev, principals: [dev], command_types: [config_update], labels: {env: dev}}
+",
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let state = Arc::new(GatewayState::for_tests(config));
+        let _prod = connect(&state, "agent-1", &["acks", "config_update"], "prod");
+        let _dev = connect(&state, "agent-2", &["acks", "config_update"], "dev");
+        let _quarantined = connect(&state, "agent-3", &["acks", "config_update"], "dev");
+        state.registry.set_quarantined("agent-3", true);
+
+        // Only where RBAC grants it, and never to a quarantined agent
+        let caller = Some(Extension(Caller("dev".to_string())));
+        let body = serde_json::json!({"labels": "role=db", "config": {"log_level": "debug"}});
+        let axum::Json(result) = push(caller.clone(), State(state.clone()), request(body))
+            .await
+            .unwrap();
+        assert_eq!(result.sent, vec!["agent-2"]);
+        assert!(result.failed["agent-1"].contains("not allowed"));
+        assert_eq!(result.failed["agent-3"], "agent is quarantined");
+
+        // Labels that decide who may command an agent stay its own
+        for config in [
+            serde_json::json!({"labels": {"env": "dev"}}),
+            serde_json::json!({"remove_labels": ["zone"]}),
+        ] {
+            let body = serde_json::json!({"agent_id": "agent-1", "config": config});
+            let refused = push(caller.clone(), State(state.clone()), request(body)).await;
+            assert!(matches!(refused, Err((StatusCode::FORBIDDEN, _))));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Read-only web dashboard
+//!
+//! `GET /dashboard` serves a page listing the zone's connected agents, with
+//! their labels, last heartbeat and latest check statuses, so operators see
+//! the zone even while the backend is unreachable. The page is static; it
+//! reads `GET /dashboard/agents`, which wants a `read` token like the other
+//! operator endpoints (the page asks for it and keeps it in the browser).
+//!
+//! ```yaml
+//! dashboard:
+//!   enabled: true   # the default
+//! ```
+
+use axum::extract::State;
+use axum::response::Html;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::health::AgentHealth;
+use crate::registry::CheckStatus;
+use crate::GatewayState;
+
+const PAGE: &str = include_str!("index.html");
+
+/// Dashboard settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DashboardSettings {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for DashboardSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+        }
+    }
+}
+
+/// The zone as the dashboard shows it
+#[derive(Debug, Serialize)]
+pub struct Overview {
+    pub gateway_id: String,
+    pub zone: String,
+    pub backend_connected: bool,
+    pub agents: Vec<AgentStatus>,
+}
+
+/// A connected agent and its checks
+#[derive(Debug, Serialize)]
+pub struct AgentStatus {
+    pub id: String,
+    pub hostname: String,
+    pub labels: HashMap<String, String>,
+    pub version: String,
+    pub quarantined: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    pub last_heartbeat: DateTime<Utc>,
+    pub heartbeat_age_secs: i64,
+    pub health: AgentHealth,
+    pub checks: Vec<CheckStatus>,
+}
+
+/// The dashboard page
+pub async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+/// Connected agents and their checks, for the page
+pub async fn agents(State(state): State<Arc<GatewayState>>) -> axum::Json<Overview> {
+    axum::Json(overview(&state))
+}
+
+fn overview(state: &GatewayState) -> Overview {
+    let now = Utc::now();
+    let mut agents: Vec<AgentStatus> = state
+        .registry
+        .list()
+        .into_iter()
+        .map(|agent| AgentStatus {
+            checks: state.registry.checks(&agent.id),
+            heartbeat_age_secs: (now - agent.last_heartbeat).num_seconds().max(0),
+            id: agent.id,
+            hostname: agent.hostname,
+            labels: agent.labels,
+            version: agent.version,
+            quarantined: agent.quarantined,
+            tenant_id: agent.tenant_id,
+            last_heartbeat: agent.last_heartbeat,
+            health: agent.health,
+        })
+        .collect();
+    agents.sort_by(|a, b| a.hostname.cmp(&b.hostname).then_with(|| a.id.cmp(&b.id)));
+
+    Overview {
+        gateway_id: state.config.gateway.id.clone(),
+        zone: state.config.gateway.zone.clone(),
+        backend_connected: state.metrics.backend_connected(),
+        agents,
+    }
+}
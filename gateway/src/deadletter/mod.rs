@@ -0,0 +1,532 @@
+//! Dead-letter queue
+//!
+//! What the gateway could not deliver is kept in a SQLite file instead of
+//! only being logged:
+//!
+//! - commands that timed out without ever reaching their agent (it never
+//!   connected), and label-routed commands no agent here matched;
+//! - messages to the backend that could not be serialized, or whose link
+//!   failed as they were sent (sequenced status updates are not among
+//!   them: they are sent again on reconnect).
+//!
+//! `GET /dead-letters` lists them (`?kind=command|backend_message`,
+//! `agent_id=`, `limit=`), `POST /dead-letters/:id/retry` routes a command
+//! again, as the API token, or sends a message to the backend again, and
+//! `DELETE /dead-letters/:id` or `DELETE /dead-letters` (`?kind=`) purge.
+//! Past `max_entries`, the oldest go first.
+//!
+//! ```yaml
+//! dead_letters:
+//!   enabled: true   # the default
+//!   file_path: /var/lib/opsmap/gateway-dead-letters.db
+//!   max_entries: 10000
+//! ```
+
+use anyhow::{Context, Result};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::auth::Caller;
+use crate::backend_client::{CommandPayload, GatewayToBackendMessage};
+use crate::sqlite::Worker;
+use crate::{BackendMessage, GatewayState};
+
+/// Dead-letter queue settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterSettings {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_file")]
+    pub file_path: String,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_file() -> String {
+    "/var/lib/opsmap/gateway-dead-letters.db".to_string()
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+impl Default for DeadLetterSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            file_path: default_file(),
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+/// What was not delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A command payload, as the backend sent it
+    Command,
+    /// A message to the backend
+    BackendMessage,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Command => "command",
+            Kind::BackendMessage => "backend_message",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "command" => Some(Kind::Command),
+            "backend_message" => Some(Kind::BackendMessage),
+            _ => None,
+        }
+    }
+}
+
+/// An undelivered command or message
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_id: Option<String>,
+    pub reason: String,
+    /// The command payload or message; a description of it when it could
+    /// not be serialized, and then it cannot be retried
+    pub payload: serde_json::Value,
+    pub retryable: bool,
+    /// Retries that failed
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Filters for `GET /dead-letters`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeadLetterQuery {
+    pub kind: Option<Kind>,
+    pub agent_id: Option<String>,
+    pub limit: Option<usize>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    agent_id TEXT,
+    reason TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    retryable INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL
+);
+";
+
+/// The queue; a disabled one keeps nothing
+///
+/// The file is on a thread of its own: recording never waits, and reading
+/// waits without blocking the runtime.
+pub struct DeadLetters {
+    worker: Option<Worker>,
+    max_entries: usize,
+}
+
+impl DeadLetters {
+    pub fn open(settings: &DeadLetterSettings) -> Result<Self> {
+        let path = std::path::Path::new(&settings.file_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open dead-letter file {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to initialize dead-letter file {}", path.display()))?;
+
+        Ok(Self {
+            worker: Some(Worker::start("dead-letters", conn)?),
+            max_entries: settings.max_entries,
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            worker: None,
+            max_entries: 0,
+        }
+    }
+
+    /// Run `query` on the file's thread, or answer `disabled` without a file
+    async fn run<T: Send + 'static>(
+        &self,
+        disabled: T,
+        query: impl FnOnce(&Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        match self.worker {
+            Some(ref worker) => worker.run(query).await,
+            None => Ok(disabled),
+        }
+    }
+
+    /// Keep a command that reached no agent
+    pub fn command(&self, agent_id: Option<&str>, payload: &CommandPayload, reason: &str) {
+        match serde_json::to_value(payload) {
+            Ok(payload) => self.record(Kind::Command, agent_id, reason, payload, true),
+            Err(e) => error!(error = %e, "Failed to dead-letter command"),
+        }
+    }
+
+    /// Keep a message the backend did not get
+    pub fn backend_message(&self, message: &GatewayToBackendMessage, reason: &str) {
+        let (payload, retryable) = match serde_json::to_value(message) {
+            Ok(payload) => (payload, true),
+            Err(_) => (serde_json::json!(format!("{:?}", message)), false),
+        };
+        self.record(Kind::BackendMessage, None, reason, payload, retryable);
+    }
+
+    fn record(
+        &self,
+        kind: Kind,
+        agent_id: Option<&str>,
+        reason: &str,
+        payload: serde_json::Value,
+        retryable: bool,
+    ) {
+        let Some(ref worker) = self.worker else {
+            return;
+        };
+        warn!(kind = kind.as_str(), agent_id = ?agent_id, reason = %reason, "Dead-lettered");
+        let id = uuid::Uuid::new_v4().to_string();
+        let agent_id = agent_id.map(str::to_string);
+        let reason = reason.to_string();
+        let created_at = Utc::now();
+        let max_entries = self.max_entries as i64;
+        worker.spawn(move |conn| {
+            let result = conn
+                .execute(
+                    "INSERT INTO dead_letters (id, kind, agent_id, reason, payload, retryable, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        id,
+                        kind.as_str(),
+                        agent_id,
+                        reason,
+                        payload.to_string(),
+                        retryable,
+                        created_at
+                    ],
+                )
+                .and_then(|_| {
+                    conn.execute(
+                        "DELETE FROM dead_letters WHERE id IN
+                         (SELECT id FROM dead_letters ORDER BY created_at DESC LIMIT -1 OFFSET ?1)",
+                        params![max_entries],
+                    )
+                });
+            if let Err(e) = result {
+                error!(error = %e, "Failed to write dead letter");
+            }
+        });
+    }
+
+    /// Entries matching `query`, newest first
+    pub async fn list(&self, query: &DeadLetterQuery) -> Result<Vec<DeadLetter>> {
+        let query = query.clone();
+        self.run(Vec::new(), move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT id, kind, agent_id, reason, payload, retryable, attempts, created_at
+                 FROM dead_letters
+                 WHERE (?1 IS NULL OR kind = ?1) AND (?2 IS NULL OR agent_id = ?2)
+                 ORDER BY created_at DESC LIMIT ?3",
+            )?;
+            let limit = query.limit.map_or(-1, |l| l as i64);
+            let rows = statement.query_map(
+                params![query.kind.map(Kind::as_str), query.agent_id, limit],
+                from_row,
+            )?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<DeadLetter>> {
+        let id = id.to_string();
+        self.run(None, move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT id, kind, agent_id, reason, payload, retryable, attempts, created_at
+                     FROM dead_letters WHERE id = ?1",
+                    params![id],
+                    from_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    /// Remove an entry; false if there was none
+    pub async fn remove(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.run(false, move |conn| {
+            let removed = conn.execute("DELETE FROM dead_letters WHERE id = ?1", params![id])?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Remove all entries, or those of a kind, returning how many
+    pub async fn purge(&self, kind: Option<Kind>) -> Result<usize> {
+        self.run(0, move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM dead_letters WHERE ?1 IS NULL OR kind = ?1",
+                params![kind.map(Kind::as_str)],
+            )?)
+        })
+        .await
+    }
+
+    fn retry_failed(&self, id: &str, reason: &str) {
+        let Some(ref worker) = self.worker else {
+            return;
+        };
+        let (id, reason) = (id.to_string(), reason.to_string());
+        worker.spawn(move |conn| {
+            let result = conn.execute(
+                "UPDATE dead_letters SET attempts = attempts + 1, reason = ?2 WHERE id = ?1",
+                params![id, reason],
+            );
+            if let Err(e) = result {
+                error!(error = %e, "Failed to update dead letter");
+            }
+        });
+    }
+}
+
+fn from_row(row: &rusqlite::Row) -> rusqlite::Result<DeadLetter> {
+    let kind: String = row.get(1)?;
+    let payload: String = row.get(4)?;
+    Ok(DeadLetter {
+        id: row.get(0)?,
+        kind: Kind::parse(&kind).unwrap_or(Kind::BackendMessage),
+        agent_id: row.get(2)?,
+        reason: row.get(3)?,
+        payload: serde_json::from_str(&payload).unwrap_or(serde_json::Value::String(payload)),
+        retryable: row.get(5)?,
+        attempts: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+/// Deliver an entry again, removing it once handed over
+async fn retry(
+    state: &GatewayState,
+    entry: &DeadLetter,
+    caller: Option<&str>,
+) -> Result<(), String> {
+    if !entry.retryable {
+        return Err("this entry cannot be retried".to_string());
+    }
+    match entry.kind {
+        Kind::Command => {
+            let mut payload: CommandPayload =
+                serde_json::from_value(entry.payload.clone()).map_err(|e| e.to_string())?;
+            // Sent again as the API token, like POST /agents/:id/command
+            payload.requested_by = caller.map(str::to_string);
+            payload.roles = Vec::new();
+            match payload.agent_id.clone() {
+                Some(agent_id) => {
+                    if state.registry.get(&agent_id).is_none() {
+                        return Err(format!("Agent not connected: {}", agent_id));
+                    }
+                    crate::backend_client::dispatch(
+                        state,
+                        &payload.command,
+                        &agent_id,
+                        payload.requested_by.as_deref(),
+                        &[],
+                    )
+                    .await?;
+                }
+                None => crate::backend_client::route_command(payload, state).await,
+            }
+        }
+        Kind::BackendMessage => {
+            if !state.metrics.backend_connected() {
+                return Err("backend not connected".to_string());
+            }
+            let message: GatewayToBackendMessage =
+                serde_json::from_value(entry.payload.clone()).map_err(|e| e.to_string())?;
+            if !crate::outbound::post(state, BackendMessage::Redelivery(Box::new(message))) {
+                return Err("backend queue full".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `GET /dead-letters`
+pub async fn list(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<DeadLetterQuery>,
+) -> Result<axum::Json<Vec<DeadLetter>>, (StatusCode, String)> {
+    state
+        .dead_letters
+        .list(&query)
+        .await
+        .map(axum::Json)
+        .map_err(internal)
+}
+
+/// `POST /dead-letters/:id/retry`
+pub async fn retry_handler(
+    caller: Option<Extension<Caller>>,
+    State(state): State<Arc<GatewayState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let entry = state
+        .dead_letters
+        .get(&id)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, format!("No dead letter {}", id)))?;
+    let caller = caller.map(|Extension(Caller(name))| name);
+
+    match retry(&state, &entry, caller.as_deref()).await {
+        Ok(()) => {
+            info!(id = %id, kind = entry.kind.as_str(), "Dead letter retried");
+            state.dead_letters.remove(&id).await.map_err(internal)?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(reason) => {
+            state.dead_letters.retry_failed(&id, &reason);
+            Err((StatusCode::CONFLICT, reason))
+        }
+    }
+}
+
+/// `DELETE /dead-letters/:id`
+pub async fn remove(
+    State(state): State<Arc<GatewayState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.dead_letters.remove(&id).await.map_err(internal)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err((StatusCode::NOT_FOUND, format!("No dead letter {}", id))),
+    }
+}
+
+/// `DELETE /dead-letters`, answering how many were removed
+pub async fn purge(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<DeadLetterQuery>,
+) -> Result<axum::Json<serde_json::Value>, (StatusCode, String)> {
+    let purged = state
+        .dead_letters
+        .purge(query.kind)
+        .await
+        .map_err(internal)?;
+    info!(purged = purged, "Dead letters purged");
+    Ok(axum::Json(serde_json::json!({ "purged": purged })))
+}
+
+fn internal(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::AgentCommand;
+
+    #[tokio::test]
+    async fn test_record_list_purge() {
+        let path = std::env::temp_dir().join(format!("opsmap-dlq-{}.db", uuid::Uuid::new_v4()));
+        let dlq = DeadLetters::open(&DeadLetterSettings {
+            enabled: true,
+            file_path: path.to_string_lossy().into_owned(),
+            max_entries: 2,
+        })
+        .unwrap();
+
+        let payload = CommandPayload {
+            agent_id: Some("agent-1".to_string()),
+            labels: None,
+            command: AgentCommand {
+                id: "cmd-1".to_string(),
+                command_type: "restart".to_string(),
+                component_id: "postgres".to_string(),
+                action_name: None,
+                params: serde_json::Value::Null,
+                timeout_secs: 60,
+                trace_context: None,
+                run_at: None,
+                run_after_secs: None,
+                tenant_id: None,
+                correlation_id: None,
+            },
+            requested_by: Some("alice".to_string()),
+            roles: Vec::new(),
+            rollout: Default::default(),
+            strategy: None,
+            zone: None,
+            group: None,
+        };
+        dlq.command(Some("agent-1"), &payload, "agent never connected");
+        dlq.backend_message(&GatewayToBackendMessage::Pong, "backend link failed");
+        dlq.backend_message(
+            &GatewayToBackendMessage::AgentDisconnected {
+                agent_id: "agent-2".to_string(),
+            },
+            "backend link failed",
+        );
+
+        // Only the newest max_entries are kept
+        let all = dlq.list(&DeadLetterQuery::default()).await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert!(all
+            .iter()
+            .all(|e| e.kind == Kind::BackendMessage && e.retryable));
+        assert_eq!(all[0].payload["payload"]["agent_id"], "agent-2");
+
+        dlq.command(Some("agent-1"), &payload, "agent never connected");
+        let commands = dlq
+            .list(&DeadLetterQuery {
+                kind: Some(Kind::Command),
+                agent_id: Some("agent-1".to_string()),
+                limit: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(commands.len(), 1);
+        let entry = dlq.get(&commands[0].id).await.unwrap().unwrap();
+        let again: CommandPayload = serde_json::from_value(entry.payload).unwrap();
+        assert_eq!(again.command.id, "cmd-1");
+
+        dlq.retry_failed(&entry.id, "Agent not connected: agent-1");
+        assert_eq!(dlq.get(&entry.id).await.unwrap().unwrap().attempts, 1);
+        assert!(dlq.remove(&entry.id).await.unwrap());
+        assert!(!dlq.remove(&entry.id).await.unwrap());
+        assert_eq!(dlq.purge(Some(Kind::BackendMessage)).await.unwrap(), 1);
+        assert!(dlq
+            .list(&DeadLetterQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
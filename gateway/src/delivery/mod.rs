@@ -0,0 +1,23 @@
+//! At-least-once delivery
+//!
+//! Status updates carry a sequence number on both hops. The backend acks
+//! what it has handled; only then does the Gateway ack the agent, so a crash
+//! anywhere in between leaves the update unacked and the sender retransmits
+//! it after reconnecting. Acks are cumulative.
+
+#[path = "../../../shared/outbox.rs"]
+mod outbox;
+
+pub use outbox::Outbox;
+
+use serde::{Deserialize, Serialize};
+
+/// Unacked messages kept for the backend link
+pub const MAX_PENDING: usize = 10_000;
+
+/// The agent message a forwarded update came from, acked once it is delivered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub agent_id: String,
+    pub seq: u64,
+}
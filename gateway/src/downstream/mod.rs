@@ -0,0 +1,443 @@
+//! Downstream gateways
+//!
+//! Networks segmented deeper than one gateway per zone chain gateways: a
+//! site gateway's `backend.url` is a regional gateway's `/ws`, and the
+//! regional one, with `downstream.enabled`, serves it as the backend would.
+//! The site gateway's agents are registered at the regional gateway and
+//! reported from there; their commands, snapshots and deltas go down the
+//! chain, and their responses and status come back up.
+//!
+//! ```yaml
+//! downstream:
+//!   enabled: true
+//!   gateway_ids: [site-lyon, site-nantes]   # empty = any
+//! ```
+//!
+//! A downstream gateway authenticates with its certificate, like an agent,
+//! and its `gateway_id` must be the certificate's CN or one of its DNS
+//! SANs: an agent certificate cannot pose as a gateway and take over other
+//! agents, and without client certificates no gateway is accepted. Commands are authorized and tracked by the gateway facing the backend;
+//! those down the chain see no principal, so their `rbac` stays disabled.
+//! Status updates are not acknowledged across a hop.
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use dashmap::DashMap;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::agent_server::GatewayToAgentMessage;
+use crate::backend_client::{
+    BackendToGatewayMessage, CommandPayload, GatewayToBackendMessage, RegisterPayload,
+    SnapshotDeltaPayload, SnapshotPayload,
+};
+use crate::outbound::Connection;
+use crate::protocol::{self, Accepted};
+use crate::registry::AgentInfo;
+use crate::tls::PeerCertificate;
+use crate::{BackendMessage, GatewayState};
+
+/// Downstream gateway settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownstreamSettings {
+    /// Accept gateways registering on `/ws`
+    #[serde(default)]
+    pub enabled: bool,
+    /// Gateways accepted, all when empty
+    #[serde(default)]
+    pub gateway_ids: Vec<String>,
+}
+
+impl DownstreamSettings {
+    /// Whether a gateway registering as `gateway_id` is served
+    fn accepts(&self, gateway_id: &str, peer: &PeerCertificate) -> bool {
+        self.enabled
+            && (self.gateway_ids.is_empty() || self.gateway_ids.iter().any(|id| id == gateway_id))
+            && peer.names(gateway_id)
+    }
+}
+
+/// Links to downstream gateways, by gateway id
+#[derive(Default)]
+pub struct Downstreams {
+    links: DashMap<String, mpsc::Sender<BackendToGatewayMessage>>,
+}
+
+impl Downstreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a message to every downstream gateway
+    pub fn broadcast(&self, message: &BackendToGatewayMessage) {
+        for link in self.links.iter() {
+            let _ = link.try_send(message.clone());
+        }
+    }
+}
+
+/// Serve a gateway that registered on `/ws`, until it goes away
+pub async fn serve(
+    state: Arc<GatewayState>,
+    mut ws_sender: SplitSink<WebSocket, Message>,
+    mut ws_receiver: SplitStream<WebSocket>,
+    registration: RegisterPayload,
+    peer: &PeerCertificate,
+) {
+    let gateway_id = registration.gateway_id.clone();
+    if !state.config.downstream.accepts(&gateway_id, peer) {
+        warn!(gateway_id = %gateway_id, certificate = ?peer.names, "Downstream gateway refused");
+        let close = CloseFrame {
+            code: close_code::POLICY,
+            reason: "downstream gateway not accepted".into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return;
+    }
+
+    // Neither acks nor deflate across the hop
+    let accepted = BackendToGatewayMessage::Registered(Accepted {
+        protocol_version: registration
+            .protocol_version
+            .min(protocol::PROTOCOL_VERSION),
+        capabilities: Vec::new(),
+    });
+    if let Ok(json) = serde_json::to_string(&accepted) {
+        if ws_sender.send(Message::Text(json)).await.is_err() {
+            return;
+        }
+    }
+    info!(
+        gateway_id = %gateway_id,
+        zone = %registration.zone,
+        agents = registration.agents.len(),
+        "Downstream gateway connected"
+    );
+
+    let (tx, mut rx) = mpsc::channel::<BackendToGatewayMessage>(1000);
+    state
+        .downstreams
+        .links
+        .insert(gateway_id.clone(), tx.clone());
+
+    // Agents registered through this gateway, and their registration
+    let mut agents: HashMap<String, mpsc::WeakSender<GatewayToAgentMessage>> = HashMap::new();
+    let queue = state.outbound.connection("downstream");
+    for agent in registration.agents {
+        connected(&state, agent, &tx, &mut agents);
+    }
+
+    loop {
+        tokio::select! {
+            msg = ws_receiver.next() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<GatewayToBackendMessage>(&text) {
+                            Ok(message) => handle(&state, message, &queue, &tx, &mut agents).await,
+                            Err(e) => warn!(gateway_id = %gateway_id, error = %e, "Invalid downstream message"),
+                        }
+                    }
+                    Some(Ok(Message::Ping(data))) => {
+                        if ws_sender.send(Message::Pong(data)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+            Some(message) = rx.recv() => {
+                let json = match serde_json::to_string(&message) {
+                    Ok(json) => json,
+                    Err(_) => continue,
+                };
+                if ws_sender.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    state
+        .downstreams
+        .links
+        .remove_if(&gateway_id, |_, link| link.same_channel(&tx));
+    for (agent_id, registration) in agents {
+        disconnected(&state, &agent_id, &registration);
+    }
+    info!(gateway_id = %gateway_id, "Downstream gateway disconnected");
+}
+
+async fn handle(
+    state: &GatewayState,
+    message: GatewayToBackendMessage,
+    queue: &Connection,
+    link: &mpsc::Sender<BackendToGatewayMessage>,
+    agents: &mut HashMap<String, mpsc::WeakSender<GatewayToAgentMessage>>,
+) {
+    let forward = match message {
+        GatewayToBackendMessage::AgentConnected(agent) => {
+            connected(state, agent, link, agents);
+            return;
+        }
+        GatewayToBackendMessage::AgentDisconnected { agent_id } => {
+            if let Some(registration) = agents.remove(&agent_id) {
+                disconnected(state, &agent_id, &registration);
+            }
+            return;
+        }
+        GatewayToBackendMessage::AgentMetadata(agent) => {
+            match state.registry.update_metadata(
+                &agent.id,
+                agent.hostname,
+                agent.labels,
+                agent.ip_addresses,
+            ) {
+                Some(info) => BackendMessage::AgentMetadata(info),
+                None => return,
+            }
+        }
+        GatewayToBackendMessage::StatusUpdate(update) => BackendMessage::StatusUpdate {
+            update,
+            delivery: None,
+        },
+        GatewayToBackendMessage::CommandResponse(response) => {
+            command_response(state, queue, response).await;
+            return;
+        }
+        // Not negotiated with downstream gateways, unpacked all the same
+        GatewayToBackendMessage::CommandResponses(batch) => {
+            for response in batch.responses {
+                command_response(state, queue, response).await;
+            }
+            return;
+        }
+        GatewayToBackendMessage::JobUpdate(update) => BackendMessage::JobUpdate(update),
+        GatewayToBackendMessage::Discovery { agent_id, report } => {
+            BackendMessage::Discovery { agent_id, report }
+        }
+        GatewayToBackendMessage::Inventory {
+            agent_id,
+            inventory,
+        } => BackendMessage::Inventory {
+            agent_id,
+            inventory,
+        },
+        GatewayToBackendMessage::Diagnostics { agent_id, chunk } => {
+            BackendMessage::Diagnostics { agent_id, chunk }
+        }
+        GatewayToBackendMessage::SnapshotRequest { agent_id, version } => {
+            BackendMessage::SnapshotRequest { agent_id, version }
+        }
+        GatewayToBackendMessage::CommandSummary(summary) => BackendMessage::CommandSummary(summary),
+        // Commands are tracked here too, and time out here
+        GatewayToBackendMessage::CommandTimeout(timeout) => {
+            debug!(command_id = %timeout.job_id, "Downstream command timeout");
+            return;
+        }
+        GatewayToBackendMessage::ProtocolError(error) => {
+            warn!(kind = error.kind.as_str(), error = %error.message, "Downstream gateway refused a message");
+            return;
+        }
+        GatewayToBackendMessage::Register(_) | GatewayToBackendMessage::Pong => return,
+    };
+    crate::outbound::forward(state, queue, forward).await;
+}
+
+/// Take a downstream agent's command response as if it were connected here
+async fn command_response(state: &GatewayState, queue: &Connection, response: serde_json::Value) {
+    let agent_id = response
+        .get("agent_id")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    crate::agent_server::command_response(state, &agent_id, queue, response).await;
+}
+
+/// Register an agent of a downstream gateway here, relaying to it what the
+/// registry sends the agent
+fn connected(
+    state: &GatewayState,
+    agent: AgentInfo,
+    link: &mpsc::Sender<BackendToGatewayMessage>,
+    agents: &mut HashMap<String, mpsc::WeakSender<GatewayToAgentMessage>>,
+) {
+    let agent_id = agent.id.clone();
+    let tenant_id = agent.tenant_id.clone();
+    let (agent_tx, mut agent_rx) = mpsc::channel::<GatewayToAgentMessage>(100);
+    agents.insert(agent_id.clone(), agent_tx.downgrade());
+    state.registry.register(agent, agent_tx);
+    crate::ha::announce(state, &agent_id);
+    crate::commands::redeliver(state, &agent_id);
+    if let Some(info) = state.registry.get(&agent_id) {
+        crate::outbound::post(state, BackendMessage::AgentConnected(info));
+    }
+
+    let link = link.clone();
+    tokio::spawn(async move {
+        while let Some(message) = agent_rx.recv().await {
+            let relayed = match message {
+                GatewayToAgentMessage::Command(command) => {
+                    BackendToGatewayMessage::Command(Box::new(CommandPayload {
+                        agent_id: Some(agent_id.clone()),
+                        labels: None,
+                        command: *command,
+                        requested_by: None,
+                        roles: Vec::new(),
+                        rollout: Default::default(),
+                        strategy: None,
+                        zone: None,
+                        group: None,
+                    }))
+                }
+                GatewayToAgentMessage::Snapshot(snapshot) => {
+                    BackendToGatewayMessage::Snapshot(SnapshotPayload {
+                        agent_id: agent_id.clone(),
+                        snapshot,
+                        tenant_id: tenant_id.clone(),
+                    })
+                }
+                GatewayToAgentMessage::SnapshotDelta(delta) => {
+                    BackendToGatewayMessage::SnapshotDelta(SnapshotDeltaPayload {
+                        agent_id: agent_id.clone(),
+                        delta,
+                        tenant_id: tenant_id.clone(),
+                    })
+                }
+                // The downstream gateway pings and acks its agents itself
+                _ => continue,
+            };
+            if link.send(relayed).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Unregister an agent of a downstream gateway, unless it registered again
+/// through another connection since
+fn disconnected(
+    state: &GatewayState,
+    agent_id: &str,
+    registration: &mpsc::WeakSender<GatewayToAgentMessage>,
+) {
+    if registration.upgrade().is_some() {
+        state.registry.unregister(agent_id);
+        crate::outbound::post(
+            state,
+            BackendMessage::AgentDisconnected(agent_id.to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GatewayConfig;
+
+    fn state() -> GatewayState {
+        GatewayState::for_tests(GatewayConfig::default())
+    }
+
+    fn agent(id: &str) -> AgentInfo {
+        crate::agent_server::registered_agent(
+            serde_json::from_value(serde_json::json!({
+                "agent_id": id,
+                "hostname": "host-1",
+                "labels": {"role": "db"},
+                "version": "1.0.0",
+                "os": "linux",
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_accepts() {
+        let settings = DownstreamSettings {
+            enabled: true,
+            gateway_ids: vec!["site-lyon".to_string(), "site-nantes".to_string()],
+        };
+        let certificate = |names: &[&str]| PeerCertificate {
+            verified: true,
+            names: names.iter().map(|n| n.to_string()).collect(),
+            tenant: None,
+        };
+
+        assert!(settings.accepts("site-lyon", &certificate(&["site-lyon"])));
+        // Listed, but the certificate is someone else's
+        assert!(!settings.accepts("site-nantes", &certificate(&["site-lyon"])));
+        assert!(!settings.accepts("site-lyon", &certificate(&["agent-1"])));
+        assert!(!settings.accepts("site-lyon", &PeerCertificate::default()));
+        // Its own certificate, but not listed
+        assert!(!settings.accepts("site-paris", &certificate(&["site-paris"])));
+
+        let any = DownstreamSettings {
+            gateway_ids: Vec::new(),
+            ..settings
+        };
+        assert!(any.accepts("site-paris", &certificate(&["site-paris"])));
+        assert!(!any.accepts("site-paris", &certificate(&["agent-1"])));
+    }
+
+    #[tokio::test]
+    async fn test_downstream_agents() {
+        let state = state();
+        let mut backend_rx = state.backend_tx.subscribe();
+        let (link, mut link_rx) = mpsc::channel(16);
+        let mut agents = HashMap::new();
+        let queue = state.outbound.connection("downstream");
+
+        let connected = GatewayToBackendMessage::AgentConnected(agent("agent-1"));
+        handle(&state, connected, &queue, &link, &mut agents).await;
+        assert!(state.registry.get("agent-1").is_some());
+        assert!(matches!(
+            backend_rx.recv().await.unwrap(),
+            BackendMessage::AgentConnected(info) if info.id == "agent-1"
+        ));
+
+        // Commands for the agent go down the link, addressed to it
+        let command: crate::registry::AgentCommand = serde_json::from_value(serde_json::json!({
+            "id": "cmd-1",
+            "command_type": "restart",
+            "component_id": "postgres",
+            "action_name": null,
+            "params": null,
+            "timeout_secs": 60,
+        }))
+        .unwrap();
+        state
+            .registry
+            .send_command("agent-1", command)
+            .await
+            .unwrap();
+        match link_rx.recv().await.unwrap() {
+            BackendToGatewayMessage::Command(payload) => {
+                assert_eq!(payload.agent_id.as_deref(), Some("agent-1"));
+                assert_eq!(payload.command.id, "cmd-1");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // Its responses come back up
+        let response =
+            serde_json::json!({"job_id": "cmd-1", "agent_id": "agent-1", "status": "completed"});
+        let answered = GatewayToBackendMessage::CommandResponse(response);
+        handle(&state, answered, &queue, &link, &mut agents).await;
+        assert!(matches!(
+            backend_rx.recv().await.unwrap(),
+            BackendMessage::CommandResponse(r) if r["job_id"] == "cmd-1"
+        ));
+
+        let gone = GatewayToBackendMessage::AgentDisconnected {
+            agent_id: "agent-1".to_string(),
+        };
+        handle(&state, gone, &queue, &link, &mut agents).await;
+        assert!(state.registry.get("agent-1").is_none());
+        assert!(agents.is_empty());
+    }
+}
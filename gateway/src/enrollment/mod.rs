@@ -0,0 +1,484 @@
+//! Agent enrollment
+//!
+//! Lets a new agent obtain its client certificate with a one-time token
+//! instead of out-of-band certificate distribution:
+//! 1. An operator runs `opsmap-gateway create-token` and hands the token to
+//!    the agent's provisioning (cloud-init, Ansible, ...)
+//! 2. The agent generates a keypair and POSTs a CSR with the token to
+//!    `/enroll`
+//! 3. The gateway consumes the token, assigns an agent ID and returns a
+//!    certificate signed by the zone CA, with the ID as common name and
+//!    only subject alternative name: nothing the CSR asks for beyond its
+//!    key ends up in the certificate
+//!
+//! A token created with `--tenant acme` enrolls agents of that tenant: their
+//! certificate also carries the URI SAN `urn:opsmap:tenant:acme`, which is
+//! where the gateway takes an agent's tenant from.
+//!
+//! Only token hashes are stored.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use rcgen::{
+    Certificate, CertificateParams, CertificateSigningRequestParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType, SerialNumber,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::tls::TENANT_URI_PREFIX;
+
+/// Enrollment settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// CA used to sign agent certificates (the one in tls.ca_file)
+    #[serde(default)]
+    pub ca_cert_file: Option<String>,
+    #[serde(default)]
+    pub ca_key_file: Option<String>,
+    #[serde(default = "default_tokens_file")]
+    pub tokens_file: String,
+    #[serde(default = "default_cert_validity_days")]
+    pub cert_validity_days: u32,
+}
+
+fn default_tokens_file() -> String {
+    "/var/lib/opsmap/enrollment-tokens.json".to_string()
+}
+
+fn default_cert_validity_days() -> u32 {
+    365
+}
+
+impl Default for EnrollmentSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ca_cert_file: None,
+            ca_key_file: None,
+            tokens_file: default_tokens_file(),
+            cert_validity_days: default_cert_validity_days(),
+        }
+    }
+}
+
+/// Enrollment request from an agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollRequest {
+    pub token: String,
+    /// PEM-encoded certificate signing request
+    pub csr: String,
+    pub hostname: String,
+}
+
+/// Issued identity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollResponse {
+    pub agent_id: String,
+    /// PEM-encoded client certificate
+    pub certificate: String,
+    /// PEM-encoded CA certificate, to verify the Gateway
+    pub ca_certificate: String,
+}
+
+/// Why an enrollment was refused
+#[derive(Debug, thiserror::Error)]
+pub enum EnrollError {
+    #[error("invalid enrollment token")]
+    InvalidToken,
+    #[error("enrollment token already used")]
+    TokenUsed,
+    #[error("enrollment token expired")]
+    TokenExpired,
+    #[error("invalid certificate signing request: {0}")]
+    InvalidCsr(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+/// A one-time enrollment token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentToken {
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    #[serde(default)]
+    pub used_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub used_by: Option<String>,
+    /// Tenant of the agents it enrolls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+}
+
+/// Token store, persisted as a JSON file
+pub struct TokenStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl TokenStore {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Create a token valid for `ttl` enrolling agents of `tenant_id`,
+    /// returning its only plaintext copy
+    pub fn create(&self, ttl: chrono::Duration, tenant_id: Option<&str>) -> Result<String> {
+        let _guard = self.lock.lock().unwrap();
+        let token = format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+
+        let mut tokens = self.load()?;
+        let now = Utc::now();
+        tokens.retain(|t| t.used_at.is_none() && t.expires_at > now);
+        tokens.push(EnrollmentToken {
+            token_hash: hash_token(&token),
+            created_at: now,
+            expires_at: now + ttl,
+            used_at: None,
+            used_by: None,
+            tenant_id: tenant_id.map(str::to_string),
+        });
+        self.save(&tokens)?;
+
+        Ok(token)
+    }
+
+    /// Mark a token used by `agent_id`, returning the tenant it enrolls
+    /// agents of, or failing if it cannot be used
+    pub fn consume(&self, token: &str, agent_id: &str) -> Result<Option<String>, EnrollError> {
+        let _guard = self.lock.lock().unwrap();
+        let hash = hash_token(token);
+
+        let mut tokens = self.load()?;
+        let entry = tokens
+            .iter_mut()
+            .find(|t| t.token_hash == hash)
+            .ok_or(EnrollError::InvalidToken)?;
+
+        if entry.used_at.is_some() {
+            return Err(EnrollError::TokenUsed);
+        }
+        if entry.expires_at <= Utc::now() {
+            return Err(EnrollError::TokenExpired);
+        }
+
+        entry.used_at = Some(Utc::now());
+        entry.used_by = Some(agent_id.to_string());
+        let tenant_id = entry.tenant_id.clone();
+        self.save(&tokens)?;
+        Ok(tenant_id)
+    }
+
+    fn load(&self) -> Result<Vec<EnrollmentToken>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    fn save(&self, tokens: &[EnrollmentToken]) -> Result<()> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(tokens)?)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Signs agent CSRs with the zone CA
+pub struct CertSigner {
+    ca_cert: Certificate,
+    ca_key: KeyPair,
+    ca_pem: String,
+    validity_days: u32,
+}
+
+impl CertSigner {
+    pub fn load(settings: &EnrollmentSettings) -> Result<Self> {
+        let cert_file = settings
+            .ca_cert_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("enrollment.ca_cert_file is required"))?;
+        let key_file = settings
+            .ca_key_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("enrollment.ca_key_file is required"))?;
+
+        let ca_pem = std::fs::read_to_string(cert_file)
+            .with_context(|| format!("Failed to read CA certificate {}", cert_file))?;
+        let key_pem = std::fs::read_to_string(key_file)
+            .with_context(|| format!("Failed to read CA key {}", key_file))?;
+
+        Self::from_pem(&ca_pem, &key_pem, settings.cert_validity_days)
+    }
+
+    pub fn from_pem(ca_pem: &str, key_pem: &str, validity_days: u32) -> Result<Self> {
+        let ca_key = KeyPair::from_pem(key_pem).context("Invalid CA key")?;
+        // Rebuild the issuer from the CA's own fields; only its name and key
+        // end up in issued certificates
+        let ca_cert = CertificateParams::from_ca_cert_pem(ca_pem)
+            .context("Invalid CA certificate")?
+            .self_signed(&ca_key)?;
+
+        Ok(Self {
+            ca_cert,
+            ca_key,
+            ca_pem: ca_pem.to_string(),
+            validity_days,
+        })
+    }
+
+    /// Parse and check a PEM CSR
+    pub fn parse(&self, csr_pem: &str) -> Result<CertificateSigningRequestParams, EnrollError> {
+        CertificateSigningRequestParams::from_pem(csr_pem)
+            .map_err(|e| EnrollError::InvalidCsr(e.to_string()))
+    }
+
+    /// Issue a client certificate for `agent_id` of `tenant_id` to the key
+    /// of a CSR; its names and extensions are replaced by the agent's identity
+    pub fn sign(
+        &self,
+        mut csr: CertificateSigningRequestParams,
+        agent_id: &str,
+        tenant_id: Option<&str>,
+    ) -> Result<String, EnrollError> {
+        let ia5 = |name: String| {
+            name.try_into()
+                .map_err(|e: rcgen::Error| EnrollError::Internal(e.into()))
+        };
+        let mut subject_alt_names = vec![SanType::DnsName(ia5(agent_id.to_string())?)];
+        if let Some(tenant_id) = tenant_id {
+            let uri = format!("{}{}", TENANT_URI_PREFIX, tenant_id);
+            subject_alt_names.push(SanType::URI(ia5(uri)?));
+        }
+
+        let now = time::OffsetDateTime::now_utc();
+        let params = &mut csr.params;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, agent_id);
+        params.subject_alt_names = subject_alt_names;
+        params.custom_extensions = Vec::new();
+        params.not_before = now;
+        params.not_after = now + time::Duration::days(self.validity_days as i64);
+        params.serial_number = Some(SerialNumber::from_slice(uuid::Uuid::new_v4().as_bytes()));
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.use_authority_key_identifier_extension = true;
+
+        let cert = csr
+            .signed_by(&self.ca_cert, &self.ca_key)
+            .map_err(|e| EnrollError::Internal(e.into()))?;
+        Ok(cert.pem())
+    }
+
+    pub fn ca_pem(&self) -> &str {
+        &self.ca_pem
+    }
+}
+
+/// Enrollment service
+pub struct Enrollment {
+    pub tokens: TokenStore,
+    signer: CertSigner,
+}
+
+impl Enrollment {
+    pub fn new(settings: &EnrollmentSettings) -> Result<Self> {
+        Ok(Self {
+            tokens: TokenStore::new(Path::new(&settings.tokens_file)),
+            signer: CertSigner::load(settings)?,
+        })
+    }
+
+    /// Validate the request, consume its token and issue an identity
+    pub fn enroll(&self, request: &EnrollRequest) -> Result<EnrollResponse, EnrollError> {
+        let agent_id = generate_agent_id(&request.hostname);
+
+        // A malformed CSR does not burn the token, but nothing is signed
+        // before the token is used up
+        let csr = self.signer.parse(&request.csr)?;
+        let tenant_id = self.tokens.consume(&request.token, &agent_id)?;
+        let certificate = self.signer.sign(csr, &agent_id, tenant_id.as_deref())?;
+
+        Ok(EnrollResponse {
+            agent_id,
+            certificate,
+            ca_certificate: self.signer.ca_pem().to_string(),
+        })
+    }
+}
+
+/// Agent ID in the same form agents generate for themselves
+fn generate_agent_id(hostname: &str) -> String {
+    let hostname: String = hostname
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '.')
+        .collect();
+    let hostname = if hostname.is_empty() {
+        "agent".to_string()
+    } else {
+        hostname
+    };
+    let suffix = uuid::Uuid::new_v4().to_string()[..8].to_string();
+
+    format!("{}-{}", hostname, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("opsmap-enroll-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_ca() -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "OpsMap Test CA");
+        params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn csr(hostname: &str) -> String {
+        let key = KeyPair::generate().unwrap();
+        let mut params =
+            CertificateParams::new(vec![hostname.to_string(), "gateway.example".to_string()])
+                .unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "another-agent");
+        params.serialize_request(&key).unwrap().pem().unwrap()
+    }
+
+    #[test]
+    fn test_token_is_single_use() {
+        let dir = temp_dir();
+        let store = TokenStore::new(&dir.join("tokens.json"));
+
+        let token = store.create(chrono::Duration::hours(1), None).unwrap();
+        assert!(matches!(store.consume(&token, "agent-1"), Ok(None)));
+        assert!(matches!(
+            store.consume(&token, "agent-2"),
+            Err(EnrollError::TokenUsed)
+        ));
+        assert!(matches!(
+            store.consume("bogus", "agent-2"),
+            Err(EnrollError::InvalidToken)
+        ));
+
+        let expired = store.create(chrono::Duration::seconds(-1), None).unwrap();
+        assert!(matches!(
+            store.consume(&expired, "agent-3"),
+            Err(EnrollError::TokenExpired)
+        ));
+
+        // Plaintext tokens are never stored
+        let content = std::fs::read_to_string(dir.join("tokens.json")).unwrap();
+        assert!(!content.contains(&token));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_enroll() {
+        let dir = temp_dir();
+        let (ca_pem, key_pem) = test_ca();
+        let enrollment = Enrollment {
+            tokens: TokenStore::new(&dir.join("tokens.json")),
+            signer: CertSigner::from_pem(&ca_pem, &key_pem, 30).unwrap(),
+        };
+        let token = enrollment
+            .tokens
+            .create(chrono::Duration::hours(1), Some("acme"))
+            .unwrap();
+
+        // A bad CSR is refused without consuming the token
+        let bad = EnrollRequest {
+            token: token.clone(),
+            csr: "not a csr".to_string(),
+            hostname: "web-01".to_string(),
+        };
+        assert!(matches!(
+            enrollment.enroll(&bad),
+            Err(EnrollError::InvalidCsr(_))
+        ));
+
+        let request = EnrollRequest {
+            token,
+            csr: csr("web-01"),
+            hostname: "web-01".to_string(),
+        };
+        let response = enrollment.enroll(&request).unwrap();
+        assert!(response.agent_id.starts_with("web-01-"));
+        assert!(response
+            .certificate
+            .starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(response.ca_certificate, ca_pem);
+
+        // The agent's id is its only name, whatever the CSR asked for, and
+        // the token's tenant is written next to it
+        let (_, pem) = x509_parser::pem::parse_x509_pem(response.certificate.as_bytes()).unwrap();
+        let cert = pem.parse_x509().unwrap();
+        let cn = cert.subject().iter_common_name().next().unwrap();
+        assert_eq!(cn.as_str().unwrap(), response.agent_id);
+        let sans: Vec<String> = cert
+            .subject_alternative_name()
+            .unwrap()
+            .unwrap()
+            .value
+            .general_names
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(
+            sans,
+            [
+                format!("DNSName({})", response.agent_id),
+                "URI(urn:opsmap:tenant:acme)".to_string()
+            ]
+        );
+
+        assert!(matches!(
+            enrollment.enroll(&request),
+            Err(EnrollError::TokenUsed)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
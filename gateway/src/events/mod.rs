@@ -0,0 +1,235 @@
+//! Live event stream
+//!
+//! `GET /events` streams Server-Sent Events as agents connect,
+//! disconnect or change their metadata, and as their checks change status,
+//! so UIs and CLIs can follow the zone instead of polling `/agents`. The
+//! `/agents` filters apply (`labels`, `zone`, `hostname`, `tenant_id`,
+//! `connected_since`), to the agent as it was at the time of the event.
+//!
+//! Each event's SSE name is its type, its data the event as JSON. A client
+//! too slow to keep up gets a `lagged` event with the number missed, and
+//! should read `/agents` again.
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{self, KeepAlive, Sse};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::registry::{AgentInfo, AgentQuery};
+use crate::{BackendMessage, GatewayState};
+
+/// Events held for slow subscribers before they miss some
+const CAPACITY: usize = 1024;
+
+/// Something that happened to an agent
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub agent: AgentInfo,
+    /// For `status_changed`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<StatusChange>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A check going from one status to another
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusChange {
+    pub component_id: String,
+    pub check_name: String,
+    /// None for the check's first status since the agent registered
+    pub previous: Option<String>,
+    pub status: String,
+    pub message: Option<String>,
+}
+
+/// Subscribers to the event stream
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    fn publish(&self, kind: &'static str, agent: AgentInfo, check: Option<StatusChange>) {
+        let _ = self.tx.send(Event {
+            kind,
+            agent,
+            check,
+            timestamp: Utc::now(),
+        });
+    }
+
+    /// Whether anyone listens, so events are only built for them
+    fn watched(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Publish a status update from an agent if its check changed status
+pub fn status(
+    state: &GatewayState,
+    agent_id: &str,
+    previous: Option<&str>,
+    update: &serde_json::Value,
+) {
+    let field = |name: &str| update.get(name).and_then(|v| v.as_str());
+    let status = match field("status") {
+        Some(status) if previous != Some(status) => status,
+        _ => return,
+    };
+    if !state.events.watched() {
+        return;
+    }
+    let Some(agent) = state.registry.get(agent_id) else {
+        return;
+    };
+    let check = StatusChange {
+        component_id: field("component_id").unwrap_or_default().to_string(),
+        check_name: field("check_name").unwrap_or_default().to_string(),
+        previous: previous.map(str::to_string),
+        status: status.to_string(),
+        message: field("message").map(str::to_string),
+    };
+    state.events.publish("status_changed", agent, Some(check));
+}
+
+/// Publish agents connecting, changing and disconnecting, until the
+/// gateway stops
+pub async fn run(state: Arc<GatewayState>) {
+    let mut rx = state.backend_tx.subscribe();
+    // Agents as last seen, to describe them once they are gone
+    let mut agents: HashMap<String, AgentInfo> = HashMap::new();
+
+    loop {
+        let message = match rx.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(missed)) => {
+                debug!(missed = missed, "Event stream fell behind");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        match message {
+            BackendMessage::AgentConnected(agent) => {
+                agents.insert(agent.id.clone(), agent.clone());
+                state.events.publish("agent_connected", agent, None);
+            }
+            BackendMessage::AgentMetadata(agent) => {
+                agents.insert(agent.id.clone(), agent.clone());
+                state.events.publish("agent_updated", agent, None);
+            }
+            BackendMessage::AgentDisconnected(agent_id) => {
+                if let Some(agent) = agents.remove(&agent_id) {
+                    state.events.publish("agent_disconnected", agent, None);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `GET /events`
+pub async fn stream(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<AgentQuery>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, (StatusCode, String)> {
+    let selector = query.selector().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let zone = state.config.gateway.zone.clone();
+    let rx = state.events.tx.subscribe();
+
+    let events = stream::unfold(rx, move |mut rx| {
+        let (query, selector, zone) = (query.clone(), selector.clone(), zone.clone());
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if query.matches(&event.agent, &selector, &zone) => {
+                        let data = serde_json::to_string(&event).unwrap_or_default();
+                        let event = sse::Event::default().event(event.kind).data(data);
+                        return Some((Ok(event), rx));
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        let event = sse::Event::default()
+                            .event("lagged")
+                            .data(missed.to_string());
+                        return Some((Ok(event), rx));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(id: &str, role: &str) -> AgentInfo {
+        AgentInfo {
+            id: id.to_string(),
+            hostname: format!("{}.prod", id),
+            labels: HashMap::from([("role".to_string(), role.to_string())]),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: Utc::now(),
+            last_heartbeat: Utc::now(),
+            health: Default::default(),
+            tx: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_filtered_by_query() {
+        use axum::response::IntoResponse;
+        use http_body_util::BodyExt;
+
+        let state = Arc::new(GatewayState::for_tests(crate::GatewayConfig::default()));
+        assert!(!state.events.watched());
+        let query: AgentQuery = serde_json::from_str(r#"{"labels": "role=database"}"#).unwrap();
+        let sse = stream(State(state.clone()), Query(query)).await.unwrap();
+        assert!(state.events.watched());
+
+        state
+            .events
+            .publish("agent_connected", agent("web-1", "web"), None);
+        state
+            .events
+            .publish("agent_connected", agent("db-1", "database"), None);
+
+        let mut body = sse.into_response().into_body();
+        let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(frame.starts_with("event: agent_connected\n"));
+        assert!(frame.contains(r#""id":"db-1""#));
+        assert!(!frame.contains("web-1"));
+    }
+}
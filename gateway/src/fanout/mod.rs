@@ -0,0 +1,544 @@
+//! Command fan-out
+//!
+//! A command routed by labels goes to every matching agent as one fan-out
+//! job, which collects the agents' final responses and sends the backend a
+//! `command_summary` once every agent has settled. The payload's `rollout`
+//! controls how the command spreads:
+//!
+//! ```json
+//! {"labels": {"role": "web"}, "command": {...}, "rollout": {"canary": 1, "max_parallel": 5}}
+//! ```
+//!
+//! - `canary`: this many agents run it first; the others start once all of
+//!   them succeeded, and are skipped if one did not;
+//! - `max_parallel`: at most this many agents run it at a time, the next
+//!   one starting as one settles.
+//!
+//! An agent settles with its final response, a refusal (policy, quarantine)
+//! or the pending command timeout. The per-agent responses still reach the
+//! backend as they come, except for fan-outs of `batch_command_types`
+//! (checks on hundreds of agents) when the backend accepted
+//! `response_batches`: their responses are held on the backend link and
+//! sent as `command_responses` batches of one fan-out, once `batch_size`
+//! are waiting, every `batch_interval_ms`, and before its
+//! `command_summary`.
+//!
+//! ```yaml
+//! fanout:
+//!   batch_command_types: [check]   # [] = every response on its own
+//!   batch_size: 100
+//!   batch_interval_ms: 500
+//! ```
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::backend_client::dispatch;
+use crate::registry::AgentCommand;
+use crate::{BackendMessage, GatewayState};
+
+/// Fan-out settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutSettings {
+    /// Command types whose fan-outs send their responses in batches
+    #[serde(default = "default_batch_command_types")]
+    pub batch_command_types: Vec<String>,
+    /// Responses in a batch at most
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Longest a response waits for its batch
+    #[serde(default = "default_batch_interval")]
+    pub batch_interval_ms: u64,
+}
+
+fn default_batch_command_types() -> Vec<String> {
+    vec!["check".to_string()]
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_batch_interval() -> u64 {
+    500
+}
+
+impl Default for FanOutSettings {
+    fn default() -> Self {
+        Self {
+            batch_command_types: default_batch_command_types(),
+            batch_size: default_batch_size(),
+            batch_interval_ms: default_batch_interval(),
+        }
+    }
+}
+
+/// How a command reaches the agents its labels select
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rollout {
+    /// Agents that run the command before the others
+    #[serde(default)]
+    pub canary: usize,
+    /// Agents running the command at a time (no limit by default)
+    #[serde(default)]
+    pub max_parallel: Option<usize>,
+}
+
+/// How the command went on one agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentOutcome {
+    pub agent_id: String,
+    /// The final response status, "rejected", "timeout" or "skipped"
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The outcome of a fan-out, for the backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandSummary {
+    pub job_id: String,
+    pub targeted: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    /// A canary failed, so the agents after it were skipped
+    pub aborted: bool,
+    pub results: Vec<AgentOutcome>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+/// Responses to one fan-out, sent to the backend together
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseBatch {
+    pub job_id: String,
+    pub responses: Vec<serde_json::Value>,
+}
+
+/// Responses held back for their batch, by fan-out
+#[derive(Debug, Default)]
+pub struct Batches {
+    jobs: HashMap<String, Vec<serde_json::Value>>,
+}
+
+impl Batches {
+    /// Hold a response back; its batch is returned once `size` are waiting
+    pub fn push(
+        &mut self,
+        job_id: String,
+        response: serde_json::Value,
+        size: usize,
+    ) -> Option<ResponseBatch> {
+        let responses = self.jobs.entry(job_id.clone()).or_default();
+        responses.push(response);
+        if responses.len() < size {
+            return None;
+        }
+        self.take(&job_id)
+    }
+
+    /// The responses of a fan-out waiting, if any
+    pub fn take(&mut self, job_id: &str) -> Option<ResponseBatch> {
+        self.jobs.remove(job_id).map(|responses| ResponseBatch {
+            job_id: job_id.to_string(),
+            responses,
+        })
+    }
+
+    /// Every response waiting
+    pub fn drain(&mut self) -> Vec<ResponseBatch> {
+        self.jobs
+            .drain()
+            .map(|(job_id, responses)| ResponseBatch { job_id, responses })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+/// A command on its way to the agents its labels selected
+#[derive(Debug)]
+pub struct FanOut {
+    command: AgentCommand,
+    requested_by: Option<String>,
+    roles: Vec<String>,
+    rollout: Rollout,
+    canaries: Vec<String>,
+    queued: VecDeque<String>,
+    running: HashSet<String>,
+    results: Vec<AgentOutcome>,
+    aborted: bool,
+    /// Its responses go to the backend in batches
+    batched: bool,
+    started_at: DateTime<Utc>,
+}
+
+/// Final statuses counted as success; a scheduled command is the agent's
+fn succeeded(status: &str) -> bool {
+    matches!(status, "completed" | "detached" | "scheduled")
+}
+
+impl FanOut {
+    pub fn new(
+        command: AgentCommand,
+        targets: Vec<String>,
+        rollout: Rollout,
+        requested_by: Option<String>,
+        roles: Vec<String>,
+    ) -> Self {
+        Self {
+            canaries: targets.iter().take(rollout.canary).cloned().collect(),
+            queued: targets.into(),
+            command,
+            requested_by,
+            roles,
+            rollout,
+            running: HashSet::new(),
+            results: Vec::new(),
+            aborted: false,
+            batched: false,
+            started_at: Utc::now(),
+        }
+    }
+
+    /// Agents to send the command to now
+    fn next(&mut self) -> Vec<String> {
+        let limit = match self.rollout.max_parallel {
+            Some(n) if n > 0 => n,
+            _ => usize::MAX,
+        };
+        let mut next = Vec::new();
+
+        while self.running.len() < limit {
+            let waits_for_canaries = match self.queued.front() {
+                Some(agent) => !self.canaries.contains(agent) && !self.canaries_settled(),
+                None => break,
+            };
+            if waits_for_canaries {
+                break;
+            }
+            if let Some(agent) = self.queued.pop_front() {
+                self.running.insert(agent.clone());
+                next.push(agent);
+            }
+        }
+        next
+    }
+
+    fn canaries_settled(&self) -> bool {
+        self.canaries
+            .iter()
+            .all(|c| self.results.iter().any(|r| &r.agent_id == c))
+    }
+
+    /// Record how an agent finished; false when it was not running
+    fn settle(&mut self, agent_id: &str, status: &str, error: Option<String>) -> bool {
+        if !self.running.remove(agent_id) {
+            return false;
+        }
+        self.results.push(AgentOutcome {
+            agent_id: agent_id.to_string(),
+            status: status.to_string(),
+            error,
+        });
+
+        if !succeeded(status) && self.canaries.iter().any(|c| c == agent_id) {
+            self.aborted = true;
+            let error = format!("Canary {} did not succeed", agent_id);
+            let skipped: Vec<AgentOutcome> = self
+                .queued
+                .drain(..)
+                .map(|agent_id| AgentOutcome {
+                    agent_id,
+                    status: "skipped".to_string(),
+                    error: Some(error.clone()),
+                })
+                .collect();
+            self.results.extend(skipped);
+        }
+        true
+    }
+
+    fn done(&self) -> bool {
+        self.running.is_empty() && self.queued.is_empty()
+    }
+
+    fn summary(&self) -> CommandSummary {
+        let skipped = self
+            .results
+            .iter()
+            .filter(|r| r.status == "skipped")
+            .count();
+        let succeeded = self.results.iter().filter(|r| succeeded(&r.status)).count();
+        CommandSummary {
+            job_id: self.command.id.clone(),
+            targeted: self.results.len(),
+            succeeded,
+            failed: self.results.len() - succeeded - skipped,
+            skipped,
+            aborted: self.aborted,
+            results: self.results.clone(),
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            correlation_id: self.command.correlation_id.clone(),
+        }
+    }
+}
+
+/// Fan-outs in progress, by command id
+#[derive(Default)]
+pub struct FanOuts {
+    jobs: Mutex<HashMap<String, FanOut>>,
+}
+
+impl FanOuts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fan-out a response is to, if its responses go in batches
+    pub fn batched(&self, response: &serde_json::Value) -> Option<String> {
+        let job_id = response.get("job_id")?.as_str()?;
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .is_some_and(|job| job.batched)
+            .then(|| job_id.to_string())
+    }
+}
+
+/// Send a command to the first of its agents
+pub async fn start(state: &GatewayState, mut fan_out: FanOut) {
+    let job_id = fan_out.command.id.clone();
+    fan_out.batched = state
+        .config
+        .fanout
+        .batch_command_types
+        .contains(&fan_out.command.command_type);
+    info!(
+        command_id = %job_id,
+        agents = fan_out.queued.len(),
+        canary = fan_out.rollout.canary,
+        max_parallel = ?fan_out.rollout.max_parallel,
+        "Fanning out command"
+    );
+
+    let first = fan_out.next();
+    state
+        .fanouts
+        .jobs
+        .lock()
+        .unwrap()
+        .insert(job_id.clone(), fan_out);
+    launch(state, &job_id, first).await;
+    finish_if_done(state, &job_id);
+}
+
+/// Take note of an agent's command response
+pub async fn answered(state: &GatewayState, agent_id: &str, response: &serde_json::Value) {
+    let field = |name: &str| response.get(name).and_then(|v| v.as_str());
+    let (job_id, status) = match (field("job_id"), field("status")) {
+        (Some(job_id), Some(status)) if status != "started" => (job_id, status),
+        _ => return,
+    };
+    settle(
+        state,
+        agent_id,
+        job_id,
+        status,
+        field("error").map(str::to_string),
+    )
+    .await;
+}
+
+/// Record how the command went on an agent, and send it to the next ones
+pub async fn settle(
+    state: &GatewayState,
+    agent_id: &str,
+    job_id: &str,
+    status: &str,
+    error: Option<String>,
+) {
+    let next = {
+        let mut jobs = state.fanouts.jobs.lock().unwrap();
+        let job = match jobs.get_mut(job_id) {
+            Some(job) => job,
+            None => return,
+        };
+        if !job.settle(agent_id, status, error) {
+            return;
+        }
+        job.next()
+    };
+    launch(state, job_id, next).await;
+    finish_if_done(state, job_id);
+}
+
+/// Send the command to `agents`; those refusing it settle at once, which
+/// may let more start
+async fn launch(state: &GatewayState, job_id: &str, mut agents: Vec<String>) {
+    while !agents.is_empty() {
+        let (command, requested_by, roles) = {
+            let jobs = state.fanouts.jobs.lock().unwrap();
+            match jobs.get(job_id) {
+                Some(job) => (
+                    job.command.clone(),
+                    job.requested_by.clone(),
+                    job.roles.clone(),
+                ),
+                None => return,
+            }
+        };
+
+        let mut refused = Vec::new();
+        for agent_id in agents {
+            if let Err(reason) =
+                dispatch(state, &command, &agent_id, requested_by.as_deref(), &roles).await
+            {
+                refused.push((agent_id, reason));
+            }
+        }
+
+        let mut jobs = state.fanouts.jobs.lock().unwrap();
+        agents = match jobs.get_mut(job_id) {
+            Some(job) if !refused.is_empty() => {
+                for (agent_id, reason) in refused {
+                    job.settle(&agent_id, "rejected", Some(reason));
+                }
+                job.next()
+            }
+            _ => Vec::new(),
+        };
+    }
+}
+
+/// Report a fan-out whose agents all settled
+fn finish_if_done(state: &GatewayState, job_id: &str) {
+    let summary = {
+        let mut jobs = state.fanouts.jobs.lock().unwrap();
+        if !jobs.get(job_id).is_some_and(FanOut::done) {
+            return;
+        }
+        jobs.remove(job_id).map(|job| job.summary())
+    };
+
+    if let Some(summary) = summary {
+        info!(
+            command_id = %summary.job_id,
+            succeeded = summary.succeeded,
+            failed = summary.failed,
+            skipped = summary.skipped,
+            "Fan-out finished"
+        );
+        state.schedules.finished(&summary);
+        crate::outbound::post(state, BackendMessage::CommandSummary(summary));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fan_out(agents: &[&str], canary: usize, max_parallel: Option<usize>) -> FanOut {
+        let command = AgentCommand {
+            id: "cmd-1".to_string(),
+            command_type: "restart".to_string(),
+            component_id: "web".to_string(),
+            action_name: None,
+            params: serde_json::Value::Null,
+            timeout_secs: 60,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: None,
+        };
+        FanOut::new(
+            command,
+            agents.iter().map(|a| a.to_string()).collect(),
+            Rollout {
+                canary,
+                max_parallel,
+            },
+            None,
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn test_rollout() {
+        let mut job = fan_out(&["a", "b", "c", "d"], 1, Some(2));
+        assert_eq!(job.next(), ["a"]);
+        // Waiting for the canary
+        assert!(job.next().is_empty());
+        assert!(!job.settle("x", "completed", None));
+
+        assert!(job.settle("a", "completed", None));
+        assert_eq!(job.next(), ["b", "c"]);
+        job.settle("b", "failed", Some("exit 1".to_string()));
+        assert_eq!(job.next(), ["d"]);
+        job.settle("c", "completed", None);
+        job.settle("d", "scheduled", None);
+        assert!(job.done());
+
+        let summary = job.summary();
+        assert_eq!(
+            (
+                summary.targeted,
+                summary.succeeded,
+                summary.failed,
+                summary.skipped
+            ),
+            (4, 3, 1, 0)
+        );
+        assert!(!summary.aborted);
+    }
+
+    #[test]
+    fn test_failed_canary() {
+        let mut job = fan_out(&["a", "b", "c"], 1, None);
+        assert_eq!(job.next(), ["a"]);
+        job.settle("a", "timeout", None);
+        assert!(job.next().is_empty());
+        assert!(job.done());
+
+        let summary = job.summary();
+        assert!(summary.aborted);
+        assert_eq!((summary.failed, summary.skipped), (1, 2));
+
+        // Without a rollout, everything at once
+        assert_eq!(fan_out(&["a", "b", "c"], 0, None).next(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_batches() {
+        let response = |agent: &str| serde_json::json!({"job_id": "cmd-1", "agent_id": agent});
+        let mut batches = Batches::default();
+        assert!(batches
+            .push("cmd-1".to_string(), response("a"), 2)
+            .is_none());
+        assert!(batches
+            .push("cmd-2".to_string(), response("x"), 2)
+            .is_none());
+        let full = batches.push("cmd-1".to_string(), response("b"), 2).unwrap();
+        assert_eq!(full.job_id, "cmd-1");
+        assert_eq!(full.responses[1]["agent_id"], "b");
+        assert!(batches.take("cmd-1").is_none());
+
+        assert!(batches
+            .push("cmd-1".to_string(), response("c"), 2)
+            .is_none());
+        assert_eq!(batches.take("cmd-1").unwrap().responses.len(), 1);
+        assert_eq!(batches.drain().len(), 1);
+        assert!(batches.is_empty());
+    }
+}
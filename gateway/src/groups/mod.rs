@@ -0,0 +1,417 @@
+//! Named agent groups
+//!
+//! A group names a set of agents, so operators and the backend can address
+//! "payments-prod-frontends" instead of repeating its selector everywhere.
+//! Its members are the agents matching its `labels` selector, plus those
+//! listed in `agents`, as they are connected now:
+//!
+//! ```yaml
+//! groups:
+//!   - name: payments-prod-frontends
+//!     labels: app=payments,env=prod,tier in (web,edge)
+//!   - name: db-primaries
+//!     agents: [pg-01, pg-07]
+//! ```
+//!
+//! Groups from the config are fixed; `PUT /groups/:name` and
+//! `DELETE /groups/:name` manage others, which last until the gateway
+//! restarts. A backend command with `group` fans out to the members like
+//! one with `labels`, as does `POST /groups/:name/command`, and
+//! `/summary` counts each group's agents in `agent_groups`.
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+use crate::auth::Caller;
+use crate::backend_client::{self, CommandPayload};
+use crate::fanout::Rollout;
+use crate::registry::{AgentCommand, AgentInfo, AgentRegistry};
+use crate::selector::Selector;
+use crate::GatewayState;
+
+/// A group as configured or put through the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupDefinition {
+    pub name: String,
+    /// Agents whose labels match are members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Selector>,
+    /// Agents that are members whatever their labels
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agents: Vec<String>,
+}
+
+impl GroupDefinition {
+    pub fn contains(&self, agent: &AgentInfo) -> bool {
+        self.agents.contains(&agent.id)
+            || self
+                .labels
+                .as_ref()
+                .is_some_and(|labels| labels.matches(&agent.labels))
+    }
+
+    /// What is wrong with the definition, if anything
+    pub fn check(&self) -> Result<(), String> {
+        let name_ok = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !name_ok {
+            return Err(format!(
+                "group name '{}' must be letters, digits, '-', '_' or '.'",
+                self.name
+            ));
+        }
+        if self.labels.is_none() && self.agents.is_empty() {
+            return Err(format!(
+                "group '{}' has neither labels nor agents",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Where a group comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Config,
+    Api,
+}
+
+/// A group with its connected members, for `GET /groups`
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    #[serde(flatten)]
+    pub definition: GroupDefinition,
+    pub source: Source,
+    pub members: Vec<String>,
+}
+
+/// The gateway's groups, by name
+pub struct Groups {
+    groups: RwLock<BTreeMap<String, (GroupDefinition, Source)>>,
+}
+
+impl Groups {
+    /// The groups of the config; invalid ones were refused by validation
+    pub fn new(configured: &[GroupDefinition]) -> Self {
+        let groups = configured
+            .iter()
+            .map(|group| (group.name.clone(), (group.clone(), Source::Config)))
+            .collect();
+        Self {
+            groups: RwLock::new(groups),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<GroupDefinition> {
+        self.groups
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|(group, _)| group.clone())
+    }
+
+    /// Every group, sorted by name
+    pub fn definitions(&self) -> Vec<GroupDefinition> {
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .map(|(group, _)| group.clone())
+            .collect()
+    }
+
+    /// Connected members of a group, sorted, or `None` for no such group
+    pub fn members(&self, name: &str, registry: &AgentRegistry) -> Option<Vec<String>> {
+        let group = self.get(name)?;
+        let mut members: Vec<String> = registry
+            .list()
+            .into_iter()
+            .filter(|agent| group.contains(agent))
+            .map(|agent| agent.id)
+            .collect();
+        members.sort();
+        Some(members)
+    }
+
+    /// Add or replace a group of the API; config groups stay as they are
+    pub fn put(&self, group: GroupDefinition) -> Result<(), String> {
+        group.check()?;
+        let mut groups = self.groups.write().unwrap();
+        if matches!(groups.get(&group.name), Some((_, Source::Config))) {
+            return Err(format!("group '{}' is defined in the config", group.name));
+        }
+        groups.insert(group.name.clone(), (group, Source::Api));
+        Ok(())
+    }
+
+    /// Remove a group of the API; `Ok(false)` if there was none
+    pub fn remove(&self, name: &str) -> Result<bool, String> {
+        let mut groups = self.groups.write().unwrap();
+        match groups.get(name) {
+            None => Ok(false),
+            Some((_, Source::Config)) => Err(format!("group '{}' is defined in the config", name)),
+            Some((_, Source::Api)) => Ok(groups.remove(name).is_some()),
+        }
+    }
+
+    fn info(&self, registry: &AgentRegistry) -> Vec<GroupInfo> {
+        let agents = registry.list();
+        self.groups
+            .read()
+            .unwrap()
+            .values()
+            .map(|(group, source)| {
+                let mut members: Vec<String> = agents
+                    .iter()
+                    .filter(|agent| group.contains(agent))
+                    .map(|agent| agent.id.clone())
+                    .collect();
+                members.sort();
+                GroupInfo {
+                    definition: group.clone(),
+                    source: *source,
+                    members,
+                }
+            })
+            .collect()
+    }
+}
+
+/// `GET /groups`
+pub async fn list(State(state): State<Arc<GatewayState>>) -> axum::Json<Vec<GroupInfo>> {
+    axum::Json(state.groups.info(&state.registry))
+}
+
+/// `GET /groups/:name`
+pub async fn get(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+) -> Result<axum::Json<GroupInfo>, (StatusCode, String)> {
+    state
+        .groups
+        .info(&state.registry)
+        .into_iter()
+        .find(|group| group.definition.name == name)
+        .map(axum::Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("No group {}", name)))
+}
+
+/// Body of `PUT /groups/:name`
+#[derive(Debug, Deserialize)]
+pub struct GroupRequest {
+    #[serde(default)]
+    labels: Option<Selector>,
+    #[serde(default)]
+    agents: Vec<String>,
+}
+
+/// `PUT /groups/:name`
+pub async fn put(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+    axum::Json(request): axum::Json<GroupRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let group = GroupDefinition {
+        name,
+        labels: request.labels,
+        agents: request.agents,
+    };
+    group.check().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let name = group.name.clone();
+    state
+        .groups
+        .put(group)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    info!(group = %name, "Agent group set");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /groups/:name`
+pub async fn remove(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.groups.remove(&name) {
+        Ok(true) => {
+            info!(group = %name, "Agent group removed");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("No group {}", name))),
+        Err(e) => Err((StatusCode::CONFLICT, e)),
+    }
+}
+
+/// Body of `POST /groups/:name/command`
+#[derive(Debug, Deserialize)]
+pub struct GroupCommandRequest {
+    command_type: String,
+    component_id: String,
+    #[serde(default)]
+    action_name: Option<String>,
+    #[serde(default)]
+    params: serde_json::Value,
+    #[serde(default = "default_command_timeout")]
+    timeout_secs: u64,
+    #[serde(default)]
+    rollout: Rollout,
+}
+
+fn default_command_timeout() -> u64 {
+    300
+}
+
+/// `POST /groups/:name/command`: fan a command out to the group's members,
+/// as the API token, for their tenant; answers 202 with the command id, and
+/// the backend gets the `command_summary`. A group whose members belong to
+/// different tenants is refused, a command only reaching one tenant.
+pub async fn command(
+    caller: Option<Extension<Caller>>,
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+    axum::Json(request): axum::Json<GroupCommandRequest>,
+) -> Result<(StatusCode, axum::Json<serde_json::Value>), (StatusCode, String)> {
+    let members = state
+        .groups
+        .members(&name, &state.registry)
+        .ok_or((StatusCode::NOT_FOUND, format!("No group {}", name)))?;
+    let tenants: BTreeSet<Option<String>> = members
+        .iter()
+        .filter_map(|agent_id| state.registry.get(agent_id))
+        .map(|agent| agent.tenant_id)
+        .collect();
+    let mut tenants = tenants.into_iter();
+    let tenant_id = match (tenants.next(), tenants.next()) {
+        (Some(tenant_id), None) => tenant_id,
+        (None, _) => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("No member of group {} is connected", name),
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("Members of group {} belong to different tenants", name),
+            ))
+        }
+    };
+
+    let command = AgentCommand {
+        id: uuid::Uuid::new_v4().to_string(),
+        command_type: request.command_type,
+        component_id: request.component_id,
+        action_name: request.action_name,
+        params: request.params,
+        timeout_secs: request.timeout_secs,
+        trace_context: None,
+        run_at: None,
+        run_after_secs: None,
+        tenant_id,
+        correlation_id: Some(uuid::Uuid::new_v4().to_string()),
+    };
+    let accepted = serde_json::json!({
+        "job_id": command.id,
+        "group": name,
+        "correlation_id": command.correlation_id,
+    });
+    let payload = CommandPayload {
+        agent_id: None,
+        labels: None,
+        command,
+        requested_by: caller.map(|Extension(Caller(name))| name),
+        roles: Vec::new(),
+        rollout: request.rollout,
+        strategy: None,
+        zone: None,
+        group: Some(name),
+    };
+    backend_client::route_command(payload, &state).await;
+    Ok((StatusCode::ACCEPTED, axum::Json(accepted)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn agent(id: &str, labels: &[(&str, &str)]) -> AgentInfo {
+        AgentInfo {
+            id: id.to_string(),
+            hostname: id.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 2,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: chrono::Utc::now(),
+            last_heartbeat: chrono::Utc::now(),
+            health: Default::default(),
+            tx: None,
+        }
+    }
+
+    fn group(yaml: &str) -> GroupDefinition {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_membership() {
+        let frontends = group("{name: frontends, labels: 'app=payments,tier in (web,edge)'}");
+        let primaries = group("{name: primaries, agents: [pg-01], labels: {role: primary}}");
+        assert!(frontends.contains(&agent("web-1", &[("app", "payments"), ("tier", "web")])));
+        assert!(!frontends.contains(&agent("db-1", &[("app", "payments"), ("tier", "db")])));
+        assert!(primaries.contains(&agent("pg-01", &[])));
+        assert!(primaries.contains(&agent("pg-02", &[("role", "primary")])));
+
+        let registry = AgentRegistry::new();
+        let groups = Groups::new(&[frontends]);
+        for agent in [
+            agent("web-2", &[("app", "payments"), ("tier", "edge")]),
+            agent("web-1", &[("app", "payments"), ("tier", "web")]),
+            agent("pg-01", &[]),
+        ] {
+            let (tx, _rx) = tokio::sync::mpsc::channel(1);
+            registry.register(agent, tx);
+        }
+        assert_eq!(
+            groups.members("frontends", &registry).unwrap(),
+            ["web-1", "web-2"]
+        );
+        assert!(groups.members("primaries", &registry).is_none());
+
+        groups.put(primaries).unwrap();
+        assert_eq!(groups.members("primaries", &registry).unwrap(), ["pg-01"]);
+    }
+
+    #[test]
+    fn test_config_groups_are_fixed() {
+        let groups = Groups::new(&[group("{name: frontends, labels: tier=web}")]);
+        assert!(groups.put(group("{name: frontends, agents: [a]}")).is_err());
+        assert!(groups.remove("frontends").is_err());
+
+        assert!(groups
+            .put(group("{name: 'bad name', agents: [a]}"))
+            .is_err());
+        assert!(groups.put(group("{name: empty}")).is_err());
+        groups.put(group("{name: canaries, agents: [a]}")).unwrap();
+        assert_eq!(groups.remove("canaries"), Ok(true));
+        assert_eq!(groups.remove("canaries"), Ok(false));
+    }
+}
@@ -0,0 +1,487 @@
+//! High availability
+//!
+//! Gateways of one zone behind a load balancer share which agents each has
+//! and hand over their pending commands, so the backend can send a command
+//! to any of them:
+//!
+//! ```yaml
+//! ha:
+//!   enabled: true
+//!   token: ${file:/etc/opsmap/secrets/peer-token}   # the same on every gateway
+//!   peers: [wss://gateway-2.internal:8443/peer]
+//! ```
+//!
+//! Each gateway keeps a WebSocket link to its peers (`/peer`, bearer
+//! `ha.token`) and tells them which agents it has: at once when one
+//! registers, and in full every `ha.sync_interval_secs`. Then:
+//!
+//! - a command for an agent connected to a peer is forwarded to it, and
+//!   authorized and tracked there;
+//! - an agent that reconnects through another gateway gets there the
+//!   commands left pending here, which are handed over.
+//!
+//! Responses reach the backend from the gateway holding the agent. Links
+//! need not be configured both ways; a peer's certificate must be trusted
+//! by `ha.ca_file` (`tls.ca_file` by default).
+
+use dashmap::DashMap;
+use futures_util::{future, Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+use tracing::{debug, info, warn};
+
+use crate::registry::AgentCommand;
+use crate::GatewayState;
+
+/// HA settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HaSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Peer gateway `/peer` URLs
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Shared secret the gateways present to each other
+    #[serde(default)]
+    pub token: String,
+    /// CA trusted for peers, `tls.ca_file` when unset
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval_secs: u64,
+    #[serde(default = "default_reconnect_interval")]
+    pub reconnect_interval_secs: u64,
+}
+
+fn default_sync_interval() -> u64 {
+    10
+}
+
+fn default_reconnect_interval() -> u64 {
+    5
+}
+
+impl Default for HaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            peers: Vec::new(),
+            token: String::new(),
+            ca_file: None,
+            sync_interval_secs: default_sync_interval(),
+            reconnect_interval_secs: default_reconnect_interval(),
+        }
+    }
+}
+
+/// Messages between gateways
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum PeerMessage {
+    #[serde(rename = "hello")]
+    Hello { gateway_id: String, zone: String },
+    /// Every agent the sender has, replacing what was known of it
+    #[serde(rename = "presence")]
+    Presence { agents: Vec<String> },
+    #[serde(rename = "agent_up")]
+    AgentUp { agent_id: String },
+    /// Commands pending for an agent now with the receiver
+    #[serde(rename = "handover")]
+    Handover {
+        agent_id: String,
+        commands: Vec<AgentCommand>,
+    },
+    /// A backend command for an agent the receiver has
+    #[serde(rename = "command")]
+    Command {
+        agent_id: String,
+        command: Box<AgentCommand>,
+        #[serde(default)]
+        requested_by: Option<String>,
+        #[serde(default)]
+        roles: Vec<String>,
+    },
+}
+
+/// A link to a peer gateway
+struct Link {
+    id: u64,
+    tx: mpsc::Sender<PeerMessage>,
+}
+
+/// What is known of the peer gateways
+#[derive(Default)]
+pub struct Peers {
+    /// Links by peer gateway id
+    links: DashMap<String, Link>,
+    /// Agents connected to a peer, with its gateway id
+    remote: DashMap<String, String>,
+    next_link: AtomicU64,
+}
+
+/// A peer as shown by `GET /peers`
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub gateway_id: String,
+    pub agents: usize,
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The peer an agent is connected to, if any
+    pub fn owner(&self, agent_id: &str) -> Option<String> {
+        self.remote.get(agent_id).map(|owner| owner.clone())
+    }
+
+    /// Linked peers, by gateway id
+    pub fn list(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .links
+            .iter()
+            .map(|link| PeerInfo {
+                gateway_id: link.key().clone(),
+                agents: self
+                    .remote
+                    .iter()
+                    .filter(|r| r.value() == link.key())
+                    .count(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.gateway_id.cmp(&b.gateway_id));
+        peers
+    }
+
+    fn send(&self, gateway_id: &str, message: PeerMessage) -> bool {
+        self.links
+            .get(gateway_id)
+            .is_some_and(|link| link.tx.try_send(message).is_ok())
+    }
+
+    /// Record what a peer says it has; returns the agents it just gained
+    fn presence(&self, gateway_id: &str, agents: Vec<String>) -> Vec<String> {
+        let gained: Vec<String> = agents
+            .iter()
+            .filter(|id| self.owner(id).as_deref() != Some(gateway_id))
+            .cloned()
+            .collect();
+        self.remote.retain(|_, owner| owner != gateway_id);
+        for agent_id in agents {
+            self.remote.insert(agent_id, gateway_id.to_string());
+        }
+        gained
+    }
+
+    /// Forget a peer whose link `link_id` closed, unless it linked again
+    fn unlink(&self, gateway_id: &str, link_id: u64) {
+        if self
+            .links
+            .remove_if(gateway_id, |_, link| link.id == link_id)
+            .is_some()
+        {
+            self.remote.retain(|_, owner| owner != gateway_id);
+        }
+    }
+}
+
+/// Whether a request carries the peer token
+pub fn authorized(settings: &HaSettings, headers: &axum::http::HeaderMap) -> bool {
+    !settings.token.is_empty() && crate::auth::bearer_matches(headers, [&settings.token]).is_some()
+}
+
+/// Tell the peers an agent registered here
+pub fn announce(state: &GatewayState, agent_id: &str) {
+    if !state.config.ha.enabled {
+        return;
+    }
+    state.peers.remote.remove(agent_id);
+    for link in state.peers.links.iter() {
+        let _ = link.tx.try_send(PeerMessage::AgentUp {
+            agent_id: agent_id.to_string(),
+        });
+    }
+}
+
+/// Send a command for an agent connected to a peer there; false when no
+/// peer has it
+pub fn forward(
+    state: &GatewayState,
+    agent_id: &str,
+    command: &AgentCommand,
+    requested_by: Option<&str>,
+    roles: &[String],
+) -> bool {
+    let owner = match state.peers.owner(agent_id) {
+        Some(owner) => owner,
+        None => return false,
+    };
+    let forwarded = state.peers.send(
+        &owner,
+        PeerMessage::Command {
+            agent_id: agent_id.to_string(),
+            command: Box::new(command.clone()),
+            requested_by: requested_by.map(str::to_string),
+            roles: roles.to_vec(),
+        },
+    );
+    if forwarded {
+        info!(agent_id = %agent_id, command_id = %command.id, peer = %owner, "Command forwarded to peer gateway");
+    }
+    forwarded
+}
+
+/// Keep links to the configured peers, until the gateway stops
+pub fn start(state: &Arc<GatewayState>) {
+    for url in state.config.ha.peers.clone() {
+        tokio::spawn(connect(state.clone(), url));
+    }
+}
+
+/// Keep a link to one peer
+async fn connect(state: Arc<GatewayState>, url: String) {
+    let wait = Duration::from_secs(state.config.ha.reconnect_interval_secs.max(1));
+    loop {
+        match open(&state, &url).await {
+            Ok(ws) => {
+                info!(peer = %url, "Connected to peer gateway");
+                let (sink, stream) = ws.split();
+                let sink = sink.with(|text: String| {
+                    future::ready(Ok::<_, tokio_tungstenite::tungstenite::Error>(
+                        Message::Text(text),
+                    ))
+                });
+                let stream = stream
+                    .take_while(|m| future::ready(m.is_ok()))
+                    .filter_map(|m| {
+                        future::ready(match m {
+                            Ok(Message::Text(text)) => Some(text),
+                            _ => None,
+                        })
+                    });
+                link(&state, Box::pin(sink), Box::pin(stream)).await;
+                warn!(peer = %url, "Link to peer gateway closed");
+            }
+            Err(e) => debug!(peer = %url, error = %e, "Failed to connect to peer gateway"),
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+async fn open(
+    state: &GatewayState,
+    url: &str,
+) -> anyhow::Result<
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        axum::http::header::AUTHORIZATION,
+        format!("Bearer {}", state.config.ha.token).parse()?,
+    );
+    let (ws, _) = if url.starts_with("wss://") {
+        let ca_file = state
+            .config
+            .ha
+            .ca_file
+            .as_deref()
+            .or(state.config.tls.ca_file.as_deref());
+        let connector = crate::backend_client::build_tls_connector(&state.config, ca_file)?;
+        tokio_tungstenite::connect_async_tls_with_config(request, None, false, Some(connector))
+            .await?
+    } else {
+        tokio_tungstenite::connect_async(request).await?
+    };
+    Ok(ws)
+}
+
+/// Serve a link opened by a peer on `/peer`
+pub async fn accept(state: Arc<GatewayState>, socket: axum::extract::ws::WebSocket) {
+    use axum::extract::ws::Message;
+
+    let (sink, stream) = socket.split();
+    let sink = sink.with(|text: String| future::ready(Ok::<_, axum::Error>(Message::Text(text))));
+    let stream = stream
+        .take_while(|m| future::ready(m.is_ok()))
+        .filter_map(|m| {
+            future::ready(match m {
+                Ok(Message::Text(text)) => Some(text),
+                _ => None,
+            })
+        });
+    link(&state, Box::pin(sink), Box::pin(stream)).await;
+}
+
+/// Exchange messages with a peer, whichever side opened the link
+async fn link<S, R>(state: &Arc<GatewayState>, mut sink: S, mut stream: R)
+where
+    S: Sink<String> + Unpin,
+    R: Stream<Item = String> + Unpin,
+{
+    let link_id = state.peers.next_link.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel::<PeerMessage>(1024);
+    let _ = tx.try_send(PeerMessage::Hello {
+        gateway_id: state.config.gateway.id.clone(),
+        zone: state.config.gateway.zone.clone(),
+    });
+    let mut sync = tokio::time::interval(Duration::from_secs(
+        state.config.ha.sync_interval_secs.max(1),
+    ));
+    let mut peer: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            Some(message) = rx.recv() => {
+                let text = match serde_json::to_string(&message) {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+                if sink.send(text).await.is_err() {
+                    break;
+                }
+            }
+            text = stream.next() => {
+                let text = match text {
+                    Some(text) => text,
+                    None => break,
+                };
+                match serde_json::from_str::<PeerMessage>(&text) {
+                    Ok(PeerMessage::Hello { gateway_id, zone }) => {
+                        if gateway_id == state.config.gateway.id {
+                            warn!("Peer link to this gateway itself, closing");
+                            break;
+                        }
+                        info!(peer = %gateway_id, zone = %zone, "Peer gateway linked");
+                        state.peers.links.insert(gateway_id.clone(), Link { id: link_id, tx: tx.clone() });
+                        peer = Some(gateway_id);
+                    }
+                    Ok(message) => match peer {
+                        Some(ref gateway_id) => handle(state, gateway_id, message).await,
+                        None => debug!("Peer message before hello, ignored"),
+                    },
+                    Err(e) => warn!(error = %e, "Invalid peer message"),
+                }
+            }
+            _ = sync.tick() => {
+                let agents = state.registry.list().into_iter().map(|a| a.id).collect();
+                let _ = tx.try_send(PeerMessage::Presence { agents });
+            }
+        }
+    }
+
+    if let Some(gateway_id) = peer {
+        state.peers.unlink(&gateway_id, link_id);
+        info!(peer = %gateway_id, "Peer gateway unlinked");
+    }
+}
+
+async fn handle(state: &GatewayState, peer: &str, message: PeerMessage) {
+    match message {
+        PeerMessage::Hello { .. } => {}
+        PeerMessage::Presence { agents } => {
+            for agent_id in state.peers.presence(peer, agents) {
+                hand_over(state, peer, &agent_id);
+            }
+        }
+        PeerMessage::AgentUp { agent_id } => {
+            state
+                .peers
+                .remote
+                .insert(agent_id.clone(), peer.to_string());
+            hand_over(state, peer, &agent_id);
+        }
+        PeerMessage::Handover { agent_id, commands } => {
+            info!(agent_id = %agent_id, peer = %peer, commands = commands.len(), "Pending commands handed over");
+            for command in &commands {
+                state.commands.track(&agent_id, command);
+            }
+            crate::commands::redeliver(state, &agent_id);
+        }
+        PeerMessage::Command {
+            agent_id,
+            command,
+            requested_by,
+            roles,
+        } => {
+            debug!(agent_id = %agent_id, command_id = %command.id, peer = %peer, "Command from peer gateway");
+            let _ = crate::backend_client::dispatch(
+                state,
+                &command,
+                &agent_id,
+                requested_by.as_deref(),
+                &roles,
+            )
+            .await;
+        }
+    }
+}
+
+/// Send a peer the commands pending here for an agent it now has
+fn hand_over(state: &GatewayState, peer: &str, agent_id: &str) {
+    if state.registry.get(agent_id).is_some() {
+        return;
+    }
+    let commands = state.commands.take(agent_id);
+    if commands.is_empty() {
+        return;
+    }
+    let count = commands.len();
+    let handover = PeerMessage::Handover {
+        agent_id: agent_id.to_string(),
+        commands: commands.clone(),
+    };
+    if state.peers.send(peer, handover) {
+        info!(agent_id = %agent_id, peer = %peer, commands = count, "Pending commands handed to peer gateway");
+    } else {
+        // Kept for when the agent or the link comes back
+        for command in &commands {
+            state.commands.track(agent_id, command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers() {
+        let peers = Peers::new();
+        let (tx, _rx) = mpsc::channel(4);
+        peers.links.insert("gw-2".to_string(), Link { id: 7, tx });
+
+        assert_eq!(
+            peers.presence("gw-2", vec!["a".into(), "b".into()]),
+            ["a", "b"]
+        );
+        // Only agents new to the peer are gained
+        assert_eq!(peers.presence("gw-2", vec!["b".into(), "c".into()]), ["c"]);
+        assert_eq!(peers.owner("a"), None);
+        assert_eq!(peers.owner("c").as_deref(), Some("gw-2"));
+        assert_eq!(peers.list()[0].agents, 2);
+
+        // A link replaced by a newer one does not take the peer away
+        peers.unlink("gw-2", 6);
+        assert_eq!(peers.owner("b").as_deref(), Some("gw-2"));
+        peers.unlink("gw-2", 7);
+        assert!(peers.owner("b").is_none() && peers.list().is_empty());
+
+        let settings = HaSettings {
+            token: "peer-token-0123456789".to_string(),
+            ..HaSettings::default()
+        };
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(!authorized(&settings, &headers));
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            "Bearer peer-token-0123456789".parse().unwrap(),
+        );
+        assert!(authorized(&settings, &headers));
+        assert!(!authorized(&HaSettings::default(), &headers));
+    }
+}
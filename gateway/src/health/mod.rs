@@ -0,0 +1,149 @@
+//! Agent health
+//!
+//! A connection can outlive its agent: behind a NAT or a load balancer the
+//! socket stays open while the agent is hung, or too busy to read what it
+//! is sent. Every connected agent is given a health from the age of its
+//! last heartbeat (any message or ping counts) and from messages its
+//! channel refused lately:
+//!
+//! - `healthy`;
+//! - `degraded`: silent for `degraded_after_secs`, or a send to it failed
+//!   within `send_failure_secs`;
+//! - `stale`: silent for `stale_after_secs`.
+//!
+//! Health is shown in `/agents`, and an `agent_metadata` message tells the
+//! backend each time it changes.
+//!
+//! ```yaml
+//! health:
+//!   degraded_after_secs: 75   # agents ping every 30s by default
+//!   stale_after_secs: 150
+//!   send_failure_secs: 300
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::{BackendMessage, GatewayState};
+
+/// How often agents' health is worked out again
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Agent health settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthSettings {
+    /// Seconds without a heartbeat before an agent is degraded
+    #[serde(default = "default_degraded_after")]
+    pub degraded_after_secs: u64,
+    /// Seconds without a heartbeat before an agent is stale
+    #[serde(default = "default_stale_after")]
+    pub stale_after_secs: u64,
+    /// Seconds an agent stays degraded after a send to it failed
+    #[serde(default = "default_send_failure")]
+    pub send_failure_secs: u64,
+}
+
+fn default_degraded_after() -> u64 {
+    75
+}
+
+fn default_stale_after() -> u64 {
+    150
+}
+
+fn default_send_failure() -> u64 {
+    300
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            degraded_after_secs: default_degraded_after(),
+            stale_after_secs: default_stale_after(),
+            send_failure_secs: default_send_failure(),
+        }
+    }
+}
+
+/// How a connected agent is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentHealth {
+    #[default]
+    Healthy,
+    Degraded,
+    Stale,
+}
+
+impl AgentHealth {
+    /// Health of an agent last heard from `heartbeat_age_secs` ago, whose
+    /// last failed send was `send_failure_age_secs` ago, if ever
+    pub fn of(
+        heartbeat_age_secs: u64,
+        send_failure_age_secs: Option<u64>,
+        settings: &HealthSettings,
+    ) -> Self {
+        if heartbeat_age_secs >= settings.stale_after_secs {
+            AgentHealth::Stale
+        } else if heartbeat_age_secs >= settings.degraded_after_secs
+            || send_failure_age_secs.is_some_and(|age| age < settings.send_failure_secs)
+        {
+            AgentHealth::Degraded
+        } else {
+            AgentHealth::Healthy
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AgentHealth::Healthy => "healthy",
+            AgentHealth::Degraded => "degraded",
+            AgentHealth::Stale => "stale",
+        }
+    }
+}
+
+/// Work out agents' health every few seconds, telling the backend of changes
+pub async fn run(state: Arc<GatewayState>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        for (agent, previous) in state.registry.refresh_health(&state.config.health) {
+            let (from, to) = (previous.as_str(), agent.health.as_str());
+            if agent.health == AgentHealth::Healthy {
+                info!(agent_id = %agent.id, from = from, to = to, "Agent healthy again");
+            } else {
+                warn!(agent_id = %agent.id, from = from, to = to, "Agent health changed");
+            }
+            crate::outbound::post(&state, BackendMessage::AgentMetadata(agent));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of() {
+        let settings = HealthSettings::default();
+        assert_eq!(AgentHealth::of(10, None, &settings), AgentHealth::Healthy);
+        assert_eq!(AgentHealth::of(75, None, &settings), AgentHealth::Degraded);
+        assert_eq!(AgentHealth::of(150, None, &settings), AgentHealth::Stale);
+        // A recent failed send degrades an agent that still pings
+        assert_eq!(
+            AgentHealth::of(10, Some(20), &settings),
+            AgentHealth::Degraded
+        );
+        assert_eq!(
+            AgentHealth::of(10, Some(300), &settings),
+            AgentHealth::Healthy
+        );
+        assert_eq!(
+            AgentHealth::of(200, Some(20), &settings),
+            AgentHealth::Stale
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Rate and size limits
+//!
+//! An agent stuck in a loop can flood the backend link with status deltas,
+//! and a crashing one reconnect as fast as it can. Each agent connection
+//! gets a token bucket for messages and one for bytes; past either, its
+//! messages are dropped or its connection closed, depending on `on_limit`.
+//! Sequenced messages dropped this way are not acked, so the agent sends
+//! them again later. Connection attempts (`/ws`, and `/poll` without a
+//! session) are limited per source address.
+//!
+//! Caps keep a zone from exhausting the gateway's memory: connections past
+//! `max_connections` are closed with 1013 (try again later), frames over
+//! `max_frame_bytes` and registrations over `max_register_bytes` with 1009
+//! (message too big). A message over `max_message_bytes` once inflated, or
+//! from the backend over `max_backend_message_bytes`, is refused with a
+//! `protocol_error` before it is parsed (see `schema`). What goes to the
+//! backend waits in a queue of `backend_queue` messages, of which one
+//! connection holds `connection_queue` at most (see `outbound`).
+//!
+//! ```yaml
+//! limits:
+//!   messages_per_sec: 50
+//!   bytes_per_sec: 1048576
+//!   burst_secs: 5            # traffic at the limit allowed at once
+//!   on_limit: drop           # or close
+//!   connections_per_ip_per_min: 30
+//!   max_connections: 5000    # WebSockets and poll sessions
+//!   max_frame_bytes: 16777216
+//!   max_register_bytes: 65536
+//!   max_message_bytes: 16777216
+//!   max_backend_message_bytes: 67108864
+//!   backend_queue: 10000
+//!   connection_queue: 100    # then the connection is not read for a while
+//!   backend_queue_wait_secs: 10
+//! ```
+//!
+//! Rate limits and `max_connections` are off at 0, the default.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Addresses tracked before idle ones are forgotten
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+
+/// Rate limit settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LimitSettings {
+    /// Messages per second from one agent connection
+    #[serde(default)]
+    pub messages_per_sec: u32,
+    /// Bytes per second from one agent connection
+    #[serde(default)]
+    pub bytes_per_sec: u64,
+    /// Seconds of traffic at the limit an agent may send at once
+    #[serde(default = "default_burst_secs")]
+    pub burst_secs: u32,
+    #[serde(default)]
+    pub on_limit: LimitAction,
+    /// Connection attempts per minute from one source address
+    #[serde(default)]
+    pub connections_per_ip_per_min: u32,
+    /// Agent connections open at once, WebSockets and poll sessions
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Largest WebSocket frame, and message, taken from an agent
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Largest registration message
+    #[serde(default = "default_max_register_bytes")]
+    pub max_register_bytes: usize,
+    /// Largest message from an agent, once inflated
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// Largest message from the backend, once inflated
+    #[serde(default = "default_max_backend_message_bytes")]
+    pub max_backend_message_bytes: usize,
+    /// Messages waiting for the backend link, from every connection
+    #[serde(default = "default_backend_queue")]
+    pub backend_queue: usize,
+    /// Messages of one connection waiting for the backend link; past it,
+    /// the connection is not read until some are sent
+    #[serde(default = "default_connection_queue")]
+    pub connection_queue: usize,
+    /// Longest a connection waits for room before its message is dropped
+    #[serde(default = "default_backend_queue_wait")]
+    pub backend_queue_wait_secs: u64,
+}
+
+fn default_burst_secs() -> u32 {
+    5
+}
+
+fn default_max_frame_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_register_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_message_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_backend_message_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_backend_queue() -> usize {
+    10_000
+}
+
+fn default_connection_queue() -> usize {
+    100
+}
+
+fn default_backend_queue_wait() -> u64 {
+    10
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 0,
+            bytes_per_sec: 0,
+            burst_secs: default_burst_secs(),
+            on_limit: LimitAction::default(),
+            connections_per_ip_per_min: 0,
+            max_connections: 0,
+            max_frame_bytes: default_max_frame_bytes(),
+            max_register_bytes: default_max_register_bytes(),
+            max_message_bytes: default_max_message_bytes(),
+            max_backend_message_bytes: default_max_backend_message_bytes(),
+            backend_queue: default_backend_queue(),
+            connection_queue: default_connection_queue(),
+            backend_queue_wait_secs: default_backend_queue_wait(),
+        }
+    }
+}
+
+/// What happens to an agent over its limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Drop its messages until it slows down
+    #[default]
+    Drop,
+    /// Close its connection
+    Close,
+}
+
+/// Tokens refilled at a steady rate, up to a burst
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst_secs: u32, now: Instant) -> Self {
+        let capacity = rate * f64::from(burst_secs.max(1));
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take `amount` tokens while any are left; a large amount runs the
+    /// bucket into debt rather than never fitting
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    fn full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// The limits of one agent connection
+#[derive(Debug)]
+pub struct AgentLimiter {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    action: LimitAction,
+    /// Messages dropped since the last one let through
+    dropped: u64,
+}
+
+impl AgentLimiter {
+    pub fn new(settings: &LimitSettings) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: f64| (rate > 0.0).then(|| Bucket::new(rate, settings.burst_secs, now));
+        Self {
+            messages: bucket(f64::from(settings.messages_per_sec)),
+            bytes: bucket(settings.bytes_per_sec as f64),
+            action: settings.on_limit,
+            dropped: 0,
+        }
+    }
+
+    /// Let a message of `len` bytes through, or name the limit it is over
+    pub fn check(&mut self, len: usize) -> Result<(), &'static str> {
+        self.check_at(len, Instant::now())
+    }
+
+    fn check_at(&mut self, len: usize, now: Instant) -> Result<(), &'static str> {
+        let over = if self.messages.as_mut().is_some_and(|b| !b.take(1.0, now)) {
+            Some("messages")
+        } else if self
+            .bytes
+            .as_mut()
+            .is_some_and(|b| !b.take(len as f64, now))
+        {
+            Some("bytes")
+        } else {
+            None
+        };
+        match over {
+            Some(limit) => {
+                self.dropped += 1;
+                Err(limit)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.action
+    }
+
+    /// Messages dropped since the last one let through
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The same, resetting the count
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+/// Connection attempts by source address, and connections open
+pub struct ConnectionLimiter {
+    per_min: u32,
+    addresses: DashMap<IpAddr, Bucket>,
+    max_connections: usize,
+    open: AtomicUsize,
+}
+
+/// An open WebSocket, counted until dropped
+pub struct ConnectionSlot<'a>(&'a ConnectionLimiter);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(settings: &LimitSettings) -> Self {
+        Self {
+            per_min: settings.connections_per_ip_per_min,
+            addresses: DashMap::new(),
+            max_connections: settings.max_connections,
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a WebSocket as open, unless it and the `others` (poll
+    /// sessions) would go over `max_connections`
+    pub fn open(&self, others: usize) -> Option<ConnectionSlot<'_>> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(self);
+        if self.max_connections > 0 && open + others >= self.max_connections {
+            return None;
+        }
+        Some(slot)
+    }
+
+    /// Whether another connection would go over `max_connections`, with
+    /// `others` open besides the WebSockets
+    pub fn full(&self, others: usize) -> bool {
+        self.max_connections > 0
+            && self.open.load(Ordering::SeqCst) + others >= self.max_connections
+    }
+
+    /// Count a connection attempt, false when the address is over its limit
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.per_min == 0 {
+            return true;
+        }
+        // A full bucket is the same as none
+        if self.addresses.len() >= MAX_TRACKED_ADDRESSES {
+            self.addresses.retain(|_, bucket| !bucket.full(now));
+        }
+        let rate = f64::from(self.per_min) / 60.0;
+        self.addresses
+            .entry(ip)
+            .or_insert_with(|| Bucket {
+                tokens: f64::from(self.per_min),
+                capacity: f64::from(self.per_min),
+                rate,
+                updated: now,
+            })
+            .take(1.0, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_agent_limiter() {
+        let settings = LimitSettings {
+            messages_per_sec: 10,
+            bytes_per_sec: 1000,
+            burst_secs: 1,
+            ..Default::default()
+        };
+        let mut limiter = AgentLimiter::new(&settings);
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check_at(10, start).is_ok());
+        }
+        assert_eq!(limiter.check_at(10, start), Err("messages"));
+        assert_eq!(limiter.take_dropped(), 1);
+
+        // Refilled after a second; a large message borrows from the next
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(1500, later).is_ok());
+        assert_eq!(limiter.check_at(10, later), Err("bytes"));
+        assert!(limiter.check_at(10, later + Duration::from_secs(1)).is_ok());
+
+        let mut unlimited = AgentLimiter::new(&LimitSettings::default());
+        assert!((0..10_000).all(|_| unlimited.check(1 << 20).is_ok()));
+    }
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new(&LimitSettings {
+            connections_per_ip_per_min: 2,
+            ..Default::default()
+        });
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.allow_at(a, start));
+        assert!(limiter.allow_at(a, start));
+        assert!(!limiter.allow_at(a, start));
+        assert!(limiter.allow_at(b, start));
+        // One more every 30 seconds
+        assert!(limiter.allow_at(a, start + Duration::from_secs(31)));
+
+        let capped = ConnectionLimiter::new(&LimitSettings {
+            max_connections: 2,
+            ..Default::default()
+        });
+        let first = capped.open(0).unwrap();
+        assert!(capped.open(1).is_none());
+        assert!(capped.full(1));
+        let second = capped.open(0).unwrap();
+        assert!(capped.open(0).is_none());
+        drop((first, second));
+        assert!(!capped.full(1));
+    }
+}
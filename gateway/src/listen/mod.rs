@@ -0,0 +1,167 @@
+//! Listening addresses
+//!
+//! The gateway listens on `gateway.listen_addr:listen_port`, or on each
+//! address of `gateway.listen` when set, e.g. both families of a
+//! dual-stack host:
+//!
+//! ```yaml
+//! gateway:
+//!   listen: ["0.0.0.0:8443", "[::]:8443"]
+//! ```
+//!
+//! An entry without a port takes `listen_port`. An IPv6 socket sharing its
+//! port with an IPv4 one is bound v6-only, so the two do not collide; on
+//! its own `[::]` also takes IPv4 clients where the host maps them. On a
+//! host without one of the families (IPv4 on a v6-only host), a wildcard
+//! address of that family is skipped with a warning, as long as another
+//! address could be bound.
+
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+use crate::GatewaySettings;
+
+/// Pending connections queued by the kernel, as tokio's default
+const BACKLOG: i32 = 1024;
+
+/// The addresses to listen on, in the order given
+pub fn addresses(settings: &GatewaySettings) -> Result<Vec<SocketAddr>, String> {
+    if settings.listen.is_empty() {
+        let ip = settings.listen_addr.parse::<IpAddr>().map_err(|_| {
+            format!(
+                "gateway.listen_addr '{}' is not an IP address",
+                settings.listen_addr
+            )
+        })?;
+        return Ok(vec![SocketAddr::new(ip, settings.listen_port)]);
+    }
+
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for entry in &settings.listen {
+        let addr = parse(entry, settings.listen_port).ok_or_else(|| {
+            format!(
+                "gateway.listen '{}' is not an address like 0.0.0.0:8443 or [::]:8443",
+                entry
+            )
+        })?;
+        if addrs.contains(&addr) {
+            return Err(format!("gateway.listen has {} twice", addr));
+        }
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+/// `ip:port`, `[ipv6]:port`, or an address alone with `default_port`
+fn parse(entry: &str, default_port: u16) -> Option<SocketAddr> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = entry
+        .strip_prefix('[')
+        .and_then(|e| e.strip_suffix(']'))
+        .unwrap_or(entry);
+    ip.parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, default_port))
+}
+
+/// Bind a listener on each address
+pub fn bind(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for &addr in addrs {
+        let socket = match Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!(addr = %addr, error = %e, "Address family not available on this host, not listening on it");
+                continue;
+            }
+        };
+        let v6_only = addr.is_ipv6()
+            && addrs
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == addr.port());
+        match listen(socket, addr, v6_only) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if e.kind() == ErrorKind::AddrNotAvailable && addr.ip().is_unspecified() => {
+                warn!(addr = %addr, error = %e, "Address family not available on this host, not listening on it");
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to listen on {}", addr)),
+        }
+    }
+    if listeners.is_empty() {
+        bail!("None of the listen addresses could be bound");
+    }
+    Ok(listeners)
+}
+
+fn listen(socket: Socket, addr: SocketAddr, v6_only: bool) -> std::io::Result<TcpListener> {
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    // As TcpListener::bind does, so a restart does not wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(listen: &[&str]) -> GatewaySettings {
+        let mut settings = crate::GatewayConfig::default().gateway;
+        settings.listen = listen.iter().map(|s| s.to_string()).collect();
+        settings
+    }
+
+    #[test]
+    fn test_addresses() {
+        let single = addresses(&settings(&[])).unwrap();
+        assert_eq!(single, vec!["0.0.0.0:8443".parse().unwrap()]);
+
+        let dual = addresses(&settings(&[
+            "0.0.0.0:8443",
+            "[::]:8443",
+            "::1",
+            "[fe80::1]",
+        ]))
+        .unwrap();
+        let expected: Vec<SocketAddr> =
+            ["0.0.0.0:8443", "[::]:8443", "[::1]:8443", "[fe80::1]:8443"]
+                .iter()
+                .map(|a| a.parse().unwrap())
+                .collect();
+        assert_eq!(dual, expected);
+
+        assert!(addresses(&settings(&["localhost:8443"])).is_err());
+        assert!(addresses(&settings(&["0.0.0.0", "0.0.0.0:8443"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() {
+        // Port 0 is a different port for each; bind one, then both families on it
+        let first = bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let port = first[0].local_addr().unwrap().port();
+        drop(first);
+
+        let addrs: Vec<SocketAddr> = vec![
+            SocketAddr::new("0.0.0.0".parse().unwrap(), port),
+            SocketAddr::new("::".parse().unwrap(), port),
+        ];
+        let listeners = bind(&addrs).unwrap();
+        assert!(!listeners.is_empty());
+        assert!(listeners
+            .iter()
+            .all(|l| l.local_addr().unwrap().port() == port));
+    }
+}
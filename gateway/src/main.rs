@@ -1,36 +1,81 @@
 //! OpsMap Gateway - Zone relay between agents and backend
 //!
 //! The Gateway:
-//! - Accepts WebSocket connections from Agents
+//! - Accepts WebSocket connections from Agents, or HTTPS polls from Agents
+//!   that cannot hold one open
 //! - Maintains a registry of connected agents
 //! - Connects to the Backend via WebSocket
 //! - Routes commands from Backend to appropriate Agents
 //! - Aggregates and forwards agent status updates to Backend
 
 mod agent_server;
+mod audit;
+mod auth;
+mod backend_auth;
 mod backend_client;
+mod commands;
+mod config_update;
+mod dashboard;
+mod deadletter;
+mod delivery;
+mod downstream;
+mod enrollment;
+mod events;
+mod fanout;
+mod groups;
+mod ha;
+mod health;
+#[path = "../../shared/interpolate.rs"]
+mod interpolate;
+mod limits;
+mod listen;
+mod metrics;
+mod outbound;
+mod persist;
+mod policy;
+mod poll;
+mod protocol;
 mod registry;
 mod router;
+mod scheduler;
+mod schema;
+mod selector;
+mod sqlite;
+mod summary;
+mod telemetry;
+mod tls;
+mod validate;
+mod versions;
+mod webhooks;
 
 use anyhow::Result;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, Path, Query, State,
     },
-    routing::get,
-    Router,
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Extension, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
-use registry::{AgentInfo, AgentRegistry};
+use audit::{AuditEvent, AuditLog, AuditQuery};
+use auth::ApiSettings;
+use enrollment::{EnrollError, EnrollRequest, EnrollResponse, Enrollment, EnrollmentSettings};
+use metrics::GatewayMetrics;
+use policy::{PolicyEngine, RbacSettings};
+use registry::{AgentDetails, AgentInfo, AgentQuery, AgentRegistry};
+use versions::{Verdict, VersionGate, VersionPolicy};
 
 /// Gateway configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,32 +83,126 @@ pub struct GatewayConfig {
     pub gateway: GatewaySettings,
     pub backend: BackendSettings,
     pub tls: TlsSettings,
+    #[serde(default)]
+    pub audit: AuditSettings,
+    #[serde(default)]
+    pub rbac: RbacSettings,
+    #[serde(default)]
+    pub enrollment: EnrollmentSettings,
+    #[serde(default)]
+    pub agent_versions: VersionPolicy,
+    #[serde(default)]
+    pub api: ApiSettings,
+    #[serde(default)]
+    pub commands: commands::CommandSettings,
+    #[serde(default)]
+    pub fanout: fanout::FanOutSettings,
+    #[serde(default)]
+    pub ha: ha::HaSettings,
+    #[serde(default)]
+    pub health: health::HealthSettings,
+    #[serde(default)]
+    pub downstream: downstream::DownstreamSettings,
+    #[serde(default)]
+    pub limits: limits::LimitSettings,
+    #[serde(default)]
+    pub webhooks: Vec<webhooks::Webhook>,
+    #[serde(default)]
+    pub dashboard: dashboard::DashboardSettings,
+    #[serde(default)]
+    pub persistence: persist::PersistenceSettings,
+    #[serde(default)]
+    pub dead_letters: deadletter::DeadLetterSettings,
+    #[serde(default)]
+    pub groups: Vec<groups::GroupDefinition>,
+    #[serde(default)]
+    pub schedules: scheduler::ScheduleSettings,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatewaySettings {
     pub id: String,
     pub zone: String,
+    #[serde(default = "default_listen_addr")]
     pub listen_addr: String,
     #[serde(default = "default_listen_port")]
     pub listen_port: u16,
+    /// Addresses to listen on instead of `listen_addr`, e.g. "0.0.0.0:8443"
+    /// and "[::]:8443" for both families
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Frames larger than this are deflated, on both links, for peers that
+    /// accepted the "deflate" capability
+    #[serde(default = "default_compress_above")]
+    pub compress_above: usize,
+    /// Polling agents silent for this long are considered disconnected
+    #[serde(default = "default_poll_session_timeout")]
+    pub poll_session_timeout_secs: u64,
+    /// Zones besides `zone` whose agents may be sent commands; "*" for any
+    #[serde(default)]
+    pub allowed_zones: Vec<String>,
+}
+
+impl GatewaySettings {
+    /// Whether agents of `zone` may be sent commands through this gateway
+    pub fn commands_zone(&self, zone: &str) -> bool {
+        zone == self.zone || self.allowed_zones.iter().any(|z| z == zone || z == "*")
+    }
+}
+
+fn default_listen_addr() -> String {
+    "0.0.0.0".to_string()
 }
 
 fn default_listen_port() -> u16 {
     8443
 }
 
+fn default_compress_above() -> usize {
+    64 * 1024
+}
+
+fn default_poll_session_timeout() -> u64 {
+    120
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackendSettings {
     pub url: String,
     #[serde(default = "default_reconnect_interval")]
     pub reconnect_interval_secs: u64,
+    /// Backend CA; when set, it is the only CA trusted for the backend
+    /// (otherwise the system roots are used)
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Tokens the gateway and backend authenticate each other with
+    #[serde(default)]
+    pub auth: backend_auth::BackendAuth,
+    /// Backends to fail over to, by priority, when `url` is down
+    #[serde(default)]
+    pub standby_urls: Vec<String>,
+    /// How often, while on a standby, the backends before it are tried
+    #[serde(default = "default_failback_interval")]
+    pub failback_interval_secs: u64,
 }
 
 fn default_reconnect_interval() -> u64 {
     5
 }
 
+fn default_failback_interval() -> u64 {
+    60
+}
+
+impl BackendSettings {
+    /// `url` then the standbys, most preferred first
+    pub fn urls(&self) -> Vec<&str> {
+        std::iter::once(self.url.as_str())
+            .chain(self.standby_urls.iter().map(String::as_str))
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsSettings {
     pub enabled: bool,
@@ -78,18 +217,52 @@ fn default_verify_clients() -> bool {
     true
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditSettings {
+    /// Record routed commands and their results
+    #[serde(default = "default_audit_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_audit_file")]
+    pub file_path: String,
+}
+
+fn default_audit_enabled() -> bool {
+    true
+}
+
+fn default_audit_file() -> String {
+    "/var/lib/opsmap/gateway-audit.jsonl".to_string()
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_audit_enabled(),
+            file_path: default_audit_file(),
+        }
+    }
+}
+
 impl Default for GatewayConfig {
     fn default() -> Self {
         Self {
             gateway: GatewaySettings {
                 id: "gateway-1".to_string(),
                 zone: "default".to_string(),
-                listen_addr: "0.0.0.0".to_string(),
+                listen_addr: default_listen_addr(),
                 listen_port: 8443,
+                listen: Vec::new(),
+                compress_above: default_compress_above(),
+                poll_session_timeout_secs: default_poll_session_timeout(),
+                allowed_zones: Vec::new(),
             },
             backend: BackendSettings {
                 url: "wss://backend.opsmap.local:443/gateway".to_string(),
                 reconnect_interval_secs: 5,
+                ca_file: None,
+                auth: backend_auth::BackendAuth::default(),
+                standby_urls: Vec::new(),
+                failback_interval_secs: default_failback_interval(),
             },
             tls: TlsSettings {
                 enabled: true,
@@ -98,6 +271,23 @@ impl Default for GatewayConfig {
                 ca_file: Some("/etc/opsmap/certs/ca.crt".to_string()),
                 verify_clients: true,
             },
+            audit: AuditSettings::default(),
+            rbac: RbacSettings::default(),
+            enrollment: EnrollmentSettings::default(),
+            agent_versions: VersionPolicy::default(),
+            api: ApiSettings::default(),
+            commands: commands::CommandSettings::default(),
+            fanout: fanout::FanOutSettings::default(),
+            ha: ha::HaSettings::default(),
+            health: health::HealthSettings::default(),
+            downstream: downstream::DownstreamSettings::default(),
+            limits: limits::LimitSettings::default(),
+            webhooks: Vec::new(),
+            dashboard: dashboard::DashboardSettings::default(),
+            persistence: persist::PersistenceSettings::default(),
+            dead_letters: deadletter::DeadLetterSettings::default(),
+            groups: Vec::new(),
+            schedules: scheduler::ScheduleSettings::default(),
         }
     }
 }
@@ -108,8 +298,11 @@ impl Default for GatewayConfig {
 #[command(about = "OpsMap Gateway - Zone relay")]
 #[command(version)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Path to configuration file
-    #[arg(short, long, default_value = "/etc/opsmap/gateway.yaml")]
+    #[arg(short, long, default_value = "/etc/opsmap/gateway.yaml", global = true)]
     config: PathBuf,
 
     /// Override zone name
@@ -121,11 +314,75 @@ struct Args {
     log_level: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Validate the configuration file and the paths it references
+    Validate,
+    /// Print the effective configuration, after overrides and interpolation
+    PrintConfig,
+    /// Create a one-time agent enrollment token
+    CreateToken {
+        /// Hours until the token expires
+        #[arg(long, default_value_t = 24)]
+        ttl_hours: i64,
+        /// Tenant of the agents it enrolls, written into their certificate
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+}
+
 /// Shared gateway state
 pub struct GatewayState {
     pub config: GatewayConfig,
     pub registry: AgentRegistry,
     pub backend_tx: broadcast::Sender<BackendMessage>,
+    pub metrics: GatewayMetrics,
+    pub audit: AuditLog,
+    pub policy: PolicyEngine,
+    pub enrollment: Option<Arc<Enrollment>>,
+    pub versions: VersionGate,
+    pub polls: poll::PollSessions,
+    pub commands: commands::PendingCommands,
+    pub fanouts: fanout::FanOuts,
+    pub peers: ha::Peers,
+    pub downstreams: downstream::Downstreams,
+    pub connections: limits::ConnectionLimiter,
+    pub outbound: outbound::Outbound,
+    pub webhooks: webhooks::Webhooks,
+    pub events: events::Events,
+    pub dead_letters: deadletter::DeadLetters,
+    pub groups: groups::Groups,
+    pub schedules: scheduler::Schedules,
+}
+
+impl GatewayState {
+    /// State for unit tests: nothing persisted, audited or enrolled
+    #[cfg(test)]
+    pub fn for_tests(config: GatewayConfig) -> Self {
+        let (backend_tx, _) = broadcast::channel(16);
+        Self {
+            metrics: GatewayMetrics::new(&config.gateway.id, &config.gateway.zone).unwrap(),
+            audit: AuditLog::disabled(),
+            policy: PolicyEngine::new(config.rbac.clone(), &config.gateway.zone),
+            enrollment: None,
+            versions: VersionGate::new(config.agent_versions.clone()),
+            registry: AgentRegistry::new(),
+            polls: poll::PollSessions::new(),
+            commands: commands::PendingCommands::new(config.commands.clone()),
+            fanouts: fanout::FanOuts::new(),
+            peers: ha::Peers::new(),
+            downstreams: downstream::Downstreams::new(),
+            connections: limits::ConnectionLimiter::new(&config.limits),
+            outbound: outbound::Outbound::new(&config.limits),
+            webhooks: webhooks::Webhooks::new(config.webhooks.clone()),
+            events: events::Events::new(),
+            dead_letters: deadletter::DeadLetters::disabled(),
+            groups: groups::Groups::new(&config.groups),
+            schedules: scheduler::Schedules::disabled(),
+            config,
+            backend_tx,
+        }
+    }
 }
 
 /// Message types for internal communication
@@ -133,14 +390,72 @@ pub struct GatewayState {
 pub enum BackendMessage {
     AgentConnected(AgentInfo),
     AgentDisconnected(String),
-    StatusUpdate(serde_json::Value),
+    /// A connected agent's hostname, labels or addresses changed
+    AgentMetadata(AgentInfo),
+    StatusUpdate {
+        update: serde_json::Value,
+        /// Set on the last update of a sequenced agent message
+        delivery: Option<delivery::Delivery>,
+    },
     CommandResponse(serde_json::Value),
+    JobUpdate(serde_json::Value),
+    Discovery {
+        agent_id: String,
+        report: serde_json::Value,
+    },
+    Inventory {
+        agent_id: String,
+        inventory: serde_json::Value,
+    },
+    /// Part of a support bundle
+    Diagnostics {
+        agent_id: String,
+        chunk: serde_json::Value,
+    },
+    SnapshotRequest {
+        agent_id: String,
+        version: Option<u64>,
+    },
+    /// A command got no final response in time
+    CommandTimeout(commands::CommandTimeout),
+    /// A response to a fan-out whose responses go in batches
+    BatchedResponse {
+        job_id: String,
+        response: serde_json::Value,
+    },
+    /// Every agent a fanned out command targeted settled
+    CommandSummary(fanout::CommandSummary),
+    /// A dead-lettered message, sent again
+    Redelivery(Box<backend_client::GatewayToBackendMessage>),
+    /// A message from the backend was refused, and why
+    ProtocolError(schema::ProtocolError),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    match args.command {
+        Some(Commands::Validate) => return validate_config(&args.config, args.zone),
+        Some(Commands::CreateToken { ttl_hours, tenant }) => {
+            let config = load_config(&args.config)?;
+            let store =
+                enrollment::TokenStore::new(std::path::Path::new(&config.enrollment.tokens_file));
+            let ttl = chrono::Duration::hours(ttl_hours);
+            println!("{}", store.create(ttl, tenant.as_deref())?);
+            return Ok(());
+        }
+        Some(Commands::PrintConfig) => {
+            let mut config = load_config(&args.config)?;
+            if let Some(zone) = args.zone {
+                config.gateway.zone = zone;
+            }
+            print!("{}", serde_yaml::to_string(&config)?);
+            return Ok(());
+        }
+        None => {}
+    }
+
     // Initialize logging
     init_logging(&args.log_level)?;
 
@@ -163,49 +478,309 @@ async fn main() -> Result<()> {
         "Gateway configured"
     );
 
+    // Open the audit trail; routing continues without it rather than failing
+    let audit = if config.audit.enabled {
+        AuditLog::open(std::path::Path::new(&config.audit.file_path)).unwrap_or_else(|e| {
+            error!(error = %e, "Audit log unavailable, commands will not be audited");
+            AuditLog::disabled()
+        })
+    } else {
+        AuditLog::disabled()
+    };
+
+    // Same for the dead-letter queue: undelivered messages are then only logged
+    let dead_letters = if config.dead_letters.enabled {
+        deadletter::DeadLetters::open(&config.dead_letters).unwrap_or_else(|e| {
+            error!(error = %e, "Dead-letter queue unavailable, undelivered messages will only be logged");
+            deadletter::DeadLetters::disabled()
+        })
+    } else {
+        deadletter::DeadLetters::disabled()
+    };
+
+    // And for schedules: none run, and none can be set
+    let schedules = if config.schedules.enabled {
+        scheduler::Schedules::open(&config.schedules).unwrap_or_else(|e| {
+            error!(error = %e, "Schedule file unavailable, scheduled commands are disabled");
+            scheduler::Schedules::disabled()
+        })
+    } else {
+        scheduler::Schedules::disabled()
+    };
+
+    let enrollment = if config.enrollment.enabled {
+        Some(Arc::new(Enrollment::new(&config.enrollment)?))
+    } else {
+        None
+    };
+
     // Create shared state
     let (backend_tx, _) = broadcast::channel(1000);
     let state = Arc::new(GatewayState {
+        metrics: GatewayMetrics::new(&config.gateway.id, &config.gateway.zone)?,
+        audit,
+        policy: PolicyEngine::new(config.rbac.clone(), &config.gateway.zone),
+        enrollment,
+        versions: VersionGate::new(config.agent_versions.clone()),
         config: config.clone(),
         registry: AgentRegistry::new(),
+        polls: poll::PollSessions::new(),
+        commands: commands::PendingCommands::new(config.commands.clone()),
+        fanouts: fanout::FanOuts::new(),
+        peers: ha::Peers::new(),
+        downstreams: downstream::Downstreams::new(),
+        connections: limits::ConnectionLimiter::new(&config.limits),
+        outbound: outbound::Outbound::new(&config.limits),
+        webhooks: webhooks::Webhooks::new(config.webhooks.clone()),
+        events: events::Events::new(),
+        dead_letters,
+        groups: groups::Groups::new(&config.groups),
+        schedules,
         backend_tx,
     });
 
+    // Agents known before a restart, unconfirmed until they reconnect;
+    // the gateway runs without them rather than failing
+    if config.persistence.enabled {
+        match persist::restore(&state) {
+            Ok(store) => {
+                tokio::spawn(persist::run(state.clone(), store));
+            }
+            Err(e) => {
+                error!(error = %e, "State file unavailable, agents will not be kept across restarts")
+            }
+        }
+    }
+
     // Start backend connection
     let backend_state = state.clone();
     tokio::spawn(async move {
         backend_client::run(backend_state).await;
     });
 
-    // Build HTTP/WebSocket router
-    let app = Router::new()
-        .route("/ws", get(agent_ws_handler))
-        .route("/health", get(health_handler))
+    // Expire polling agents that went quiet
+    tokio::spawn(poll::reap(state.clone()));
+
+    // Report commands the agents never answered
+    tokio::spawn(commands::expire(state.clone()));
+
+    // Tell connected but unresponsive agents from healthy ones
+    tokio::spawn(health::run(state.clone()));
+
+    // Live agent and status events for /events
+    tokio::spawn(events::run(state.clone()));
+
+    // Commands run on a cron schedule
+    if config.schedules.enabled {
+        tokio::spawn(scheduler::run(state.clone()));
+    }
+
+    if state.webhooks.enabled() {
+        tokio::spawn(webhooks::run(state.clone()));
+    }
+
+    // Share agents and pending commands with the other gateways of the zone
+    if config.ha.enabled {
+        ha::start(&state);
+    }
+
+    if config.api.open() {
+        warn!(
+            "api.tokens is empty: /agents, /metrics and /audit are not authenticated, and the \
+             command endpoints (/agents/:id/command, /groups/:name/command, /schedules, \
+             /config-updates, /dead-letters retry) are refused"
+        );
+    }
+
+    // Build HTTP/WebSocket router; operator endpoints need a token
+    let mut read_api = Router::new()
         .route("/metrics", get(metrics_handler))
         .route("/agents", get(agents_handler))
-        .with_state(state.clone());
+        .route("/agents/:id", get(agent_handler))
+        .route("/audit", get(audit_handler))
+        .route("/peers", get(peers_handler))
+        .route("/events", get(events::stream))
+        .route("/summary", get(summary::summary))
+        .route("/dead-letters", get(deadletter::list))
+        .route("/groups", get(groups::list))
+        .route("/groups/:name", get(groups::get))
+        .route("/schedules", get(scheduler::list))
+        .route("/schedules/:name", get(scheduler::get))
+        .route("/schedules/:name/runs", get(scheduler::runs));
+    if config.dashboard.enabled {
+        read_api = read_api.route("/dashboard/agents", get(dashboard::agents));
+    }
+    let read_api = read_api.route_layer(middleware::from_fn_with_state(state.clone(), auth::read));
+
+    let command_api = Router::new()
+        .route("/agents/:id/disconnect", post(disconnect_handler))
+        .route("/agents/:id/command", post(command_handler))
+        .route(
+            "/agents/:id/quarantine",
+            post(quarantine_handler).delete(release_handler),
+        )
+        .route("/config-updates", post(config_update::push))
+        .route("/dead-letters", delete(deadletter::purge))
+        .route("/dead-letters/:id", delete(deadletter::remove))
+        .route("/dead-letters/:id/retry", post(deadletter::retry_handler))
+        .route("/groups/:name", put(groups::put).delete(groups::remove))
+        .route("/groups/:name/command", post(groups::command))
+        .route(
+            "/schedules/:name",
+            put(scheduler::put).delete(scheduler::remove),
+        )
+        .route("/schedules/:name/run", post(scheduler::run_now))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::command));
+
+    let mut app = Router::new()
+        .route("/ws", get(agent_ws_handler))
+        .route("/poll", post(poll_handler))
+        .route("/health", get(health_handler))
+        .route("/enroll", post(enroll_handler))
+        .route("/peer", get(peer_ws_handler))
+        .merge(read_api)
+        .merge(command_api);
+    // The page itself holds no data
+    if config.dashboard.enabled {
+        app = app.route("/dashboard", get(dashboard::page));
+    }
+    let app = app.with_state(state.clone());
 
     // Start server
-    let addr: SocketAddr = format!(
-        "{}:{}",
-        config.gateway.listen_addr, config.gateway.listen_port
-    )
-    .parse()?;
+    let addrs = listen::addresses(&config.gateway).map_err(anyhow::Error::msg)?;
 
-    info!(addr = %addr, "Starting Gateway server");
+    // Certificates are reloaded in place when renewed, see tls::watch
+    let tls = if config.tls.enabled {
+        let tls = Arc::new(tls::TlsReloader::new(
+            &config.tls,
+            config.enrollment.enabled,
+        )?);
+        tokio::spawn(tls::watch(tls.clone()));
+        Some(tls)
+    } else {
+        None
+    };
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let listeners = listen::bind(&addrs)?;
+    let bound: Vec<SocketAddr> = listeners
+        .iter()
+        .filter_map(|l| l.local_addr().ok())
+        .collect();
+    info!(addrs = ?bound, tls = tls.is_some(), "Starting Gateway server");
+
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        let (app, tls) = (app.clone(), tls.clone());
+        servers.spawn(async move {
+            match tls {
+                Some(tls) => tls::serve(listener, app, tls).await,
+                None => axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+                .map_err(Into::into),
+            }
+        });
+    }
+    // The gateway stops with the first listener that does
+    let result = match servers.join_next().await {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => Err(e.into()),
+        None => Ok(()),
+    };
 
+    telemetry::shutdown();
+    result?;
     Ok(())
 }
 
 /// WebSocket handler for agent connections
 async fn agent_ws_handler(
     ws: WebSocketUpgrade,
+    peer: Option<Extension<tls::PeerCertificate>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<GatewayState>>,
+) -> Response {
+    if !connection_allowed(&state, addr) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many connection attempts",
+        )
+            .into_response();
+    }
+    // With enrollment enabled, clients without a certificate pass the TLS
+    // handshake so they can reach /enroll; they may not connect as agents.
+    // Without TLS there is no certificate to ask for.
+    let peer = peer.map(|Extension(peer)| peer).unwrap_or_default();
+    let tls = &state.config.tls;
+    if tls.enabled && tls.verify_clients && !peer.verified {
+        return (StatusCode::UNAUTHORIZED, "client certificate required").into_response();
+    }
+
+    let max_frame = state.config.limits.max_frame_bytes;
+    ws.max_frame_size(max_frame)
+        .max_message_size(max_frame)
+        .on_upgrade(move |socket| agent_server::handle_agent(socket, state, peer))
+}
+
+/// WebSocket handler for peer gateway links
+async fn peer_ws_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
     State(state): State<Arc<GatewayState>>,
-) -> impl axum::response::IntoResponse {
-    ws.on_upgrade(move |socket| agent_server::handle_agent(socket, state))
+) -> Response {
+    if !state.config.ha.enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !ha::authorized(&state.config.ha, &headers) {
+        warn!("Peer link refused: bad or missing ha.token");
+        return (StatusCode::UNAUTHORIZED, "peer token required").into_response();
+    }
+
+    ws.on_upgrade(move |socket| ha::accept(state, socket))
+}
+
+/// Peer gateways linked to this one, with their agent counts
+async fn peers_handler(State(state): State<Arc<GatewayState>>) -> axum::Json<Vec<ha::PeerInfo>> {
+    axum::Json(state.peers.list())
+}
+
+/// Polling endpoint for agents without a WebSocket
+async fn poll_handler(
+    peer: Option<Extension<tls::PeerCertificate>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<GatewayState>>,
+    axum::Json(request): axum::Json<poll::PollRequest>,
+) -> Result<axum::Json<poll::PollResponse>, (StatusCode, String)> {
+    // Opening a session is the polling agent's connection attempt
+    if request.session.is_none() && !connection_allowed(&state, addr) {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many connection attempts".to_string(),
+        ));
+    }
+    // Same rule as for WebSocket agents
+    let peer = peer.map(|Extension(peer)| peer).unwrap_or_default();
+    let tls = &state.config.tls;
+    if tls.enabled && tls.verify_clients && !peer.verified {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "client certificate required".to_string(),
+        ));
+    }
+
+    poll::poll(&state, request, &peer).await.map(axum::Json)
+}
+
+/// Count a connection attempt against its source address's limit
+fn connection_allowed(state: &GatewayState, addr: SocketAddr) -> bool {
+    let allowed = state.connections.allow(addr.ip());
+    if !allowed {
+        debug!(addr = %addr, "Connection attempt over the per-address limit");
+        state.metrics.rate_limited("connections", "refuse");
+    }
+    allowed
 }
 
 /// Health check endpoint
@@ -215,35 +790,275 @@ async fn health_handler() -> &'static str {
 
 /// Metrics endpoint (Prometheus format)
 async fn metrics_handler(State(state): State<Arc<GatewayState>>) -> String {
-    let agents = state.registry.count();
+    let agents = state.registry.list();
+    state.metrics.set_agent_versions(agents.iter().map(|a| {
+        (
+            a.version.as_str(),
+            state.versions.check(&a.version) != Verdict::Supported,
+        )
+    }));
+    state
+        .metrics
+        .set_agent_tenants(agents.iter().map(|a| a.tenant_id.as_deref()));
+    state.metrics.set_agent_traffic(&state.registry.traffic());
+    state.metrics.render(agents.len())
+}
 
-    format!(
-        "# HELP opsmap_gateway_connected_agents Number of connected agents\n\
-         # TYPE opsmap_gateway_connected_agents gauge\n\
-         opsmap_gateway_connected_agents {}\n",
-        agents
-    )
+/// List connected agents, filtered and paged; the count before paging is
+/// in `X-Total-Count`
+async fn agents_handler(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<AgentQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let (total, agents) = state
+        .registry
+        .query(&query, &state.config.gateway.zone)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(([("x-total-count", total.to_string())], axum::Json(agents)).into_response())
 }
 
-/// List connected agents
-async fn agents_handler(State(state): State<Arc<GatewayState>>) -> axum::Json<Vec<AgentInfo>> {
-    let agents = state.registry.list();
-    axum::Json(agents)
+/// A connected agent with its heartbeat age, traffic and pending commands
+async fn agent_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(agent_id): Path<String>,
+) -> Result<axum::Json<AgentDetails>, (StatusCode, String)> {
+    let mut details = state.registry.details(&agent_id).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("Agent not connected: {}", agent_id),
+    ))?;
+    details.pending_commands = state.commands.for_agent(&agent_id);
+    details.checks = state.registry.checks(&agent_id);
+    Ok(axum::Json(details))
+}
+
+/// Close an agent's connection; it has to authenticate and register again
+async fn disconnect_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(agent_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.registry.disconnect(&agent_id).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("Agent not connected: {}", agent_id),
+    ))?;
+    outbound::post(&state, BackendMessage::AgentDisconnected(agent_id));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop routing commands to an agent, keeping it connected
+async fn quarantine_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(agent_id): Path<String>,
+) -> Result<axum::Json<AgentInfo>, (StatusCode, String)> {
+    set_quarantined(&state, agent_id, true)
+}
+
+/// Route commands to a quarantined agent again
+async fn release_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(agent_id): Path<String>,
+) -> Result<axum::Json<AgentInfo>, (StatusCode, String)> {
+    set_quarantined(&state, agent_id, false)
+}
+
+/// Flag or clear an agent's quarantine, telling the backend
+fn set_quarantined(
+    state: &GatewayState,
+    agent_id: String,
+    quarantined: bool,
+) -> Result<axum::Json<AgentInfo>, (StatusCode, String)> {
+    let info = state
+        .registry
+        .set_quarantined(&agent_id, quarantined)
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Agent not connected: {}", agent_id),
+        ))?;
+    outbound::post(state, BackendMessage::AgentMetadata(info.clone()));
+    Ok(axum::Json(info))
+}
+
+/// Body of `POST /agents/:id/command`
+#[derive(Debug, Deserialize)]
+struct CommandRequest {
+    command_type: String,
+    component_id: String,
+    #[serde(default)]
+    action_name: Option<String>,
+    #[serde(default)]
+    params: serde_json::Value,
+    #[serde(default = "default_command_timeout")]
+    timeout_secs: u64,
+    /// Seconds to wait for the final response; 0 answers once it is sent
+    #[serde(default)]
+    wait_secs: u64,
+}
+
+fn default_command_timeout() -> u64 {
+    300
+}
+
+/// Send a command to an agent, as the API token, and wait for its final
+/// response: 200 with it, or 202 with the command id when not waiting or
+/// out of time
+async fn command_handler(
+    caller: Option<Extension<auth::Caller>>,
+    State(state): State<Arc<GatewayState>>,
+    Path(agent_id): Path<String>,
+    axum::Json(request): axum::Json<CommandRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = state.registry.get(&agent_id).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("Agent not connected: {}", agent_id),
+    ))?;
+    let command = registry::AgentCommand {
+        id: uuid::Uuid::new_v4().to_string(),
+        command_type: request.command_type,
+        component_id: request.component_id,
+        action_name: request.action_name,
+        params: request.params,
+        timeout_secs: request.timeout_secs,
+        trace_context: None,
+        run_at: None,
+        run_after_secs: None,
+        tenant_id: agent.tenant_id,
+        correlation_id: Some(uuid::Uuid::new_v4().to_string()),
+    };
+    let requested_by = caller.map(|Extension(auth::Caller(name))| name);
+
+    // Subscribed before sending, so a quick answer is not missed
+    let mut rx = state.backend_tx.subscribe();
+    backend_client::dispatch(&state, &command, &agent_id, requested_by.as_deref(), &[])
+        .await
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let accepted = serde_json::json!({
+        "job_id": command.id,
+        "agent_id": agent_id,
+        "correlation_id": command.correlation_id,
+    });
+
+    let wait = std::time::Duration::from_secs(request.wait_secs);
+    let answer = tokio::time::timeout(wait, async {
+        loop {
+            match rx.recv().await {
+                Ok(BackendMessage::CommandResponse(response))
+                    if response["job_id"] == command.id.as_str()
+                        && response["status"] != "started" =>
+                {
+                    return Some(response);
+                }
+                Ok(BackendMessage::CommandTimeout(timeout)) if timeout.job_id == command.id => {
+                    return None;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+                _ => {}
+            }
+        }
+    })
+    .await;
+    Ok(match answer {
+        Ok(Some(response)) => axum::Json(response).into_response(),
+        _ => (StatusCode::ACCEPTED, axum::Json(accepted)).into_response(),
+    })
+}
+
+/// Query the command audit trail
+async fn audit_handler(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<axum::Json<Vec<AuditEvent>>, (StatusCode, String)> {
+    state
+        .audit
+        .query(query)
+        .await
+        .map(axum::Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Issue a client certificate to an agent presenting an enrollment token
+async fn enroll_handler(
+    State(state): State<Arc<GatewayState>>,
+    axum::Json(request): axum::Json<EnrollRequest>,
+) -> Result<axum::Json<EnrollResponse>, (StatusCode, String)> {
+    let enrollment = state
+        .enrollment
+        .clone()
+        .ok_or((StatusCode::NOT_FOUND, "enrollment is disabled".to_string()))?;
+
+    // The token file is read and rewritten and the CSR signed off the runtime
+    let enrolled = tokio::task::spawn_blocking({
+        let request = request.clone();
+        move || enrollment.enroll(&request)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match enrolled {
+        Ok(response) => {
+            info!(agent_id = %response.agent_id, hostname = %request.hostname, "Agent enrolled");
+            Ok(axum::Json(response))
+        }
+        Err(e) => {
+            warn!(hostname = %request.hostname, error = %e, "Enrollment refused");
+            let status = match e {
+                EnrollError::InvalidToken | EnrollError::TokenUsed | EnrollError::TokenExpired => {
+                    StatusCode::UNAUTHORIZED
+                }
+                EnrollError::InvalidCsr(_) => StatusCode::BAD_REQUEST,
+                EnrollError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, e.to_string()))
+        }
+    }
 }
 
 /// Initialize logging
 fn init_logging(level: &str) -> Result<()> {
-    use tracing_subscriber::{fmt, EnvFilter};
+    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-    let filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
 
-    fmt()
-        .with_env_filter(filter)
-        .with_target(true)
-        .json()
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_target(true).json());
+
+    #[cfg(feature = "otel")]
+    subscriber
+        .with(telemetry::layer(telemetry::SERVICE_NAME)?)
         .init();
+    #[cfg(not(feature = "otel"))]
+    subscriber.init();
+
+    Ok(())
+}
+
+/// Validate the configuration and report problems, failing on errors
+fn validate_config(path: &PathBuf, zone: Option<String>) -> Result<()> {
+    if !path.exists() {
+        anyhow::bail!("Config file not found: {}", path.display());
+    }
+
+    let mut config = load_config(path)?;
+    if let Some(zone) = zone {
+        config.gateway.zone = zone;
+    }
+
+    let validation = validate::validate(&config);
+    for warning in &validation.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &validation.errors {
+        eprintln!("error: {}", error);
+    }
+
+    if !validation.is_ok() {
+        anyhow::bail!(
+            "{} has {} error(s)",
+            path.display(),
+            validation.errors.len()
+        );
+    }
 
+    println!("{} is valid", path.display());
     Ok(())
 }
 
@@ -251,7 +1066,9 @@ fn init_logging(level: &str) -> Result<()> {
 fn load_config(path: &PathBuf) -> Result<GatewayConfig> {
     if path.exists() {
         let content = std::fs::read_to_string(path)?;
-        let config: GatewayConfig = serde_yaml::from_str(&content)?;
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&content)?;
+        interpolate::interpolate_value(&mut value)?;
+        let config: GatewayConfig = serde_yaml::from_value(value)?;
         Ok(config)
     } else {
         warn!(path = %path.display(), "Config file not found, using defaults");
@@ -0,0 +1,567 @@
+//! Gateway metrics
+//!
+//! Prometheus counters, gauges and histograms for agent traffic, the backend
+//! link and command routing. Every series carries the gateway id and zone.
+//! Per-agent traffic (`opsmap_gateway_agent_*`, labelled `agent_id`) is
+//! taken from the registry on each scrape, for connected agents only.
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::registry::AgentTraffic;
+
+/// Gateway metrics, registered in their own registry; clones share them
+#[derive(Clone)]
+pub struct GatewayMetrics {
+    registry: Registry,
+    connected_agents: IntGauge,
+    messages_received: IntCounterVec,
+    messages_forwarded: IntCounterVec,
+    backend_connected: IntGauge,
+    backend_reconnects: IntCounter,
+    backend_active: IntGaugeVec,
+    backend_failovers: IntCounter,
+    command_routing_failures: IntCounter,
+    command_redeliveries: IntCounter,
+    command_timeouts: IntCounter,
+    send_duration: HistogramVec,
+    agents_by_version: IntGaugeVec,
+    agents_rejected: IntCounterVec,
+    agents_by_tenant: IntGaugeVec,
+    rate_limited: IntCounterVec,
+    webhook_deliveries: IntCounterVec,
+    dead_letters: IntCounterVec,
+    protocol_errors: IntCounterVec,
+    backend_queue_depth: IntGauge,
+    backend_dropped: IntCounterVec,
+    backpressure_waits: IntCounterVec,
+    agent_received: IntGaugeVec,
+    agent_received_bytes: IntGaugeVec,
+    agent_last_message: IntGaugeVec,
+}
+
+impl GatewayMetrics {
+    pub fn new(gateway_id: &str, zone: &str) -> prometheus::Result<Self> {
+        let labels = HashMap::from([
+            ("gateway_id".to_string(), gateway_id.to_string()),
+            ("zone".to_string(), zone.to_string()),
+        ]);
+        let registry = Registry::new_custom(None, Some(labels))?;
+
+        let connected_agents = IntGauge::new(
+            "opsmap_gateway_connected_agents",
+            "Number of connected agents",
+        )?;
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_messages_received_total",
+                "Messages received from agents",
+            ),
+            &["type"],
+        )?;
+        let messages_forwarded = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_messages_forwarded_total",
+                "Messages forwarded to the backend",
+            ),
+            &["type"],
+        )?;
+        let backend_connected = IntGauge::new(
+            "opsmap_gateway_backend_connected",
+            "Whether the gateway is connected to the backend",
+        )?;
+        let backend_reconnects = IntCounter::new(
+            "opsmap_gateway_backend_reconnects_total",
+            "Backend reconnection attempts",
+        )?;
+        let backend_active = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_backend_active",
+                "Whether this backend is the one the gateway is connected to",
+            ),
+            &["url", "priority"],
+        )?;
+        let backend_failovers = IntCounter::new(
+            "opsmap_gateway_backend_failovers_total",
+            "Switches from one backend to another, failing over or back",
+        )?;
+        let command_routing_failures = IntCounter::new(
+            "opsmap_gateway_command_routing_failures_total",
+            "Backend commands that could not be delivered to an agent",
+        )?;
+        let command_redeliveries = IntCounter::new(
+            "opsmap_gateway_command_redeliveries_total",
+            "Commands sent again to an agent that reconnected",
+        )?;
+        let command_timeouts = IntCounter::new(
+            "opsmap_gateway_command_timeouts_total",
+            "Commands given up on without a final response from the agent",
+        )?;
+        let send_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "opsmap_gateway_ws_send_duration_seconds",
+                "Time to write a WebSocket message",
+            )
+            .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+            &["peer"],
+        )?;
+
+        let agents_by_version = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_agents_by_version",
+                "Connected agents per agent version",
+            ),
+            &["version", "outdated"],
+        )?;
+        let agents_rejected = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_agents_rejected_total",
+                "Agents turned away for being older than the minimum version",
+            ),
+            &["version"],
+        )?;
+        let agents_by_tenant = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_agents_by_tenant",
+                "Connected agents per tenant (empty for agents without one)",
+            ),
+            &["tenant"],
+        )?;
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_rate_limited_total",
+                "Agent messages and connection attempts over a rate limit",
+            ),
+            &["limit", "action"],
+        )?;
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_webhook_deliveries_total",
+                "Webhook notifications delivered, given up on (failed) or dropped from a full queue",
+            ),
+            &["webhook", "result"],
+        )?;
+        let dead_letters = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_dead_letters_total",
+                "Commands and backend messages that could not be delivered",
+            ),
+            &["kind"],
+        )?;
+        let protocol_errors = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_protocol_errors_total",
+                "Messages refused by validation, by sender and kind of error",
+            ),
+            &["peer", "kind"],
+        )?;
+        let backend_queue_depth = IntGauge::new(
+            "opsmap_gateway_backend_queue_depth",
+            "Messages queued for the backend link",
+        )?;
+        let backend_dropped = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_backend_dropped_total",
+                "Messages for the backend dropped for lack of room in its queue",
+            ),
+            &["type", "reason"],
+        )?;
+        let backpressure_waits = IntCounterVec::new(
+            Opts::new(
+                "opsmap_gateway_backpressure_waits_total",
+                "Times a connection stopped reading to wait for room in the backend queue",
+            ),
+            &["peer"],
+        )?;
+        let agent_received = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_agent_received",
+                "Messages, status deltas, batches and command responses from each agent since it registered",
+            ),
+            &["agent_id", "kind"],
+        )?;
+        let agent_received_bytes = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_agent_received_bytes",
+                "Bytes of frames from each agent since it registered",
+            ),
+            &["agent_id"],
+        )?;
+        let agent_last_message = IntGaugeVec::new(
+            Opts::new(
+                "opsmap_gateway_agent_last_message_timestamp_seconds",
+                "When each agent last sent a message",
+            ),
+            &["agent_id"],
+        )?;
+
+        registry.register(Box::new(connected_agents.clone()))?;
+        registry.register(Box::new(messages_received.clone()))?;
+        registry.register(Box::new(messages_forwarded.clone()))?;
+        registry.register(Box::new(backend_connected.clone()))?;
+        registry.register(Box::new(backend_reconnects.clone()))?;
+        registry.register(Box::new(backend_active.clone()))?;
+        registry.register(Box::new(backend_failovers.clone()))?;
+        registry.register(Box::new(command_routing_failures.clone()))?;
+        registry.register(Box::new(command_redeliveries.clone()))?;
+        registry.register(Box::new(command_timeouts.clone()))?;
+        registry.register(Box::new(send_duration.clone()))?;
+        registry.register(Box::new(agents_by_version.clone()))?;
+        registry.register(Box::new(agents_rejected.clone()))?;
+        registry.register(Box::new(agents_by_tenant.clone()))?;
+        registry.register(Box::new(rate_limited.clone()))?;
+        registry.register(Box::new(webhook_deliveries.clone()))?;
+        registry.register(Box::new(dead_letters.clone()))?;
+        registry.register(Box::new(protocol_errors.clone()))?;
+        registry.register(Box::new(backend_queue_depth.clone()))?;
+        registry.register(Box::new(backend_dropped.clone()))?;
+        registry.register(Box::new(backpressure_waits.clone()))?;
+        registry.register(Box::new(agent_received.clone()))?;
+        registry.register(Box::new(agent_received_bytes.clone()))?;
+        registry.register(Box::new(agent_last_message.clone()))?;
+
+        Ok(Self {
+            registry,
+            connected_agents,
+            messages_received,
+            messages_forwarded,
+            backend_connected,
+            backend_reconnects,
+            backend_active,
+            backend_failovers,
+            command_routing_failures,
+            command_redeliveries,
+            command_timeouts,
+            send_duration,
+            agents_by_version,
+            agents_rejected,
+            agents_by_tenant,
+            rate_limited,
+            webhook_deliveries,
+            dead_letters,
+            protocol_errors,
+            backend_queue_depth,
+            backend_dropped,
+            backpressure_waits,
+            agent_received,
+            agent_received_bytes,
+            agent_last_message,
+        })
+    }
+
+    pub fn message_received(&self, message_type: &str) {
+        self.messages_received
+            .with_label_values(&[message_type])
+            .inc();
+    }
+
+    pub fn message_forwarded(&self, message_type: &str) {
+        self.messages_forwarded
+            .with_label_values(&[message_type])
+            .inc();
+    }
+
+    pub fn set_backend_connected(&self, connected: bool) {
+        self.backend_connected.set(connected as i64);
+    }
+
+    pub fn backend_connected(&self) -> bool {
+        self.backend_connected.get() == 1
+    }
+
+    pub fn backend_reconnect(&self) {
+        self.backend_reconnects.inc();
+    }
+
+    /// Mark which of the backends, by priority, is connected; none with `None`
+    pub fn set_backend_active(&self, urls: &[&str], active: Option<usize>) {
+        for (priority, url) in urls.iter().enumerate() {
+            self.backend_active
+                .with_label_values(&[url, &priority.to_string()])
+                .set((active == Some(priority)) as i64);
+        }
+    }
+
+    pub fn backend_failover(&self) {
+        self.backend_failovers.inc();
+    }
+
+    pub fn command_routing_failed(&self) {
+        self.command_routing_failures.inc();
+    }
+
+    pub fn command_redelivered(&self) {
+        self.command_redeliveries.inc();
+    }
+
+    pub fn command_timed_out(&self) {
+        self.command_timeouts.inc();
+    }
+
+    pub fn agent_rejected(&self, version: &str) {
+        self.agents_rejected.with_label_values(&[version]).inc();
+    }
+
+    /// Count a message or connection attempt over `limit` ("messages",
+    /// "bytes" or "connections") and what was done about it
+    pub fn rate_limited(&self, limit: &str, action: &str) {
+        self.rate_limited.with_label_values(&[limit, action]).inc();
+    }
+
+    /// Count a webhook notification "delivered", "failed" or "dropped"
+    pub fn webhook_delivery(&self, webhook: &str, result: &str) {
+        self.webhook_deliveries
+            .with_label_values(&[webhook, result])
+            .inc();
+    }
+
+    /// Count an undelivered "command" or "backend_message"
+    pub fn dead_lettered(&self, kind: &str) {
+        self.dead_letters.with_label_values(&[kind]).inc();
+    }
+
+    /// Count a message from `peer` ("agent" or "backend") refused by
+    /// validation, by kind of error
+    pub fn protocol_error(&self, peer: &str, kind: &str) {
+        self.protocol_errors.with_label_values(&[peer, kind]).inc();
+    }
+
+    pub fn set_backend_queue_depth(&self, depth: usize) {
+        self.backend_queue_depth.set(depth as i64);
+    }
+
+    /// Count a message the backend will not get, by type and why
+    pub fn backend_dropped(&self, message_type: &str, reason: &str) {
+        self.backend_dropped
+            .with_label_values(&[message_type, reason])
+            .inc();
+    }
+
+    /// Count a connection from `peer` held up by a full backend queue
+    pub fn backpressure_wait(&self, peer: &str) {
+        self.backpressure_waits.with_label_values(&[peer]).inc();
+    }
+
+    /// Count connected agents by version, given each one's version and
+    /// whether it is below the minimum
+    pub fn set_agent_versions<'a>(&self, agents: impl IntoIterator<Item = (&'a str, bool)>) {
+        self.agents_by_version.reset();
+        for (version, outdated) in agents {
+            let outdated = if outdated { "true" } else { "false" };
+            self.agents_by_version
+                .with_label_values(&[version, outdated])
+                .inc();
+        }
+    }
+
+    /// Count connected agents by tenant
+    pub fn set_agent_tenants<'a>(&self, tenants: impl IntoIterator<Item = Option<&'a str>>) {
+        self.agents_by_tenant.reset();
+        for tenant in tenants {
+            self.agents_by_tenant
+                .with_label_values(&[tenant.unwrap_or_default()])
+                .inc();
+        }
+    }
+
+    /// Traffic of each connected agent, to find the noisy one
+    pub fn set_agent_traffic(&self, agents: &[(String, AgentTraffic)]) {
+        self.agent_received.reset();
+        self.agent_received_bytes.reset();
+        self.agent_last_message.reset();
+        for (agent_id, traffic) in agents {
+            for (kind, count) in [
+                ("messages", traffic.messages_received),
+                ("deltas", traffic.deltas_received),
+                ("batches", traffic.batches_received),
+                ("command_responses", traffic.command_responses),
+            ] {
+                self.agent_received
+                    .with_label_values(&[agent_id, kind])
+                    .set(count as i64);
+            }
+            self.agent_received_bytes
+                .with_label_values(&[agent_id])
+                .set(traffic.bytes_received as i64);
+            if let Some(at) = traffic.last_message_at {
+                self.agent_last_message
+                    .with_label_values(&[agent_id])
+                    .set(at.timestamp());
+            }
+        }
+    }
+
+    /// Record a WebSocket write to an agent or the backend
+    pub fn observe_send(&self, peer: &str, duration: Duration) {
+        self.send_duration
+            .with_label_values(&[peer])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub fn render(&self, connected_agents: usize) -> String {
+        self.connected_agents.set(connected_agents as i64);
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::warn!(error = %e, "Failed to encode metrics");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the sample with this name carrying all these labels
+    fn sample(text: &str, name: &str, labels: &[&str]) -> Option<f64> {
+        text.lines()
+            .filter(|line| line.starts_with(&format!("{}{{", name)))
+            .find(|line| labels.iter().all(|label| line.contains(label)))
+            .and_then(|line| line.rsplit(' ').next())
+            .and_then(|value| value.parse().ok())
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = GatewayMetrics::new("gw-1", "dmz").unwrap();
+        metrics.message_received("status_delta");
+        metrics.message_received("status_delta");
+        metrics.message_forwarded("status_update");
+        metrics.command_routing_failed();
+        metrics.command_timed_out();
+        metrics.observe_send("agent", Duration::from_millis(2));
+        metrics.set_agent_versions([("0.1.0", true), ("0.2.0", false), ("0.2.0", false)]);
+        metrics.set_agent_tenants([Some("acme"), None, Some("acme")]);
+        metrics.rate_limited("messages", "drop");
+        metrics.protocol_error("agent", "unknown_field");
+        metrics.set_backend_active(&["wss://primary", "wss://standby"], Some(1));
+        let traffic = AgentTraffic {
+            deltas_received: 40,
+            bytes_received: 2048,
+            ..Default::default()
+        };
+        metrics.set_agent_traffic(&[("agent-1".to_string(), traffic)]);
+
+        let text = metrics.render(3);
+        let gateway = ["gateway_id=\"gw-1\"", "zone=\"dmz\""];
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_backend_active",
+                &["url=\"wss://primary\""]
+            ),
+            Some(0.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_backend_active",
+                &["url=\"wss://standby\"", "priority=\"1\""]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(&text, "opsmap_gateway_connected_agents", &gateway),
+            Some(3.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_messages_received_total",
+                &[gateway[0], gateway[1], "type=\"status_delta\""]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_command_routing_failures_total",
+                &gateway
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(&text, "opsmap_gateway_command_timeouts_total", &gateway),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_ws_send_duration_seconds_count",
+                &[gateway[0], gateway[1], "peer=\"agent\""]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agents_by_version",
+                &["version=\"0.2.0\"", "outdated=\"false\""]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agents_by_version",
+                &["version=\"0.1.0\"", "outdated=\"true\""]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agents_by_tenant",
+                &["tenant=\"acme\""]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_rate_limited_total",
+                &["limit=\"messages\"", "action=\"drop\""]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_protocol_errors_total",
+                &["peer=\"agent\"", "kind=\"unknown_field\""]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agent_received",
+                &["agent_id=\"agent-1\"", "kind=\"deltas\""]
+            ),
+            Some(40.0)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agent_received_bytes",
+                &["agent_id=\"agent-1\""]
+            ),
+            Some(2048.0)
+        );
+        // Never heard from
+        assert_eq!(
+            sample(
+                &text,
+                "opsmap_gateway_agent_last_message_timestamp_seconds",
+                &["agent_id=\"agent-1\""]
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! Backend queue
+//!
+//! Everything for the backend waits in one queue that the backend link
+//! drains, where a broadcast channel used to drop what a slow link could
+//! not take without a trace. The queue is bounded twice:
+//!
+//! - `limits.backend_queue` messages in all;
+//! - `limits.connection_queue` messages from one connection (an agent's
+//!   WebSocket, a poll request, a downstream gateway's link), so one
+//!   flooding agent cannot take the room of all the others.
+//!
+//! A connection over its share, or finding the queue full, stops reading
+//! from its socket until there is room: the flood slows its agent down
+//! instead of growing the gateway's memory. After
+//! `limits.backend_queue_wait_secs`, or at once while the backend is
+//! disconnected, the message is dropped instead; messages that do not come
+//! from a connection (command refusals, fan-out summaries) are dropped as
+//! soon as the queue is full. Status updates dropped are not acked, so
+//! their agent sends them again.
+//!
+//! Command responses, job updates, timeouts and fan-out summaries dropped
+//! are kept in the dead letters, to be sent again once the backend is back;
+//! nothing else would tell the backend how those commands ended.
+//!
+//! Drops are counted in `opsmap_gateway_backend_dropped_total{type,reason}`
+//! (`timeout`, `backend_disconnected`, `queue_full`), waits in
+//! `opsmap_gateway_backpressure_waits_total{peer}`, and the queue's length
+//! is `opsmap_gateway_backend_queue_depth`.
+//!
+//! Whatever is queued or dropped is also broadcast on `backend_tx` to the
+//! gateway's own observers: events, webhooks, commands waiting for their
+//! response.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::backend_client::GatewayToBackendMessage;
+use crate::limits::LimitSettings;
+use crate::{BackendMessage, GatewayState};
+
+/// A message waiting for the backend link, holding its room in the queue
+pub struct Queued {
+    message: BackendMessage,
+    _room: OwnedSemaphorePermit,
+    _share: Option<OwnedSemaphorePermit>,
+}
+
+impl Queued {
+    /// The message, its room given back
+    pub fn into_message(self) -> BackendMessage {
+        self.message
+    }
+}
+
+/// The queue to the backend link
+pub struct Outbound {
+    tx: mpsc::UnboundedSender<Queued>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<Queued>>>,
+    room: Arc<Semaphore>,
+    capacity: usize,
+    connection_queue: usize,
+    wait: Duration,
+    /// What the backend accepted when the link last registered
+    backend_capabilities: RwLock<Vec<String>>,
+}
+
+/// One connection's share of the queue
+#[derive(Clone)]
+pub struct Connection {
+    share: Arc<Semaphore>,
+    peer: &'static str,
+}
+
+impl Outbound {
+    pub fn new(limits: &LimitSettings) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let capacity = limits.backend_queue.max(1);
+        Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            room: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            connection_queue: limits.connection_queue.max(1),
+            wait: Duration::from_secs(limits.backend_queue_wait_secs),
+            backend_capabilities: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Record what the backend accepted on registering
+    pub fn set_backend_capabilities(&self, capabilities: &[String]) {
+        *self.backend_capabilities.write().unwrap() = capabilities.to_vec();
+    }
+
+    /// Whether the backend accepted an optional feature, last time the link
+    /// registered
+    pub fn backend_supports(&self, capability: &str) -> bool {
+        self.backend_capabilities
+            .read()
+            .unwrap()
+            .iter()
+            .any(|c| c == capability)
+    }
+
+    /// A share of the queue for a new connection from `peer` ("agent",
+    /// "poll" or "downstream")
+    pub fn connection(&self, peer: &'static str) -> Connection {
+        Connection {
+            share: Arc::new(Semaphore::new(self.connection_queue)),
+            peer,
+        }
+    }
+
+    /// The receiving end, for the backend link; there is only one
+    pub fn take_receiver(&self) -> Option<mpsc::UnboundedReceiver<Queued>> {
+        self.rx.lock().unwrap().take()
+    }
+
+    /// Messages waiting
+    pub fn depth(&self) -> usize {
+        self.capacity - self.room.available_permits()
+    }
+}
+
+/// Queue a message read from a connection, waiting for room while the
+/// backend is connected
+pub async fn forward(state: &GatewayState, connection: &Connection, message: BackendMessage) {
+    let _ = state.backend_tx.send(message.clone());
+    let share = match room(state, &connection.share, connection.peer).await {
+        Ok(share) => share,
+        Err(reason) => return dropped(state, &message, reason),
+    };
+    match room(state, &state.outbound.room, connection.peer).await {
+        Ok(room) => push(state, message, room, Some(share)),
+        Err(reason) => dropped(state, &message, reason),
+    }
+}
+
+/// Queue a message of the gateway's own, dropping it if the queue is full;
+/// false when dropped
+pub fn post(state: &GatewayState, message: BackendMessage) -> bool {
+    let _ = state.backend_tx.send(message.clone());
+    match state.outbound.room.clone().try_acquire_owned() {
+        Ok(room) => {
+            push(state, message, room, None);
+            true
+        }
+        Err(_) => {
+            dropped(state, &message, "queue_full");
+            false
+        }
+    }
+}
+
+/// Room in a queue, or why there is none
+async fn room(
+    state: &GatewayState,
+    queue: &Arc<Semaphore>,
+    peer: &str,
+) -> Result<OwnedSemaphorePermit, &'static str> {
+    if let Ok(permit) = queue.clone().try_acquire_owned() {
+        return Ok(permit);
+    }
+    // Nothing is sent until the link is back, waiting would only stall
+    if !state.metrics.backend_connected() {
+        return Err("backend_disconnected");
+    }
+    state.metrics.backpressure_wait(peer);
+    debug!(peer = peer, "Backend queue full, waiting for room");
+    match tokio::time::timeout(state.outbound.wait, queue.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => Err("timeout"),
+    }
+}
+
+fn push(
+    state: &GatewayState,
+    message: BackendMessage,
+    room: OwnedSemaphorePermit,
+    share: Option<OwnedSemaphorePermit>,
+) {
+    let queued = Queued {
+        message,
+        _room: room,
+        _share: share,
+    };
+    // The receiver lives as long as the backend link task
+    if let Err(mpsc::error::SendError(queued)) = state.outbound.tx.send(queued) {
+        dropped(state, &queued.message, "closed");
+    }
+    state
+        .metrics
+        .set_backend_queue_depth(state.outbound.depth());
+}
+
+fn dropped(state: &GatewayState, message: &BackendMessage, reason: &str) {
+    let message_type = message_type(message);
+    debug!(
+        message_type = message_type,
+        reason = reason,
+        "Message for the backend dropped"
+    );
+    state.metrics.backend_dropped(message_type, reason);
+    if let Some(message) = dead_letter(message) {
+        state
+            .dead_letters
+            .backend_message(&message, &format!("backend queue: {}", reason));
+        state.metrics.dead_lettered("backend_message");
+    }
+}
+
+/// What a dropped message is kept as in the dead letters, if it is one
+/// whose loss nothing makes up for
+fn dead_letter(message: &BackendMessage) -> Option<GatewayToBackendMessage> {
+    match message {
+        BackendMessage::CommandResponse(response)
+        | BackendMessage::BatchedResponse { response, .. } => {
+            Some(GatewayToBackendMessage::CommandResponse(response.clone()))
+        }
+        BackendMessage::JobUpdate(update) => {
+            Some(GatewayToBackendMessage::JobUpdate(update.clone()))
+        }
+        BackendMessage::CommandTimeout(timeout) => {
+            Some(GatewayToBackendMessage::CommandTimeout(timeout.clone()))
+        }
+        BackendMessage::CommandSummary(summary) => {
+            Some(GatewayToBackendMessage::CommandSummary(summary.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Wire type name of what a message becomes, used as a metrics label
+fn message_type(message: &BackendMessage) -> &'static str {
+    match message {
+        BackendMessage::AgentConnected(_) => "agent_connected",
+        BackendMessage::AgentDisconnected(_) => "agent_disconnected",
+        BackendMessage::AgentMetadata(_) => "agent_metadata",
+        BackendMessage::StatusUpdate { .. } => "status_update",
+        BackendMessage::CommandResponse(_) | BackendMessage::BatchedResponse { .. } => {
+            "command_response"
+        }
+        BackendMessage::JobUpdate(_) => "job_update",
+        BackendMessage::Discovery { .. } => "discovery",
+        BackendMessage::Inventory { .. } => "inventory",
+        BackendMessage::Diagnostics { .. } => "diagnostics",
+        BackendMessage::SnapshotRequest { .. } => "snapshot_request",
+        BackendMessage::CommandTimeout(_) => "command_timeout",
+        BackendMessage::CommandSummary(_) => "command_summary",
+        BackendMessage::Redelivery(_) => "redelivery",
+        BackendMessage::ProtocolError(_) => "protocol_error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GatewayConfig;
+    use std::sync::Arc;
+
+    fn state(backend_queue: usize, connection_queue: usize) -> GatewayState {
+        let mut config = GatewayConfig::default();
+        config.limits.backend_queue = backend_queue;
+        config.limits.connection_queue = connection_queue;
+        GatewayState::for_tests(config)
+    }
+
+    fn status(n: u64) -> BackendMessage {
+        BackendMessage::StatusUpdate {
+            update: serde_json::json!({"n": n}),
+            delivery: None,
+        }
+    }
+
+    /// Count of messages of this type dropped for this reason
+    fn dropped_count(state: &GatewayState, message_type: &str, reason: &str) -> String {
+        let labels = [
+            format!("reason=\"{}\"", reason),
+            format!("type=\"{}\"", message_type),
+        ];
+        state
+            .metrics
+            .render(0)
+            .lines()
+            .filter(|line| line.starts_with("opsmap_gateway_backend_dropped_total{"))
+            .find(|line| labels.iter().all(|label| line.contains(label.as_str())))
+            .and_then(|line| line.rsplit(' ').next())
+            .unwrap_or("0")
+            .to_string()
+    }
+
+    #[test]
+    fn test_backend_capabilities() {
+        let state = state(3, 2);
+        let mut agent = crate::agent_server::registered_agent(
+            serde_json::from_value(serde_json::json!({
+                "agent_id": "agent-1",
+                "hostname": "host-1",
+                "labels": {},
+                "version": "1.0.0",
+                "os": "linux",
+                "protocol_version": 2,
+                "capabilities": ["acks", "diagnostics"],
+            }))
+            .unwrap(),
+        );
+
+        // Bundles in chunks only while the backend takes them
+        let mut offered = agent.clone();
+        crate::agent_server::backend_capabilities(&state, &mut offered);
+        assert_eq!(offered.capabilities, vec!["acks"]);
+
+        state
+            .outbound
+            .set_backend_capabilities(&["acks".to_string(), "diagnostics".to_string()]);
+        crate::agent_server::backend_capabilities(&state, &mut agent);
+        assert_eq!(agent.capabilities, vec!["acks", "diagnostics"]);
+    }
+
+    #[tokio::test]
+    async fn test_drops_while_disconnected() {
+        let state = state(3, 2);
+        let mut observer = state.backend_tx.subscribe();
+        let mut rx = state.outbound.take_receiver().unwrap();
+        assert!(state.outbound.take_receiver().is_none());
+
+        // A connection gets its share, then its messages are dropped
+        let agent = state.outbound.connection("agent");
+        for n in 0..3 {
+            forward(&state, &agent, status(n)).await;
+        }
+        assert_eq!(state.outbound.depth(), 2);
+        assert_eq!(
+            dropped_count(&state, "status_update", "backend_disconnected"),
+            "1"
+        );
+        // Observers saw all three all the same
+        for _ in 0..3 {
+            observer.recv().await.unwrap();
+        }
+
+        // The gateway's own messages take what is left
+        assert!(post(
+            &state,
+            BackendMessage::AgentDisconnected("agent-1".into())
+        ));
+        assert!(!post(
+            &state,
+            BackendMessage::AgentDisconnected("agent-2".into())
+        ));
+        assert_eq!(
+            dropped_count(&state, "agent_disconnected", "queue_full"),
+            "1"
+        );
+
+        // Taking a message gives its room back
+        let first = rx.recv().await.unwrap().into_message();
+        assert!(matches!(first, BackendMessage::StatusUpdate { update, .. } if update["n"] == 0));
+        assert_eq!(state.outbound.depth(), 2);
+        forward(&state, &agent, status(3)).await;
+        assert_eq!(state.outbound.depth(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_drops() {
+        let mut state = state(1, 1);
+        let path = std::env::temp_dir().join(format!("opsmap-dlq-{}.db", uuid::Uuid::new_v4()));
+        state.dead_letters =
+            crate::deadletter::DeadLetters::open(&crate::deadletter::DeadLetterSettings {
+                enabled: true,
+                file_path: path.to_string_lossy().into_owned(),
+                max_entries: 10,
+            })
+            .unwrap();
+
+        // A status update is sent again by its agent, a response is not
+        assert!(post(&state, status(0)));
+        assert!(!post(&state, status(1)));
+        let response = serde_json::json!({"job_id": "cmd-1", "status": "completed"});
+        assert!(!post(&state, BackendMessage::CommandResponse(response)));
+
+        let kept = state
+            .dead_letters
+            .list(&crate::deadletter::DeadLetterQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].reason, "backend queue: queue_full");
+        assert_eq!(kept[0].payload["type"], "command_response");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let state = Arc::new(state(10, 1));
+        state.metrics.set_backend_connected(true);
+        let mut rx = state.outbound.take_receiver().unwrap();
+        let agent = state.outbound.connection("agent");
+
+        // Over its share, a connection waits for the backend link
+        forward(&state, &agent, status(0)).await;
+        let waiting = {
+            let state = state.clone();
+            let agent = agent.clone();
+            tokio::spawn(async move { forward(&state, &agent, status(1)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        assert!(state.metrics.render(0).lines().any(|line| line
+            .starts_with("opsmap_gateway_backpressure_waits_total{")
+            && line.contains("peer=\"agent\"")
+            && line.ends_with(" 1")));
+
+        // Other connections are not held up
+        forward(&state, &state.outbound.connection("poll"), status(2)).await;
+
+        rx.recv().await.unwrap();
+        waiting.await.unwrap();
+        let queued: Vec<_> = [rx.recv().await.unwrap(), rx.recv().await.unwrap()]
+            .into_iter()
+            .map(|queued| match queued.into_message() {
+                BackendMessage::StatusUpdate { update, .. } => update["n"].as_u64().unwrap(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(queued, vec![2, 1]);
+        assert_eq!(dropped_count(&state, "status_update", "timeout"), "0");
+    }
+}
@@ -0,0 +1,335 @@
+//! Gateway state kept across restarts
+//!
+//! The registry's agents and their latest check statuses are written to a
+//! SQLite file every `flush_interval_secs`. On startup they are read back
+//! as unconfirmed: `/agents/:id` and `/summary` show them, the backend
+//! and `/agents` only see connected agents. An agent registering again
+//! takes its place back, its checks standing until it reports them again,
+//! so a status that did not change over the restart is not taken for a
+//! new one. Agents not heard from in `forget_after_hours` are dropped.
+//!
+//! ```yaml
+//! persistence:
+//!   enabled: true   # the default
+//!   file_path: /var/lib/opsmap/gateway-state.db
+//!   flush_interval_secs: 10
+//!   forget_after_hours: 168
+//! ```
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error};
+
+use crate::registry::{AgentInfo, CheckStatus, RestoredAgent};
+use crate::GatewayState;
+
+/// Persistence settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistenceSettings {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_file")]
+    pub file_path: String,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval_secs: u64,
+    /// Agents not heard from for this long are forgotten
+    #[serde(default = "default_forget_after")]
+    pub forget_after_hours: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_file() -> String {
+    "/var/lib/opsmap/gateway-state.db".to_string()
+}
+
+fn default_flush_interval() -> u64 {
+    10
+}
+
+fn default_forget_after() -> u64 {
+    7 * 24
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            file_path: default_file(),
+            flush_interval_secs: default_flush_interval(),
+            forget_after_hours: default_forget_after(),
+        }
+    }
+}
+
+impl PersistenceSettings {
+    fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::hours(self.forget_after_hours.min(i64::MAX as u64 / 3600) as i64)
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS agents (
+    id TEXT PRIMARY KEY,
+    info TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS checks (
+    agent_id TEXT NOT NULL,
+    component_id TEXT NOT NULL,
+    check_name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    message TEXT,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (agent_id, component_id, check_name)
+);
+";
+
+/// The SQLite file
+pub struct StateStore {
+    conn: Mutex<Connection>,
+}
+
+impl StateStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open state file {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to initialize state file {}", path.display()))?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Agents and checks as last saved
+    pub fn load(&self) -> Result<Vec<RestoredAgent>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut checks: HashMap<String, Vec<CheckStatus>> = HashMap::new();
+        let mut statement = conn.prepare(
+            "SELECT agent_id, component_id, check_name, status, message, updated_at FROM checks",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                CheckStatus {
+                    component_id: row.get(1)?,
+                    check_name: row.get(2)?,
+                    status: row.get(3)?,
+                    message: row.get(4)?,
+                    updated_at: row.get(5)?,
+                    unconfirmed: true,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (agent_id, check) = row?;
+            checks.entry(agent_id).or_default().push(check);
+        }
+
+        let mut agents = Vec::new();
+        let mut statement = conn.prepare("SELECT id, info FROM agents")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, info) = row?;
+            match serde_json::from_str::<AgentInfo>(&info) {
+                Ok(agent) => agents.push(RestoredAgent {
+                    checks: checks.remove(&id).unwrap_or_default(),
+                    agent,
+                }),
+                Err(e) => debug!(agent_id = %id, error = %e, "Saved agent not readable, skipped"),
+            }
+        }
+        Ok(agents)
+    }
+
+    /// Save agents with their checks, replacing what was saved for them,
+    /// and drop agents last seen before `cutoff`
+    pub fn save(&self, agents: &[RestoredAgent], cutoff: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO agents (id, info, last_seen) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(id) DO UPDATE SET info = excluded.info, last_seen = excluded.last_seen",
+            )?;
+            let mut clear = tx.prepare("DELETE FROM checks WHERE agent_id = ?1")?;
+            let mut insert = tx.prepare(
+                "INSERT INTO checks (agent_id, component_id, check_name, status, message, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for RestoredAgent { agent, checks } in agents {
+                upsert.execute(params![
+                    agent.id,
+                    serde_json::to_string(agent)?,
+                    agent.last_heartbeat
+                ])?;
+                clear.execute(params![agent.id])?;
+                for check in checks {
+                    insert.execute(params![
+                        agent.id,
+                        check.component_id,
+                        check.check_name,
+                        check.status,
+                        check.message,
+                        check.updated_at
+                    ])?;
+                }
+            }
+            tx.execute(
+                "DELETE FROM checks WHERE agent_id IN (SELECT id FROM agents WHERE last_seen < ?1)",
+                params![cutoff],
+            )?;
+            tx.execute("DELETE FROM agents WHERE last_seen < ?1", params![cutoff])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Open the state file and put what it holds back in the registry
+pub fn restore(state: &GatewayState) -> Result<Arc<StateStore>> {
+    let settings = &state.config.persistence;
+    let store = StateStore::open(Path::new(&settings.file_path))?;
+    let cutoff = settings.cutoff(Utc::now());
+    let agents: Vec<RestoredAgent> = store
+        .load()?
+        .into_iter()
+        .filter(|restored| restored.agent.last_heartbeat >= cutoff)
+        .collect();
+    if !agents.is_empty() {
+        tracing::info!(
+            agents = agents.len(),
+            file = %settings.file_path,
+            "Agents known before the restart restored, unconfirmed until they reconnect"
+        );
+    }
+    state.registry.restore(agents);
+    Ok(Arc::new(store))
+}
+
+/// Save the registry every `flush_interval_secs`, until the gateway stops
+pub async fn run(state: Arc<GatewayState>, store: Arc<StateStore>) {
+    let period = Duration::from_secs(state.config.persistence.flush_interval_secs.max(1));
+    loop {
+        tokio::time::sleep(period).await;
+
+        let cutoff = state.config.persistence.cutoff(Utc::now());
+        state.registry.forget_restored(cutoff);
+        let agents: Vec<RestoredAgent> = state
+            .registry
+            .list()
+            .into_iter()
+            .map(|agent| RestoredAgent {
+                checks: state.registry.checks(&agent.id),
+                agent,
+            })
+            .chain(state.registry.restored())
+            .collect();
+
+        let store = store.clone();
+        let saved = tokio::task::spawn_blocking(move || store.save(&agents, cutoff)).await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(error = %e, "Failed to save gateway state"),
+            Err(e) => error!(error = %e, "Failed to save gateway state"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::AgentRegistry;
+
+    fn agent(id: &str, last_seen: DateTime<Utc>) -> AgentInfo {
+        AgentInfo {
+            id: id.to_string(),
+            hostname: format!("{}.prod", id),
+            labels: HashMap::from([("role".to_string(), "database".to_string())]),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 2,
+            capabilities: vec!["acks".to_string()],
+            outdated: false,
+            quarantined: true,
+            tenant_id: None,
+            connected_at: last_seen,
+            last_heartbeat: last_seen,
+            health: Default::default(),
+            tx: None,
+        }
+    }
+
+    #[test]
+    fn test_save_restore_reconcile() {
+        let path = std::env::temp_dir().join(format!("opsmap-state-{}.db", uuid::Uuid::new_v4()));
+        let store = StateStore::open(&path).unwrap();
+        let now = Utc::now();
+        let check = CheckStatus {
+            component_id: "postgres".to_string(),
+            check_name: "port".to_string(),
+            status: "error".to_string(),
+            message: Some("refused".to_string()),
+            updated_at: now,
+            unconfirmed: false,
+        };
+        store
+            .save(
+                &[
+                    RestoredAgent {
+                        agent: agent("a", now),
+                        checks: vec![check.clone()],
+                    },
+                    RestoredAgent {
+                        agent: agent("old", now - chrono::Duration::days(30)),
+                        checks: vec![check],
+                    },
+                ],
+                now - chrono::Duration::days(7),
+            )
+            .unwrap();
+        drop(store);
+
+        let restored = StateStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].agent.hostname, "a.prod");
+        assert!(restored[0].checks[0].unconfirmed);
+
+        let registry = AgentRegistry::new();
+        registry.restore(restored);
+        assert_eq!(registry.count(), 0);
+        let details = registry.details("a").unwrap();
+        assert!(details.unconfirmed);
+        assert_eq!(registry.checks("a")[0].status, "error");
+
+        // Back: its checks stand until reported again, quarantine too
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        registry.register(agent("a", Utc::now()), tx);
+        assert!(registry.restored().is_empty());
+        assert!(registry.get("a").unwrap().quarantined);
+        let update = serde_json::json!({"component_id": "postgres", "check_name": "port", "status": "error"});
+        assert_eq!(
+            registry.record_check("a", &update).as_deref(),
+            Some("error")
+        );
+        assert!(!registry.checks("a")[0].unconfirmed);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Command authorization
+//!
+//! Maps the principal and roles supplied by the backend with each command to
+//! what they may run: command types, zones, and the labels of target agents.
+//! A command is allowed when at least one policy grants it; with RBAC
+//! enabled, anything not granted is denied.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// RBAC settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RbacSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+}
+
+/// A grant; empty lists match anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Policy {
+    pub name: String,
+    /// Users (the backend's `requested_by`) this policy applies to
+    #[serde(default)]
+    pub principals: Vec<String>,
+    /// Roles this policy applies to
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Allowed command types (start, stop, restart, action, ...)
+    #[serde(default)]
+    pub command_types: Vec<String>,
+    /// Gateway zones where the grant applies
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Labels the target agent must carry
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// Who is asking
+#[derive(Debug, Clone, Default)]
+pub struct Principal<'a> {
+    pub name: Option<&'a str>,
+    pub roles: &'a [String],
+}
+
+impl Policy {
+    fn applies_to(&self, principal: &Principal) -> bool {
+        let by_name = principal
+            .name
+            .is_some_and(|name| self.principals.iter().any(|p| p == name));
+        let by_role = principal.roles.iter().any(|role| self.roles.contains(role));
+        by_name || by_role
+    }
+
+    fn grants(
+        &self,
+        command_type: &str,
+        zone: &str,
+        agent_labels: &HashMap<String, String>,
+    ) -> bool {
+        let any_or = |list: &[String], value: &str| {
+            list.is_empty() || list.iter().any(|v| v == "*" || v == value)
+        };
+
+        any_or(&self.command_types, command_type)
+            && any_or(&self.zones, zone)
+            && self
+                .labels
+                .iter()
+                .all(|(k, v)| agent_labels.get(k) == Some(v))
+    }
+}
+
+/// Evaluates commands against the configured policies
+#[derive(Debug, Clone)]
+pub struct PolicyEngine {
+    settings: RbacSettings,
+    zone: String,
+}
+
+impl PolicyEngine {
+    pub fn new(settings: RbacSettings, zone: &str) -> Self {
+        Self {
+            settings,
+            zone: zone.to_string(),
+        }
+    }
+
+    /// Whether a policy grants on the agent label `key`, which only the
+    /// agent's own configuration may then set
+    pub fn guards_label(&self, key: &str) -> bool {
+        self.settings
+            .policies
+            .iter()
+            .any(|p| p.labels.contains_key(key))
+    }
+
+    /// Allow or deny a command on an agent, with the reason for a denial
+    pub fn authorize(
+        &self,
+        principal: &Principal,
+        command_type: &str,
+        agent_labels: &HashMap<String, String>,
+    ) -> Result<(), String> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+
+        let who = principal.name.unwrap_or("anonymous");
+        let policies: Vec<&Policy> = self
+            .settings
+            .policies
+            .iter()
+            .filter(|p| p.applies_to(principal))
+            .collect();
+
+        if policies.is_empty() {
+            return Err(format!(
+                "no policy applies to '{}' (roles: {:?})",
+                who, principal.roles
+            ));
+        }
+
+        if policies
+            .iter()
+            .any(|p| p.grants(command_type, &self.zone, agent_labels))
+        {
+            Ok(())
+        } else {
+            Err(format!(
+                "'{}' is not allowed to run '{}' on this agent in zone '{}'",
+                who, command_type, self.zone
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> PolicyEngine {
+        let settings: RbacSettings = serde_yaml::from_str(
+            r#"
+enabled: true
+policies:
+  - name: db-team
+    roles: [team-db]
+    command_types: [restart]
+    labels: {role: database}
+  - name: admins
+    principals: [root-admin]
+"#,
+        )
+        .unwrap();
+        PolicyEngine::new(settings, "production")
+    }
+
+    fn labels(role: &str) -> HashMap<String, String> {
+        HashMap::from([("role".to_string(), role.to_string())])
+    }
+
+    #[test]
+    fn test_role_restricted_by_type_and_labels() {
+        let engine = engine();
+        let roles = vec!["team-db".to_string()];
+        let principal = Principal {
+            name: Some("alice"),
+            roles: &roles,
+        };
+
+        assert!(engine
+            .authorize(&principal, "restart", &labels("database"))
+            .is_ok());
+        assert!(engine
+            .authorize(&principal, "restart", &labels("web"))
+            .is_err());
+        assert!(engine
+            .authorize(&principal, "stop", &labels("database"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_default_deny_and_disabled() {
+        let engine = engine();
+        let principal = Principal {
+            name: Some("mallory"),
+            roles: &[],
+        };
+        assert!(engine
+            .authorize(&principal, "restart", &labels("web"))
+            .is_err());
+        assert!(engine
+            .authorize(&Principal::default(), "check", &labels("web"))
+            .is_err());
+
+        let admin = Principal {
+            name: Some("root-admin"),
+            roles: &[],
+        };
+        assert!(engine.authorize(&admin, "stop", &labels("web")).is_ok());
+
+        let open = PolicyEngine::new(RbacSettings::default(), "production");
+        assert!(open.authorize(&principal, "stop", &labels("web")).is_ok());
+    }
+}
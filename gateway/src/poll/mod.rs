@@ -0,0 +1,478 @@
+//! HTTPS polling for agents
+//!
+//! Agents that cannot hold a WebSocket open, because a proxy blocks the
+//! upgrade or they were built without WebSocket support, POST to `/poll` at
+//! their poll interval. A request carries the messages the agent queued and
+//! the response those queued for it since the last poll:
+//!
+//! ```json
+//! > {"session": "3f2c...", "messages": [{"type": "status_batch", "payload": {...}, "seq": 7}]}
+//! < {"session": "3f2c...", "messages": [{"type": "ack", "payload": {"seq": 7}}]}
+//! ```
+//!
+//! The first request has no session and starts with `register`. Sessions not
+//! polled within `gateway.poll_session_timeout_secs` are dropped and their
+//! agent unregistered, as if its WebSocket had closed.
+
+use axum::http::StatusCode;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::agent_server::{self, AgentMessage, GatewayToAgentMessage};
+use crate::limits::{AgentLimiter, LimitAction};
+use crate::protocol::{self, Accepted};
+use crate::schema;
+use crate::tls::PeerCertificate;
+use crate::{BackendMessage, GatewayState};
+
+/// How often expired sessions are looked for
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+pub struct PollRequest {
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub messages: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PollResponse {
+    pub session: String,
+    pub messages: Vec<GatewayToAgentMessage>,
+}
+
+/// A polling agent, with the messages waiting for its next poll
+struct Session {
+    agent_id: String,
+    tx: mpsc::Sender<GatewayToAgentMessage>,
+    rx: mpsc::Receiver<GatewayToAgentMessage>,
+    last_poll: Instant,
+    limiter: AgentLimiter,
+}
+
+/// Open polling sessions by id
+#[derive(Default)]
+pub struct PollSessions {
+    sessions: DashMap<String, Arc<Mutex<Session>>>,
+}
+
+impl PollSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.sessions.get(id).map(|s| s.clone())
+    }
+}
+
+/// Handle one poll
+pub async fn poll(
+    state: &GatewayState,
+    request: PollRequest,
+    peer: &PeerCertificate,
+) -> Result<PollResponse, (StatusCode, String)> {
+    let mut messages = request.messages.into_iter();
+
+    let (id, mut reply) = match request.session {
+        Some(id) => (id, Vec::new()),
+        None => {
+            let register = messages.next().ok_or((
+                StatusCode::BAD_REQUEST,
+                "first poll must register".to_string(),
+            ))?;
+            open(state, register, peer)?
+        }
+    };
+
+    let session = state.polls.get(&id).ok_or((
+        StatusCode::NOT_FOUND,
+        "unknown or expired session".to_string(),
+    ))?;
+    let mut session = session.lock().await;
+    if !current(state, &session) {
+        // Disconnected by an operator, or registered again elsewhere
+        state.polls.sessions.remove(&id);
+        return Err((
+            StatusCode::NOT_FOUND,
+            "unknown or expired session".to_string(),
+        ));
+    }
+    session.last_poll = Instant::now();
+    state.registry.heartbeat(&session.agent_id);
+
+    let mut disconnected = false;
+    let queue = state.outbound.connection("poll");
+    for message in messages {
+        let len = message.to_string().len();
+        let agent_id = session.agent_id.clone();
+        state.registry.received_bytes(&agent_id, len);
+        match agent_server::over_limit(state, &agent_id, &mut session.limiter, len) {
+            Some(LimitAction::Close) => {
+                close(state, &id, &session);
+                return Err((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate limit exceeded".to_string(),
+                ));
+            }
+            Some(LimitAction::Drop) => continue,
+            None => {}
+        }
+        disconnected |= message["type"] == "disconnect";
+        let frame = schema::check_size(len, state.config.limits.max_message_bytes)
+            .and_then(|()| schema::from_value(&message, agent_server::AGENT_MESSAGE_TYPES));
+        match frame {
+            Ok(frame) => agent_server::handle_agent_message(frame, state, &agent_id, &queue).await,
+            Err(error) => reply.push(agent_server::rejected(state, &agent_id, error)),
+        }
+    }
+
+    while let Ok(message) = session.rx.try_recv() {
+        reply.push(message);
+    }
+
+    if disconnected {
+        close(state, &id, &session);
+    }
+
+    Ok(PollResponse {
+        session: id,
+        messages: reply,
+    })
+}
+
+/// Register a polling agent and open its session
+fn open(
+    state: &GatewayState,
+    register: serde_json::Value,
+    peer: &PeerCertificate,
+) -> Result<(String, Vec<GatewayToAgentMessage>), (StatusCode, String)> {
+    if register.to_string().len() > state.config.limits.max_register_bytes {
+        warn!("Poll registration exceeds limits.max_register_bytes");
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "registration too large".to_string(),
+        ));
+    }
+    let payload = match serde_json::from_value(register) {
+        Ok(AgentMessage::Register(payload)) => payload,
+        _ => {
+            warn!("First poll was not registration");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "first poll must register".to_string(),
+            ));
+        }
+    };
+
+    let mut agent_info = agent_server::registered_agent(payload);
+    // Poll bodies are always JSON
+    agent_info
+        .capabilities
+        .retain(|c| c != protocol::MSGPACK && c != protocol::DEFLATE);
+    agent_server::backend_capabilities(state, &mut agent_info);
+    agent_server::check_identity(state, &mut agent_info, peer)
+        .map_err(|reason| (StatusCode::FORBIDDEN, reason))?;
+    agent_server::check_version(state, &mut agent_info)
+        .map_err(|reason| (StatusCode::UPGRADE_REQUIRED, reason))?;
+    if state.connections.full(state.polls.count()) {
+        warn!(agent_id = %agent_info.id, "Poll session refused, gateway at limits.max_connections");
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "gateway at capacity".to_string(),
+        ));
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    info!(
+        agent_id = %agent_info.id,
+        hostname = %agent_info.hostname,
+        protocol_version = agent_info.protocol_version,
+        capabilities = ?agent_info.capabilities,
+        session = %id,
+        "Agent connected by polling"
+    );
+
+    // Agents from before negotiation would not understand the answer
+    let mut reply = Vec::new();
+    if agent_info.protocol_version > 1 {
+        reply.push(GatewayToAgentMessage::Registered(Accepted {
+            protocol_version: agent_info.protocol_version,
+            capabilities: agent_info.capabilities.clone(),
+        }));
+    }
+
+    let (tx, rx) = mpsc::channel::<GatewayToAgentMessage>(100);
+    state.registry.register(agent_info.clone(), tx.clone());
+    crate::ha::announce(state, &agent_info.id);
+    crate::commands::redeliver(state, &agent_info.id);
+    crate::outbound::post(state, BackendMessage::AgentConnected(agent_info.clone()));
+
+    state.polls.sessions.insert(
+        id.clone(),
+        Arc::new(Mutex::new(Session {
+            agent_id: agent_info.id,
+            tx,
+            rx,
+            last_poll: Instant::now(),
+            limiter: AgentLimiter::new(&state.config.limits),
+        })),
+    );
+
+    Ok((id, reply))
+}
+
+/// End a session, unregistering its agent unless it has since registered
+/// again on another connection
+fn close(state: &GatewayState, id: &str, session: &Session) {
+    state.polls.sessions.remove(id);
+
+    if current(state, session) {
+        state.registry.unregister(&session.agent_id);
+        crate::outbound::post(
+            state,
+            BackendMessage::AgentDisconnected(session.agent_id.clone()),
+        );
+    }
+
+    info!(agent_id = %session.agent_id, session = %id, "Polling agent disconnected");
+}
+
+/// Whether the registry still routes to this session
+fn current(state: &GatewayState, session: &Session) -> bool {
+    state
+        .registry
+        .get(&session.agent_id)
+        .and_then(|agent| agent.tx)
+        .is_some_and(|tx| tx.same_channel(&session.tx))
+}
+
+/// Drop sessions that stopped polling
+pub async fn reap(state: Arc<GatewayState>) {
+    let timeout = Duration::from_secs(state.config.gateway.poll_session_timeout_secs);
+
+    loop {
+        tokio::time::sleep(REAP_INTERVAL).await;
+
+        let ids: Vec<String> = state
+            .polls
+            .sessions
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for id in ids {
+            let session = match state.polls.get(&id) {
+                Some(session) => session,
+                None => continue,
+            };
+            // Being polled right now
+            let session = match session.try_lock() {
+                Ok(session) => session,
+                Err(_) => continue,
+            };
+            if session.last_poll.elapsed() > timeout {
+                debug!(agent_id = %session.agent_id, session = %id, "Poll session expired");
+                close(&state, &id, &session);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GatewayConfig;
+
+    fn state() -> GatewayState {
+        GatewayState::for_tests(GatewayConfig::default())
+    }
+
+    fn certificate() -> PeerCertificate {
+        PeerCertificate {
+            verified: true,
+            names: vec!["agent-1".to_string()],
+            tenant: None,
+        }
+    }
+
+    fn request(session: Option<&str>, messages: serde_json::Value) -> PollRequest {
+        serde_json::from_value(serde_json::json!({
+            "session": session,
+            "messages": messages,
+        }))
+        .unwrap()
+    }
+
+    fn register(version: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": "register",
+            "payload": {
+                "agent_id": "agent-1",
+                "hostname": "host-1",
+                "labels": {},
+                "version": version,
+                "os": "linux",
+                "protocol_version": 2,
+                "capabilities": ["acks", "msgpack"],
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_poll_session() {
+        let state = state();
+
+        let reply = poll(
+            &state,
+            request(None, serde_json::json!([register("1.0.0")])),
+            &certificate(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.registry.count(), 1);
+        assert!(matches!(
+            reply.messages.as_slice(),
+            [GatewayToAgentMessage::Registered(accepted)]
+                if accepted.supports("acks") && !accepted.supports("msgpack")
+        ));
+
+        // Messages queued between polls are delivered on the next one
+        state
+            .registry
+            .send("agent-1", GatewayToAgentMessage::Ping)
+            .await
+            .unwrap();
+        let next = poll(
+            &state,
+            request(Some(&reply.session), serde_json::json!([])),
+            &certificate(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            next.messages.as_slice(),
+            [GatewayToAgentMessage::Ping]
+        ));
+
+        let disconnect =
+            serde_json::json!([{"type": "disconnect", "payload": {"reason": "shutdown"}}]);
+        poll(
+            &state,
+            request(Some(&reply.session), disconnect),
+            &certificate(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.registry.count(), 0);
+        assert_eq!(state.polls.count(), 0);
+
+        let expired = poll(
+            &state,
+            request(Some(&reply.session), serde_json::json!([])),
+            &certificate(),
+        )
+        .await;
+        assert!(matches!(expired, Err((StatusCode::NOT_FOUND, _))));
+    }
+
+    #[tokio::test]
+    async fn test_poll_redelivery() {
+        let state = state();
+        let command = serde_json::from_value(serde_json::json!({
+            "id": "cmd-1",
+            "command_type": "restart",
+            "component_id": "postgres",
+            "action_name": null,
+            "params": {},
+            "timeout_secs": 60,
+        }))
+        .unwrap();
+        // Routed while the agent was away
+        state.commands.track("agent-1", &command);
+
+        let reply = poll(
+            &state,
+            request(None, serde_json::json!([register("1.0.0")])),
+            &certificate(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            reply.messages.as_slice(),
+            [GatewayToAgentMessage::Registered(_), GatewayToAgentMessage::Command(c)] if c.id == "cmd-1"
+        ));
+        assert_eq!(state.commands.for_agent("agent-1")[0].deliveries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_poll_disconnected_by_operator() {
+        let state = state();
+        let reply = poll(
+            &state,
+            request(None, serde_json::json!([register("1.0.0")])),
+            &certificate(),
+        )
+        .await
+        .unwrap();
+
+        state.registry.disconnect("agent-1").unwrap();
+        let next = poll(
+            &state,
+            request(Some(&reply.session), serde_json::json!([])),
+            &certificate(),
+        )
+        .await;
+        assert!(matches!(next, Err((StatusCode::NOT_FOUND, _))));
+        assert_eq!(state.polls.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_poll_registration() {
+        let mut state = state();
+
+        // A certificate issued for another agent
+        let other = PeerCertificate {
+            verified: true,
+            names: vec!["agent-2".to_string()],
+            tenant: None,
+        };
+        let impostor = poll(
+            &state,
+            request(None, serde_json::json!([register("1.0.0")])),
+            &other,
+        )
+        .await;
+        assert!(matches!(impostor, Err((StatusCode::FORBIDDEN, _))));
+        assert_eq!(state.registry.count(), 0);
+
+        let unregistered = poll(
+            &state,
+            request(None, serde_json::json!([{"type": "pong"}])),
+            &certificate(),
+        )
+        .await;
+        assert!(matches!(unregistered, Err((StatusCode::BAD_REQUEST, _))));
+
+        state.versions = crate::versions::VersionGate::new(crate::versions::VersionPolicy {
+            min_version: Some("2.0.0".to_string()),
+            reject: true,
+        });
+        let outdated = poll(
+            &state,
+            request(None, serde_json::json!([register("1.0.0")])),
+            &certificate(),
+        )
+        .await;
+        assert!(matches!(outdated, Err((StatusCode::UPGRADE_REQUIRED, _))));
+        assert_eq!(state.registry.count(), 0);
+    }
+}
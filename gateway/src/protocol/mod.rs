@@ -0,0 +1,141 @@
+//! Protocol negotiation
+//!
+//! Agents and the Gateway announce a protocol version and the optional
+//! features they understand when registering; the receiving side answers
+//! with what both support, so new message types are only sent to peers that
+//! can parse them. Peers that send no version predate negotiation and are
+//! treated as protocol 1 without capabilities.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[path = "../../../shared/deflate.rs"]
+mod deflate;
+pub use deflate::inflate;
+
+/// Version spoken by this Gateway on both links
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Optional features this Gateway understands
+pub const CAPABILITIES: &[&str] = &[
+    "acks",
+    "config_update",
+    "deflate",
+    "diagnostics",
+    "discovery",
+    "inventory",
+    "metadata",
+    "msgpack",
+    "response_batches",
+    "snapshot_delta",
+];
+
+/// Sequence numbers and acks for status messages
+pub const ACKS: &str = "acks";
+
+/// Settings pushed with `POST /config-updates`
+pub const CONFIG_UPDATE: &str = "config_update";
+
+/// MessagePack binary frames instead of JSON text on the agent link
+pub const MSGPACK: &str = "msgpack";
+
+/// zlib-compressed binary frames above a size threshold, on both links
+pub const DEFLATE: &str = "deflate";
+
+/// Support bundles sent by agents in `diagnostics` chunks; offered to agents
+/// only while the backend takes them too
+pub const DIAGNOSTICS: &str = "diagnostics";
+
+/// Responses to a fan-out sent to the backend as `command_responses` batches
+pub const RESPONSE_BATCHES: &str = "response_batches";
+
+/// What a registration settled on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Accepted {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Accepted {
+    /// A peer that predates negotiation
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Our capabilities, as announced on registration
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+/// The version and features shared with a peer
+pub fn negotiate(protocol_version: u32, capabilities: &[String]) -> Accepted {
+    Accepted {
+        protocol_version: protocol_version.min(PROTOCOL_VERSION),
+        capabilities: capabilities
+            .iter()
+            .filter(|c| CAPABILITIES.contains(&c.as_str()))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Encode a message as MessagePack, keeping field names so the same serde
+/// definitions (tags, flattening, defaults) apply as for JSON
+pub fn to_msgpack<T: Serialize>(message: &T) -> anyhow::Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec_named(message)?)
+}
+
+pub fn from_msgpack<T: DeserializeOwned>(data: &[u8]) -> anyhow::Result<T> {
+    Ok(rmp_serde::from_slice(data)?)
+}
+
+/// Deflate a frame larger than `compress_above`; `None` leaves it as is
+pub fn compress(data: &[u8], compress_above: Option<usize>) -> anyhow::Result<Option<Vec<u8>>> {
+    match compress_above {
+        Some(limit) if data.len() > limit => Ok(Some(deflate::deflate(data)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Serde default for registrations without a version
+pub fn legacy_version() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let offered = vec!["acks".to_string(), "zstd".to_string()];
+        let accepted = negotiate(5, &offered);
+
+        assert_eq!(accepted.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(accepted.capabilities, vec!["acks"]);
+        assert!(accepted.supports(ACKS));
+        assert!(!Accepted::legacy().supports(ACKS));
+    }
+
+    #[test]
+    fn test_compression_threshold() {
+        let json = serde_json::to_vec(&serde_json::json!({"deltas": vec!["ok"; 1000]})).unwrap();
+        assert!(compress(&json, None).unwrap().is_none());
+        assert!(compress(&json, Some(json.len())).unwrap().is_none());
+
+        let deflated = compress(&json, Some(100)).unwrap().unwrap();
+        assert!(deflated.len() < json.len());
+        assert_eq!(inflate(deflated).unwrap(), json);
+
+        // Uncompressed frames pass through
+        assert_eq!(inflate(json.clone()).unwrap(), json);
+    }
+}
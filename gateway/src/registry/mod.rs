@@ -1,27 +1,74 @@
 //! Agent registry module
 //!
-//! Maintains a registry of connected agents and their metadata.
+//! Maintains a registry of connected agents and their metadata, with the
+//! messages exchanged with each, their latest check statuses and the
+//! commands they have not answered yet.
 
 use chrono::{DateTime, Utc};
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 
+use crate::agent_server::GatewayToAgentMessage;
+use crate::health::{AgentHealth, HealthSettings};
+use crate::selector::{glob, Selector};
+
 /// Information about a connected agent
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentInfo {
     pub id: String,
     pub hostname: String,
     pub labels: HashMap<String, String>,
+    /// Addresses of the agent's host, as it last reported them
+    #[serde(default)]
+    pub ip_addresses: Vec<String>,
     pub version: String,
     pub os: String,
+    /// Protocol version agreed on at registration
+    #[serde(default = "crate::protocol::legacy_version")]
+    pub protocol_version: u32,
+    /// Optional features agreed on at registration
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Older than the minimum agent version when it registered
+    #[serde(default)]
+    pub outdated: bool,
+    /// Connected, but no command is routed to it
+    #[serde(default)]
+    pub quarantined: bool,
+    /// Customer the agent belongs to; only that tenant's commands and
+    /// snapshots reach it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
     pub connected_at: DateTime<Utc>,
     pub last_heartbeat: DateTime<Utc>,
+    /// From its heartbeat's age and failed sends, see `health`
+    #[serde(default)]
+    pub health: AgentHealth,
     #[serde(skip)]
-    pub tx: Option<mpsc::Sender<AgentCommand>>,
+    pub tx: Option<mpsc::Sender<GatewayToAgentMessage>>,
+}
+
+impl AgentInfo {
+    /// Whether an optional feature was agreed on at registration
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Whether something of `tenant_id` may reach the agent: the same
+    /// tenant, or neither has one
+    pub fn of_tenant(&self, tenant_id: Option<&str>) -> bool {
+        self.tenant_id.as_deref() == tenant_id
+    }
+
+    /// The agent's zone: its `zone` label, else the gateway's
+    pub fn zone<'a>(&'a self, gateway_zone: &'a str) -> &'a str {
+        self.labels.get("zone").map_or(gateway_zone, String::as_str)
+    }
 }
 
 /// Command to send to an agent
@@ -33,34 +80,216 @@ pub struct AgentCommand {
     pub action_name: Option<String>,
     pub params: serde_json::Value,
     pub timeout_secs: u64,
+    /// W3C trace context, propagated from the backend to the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_context: Option<crate::telemetry::TraceContext>,
+    /// Run on the agent at this time instead of now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Run on the agent this many seconds after it is received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_after_secs: Option<u64>,
+    /// Tenant the command is for; agents of other tenants refuse it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    /// Id tying together every message about the command, from its
+    /// submission to its final response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl AgentCommand {
+    /// The command's correlation id, given one if it was submitted without
+    pub fn correlate(&mut self) -> &str {
+        self.correlation_id
+            .get_or_insert_with(|| uuid::Uuid::new_v4().to_string())
+    }
+}
+
+/// Traffic with an agent since it registered
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AgentTraffic {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    /// Status deltas, alone or in batches
+    pub deltas_received: u64,
+    pub batches_received: u64,
+    pub command_responses: u64,
+    /// As framed on the wire, compressed or not
+    pub bytes_received: u64,
+    pub last_message_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct AgentStats {
+    traffic: AgentTraffic,
+    last_send_failure: Option<Instant>,
+}
+
+/// An agent's latest result for one of its checks
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckStatus {
+    pub component_id: String,
+    pub check_name: String,
+    pub status: String,
+    pub message: Option<String>,
+    pub updated_at: DateTime<Utc>,
+    /// Reloaded from before a gateway restart, not reported again since
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unconfirmed: bool,
+}
+
+/// An agent with its traffic and unanswered commands, for `GET /agents/:id`
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentDetails {
+    #[serde(flatten)]
+    pub agent: AgentInfo,
+    pub heartbeat_age_secs: i64,
+    /// Known from before a gateway restart, not reconnected since
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unconfirmed: bool,
+    #[serde(flatten)]
+    pub traffic: AgentTraffic,
+    pub pending_commands: Vec<crate::commands::PendingCommand>,
+    /// Latest check statuses
+    pub checks: Vec<CheckStatus>,
+}
+
+/// Filters and page for `GET /agents`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AgentQuery {
+    /// Label selector expression (see `selector`)
+    pub labels: Option<String>,
+    /// The agent's `zone` label, or the gateway's zone when it has none
+    pub zone: Option<String>,
+    /// Hostname glob (`*` and `?`, case-insensitive)
+    pub hostname: Option<String>,
+    pub connected_since: Option<DateTime<Utc>>,
+    pub tenant_id: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl AgentQuery {
+    /// The label selectors, failing on one without `=`
+    pub fn selector(&self) -> Result<Selector, String> {
+        match self.labels {
+            Some(ref labels) => Selector::parse(labels),
+            None => Ok(Selector::default()),
+        }
+    }
+
+    pub fn matches(&self, agent: &AgentInfo, selector: &Selector, zone: &str) -> bool {
+        selector.matches(&agent.labels)
+            && self.zone.as_ref().is_none_or(|z| agent.zone(zone) == z)
+            && self.hostname.as_ref().is_none_or(|pattern| {
+                glob(
+                    &pattern.to_ascii_lowercase(),
+                    &agent.hostname.to_ascii_lowercase(),
+                )
+            })
+            && self
+                .connected_since
+                .is_none_or(|since| agent.connected_at >= since)
+            && self
+                .tenant_id
+                .as_ref()
+                .is_none_or(|tenant| agent.of_tenant(Some(tenant)))
+    }
 }
 
 /// Agent registry
 pub struct AgentRegistry {
     agents: DashMap<String, AgentInfo>,
+    stats: DashMap<String, AgentStats>,
+    /// Latest check statuses by agent, then component and check
+    checks: DashMap<String, HashMap<(String, String), CheckStatus>>,
+    /// Quarantined agent ids, kept across reconnections
+    quarantine: DashSet<String>,
+    /// Agents and checks reloaded from before a restart, until they register
+    restored: DashMap<String, RestoredAgent>,
+}
+
+/// An agent as last known before a gateway restart
+#[derive(Debug, Clone)]
+pub struct RestoredAgent {
+    pub agent: AgentInfo,
+    pub checks: Vec<CheckStatus>,
 }
 
 impl AgentRegistry {
     pub fn new() -> Self {
         Self {
             agents: DashMap::new(),
+            stats: DashMap::new(),
+            checks: DashMap::new(),
+            quarantine: DashSet::new(),
+            restored: DashMap::new(),
         }
     }
 
     /// Register a new agent
-    pub fn register(&self, mut info: AgentInfo, tx: mpsc::Sender<AgentCommand>) {
+    pub fn register(&self, mut info: AgentInfo, tx: mpsc::Sender<GatewayToAgentMessage>) {
         info.tx = Some(tx);
+        info.quarantined = self.quarantine.contains(&info.id);
         info!(
             agent_id = %info.id,
             hostname = %info.hostname,
             version = %info.version,
             "Agent registered"
         );
+        // Checks known from before a restart stand until reported again,
+        // so a status that did not change is not taken for a new one
+        let checks = self
+            .restored
+            .remove(&info.id)
+            .map(|(_, restored)| {
+                restored
+                    .checks
+                    .into_iter()
+                    .map(|c| ((c.component_id.clone(), c.check_name.clone()), c))
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.stats.insert(info.id.clone(), AgentStats::default());
+        self.checks.insert(info.id.clone(), checks);
         self.agents.insert(info.id.clone(), info);
     }
 
+    /// Take back agents known before a restart, as unconfirmed until they
+    /// register; connected agents are left alone
+    pub fn restore(&self, agents: Vec<RestoredAgent>) {
+        for mut restored in agents {
+            if self.agents.contains_key(&restored.agent.id) {
+                continue;
+            }
+            restored.agent.tx = None;
+            for check in &mut restored.checks {
+                check.unconfirmed = true;
+            }
+            if restored.agent.quarantined {
+                self.quarantine.insert(restored.agent.id.clone());
+            }
+            self.restored.insert(restored.agent.id.clone(), restored);
+        }
+    }
+
+    /// Agents known from before a restart that have not registered since
+    pub fn restored(&self) -> Vec<RestoredAgent> {
+        self.restored.iter().map(|r| r.clone()).collect()
+    }
+
+    /// Forget restored agents last heard from before `cutoff`
+    pub fn forget_restored(&self, cutoff: DateTime<Utc>) {
+        self.restored
+            .retain(|_, restored| restored.agent.last_heartbeat >= cutoff);
+    }
+
     /// Unregister an agent
     pub fn unregister(&self, agent_id: &str) {
+        self.stats.remove(agent_id);
+        self.checks.remove(agent_id);
         if let Some((_, info)) = self.agents.remove(agent_id) {
             info!(
                 agent_id = %agent_id,
@@ -75,6 +304,177 @@ impl AgentRegistry {
         self.agents.get(agent_id).map(|r| r.clone())
     }
 
+    /// Drop an agent's registration, so its connection closes and it has
+    /// to register again; returns the agent as it was
+    pub fn disconnect(&self, agent_id: &str) -> Option<AgentInfo> {
+        self.stats.remove(agent_id);
+        self.checks.remove(agent_id);
+        let (_, info) = self.agents.remove(agent_id)?;
+        warn!(agent_id = %agent_id, hostname = %info.hostname, "Agent disconnected by operator");
+        Some(info)
+    }
+
+    /// Stop or resume routing commands to an agent, returning it as updated
+    pub fn set_quarantined(&self, agent_id: &str, quarantined: bool) -> Option<AgentInfo> {
+        let mut agent = self.agents.get_mut(agent_id)?;
+        if quarantined {
+            self.quarantine.insert(agent_id.to_string());
+            warn!(agent_id = %agent_id, hostname = %agent.hostname, "Agent quarantined");
+        } else if self.quarantine.remove(agent_id).is_some() {
+            info!(
+                agent_id = %agent_id,
+                hostname = %agent.hostname,
+                "Agent released from quarantine"
+            );
+        }
+        agent.quarantined = quarantined;
+        Some(agent.clone())
+    }
+
+    /// An agent with its traffic since it registered, or as known before
+    /// a restart
+    pub fn details(&self, agent_id: &str) -> Option<AgentDetails> {
+        let (agent, unconfirmed) = match self.get(agent_id) {
+            Some(agent) => (agent, false),
+            None => (self.restored.get(agent_id)?.agent.clone(), true),
+        };
+        Some(AgentDetails {
+            heartbeat_age_secs: (Utc::now() - agent.last_heartbeat).num_seconds().max(0),
+            unconfirmed,
+            traffic: self
+                .stats
+                .get(agent_id)
+                .map(|s| s.traffic.clone())
+                .unwrap_or_default(),
+            // Filled in from the pending command table
+            pending_commands: Vec::new(),
+            checks: Vec::new(),
+            agent,
+        })
+    }
+
+    /// Count a message received from an agent; like a heartbeat, it shows
+    /// the agent is alive
+    pub fn received(&self, agent_id: &str) {
+        let now = Utc::now();
+        self.add_traffic(agent_id, |traffic| {
+            traffic.messages_received += 1;
+            traffic.last_message_at = Some(now);
+        });
+        if let Some(mut agent) = self.agents.get_mut(agent_id) {
+            agent.last_heartbeat = now;
+        }
+    }
+
+    /// Count status deltas from an agent, `batch` when they came in one
+    pub fn received_deltas(&self, agent_id: &str, count: usize, batch: bool) {
+        self.add_traffic(agent_id, |traffic| {
+            traffic.deltas_received += count as u64;
+            traffic.batches_received += batch as u64;
+        });
+    }
+
+    pub fn received_response(&self, agent_id: &str) {
+        self.add_traffic(agent_id, |traffic| traffic.command_responses += 1);
+    }
+
+    /// Count a frame of `len` bytes from an agent, taken or not
+    pub fn received_bytes(&self, agent_id: &str, len: usize) {
+        self.add_traffic(agent_id, |traffic| traffic.bytes_received += len as u64);
+    }
+
+    fn sent(&self, agent_id: &str) {
+        self.add_traffic(agent_id, |traffic| traffic.messages_sent += 1);
+    }
+
+    fn add_traffic(&self, agent_id: &str, f: impl FnOnce(&mut AgentTraffic)) {
+        if let Some(mut stats) = self.stats.get_mut(agent_id) {
+            f(&mut stats.traffic);
+        }
+    }
+
+    /// Traffic of every connected agent, by agent id
+    pub fn traffic(&self) -> Vec<(String, AgentTraffic)> {
+        self.stats
+            .iter()
+            .map(|stats| (stats.key().clone(), stats.traffic.clone()))
+            .collect()
+    }
+
+    /// Note a message the agent's channel refused, full or closed
+    fn send_failed(&self, agent_id: &str) {
+        if let Some(mut stats) = self.stats.get_mut(agent_id) {
+            stats.last_send_failure = Some(Instant::now());
+        }
+    }
+
+    /// Work out every agent's health again, returning those whose health
+    /// changed, as updated, with their health before
+    pub fn refresh_health(&self, settings: &HealthSettings) -> Vec<(AgentInfo, AgentHealth)> {
+        let now = Utc::now();
+        let mut changed = Vec::new();
+        for mut agent in self.agents.iter_mut() {
+            let heartbeat_age = (now - agent.last_heartbeat).num_seconds().max(0) as u64;
+            let send_failure_age = self
+                .stats
+                .get(&agent.id)
+                .and_then(|s| s.last_send_failure)
+                .map(|at| at.elapsed().as_secs());
+            let health = AgentHealth::of(heartbeat_age, send_failure_age, settings);
+            if health != agent.health {
+                let previous = std::mem::replace(&mut agent.health, health);
+                changed.push((agent.clone(), previous));
+            }
+        }
+        changed
+    }
+
+    /// Keep a status update from an agent as its check's latest, returning
+    /// the status the check had before
+    pub fn record_check(&self, agent_id: &str, update: &serde_json::Value) -> Option<String> {
+        let field = |name: &str| update.get(name).and_then(|v| v.as_str());
+        let (component_id, check_name, status) =
+            match (field("component_id"), field("check_name"), field("status")) {
+                (Some(component_id), Some(check_name), Some(status)) => {
+                    (component_id, check_name, status)
+                }
+                _ => return None,
+            };
+        let check = CheckStatus {
+            component_id: component_id.to_string(),
+            check_name: check_name.to_string(),
+            status: status.to_string(),
+            message: field("message").map(str::to_string),
+            updated_at: field("timestamp")
+                .and_then(|t| t.parse().ok())
+                .unwrap_or_else(Utc::now),
+            unconfirmed: false,
+        };
+        let mut checks = self.checks.get_mut(agent_id)?;
+        checks
+            .insert(
+                (check.component_id.clone(), check.check_name.clone()),
+                check,
+            )
+            .map(|previous| previous.status)
+    }
+
+    /// An agent's latest check statuses, by component and check
+    pub fn checks(&self, agent_id: &str) -> Vec<CheckStatus> {
+        let mut checks: Vec<CheckStatus> = match self.checks.get(agent_id) {
+            Some(checks) => checks.values().cloned().collect(),
+            None => self
+                .restored
+                .get(agent_id)
+                .map(|r| r.checks.clone())
+                .unwrap_or_default(),
+        };
+        checks.sort_by(|a, b| {
+            (&a.component_id, &a.check_name).cmp(&(&b.component_id, &b.check_name))
+        });
+        checks
+    }
+
     /// Update agent heartbeat
     pub fn heartbeat(&self, agent_id: &str) {
         if let Some(mut agent) = self.agents.get_mut(agent_id) {
@@ -83,6 +483,27 @@ impl AgentRegistry {
         }
     }
 
+    /// Apply an agent's metadata update, returning the agent as updated
+    pub fn update_metadata(
+        &self,
+        agent_id: &str,
+        hostname: String,
+        labels: HashMap<String, String>,
+        ip_addresses: Vec<String>,
+    ) -> Option<AgentInfo> {
+        let mut agent = self.agents.get_mut(agent_id)?;
+        info!(
+            agent_id = %agent_id,
+            hostname = %hostname,
+            "Agent metadata updated"
+        );
+        agent.hostname = hostname;
+        agent.labels = labels;
+        agent.ip_addresses = ip_addresses;
+        agent.last_heartbeat = Utc::now();
+        Some(agent.clone())
+    }
+
     /// List all agents
     pub fn list(&self) -> Vec<AgentInfo> {
         self.agents.iter().map(|r| r.clone()).collect()
@@ -93,14 +514,44 @@ impl AgentRegistry {
         self.agents.len()
     }
 
+    /// Agents matching `query`, sorted by id, and how many match before
+    /// the page is taken
+    pub fn query(&self, query: &AgentQuery, zone: &str) -> Result<(usize, Vec<AgentInfo>), String> {
+        let selector = query.selector()?;
+        let mut agents: Vec<AgentInfo> = self
+            .agents
+            .iter()
+            .filter(|agent| query.matches(agent, &selector, zone))
+            .map(|r| r.clone())
+            .collect();
+        agents.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let total = agents.len();
+        let page = agents
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok((total, page))
+    }
+
+    /// Find agents matching a label selector
+    pub fn find_matching(&self, selector: &Selector) -> Vec<AgentInfo> {
+        self.agents
+            .iter()
+            .filter(|agent| selector.matches(&agent.labels))
+            .map(|r| r.clone())
+            .collect()
+    }
+
     /// Find agents matching labels
     pub fn find_by_labels(&self, labels: &HashMap<String, String>) -> Vec<AgentInfo> {
         self.agents
             .iter()
             .filter(|agent| {
-                labels.iter().all(|(k, v)| {
-                    agent.labels.get(k).map_or(false, |av| av == v)
-                })
+                labels
+                    .iter()
+                    .all(|(k, v)| agent.labels.get(k).map_or(false, |av| av == v))
             })
             .map(|r| r.clone())
             .collect()
@@ -116,17 +567,113 @@ impl AgentRegistry {
 
     /// Send command to specific agent
     pub async fn send_command(&self, agent_id: &str, command: AgentCommand) -> Result<(), String> {
-        if let Some(agent) = self.agents.get(agent_id) {
-            if let Some(ref tx) = agent.tx {
-                tx.send(command)
-                    .await
-                    .map_err(|e| format!("Failed to send command: {}", e))?;
-                Ok(())
-            } else {
-                Err("Agent has no command channel".to_string())
+        // Out of the map before awaiting: a full channel would otherwise
+        // hold the shard's lock for as long as the agent is not reading
+        let tx = {
+            let agent = self
+                .agents
+                .get(agent_id)
+                .ok_or_else(|| format!("Agent not found: {}", agent_id))?;
+            if agent.quarantined {
+                return Err("Agent is quarantined".to_string());
+            }
+            if !agent.of_tenant(command.tenant_id.as_deref()) {
+                return Err("Agent belongs to another tenant".to_string());
+            }
+            agent
+                .tx
+                .clone()
+                .ok_or_else(|| "Agent has no command channel".to_string())?
+        };
+
+        if let Err(e) = tx
+            .send(GatewayToAgentMessage::Command(Box::new(command)))
+            .await
+        {
+            self.send_failed(agent_id);
+            return Err(format!("Failed to send command: {}", e));
+        }
+        self.sent(agent_id);
+        Ok(())
+    }
+
+    /// Send a command only if the agent's channel has room for it now
+    pub fn try_send_command(&self, agent_id: &str, command: AgentCommand) -> Result<(), String> {
+        let agent = self
+            .agents
+            .get(agent_id)
+            .ok_or_else(|| format!("Agent not found: {}", agent_id))?;
+        if agent.quarantined {
+            return Err("Agent is quarantined".to_string());
+        }
+        if !agent.of_tenant(command.tenant_id.as_deref()) {
+            return Err("Agent belongs to another tenant".to_string());
+        }
+        let sent = agent
+            .tx
+            .as_ref()
+            .ok_or_else(|| "Agent has no command channel".to_string())?
+            .try_send(GatewayToAgentMessage::Command(Box::new(command)));
+        if let Err(e) = sent {
+            self.send_failed(agent_id);
+            return Err(format!("Failed to send command: {}", e));
+        }
+        self.sent(agent_id);
+        Ok(())
+    }
+
+    /// Send any other message to a specific agent
+    pub async fn send(&self, agent_id: &str, message: GatewayToAgentMessage) -> Result<(), String> {
+        let tx = self
+            .agents
+            .get(agent_id)
+            .ok_or_else(|| format!("Agent not found: {}", agent_id))?
+            .tx
+            .clone()
+            .ok_or_else(|| "Agent has no command channel".to_string())?;
+
+        if let Err(e) = tx.send(message).await {
+            self.send_failed(agent_id);
+            return Err(format!("Failed to send message: {}", e));
+        }
+        self.sent(agent_id);
+        Ok(())
+    }
+
+    /// Send any other message only if the agent's channel has room for it now
+    pub fn try_send(&self, agent_id: &str, message: GatewayToAgentMessage) -> Result<(), String> {
+        let tx = self
+            .agents
+            .get(agent_id)
+            .ok_or_else(|| format!("Agent not found: {}", agent_id))?
+            .tx
+            .clone()
+            .ok_or_else(|| "Agent has no command channel".to_string())?;
+
+        if let Err(e) = tx.try_send(message) {
+            self.send_failed(agent_id);
+            return Err(format!("Failed to send message: {}", e));
+        }
+        self.sent(agent_id);
+        Ok(())
+    }
+
+    /// Acknowledge an agent's messages up to `seq`
+    pub fn ack(&self, agent_id: &str, seq: u64) {
+        let tx = match self.agents.get(agent_id).and_then(|agent| agent.tx.clone()) {
+            Some(tx) => tx,
+            // Gone; it retransmits on reconnect and is acked then
+            None => return,
+        };
+
+        // Never block the backend link on a slow agent; a missed ack is
+        // covered by the next, cumulative one
+        match tx.try_send(GatewayToAgentMessage::Ack { seq }) {
+            Ok(()) => self.sent(agent_id),
+            Err(_) => {
+                debug!(agent_id = %agent_id, seq = seq, "Ack not queued");
+                self.send_failed(agent_id);
             }
-        } else {
-            Err(format!("Agent not found: {}", agent_id))
         }
     }
 
@@ -183,18 +730,54 @@ mod tests {
             id: "agent-1".to_string(),
             hostname: "host-1".to_string(),
             labels: HashMap::new(),
+            ip_addresses: Vec::new(),
             version: "1.0".to_string(),
             os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
             connected_at: Utc::now(),
             last_heartbeat: Utc::now(),
+            health: Default::default(),
             tx: None,
         };
 
         registry.register(info, tx);
         assert_eq!(registry.count(), 1);
 
+        let update = |component: &str, status: &str| serde_json::json!({"component_id": component, "check_name": "port", "status": status});
+        assert_eq!(
+            registry.record_check("agent-1", &update("postgres", "ok")),
+            None
+        );
+        assert_eq!(
+            registry
+                .record_check("agent-1", &update("postgres", "error"))
+                .as_deref(),
+            Some("ok")
+        );
+        registry.record_check("agent-1", &update("nginx", "ok"));
+        let checks = registry.checks("agent-1");
+        assert_eq!(
+            checks
+                .iter()
+                .map(|c| c.component_id.as_str())
+                .collect::<Vec<_>>(),
+            ["nginx", "postgres"]
+        );
+        assert_eq!(checks[1].status, "error");
+        // Not kept for agents that are not connected
+        assert_eq!(
+            registry.record_check("agent-2", &update("postgres", "ok")),
+            None
+        );
+        assert!(registry.checks("agent-2").is_empty());
+
         registry.unregister("agent-1");
         assert_eq!(registry.count(), 0);
+        assert!(registry.checks("agent-1").is_empty());
     }
 
     #[test]
@@ -209,10 +792,17 @@ mod tests {
             id: "agent-1".to_string(),
             hostname: "host-1".to_string(),
             labels: labels.clone(),
+            ip_addresses: Vec::new(),
             version: "1.0".to_string(),
             os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
             connected_at: Utc::now(),
             last_heartbeat: Utc::now(),
+            health: Default::default(),
             tx: None,
         };
 
@@ -225,5 +815,322 @@ mod tests {
         other_labels.insert("role".to_string(), "web".to_string());
         let not_found = registry.find_by_labels(&other_labels);
         assert_eq!(not_found.len(), 0);
+
+        // Relabelled without reconnecting
+        let updated = registry
+            .update_metadata(
+                "agent-1",
+                "host-1b".to_string(),
+                other_labels.clone(),
+                vec!["10.0.0.7".to_string()],
+            )
+            .unwrap();
+        assert_eq!(updated.hostname, "host-1b");
+        assert_eq!(updated.ip_addresses, ["10.0.0.7"]);
+        assert_eq!(registry.find_by_labels(&other_labels).len(), 1);
+        assert!(registry.find_by_labels(&labels).is_empty());
+        assert!(registry
+            .update_metadata("unknown", String::new(), HashMap::new(), Vec::new())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_and_disconnect() {
+        let registry = AgentRegistry::new();
+        let info = AgentInfo {
+            id: "agent-1".to_string(),
+            hostname: "host-1".to_string(),
+            labels: HashMap::new(),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: Utc::now(),
+            last_heartbeat: Utc::now(),
+            health: Default::default(),
+            tx: None,
+        };
+        let command = AgentCommand {
+            id: "cmd-1".to_string(),
+            command_type: "restart".to_string(),
+            component_id: "postgres".to_string(),
+            action_name: None,
+            params: serde_json::Value::Null,
+            timeout_secs: 60,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: None,
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        registry.register(info.clone(), tx);
+        assert!(
+            registry
+                .set_quarantined("agent-1", true)
+                .unwrap()
+                .quarantined
+        );
+        assert!(registry
+            .send_command("agent-1", command.clone())
+            .await
+            .is_err());
+
+        // Still quarantined after reconnecting
+        registry.disconnect("agent-1").unwrap();
+        assert!(rx.recv().await.is_none());
+        let (tx, _rx) = mpsc::channel(10);
+        registry.register(info, tx);
+        assert!(registry.get("agent-1").unwrap().quarantined);
+
+        registry.set_quarantined("agent-1", false).unwrap();
+        assert!(registry.send_command("agent-1", command).await.is_ok());
+        assert!(registry.set_quarantined("unknown", true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let registry = AgentRegistry::new();
+        let settings = HealthSettings::default();
+        let agent = |id: &str, silent_secs: i64| AgentInfo {
+            id: id.to_string(),
+            hostname: id.to_string(),
+            labels: HashMap::new(),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: Utc::now(),
+            last_heartbeat: Utc::now() - chrono::Duration::seconds(silent_secs),
+            health: Default::default(),
+            tx: None,
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        registry.register(agent("a", 0), tx);
+        let (tx, _rx_b) = mpsc::channel(1);
+        registry.register(agent("b", 100), tx.clone());
+        registry.register(agent("c", 500), tx);
+
+        let mut changed: Vec<_> = registry
+            .refresh_health(&settings)
+            .into_iter()
+            .map(|(agent, previous)| (agent.id, previous, agent.health))
+            .collect();
+        changed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            changed,
+            [
+                ("b".to_string(), AgentHealth::Healthy, AgentHealth::Degraded),
+                ("c".to_string(), AgentHealth::Healthy, AgentHealth::Stale),
+            ]
+        );
+        assert!(registry.refresh_health(&settings).is_empty());
+
+        // An agent that does not take what it is sent is degraded, pings or not
+        registry.ack("a", 1);
+        registry.ack("a", 2);
+        let changed = registry.refresh_health(&settings);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0.health, AgentHealth::Degraded);
+        assert_eq!(registry.get("a").unwrap().health, AgentHealth::Degraded);
+
+        // Heard from again
+        registry.heartbeat("c");
+        let changed = registry.refresh_health(&settings);
+        assert_eq!(changed[0].0.id, "c");
+        assert_eq!(changed[0].0.health, AgentHealth::Healthy);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_send_to_full_channel() {
+        let registry = Arc::new(AgentRegistry::new());
+        let agent = AgentInfo {
+            id: "slow".to_string(),
+            hostname: "slow".to_string(),
+            labels: HashMap::new(),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: Utc::now(),
+            last_heartbeat: Utc::now(),
+            health: Default::default(),
+            tx: None,
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        registry.register(agent, tx);
+        registry.ack("slow", 1);
+
+        // Waiting for room, the send leaves the map free for the health task
+        let waiting = {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                let command: AgentCommand = serde_json::from_value(serde_json::json!({
+                    "id": "cmd-1",
+                    "command_type": "restart",
+                    "component_id": "postgres",
+                    "action_name": null,
+                    "params": {},
+                    "timeout_secs": 60,
+                }))
+                .unwrap();
+                registry.send_command("slow", command).await
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let refreshed = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            tokio::task::spawn_blocking({
+                let registry = registry.clone();
+                move || registry.refresh_health(&HealthSettings::default())
+            }),
+        )
+        .await;
+        assert!(refreshed.is_ok());
+        assert!(!waiting.is_finished());
+        waiting.abort();
+    }
+
+    #[tokio::test]
+    async fn test_query_and_details() {
+        use serde_json::json;
+
+        let registry = AgentRegistry::new();
+        let (tx, mut rx) = mpsc::channel(10);
+
+        let agent =
+            |id: &str, hostname: &str, labels: &[(&str, &str)], minutes_ago: i64| AgentInfo {
+                id: id.to_string(),
+                hostname: hostname.to_string(),
+                labels: labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                ip_addresses: Vec::new(),
+                version: "1.0".to_string(),
+                os: "linux".to_string(),
+                protocol_version: 1,
+                capabilities: Vec::new(),
+                outdated: false,
+                quarantined: false,
+                tenant_id: None,
+                connected_at: Utc::now() - chrono::Duration::minutes(minutes_ago),
+                last_heartbeat: Utc::now(),
+                health: Default::default(),
+                tx: None,
+            };
+        registry.register(
+            agent("c", "DB-02.prod", &[("role", "database")], 5),
+            tx.clone(),
+        );
+        registry.register(
+            agent(
+                "a",
+                "db-01.prod",
+                &[("role", "database"), ("zone", "dmz")],
+                60,
+            ),
+            tx.clone(),
+        );
+        registry.register(agent("b", "web-01.prod", &[("role", "web")], 1), tx.clone());
+
+        let ids = |query: serde_json::Value| -> (usize, Vec<String>) {
+            let query: AgentQuery = serde_json::from_value(query).unwrap();
+            let (total, agents) = registry.query(&query, "default").unwrap();
+            (total, agents.into_iter().map(|a| a.id).collect())
+        };
+        assert_eq!(
+            ids(json!({})),
+            (3, vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(
+            ids(json!({"labels": "role=database", "hostname": "db-0?.*"})).1,
+            ["a", "c"]
+        );
+        assert_eq!(ids(json!({"zone": "default"})).1, ["b", "c"]);
+        assert_eq!(ids(json!({"zone": "dmz"})).1, ["a"]);
+        let since = Utc::now() - chrono::Duration::minutes(10);
+        assert_eq!(ids(json!({"connected_since": since})).1, ["b", "c"]);
+        assert_eq!(ids(json!({"limit": 1, "offset": 1})), (3, vec!["b".into()]));
+
+        assert_eq!(
+            ids(json!({"labels": "role in (web,data*),zone!=dmz"})).1,
+            ["b", "c"]
+        );
+        let bad = AgentQuery {
+            labels: Some("role in (".to_string()),
+            ..Default::default()
+        };
+        assert!(registry.query(&bad, "default").is_err());
+        assert!(!glob("db-*", "web-db-1"));
+
+        let command = |id: &str| AgentCommand {
+            id: id.to_string(),
+            command_type: "action".to_string(),
+            component_id: "postgres".to_string(),
+            action_name: Some("restart".to_string()),
+            params: serde_json::Value::Null,
+            timeout_secs: 60,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: None,
+        };
+        registry.send_command("a", command("cmd-1")).await.unwrap();
+        registry.try_send_command("a", command("cmd-2")).unwrap();
+        registry.ack("a", 7);
+        registry.received("a");
+        registry.received_bytes("a", 120);
+        registry.received_deltas("a", 1, false);
+        registry.received("a");
+        registry.received_bytes("a", 800);
+        registry.received_deltas("a", 20, true);
+        registry.received_response("a");
+        while rx.try_recv().is_ok() {}
+
+        let details = registry.details("a").unwrap();
+        let traffic = &details.traffic;
+        assert_eq!((traffic.messages_sent, traffic.messages_received), (3, 2));
+        assert_eq!((traffic.deltas_received, traffic.batches_received), (21, 1));
+        assert_eq!(
+            (traffic.command_responses, traffic.bytes_received),
+            (1, 920)
+        );
+        assert!(traffic.last_message_at.is_some());
+        let json = serde_json::to_value(&details).unwrap();
+        assert_eq!(json["bytes_received"], 920);
+        assert!(details.heartbeat_age_secs < 5);
+        assert!(registry.details("unknown").is_none());
+
+        // Tenants see their own agents, and only their commands reach them
+        let tenant_agent = AgentInfo {
+            tenant_id: Some("acme".to_string()),
+            ..agent("d", "db-03.acme", &[("role", "database")], 1)
+        };
+        registry.register(tenant_agent, tx.clone());
+        assert_eq!(ids(json!({"tenant_id": "acme"})).1, ["d"]);
+        assert!(registry.send_command("d", command("cmd-3")).await.is_err());
+        let acme_command = AgentCommand {
+            tenant_id: Some("acme".to_string()),
+            ..command("cmd-4")
+        };
+        assert!(registry
+            .try_send_command("a", acme_command.clone())
+            .is_err());
+        registry.try_send_command("d", acme_command).unwrap();
     }
 }
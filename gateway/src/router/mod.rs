@@ -1,11 +1,30 @@
 //! Command router module
 //!
 //! Routes commands from backend to appropriate agents.
+//!
+//! A component whose agent selector matches several agents runs on one of
+//! them, picked by the selector's `strategy`:
+//!
+//! - `first` (the default): the lowest agent id;
+//! - `round_robin`: each in turn, per component;
+//! - `least_loaded`: the one with the fewest commands not answered yet;
+//! - `sticky`: the same agent for a component for as long as it matches,
+//!   by rendezvous hashing on the component id, so agents joining or leaving
+//!   move only the components they win or held.
+//!
+//! Quarantined agents are never picked. A backend command routed by labels
+//! with a `strategy` runs on the agent picked for its component, rather than
+//! on all of them.
 
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
 use tracing::{debug, info};
 
+use crate::commands::PendingCommands;
 use crate::registry::{AgentCommand, AgentInfo, AgentRegistry};
+use crate::selector::Selector;
 
 /// Route a command to agents
 pub async fn route_command(
@@ -47,40 +66,213 @@ pub struct RouteResult {
     pub error: Option<String>,
 }
 
-/// Find the best agent for a component
+/// Next round-robin position, by component id
+static ROUND_ROBIN: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Find the best agent for a component, among those of `tenant_id`
 pub fn find_agent_for_component(
     registry: &AgentRegistry,
+    pending: &PendingCommands,
+    component_id: &str,
     component_agent_selector: &AgentSelector,
+    tenant_id: Option<&str>,
 ) -> Option<AgentInfo> {
     // If specific agent ID is specified
     if let Some(ref agent_id) = component_agent_selector.agent_id {
-        return registry.get(agent_id);
+        return registry
+            .get(agent_id)
+            .filter(|agent| agent.of_tenant(tenant_id));
     }
 
     // Find by labels
     if let Some(ref labels) = component_agent_selector.labels {
-        let agents = registry.find_by_labels(labels);
-        // Return first matching agent (could implement load balancing here)
-        return agents.into_iter().next();
+        let mut agents: Vec<AgentInfo> = registry
+            .find_matching(labels)
+            .into_iter()
+            .filter(|agent| !agent.quarantined && agent.of_tenant(tenant_id))
+            .collect();
+        agents.sort_by(|a, b| a.id.cmp(&b.id));
+        return select(
+            pending,
+            component_id,
+            component_agent_selector.strategy,
+            agents,
+        );
     }
 
     None
 }
 
+/// Pick one of `agents`, sorted by id
+fn select(
+    pending: &PendingCommands,
+    component_id: &str,
+    strategy: Strategy,
+    agents: Vec<AgentInfo>,
+) -> Option<AgentInfo> {
+    if agents.is_empty() {
+        return None;
+    }
+
+    let index = match strategy {
+        Strategy::First => 0,
+        Strategy::RoundRobin => {
+            let mut positions = ROUND_ROBIN.lock().unwrap();
+            let next = positions.entry(component_id.to_string()).or_insert(0);
+            let index = *next % agents.len();
+            *next = index + 1;
+            index
+        }
+        Strategy::LeastLoaded => {
+            let in_flight = pending.count_by_agent();
+            (0..agents.len())
+                .min_by_key(|&i| in_flight.get(&agents[i].id).copied().unwrap_or(0))
+                .unwrap_or(0)
+        }
+        Strategy::Sticky => (0..agents.len())
+            .max_by_key(|&i| weight(component_id, &agents[i].id))
+            .unwrap_or(0),
+    };
+    debug!(component_id = %component_id, strategy = ?strategy, agent_id = %agents[index].id, "Agent selected");
+    agents.into_iter().nth(index)
+}
+
+/// Rendezvous weight of an agent for a component, the same on every gateway
+fn weight(component_id: &str, agent_id: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(component_id.as_bytes())
+        .chain_update([0])
+        .chain_update(agent_id.as_bytes())
+        .finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// How a component's agent is picked among those its labels match
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    First,
+    RoundRobin,
+    LeastLoaded,
+    Sticky,
+}
+
 /// Agent selector from component config
 #[derive(Debug, Clone)]
 pub struct AgentSelector {
     pub agent_id: Option<String>,
-    pub labels: Option<HashMap<String, String>>,
+    /// An expression, or an object of labels all equal
+    pub labels: Option<Selector>,
+    pub strategy: Strategy,
 }
 
 impl AgentSelector {
     pub fn from_json(value: &serde_json::Value) -> Self {
         Self {
-            agent_id: value.get("agent_id").and_then(|v| v.as_str().map(String::from)),
-            labels: value.get("labels").and_then(|v| {
-                serde_json::from_value(v.clone()).ok()
-            }),
+            agent_id: value
+                .get("agent_id")
+                .and_then(|v| v.as_str().map(String::from)),
+            labels: value
+                .get("labels")
+                .and_then(|v| serde_json::from_value(v.clone()).ok()),
+            strategy: value
+                .get("strategy")
+                .and_then(|v| serde_json::from_value(v.clone()).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandSettings;
+    use tokio::sync::mpsc;
+
+    fn agent(id: &str) -> AgentInfo {
+        AgentInfo {
+            id: id.to_string(),
+            hostname: id.to_string(),
+            labels: HashMap::from([("role".to_string(), "cache".to_string())]),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: chrono::Utc::now(),
+            last_heartbeat: chrono::Utc::now(),
+            health: Default::default(),
+            tx: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strategies() {
+        let registry = AgentRegistry::new();
+        let pending = PendingCommands::new(CommandSettings::default());
+        let (tx, _rx) = mpsc::channel(10);
+        for id in ["a", "b", "c"] {
+            registry.register(agent(id), tx.clone());
+        }
+        let pick = |component_id: &str, strategy: &str| {
+            let selector = AgentSelector::from_json(
+                &serde_json::json!({"labels": "role=cache", "strategy": strategy}),
+            );
+            find_agent_for_component(&registry, &pending, component_id, &selector, None)
+                .map(|a| a.id)
+        };
+
+        assert_eq!(pick("redis", "first").as_deref(), Some("a"));
+        // None of them is another tenant's
+        let selector = AgentSelector::from_json(&serde_json::json!({"labels": "role=cache"}));
+        assert!(
+            find_agent_for_component(&registry, &pending, "redis", &selector, Some("acme"))
+                .is_none()
+        );
+        let turns: Vec<String> = (0..4).filter_map(|_| pick("rr", "round_robin")).collect();
+        assert_eq!(turns, ["a", "b", "c", "a"]);
+
+        // Messages sent do not count, commands still running do
+        registry.ack("c", 1);
+        let command = |id: &str| AgentCommand {
+            id: id.to_string(),
+            command_type: "restart".to_string(),
+            component_id: "redis".to_string(),
+            action_name: None,
+            params: serde_json::Value::Null,
+            timeout_secs: 60,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: None,
+            correlation_id: None,
+        };
+        pending.track("a", &command("cmd-1"));
+        pending.track("b", &command("cmd-2"));
+        assert_eq!(pick("redis", "least_loaded").as_deref(), Some("c"));
+        pending.answered(
+            "a",
+            &serde_json::json!({"job_id": "cmd-1", "status": "completed"}),
+        );
+        assert_eq!(pick("redis", "least_loaded").as_deref(), Some("a"));
+
+        // Sticky picks stay put unless the agent leaves or is quarantined
+        let sticky: Vec<String> = (0..20)
+            .filter_map(|i| pick(&format!("component-{}", i), "sticky"))
+            .collect();
+        assert!(["a", "b", "c"]
+            .iter()
+            .all(|id| sticky.iter().any(|s| s == id)));
+        registry.set_quarantined("b", true);
+        for (i, before) in sticky.iter().enumerate() {
+            let after = pick(&format!("component-{}", i), "sticky").unwrap();
+            assert_eq!(before == "b", &after != before);
         }
     }
 }
@@ -0,0 +1,830 @@
+//! Scheduled commands
+//!
+//! The gateway runs commands on a cron schedule by itself, backend or not:
+//! nightly log rotation, weekly cache purges. A schedule fans its command
+//! out to the agents matching `labels` as they are connected when it fires,
+//! like a label-routed command from the backend (rollout, RBAC as the API
+//! token that set it, `command_summary`).
+//!
+//! ```json
+//! PUT /schedules/nightly-logrotate
+//! {"cron": "30 2 * * *", "labels": "role=web,env=prod",
+//!  "command_type": "action", "component_id": "nginx", "action_name": "rotate_logs",
+//!  "rollout": {"max_parallel": 5}}
+//! ```
+//!
+//! `cron` has five fields (minute, hour, day of month, month, day of week,
+//! 0 or 7 being Sunday), or six with seconds first, and is read in UTC.
+//! Schedules and their latest runs are kept in a SQLite file: a run is
+//! `running` until its fan-out settles, then `succeeded`, `failed`,
+//! `aborted` (a canary failed) or `no_agents`; runs cut short by a restart
+//! end up `interrupted`. Occurrences missed while the gateway was down are
+//! not caught up.
+//!
+//! ```yaml
+//! schedules:
+//!   enabled: true   # off by default
+//!   file_path: /var/lib/opsmap/gateway-schedules.db
+//!   history: 100    # runs kept per schedule
+//! ```
+
+use anyhow::{Context, Result};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth::Caller;
+use crate::backend_client::{self, CommandPayload};
+use crate::fanout::{CommandSummary, Rollout};
+use crate::registry::AgentCommand;
+use crate::selector::Selector;
+use crate::sqlite::Worker;
+use crate::GatewayState;
+
+/// Scheduler settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleSettings {
+    /// Off unless asked for: on, the gateway keeps a file and runs a loop
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_file")]
+    pub file_path: String,
+    /// Runs kept per schedule, oldest dropped first
+    #[serde(default = "default_history")]
+    pub history: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_file() -> String {
+    "/var/lib/opsmap/gateway-schedules.db".to_string()
+}
+
+fn default_history() -> usize {
+    100
+}
+
+impl Default for ScheduleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_path: default_file(),
+            history: default_history(),
+        }
+    }
+}
+
+/// A command run on a schedule, as put through the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Taken from the path
+    #[serde(default)]
+    pub name: String,
+    pub cron: String,
+    /// Agents that get the command; an empty selector is refused
+    pub labels: Selector,
+    pub command_type: String,
+    pub component_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action_name: Option<String>,
+    #[serde(default)]
+    pub params: serde_json::Value,
+    #[serde(default = "default_command_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub rollout: Rollout,
+    /// Members of a tenant only get commands naming it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    /// A paused schedule only runs through `POST /schedules/:name/run`
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// API token that set the schedule, which its commands are run as
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+}
+
+fn default_command_timeout() -> u64 {
+    300
+}
+
+impl Schedule {
+    /// The parsed `cron`, or what is wrong with the schedule
+    pub fn check(&self) -> Result<Cron, String> {
+        let name_ok = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !name_ok {
+            return Err(format!(
+                "schedule name '{}' must be letters, digits, '-', '_' or '.'",
+                self.name
+            ));
+        }
+        if self.labels.is_empty() {
+            return Err(format!(
+                "schedule '{}' has no labels to select agents",
+                self.name
+            ));
+        }
+        Cron::new(&self.cron)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|e| {
+                format!(
+                    "schedule '{}' has an invalid cron '{}': {}",
+                    self.name, self.cron, e
+                )
+            })
+    }
+
+    fn command(&self) -> AgentCommand {
+        AgentCommand {
+            id: uuid::Uuid::new_v4().to_string(),
+            command_type: self.command_type.clone(),
+            component_id: self.component_id.clone(),
+            action_name: self.action_name.clone(),
+            params: self.params.clone(),
+            timeout_secs: self.timeout_secs,
+            trace_context: None,
+            run_at: None,
+            run_after_secs: None,
+            tenant_id: self.tenant_id.clone(),
+            correlation_id: Some(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+/// What started a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Cron,
+    /// `POST /schedules/:name/run`
+    Manual,
+}
+
+impl Trigger {
+    fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Cron => "cron",
+            Trigger::Manual => "manual",
+        }
+    }
+
+    fn parse(trigger: &str) -> Self {
+        match trigger {
+            "manual" => Trigger::Manual,
+            _ => Trigger::Cron,
+        }
+    }
+}
+
+/// How a run went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    /// At least one agent did not succeed
+    Failed,
+    /// A canary failed, the other agents were skipped
+    Aborted,
+    NoAgents,
+    /// The gateway restarted before the run settled
+    Interrupted,
+}
+
+impl RunStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+            RunStatus::Aborted => "aborted",
+            RunStatus::NoAgents => "no_agents",
+            RunStatus::Interrupted => "interrupted",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "running" => RunStatus::Running,
+            "succeeded" => RunStatus::Succeeded,
+            "failed" => RunStatus::Failed,
+            "aborted" => RunStatus::Aborted,
+            "no_agents" => RunStatus::NoAgents,
+            _ => RunStatus::Interrupted,
+        }
+    }
+
+    fn of(summary: &CommandSummary) -> Self {
+        if summary.targeted == 0 {
+            RunStatus::NoAgents
+        } else if summary.aborted {
+            RunStatus::Aborted
+        } else if summary.failed > 0 {
+            RunStatus::Failed
+        } else {
+            RunStatus::Succeeded
+        }
+    }
+}
+
+/// One run of a schedule
+#[derive(Debug, Clone, Serialize)]
+pub struct Run {
+    /// The command id, as in the audit trail and `command_summary`
+    pub job_id: String,
+    pub schedule: String,
+    pub trigger: Trigger,
+    pub status: RunStatus,
+    pub targeted: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// A schedule with when it runs next and how it last went, for
+/// `GET /schedules`
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleInfo {
+    #[serde(flatten)]
+    pub schedule: Schedule,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<Run>,
+}
+
+/// Filters for `GET /schedules/:name/runs`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RunQuery {
+    pub limit: Option<usize>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS schedules (
+    name TEXT PRIMARY KEY,
+    definition TEXT NOT NULL,
+    requested_by TEXT
+);
+CREATE TABLE IF NOT EXISTS schedule_runs (
+    job_id TEXT PRIMARY KEY,
+    schedule TEXT NOT NULL,
+    trigger TEXT NOT NULL,
+    status TEXT NOT NULL,
+    targeted INTEGER NOT NULL DEFAULT 0,
+    succeeded INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    skipped INTEGER NOT NULL DEFAULT 0,
+    started_at TEXT NOT NULL,
+    finished_at TEXT
+);
+CREATE INDEX IF NOT EXISTS schedule_runs_by_schedule ON schedule_runs (schedule, started_at);
+";
+
+/// The schedules, with their parsed cron; a disabled store has none and
+/// refuses new ones. The file is on a thread of its own: runs are recorded
+/// without waiting, from the response path too.
+pub struct Schedules {
+    worker: Option<Worker>,
+    schedules: RwLock<BTreeMap<String, (Schedule, Cron)>>,
+    history: usize,
+}
+
+impl Schedules {
+    pub fn open(settings: &ScheduleSettings) -> Result<Self> {
+        let path = std::path::Path::new(&settings.file_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open schedule file {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to initialize schedule file {}", path.display()))?;
+
+        let interrupted = conn.execute(
+            "UPDATE schedule_runs SET status = ?1 WHERE status = ?2",
+            params![RunStatus::Interrupted.as_str(), RunStatus::Running.as_str()],
+        )?;
+        if interrupted > 0 {
+            warn!(
+                runs = interrupted,
+                "Scheduled runs interrupted by the restart"
+            );
+        }
+
+        let mut schedules = BTreeMap::new();
+        {
+            let mut statement =
+                conn.prepare("SELECT name, definition, requested_by FROM schedules")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?;
+            for row in rows {
+                let (name, definition, requested_by) = row?;
+                let parsed = serde_json::from_str::<Schedule>(&definition)
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut schedule| {
+                        schedule.requested_by = requested_by;
+                        let cron = schedule.check()?;
+                        Ok((schedule, cron))
+                    });
+                match parsed {
+                    Ok(entry) => {
+                        schedules.insert(name, entry);
+                    }
+                    Err(e) => error!(schedule = %name, error = %e, "Skipping unreadable schedule"),
+                }
+            }
+        }
+        info!(schedules = schedules.len(), "Schedules loaded");
+
+        Ok(Self {
+            worker: Some(Worker::start("schedules", conn)?),
+            schedules: RwLock::new(schedules),
+            history: settings.history,
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            worker: None,
+            schedules: RwLock::new(BTreeMap::new()),
+            history: 0,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Schedule> {
+        self.schedules
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|(schedule, _)| schedule.clone())
+    }
+
+    /// Add or replace a schedule; an error is the schedule's fault unless
+    /// the store is disabled or failed
+    pub async fn put(&self, schedule: Schedule) -> Result<(), String> {
+        let Some(ref worker) = self.worker else {
+            return Err("scheduled commands are disabled".to_string());
+        };
+        let cron = schedule.check()?;
+        let definition = serde_json::to_string(&schedule).map_err(|e| e.to_string())?;
+        let (name, requested_by) = (schedule.name.clone(), schedule.requested_by.clone());
+        worker
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO schedules (name, definition, requested_by) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (name) DO UPDATE SET definition = ?2, requested_by = ?3",
+                    params![name, definition, requested_by],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        self.schedules
+            .write()
+            .unwrap()
+            .insert(schedule.name.clone(), (schedule, cron));
+        Ok(())
+    }
+
+    /// Remove a schedule and its runs; false if there was none
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let Some(ref worker) = self.worker else {
+            return Ok(false);
+        };
+        let removed = name.to_string();
+        worker
+            .run(move |conn| {
+                conn.execute(
+                    "DELETE FROM schedule_runs WHERE schedule = ?1",
+                    params![removed],
+                )?;
+                conn.execute("DELETE FROM schedules WHERE name = ?1", params![removed])?;
+                Ok(())
+            })
+            .await?;
+        Ok(self.schedules.write().unwrap().remove(name).is_some())
+    }
+
+    /// Every schedule, sorted by name
+    pub async fn info(&self, now: DateTime<Utc>) -> Result<Vec<ScheduleInfo>> {
+        let schedules: Vec<(Schedule, Cron)> =
+            self.schedules.read().unwrap().values().cloned().collect();
+        // Each schedule's last run, in one trip to the file's thread
+        let names: Vec<String> = schedules.iter().map(|(s, _)| s.name.clone()).collect();
+        let last_runs = match self.worker {
+            Some(ref worker) => {
+                worker
+                    .run(move |conn| {
+                        names
+                            .iter()
+                            .map(|name| Ok(query_runs(conn, name, Some(1))?.pop()))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .await?
+            }
+            None => vec![None; schedules.len()],
+        };
+        Ok(schedules
+            .into_iter()
+            .zip(last_runs)
+            .map(|((schedule, cron), last_run)| {
+                let next_run = match schedule.enabled {
+                    true => cron.find_next_occurrence(&now, false).ok(),
+                    false => None,
+                };
+                ScheduleInfo {
+                    schedule,
+                    next_run,
+                    last_run,
+                }
+            })
+            .collect())
+    }
+
+    /// Enabled schedules with an occurrence after `since`, up to `now`
+    fn due(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<Schedule> {
+        self.schedules
+            .read()
+            .unwrap()
+            .values()
+            .filter(|(schedule, cron)| {
+                schedule.enabled
+                    && cron
+                        .find_next_occurrence(&since, false)
+                        .is_ok_and(|next| next <= now)
+            })
+            .map(|(schedule, _)| schedule.clone())
+            .collect()
+    }
+
+    /// Runs of a schedule, newest first
+    pub async fn runs(&self, name: &str, limit: Option<usize>) -> Result<Vec<Run>> {
+        let Some(ref worker) = self.worker else {
+            return Ok(Vec::new());
+        };
+        let name = name.to_string();
+        worker.run(move |conn| query_runs(conn, &name, limit)).await
+    }
+
+    /// Record a run starting, dropping the oldest past `history`
+    fn started(&self, name: &str, job_id: &str, trigger: Trigger) {
+        let Some(ref worker) = self.worker else {
+            return;
+        };
+        let (name, job_id) = (name.to_string(), job_id.to_string());
+        let (history, started_at) = (self.history as i64, Utc::now());
+        worker.spawn(move |conn| {
+            let result = conn
+                .execute(
+                    "INSERT INTO schedule_runs (job_id, schedule, trigger, status, started_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        job_id,
+                        name,
+                        trigger.as_str(),
+                        RunStatus::Running.as_str(),
+                        started_at
+                    ],
+                )
+                .and_then(|_| {
+                    conn.execute(
+                        "DELETE FROM schedule_runs WHERE job_id IN
+                         (SELECT job_id FROM schedule_runs WHERE schedule = ?1
+                          ORDER BY started_at DESC LIMIT -1 OFFSET ?2)",
+                        params![name, history],
+                    )
+                });
+            if let Err(e) = result {
+                error!(schedule = %name, error = %e, "Failed to record scheduled run");
+            }
+        });
+    }
+
+    /// Record how a fan-out went, if it was a scheduled run
+    pub fn finished(&self, summary: &CommandSummary) {
+        let Some(ref worker) = self.worker else {
+            return;
+        };
+        let summary = summary.clone();
+        worker.spawn(move |conn| {
+            let result = conn.execute(
+                "UPDATE schedule_runs
+                 SET status = ?2, targeted = ?3, succeeded = ?4, failed = ?5, skipped = ?6,
+                     finished_at = ?7
+                 WHERE job_id = ?1",
+                params![
+                    summary.job_id,
+                    RunStatus::of(&summary).as_str(),
+                    summary.targeted as i64,
+                    summary.succeeded as i64,
+                    summary.failed as i64,
+                    summary.skipped as i64,
+                    summary.finished_at
+                ],
+            );
+            if let Err(e) = result {
+                error!(command_id = %summary.job_id, error = %e, "Failed to record scheduled run");
+            }
+        });
+    }
+}
+
+/// Runs of a schedule, newest first
+fn query_runs(conn: &Connection, name: &str, limit: Option<usize>) -> Result<Vec<Run>> {
+    let mut statement = conn.prepare(
+        "SELECT job_id, schedule, trigger, status, targeted, succeeded, failed, skipped,
+                started_at, finished_at
+         FROM schedule_runs WHERE schedule = ?1
+         ORDER BY started_at DESC LIMIT ?2",
+    )?;
+    let limit = limit.map_or(-1, |l| l as i64);
+    let rows = statement.query_map(params![name, limit], from_row)?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Run> {
+    let trigger: String = row.get(2)?;
+    let status: String = row.get(3)?;
+    Ok(Run {
+        job_id: row.get(0)?,
+        schedule: row.get(1)?,
+        trigger: Trigger::parse(&trigger),
+        status: RunStatus::parse(&status),
+        targeted: row.get::<_, i64>(4)? as usize,
+        succeeded: row.get::<_, i64>(5)? as usize,
+        failed: row.get::<_, i64>(6)? as usize,
+        skipped: row.get::<_, i64>(7)? as usize,
+        started_at: row.get(8)?,
+        finished_at: row.get(9)?,
+    })
+}
+
+/// Fire the schedules as they come due
+pub async fn run(state: Arc<GatewayState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut since = Utc::now();
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        for schedule in state.schedules.due(since, now) {
+            fire(&state, &schedule, Trigger::Cron).await;
+        }
+        since = now;
+    }
+}
+
+/// Fan a schedule's command out, answering its job id
+async fn fire(state: &GatewayState, schedule: &Schedule, trigger: Trigger) -> String {
+    let command = schedule.command();
+    let job_id = command.id.clone();
+    info!(
+        schedule = %schedule.name,
+        command_id = %job_id,
+        trigger = trigger.as_str(),
+        "Running scheduled command"
+    );
+    // Recorded first: a fan-out without agents is over before route_command returns
+    state.schedules.started(&schedule.name, &job_id, trigger);
+
+    let payload = CommandPayload {
+        agent_id: None,
+        labels: Some(schedule.labels.clone()),
+        command,
+        requested_by: schedule.requested_by.clone(),
+        roles: Vec::new(),
+        rollout: schedule.rollout.clone(),
+        strategy: None,
+        zone: None,
+        group: None,
+    };
+    backend_client::route_command(payload, state).await;
+    job_id
+}
+
+/// `GET /schedules`
+pub async fn list(
+    State(state): State<Arc<GatewayState>>,
+) -> Result<axum::Json<Vec<ScheduleInfo>>, (StatusCode, String)> {
+    state
+        .schedules
+        .info(Utc::now())
+        .await
+        .map(axum::Json)
+        .map_err(internal)
+}
+
+/// `GET /schedules/:name`
+pub async fn get(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+) -> Result<axum::Json<ScheduleInfo>, (StatusCode, String)> {
+    state
+        .schedules
+        .info(Utc::now())
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .find(|info| info.schedule.name == name)
+        .map(axum::Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("No schedule {}", name)))
+}
+
+/// `GET /schedules/:name/runs`
+pub async fn runs(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+    Query(query): Query<RunQuery>,
+) -> Result<axum::Json<Vec<Run>>, (StatusCode, String)> {
+    if state.schedules.get(&name).is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("No schedule {}", name)));
+    }
+    state
+        .schedules
+        .runs(&name, query.limit)
+        .await
+        .map(axum::Json)
+        .map_err(internal)
+}
+
+/// `PUT /schedules/:name`; the commands run as the caller's API token
+pub async fn put(
+    caller: Option<Extension<Caller>>,
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+    axum::Json(mut schedule): axum::Json<Schedule>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    schedule.name = name;
+    schedule.requested_by = caller.map(|Extension(Caller(name))| name);
+    schedule.check().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let name = schedule.name.clone();
+    let cron = schedule.cron.clone();
+    state
+        .schedules
+        .put(schedule)
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    info!(schedule = %name, cron = %cron, "Schedule set");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /schedules/:name`, with its runs
+pub async fn remove(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.schedules.remove(&name).await.map_err(internal)? {
+        true => {
+            info!(schedule = %name, "Schedule removed");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        false => Err((StatusCode::NOT_FOUND, format!("No schedule {}", name))),
+    }
+}
+
+/// `POST /schedules/:name/run`: run it now, paused or not; answers 202 with
+/// the job id
+pub async fn run_now(
+    State(state): State<Arc<GatewayState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, axum::Json<serde_json::Value>), (StatusCode, String)> {
+    let schedule = state
+        .schedules
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, format!("No schedule {}", name)))?;
+    let job_id = fire(&state, &schedule, Trigger::Manual).await;
+    Ok((
+        StatusCode::ACCEPTED,
+        axum::Json(serde_json::json!({ "job_id": job_id, "schedule": name })),
+    ))
+}
+
+fn internal(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(name: &str, cron: &str) -> Schedule {
+        let mut schedule: Schedule = serde_json::from_value(serde_json::json!({
+            "cron": cron,
+            "labels": "role=web",
+            "command_type": "action",
+            "component_id": "nginx",
+            "action_name": "rotate_logs",
+        }))
+        .unwrap();
+        schedule.name = name.to_string();
+        schedule
+    }
+
+    #[tokio::test]
+    async fn test_check_and_due() {
+        assert!(schedule("bad name", "0 3 * * *").check().is_err());
+        assert!(schedule("nightly", "0 25 * * *").check().is_err());
+        let mut everything = schedule("all", "* * * * *");
+        everything.labels = Selector::default();
+        assert!(everything.check().is_err());
+
+        let store = Schedules::disabled();
+        assert!(store.put(schedule("nightly", "0 3 * * *")).await.is_err());
+
+        let nightly = schedule("nightly", "30 2 * * *");
+        let cron = nightly.check().unwrap();
+        store
+            .schedules
+            .write()
+            .unwrap()
+            .insert(nightly.name.clone(), (nightly, cron));
+        let at = |h, m, s| Utc.with_ymd_and_hms(2026, 3, 14, h, m, s).unwrap();
+        assert_eq!(store.due(at(2, 29, 59), at(2, 30, 0)).len(), 1);
+        assert!(store.due(at(2, 30, 0), at(2, 30, 1)).is_empty());
+        assert!(store.due(at(2, 28, 0), at(2, 29, 0)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_persisted_runs() {
+        let path =
+            std::env::temp_dir().join(format!("opsmap-schedules-{}.db", uuid::Uuid::new_v4()));
+        let settings = ScheduleSettings {
+            enabled: true,
+            file_path: path.to_string_lossy().into_owned(),
+            history: 2,
+        };
+        let store = Schedules::open(&settings).unwrap();
+        let mut nightly = schedule("nightly", "0 30 2 * * *");
+        nightly.requested_by = Some("ops".to_string());
+        store.put(nightly).await.unwrap();
+
+        for job_id in ["job-1", "job-2", "job-3"] {
+            store.started("nightly", job_id, Trigger::Cron);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let summary = |job_id: &str, targeted, failed| CommandSummary {
+            job_id: job_id.to_string(),
+            targeted,
+            succeeded: targeted - failed,
+            failed,
+            skipped: 0,
+            aborted: false,
+            results: Vec::new(),
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            correlation_id: None,
+        };
+        store.finished(&summary("job-3", 2, 1));
+        store.finished(&summary("unscheduled", 1, 0));
+
+        // Only the newest `history` runs are kept
+        let runs = store.runs("nightly", None).await.unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].job_id, "job-3");
+        assert_eq!(runs[0].status, RunStatus::Failed);
+        assert_eq!(runs[1].status, RunStatus::Running);
+        drop(store);
+
+        // Reopened: the schedule is back, the unsettled run was interrupted
+        let store = Schedules::open(&settings).unwrap();
+        assert_eq!(
+            store.get("nightly").unwrap().requested_by.as_deref(),
+            Some("ops")
+        );
+        let info = store.info(Utc::now()).await.unwrap();
+        assert_eq!(info[0].last_run.as_ref().unwrap().job_id, "job-3");
+        assert!(info[0].next_run.is_some());
+        assert_eq!(
+            store.runs("nightly", None).await.unwrap()[1].status,
+            RunStatus::Interrupted
+        );
+
+        assert!(store.remove("nightly").await.unwrap());
+        assert!(store.runs("nightly", None).await.unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! Message validation
+//!
+//! Messages from agents and from the backend are checked against their
+//! size limit before they are parsed, then parsed into the protocol types.
+//! One that fails is not silently dropped: the sender gets a
+//! `protocol_error` message saying what was wrong, and
+//! `opsmap_gateway_protocol_errors_total{peer, kind}` counts it.
+//!
+//! Kinds of errors:
+//!
+//! - `oversized`: over `limits.max_message_bytes` (agents) or
+//!   `limits.max_backend_message_bytes` (backend), once inflated;
+//! - `invalid_encoding`: a binary frame that does not inflate or decode;
+//! - `invalid_json`: not JSON at all;
+//! - `missing_type`: not an object with a `type`;
+//! - `unknown_type`: a `type` this gateway does not know;
+//! - `unknown_field`: a field the message may not have;
+//! - `invalid_payload`: a known message whose fields are missing or wrong.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Top-level fields of a message
+const ENVELOPE_FIELDS: &[&str] = &["type", "payload", "seq"];
+
+/// What was wrong with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Oversized,
+    InvalidEncoding,
+    InvalidJson,
+    MissingType,
+    UnknownType,
+    UnknownField,
+    InvalidPayload,
+}
+
+impl ErrorKind {
+    /// Metrics label
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Oversized => "oversized",
+            ErrorKind::InvalidEncoding => "invalid_encoding",
+            ErrorKind::InvalidJson => "invalid_json",
+            ErrorKind::MissingType => "missing_type",
+            ErrorKind::UnknownType => "unknown_type",
+            ErrorKind::UnknownField => "unknown_field",
+            ErrorKind::InvalidPayload => "invalid_payload",
+        }
+    }
+}
+
+/// A message refused, as told to its sender
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub kind: ErrorKind,
+    /// What to fix, in words
+    pub message: String,
+    /// The `type` of the message, when it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    /// The field at fault, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Size of an oversized message, and the limit it went over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl ProtocolError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            message_type: None,
+            field: None,
+            size: None,
+            limit: None,
+        }
+    }
+
+    /// A binary frame that did not inflate or decode
+    pub fn encoding(error: impl std::fmt::Display) -> Self {
+        Self::new(ErrorKind::InvalidEncoding, error.to_string())
+    }
+}
+
+/// Refuse a message of `size` bytes over `limit`, before parsing it
+pub fn check_size(size: usize, limit: usize) -> Result<(), ProtocolError> {
+    if size <= limit {
+        return Ok(());
+    }
+    Err(ProtocolError {
+        size: Some(size),
+        limit: Some(limit),
+        ..ProtocolError::new(
+            ErrorKind::Oversized,
+            format!(
+                "message of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            ),
+        )
+    })
+}
+
+/// Parse a JSON message whose `type` is one of `known`
+pub fn parse<T: DeserializeOwned>(data: &[u8], known: &[&str]) -> Result<T, ProtocolError> {
+    let value: serde_json::Value = serde_json::from_slice(data)
+        .map_err(|e| ProtocolError::new(ErrorKind::InvalidJson, e.to_string()))?;
+    from_value(&value, known)
+}
+
+/// Same as `parse`, for a message already decoded
+pub fn from_value<T: DeserializeOwned>(
+    value: &serde_json::Value,
+    known: &[&str],
+) -> Result<T, ProtocolError> {
+    let message_type = envelope(value, known)?;
+
+    T::deserialize(value).map_err(|error| {
+        // serde names the field in its message: "missing field `x`"
+        let text = error.to_string();
+        let field = text
+            .split('`')
+            .nth(1)
+            .filter(|_| text.contains("field `"))
+            .map(str::to_string);
+        let kind = if text.starts_with("unknown field") {
+            ErrorKind::UnknownField
+        } else {
+            ErrorKind::InvalidPayload
+        };
+        ProtocolError {
+            message_type: Some(message_type.to_string()),
+            field,
+            ..ProtocolError::new(kind, text)
+        }
+    })
+}
+
+/// Check the fields around the payload, returning the message's type
+fn envelope<'a>(value: &'a serde_json::Value, known: &[&str]) -> Result<&'a str, ProtocolError> {
+    let object = value.as_object().ok_or_else(|| {
+        ProtocolError::new(ErrorKind::MissingType, "message is not a JSON object")
+    })?;
+    let message_type = object.get("type").and_then(|t| t.as_str()).ok_or_else(|| {
+        ProtocolError::new(ErrorKind::MissingType, "message has no string `type`")
+    })?;
+    if !known.contains(&message_type) {
+        return Err(ProtocolError {
+            message_type: Some(message_type.to_string()),
+            ..ProtocolError::new(
+                ErrorKind::UnknownType,
+                format!(
+                    "unknown message type; expected one of: {}",
+                    known.join(", ")
+                ),
+            )
+        });
+    }
+    if let Some(field) = object
+        .keys()
+        .find(|k| !ENVELOPE_FIELDS.contains(&k.as_str()))
+    {
+        return Err(ProtocolError {
+            message_type: Some(message_type.to_string()),
+            field: Some(field.clone()),
+            ..ProtocolError::new(
+                ErrorKind::UnknownField,
+                format!("unknown field `{}`; fields go in `payload`", field),
+            )
+        });
+    }
+    Ok(message_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", content = "payload")]
+    enum Message {
+        #[serde(rename = "status_delta")]
+        StatusDelta { status: String },
+        #[serde(rename = "pong")]
+        Pong,
+    }
+
+    const KNOWN: &[&str] = &["status_delta", "pong"];
+
+    fn error(text: &str) -> ProtocolError {
+        parse::<Message>(text.as_bytes(), KNOWN).unwrap_err()
+    }
+
+    #[test]
+    fn test_classify() {
+        assert!(matches!(
+            parse::<Message>(br#"{"type":"pong"}"#, KNOWN),
+            Ok(Message::Pong)
+        ));
+        let delta = parse::<Message>(
+            br#"{"type":"status_delta","payload":{"status":"ok"}}"#,
+            KNOWN,
+        );
+        assert!(matches!(delta, Ok(Message::StatusDelta { status }) if status == "ok"));
+
+        assert_eq!(error(r#"{"type":"pong""#).kind, ErrorKind::InvalidJson);
+        assert_eq!(error(r#"[1, 2]"#).kind, ErrorKind::MissingType);
+        assert_eq!(error(r#"{"payload":{}}"#).kind, ErrorKind::MissingType);
+
+        let unknown = error(r#"{"type":"status_deltas","payload":{}}"#);
+        assert_eq!(unknown.kind, ErrorKind::UnknownType);
+        assert_eq!(unknown.message_type.as_deref(), Some("status_deltas"));
+
+        let misplaced = error(r#"{"type":"status_delta","status":"ok"}"#);
+        assert_eq!(misplaced.kind, ErrorKind::UnknownField);
+        assert_eq!(misplaced.field.as_deref(), Some("status"));
+
+        let missing = error(r#"{"type":"status_delta","payload":{"state":"ok"}}"#);
+        assert_eq!(missing.kind, ErrorKind::InvalidPayload);
+        assert_eq!(missing.field.as_deref(), Some("status"));
+        assert_eq!(missing.message_type.as_deref(), Some("status_delta"));
+
+        let value = serde_json::json!({"type": "status_delta", "payload": {"status": 3}});
+        let wrong = from_value::<Message>(&value, KNOWN).unwrap_err();
+        assert_eq!(wrong.kind, ErrorKind::InvalidPayload);
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert!(check_size(10, 10).is_ok());
+        let oversized = check_size(11, 10).unwrap_err();
+        assert_eq!(oversized.kind, ErrorKind::Oversized);
+        assert_eq!((oversized.size, oversized.limit), (Some(11), Some(10)));
+        assert_eq!(
+            serde_json::to_value(&oversized).unwrap()["kind"],
+            "oversized"
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Label selectors
+//!
+//! Agents are picked by their labels with comma-separated requirements, all
+//! of which must hold:
+//!
+//! ```text
+//! role=database             # equal (also ==)
+//! env!=prod                 # not equal, or no such label
+//! tier in (web,api)         # one of
+//! tier notin (batch)        # none of, or no such label
+//! canary                    # has the label
+//! !maintenance              # does not have it
+//! host=db-*                 # values may be globs: `*` any run, `?` one character
+//! ```
+//!
+//! Commands from the backend (`labels`), component agent selectors and
+//! `GET /agents?labels=` take an expression; a JSON object of labels still
+//! means all of them equal, as before expressions existed.
+//!
+//! Label values with a literal `*` or `?` cannot be told from globs, which
+//! match them anyway.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+/// One condition on an agent's labels
+#[derive(Debug, Clone, PartialEq)]
+pub enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+/// Requirements all to match; an empty selector matches every agent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    /// Parse a selector expression
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let requirements = split(expression)
+            .into_iter()
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(requirement)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { requirements })
+    }
+
+    /// All of `labels` equal
+    pub fn exact(labels: &HashMap<String, String>) -> Self {
+        let mut requirements: Vec<Requirement> = labels
+            .iter()
+            .map(|(k, v)| Requirement::Equals(k.clone(), v.clone()))
+            .collect();
+        // Stable, for logs and equality
+        requirements.sort_by_key(|r| r.to_string());
+        Self { requirements }
+    }
+
+    /// Whether labels satisfy every requirement
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+}
+
+impl Requirement {
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        let any = |key: &str, patterns: &[String]| {
+            labels
+                .get(key)
+                .is_some_and(|value| patterns.iter().any(|p| glob(p, value)))
+        };
+        match self {
+            Requirement::Equals(key, pattern) => any(key, std::slice::from_ref(pattern)),
+            Requirement::NotEquals(key, pattern) => !any(key, std::slice::from_ref(pattern)),
+            Requirement::In(key, patterns) => any(key, patterns),
+            Requirement::NotIn(key, patterns) => !any(key, patterns),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// Split on the commas outside parentheses
+fn split(expression: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (i, c) in expression.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(&expression[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&expression[start..]);
+    parts
+}
+
+fn requirement(text: &str) -> Result<Requirement, String> {
+    let invalid = |why: &str| format!("Invalid label selector {:?}: {}", text, why);
+
+    if let Some(open) = text.find('(') {
+        let list = text[open + 1..]
+            .strip_suffix(')')
+            .ok_or_else(|| invalid("missing )"))?;
+        let (key, operator) = text[..open]
+            .trim()
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| invalid("expected `key in (...)` or `key notin (...)`"))?;
+        let values: Vec<String> = list
+            .split(',')
+            .map(|v| value(v.trim()).map_err(|e| invalid(&e)))
+            .collect::<Result<_, _>>()?;
+        let key = label_key(key.trim()).map_err(|e| invalid(&e))?;
+        return match operator {
+            "in" => Ok(Requirement::In(key, values)),
+            "notin" => Ok(Requirement::NotIn(key, values)),
+            other => Err(invalid(&format!("unknown operator {:?}", other))),
+        };
+    }
+    if text.contains(')') {
+        return Err(invalid("missing ("));
+    }
+
+    let (key, value_text, equals) = if let Some((k, v)) = text.split_once("!=") {
+        (k, v, false)
+    } else if let Some((k, v)) = text.split_once("==").or_else(|| text.split_once('=')) {
+        (k, v, true)
+    } else if let Some(key) = text.strip_prefix('!') {
+        return Ok(Requirement::NotExists(
+            label_key(key.trim()).map_err(|e| invalid(&e))?,
+        ));
+    } else {
+        return Ok(Requirement::Exists(
+            label_key(text).map_err(|e| invalid(&e))?,
+        ));
+    };
+
+    let key = label_key(key.trim()).map_err(|e| invalid(&e))?;
+    let value = value(value_text.trim()).map_err(|e| invalid(&e))?;
+    Ok(if equals {
+        Requirement::Equals(key, value)
+    } else {
+        Requirement::NotEquals(key, value)
+    })
+}
+
+fn label_key(key: &str) -> Result<String, String> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+    if valid {
+        Ok(key.to_string())
+    } else {
+        Err(format!("bad label key {:?}", key))
+    }
+}
+
+fn value(value: &str) -> Result<String, String> {
+    if value.contains(|c: char| c.is_whitespace() || matches!(c, '=' | '!' | '(' | ')')) {
+        return Err(format!("bad label value {:?}", value));
+    }
+    Ok(value.to_string())
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Requirement::Equals(key, value) => write!(f, "{}={}", key, value),
+            Requirement::NotEquals(key, value) => write!(f, "{}!={}", key, value),
+            Requirement::In(key, values) => write!(f, "{} in ({})", key, values.join(",")),
+            Requirement::NotIn(key, values) => write!(f, "{} notin ({})", key, values.join(",")),
+            Requirement::Exists(key) => write!(f, "{}", key),
+            Requirement::NotExists(key) => write!(f, "!{}", key),
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, requirement) in self.requirements.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", requirement)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Selector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Expression(String),
+            Labels(HashMap<String, String>),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Expression(expression) => {
+                Selector::parse(&expression).map_err(serde::de::Error::custom)
+            }
+            Raw::Labels(labels) => Ok(Selector::exact(&labels)),
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` is any run of characters
+/// and `?` any one
+pub fn glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the name position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the `*` take one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let selector = Selector::parse(
+            "role=db, env != prod,tier in (web, api-*),zone notin (dmz),canary,!maint",
+        )
+        .unwrap();
+        assert_eq!(
+            selector.requirements,
+            [
+                Requirement::Equals("role".into(), "db".into()),
+                Requirement::NotEquals("env".into(), "prod".into()),
+                Requirement::In("tier".into(), vec!["web".into(), "api-*".into()]),
+                Requirement::NotIn("zone".into(), vec!["dmz".into()]),
+                Requirement::Exists("canary".into()),
+                Requirement::NotExists("maint".into()),
+            ]
+        );
+        assert_eq!(
+            selector.to_string(),
+            "role=db,env!=prod,tier in (web,api-*),zone notin (dmz),canary,!maint"
+        );
+        assert_eq!(
+            Selector::parse("role==db").unwrap(),
+            Selector::parse("role=db").unwrap()
+        );
+        assert!(Selector::parse("").unwrap().is_empty());
+
+        for bad in [
+            "tier in (web",
+            "tier in web)",
+            "tier within (web)",
+            "in (web)",
+            "=db",
+            "role=a b",
+            "ro le",
+        ] {
+            assert!(Selector::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let agent = labels(&[("role", "db"), ("env", "staging"), ("host", "db-01")]);
+        let matches = |expression: &str| Selector::parse(expression).unwrap().matches(&agent);
+
+        assert!(matches("role=db,host=db-*"));
+        assert!(matches("env!=prod,tier!=web"));
+        assert!(matches("env in (prod,stag*)"));
+        assert!(matches("tier notin (web),!tier"));
+        assert!(!matches("role=db,env=prod"));
+        assert!(!matches("host=db-0?x"));
+        assert!(!matches("env notin (staging)"));
+        assert!(!matches("canary"));
+
+        // A JSON object is labels all equal, a string an expression
+        let exact: Selector =
+            serde_json::from_value(serde_json::json!({"role": "db", "env": "prod"})).unwrap();
+        assert_eq!(exact.to_string(), "env=prod,role=db");
+        assert!(!exact.matches(&agent));
+        let parsed: Selector = serde_json::from_value(serde_json::json!("role=db*")).unwrap();
+        assert!(parsed.matches(&agent));
+        assert!(serde_json::from_value::<Selector>(serde_json::json!("role in (")).is_err());
+    }
+}
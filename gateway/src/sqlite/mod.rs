@@ -0,0 +1,58 @@
+//! SQLite files owned by a thread of their own
+//!
+//! rusqlite blocks; called from a task, a slow disk would hold one of the
+//! runtime's threads, and everything scheduled on it. A `Worker` owns a
+//! connection on a dedicated thread and runs what it is handed in order:
+//! `spawn` does not wait, `run` awaits the answer without blocking.
+
+use anyhow::{anyhow, Context, Result};
+use rusqlite::Connection;
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::error;
+
+/// Work for the thread owning the file
+type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+/// The thread owning a connection; it ends once the worker is dropped and
+/// what was handed to it is done
+pub struct Worker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Worker {
+    /// Hand `conn` to a new thread called `name`
+    pub fn start(name: &str, conn: Connection) -> Result<Self> {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                for job in queued {
+                    job(&conn);
+                }
+            })
+            .with_context(|| format!("Failed to start the {} thread", name))?;
+        Ok(Self { jobs })
+    }
+
+    /// Run `job` on the thread without waiting for it
+    pub fn spawn(&self, job: impl FnOnce(&Connection) + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("SQLite thread stopped");
+        }
+    }
+
+    /// Run `query` on the thread, once what was handed before is done
+    pub async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (reply, answer) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |conn: &Connection| {
+                let _ = reply.send(query(conn));
+            }))
+            .map_err(|_| anyhow!("SQLite thread stopped"))?;
+        answer.await.map_err(|_| anyhow!("SQLite thread stopped"))?
+    }
+}
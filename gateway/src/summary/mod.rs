@@ -0,0 +1,360 @@
+//! Zone summary
+//!
+//! `GET /summary` aggregates the registry and status cache into a few
+//! numbers per group of agents (connected, stale, components in error or
+//! warning, commands in flight), so the backend and dashboards can poll one
+//! cheap endpoint instead of `/agents` and every agent's checks.
+//!
+//! Agents are grouped by zone (their `zone` label, else the gateway's) or,
+//! with `?by=<label>`, by the value of that label. `?labels=<selector>`
+//! limits the summary to the matching agents, and an agent is stale when
+//! it was last heard from more than `?stale_secs=` (default 120) ago.
+//! Agents known from before a restart that have not reconnected yet are
+//! only counted as `agents_unconfirmed`. Named agent groups (see `groups`)
+//! are counted too, in `agent_groups`; an agent may be in several.
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::groups::GroupDefinition;
+use crate::registry::{AgentInfo, CheckStatus};
+use crate::selector::Selector;
+use crate::GatewayState;
+
+/// Agents silent for longer are counted as stale by default
+const DEFAULT_STALE_SECS: i64 = 120;
+
+/// What `GET /summary` takes
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SummaryQuery {
+    /// Label to group agents by, instead of their zone
+    pub by: Option<String>,
+    /// Label selector expression (see `selector`)
+    pub labels: Option<String>,
+    pub stale_secs: Option<i64>,
+}
+
+/// Numbers for a group of agents
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Aggregate {
+    pub agents: usize,
+    /// Known before a gateway restart, not reconnected since
+    pub agents_unconfirmed: usize,
+    pub agents_stale: usize,
+    pub agents_quarantined: usize,
+    /// Components with at least one check
+    pub components: usize,
+    /// Components with a check in error
+    pub components_error: usize,
+    /// Components with a check in warning and none in error
+    pub components_warning: usize,
+    pub commands_in_flight: usize,
+}
+
+/// An `Aggregate` for the agents sharing a zone or label value
+#[derive(Debug, Clone, Serialize)]
+pub struct Group {
+    /// The zone or label value; null for agents without the label
+    pub key: Option<String>,
+    #[serde(flatten)]
+    pub aggregate: Aggregate,
+}
+
+/// The zone at a glance
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub gateway_id: String,
+    pub zone: String,
+    pub backend_connected: bool,
+    /// "zone", or the label grouped by
+    pub by: String,
+    pub total: Aggregate,
+    pub groups: Vec<Group>,
+    /// One per named agent group, keyed by its name
+    pub agent_groups: Vec<Group>,
+    pub generated_at: DateTime<Utc>,
+}
+
+/// One agent's share of an `Aggregate`
+struct AgentCounts<'a> {
+    agent: &'a AgentInfo,
+    checks: &'a [CheckStatus],
+    commands_in_flight: usize,
+    unconfirmed: bool,
+}
+
+impl Aggregate {
+    fn add(&mut self, counts: &AgentCounts, stale_before: DateTime<Utc>) {
+        // What it last reported may no longer hold
+        if counts.unconfirmed {
+            self.agents_unconfirmed += 1;
+            return;
+        }
+        self.agents += 1;
+        if counts.agent.last_heartbeat < stale_before {
+            self.agents_stale += 1;
+        }
+        if counts.agent.quarantined {
+            self.agents_quarantined += 1;
+        }
+        self.commands_in_flight += counts.commands_in_flight;
+
+        // Worst status of each component
+        let mut components: HashMap<&str, &str> = HashMap::new();
+        for check in counts.checks {
+            let worst = components.entry(&check.component_id).or_insert("ok");
+            if check.status == "error" || (check.status == "warning" && *worst != "error") {
+                *worst = check.status.as_str();
+            }
+        }
+        self.components += components.len();
+        self.components_error += components.values().filter(|s| **s == "error").count();
+        self.components_warning += components.values().filter(|s| **s == "warning").count();
+    }
+}
+
+/// `GET /summary`
+pub async fn summary(
+    State(state): State<Arc<GatewayState>>,
+    Query(query): Query<SummaryQuery>,
+) -> Result<axum::Json<Summary>, (StatusCode, String)> {
+    let selector = match query.labels {
+        Some(ref labels) => Selector::parse(labels).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        None => Selector::default(),
+    };
+    let stale_before =
+        stale_before(query.stale_secs.unwrap_or(DEFAULT_STALE_SECS)).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "stale_secs is out of range".to_string(),
+            )
+        })?;
+    let agents: Vec<AgentInfo> = state.registry.find_matching(&selector);
+    let checks: HashMap<String, Vec<CheckStatus>> = agents
+        .iter()
+        .map(|agent| (agent.id.clone(), state.registry.checks(&agent.id)))
+        .collect();
+    let in_flight = state.commands.count_by_agent();
+    let restored: Vec<AgentInfo> = state
+        .registry
+        .restored()
+        .into_iter()
+        .map(|r| r.agent)
+        .filter(|agent| selector.matches(&agent.labels))
+        .collect();
+
+    let counts: Vec<AgentCounts> = agents
+        .iter()
+        .map(|agent| AgentCounts {
+            agent,
+            checks: checks.get(&agent.id).map_or(&[], Vec::as_slice),
+            commands_in_flight: in_flight.get(&agent.id).copied().unwrap_or(0),
+            unconfirmed: false,
+        })
+        .chain(restored.iter().map(|agent| AgentCounts {
+            agent,
+            checks: &[],
+            commands_in_flight: 0,
+            unconfirmed: true,
+        }))
+        .collect();
+
+    let zone = &state.config.gateway.zone;
+    let (total, groups) = aggregate(&counts, query.by.as_deref(), zone, stale_before);
+    let agent_groups = aggregate_groups(&counts, &state.groups.definitions(), stale_before);
+
+    Ok(axum::Json(Summary {
+        gateway_id: state.config.gateway.id.clone(),
+        zone: zone.clone(),
+        backend_connected: state.metrics.backend_connected(),
+        by: query.by.unwrap_or_else(|| "zone".to_string()),
+        total,
+        groups,
+        agent_groups,
+        generated_at: Utc::now(),
+    }))
+}
+
+/// When an agent last heard from before is stale; None for an age past
+/// what a date can hold
+fn stale_before(stale_secs: i64) -> Option<DateTime<Utc>> {
+    TimeDelta::try_seconds(stale_secs.max(0)).and_then(|age| Utc::now().checked_sub_signed(age))
+}
+
+/// Totals, and the groups sorted by key (agents without the label last)
+fn aggregate(
+    counts: &[AgentCounts],
+    by: Option<&str>,
+    zone: &str,
+    stale_before: DateTime<Utc>,
+) -> (Aggregate, Vec<Group>) {
+    let mut total = Aggregate::default();
+    let mut groups: BTreeMap<Option<String>, Aggregate> = BTreeMap::new();
+
+    for agent in counts {
+        let key = match by {
+            Some(label) => agent.agent.labels.get(label).cloned(),
+            None => Some(agent.agent.zone(zone).to_string()),
+        };
+        total.add(agent, stale_before);
+        groups.entry(key).or_default().add(agent, stale_before);
+    }
+
+    let mut groups: Vec<Group> = groups
+        .into_iter()
+        .map(|(key, aggregate)| Group { key, aggregate })
+        .collect();
+    groups.sort_by_key(|g| g.key.is_none());
+    (total, groups)
+}
+
+/// An `Aggregate` of each named group's agents, in the groups' order
+fn aggregate_groups(
+    counts: &[AgentCounts],
+    definitions: &[GroupDefinition],
+    stale_before: DateTime<Utc>,
+) -> Vec<Group> {
+    definitions
+        .iter()
+        .map(|definition| {
+            let mut aggregate = Aggregate::default();
+            for agent in counts.iter().filter(|c| definition.contains(c.agent)) {
+                aggregate.add(agent, stale_before);
+            }
+            Group {
+                key: Some(definition.name.clone()),
+                aggregate,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(id: &str, labels: &[(&str, &str)], silent_secs: i64) -> AgentInfo {
+        AgentInfo {
+            id: id.to_string(),
+            hostname: id.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ip_addresses: Vec::new(),
+            version: "1.0".to_string(),
+            os: "linux".to_string(),
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            outdated: false,
+            quarantined: false,
+            tenant_id: None,
+            connected_at: Utc::now(),
+            last_heartbeat: Utc::now() - chrono::Duration::seconds(silent_secs),
+            health: Default::default(),
+            tx: None,
+        }
+    }
+
+    fn check(component_id: &str, check_name: &str, status: &str) -> CheckStatus {
+        CheckStatus {
+            component_id: component_id.to_string(),
+            check_name: check_name.to_string(),
+            status: status.to_string(),
+            message: None,
+            updated_at: Utc::now(),
+            unconfirmed: false,
+        }
+    }
+
+    #[test]
+    fn test_stale_before() {
+        assert!(stale_before(120).unwrap() < Utc::now());
+        assert!(stale_before(-5).unwrap() <= Utc::now());
+        assert!(stale_before(i64::MAX).is_none());
+        assert!(stale_before(i64::MAX / 1000).is_none());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let a = agent("a", &[("role", "database"), ("zone", "dmz")], 5);
+        let b = agent("b", &[("role", "database")], 600);
+        let c = agent("c", &[], 5);
+        let a_checks = [
+            check("postgres", "port", "ok"),
+            check("postgres", "replication", "error"),
+            check("pgbouncer", "port", "warning"),
+        ];
+        let b_checks = [
+            check("postgres", "port", "warning"),
+            check("nginx", "http", "ok"),
+        ];
+        let counts = [
+            AgentCounts {
+                agent: &a,
+                checks: &a_checks,
+                commands_in_flight: 2,
+                unconfirmed: false,
+            },
+            AgentCounts {
+                agent: &b,
+                checks: &b_checks,
+                commands_in_flight: 0,
+                unconfirmed: false,
+            },
+            AgentCounts {
+                agent: &c,
+                checks: &[],
+                commands_in_flight: 1,
+                unconfirmed: false,
+            },
+            AgentCounts {
+                agent: &a,
+                checks: &[],
+                commands_in_flight: 0,
+                unconfirmed: true,
+            },
+        ];
+        let stale_before = Utc::now() - chrono::Duration::seconds(120);
+
+        let (total, groups) = aggregate(&counts, None, "prod", stale_before);
+        assert_eq!(
+            total,
+            Aggregate {
+                agents: 3,
+                agents_unconfirmed: 1,
+                agents_stale: 1,
+                agents_quarantined: 0,
+                components: 4,
+                components_error: 1,
+                components_warning: 2,
+                commands_in_flight: 3,
+            }
+        );
+        let keys: Vec<Option<&str>> = groups.iter().map(|g| g.key.as_deref()).collect();
+        assert_eq!(keys, [Some("dmz"), Some("prod")]);
+        assert_eq!(groups[1].aggregate.agents, 2);
+        assert_eq!(groups[1].aggregate.agents_stale, 1);
+
+        let (_, groups) = aggregate(&counts, Some("role"), "prod", stale_before);
+        let keys: Vec<Option<&str>> = groups.iter().map(|g| g.key.as_deref()).collect();
+        assert_eq!(keys, [Some("database"), None]);
+        assert_eq!(groups[0].aggregate.components_error, 1);
+        assert_eq!(groups[1].aggregate.commands_in_flight, 1);
+
+        let definitions: Vec<GroupDefinition> = serde_yaml::from_str(
+            "[{name: databases, labels: role=database}, {name: pinned, agents: [c, z]}]",
+        )
+        .unwrap();
+        let agent_groups = aggregate_groups(&counts, &definitions, stale_before);
+        assert_eq!(agent_groups[0].key.as_deref(), Some("databases"));
+        assert_eq!(agent_groups[0].aggregate.agents, 2);
+        assert_eq!(agent_groups[0].aggregate.agents_unconfirmed, 1);
+        assert_eq!(agent_groups[1].aggregate.agents, 1);
+        assert_eq!(agent_groups[1].aggregate.commands_in_flight, 1);
+    }
+}
@@ -0,0 +1,66 @@
+//! Distributed tracing
+//!
+//! Built with the `otel` feature, spans are exported over OTLP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set (`OTEL_SERVICE_NAME` defaults to
+//! `opsmap-gateway`). Commands carry a W3C `traceparent` in `trace_context`:
+//! the gateway continues the backend's trace with a routing span and hands
+//! its own context on to the agent. Without the feature, the context is
+//! forwarded unchanged.
+
+#[path = "../../../shared/telemetry.rs"]
+mod common;
+
+#[cfg(feature = "otel")]
+pub use common::layer;
+pub use common::{set_parent, TraceContext};
+
+use tracing::Span;
+
+#[cfg(feature = "otel")]
+pub const SERVICE_NAME: &str = "opsmap-gateway";
+
+/// Flush pending spans
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Replace a message's trace context with the context of `span`
+pub fn inject(span: &Span, context: &mut Option<TraceContext>) {
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let mut carrier = TraceContext::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&span.context(), &mut carrier)
+        });
+        if !carrier.is_empty() {
+            *context = Some(carrier);
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, context);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_keeps_context_without_exporter() {
+        let mut context = Some(TraceContext::from([(
+            "traceparent".to_string(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+        )]));
+        let span = tracing::info_span!("route_command");
+        set_parent(&span, context.as_ref());
+        inject(&span, &mut context);
+
+        assert_eq!(
+            context.unwrap()["traceparent"],
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+}
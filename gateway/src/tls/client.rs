@@ -0,0 +1,57 @@
+//! rustls client configs, for the gateway's outgoing connections and the
+//! tools shipped beside it (`opsmap-ctl`, `opsmap-loadgen`), which include
+//! this file as is: it only uses external crates
+
+use anyhow::{anyhow, Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use std::io::BufReader;
+
+/// Client config trusting `ca_file` only when set (otherwise the system
+/// roots), presenting `identity` (certificate and key files) when set
+pub fn client_config(
+    ca_file: Option<&str>,
+    identity: Option<(&str, &str)>,
+) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            for cert in load_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+        }
+        None => {
+            // Certificates the system holds but rustls refuses are skipped
+            let certs =
+                rustls_native_certs::load_native_certs().context("Failed to load system roots")?;
+            let _ = roots.add_parsable_certificates(certs);
+        }
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    match identity {
+        Some((cert_file, key_file)) => builder
+            .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .context("Invalid TLS certificate or key"),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to parse {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", path);
+    }
+    Ok(certs)
+}
+
+/// The first key of a PEM file, PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path))
+}
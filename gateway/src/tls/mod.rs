@@ -0,0 +1,322 @@
+//! TLS termination for the agent listener
+//!
+//! The rustls server config is rebuilt whenever the certificate, key or CA
+//! files change (an ACME or Vault sidecar renewing them) or on SIGHUP. New
+//! handshakes use the new config; agents already connected keep their
+//! session, so a rotation never drops them.
+
+use anyhow::{Context, Result};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use rustls::pki_types::CertificateDer;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::TlsSettings;
+
+pub mod client;
+#[path = "../../../shared/watch.rs"]
+mod watch;
+use watch::watch_files;
+
+use client::{load_certs, load_key};
+
+/// URI SAN naming the tenant a client certificate was issued for, as in
+/// `urn:opsmap:tenant:acme`
+pub const TENANT_URI_PREFIX: &str = "urn:opsmap:tenant:";
+
+/// Whether the peer presented a client certificate the CA verified, and
+/// the names it holds
+#[derive(Debug, Clone, Default)]
+pub struct PeerCertificate {
+    pub verified: bool,
+    /// Subject CN and DNS SANs of a verified certificate
+    pub names: Vec<String>,
+    /// Tenant of its `urn:opsmap:tenant:` URI SAN, if any
+    pub tenant: Option<String>,
+}
+
+impl PeerCertificate {
+    fn new(certs: Option<&[CertificateDer<'_>]>) -> Self {
+        match certs.and_then(|certs| certs.first()) {
+            Some(leaf) => {
+                let (names, tenant) = certificate_identity(leaf);
+                Self {
+                    verified: true,
+                    names,
+                    tenant,
+                }
+            }
+            None => Self::default(),
+        }
+    }
+
+    /// Whether the verified certificate was issued to `name`
+    pub fn names(&self, name: &str) -> bool {
+        self.verified && self.names.iter().any(|n| n == name)
+    }
+}
+
+/// The subject CN and DNS SANs of a certificate, and the tenant it names
+fn certificate_identity(cert: &CertificateDer<'_>) -> (Vec<String>, Option<String>) {
+    use x509_parser::extensions::GeneralName;
+
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return (Vec::new(), None);
+    };
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    let mut tenant = None;
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(dns) => names.push(dns.to_string()),
+                GeneralName::URI(uri) => {
+                    if let Some(id) = uri.strip_prefix(TENANT_URI_PREFIX) {
+                        tenant = Some(id.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    (names, tenant)
+}
+
+/// Server config that can be swapped while the listener runs
+pub struct TlsReloader {
+    settings: TlsSettings,
+    /// Let clients without a certificate through the handshake (enrollment)
+    allow_anonymous: bool,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsReloader {
+    pub fn new(settings: &TlsSettings, allow_anonymous: bool) -> Result<Self> {
+        let config = build_server_config(settings, allow_anonymous)?;
+        Ok(Self {
+            settings: settings.clone(),
+            allow_anonymous,
+            config: RwLock::new(config),
+        })
+    }
+
+    /// Acceptor for the next handshake
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+    /// Re-read the certificate files, keeping the current config on error
+    pub fn reload(&self) -> Result<()> {
+        let config = build_server_config(&self.settings, self.allow_anonymous)?;
+        *self.config.write().unwrap() = config;
+        info!("TLS certificates reloaded");
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        [
+            &self.settings.cert_file,
+            &self.settings.key_file,
+            &self.settings.ca_file,
+        ]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .collect()
+    }
+}
+
+fn build_server_config(settings: &TlsSettings, allow_anonymous: bool) -> Result<Arc<ServerConfig>> {
+    let (cert_file, key_file) = match (&settings.cert_file, &settings.key_file) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => anyhow::bail!("tls.cert_file and tls.key_file are required when tls.enabled is true"),
+    };
+
+    let certs = load_certs(cert_file)?;
+    let key = load_key(key_file)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings.ca_file {
+        Some(ref ca_file) if settings.verify_clients => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if allow_anonymous {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Accept TLS connections and serve the router on them
+pub async fn serve(listener: TcpListener, app: Router, tls: Arc<TlsReloader>) -> Result<()> {
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let acceptor = tls.acceptor();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(tcp).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!(peer = %peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+
+            let peer_certificate = PeerCertificate::new(stream.get_ref().1.peer_certificates());
+            let service = TowerToHyperService::new(
+                app.layer(axum::Extension(peer_certificate))
+                    .layer(axum::Extension(ConnectInfo(peer))),
+            );
+
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                debug!(peer = %peer, error = %e, "Connection closed with error");
+            }
+        });
+    }
+}
+
+/// Reload the certificates on SIGHUP or when their files change
+pub async fn watch(tls: Arc<TlsReloader>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "Failed to install SIGHUP handler");
+            return;
+        }
+    };
+
+    let (tx, mut changes) = mpsc::channel::<()>(16);
+    // Keep the watcher alive for as long as we are watching
+    let _watcher = match watch_files(&tls.files(), tx) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!(error = %e, "Certificate file watch unavailable, reload on SIGHUP only");
+            None
+        }
+    };
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading TLS certificates");
+            }
+            Some(()) = changes.recv() => {
+                // Renewals usually replace the cert and key one after the other
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                while changes.try_recv().is_ok() {}
+                info!("Certificate files changed, reloading TLS certificates");
+            }
+        }
+
+        if let Err(e) = tls.reload() {
+            error!(error = %e, "TLS reload failed, keeping current certificates");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn write_identity(dir: &Path, name: &str) -> (String, String) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+
+        let cert_file = dir.join(format!("{}.crt", name));
+        let key_file = dir.join(format!("{}.key", name));
+        std::fs::write(&cert_file, cert.pem()).unwrap();
+        std::fs::write(&key_file, key.serialize_pem()).unwrap();
+        (
+            cert_file.to_string_lossy().to_string(),
+            key_file.to_string_lossy().to_string(),
+        )
+    }
+
+    #[test]
+    fn test_peer_certificate_tenant() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["agent-1".to_string()]).unwrap();
+        params.subject_alt_names.push(rcgen::SanType::URI(
+            "urn:opsmap:tenant:acme".try_into().unwrap(),
+        ));
+        let cert = params.self_signed(&key).unwrap();
+
+        let peer = PeerCertificate::new(Some(&[cert.der().clone()]));
+        assert!(peer.names("agent-1"));
+        assert_eq!(peer.tenant.as_deref(), Some("acme"));
+
+        let cert = rcgen::CertificateParams::new(vec!["agent-2".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let peer = PeerCertificate::new(Some(&[cert.der().clone()]));
+        assert!(peer.names("agent-2"));
+        assert_eq!(peer.tenant, None);
+    }
+
+    #[test]
+    fn test_reload_keeps_config_on_error() {
+        let dir = std::env::temp_dir().join(format!("opsmap-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = write_identity(&dir, "gateway");
+        let (ca_file, _) = write_identity(&dir, "ca");
+
+        let settings = TlsSettings {
+            enabled: true,
+            cert_file: Some(cert_file.clone()),
+            key_file: Some(key_file),
+            ca_file: Some(ca_file),
+            verify_clients: true,
+        };
+        let tls = TlsReloader::new(&settings, true).unwrap();
+        let before = tls.config.read().unwrap().clone();
+
+        write_identity(&dir, "gateway");
+        tls.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &tls.config.read().unwrap()));
+
+        let current = tls.config.read().unwrap().clone();
+        std::fs::write(&cert_file, "not a certificate").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(Arc::ptr_eq(&current, &tls.config.read().unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+//! Config validation
+//!
+//! Checks a parsed configuration for problems that would only show up at
+//! runtime: wrong URL schemes, bad listen addresses, missing or unreadable
+//! certificates. Used by `opsmap-gateway validate`.
+
+use crate::GatewayConfig;
+
+#[path = "../../../shared/validation.rs"]
+mod validation;
+pub use validation::Validation;
+use validation::{check_private_key, check_readable_file};
+
+/// Validate a gateway configuration
+pub fn validate(config: &GatewayConfig) -> Validation {
+    let mut v = Validation::default();
+
+    // Gateway
+    if config.gateway.id.trim().is_empty() {
+        v.error("gateway.id must not be empty");
+    }
+    if config.gateway.zone.trim().is_empty() {
+        v.error("gateway.zone must not be empty");
+    }
+    if let Err(e) = crate::listen::addresses(&config.gateway) {
+        v.error(e);
+    }
+
+    if config
+        .gateway
+        .allowed_zones
+        .iter()
+        .any(|z| z.trim().is_empty())
+    {
+        v.error("gateway.allowed_zones must not contain an empty zone");
+    }
+    if config.gateway.allowed_zones.iter().any(|z| z == "*") {
+        v.warning("gateway.allowed_zones has \"*\": commands reach agents of any zone");
+    }
+
+    if config.gateway.poll_session_timeout_secs == 0 {
+        v.error("gateway.poll_session_timeout_secs must be greater than 0");
+    }
+
+    if let Some(ref minimum) = config.agent_versions.min_version {
+        if crate::versions::parse(minimum).is_none() {
+            v.error(format!(
+                "agent_versions.min_version '{}' is not a version like 1.2.3",
+                minimum
+            ));
+        }
+    }
+
+    // Backend
+    let urls = config.backend.urls();
+    for (priority, url) in urls.iter().enumerate() {
+        let field = match priority {
+            0 => "backend.url".to_string(),
+            n => format!("backend.standby_urls[{}]", n - 1),
+        };
+        if url.starts_with("ws://") {
+            v.warning(format!("{} '{}' is not encrypted", field, url));
+        } else if !url.starts_with("wss://") {
+            v.error(format!(
+                "{} '{}' must start with ws:// or wss://",
+                field, url
+            ));
+        }
+    }
+    if config.backend.reconnect_interval_secs == 0 {
+        v.error("backend.reconnect_interval_secs must be greater than 0");
+    }
+    if !config.backend.standby_urls.is_empty() && config.backend.failback_interval_secs == 0 {
+        v.error("backend.failback_interval_secs must be greater than 0");
+    }
+    let any_plain = urls.iter().any(|url| url.starts_with("ws://"));
+    match config.backend.ca_file {
+        Some(ref ca) => check_readable_file(&mut v, "backend.ca_file", ca),
+        None if urls.iter().any(|url| url.starts_with("wss://")) => v.warning(
+            "backend.ca_file is not set, the backend certificate is checked against system roots",
+        ),
+        None => {}
+    }
+    let auth = &config.backend.auth;
+    if auth.token.is_some() && auth.jwt_secret.is_some() && !auth.require_backend_jwt {
+        v.warning("backend.auth.token is sent, jwt_secret is not used");
+    }
+    if auth.require_backend_jwt && auth.jwt_secret.is_none() {
+        v.error("backend.auth.require_backend_jwt needs backend.auth.jwt_secret");
+    }
+    if auth.jwt_secret.is_some() && auth.jwt_ttl_secs == 0 {
+        v.error("backend.auth.jwt_ttl_secs must be greater than 0");
+    }
+    if (auth.token.is_some() || auth.jwt_secret.is_some()) && any_plain {
+        v.warning("backend.auth credentials are sent over an unencrypted backend URL");
+    }
+    if (auth.token.is_some() || auth.jwt_secret.is_some())
+        && auth.backend_token.is_none()
+        && !auth.require_backend_jwt
+    {
+        v.warning("backend.auth does not authenticate the backend in return");
+    }
+
+    // TLS
+    if config.tls.enabled {
+        match (&config.tls.cert_file, &config.tls.key_file) {
+            (Some(cert), Some(key)) => {
+                check_readable_file(&mut v, "tls.cert_file", cert);
+                check_readable_file(&mut v, "tls.key_file", key);
+                check_private_key(&mut v, "tls.key_file", key);
+            }
+            _ => v.error("tls.cert_file and tls.key_file are required when tls.enabled is true"),
+        }
+
+        match config.tls.ca_file {
+            Some(ref ca) => check_readable_file(&mut v, "tls.ca_file", ca),
+            None if config.tls.verify_clients => {
+                v.error("tls.ca_file is required to verify agent certificates")
+            }
+            None => {}
+        }
+
+        if !config.tls.verify_clients {
+            v.warning("tls.verify_clients is false, agents are not authenticated by certificate");
+        }
+    }
+
+    // Downstream gateways are identified by their certificate
+    if config.downstream.enabled && !(config.tls.enabled && config.tls.verify_clients) {
+        v.error("downstream.enabled needs tls.enabled and tls.verify_clients, gateways are accepted by their certificate");
+    }
+
+    // Enrollment
+    if config.enrollment.enabled {
+        match (
+            &config.enrollment.ca_cert_file,
+            &config.enrollment.ca_key_file,
+        ) {
+            (Some(cert), Some(key)) => {
+                check_readable_file(&mut v, "enrollment.ca_cert_file", cert);
+                check_readable_file(&mut v, "enrollment.ca_key_file", key);
+                check_private_key(&mut v, "enrollment.ca_key_file", key);
+            }
+            _ => v.error("enrollment.ca_cert_file and enrollment.ca_key_file are required"),
+        }
+    }
+
+    // RBAC
+    if config.rbac.enabled {
+        if config.rbac.policies.is_empty() {
+            v.warning("rbac.enabled is true with no policies, every command will be denied");
+        }
+        for policy in &config.rbac.policies {
+            if policy.principals.is_empty() && policy.roles.is_empty() {
+                v.warning(format!(
+                    "rbac policy '{}' has no principals or roles and never applies",
+                    policy.name
+                ));
+            }
+        }
+    }
+
+    // API
+    if config.api.open() {
+        v.error(
+            "api.tokens is empty: the command endpoints (POST /agents/:id/command, \
+             /groups/:name/command, /schedules/:name/run, /config-updates, \
+             /dead-letters/:id/retry, PUT /schedules/:name) are refused, and /agents, \
+             /metrics and /audit are not authenticated",
+        );
+    }
+    for (i, token) in config.api.tokens.iter().enumerate() {
+        if token.token.len() < MIN_API_TOKEN_LEN {
+            v.error(format!(
+                "api token '{}' must be at least {} characters",
+                token.name, MIN_API_TOKEN_LEN
+            ));
+        }
+        if token.scopes.is_empty() {
+            v.warning(format!(
+                "api token '{}' has no scopes and is refused everywhere",
+                token.name
+            ));
+        }
+        if config.api.tokens[..i]
+            .iter()
+            .any(|t| t.token == token.token)
+        {
+            v.error(format!(
+                "api token '{}' reuses the token of another",
+                token.name
+            ));
+        }
+    }
+
+    // HA
+    if config.ha.enabled {
+        if config.ha.token.len() < MIN_API_TOKEN_LEN {
+            v.error(format!(
+                "ha.token must be at least {} characters",
+                MIN_API_TOKEN_LEN
+            ));
+        }
+        for peer in &config.ha.peers {
+            if !peer.starts_with("wss://") && !peer.starts_with("ws://") {
+                v.error(format!("ha peer '{}' must be a ws:// or wss:// URL", peer));
+            }
+        }
+        if let Some(ref ca_file) = config.ha.ca_file {
+            check_readable_file(&mut v, "ha.ca_file", ca_file);
+        }
+    }
+
+    if config.limits.burst_secs == 0 {
+        v.error("limits.burst_secs must be greater than 0");
+    }
+    if config.limits.max_register_bytes > config.limits.max_frame_bytes {
+        v.error("limits.max_register_bytes must not exceed limits.max_frame_bytes");
+    }
+    if config.limits.max_message_bytes == 0 || config.limits.max_backend_message_bytes == 0 {
+        v.error(
+            "limits.max_message_bytes and limits.max_backend_message_bytes must be greater than 0",
+        );
+    }
+    if config.limits.backend_queue == 0
+        || config.limits.connection_queue == 0
+        || config.limits.backend_queue_wait_secs == 0
+    {
+        v.error("limits.backend_queue, limits.connection_queue and limits.backend_queue_wait_secs must be greater than 0");
+    }
+
+    if config.health.degraded_after_secs == 0
+        || config.health.degraded_after_secs >= config.health.stale_after_secs
+    {
+        v.error("health.degraded_after_secs must be greater than 0 and less than health.stale_after_secs");
+    }
+
+    if config.fanout.batch_size == 0 || config.fanout.batch_interval_ms == 0 {
+        v.error("fanout.batch_size and fanout.batch_interval_ms must be greater than 0");
+    }
+
+    // Groups
+    let mut group_names = std::collections::HashSet::new();
+    for group in &config.groups {
+        if let Err(e) = group.check() {
+            v.error(e);
+        }
+        if !group_names.insert(group.name.as_str()) {
+            v.error(format!("group '{}' is defined more than once", group.name));
+        }
+    }
+
+    // Schedules
+    if config.schedules.enabled && config.schedules.history == 0 {
+        v.error("schedules.history must be greater than 0");
+    }
+
+    // Webhooks
+    for hook in &config.webhooks {
+        if !hook.url.starts_with("https://") && !hook.url.starts_with("http://") {
+            v.error(format!(
+                "webhook '{}' must have an http:// or https:// URL",
+                hook.name
+            ));
+        } else if hook.url.starts_with("http://") {
+            v.warning(format!("webhook '{}' sends events unencrypted", hook.name));
+        }
+        for event in &hook.events {
+            if !crate::webhooks::EVENTS.contains(&event.as_str()) {
+                v.error(format!(
+                    "webhook '{}' has unknown event '{}'",
+                    hook.name, event
+                ));
+            }
+        }
+    }
+
+    v
+}
+
+/// Shorter bearer tokens could be guessed
+const MIN_API_TOKEN_LEN: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> GatewayConfig {
+        let mut config = GatewayConfig::default();
+        config.tls.enabled = false;
+        config.api = serde_yaml::from_str(
+            "tokens:\n  - {name: operator, token: operator-token-0123456789, scopes: [read, command]}\n",
+        )
+        .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let v = validate(&config());
+        assert!(v.is_ok(), "{:?}", v.errors);
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let mut c = config();
+        c.gateway.listen_addr = "localhost".to_string();
+        c.backend.url = "https://backend".to_string();
+
+        let v = validate(&c);
+        assert_eq!(v.errors.len(), 2, "{:?}", v.errors);
+    }
+
+    #[test]
+    fn test_tls_files() {
+        let mut c = config();
+        c.tls.enabled = true;
+        c.tls.cert_file = Some("/nonexistent/gateway.crt".to_string());
+        c.tls.key_file = None;
+        c.tls.ca_file = None;
+
+        let v = validate(&c);
+        assert!(v
+            .errors
+            .iter()
+            .any(|e| e.contains("tls.key_file are required")));
+        assert!(v
+            .errors
+            .iter()
+            .any(|e| e.contains("tls.ca_file is required")));
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let mut c = config();
+        c.api.tokens.clear();
+        assert!(validate(&c)
+            .errors
+            .iter()
+            .any(|e| e.contains("api.tokens") && e.contains("/agents/:id/command")));
+
+        c.api = serde_yaml::from_str(
+            "tokens:\n  - {name: a, token: short}\n  - {name: b, token: short, scopes: []}\n",
+        )
+        .unwrap();
+        let v = validate(&c);
+        assert_eq!(v.errors.len(), 3, "{:?}", v.errors);
+        assert!(v.warnings.iter().any(|w| w.contains("'b' has no scopes")));
+    }
+
+    #[test]
+    fn test_ha() {
+        let mut c = config();
+        c.ha = serde_yaml::from_str(
+            "enabled: true
+peers: [https://gw-2/peer]
+",
+        )
+        .unwrap();
+        let v = validate(&c);
+        assert!(v.errors.iter().any(|e| e.contains("ha.token")));
+        assert!(v.errors.iter().any(|e| e.contains("https://gw-2/peer")));
+    }
+
+    #[test]
+    fn test_backend_ca_file() {
+        let mut c = config();
+        c.backend.url = "wss://backend.example.com/gateway".to_string();
+        assert!(validate(&c)
+            .warnings
+            .iter()
+            .any(|w| w.contains("backend.ca_file")));
+
+        c.backend.ca_file = Some("/nonexistent/backend-ca.crt".to_string());
+        assert!(validate(&c)
+            .errors
+            .iter()
+            .any(|e| e.contains("backend.ca_file")));
+    }
+
+    #[test]
+    fn test_backend_standby_urls() {
+        let mut c = config();
+        c.backend.standby_urls = vec!["https://standby".to_string()];
+        assert!(validate(&c)
+            .errors
+            .iter()
+            .any(|e| e.contains("backend.standby_urls[0]")));
+
+        c.backend.standby_urls = vec!["wss://standby.example.com/gateway".to_string()];
+        assert_eq!(c.backend.urls()[1], "wss://standby.example.com/gateway");
+        assert!(!validate(&c).errors.iter().any(|e| e.contains("standby")));
+    }
+
+    #[test]
+    fn test_backend_auth() {
+        let mut c = config();
+        c.backend.auth.require_backend_jwt = true;
+        assert!(validate(&c).errors.iter().any(|e| e.contains("jwt_secret")));
+
+        c.backend.auth.jwt_secret = Some("shared".to_string());
+        assert!(validate(&c).is_ok());
+        c.backend.auth.require_backend_jwt = false;
+        assert!(validate(&c)
+            .warnings
+            .iter()
+            .any(|w| w.contains("in return")));
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut c = config();
+        c.groups = serde_yaml::from_str(
+            "[{name: web, labels: tier=web}, {name: web, agents: [a]}, {name: none}]",
+        )
+        .unwrap();
+        let errors = validate(&c).errors;
+        assert!(errors.iter().any(|e| e.contains("more than once")));
+        assert!(errors
+            .iter()
+            .any(|e| e.contains("neither labels nor agents")));
+    }
+}
@@ -0,0 +1,135 @@
+//! Minimum agent version
+//!
+//! Agents older than `agent_versions.min_version` are flagged as outdated
+//! when they register, or turned away with an "upgrade required" close frame
+//! when `reject` is set. The backend can replace the configured policy at
+//! runtime with an `agent_version_policy` message.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::RwLock;
+
+/// WebSocket close code sent to rejected agents (HTTP 426 Upgrade Required)
+pub const UPGRADE_REQUIRED: u16 = 4426;
+
+/// Minimum version settings
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VersionPolicy {
+    /// Oldest supported agent version, e.g. "0.3.0"
+    #[serde(default)]
+    pub min_version: Option<String>,
+    /// Close the connection of older agents instead of only flagging them
+    #[serde(default)]
+    pub reject: bool,
+}
+
+/// What to do with a registering agent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Supported,
+    /// Accepted, but reported as needing an upgrade
+    Outdated,
+    Rejected,
+}
+
+/// The policy in force, replaceable by the backend
+pub struct VersionGate {
+    policy: RwLock<VersionPolicy>,
+}
+
+impl VersionGate {
+    pub fn new(policy: VersionPolicy) -> Self {
+        Self {
+            policy: RwLock::new(policy),
+        }
+    }
+
+    pub fn policy(&self) -> VersionPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set(&self, policy: VersionPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    /// Judge an agent by the version it registered with
+    pub fn check(&self, version: &str) -> Verdict {
+        let policy = self.policy.read().unwrap();
+        let minimum = match policy.min_version {
+            Some(ref minimum) => minimum,
+            None => return Verdict::Supported,
+        };
+
+        if !is_older(version, minimum) {
+            Verdict::Supported
+        } else if policy.reject {
+            Verdict::Rejected
+        } else {
+            Verdict::Outdated
+        }
+    }
+}
+
+/// Numeric release components, ignoring a leading "v" and any pre-release
+/// or build suffix
+pub fn parse(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let release = version.split(['-', '+']).next()?;
+    release.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Whether `version` is below `minimum`; an unparseable agent version is
+/// treated as older, an unparseable minimum never is
+fn is_older(version: &str, minimum: &str) -> bool {
+    let minimum = match parse(minimum) {
+        Some(minimum) => minimum,
+        None => return false,
+    };
+    let version = match parse(version) {
+        Some(version) => version,
+        None => return true,
+    };
+
+    let len = version.len().max(minimum.len());
+    let component = |parts: &[u64], i: usize| parts.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| component(&version, i).cmp(&component(&minimum, i)))
+        .find(|ordering| *ordering != Ordering::Equal)
+        == Some(Ordering::Less)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_older() {
+        assert!(is_older("0.1.0", "0.2.0"));
+        assert!(is_older("0.9.9", "0.10"));
+        assert!(is_older("v1.2.2", "1.2.3"));
+        assert!(is_older("unknown", "1.0.0"));
+        assert!(!is_older("1.2.3", "1.2.3"));
+        assert!(!is_older("1.2.3-rc1", "1.2"));
+        assert!(!is_older("2.0.0", "1.9.9"));
+        assert!(!is_older("0.1.0", "latest"));
+    }
+
+    #[test]
+    fn test_check() {
+        let gate = VersionGate::new(VersionPolicy::default());
+        assert_eq!(gate.check("0.1.0"), Verdict::Supported);
+
+        gate.set(VersionPolicy {
+            min_version: Some("0.2.0".to_string()),
+            reject: false,
+        });
+        assert_eq!(gate.check("0.1.0"), Verdict::Outdated);
+        assert_eq!(gate.check("0.2.0"), Verdict::Supported);
+
+        gate.set(VersionPolicy {
+            min_version: Some("0.2.0".to_string()),
+            reject: true,
+        });
+        assert_eq!(gate.check("0.1.0"), Verdict::Rejected);
+    }
+}
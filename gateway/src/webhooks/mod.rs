@@ -0,0 +1,486 @@
+//! Webhook notifications
+//!
+//! The gateway POSTs JSON to each configured webhook when an agent connects
+//! or disconnects, a check goes to `error`, or a command fails, so Slack or
+//! PagerDuty hear about it without the backend:
+//!
+//! ```yaml
+//! webhooks:
+//!   - name: slack
+//!     url: https://hooks.slack.com/services/T000/B000/XXXX
+//!     events: [agent_disconnected, status_error]   # empty = all
+//!     template: '{"text": "{{zone}}: {{message}}"}'
+//!   - name: pagerduty
+//!     url: https://events.pagerduty.com/v2/enqueue
+//!     events: [status_error, command_failed]
+//!     headers: {X-Routing-Key: "${file:/etc/opsmap/secrets/pd-key}"}
+//!     retries: 5
+//! ```
+//!
+//! Without a template the body is the event itself; in a template,
+//! `{{field}}` is replaced by that event field, escaped for a JSON string
+//! (`{{details}}` is JSON). A delivery failing, or answered other than 2xx,
+//! is retried with doubling delays. Each webhook has a queue of
+//! [`QUEUE_SIZE`] events, delivered in order by one task over a client
+//! shared by all webhooks (system roots); events finding it full are
+//! dropped. Deliveries are best effort: events during a backend or gateway
+//! restart may be missed.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{header, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, warn};
+
+use crate::{BackendMessage, GatewayState};
+
+/// Events a webhook can subscribe to
+pub const EVENTS: &[&str] = &[
+    "agent_connected",
+    "agent_disconnected",
+    "status_error",
+    "command_failed",
+];
+
+/// Command response statuses reported as `command_failed`
+const FAILED: &[&str] = &["failed", "timeout", "rejected"];
+
+/// Events waiting for delivery per webhook
+pub const QUEUE_SIZE: usize = 256;
+
+type HttpClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// A webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub name: String,
+    pub url: String,
+    /// Events sent, all when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Body with `{{field}}` placeholders, the event JSON when unset
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Attempts after the first
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl Webhook {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+/// Something a webhook is told about
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    pub gateway_id: String,
+    pub zone: String,
+    pub agent_id: String,
+    pub hostname: Option<String>,
+    /// One line for people
+    pub message: String,
+    pub details: serde_json::Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Configured webhooks, with the queue of each
+pub struct Webhooks {
+    hooks: Vec<(Arc<Webhook>, mpsc::Sender<String>)>,
+    /// Taken by [`run`] for the delivery tasks
+    queues: Mutex<Vec<mpsc::Receiver<String>>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> Self {
+        let (hooks, queues) = hooks
+            .into_iter()
+            .map(|hook| {
+                let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+                ((Arc::new(hook), tx), rx)
+            })
+            .unzip();
+        Self {
+            hooks,
+            queues: Mutex::new(queues),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+}
+
+/// Notify the webhooks of a status update from an agent, given the status
+/// its check had before
+pub fn status(
+    state: &GatewayState,
+    agent_id: &str,
+    previous: Option<&str>,
+    update: &serde_json::Value,
+) {
+    let went_to_error =
+        update.get("status").and_then(|v| v.as_str()) == Some("error") && previous != Some("error");
+    if !state.webhooks.enabled() || !went_to_error {
+        return;
+    }
+    let field = |name: &str| {
+        update
+            .get(name)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+    };
+    let mut message = format!(
+        "{}/{} is in error on {}",
+        field("component_id"),
+        field("check_name"),
+        agent_id
+    );
+    if !field("message").is_empty() {
+        message = format!("{}: {}", message, field("message"));
+    }
+    let hostname = state.registry.get(agent_id).map(|agent| agent.hostname);
+    send(
+        state,
+        event(
+            state,
+            "status_error",
+            agent_id,
+            hostname,
+            message,
+            update.clone(),
+        ),
+    );
+}
+
+/// Notify the webhooks of a command response from an agent
+pub fn command(state: &GatewayState, agent_id: &str, response: &serde_json::Value) {
+    let field = |name: &str| response.get(name).and_then(|v| v.as_str());
+    let status = field("status").unwrap_or_default();
+    if !state.webhooks.enabled() || !FAILED.contains(&status) {
+        return;
+    }
+    let mut message = format!(
+        "Command {} {} on {}",
+        field("job_id").unwrap_or_default(),
+        status,
+        agent_id
+    );
+    if let Some(error) = field("error") {
+        message = format!("{}: {}", message, error);
+    }
+    let hostname = state.registry.get(agent_id).map(|agent| agent.hostname);
+    send(
+        state,
+        event(
+            state,
+            "command_failed",
+            agent_id,
+            hostname,
+            message,
+            response.clone(),
+        ),
+    );
+}
+
+/// Deliver the queued events, and notify the webhooks of agents coming and
+/// going and commands timing out, until the gateway stops
+pub async fn run(state: Arc<GatewayState>) {
+    let mut rx = state.backend_tx.subscribe();
+
+    let client = match client() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(error = %e, "Webhooks disabled");
+            return;
+        }
+    };
+    let queues = std::mem::take(&mut *state.webhooks.queues.lock().unwrap());
+    for ((hook, _), queue) in state.webhooks.hooks.iter().zip(queues) {
+        tokio::spawn(deliver_queued(
+            hook.clone(),
+            queue,
+            client.clone(),
+            state.metrics.clone(),
+        ));
+    }
+    // Hostnames of connected agents, gone from the registry on disconnect
+    let mut hostnames: HashMap<String, String> = HashMap::new();
+
+    loop {
+        let message = match rx.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    missed = missed,
+                    "Webhook notifications fell behind, events missed"
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let event = match message {
+            BackendMessage::AgentConnected(agent) => {
+                hostnames.insert(agent.id.clone(), agent.hostname.clone());
+                let message = format!("Agent {} connected from {}", agent.id, agent.hostname);
+                let details = serde_json::to_value(&agent).unwrap_or_default();
+                event(
+                    &state,
+                    "agent_connected",
+                    &agent.id,
+                    Some(agent.hostname),
+                    message,
+                    details,
+                )
+            }
+            BackendMessage::AgentDisconnected(agent_id) => {
+                let hostname = hostnames.remove(&agent_id);
+                let message = format!("Agent {} disconnected", agent_id);
+                event(
+                    &state,
+                    "agent_disconnected",
+                    &agent_id,
+                    hostname,
+                    message,
+                    serde_json::Value::Null,
+                )
+            }
+            BackendMessage::CommandTimeout(timeout) => {
+                let message = format!(
+                    "Command {} got no final response from {}",
+                    timeout.job_id, timeout.agent_id
+                );
+                let hostname = hostnames.get(&timeout.agent_id).cloned();
+                let details = serde_json::to_value(&timeout).unwrap_or_default();
+                event(
+                    &state,
+                    "command_failed",
+                    &timeout.agent_id,
+                    hostname,
+                    message,
+                    details,
+                )
+            }
+            _ => continue,
+        };
+        send(&state, event);
+    }
+}
+
+fn event(
+    state: &GatewayState,
+    kind: &'static str,
+    agent_id: &str,
+    hostname: Option<String>,
+    message: String,
+    details: serde_json::Value,
+) -> Event {
+    Event {
+        event: kind,
+        gateway_id: state.config.gateway.id.clone(),
+        zone: state.config.gateway.zone.clone(),
+        agent_id: agent_id.to_string(),
+        hostname,
+        message,
+        details,
+        timestamp: Utc::now(),
+    }
+}
+
+/// Queue an event for the webhooks subscribed to it
+fn send(state: &GatewayState, event: Event) {
+    for (hook, queue) in &state.webhooks.hooks {
+        if !hook.wants(event.event) {
+            continue;
+        }
+        let body = match hook.template {
+            Some(ref template) => render(template, &event),
+            None => serde_json::to_string(&event).unwrap_or_default(),
+        };
+        if let Err(TrySendError::Full(_)) = queue.try_send(body) {
+            warn!(webhook = %hook.name, event = event.event, "Webhook queue full, event dropped");
+            state.metrics.webhook_delivery(&hook.name, "dropped");
+        }
+    }
+}
+
+/// The client all webhooks are delivered with
+fn client() -> anyhow::Result<HttpClient> {
+    let config = crate::tls::client::client_config(None, None)?;
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder(TokioExecutor::new()).build(connector))
+}
+
+/// Deliver the events queued for a webhook, one at a time
+async fn deliver_queued(
+    hook: Arc<Webhook>,
+    mut queue: mpsc::Receiver<String>,
+    client: HttpClient,
+    metrics: crate::metrics::GatewayMetrics,
+) {
+    while let Some(body) = queue.recv().await {
+        let delivered = deliver(&client, &hook, body).await;
+        metrics.webhook_delivery(&hook.name, if delivered { "delivered" } else { "failed" });
+    }
+}
+
+/// POST a body to a webhook, retrying; true once it is accepted
+async fn deliver(client: &HttpClient, hook: &Webhook, body: String) -> bool {
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    let mut delay = Duration::from_secs(1);
+
+    for attempt in 0..=hook.retries {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        let error = match tokio::time::timeout(timeout, post(client, hook, body.clone())).await {
+            Ok(Ok(status)) if status.is_success() => return true,
+            Ok(Ok(status)) => format!("answered {}", status),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+        debug!(webhook = %hook.name, attempt = attempt, error = %error, "Webhook delivery failed");
+    }
+    warn!(webhook = %hook.name, attempts = hook.retries + 1, "Webhook delivery given up");
+    false
+}
+
+async fn post(client: &HttpClient, hook: &Webhook, body: String) -> anyhow::Result<StatusCode> {
+    let uri: hyper::Uri = hook.url.parse().context("invalid webhook URL")?;
+    let mut request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(
+            header::USER_AGENT,
+            concat!("opsmap-gateway/", env!("CARGO_PKG_VERSION")),
+        );
+    for (name, value) in &hook.headers {
+        request = request.header(name, value);
+    }
+    let response = client
+        .request(request.body(Full::new(Bytes::from(body)))?)
+        .await?;
+    let status = response.status();
+    // Read to the end, for the connection to be reused
+    response.into_body().collect().await?;
+    Ok(status)
+}
+
+/// Fill a template's `{{field}}` placeholders from an event
+fn render(template: &str, event: &Event) -> String {
+    let fields = serde_json::to_value(event).unwrap_or_default();
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match fields.get(rest[start + 2..end].trim()) {
+            // Escaped as in a JSON string, without the quotes
+            Some(serde_json::Value::String(s)) => {
+                let quoted = serde_json::to_string(s).unwrap_or_default();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(serde_json::Value::Null) | None => {}
+            Some(value) => out.push_str(&value.to_string()),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let event = Event {
+            event: "status_error",
+            gateway_id: "gw-1".to_string(),
+            zone: "dmz".to_string(),
+            agent_id: "agent-1".to_string(),
+            hostname: None,
+            message: "postgres/port is in error: \"refused\"".to_string(),
+            details: serde_json::json!({"status": "error"}),
+            timestamp: Utc::now(),
+        };
+        let body = render(
+            r#"{"text": "{{zone}} {{ message }}{{hostname}}", "details": {{details}}, "x": "{{unknown}}"}"#,
+            &event,
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["text"], "dmz postgres/port is in error: \"refused\"");
+        assert_eq!(body["details"]["status"], "error");
+        assert_eq!(body["x"], "");
+    }
+
+    #[tokio::test]
+    async fn test_deliver_ipv6() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return; // no IPv6 here
+        };
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let n = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+
+        let hook = Webhook {
+            name: "local".to_string(),
+            url: format!("http://[::1]:{}/hook", port),
+            events: Vec::new(),
+            template: None,
+            headers: HashMap::new(),
+            retries: 0,
+            timeout_secs: 5,
+        };
+        assert!(deliver(&client().unwrap(), &hook, "{}".to_string()).await);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("host: [::1]:{}\r\n", port)));
+    }
+}
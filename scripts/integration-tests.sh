@@ -0,0 +1,20 @@
+#!/bin/bash
+# scripts/integration-tests.sh
+# Build the gateway and the agent, then run the end-to-end tests (e2e/)
+# against those binaries
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+
+echo "Building gateway..."
+cargo build --manifest-path "$ROOT/gateway/Cargo.toml"
+
+echo "Building agent..."
+cargo build --manifest-path "$ROOT/agent/Cargo.toml"
+
+export OPSMAP_GATEWAY_BIN="${OPSMAP_GATEWAY_BIN:-$ROOT/gateway/target/debug/opsmap-gateway}"
+export OPSMAP_AGENT_BIN="${OPSMAP_AGENT_BIN:-$ROOT/agent/target/debug/opsmap-agent}"
+
+echo "Running end-to-end tests..."
+cargo test --manifest-path "$ROOT/e2e/Cargo.toml" -- --include-ignored "$@"
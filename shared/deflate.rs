@@ -0,0 +1,38 @@
+//! zlib compression of WebSocket frames. The agent and the gateway both
+//! include this file as is: it only uses external crates.
+
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Refuse to inflate frames beyond this size
+pub const MAX_INFLATED: u64 = 64 * 1024 * 1024;
+
+/// zlib-compress a frame
+pub fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Inflate a binary frame if it is deflated. zlib streams start with 0x78,
+/// which neither a JSON object nor a MessagePack map ever does.
+pub fn inflate(data: Vec<u8>) -> Result<Vec<u8>> {
+    if data.first() != Some(&0x78) {
+        return Ok(data);
+    }
+
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data.as_slice())
+        .take(MAX_INFLATED + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > MAX_INFLATED {
+        return Err(anyhow!(
+            "Compressed frame inflates beyond {} bytes",
+            MAX_INFLATED
+        ));
+    }
+    Ok(inflated)
+}
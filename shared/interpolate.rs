@@ -0,0 +1,139 @@
+//! Config value interpolation
+//!
+//! String values in the config file may reference the environment or files:
+//! - `${VAR}` / `${VAR:-default}`: environment variable
+//! - `${file:/path}`: file contents, without the trailing newline
+//! - `${fact:NAME}`: left as is, for the agent's labels to resolve when sent
+//! - `$${`: a literal `${`
+//!
+//! Lets secrets and certificate paths come from systemd credentials or
+//! Kubernetes without templating the YAML externally. The agent and the
+//! gateway both include this file as is: it only uses external crates.
+
+use anyhow::{anyhow, Context, Result};
+
+/// Interpolate every string value in a parsed YAML document
+pub fn interpolate_value(value: &mut serde_yaml::Value) -> Result<()> {
+    match value {
+        serde_yaml::Value::String(s) if s.contains('$') => *s = interpolate(s)?,
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                interpolate_value(item)?;
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for (_, item) in map.iter_mut() {
+                interpolate_value(item)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => interpolate_value(&mut tagged.value)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand the placeholders in a single string
+pub fn interpolate(input: &str) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find('$') {
+        output.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+
+        if let Some(after) = tail.strip_prefix("$${") {
+            output.push_str("${");
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated placeholder in config value: {}", input))?;
+            output.push_str(&resolve(&after[..end])?);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &tail[1..];
+        }
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn resolve(expr: &str) -> Result<String> {
+    if expr.starts_with("fact:") {
+        return Ok(format!("${{{}}}", expr));
+    }
+    if let Some(path) = expr.strip_prefix("file:") {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file reference: {}", path))?;
+        return Ok(content.trim_end_matches(['\n', '\r']).to_string());
+    }
+
+    let (name, default) = match expr.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (expr, None),
+    };
+
+    match (std::env::var(name), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => Ok(default.to_string()),
+        (Err(_), None) => Err(anyhow!(
+            "Environment variable '{}' referenced in config is not set",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate_env() {
+        std::env::set_var("OPSMAP_TEST_GATEWAY_HOST", "gw.example.com");
+        assert_eq!(
+            interpolate("wss://${OPSMAP_TEST_GATEWAY_HOST}:443").unwrap(),
+            "wss://gw.example.com:443"
+        );
+        assert_eq!(
+            interpolate("${OPSMAP_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert!(interpolate("${OPSMAP_TEST_UNSET}").is_err());
+    }
+
+    #[test]
+    fn test_interpolate_file_and_escapes() {
+        let path = std::env::temp_dir().join(format!("opsmap-secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+
+        let value = format!("token=${{file:{}}}", path.display());
+        assert_eq!(interpolate(&value).unwrap(), "token=s3cret");
+        assert_eq!(
+            interpolate("cost $5, literal $${HOME}").unwrap(),
+            "cost $5, literal ${HOME}"
+        );
+        assert!(interpolate("${unterminated").is_err());
+        assert_eq!(
+            interpolate("${fact:kernel_version}").unwrap(),
+            "${fact:kernel_version}"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interpolate_yaml_document() {
+        std::env::set_var("OPSMAP_TEST_ROLE", "database");
+        let mut value: serde_yaml::Value = serde_yaml::from_str(
+            "labels:\n  role: ${OPSMAP_TEST_ROLE}\nlist: [\"${OPSMAP_TEST_ROLE}\"]\nport: 8080\n",
+        )
+        .unwrap();
+
+        interpolate_value(&mut value).unwrap();
+        assert_eq!(value["labels"]["role"], "database");
+        assert_eq!(value["list"][0], "database");
+        assert_eq!(value["port"], 8080);
+    }
+}
@@ -0,0 +1,96 @@
+//! Sent messages kept until they are acked, for at-least-once delivery. The
+//! agent and the gateway both include this file as is: it only uses external
+//! crates.
+
+use std::collections::VecDeque;
+use tracing::warn;
+
+/// Sent messages waiting for an ack, in sequence order
+pub struct Outbox<T> {
+    next_seq: u64,
+    pending: VecDeque<(u64, T)>,
+    max_pending: usize,
+}
+
+impl<T> Outbox<T> {
+    pub fn with_capacity(max_pending: usize) -> Self {
+        Self {
+            // Start from the clock so numbers keep increasing across restarts
+            // and a late ack for the previous process never matches
+            next_seq: chrono::Utc::now().timestamp_millis().max(1) as u64,
+            pending: VecDeque::new(),
+            max_pending,
+        }
+    }
+
+    /// Keep a message until it is acked, returning its sequence number
+    pub fn track(&mut self, message: T) -> u64 {
+        if self.pending.len() >= self.max_pending {
+            if let Some((seq, _)) = self.pending.pop_front() {
+                warn!(
+                    seq = seq,
+                    "Too many unacknowledged messages, dropping the oldest"
+                );
+            }
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.push_back((seq, message));
+        seq
+    }
+
+    /// Remove and return the messages up to and including `seq`
+    pub fn ack(&mut self, seq: u64) -> Vec<T> {
+        let count = self.pending.iter().take_while(|(s, _)| *s <= seq).count();
+        self.pending
+            .drain(..count)
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    /// Unacked messages, oldest first, for retransmission
+    pub fn pending(&self) -> impl Iterator<Item = (u64, &T)> {
+        self.pending.iter().map(|(seq, message)| (*seq, message))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cumulative_ack() {
+        let mut outbox = Outbox::with_capacity(10);
+        let first = outbox.track("a");
+        let second = outbox.track("b");
+        outbox.track("c");
+        assert_eq!(second, first + 1);
+
+        assert_eq!(outbox.ack(second), vec!["a", "b"]);
+        assert_eq!(
+            outbox.pending().map(|(_, m)| *m).collect::<Vec<_>>(),
+            vec!["c"]
+        );
+        assert!(outbox.ack(first).is_empty());
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest() {
+        let mut outbox = Outbox::with_capacity(2);
+        outbox.track(1);
+        outbox.track(2);
+        let last = outbox.track(3);
+
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.ack(last), vec![2, 3]);
+    }
+}
@@ -0,0 +1,61 @@
+//! OTLP export and trace context propagation. The agent and the gateway both
+//! include this file as is: it only uses external crates and their `otel`
+//! feature.
+
+use std::collections::HashMap;
+use tracing::Span;
+
+/// W3C trace context headers (`traceparent`, `tracestate`)
+pub type TraceContext = HashMap<String, String>;
+
+/// Tracing layer exporting spans over OTLP, if an endpoint is configured.
+/// `OTEL_SERVICE_NAME` overrides `service_name`.
+#[cfg(feature = "otel")]
+pub fn layer<S>(
+    service_name: &str,
+) -> anyhow::Result<
+    Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>,
+>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service_name.to_string());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name,
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Make `span` a child of the trace carried by a message
+pub fn set_parent(span: &Span, context: Option<&TraceContext>) {
+    #[cfg(feature = "otel")]
+    if let Some(context) = context {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(context)
+        });
+        span.set_parent(parent);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, context);
+}
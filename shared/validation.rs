@@ -0,0 +1,53 @@
+//! Config validation results and the file checks both config validators
+//! make. The agent and the gateway both include this file as is: it only
+//! uses the standard library.
+
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Problems found in a configuration
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Validation {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.errors.push(message.into());
+    }
+
+    pub fn warning(&mut self, message: impl Into<String>) {
+        self.warnings.push(message.into());
+    }
+}
+
+pub fn check_readable_file(v: &mut Validation, field: &str, path: &str) {
+    if !Path::new(path).is_file() {
+        v.error(format!("{} '{}' does not exist", field, path));
+    } else if let Err(e) = File::open(path) {
+        v.error(format!("{} '{}' is not readable: {}", field, path, e));
+    }
+}
+
+#[cfg(unix)]
+pub fn check_private_key(v: &mut Validation, field: &str, path: &str) {
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            v.warning(format!(
+                "{} '{}' is accessible by group/others, use chmod 600",
+                field, path
+            ));
+        }
+    }
+}
+
+/// Key files on Windows are protected by ACLs, not mode bits
+#[cfg(not(unix))]
+pub fn check_private_key(_v: &mut Validation, _field: &str, _path: &str) {}
@@ -0,0 +1,54 @@
+//! File change notifications. The agent and the gateway both include this
+//! file as is: it only uses external crates.
+
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Send on `tx` when one of the files is created or changed, for as long as
+/// the returned watcher is kept. The files' directories are watched, so
+/// replaced files are noticed too.
+pub fn watch_files(paths: &[PathBuf], tx: mpsc::Sender<()>) -> Result<notify::RecommendedWatcher> {
+    let file_names: HashSet<_> = paths
+        .iter()
+        .filter_map(|p| p.file_name())
+        .map(|n| n.to_os_string())
+        .collect();
+    let directories: HashSet<PathBuf> = paths
+        .iter()
+        .map(|p| {
+            p.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf()
+        })
+        .collect();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+
+        // Kubernetes secret and configmap volumes swap a "..data" symlink
+        let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|p| {
+                p.file_name()
+                    .is_some_and(|n| file_names.contains(n) || n == "..data")
+            });
+
+        if relevant {
+            let _ = tx.try_send(());
+        }
+    })?;
+
+    for directory in &directories {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {}", directory.display()))?;
+    }
+
+    Ok(watcher)
+}